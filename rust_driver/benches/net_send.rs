@@ -0,0 +1,22 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use open_rdma_driver::net_bench::WriteSender;
+
+const PMTU: usize = 4096;
+
+/// Send a write in 4KB packets, copying the payload into the frames, staging it in a buffer before
+/// gathering it with `sendmsg` like the path before the sges pointed into the MR, or gathering it
+/// from the source buffer with `sendmsg`.
+fn udp_send(c: &mut Criterion) {
+    for (name, len) in [("udp_send_64k", 64 * 1024), ("udp_send_4m", 4 * 1024 * 1024)] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(len as u64));
+        let mut sender = WriteSender::new(len, PMTU);
+        group.bench_function("copied", |b| b.iter(|| sender.send_copied()));
+        group.bench_function("staged", |b| b.iter(|| sender.send_staged()));
+        group.bench_function("vectored", |b| b.iter(|| sender.send_vectored()));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, udp_send);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use open_rdma_driver::{
+    bench::{generate_random_descriptors, round_robin_strategy},
+    types::Qpn,
+};
+
+const DESC_CNT_PER_QP: usize = 64;
+const QP_CNTS: [u32; 4] = [1, 4, 16, 64];
+
+/// Push `DESC_CNT_PER_QP` descriptors of every QP to the round-robin strategy, then pop all of them.
+fn round_robin_push_pop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("round_robin_push_pop");
+    for qp_cnt in QP_CNTS {
+        group.throughput(Throughput::Elements(u64::from(qp_cnt) * DESC_CNT_PER_QP as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(qp_cnt), &qp_cnt, |b, &qp_cnt| {
+            let strategy = round_robin_strategy(1);
+            b.iter_batched(
+                || {
+                    (0..qp_cnt)
+                        .map(|qpn| generate_random_descriptors(qpn, DESC_CNT_PER_QP))
+                        .collect::<Vec<_>>()
+                },
+                |descs| {
+                    for (qpn, desc) in (0..qp_cnt).zip(descs) {
+                        strategy.push(Qpn::new(qpn), desc).unwrap();
+                    }
+                    while let Some(desc) = strategy.pop().unwrap() {
+                        let _ = black_box(desc);
+                    }
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_robin_push_pop);
+criterion_main!(benches);
@@ -1,8 +1,16 @@
-use std::{alloc::{alloc, dealloc, Layout}, ops::{Deref, DerefMut}, slice::from_raw_parts_mut};
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    sync::Mutex,
+};
 
-use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use log::{Level, LevelFilter, Metadata, Record};
 
-struct SimpleLogger;
+/// Print the records to stdout and append them to a file
+struct SimpleLogger {
+    file: Mutex<BufWriter<File>>,
+}
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -12,60 +20,29 @@ impl log::Log for SimpleLogger {
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             println!("{} - {}", record.level(), record.args());
+            if let Ok(mut file) = self.file.lock() {
+                let _ = writeln!(file, "{} - {}", record.level(), record.args());
+            }
         }
     }
 
-    fn flush(&self) {}
-}
-
-pub fn init_logging() -> Result<(), SetLoggerError> {
-    log::set_boxed_logger(Box::new(SimpleLogger)).map(|()| log::set_max_level(LevelFilter::Debug))
-}
-
-const PAGE_SIZE: usize = 1024 * 1024 * 2;
-
-#[allow(unused)]
-pub(crate) fn allocate_aligned_memory(size: usize) -> &'static mut [u8] {
-    let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
-    let ptr = unsafe { alloc(layout) };
-    unsafe { from_raw_parts_mut(ptr, size) }
-}
-
-pub(crate) fn deallocate_aligned_memory(buf: &mut [u8], size: usize) {
-    let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
-    unsafe {
-        dealloc(buf.as_mut_ptr(), layout);
-    }
-}
-
-/// An aligned memory buffer.
-#[derive(Debug)]
-pub struct AlignedMemory<'a>(&'a mut [u8]);
-
-impl AlignedMemory<'_> {
-    /// # Errors
-    /// Return an error if the size is too large.
-    pub fn new(size: usize) -> Self {
-       AlignedMemory(allocate_aligned_memory(size))
-    }
-}
-
-impl Drop for AlignedMemory<'_> {
-    fn drop(&mut self) {
-        deallocate_aligned_memory(self.0, self.0.len())
-    }
-}
-
-impl Deref for AlignedMemory<'_> {
-    type Target = [u8];
-
-    fn deref(&self) -> &Self::Target {
-        self.0
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
     }
 }
 
-impl DerefMut for AlignedMemory<'_> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0
-    }
+/// Log to stdout and the file at `path`. The logs are appended if the file exists.
+///
+/// # Errors
+/// Return an error if the file can't be opened or a logger has been set.
+pub fn init_logging(path: &str) -> Result<(), Box<dyn Error>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let logger = SimpleLogger {
+        file: Mutex::new(BufWriter::new(file)),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(LevelFilter::Debug);
+    Ok(())
 }
@@ -1,17 +1,16 @@
 use buddy_system_allocator::LockedHeap;
 
-use common::AlignedMemory;
 use eui48::MacAddress;
 use log::info;
 use open_rdma_driver::{
     qp::QpManager,
     types::{
         MemAccessTypeFlag, Pmtu, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
-        RdmaDeviceNetworkParamBuilder, PAGE_SIZE, Sge,
+        RdmaDeviceNetworkParamBuilder, Sge,
     },
-    Device, Mr, Pd
+    AlignedMemory, Device, Mr, Pd
 };
-use std::{ffi::c_void, net::Ipv4Addr};
+use std::{ffi::c_void, net::Ipv4Addr, time::Duration};
 
 use crate::common::init_logging;
 
@@ -27,6 +26,8 @@ static HEAP_ALLOCATOR: LockedHeap<ORDER> = LockedHeap::<ORDER>::new();
 const HEAP_BLOCK_SIZE: usize = 1024 * 1024 * 64;
 const BUFFER_LENGTH: usize = 1024 * 128;
 const SEND_CNT: usize = 1024 * 64;
+/// Fail an operation instead of hanging if its ACK is lost
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
 static mut HEAP_START_ADDR: usize = 0;
 
 mod common;
@@ -60,13 +61,13 @@ fn init_global_allocator() {
     }
 }
 
-fn create_and_init_card<'a>(
+fn create_and_init_card(
     card_id: usize,
     mock_server_addr: &str,
     qpn: Qpn,
     local_network: &RdmaDeviceNetworkParam,
     remote_network: &RdmaDeviceNetworkParam,
-) -> (Device, Pd, Mr, AlignedMemory<'a>) {
+) -> (Device, Pd, Mr, AlignedMemory) {
     let head_start_addr = unsafe { HEAP_START_ADDR };
     let dev = Device::new_emulated(
         mock_server_addr.parse().unwrap(),
@@ -79,28 +80,20 @@ fn create_and_init_card<'a>(
     let pd = dev.alloc_pd().unwrap();
     info!("[{}] PD allocated", card_id);
 
-    let mut mr_buffer = AlignedMemory::new(BUFFER_LENGTH);
+    let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+        | MemAccessTypeFlag::IbvAccessRemoteWrite
+        | MemAccessTypeFlag::IbvAccessLocalWrite;
+    let (mr, mr_buffer) = dev
+        .alloc_and_reg_mr(pd, BUFFER_LENGTH, access_flag)
+        .unwrap();
 
     unsafe {
         info!(
             "[{}] MR's PA_START={:X}",
             card_id,
-            mr_buffer.as_mut_ptr() as usize - HEAP_START_ADDR
+            mr_buffer.as_ptr() as usize - HEAP_START_ADDR
         );
     }
-
-    let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
-        | MemAccessTypeFlag::IbvAccessRemoteWrite
-        | MemAccessTypeFlag::IbvAccessLocalWrite;
-    let mr = dev
-        .reg_mr(
-            pd,
-            mr_buffer.as_mut_ptr() as u64,
-            mr_buffer.len() as u32,
-            PAGE_SIZE as u32,
-            access_flag,
-        )
-        .unwrap();
     info!("[{}] MR registered", card_id);
     let qp = QpBuilder::default()
         .pd(pd)
@@ -110,6 +103,7 @@ fn create_and_init_card<'a>(
         .pmtu(Pmtu::Mtu4096)
         .dqp_ip(remote_network.ipaddr)
         .dqp_mac(remote_network.macaddr)
+        .ack_timeout(Some(ACK_TIMEOUT))
         .build()
         .unwrap();
     dev.create_qp(&qp).unwrap();
@@ -118,7 +112,7 @@ fn create_and_init_card<'a>(
     (dev, pd, mr, mr_buffer)
 }
 fn main() {
-    init_logging().unwrap();
+    init_logging("log.txt").unwrap();
     let qp_manager = QpManager::new();
     let qpn = qp_manager.alloc().unwrap();
     let a_network = RdmaDeviceNetworkParamBuilder::default()
@@ -1,7 +1,7 @@
 use std::net::Ipv4Addr;
 
 use buddy_system_allocator::LockedHeap;
-use common::{init_logging, AlignedMemory};
+use common::init_logging;
 use eui48::MacAddress;
 use libc::c_void;
 use log::info;
@@ -9,9 +9,9 @@ use open_rdma_driver::{
     qp::QpManager,
     types::{
         MemAccessTypeFlag, Pmtu, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
-        RdmaDeviceNetworkParamBuilder, PAGE_SIZE, Sge,
+        RdmaDeviceNetworkParamBuilder, Sge,
     },
-    Device, Mr, Pd,
+    AlignedMemory, Device, Mr, Pd, ToHostWorkRbDescOpcode,
 };
 
 mod common;
@@ -58,13 +58,13 @@ fn init_global_allocator() {
     }
 }
 
-fn create_and_init_emulated_card<'a>(
+fn create_and_init_emulated_card(
     card_id: usize,
     mock_server_addr: &str,
     qpn: Qpn,
     local_network: &RdmaDeviceNetworkParam,
     remote_network: &RdmaDeviceNetworkParam,
-) -> (Device, Pd, Mr, AlignedMemory<'a>) {
+) -> (Device, Pd, Mr, AlignedMemory) {
     let head_start_addr = unsafe { HEAP_START_ADDR };
     let dev = Device::new_emulated(
         mock_server_addr.parse().unwrap(),
@@ -77,28 +77,20 @@ fn create_and_init_emulated_card<'a>(
     let pd = dev.alloc_pd().unwrap();
     info!("[{}] PD allocated", card_id);
 
-    let mut mr_buffer = AlignedMemory::new(BUFFER_LENGTH);
+    let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+        | MemAccessTypeFlag::IbvAccessRemoteWrite
+        | MemAccessTypeFlag::IbvAccessLocalWrite;
+    let (mr, mr_buffer) = dev
+        .alloc_and_reg_mr(pd, BUFFER_LENGTH, access_flag)
+        .unwrap();
 
     unsafe {
         info!(
             "[{}] MR's PA_START={:X}",
             card_id,
-            mr_buffer.as_mut_ptr() as usize - HEAP_START_ADDR
+            mr_buffer.as_ptr() as usize - HEAP_START_ADDR
         );
     }
-
-    let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
-        | MemAccessTypeFlag::IbvAccessRemoteWrite
-        | MemAccessTypeFlag::IbvAccessLocalWrite;
-    let mr = dev
-        .reg_mr(
-            pd,
-            mr_buffer.as_mut_ptr() as u64,
-            mr_buffer.len() as u32,
-            PAGE_SIZE as u32,
-            access_flag,
-        )
-        .unwrap();
     info!("[{}] MR registered", card_id);
     let qp = QpBuilder::default()
         .pd(pd)
@@ -116,31 +108,23 @@ fn create_and_init_emulated_card<'a>(
     (dev, pd, mr, mr_buffer)
 }
 
-fn create_and_init_software_card<'a>(
+fn create_and_init_software_card(
     card_id: usize,
     qpn: Qpn,
     local_network: &RdmaDeviceNetworkParam,
     remote_network: &RdmaDeviceNetworkParam,
-) -> (Device, Pd, Mr, AlignedMemory<'a>) {
+) -> (Device, Pd, Mr, AlignedMemory) {
     let dev = Device::new_software(local_network).unwrap();
     info!("[{}] Device created", card_id);
 
     let pd = dev.alloc_pd().unwrap();
     info!("[{}] PD allocated", card_id);
 
-    let mut mr_buffer = AlignedMemory::new(BUFFER_LENGTH);
-
     let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
         | MemAccessTypeFlag::IbvAccessRemoteWrite
         | MemAccessTypeFlag::IbvAccessLocalWrite;
-    let mr = dev
-        .reg_mr(
-            pd,
-            mr_buffer.as_mut_ptr() as u64,
-            mr_buffer.len() as u32,
-            PAGE_SIZE as u32,
-            access_flag,
-        )
+    let (mr, mr_buffer) = dev
+        .alloc_and_reg_mr(pd, BUFFER_LENGTH, access_flag)
         .unwrap();
     info!("[{}] MR registered", card_id);
     let qp = QpBuilder::default()
@@ -162,7 +146,7 @@ fn create_and_init_software_card<'a>(
 const SEND_CNT: usize = 1024 * 12;
 
 fn main() {
-    init_logging().unwrap();
+    init_logging("log.txt").unwrap();
 
     let qp_manager = QpManager::new();
     let qpn = qp_manager.alloc().unwrap();
@@ -212,7 +196,9 @@ fn main() {
             )
             .unwrap();
 
-        let _ = ctx1.wait();
+        let result = ctx1.wait_result().unwrap().unwrap();
+        assert_eq!(result.byte_len as usize, SEND_CNT);
+        assert_eq!(result.opcode, ToHostWorkRbDescOpcode::Acknowledge);
         assert_eq!(mr_buffer_a[0..SEND_CNT], mr_buffer_b[0..SEND_CNT]);
         info!("Emulator write to software success");
     }
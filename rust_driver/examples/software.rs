@@ -1,11 +1,10 @@
-use common::AlignedMemory;
 use eui48::MacAddress;
 use log::info;
 use open_rdma_driver::{
     qp::QpManager, types::{
         MemAccessTypeFlag, Pmtu, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
-        RdmaDeviceNetworkParamBuilder, PAGE_SIZE, Sge,
-    }, Device, Mr, Pd
+        RdmaDeviceNetworkParamBuilder, Sge,
+    }, AlignedMemory, Device, Mr, Pd
 };
 use std::net::Ipv4Addr;
 
@@ -16,31 +15,23 @@ const SEND_CNT: usize = 1024 * 64;
 
 mod common;
 
-fn create_and_init_card<'a>(
+fn create_and_init_card(
     card_id: usize,
     qpn: Qpn,
     local_network: &RdmaDeviceNetworkParam,
     remote_network: &RdmaDeviceNetworkParam,
-) -> (Device, Pd, Mr, AlignedMemory<'a>) {
+) -> (Device, Pd, Mr, AlignedMemory) {
     let dev = Device::new_software(local_network).unwrap();
     info!("[{}] Device created", card_id);
 
     let pd = dev.alloc_pd().unwrap();
     info!("[{}] PD allocated", card_id);
 
-    let mut mr_buffer = AlignedMemory::new(BUFFER_LENGTH);
-
     let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
         | MemAccessTypeFlag::IbvAccessRemoteWrite
         | MemAccessTypeFlag::IbvAccessLocalWrite;
-    let mr = dev
-        .reg_mr(
-            pd,
-            mr_buffer.as_mut_ptr() as u64,
-            mr_buffer.len() as u32,
-            PAGE_SIZE as u32,
-            access_flag,
-        )
+    let (mr, mr_buffer) = dev
+        .alloc_and_reg_mr(pd, BUFFER_LENGTH, access_flag)
         .unwrap();
     info!("[{}] MR registered", card_id);
     let qp = QpBuilder::default()
@@ -59,7 +50,7 @@ fn create_and_init_card<'a>(
     (dev, pd, mr, mr_buffer)
 }
 fn main() {
-    init_logging().unwrap();
+    init_logging("log.txt").unwrap();
     let a_network = RdmaDeviceNetworkParamBuilder::default()
         .gateway(Ipv4Addr::new(127, 0, 0, 0x1))
         .netmask(Ipv4Addr::new(255, 0, 0, 0))
@@ -0,0 +1,398 @@
+use std::{
+    fmt,
+    net::{SocketAddr, SocketAddrV4},
+    os::fd::OwnedFd,
+    sync::Arc,
+};
+
+use log::{LevelFilter, Log};
+
+#[cfg(feature = "scheduler")]
+use crate::device::scheduler::DescriptorScheduler;
+
+use crate::{
+    device::{
+        scheduler::round_robin::RoundRobinStrategy,
+        software::{
+            FaultInjector, FrameChecks, IcrcConfig, MemoryFabric, UdpSockets, NET_SERVER_BUF_SIZE,
+        },
+        EmulatedDevice, HardwareDevice, SoftwareDevice,
+    },
+    mr::ACKNOWLEDGE_BUFFER_SIZE,
+    responser::AcknowledgeBuffer,
+    types::RdmaDeviceNetworkParam,
+    Device, Error, SchedulerStrategy, DEFAULT_RMDA_PORT,
+};
+
+/// The adaptor a `Device` sends the descriptors to
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// The software device sending the packets through a raw socket
+    Software,
+    /// The software device over the raw sockets opened by another process, e.g. a privileged
+    /// helper passing the fds to an unprivileged worker, since opening them requires `CAP_NET_RAW`.
+    ///
+    /// The device duplicates the fds, so the caller can close its own ones after the build.
+    SoftwareSockets {
+        /// The socket sending the packets, an IPv4 raw socket of UDP with `IP_HDRINCL` set
+        send_socket: Arc<OwnedFd>,
+        /// The socket receiving the packets, an IPv4 raw socket of UDP bound to the device address
+        recv_socket: Arc<OwnedFd>,
+    },
+    /// The software device on the in-process loopback network, which needs no privilege
+    Loopback,
+    /// The software device on the in-process `fabric`, which needs no privilege and only reaches
+    /// the devices on the same fabric
+    Fabric {
+        /// The fabric the device is registered with
+        fabric: Arc<MemoryFabric>,
+    },
+    /// The emulated device behind the RPC server at `rpc_server_addr`
+    Emulated {
+        /// The address of the RPC server
+        rpc_server_addr: SocketAddr,
+        /// The start address of the heap memory shared with the emulator
+        heap_mem_start_addr: usize,
+    },
+    /// The hardware device named `device_name`
+    Hardware {
+        /// The name of the device
+        device_name: String,
+    },
+}
+
+/// A builder of `Device`, which collects all the tunables of the device in one place.
+///
+/// The tunables which are not set keep their default values, so a new tunable does not break the
+/// existing users.
+///
+/// ```rust,ignore
+/// let device = DeviceBuilder::new(&network)
+///     .transport(Transport::Loopback)
+///     .send_workers(4)
+///     .build()?;
+/// ```
+#[allow(clippy::module_name_repetitions)]
+pub struct DeviceBuilder {
+    network: RdmaDeviceNetworkParam,
+    transport: Transport,
+    scheduler: Option<Arc<dyn SchedulerStrategy>>,
+    max_burst: usize,
+    scheduler_capacity: Option<usize>,
+    send_workers: usize,
+    recv_buf_size: usize,
+    ack_buf_size: usize,
+    faults: Option<FaultInjector>,
+    retransmission: bool,
+    local_copy: bool,
+    link_mtu: Option<usize>,
+    checks: FrameChecks,
+    logger: Option<(Box<dyn Log>, LevelFilter)>,
+}
+
+impl fmt::Debug for DeviceBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceBuilder")
+            .field("network", &self.network)
+            .field("transport", &self.transport)
+            .field("scheduler", &self.scheduler)
+            .field("max_burst", &self.max_burst)
+            .field("scheduler_capacity", &self.scheduler_capacity)
+            .field("send_workers", &self.send_workers)
+            .field("recv_buf_size", &self.recv_buf_size)
+            .field("ack_buf_size", &self.ack_buf_size)
+            .field("faults", &self.faults)
+            .field("retransmission", &self.retransmission)
+            .field("local_copy", &self.local_copy)
+            .field("link_mtu", &self.link_mtu)
+            .field("checks", &self.checks)
+            .field("logger", &self.logger.as_ref().map(|(_, level)| level))
+            .finish()
+    }
+}
+
+impl DeviceBuilder {
+    /// Create a builder of a software device on `network`, which schedules the work descriptors in
+    /// round robin.
+    #[must_use]
+    pub fn new(network: &RdmaDeviceNetworkParam) -> Self {
+        Self {
+            network: *network,
+            transport: Transport::Software,
+            scheduler: None,
+            max_burst: 1,
+            scheduler_capacity: None,
+            send_workers: 1,
+            recv_buf_size: NET_SERVER_BUF_SIZE,
+            ack_buf_size: ACKNOWLEDGE_BUFFER_SIZE,
+            faults: None,
+            retransmission: true,
+            local_copy: false,
+            link_mtu: None,
+            checks: FrameChecks::default(),
+            logger: None,
+        }
+    }
+
+    /// Set the network parameters of the device.
+    #[must_use]
+    pub fn network(mut self, network: &RdmaDeviceNetworkParam) -> Self {
+        self.network = *network;
+        self
+    }
+
+    /// Set the adaptor of the device, `Transport::Software` by default.
+    #[must_use]
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Schedule the work descriptors with `scheduler`.
+    ///
+    /// Without the `scheduler` feature, the emulated and the hardware devices send the descriptors in
+    /// order and ignore it.
+    #[must_use]
+    pub fn scheduler(mut self, scheduler: Arc<dyn SchedulerStrategy>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Send at most `max_burst` descriptors of a QP in a row in the default round robin scheduler
+    /// before the other QPs get a turn, 1 by default. A `max_burst` of 0 is treated as 1.
+    ///
+    /// It's ignored when a `scheduler` is set.
+    #[must_use]
+    pub fn max_burst(mut self, max_burst: usize) -> Self {
+        self.max_burst = max_burst;
+        self
+    }
+
+    /// Queue at most `capacity` descriptors in the default round robin scheduler, unlimited by
+    /// default. The work requests posted while it's full fail with `Error::DeviceBusy`.
+    ///
+    /// It's ignored when a `scheduler` is set.
+    #[must_use]
+    pub fn scheduler_capacity(mut self, capacity: usize) -> Self {
+        self.scheduler_capacity = Some(capacity);
+        self
+    }
+
+    /// Send the scheduled descriptors with `send_workers` threads, see
+    /// `Device::new_software_with_send_workers`. Only the software device over a raw socket uses it.
+    #[must_use]
+    pub fn send_workers(mut self, send_workers: usize) -> Self {
+        self.send_workers = send_workers;
+        self
+    }
+
+    /// Receive the packets into a buffer of `size` bytes, which limits the largest packet the device
+    /// can receive. Only the software device over a raw socket uses it.
+    #[must_use]
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buf_size = size;
+        self
+    }
+
+    /// Allocate `size` bytes for the acknowledge buffer, which limits the acknowledges in flight.
+    ///
+    /// Every acknowledge takes a slot of 64 bytes until it's sent, so the size should be a nonzero
+    /// multiple of 64. A larger buffer keeps the responses flowing when many QPs are acknowledged at
+    /// the same time.
+    #[must_use]
+    pub fn ack_buffer_size(mut self, size: usize) -> Self {
+        self.ack_buf_size = size;
+        self
+    }
+
+    /// Inject the faults of `faults` into the packets the device sends, to test the loss recovery.
+    /// Only the software device uses it.
+    #[must_use]
+    pub fn fault_injector(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Resend the lost packets of the RC QPs, which is enabled by default. Without it, a lost packet
+    /// fails its operation. Only the software device uses it.
+    #[must_use]
+    pub fn retransmission(mut self, enabled: bool) -> Self {
+        self.retransmission = enabled;
+        self
+    }
+
+    /// Copy the payload of the RC writes of the device to itself into the MR at once, instead of
+    /// sending it through the network in packets, which is disabled by default. The acknowledges and
+    /// the other operations still go through the network. Only the software device supports it.
+    #[must_use]
+    pub fn local_copy(mut self, enabled: bool) -> Self {
+        self.local_copy = enabled;
+        self
+    }
+
+    /// Split the sent packets larger than `link_mtu` into IP fragments, see `Device::set_link_mtu`.
+    /// The packets are never split by default. Only the software device over UDP supports it.
+    #[must_use]
+    pub fn link_mtu(mut self, link_mtu: usize) -> Self {
+        self.link_mtu = Some(link_mtu);
+        self
+    }
+
+    /// Deliver the received packets failing the ICRC check instead of dropping them, which is
+    /// disabled by default. The failures are still logged, which helps diagnosing a peer computing
+    /// the ICRC differently. Only the software device uses it.
+    #[must_use]
+    pub fn accept_invalid_icrc(mut self, enabled: bool) -> Self {
+        self.checks.accept_invalid_icrc = enabled;
+        self
+    }
+
+    /// Compute the ICRC of the sent packets and check the one of the received packets with
+    /// `config`, the `RoCEv2` standard by default. It's only for the interop with the peers computing
+    /// the ICRC differently, see `IcrcConfig`. Only the software device uses it.
+    #[must_use]
+    pub fn icrc(mut self, config: IcrcConfig) -> Self {
+        self.checks.icrc = config;
+        self
+    }
+
+    /// Set `logger` as the global logger with the max level `level` when the device is built, so
+    /// that the logs of the initialization are captured as well.
+    #[must_use]
+    pub fn logger(mut self, logger: Box<dyn Log>, level: LevelFilter) -> Self {
+        self.logger = Some((logger, level));
+        self
+    }
+
+    /// Build the device.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the acknowledge buffer size is invalid, a global logger has been set, the
+    /// device failed to create the adaptor, the adaptor does not support a tunable, or the device
+    /// failed to init.
+    #[allow(clippy::too_many_lines)]
+    pub fn build(self) -> Result<Device, Error> {
+        let slot_size = AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
+        if self.ack_buf_size == 0 || self.ack_buf_size.checked_rem(slot_size) != Some(0) {
+            return Err(Error::Invalid(format!(
+                "acknowledge buffer size {:#x} is not a multiple of the slot size {slot_size:#x}",
+                self.ack_buf_size
+            )));
+        }
+        if let Some((logger, level)) = self.logger {
+            log::set_boxed_logger(logger)
+                .map_err(|e| Error::DoubleInit(format!("logger: {e}")))?;
+            log::set_max_level(level);
+        }
+        let network = &self.network;
+        let ack_buf_size = self.ack_buf_size;
+        let (max_burst, scheduler_capacity) = (self.max_burst, self.scheduler_capacity);
+        let scheduler = self.scheduler.unwrap_or_else(|| {
+            Arc::new(RoundRobinStrategy::with_limits(max_burst, scheduler_capacity))
+        });
+        let (local_copy, link_mtu) = (self.local_copy, self.link_mtu);
+        // only the software devices on a real network probe the link MTUs, the hardware one sends
+        // through its own link
+        let device = match self.transport {
+            Transport::Software => {
+                let adaptor = SoftwareDevice::init_with_options(
+                    SocketAddrV4::new(network.ipaddr, DEFAULT_RMDA_PORT),
+                    None,
+                    scheduler,
+                    self.send_workers,
+                    self.recv_buf_size,
+                    self.faults,
+                    self.retransmission,
+                    self.checks,
+                )
+                .map_err(Error::Device)?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, true)
+            }
+            Transport::SoftwareSockets {
+                send_socket,
+                recv_socket,
+            } => {
+                let sockets = UdpSockets::dup(&send_socket, &recv_socket)
+                    .map_err(|e| Error::Device(Box::new(e)))?;
+                let adaptor = SoftwareDevice::init_with_options(
+                    SocketAddrV4::new(network.ipaddr, DEFAULT_RMDA_PORT),
+                    Some(sockets),
+                    scheduler,
+                    self.send_workers,
+                    self.recv_buf_size,
+                    self.faults,
+                    self.retransmission,
+                    self.checks,
+                )
+                .map_err(Error::Device)?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, true)
+            }
+            Transport::Loopback => {
+                let fabric = Arc::clone(MemoryFabric::global());
+                let adaptor = SoftwareDevice::init_on_fabric(
+                    fabric,
+                    network.ipaddr,
+                    network.macaddr,
+                    DEFAULT_RMDA_PORT,
+                    scheduler,
+                    self.faults,
+                    self.retransmission,
+                    self.checks,
+                )
+                .map_err(Error::Device)?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, false)
+            }
+            Transport::Fabric { fabric } => {
+                let adaptor = SoftwareDevice::init_on_fabric(
+                    fabric,
+                    network.ipaddr,
+                    network.macaddr,
+                    DEFAULT_RMDA_PORT,
+                    scheduler,
+                    self.faults,
+                    self.retransmission,
+                    self.checks,
+                )
+                .map_err(Error::Device)?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, false)
+            }
+            Transport::Emulated {
+                rpc_server_addr,
+                heap_mem_start_addr,
+            } => {
+                #[cfg(feature = "scheduler")]
+                let adaptor = EmulatedDevice::init(
+                    rpc_server_addr,
+                    heap_mem_start_addr,
+                    Arc::new(DescriptorScheduler::new(scheduler)),
+                );
+                #[cfg(not(feature = "scheduler"))]
+                let adaptor = EmulatedDevice::init(rpc_server_addr, heap_mem_start_addr);
+                let adaptor = adaptor.map_err(|e| Error::Device(Box::new(e)))?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, false)
+            }
+            Transport::Hardware { device_name } => {
+                #[cfg(feature = "scheduler")]
+                let adaptor =
+                    HardwareDevice::init(device_name, Arc::new(DescriptorScheduler::new(scheduler)));
+                #[cfg(not(feature = "scheduler"))]
+                let adaptor = HardwareDevice::init(device_name);
+                let adaptor = adaptor.map_err(|e| Error::Device(Box::new(e)))?;
+                Device::new_with_adaptor(adaptor, network, ack_buf_size, false)
+            }
+        }?;
+        if local_copy {
+            device
+                .0
+                .adaptor
+                .set_local_copy(true)
+                .map_err(|e| Error::Device(Box::new(e)))?;
+        }
+        if link_mtu.is_some() {
+            device.set_link_mtu(link_mtu)?;
+        }
+        Ok(device)
+    }
+}
@@ -1,21 +1,25 @@
-use log::{debug, error};
+use log::debug;
 
 use self::rpc_cli::{
     RpcClient, ToCardCtrlRbCsrProxy, ToCardWorkRbCsrProxy, ToHostCtrlRbCsrProxy,
     ToHostWorkRbCsrProxy,
 };
 use super::{
-    constants, ringbuf::Ringbuf, DeviceAdaptor, DeviceError, ToCardCtrlRbDesc, ToCardRb,
-    ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescError,
+    constants,
+    ringbuf::{Ringbuf, TO_CARD_CTRL_RING},
+    DeviceAdaptor, DeviceError, PhysAddrResolver, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc,
+    ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescError,
 };
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
-    thread::spawn,
+    time::Duration,
 };
 
 #[cfg(feature = "scheduler")]
-use super::scheduler::DescriptorScheduler;
+use super::scheduler::{forward_to_card, DescriptorScheduler};
+#[cfg(feature = "scheduler")]
+use std::thread::spawn;
 
 mod rpc_cli;
 
@@ -141,21 +145,8 @@ impl EmulatedDevice {
         #[cfg(feature = "scheduler")]
         {
             let _: std::thread::JoinHandle<_> = spawn(move || {
-                let rb = Mutex::new(to_card_work_rb);
-                loop {
-                    match scheduler.pop() {
-                        Ok(result) => {
-                            if let Some(desc) = result {
-                                if let Err(e) = push_to_card_work_rb_desc(&rb, &desc) {
-                                    error!("push to to_card_work_rb failed: {:?}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("scheduler pop failed:{:?}", e);
-                        }
-                    }
-                }
+                let mut rb = to_card_work_rb;
+                forward_to_card(&scheduler, &mut rb)
             });
         }
 
@@ -195,6 +186,12 @@ impl DeviceAdaptor for Arc<EmulatedDevice> {
         self.rpc_cli.write_csr(addr, data)
     }
 
+    fn requires_hugepages(&self) -> bool {
+        false
+    }
+}
+
+impl PhysAddrResolver for Arc<EmulatedDevice> {
     fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
         if virt_addr < self.heap_mem_start_addr {
             return Err(DeviceError::Device(format!(
@@ -214,7 +211,7 @@ impl ToCardRb<ToCardCtrlRbDesc> for EmulatedDevice {
             .to_card_ctrl_rb
             .lock()
             .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
-        let mut writer = guard.write()?;
+        let mut writer = guard.try_write(1, TO_CARD_CTRL_RING)?;
 
         let mem = writer.next().ok_or(DeviceError::Overflow)?;
         debug!("{:?}", &desc);
@@ -225,57 +222,37 @@ impl ToCardRb<ToCardCtrlRbDesc> for EmulatedDevice {
 }
 
 impl ToHostRb<ToHostCtrlRbDesc> for EmulatedDevice {
-    fn pop(&self) -> Result<ToHostCtrlRbDesc, DeviceError> {
-        let mut guard = self
-            .to_host_ctrl_rb
-            .lock()
-            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostCtrlRbDesc>, DeviceError> {
+        let Some(mut guard) = Ringbuf::wait_pending(&self.to_host_ctrl_rb, timeout)? else {
+            return Ok(None);
+        };
         let mut reader = guard.read()?;
         let mem = reader.next().ok_or(DeviceError::Device(
             "Failed to read from ringbuf".to_owned(),
         ))?;
         let desc = ToHostCtrlRbDesc::read(mem)?;
         debug!("{:?}", &desc);
-        Ok(desc)
+        Ok(Some(desc))
     }
 }
 
 #[cfg(not(feature = "scheduler"))]
 impl ToCardRb<ToCardWorkRbDesc> for EmulatedDevice {
     fn push(&self, desc: ToCardWorkRbDesc) -> Result<(), DeviceError> {
-        // TODO: the card might not be able to handle "part of the desc"
-        // So me might need to ensure we have enough space to write the whole desc before writing
-        push_to_card_work_rb_desc(&self.to_card_work_rb, desc)
-    }
-}
-
-fn push_to_card_work_rb_desc(
-    rb: &Mutex<ToCardWorkRb>,
-    desc: &ToCardWorkRbDesc,
-) -> Result<(), DeviceError> {
-    debug!("driver send to card SQ: {:?}", &desc);
-    let mut guard = rb
-        .lock()
-        .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
-    let desc_cnt = desc.serialized_desc_cnt();
-    let mut writer = guard.write()?;
-    desc.write_0(writer.next().ok_or(DeviceError::Overflow)?);
-    desc.write_1(writer.next().ok_or(DeviceError::Overflow)?);
-    desc.write_2(writer.next().ok_or(DeviceError::Overflow)?);
-
-    if desc_cnt == 4 {
-        desc.write_3(writer.next().ok_or(DeviceError::Overflow)?);
+        let _: usize = self
+            .to_card_work_rb
+            .lock()
+            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?
+            .push_work_desc(&desc)?;
+        Ok(())
     }
-
-    Ok(())
 }
 
 impl ToHostRb<ToHostWorkRbDesc> for EmulatedDevice {
-    fn pop(&self) -> Result<ToHostWorkRbDesc, DeviceError> {
-        let mut guard = self
-            .to_host_work_rb
-            .lock()
-            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostWorkRbDesc>, DeviceError> {
+        let Some(mut guard) = Ringbuf::wait_pending(&self.to_host_work_rb, timeout)? else {
+            return Ok(None);
+        };
         let mut reader = guard.read()?;
 
         let mem = reader.next().ok_or(DeviceError::Device(
@@ -285,7 +262,7 @@ impl ToHostRb<ToHostWorkRbDesc> for EmulatedDevice {
 
         loop {
             match read_res {
-                Ok(desc) => break Ok(desc),
+                Ok(desc) => break Ok(Some(desc)),
                 Err(ToHostWorkRbDescError::DeviceError(e))=>{
                     return Err(e);
                 }
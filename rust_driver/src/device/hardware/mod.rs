@@ -1,23 +1,28 @@
-use log::{debug, error};
+use log::debug;
 
 use self::{
     csr_cli::{
         CsrClient, ToCardCtrlRbCsrProxy, ToCardWorkRbCsrProxy, ToHostCtrlRbCsrProxy,
         ToHostWorkRbCsrProxy,
     },
-    phys_addr_resolver::PhysAddrResolver,
+    phys_addr_resolver::PagemapResolver,
 };
 
 use super::{
-    constants, ringbuf::Ringbuf, scheduler::DescriptorScheduler, DeviceAdaptor, DeviceError,
-    ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc,
-    ToHostWorkRbDescError,
+    constants,
+    ringbuf::{Ringbuf, TO_CARD_CTRL_RING},
+    DeviceAdaptor, DeviceError, PhysAddrResolver, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc,
+    ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescError,
 };
+#[cfg(feature = "scheduler")]
+use super::scheduler::{forward_to_card, DescriptorScheduler};
 use std::{
     path::Path,
     sync::{Arc, Mutex},
-    thread::spawn,
+    time::Duration,
 };
+#[cfg(feature = "scheduler")]
+use std::thread::spawn;
 
 mod csr_cli;
 mod phys_addr_resolver;
@@ -54,18 +59,21 @@ type ToHostWorkRb = Ringbuf<
 #[derive(Debug)]
 pub(crate) struct HardwareDevice {
     to_card_ctrl_rb: Mutex<ToCardCtrlRb>,
+    #[cfg(not(feature = "scheduler"))]
+    to_card_work_rb: Mutex<ToCardWorkRb>,
     to_host_ctrl_rb: Mutex<ToHostCtrlRb>,
     to_host_work_rb: Mutex<ToHostWorkRb>,
     csr_cli: CsrClient,
+    #[cfg(feature = "scheduler")]
     scheduler: Arc<DescriptorScheduler>,
-    phys_addr_resolver: PhysAddrResolver,
+    phys_addr_resolver: PagemapResolver,
 }
 
 impl HardwareDevice {
     #[allow(clippy::cast_possible_truncation)]
     pub(crate) fn init<P: AsRef<Path>>(
         device_name: P,
-        scheduler: Arc<DescriptorScheduler>,
+        #[cfg(feature = "scheduler")] scheduler: Arc<DescriptorScheduler>,
     ) -> Result<Arc<Self>, DeviceError> {
         let csr_cli =
             CsrClient::new(device_name).map_err(|e| DeviceError::Device(e.to_string()))?;
@@ -78,14 +86,17 @@ impl HardwareDevice {
         let (to_host_work_rb, to_host_work_rb_addr) =
             ToHostWorkRb::new(ToHostWorkRbCsrProxy::new(csr_cli.clone()));
         let phys_addr_resolver =
-            PhysAddrResolver::new().map_err(|e| DeviceError::Device(e.to_string()))?;
+            PagemapResolver::new().map_err(|e| DeviceError::Device(e.to_string()))?;
         let dev = Arc::new(Self {
             to_card_ctrl_rb: Mutex::new(to_card_ctrl_rb),
             to_host_ctrl_rb: Mutex::new(to_host_ctrl_rb),
             to_host_work_rb: Mutex::new(to_host_work_rb),
             csr_cli,
+            #[cfg(feature = "scheduler")]
             scheduler: Arc::<DescriptorScheduler>::clone(&scheduler),
             phys_addr_resolver,
+            #[cfg(not(feature = "scheduler"))]
+            to_card_work_rb: Mutex::new(to_card_work_rb),
         });
 
         let pa_of_to_card_ctrl_rb_addr = dev.get_phys_addr(to_card_ctrl_rb_addr)?;
@@ -131,21 +142,8 @@ impl HardwareDevice {
         #[cfg(feature = "scheduler")]
         {
             let _: std::thread::JoinHandle<_> = spawn(move || {
-                let rb = Mutex::new(to_card_work_rb);
-                loop {
-                    match scheduler.pop() {
-                        Ok(result) => {
-                            if let Some(desc) = result {
-                                if let Err(e) = push_to_card_work_rb_desc(&rb, &desc) {
-                                    error!("push to to_card_work_rb failed: {:?}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("scheduler pop failed:{:?}", e);
-                        }
-                    }
-                }
+                let mut rb = to_card_work_rb;
+                forward_to_card(&scheduler, &mut rb)
             });
         }
 
@@ -163,19 +161,20 @@ impl DeviceAdaptor for Arc<HardwareDevice> {
     }
 
     fn to_card_work_rb(&self) -> Arc<dyn ToCardRb<ToCardWorkRbDesc>> {
-        Arc::<DescriptorScheduler>::clone(&self.scheduler)
+        #[cfg(feature = "scheduler")]
+        {
+            Arc::<DescriptorScheduler>::clone(&self.scheduler)
+        }
+        #[cfg(not(feature = "scheduler"))]
+        {
+            Arc::<HardwareDevice>::clone(self)
+        }
     }
 
     fn to_host_work_rb(&self) -> Arc<dyn ToHostRb<ToHostWorkRbDesc>> {
         Arc::<HardwareDevice>::clone(self)
     }
 
-    fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
-        self.phys_addr_resolver
-            .query(virt_addr)
-            .ok_or_else(|| DeviceError::Device(format!("Addr {virt_addr} not found")))
-    }
-
     fn read_csr(&self, addr: usize) -> Result<u32, DeviceError> {
         self.csr_cli.read_csr(addr)
     }
@@ -185,13 +184,21 @@ impl DeviceAdaptor for Arc<HardwareDevice> {
     }
 }
 
+impl PhysAddrResolver for Arc<HardwareDevice> {
+    fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
+        self.phys_addr_resolver
+            .query(virt_addr)
+            .ok_or_else(|| DeviceError::Device(format!("Addr {virt_addr} not found")))
+    }
+}
+
 impl ToCardRb<ToCardCtrlRbDesc> for HardwareDevice {
     fn push(&self, desc: ToCardCtrlRbDesc) -> Result<(), DeviceError> {
         let mut guard = self
             .to_card_ctrl_rb
             .lock()
             .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
-        let mut writer = guard.write()?;
+        let mut writer = guard.try_write(1, TO_CARD_CTRL_RING)?;
 
         let mem = writer.next().ok_or(DeviceError::Overflow)?;
         debug!("{:?}", &desc);
@@ -201,49 +208,38 @@ impl ToCardRb<ToCardCtrlRbDesc> for HardwareDevice {
     }
 }
 
-impl ToHostRb<ToHostCtrlRbDesc> for HardwareDevice {
-    fn pop(&self) -> Result<ToHostCtrlRbDesc, DeviceError> {
-        let mut guard = self
-            .to_host_ctrl_rb
+#[cfg(not(feature = "scheduler"))]
+impl ToCardRb<ToCardWorkRbDesc> for HardwareDevice {
+    fn push(&self, desc: ToCardWorkRbDesc) -> Result<(), DeviceError> {
+        let _: usize = self
+            .to_card_work_rb
             .lock()
-            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
+            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?
+            .push_work_desc(&desc)?;
+        Ok(())
+    }
+}
+
+impl ToHostRb<ToHostCtrlRbDesc> for HardwareDevice {
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostCtrlRbDesc>, DeviceError> {
+        let Some(mut guard) = Ringbuf::wait_pending(&self.to_host_ctrl_rb, timeout)? else {
+            return Ok(None);
+        };
         let mut reader = guard.read()?;
         let mem = reader.next().ok_or(DeviceError::Device(
             "Failed to read from ringbuf".to_owned(),
         ))?;
         let desc = ToHostCtrlRbDesc::read(mem)?;
         debug!("{:?}", &desc);
-        Ok(desc)
+        Ok(Some(desc))
     }
 }
 
-fn push_to_card_work_rb_desc(
-    rb: &Mutex<ToCardWorkRb>,
-    desc: &ToCardWorkRbDesc,
-) -> Result<(), DeviceError> {
-    debug!("driver send to card SQ: {:?}", &desc);
-    let mut guard = rb
-        .lock()
-        .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
-    let desc_cnt = desc.serialized_desc_cnt();
-    let mut writer = guard.write()?;
-    desc.write_0(writer.next().ok_or(DeviceError::Overflow)?);
-    desc.write_1(writer.next().ok_or(DeviceError::Overflow)?);
-    desc.write_2(writer.next().ok_or(DeviceError::Overflow)?);
-
-    if desc_cnt == 4 {
-        desc.write_3(writer.next().ok_or(DeviceError::Overflow)?);
-    }
-
-    Ok(())
-}
-
 impl ToHostRb<ToHostWorkRbDesc> for HardwareDevice {
-    fn pop(&self) -> Result<ToHostWorkRbDesc, DeviceError> {
-        let mut guard = self
-            .to_host_work_rb
-            .lock()
-            .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostWorkRbDesc>, DeviceError> {
+        let Some(mut guard) = Ringbuf::wait_pending(&self.to_host_work_rb, timeout)? else {
+            return Ok(None);
+        };
         let mut reader = guard.read()?;
 
         let mem = reader.next().ok_or(DeviceError::Device(
@@ -253,7 +249,7 @@ impl ToHostRb<ToHostWorkRbDesc> for HardwareDevice {
 
         loop {
             match read_res {
-                Ok(desc) => break Ok(desc),
+                Ok(desc) => break Ok(Some(desc)),
                 Err(ToHostWorkRbDescError::DeviceError(e)) => {
                     return Err(e);
                 }
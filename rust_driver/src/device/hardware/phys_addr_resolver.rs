@@ -5,18 +5,19 @@ use log::error;
 const PAGE_SHIFT: u64 = 12; // Typical page size shift for 4KB pages
 const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 
+/// Resolves the physical addresses through `/proc/self/pagemap`
 #[derive(Debug)]
-pub(crate) struct PhysAddrResolver {
+pub(crate) struct PagemapResolver {
     _pagemap: File,
     pagemap_fd: i32,
 }
 
-impl PhysAddrResolver {
-    pub(crate) fn new() -> io::Result<PhysAddrResolver> {
+impl PagemapResolver {
+    pub(crate) fn new() -> io::Result<PagemapResolver> {
         let pagemap_file = format!("/proc/{}/pagemap", process::id());
         let pagemap = File::open(pagemap_file)?;
         let pagemap_fd = pagemap.as_raw_fd();
-        Ok(PhysAddrResolver {
+        Ok(PagemapResolver {
             _pagemap: pagemap,
             pagemap_fd,
         })
@@ -1,24 +1,36 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, net::Ipv4Addr, sync::Arc, time::Duration};
 
+use eui48::MacAddress;
 use thiserror::Error;
 
+use crate::types::{EcnCodepoint, Qpn, ReadOverlapPolicy, Sge, UdpChecksumMode, PAGE_SIZE};
+
 mod constants;
 mod emulated;
 mod hardware;
 mod ringbuf;
-mod software;
+pub(crate) mod software;
 mod types;
 pub(crate) mod descriptor;
 
 pub(crate) mod scheduler;
-pub(crate) use types::ToCardWorkRbDesc;
+#[allow(clippy::module_name_repetitions)]
+pub use types::{
+    DeviceError, ToCardWorkRbDesc, ToCardWorkRbDescRead, ToCardWorkRbDescWrite,
+    ToCardWorkRbDescWriteWithImm, ToHostWorkRbDescOpcode,
+};
 
 pub(crate) use self::{
     emulated::EmulatedDevice, hardware::HardwareDevice, software::SoftwareDevice, types::*,
 };
 
+/// Translates the virtual addresses of the host memory into the physical addresses used by the device.
+pub(crate) trait PhysAddrResolver: Send + Sync + Debug {
+    fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError>;
+}
+
 /// Public interface for a device. Can be a real hardware device or a software emulation.
-pub(crate) trait DeviceAdaptor: Send + Sync + Debug {
+pub(crate) trait DeviceAdaptor: PhysAddrResolver + Send + Sync + Debug {
     fn to_card_ctrl_rb(&self) -> Arc<dyn ToCardRb<ToCardCtrlRbDesc>>;
     fn to_host_ctrl_rb(&self) -> Arc<dyn ToHostRb<ToHostCtrlRbDesc>>;
 
@@ -28,7 +40,150 @@ pub(crate) trait DeviceAdaptor: Send + Sync + Debug {
     fn read_csr(&self, addr: usize) -> Result<u32, DeviceError>;
     fn write_csr(&self, addr: usize, data: u32) -> Result<(), DeviceError>;
 
-    fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError>;
+    /// Whether the buffers shared with the adaptor must be backed by huge pages, since it reads
+    /// them by the physical addresses.
+    ///
+    /// Adaptors that access the buffers through the virtual addresses return `false`.
+    fn requires_hugepages(&self) -> bool {
+        true
+    }
+
+    /// The number of received packets of each opcode.
+    ///
+    /// Adaptors that do not track the received packets return an empty map.
+    fn opcode_counters(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        HashMap::new()
+    }
+
+    /// Mark the outgoing packets with `dscp` and `ecn` in the IP header.
+    ///
+    /// Adaptors that do not build the IP header by themselves return an error.
+    fn set_traffic_class(&self, _dscp: u8, _ecn: EcnCodepoint) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support setting traffic class".to_owned(),
+        ))
+    }
+
+    /// Fill the UDP checksum of the outgoing packets with `mode`.
+    ///
+    /// Adaptors that do not build the UDP header by themselves return an error.
+    fn set_udp_checksum_mode(&self, _mode: UdpChecksumMode) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support setting UDP checksum mode".to_owned(),
+        ))
+    }
+
+    /// Handle the read requests whose source and sink overlap with `policy`.
+    ///
+    /// Adaptors that do not serve the read requests by themselves return an error.
+    fn set_read_overlap_policy(&self, _policy: ReadOverlapPolicy) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support setting read overlap policy".to_owned(),
+        ))
+    }
+
+    /// Copy the RC writes of the device to itself into their MRs directly, without the network.
+    ///
+    /// Adaptors that do not serve the writes by themselves return an error.
+    fn set_local_copy(&self, _enabled: bool) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support local copy".to_owned(),
+        ))
+    }
+
+    /// Split the outgoing packets larger than `link_mtu` into IP fragments, or never split them if
+    /// it's `None`.
+    ///
+    /// Adaptors that do not build the IP header by themselves return an error.
+    fn set_link_mtu(&self, _link_mtu: Option<usize>) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support setting link mtu".to_owned(),
+        ))
+    }
+
+    /// Send the outgoing packets as 802.1Q tagged Ethernet frames through the interface `ifname`.
+    ///
+    /// Adaptors that do not build the packets by themselves return an error.
+    fn set_vlan(
+        &self,
+        _ifname: &str,
+        _vid: u16,
+        _pcp: u8,
+        _src_mac: MacAddress,
+        _dest_mac: MacAddress,
+    ) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support vlan".to_owned(),
+        ))
+    }
+
+    /// Address the packets of the qp `qpn` to the qp `peer_qpn` on the remote, which is `qpn` itself
+    /// unless it's set.
+    ///
+    /// Adaptors that do not build the BTH by themselves return an error.
+    fn set_peer_qpn(&self, _qpn: Qpn, _peer_qpn: Qpn) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support a destination qp other than the qp itself".to_owned(),
+        ))
+    }
+
+    /// Receive the packets sent to the multicast `group`, in addition to the unicast ones.
+    ///
+    /// Adaptors that do not receive the packets by themselves return an error.
+    fn join_multicast(&self, _group: Ipv4Addr) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support multicast".to_owned(),
+        ))
+    }
+
+    /// Stop receiving the packets sent to the multicast `group`.
+    fn leave_multicast(&self, _group: Ipv4Addr) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support multicast".to_owned(),
+        ))
+    }
+
+    /// Create the shared receive queue `srqn`.
+    ///
+    /// Adaptors that do not keep the receive buffers by themselves return an error.
+    fn create_srq(&self, _srqn: u32) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support srq".to_owned(),
+        ))
+    }
+
+    /// Destroy the shared receive queue `srqn`, with the receive buffers posted to it.
+    fn destroy_srq(&self, _srqn: u32) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support srq".to_owned(),
+        ))
+    }
+
+    /// Let the qp `qpn` draw its receive buffers from the shared receive queue `srqn`.
+    fn attach_srq(&self, _qpn: Qpn, _srqn: u32) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support srq".to_owned(),
+        ))
+    }
+
+    /// Post a receive buffer made of `sges` to the shared receive queue `srqn`.
+    fn post_srq_recv(&self, _srqn: u32, _sges: &[Sge]) -> Result<(), DeviceError> {
+        Err(DeviceError::Device(
+            "the adaptor does not support srq".to_owned(),
+        ))
+    }
+
+    /// Stop the threads of the adaptor and wait for them to exit. Shutting down twice is a no-op.
+    fn shutdown(&self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /// Whether the adaptor can translate the addresses of a MR with pages of `pg_size` bytes.
+    ///
+    /// The MR table descriptor does not carry the page size, so the card only knows `PAGE_SIZE`.
+    fn is_page_size_supported(&self, pg_size: u32) -> bool {
+        usize::try_from(pg_size).is_ok_and(|size| size == PAGE_SIZE)
+    }
 }
 
 /// Generic interface for a to-card ring buffer.
@@ -38,7 +193,9 @@ pub(crate) trait ToCardRb<D> {
 
 /// Generic interface for a to-host ring buffer.
 pub(crate) trait ToHostRb<D> {
-    fn pop(&self) -> Result<D, DeviceError>;
+    /// Pop a descriptor, or `None` if the card has not produced one within `timeout`. It never
+    /// blocks longer, so the pollers can check their stop flags between the pops.
+    fn pop(&self, timeout: Duration) -> Result<Option<D>, DeviceError>;
 }
 
 /// An error indicating that a ring buffer overflowed.
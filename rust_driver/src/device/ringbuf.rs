@@ -1,10 +1,27 @@
-use std::sync::{Mutex, MutexGuard};
+use std::{
+    sync::{
+        atomic::{fence, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
 
 use log::debug;
 
 use crate::HugePage;
 
-use super::DeviceError;
+#[cfg(feature = "scheduler")]
+use super::scheduler::ToCardWorkRing;
+use super::{DeviceError, ToCardWorkRbDesc};
+
+/// The name of the ring buffer passing the control descriptors to the card
+pub(super) const TO_CARD_CTRL_RING: &str = "to card ctrl ring";
+/// The name of the ring buffer passing the work descriptors to the card
+pub(super) const TO_CARD_WORK_RING: &str = "to card work ring";
+
+/// The first interval to poll the head of a to-host ring again, which doubles up to the max one
+const PENDING_POLL_MIN_BACKOFF: Duration = Duration::from_micros(1);
+const PENDING_POLL_MAX_BACKOFF: Duration = Duration::from_micros(100);
 
 pub(super) trait CsrWriterProxy {
     fn write_head(&self, data: u32) -> Result<(), DeviceError>;
@@ -17,6 +34,10 @@ pub(super) trait CsrReaderProxy {
 }
 
 /// The Ringbuf is a circular buffer used comunicate between the host and the card.
+///
+/// The writer fences the descriptors before it moves the head, and the reader fences the head
+/// before it reads the descriptors, so that the other side never sees a half-written descriptor on
+/// a weakly-ordered system.
 #[derive(Debug)]
 pub(super) struct Ringbuf<T, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize> {
     buf: Mutex<HugePage>,
@@ -109,6 +130,11 @@ impl<T, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize>
         head == tail
     }
 
+    /// The number of descriptors the ring buffer can take before it's full
+    pub(crate) fn free_slots(head: usize, tail: usize) -> usize {
+        DEPTH.saturating_sub(head.wrapping_sub(tail) & Self::PTR_IDX_MASK)
+    }
+
     #[allow(clippy::arithmetic_side_effects)]
     pub(crate) fn wrapping_add(cur: usize, cnt: usize) -> usize {
         (cur + cnt) & Self::PTR_IDX_MASK
@@ -133,11 +159,110 @@ impl<T: CsrWriterProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_S
             proxy: &self.proxy,
         })
     }
+
+    /// Get space for writing `desc_cnt` descriptors to the ring buffer without blocking.
+    ///
+    /// The tail is read from the card again if the known free space is not enough. If the ring buffer
+    /// still can't take all the descriptors, `DeviceError::RingFull` is returned with the name `ring`,
+    /// so that the caller can back off instead of waiting for the card.
+    pub(super) fn try_write(
+        &mut self,
+        desc_cnt: usize,
+        ring: &str,
+    ) -> Result<RingbufWriter<'_, '_, T, DEPTH, ELEM_SIZE, PAGE_SIZE>, DeviceError> {
+        if Self::free_slots(self.head, self.tail) < desc_cnt {
+            self.tail = usize::try_from(self.proxy.read_tail()?)
+                .map_err(|_| DeviceError::Device("invalid tail pointer".to_owned()))?;
+            if Self::free_slots(self.head, self.tail) < desc_cnt {
+                return Err(DeviceError::RingFull(ring.to_owned()));
+            }
+        }
+        self.write()
+    }
+
+    /// Write the work descriptor `desc` to the ring, and return the position in the ring after it.
+    pub(super) fn push_work_desc(&mut self, desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError> {
+        debug!("driver send to card SQ: {:?}", &desc);
+        let desc_cnt = desc.serialized_desc_cnt();
+        let mut writer =
+            self.try_write(desc_cnt.try_into().unwrap_or(usize::MAX), TO_CARD_WORK_RING)?;
+        desc.write_0(writer.next().ok_or(DeviceError::Overflow)?);
+        desc.write_1(writer.next().ok_or(DeviceError::Overflow)?);
+        desc.write_2(writer.next().ok_or(DeviceError::Overflow)?);
+
+        if desc_cnt == 4 {
+            desc.write_3(writer.next().ok_or(DeviceError::Overflow)?);
+        }
+        // the head moves when the writer is dropped
+        drop(writer);
+        Ok(self.head)
+    }
+}
+
+#[cfg(feature = "scheduler")]
+impl<T: CsrWriterProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize>
+    ToCardWorkRing for Ringbuf<T, DEPTH, ELEM_SIZE, PAGE_SIZE>
+{
+    fn push(&mut self, desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError> {
+        self.push_work_desc(desc)
+    }
+
+    fn is_consumed(&mut self, pos: usize) -> Result<bool, DeviceError> {
+        // the number of descriptors written after `pos`, which the card has not read if it has not
+        // read the ones before `pos`
+        let written_after = self.head.wrapping_sub(pos) & Self::PTR_IDX_MASK;
+        if self.head.wrapping_sub(self.tail) & Self::PTR_IDX_MASK > written_after {
+            self.tail = usize::try_from(self.proxy.read_tail()?)
+                .map_err(|_| DeviceError::Device("invalid tail pointer".to_owned()))?;
+        }
+        Ok(self.head.wrapping_sub(self.tail) & Self::PTR_IDX_MASK <= written_after)
+    }
 }
 
 impl<T: CsrReaderProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize>
     Ringbuf<T, DEPTH, ELEM_SIZE, PAGE_SIZE>
 {
+    /// Whether the card has produced descriptors that are not read yet. The head is read from the
+    /// card again if all the known descriptors have been read, so it never blocks.
+    pub(super) fn has_pending(&mut self) -> Result<bool, DeviceError> {
+        if Self::is_empty(self.head, self.tail) {
+            self.head = usize::try_from(self.proxy.read_head()?)
+                .map_err(|_| DeviceError::Device("invalid head pointer".to_owned()))?;
+        }
+        Ok(!Self::is_empty(self.head, self.tail))
+    }
+
+    /// Wait at most `timeout` for the card to produce a descriptor in `ring`, and return the locked
+    /// ring if it does.
+    ///
+    /// The card raises no interrupt, so the head is polled until then, with a backoff growing from
+    /// `PENDING_POLL_MIN_BACKOFF` to `PENDING_POLL_MAX_BACKOFF`. The ring is unlocked between the
+    /// polls, so the other users of it are not blocked by the wait.
+    pub(super) fn wait_pending(
+        ring: &Mutex<Self>,
+        timeout: Duration,
+    ) -> Result<Option<MutexGuard<'_, Self>>, DeviceError> {
+        let start = Instant::now();
+        let mut backoff = PENDING_POLL_MIN_BACKOFF;
+        loop {
+            let mut guard = ring
+                .lock()
+                .map_err(|e| DeviceError::LockPoisoned(e.to_string()))?;
+            if guard.has_pending()? {
+                return Ok(Some(guard));
+            }
+            drop(guard);
+            let Some(remaining) = timeout.checked_sub(start.elapsed()) else {
+                return Ok(None);
+            };
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            std::thread::sleep(backoff.min(remaining));
+            backoff = backoff.saturating_mul(2).min(PENDING_POLL_MAX_BACKOFF);
+        }
+    }
+
     /// Prepare to read some descriptors from the ring buffer.
     pub(super) fn read(
         &mut self,
@@ -163,6 +288,8 @@ impl<T: CsrWriterProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_S
         let new_head =
             Ringbuf::<T, DEPTH, ELEM_SIZE, PAGE_SIZE>::wrapping_add(head, self.written_cnt);
         *self.head = new_head;
+        // the descriptors must be visible before the head telling the card to read them
+        fence(Ordering::Release);
         // since the head is got by wrapping_add, it's safe to cast to u32
         #[allow(clippy::cast_possible_truncation)]
         self.proxy.write_head(new_head as u32)?;
@@ -253,6 +380,8 @@ impl<'a, T: CsrReaderProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PA
                 }
             }
         }
+        // the descriptor must be read after the head telling it is written by the card
+        fence(Ordering::Acquire);
         let offset = idx * ELEM_SIZE;
         let ptr = unsafe { self.buf.as_ptr().add(offset) };
 
@@ -284,12 +413,13 @@ mod test {
     use std::{
         sync::{
             atomic::{AtomicBool, AtomicU32, Ordering},
-            Arc,
+            Arc, Mutex,
         },
         thread::{sleep, spawn},
+        time::Duration,
     };
 
-    use crate::device::DeviceError;
+    use crate::{device::DeviceError, HugePage};
 
     use super::Ringbuf;
 
@@ -337,6 +467,48 @@ mod test {
             Ok(self.0.head.load(Ordering::Acquire))
         }
     }
+    #[test]
+    fn test_ringbuf_free_slots() {
+        type Rb = Ringbuf<Proxy, 128, 32, 4096>;
+        assert_eq!(Rb::free_slots(0, 0), 128);
+        assert_eq!(Rb::free_slots(100, 0), 28);
+        assert_eq!(Rb::free_slots(128, 0), 0);
+        // the head has wrapped around while the tail has not
+        assert_eq!(Rb::free_slots(3, 200), 69);
+        assert_eq!(Rb::free_slots(72, 200), 0);
+    }
+
+    #[test]
+    fn test_ringbuf_wait_pending() {
+        let proxy = Proxy(Arc::new(ProxyInner {
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+        }));
+        // the ring is built on the fallback memory, since no descriptor is read
+        let ring = Arc::new(Mutex::new(Ringbuf::<Proxy, 128, 32, 4096> {
+            buf: Mutex::new(HugePage::new_or_fallback(128 * 32).unwrap()),
+            head: 0,
+            tail: 0,
+            proxy: proxy.clone(),
+        }));
+        assert!(Ringbuf::wait_pending(&ring, Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        let waiter_ring = Arc::clone(&ring);
+        let waiter = spawn(move || {
+            Ringbuf::wait_pending(&waiter_ring, Duration::from_secs(10))
+                .unwrap()
+                .is_some()
+        });
+        sleep(Duration::from_millis(5));
+        // the ring is not held by the waiting thread
+        drop(ring.lock().unwrap());
+        assert!(!waiter.is_finished());
+        proxy.produce::<128>(1);
+        assert!(waiter.join().unwrap());
+    }
+
     #[test]
     fn test_ringbuf_writer() {
         let proxy = Proxy(Arc::new(ProxyInner {
@@ -409,3 +581,58 @@ mod test {
         checker.join().unwrap();
     }
 }
+
+/// A model of the fences between the writer and the reader of the ring buffer, checked by loom.
+///
+/// Run it with `cargo test --release --features loom loom_`.
+#[cfg(test)]
+#[cfg(feature = "loom")]
+mod loom_test {
+    use loom::{
+        cell::UnsafeCell,
+        sync::{
+            atomic::{fence, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    const DEPTH: usize = 2;
+
+    /// The descriptors of two words, one of them is torn if its words differ. The head is written
+    /// like a CSR, which carries no ordering by itself.
+    struct Ring {
+        descs: [[UnsafeCell<usize>; 2]; DEPTH],
+        head: AtomicUsize,
+    }
+
+    #[test]
+    fn loom_ring_no_torn_desc() {
+        loom::model(|| {
+            let ring = Arc::new(Ring {
+                descs: std::array::from_fn(|_| [UnsafeCell::new(0), UnsafeCell::new(0)]),
+                head: AtomicUsize::new(0),
+            });
+            let writer_ring = Arc::clone(&ring);
+            let writer = thread::spawn(move || {
+                for (i, desc) in writer_ring.descs.iter().enumerate() {
+                    for word in desc {
+                        word.with_mut(|word| unsafe { *word = i + 1 });
+                    }
+                    fence(Ordering::Release);
+                    writer_ring.head.store(i + 1, Ordering::Relaxed);
+                }
+            });
+
+            let head = ring.head.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            for (i, desc) in ring.descs.iter().take(head).enumerate() {
+                for word in desc {
+                    let word = word.with(|word| unsafe { *word });
+                    assert_eq!(word, i + 1, "torn descriptor {i}");
+                }
+            }
+            writer.join().unwrap();
+        });
+    }
+}
@@ -1,39 +1,22 @@
-use std::{collections::LinkedList, net::Ipv4Addr};
-
-use eui48::MacAddress;
+//! Descriptors and strategies for the scheduler benchmarks, enabled by the `bench` feature
+use std::{collections::LinkedList, sync::Arc};
 
 use crate::device::{
-    ToCardCtrlRbDescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon, ToCardWorkRbDescWrite,
+    scheduler::{round_robin::RoundRobinStrategy, SchedulerStrategy},
+    ToCardWorkRbDesc, ToCardWorkRbDescWriteBuilder,
 };
-use crate::types::{Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, Qpn};
+use crate::types::{Key, Psn, Qpn};
 
-#[allow(unused)]
+/// Generate `num` single packet writes of 512 bytes to the QP `qpn`
+#[allow(clippy::linkedlist)]
+#[must_use]
 pub fn generate_random_descriptors(qpn: u32, num: usize) -> LinkedList<ToCardWorkRbDesc> {
-    let desc = ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
-        common: ToCardWorkRbDescCommon {
-            total_len: 512,
-            raddr: 0x0,
-            rkey: Key::new(1234_u32),
-            dqp_ip: Ipv4Addr::new(127, 0, 0, 1),
-            dqpn: Qpn::new(qpn),
-            mac_addr: MacAddress::default(),
-            pmtu: Pmtu::Mtu1024,
-            flags: MemAccessTypeFlag::IbvAccessNoFlags,
-            qp_type: QpType::Rc,
-            psn: Psn::new(1234),
-            msn : Msn::new(0),
-        },
-        is_last: true,
-        is_first: true,
-        sge0: ToCardCtrlRbDescSge {
-            addr: 0x1000,
-            len: 512,
-            key: Key::new(0x1234_u32),
-        },
-        sge1: None,
-        sge2: None,
-        sge3: None,
-    });
+    let desc = ToCardWorkRbDescWriteBuilder::new()
+        .with_rkey(Key::new(1234_u32))
+        .with_dqpn(Qpn::new(qpn))
+        .with_psn(Psn::new(1234))
+        .with_sge(0x1000, 512, Key::new(0x1234_u32))
+        .build();
     let mut ret = LinkedList::new();
     for _ in 0..num {
         ret.push_back(desc.clone());
@@ -41,31 +24,19 @@ pub fn generate_random_descriptors(qpn: u32, num: usize) -> LinkedList<ToCardWor
     ret
 }
 
-#[allow(unused)]
+/// Generate a write of `size` bytes to the QP 4
+#[must_use]
 pub fn generate_big_descriptor(size: u32) -> ToCardWorkRbDesc {
-    ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
-        common: ToCardWorkRbDescCommon {
-            total_len: size,
-            raddr: 0x0,
-            rkey: Key::new(1234_u32),
-            dqp_ip: Ipv4Addr::new(127, 0, 0, 1),
-            dqpn: Qpn::new(4),
-            mac_addr: MacAddress::default(),
-            pmtu: Pmtu::Mtu1024,
-            flags: MemAccessTypeFlag::IbvAccessNoFlags,
-            qp_type: QpType::Rc,
-            psn: Psn::new(1234),
-            msn : Msn::new(0),
-        },
-        is_last: true,
-        is_first: true,
-        sge0: ToCardCtrlRbDescSge {
-            addr: 0x1000,
-            len: size,
-            key: Key::new(0x1234_u32),
-        },
-        sge1: None,
-        sge2: None,
-        sge3: None,
-    })
+    ToCardWorkRbDescWriteBuilder::new()
+        .with_rkey(Key::new(1234_u32))
+        .with_dqpn(Qpn::new(4))
+        .with_psn(Psn::new(1234))
+        .with_sge(0x1000, size, Key::new(0x1234_u32))
+        .build()
+}
+
+/// The round-robin strategy popping at most `max_burst` descriptors from a QP in a row
+#[must_use]
+pub fn round_robin_strategy(max_burst: usize) -> Arc<dyn SchedulerStrategy> {
+    Arc::new(RoundRobinStrategy::with_max_burst(max_burst))
 }
@@ -0,0 +1,30 @@
+/// The AETH credit value telling the sender that the receiver does not limit it with credits.
+pub(crate) const AETH_CREDIT_INVALID: u8 = 0b1_1111;
+
+/// The number of credits of every AETH credit value, except `AETH_CREDIT_INVALID`
+#[allow(clippy::decimal_literal_representation)]
+const AETH_CREDIT_COUNTS: [u32; 31] = [
+    0, 1, 2, 3, 4, 6, 8, 12, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048,
+    3072, 4096, 6144, 8192, 12288, 16384, 24576, 32768,
+];
+
+/// Decode the credit field of an ACK, which is the number of receive WQEs available on the receiver.
+///
+/// Returns `None` if the receiver does not limit the sender.
+pub(crate) fn decode_aeth_credit(value: u8) -> Option<u32> {
+    AETH_CREDIT_COUNTS.get(usize::from(value)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_aeth_credit, AETH_CREDIT_INVALID};
+
+    #[test]
+    fn test_decode_aeth_credit() {
+        assert_eq!(decode_aeth_credit(0), Some(0));
+        assert_eq!(decode_aeth_credit(4), Some(4));
+        assert_eq!(decode_aeth_credit(5), Some(6));
+        assert_eq!(decode_aeth_credit(30), Some(1 << 15));
+        assert_eq!(decode_aeth_credit(AETH_CREDIT_INVALID), None);
+    }
+}
@@ -3,26 +3,49 @@ use std::{
     fmt::Debug,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Condvar, Mutex, PoisonError,
     },
     thread::spawn,
 };
+#[cfg(feature = "scheduler")]
+use std::{collections::VecDeque, thread::sleep, time::Duration};
 
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
 use log::error;
 
 use super::{DeviceError, ToCardCtrlRbDescSge, ToCardRb, ToCardWorkRbDesc, ToCardWorkRbDescCommon};
+#[cfg(feature = "scheduler")]
+use super::ToCardWorkRbDescWrite;
 
+#[cfg(feature = "scheduler")]
+use crate::responser::Slot;
 use crate::{
+    trace::enter_span,
     types::{Pmtu, Psn, Qpn},
-    utils::get_first_packet_max_length,
+    utils::{calculate_packet_cnt, get_first_packet_max_length, PmtuFragments},
 };
 
 const SCHEDULER_SIZE_U32: u32 = 1024 * 32; // 32KB
 const SCHEDULER_SIZE: usize = SCHEDULER_SIZE_U32 as usize;
-const MAX_SGL_LENGTH: usize = 1;
-
+/// The max number of sges in a work descriptor
+const MAX_SGL_LENGTH: usize = 4;
+/// The first wait of `forward_to_card` before pushing to a full ring again
+#[cfg(feature = "scheduler")]
+const RING_FULL_MIN_BACKOFF: Duration = Duration::from_micros(10);
+/// The longest wait of `forward_to_card` before pushing to a full ring again
+#[cfg(feature = "scheduler")]
+const RING_FULL_MAX_BACKOFF: Duration = Duration::from_millis(1);
+/// The longest wait of `forward_to_card` for a new descriptor, to check the in-flight ack slots in
+/// between
+#[cfg(feature = "scheduler")]
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+pub(crate) mod credit;
 pub(crate) mod round_robin;
+pub(crate) mod sharded;
+/// descriptors and strategies for the scheduler benchmarks
+#[cfg(feature = "bench")]
+pub mod bench;
 
 /// A descriptor scheduler that cut descriptor into `SCHEDULER_SIZE` size and schedule with a strategy.
 #[derive(Debug)]
@@ -33,14 +56,59 @@ pub(crate) struct DescriptorScheduler {
     strategy: Arc<dyn SchedulerStrategy>,
     thread_handler: Option<std::thread::JoinHandle<()>>,
     stop_flag: Arc<AtomicBool>,
+    /// Notified when the strategy may have room again, i.e. a descriptor is popped or the scheduler stops
+    room: Arc<(Mutex<()>, Condvar)>,
+    /// The number of the times the strategy may have got a descriptor to pop, i.e. descriptors are
+    /// pushed to it or its credits are updated, notified on every change
+    ready: Arc<(Mutex<u64>, Condvar)>,
+    /// The name of the ring of the card which has no room for the popped descriptors, `None` if the
+    /// descriptors are forwarded to the card
+    full_ring: Mutex<Option<String>>,
 }
 
+/// The strategy deciding which descriptor is sent to the card next.
+///
+/// The scheduler splits every descriptor into `SCHEDULER_SIZE` pieces and pushes them to the strategy,
+/// then a sending thread keeps popping the descriptors from it.
+/// The descriptors of the same QP must be popped in the order they are pushed.
 #[allow(clippy::module_name_repetitions)]
-pub(crate) trait SchedulerStrategy: Send + Sync + Debug {
+pub trait SchedulerStrategy: Send + Sync + Debug {
+    /// Push the descriptors split from a work request of the QP `qpn`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the strategy failed to save the descriptors.
     #[allow(clippy::linkedlist)]
     fn push(&self, qpn: Qpn, desc: LinkedList<ToCardWorkRbDesc>) -> Result<(), DeviceError>;
 
+    /// Pop the next descriptor to send, or `None` if there is no pending descriptor.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the strategy failed to get a descriptor.
     fn pop(&self) -> Result<Option<ToCardWorkRbDesc>, DeviceError>;
+
+    /// The max number of descriptors popped from the same QP in a row before the other QPs get a turn.
+    fn max_burst(&self) -> usize {
+        1
+    }
+
+    /// Whether the strategy has queued too many descriptors to take more of the QP `qpn`. The scheduler
+    /// rejects the new work requests of the QP with `DeviceError::SchedulerFull` until some descriptors
+    /// are popped.
+    fn is_full(&self, _qpn: Qpn) -> bool {
+        false
+    }
+
+    /// Update the credits of the QP `qpn` advertised by the remote, or `None` if the remote does not
+    /// limit it. The descriptors consuming a receive WQE of the remote are held while the QP has no credit.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the strategy failed to save the credits.
+    fn update_credits(&self, _qpn: Qpn, _credits: Option<u32>) -> Result<(), DeviceError> {
+        Ok(())
+    }
 }
 
 struct SGList {
@@ -50,15 +118,51 @@ struct SGList {
 }
 
 impl SGList {
-    pub(crate) fn new_from_sge(sge: ToCardCtrlRbDescSge) -> Self {
-        let mut sge_list = Self {
-            data: [ToCardCtrlRbDescSge::default(); MAX_SGL_LENGTH],
-            cur_level: 0,
-            len: 1,
-        };
-        sge_list.data[0] = sge;
+    /// Create a sg list from the sges of a work descriptor. The optional sges are filled in order.
+    pub(crate) fn new_with_sge_list(
+        sge0: ToCardCtrlRbDescSge,
+        sge1: Option<ToCardCtrlRbDescSge>,
+        sge2: Option<ToCardCtrlRbDescSge>,
+        sge3: Option<ToCardCtrlRbDescSge>,
+    ) -> Self {
+        let mut sge_list = Self::default();
+        for sge in [Some(sge0), sge1, sge2, sge3].into_iter().flatten() {
+            // there are at most `MAX_SGL_LENGTH` sges
+            #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+            {
+                sge_list.data[sge_list.len as usize] = sge;
+                sge_list.len += 1;
+            }
+        }
         sge_list
     }
+
+    /// The total length of the sges which have not been cut
+    fn remain_length(&self) -> u32 {
+        self.data
+            .iter()
+            .take(self.len as usize)
+            .skip(self.cur_level as usize)
+            .fold(0, |acc, sge| acc.wrapping_add(sge.len))
+    }
+
+    /// Convert the sg list to the sges of a work descriptor
+    #[allow(clippy::indexing_slicing)] // `data` has `MAX_SGL_LENGTH` elements
+    fn into_four_sges(
+        self,
+    ) -> (
+        ToCardCtrlRbDescSge,
+        Option<ToCardCtrlRbDescSge>,
+        Option<ToCardCtrlRbDescSge>,
+        Option<ToCardCtrlRbDescSge>,
+    ) {
+        (
+            self.data[0],
+            (self.len > 1).then_some(self.data[1]),
+            (self.len > 2).then_some(self.data[2]),
+            (self.len > 3).then_some(self.data[3]),
+        )
+    }
 }
 
 impl Default for SGList {
@@ -78,6 +182,10 @@ impl DescriptorScheduler {
         let thread_receiver = receiver.clone();
         let stop_flag = Arc::new(AtomicBool::new(false));
         let thread_stop_flag = Arc::clone(&stop_flag);
+        let room = Arc::new((Mutex::new(()), Condvar::new()));
+        let thread_room = Arc::clone(&room);
+        let ready = Arc::new((Mutex::new(0), Condvar::new()));
+        let thread_ready = Arc::clone(&ready);
         let thread_handler = spawn(move || {
             while !thread_stop_flag.load(Ordering::Relaxed) {
                 let desc = match thread_receiver.try_recv() {
@@ -87,10 +195,28 @@ impl DescriptorScheduler {
                 };
                 if let Some(desc) = desc {
                     let dqpn = get_to_card_desc_common(&desc).dqpn;
+                    enter_span!(
+                        "schedule",
+                        qpn = dqpn.get(),
+                        opcode = ?desc.opcode(),
+                        psn = desc.common().psn.get(),
+                        byte_len = desc.common().total_len
+                    );
                     let splited_descs = split_descriptor(desc);
+                    // the descriptor is accepted already, so wait for the strategy to have room for it
+                    let (lock, popped) = &*thread_room;
+                    let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                    drop(
+                        popped
+                            .wait_while(guard, |()| {
+                                strategy.is_full(dqpn) && !thread_stop_flag.load(Ordering::Relaxed)
+                            })
+                            .unwrap_or_else(PoisonError::into_inner),
+                    );
                     if let Err(e) = strategy.push(dqpn, splited_descs) {
                         error!("failed to push descriptors: {:?}", e);
                     }
+                    notify_ready(&thread_ready);
                 }
             }
         });
@@ -100,17 +226,168 @@ impl DescriptorScheduler {
             thread_handler : Some(thread_handler),
             receiver,
             stop_flag,
+            room,
+            ready,
+            full_ring: Mutex::new(None),
         }
     }
 
     pub(crate) fn pop(self: &Arc<Self>) -> Result<Option<ToCardWorkRbDesc>, DeviceError> {
-        self.strategy.pop()
+        let desc = self.strategy.pop()?;
+        if desc.is_some() {
+            self.notify_room();
+        }
+        Ok(desc)
+    }
+
+    /// Wake up the scheduling thread waiting for the strategy to have room
+    fn notify_room(&self) {
+        let (lock, popped) = &*self.room;
+        // take the lock so that the notification can't slip in between the check and the wait
+        drop(lock.lock().unwrap_or_else(PoisonError::into_inner));
+        popped.notify_all();
+    }
+
+    /// Mark the ring `ring` of the card full, or `None` once it takes the descriptors again. The new
+    /// work requests are rejected with `DeviceError::RingFull` while it's full.
+    #[cfg(feature = "scheduler")]
+    fn set_full_ring(&self, ring: Option<String>) {
+        *self.full_ring.lock().unwrap_or_else(PoisonError::into_inner) = ring;
+    }
+
+    /// The number of the times the strategy may have got a descriptor to pop, to wait for the next
+    /// one with `wait_ready`
+    #[cfg(feature = "scheduler")]
+    fn ready_count(&self) -> u64 {
+        *self.ready.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait up to `timeout` for the strategy to get a descriptor to pop after `seen`, the
+    /// `ready_count` taken before the strategy is found empty.
+    #[cfg(feature = "scheduler")]
+    fn wait_ready(&self, seen: u64, timeout: Duration) {
+        let (lock, ready) = &*self.ready;
+        let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(
+            ready
+                .wait_timeout_while(guard, timeout, |count| *count == seen)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+
+    /// Update the credits of the QP `qpn`, see `SchedulerStrategy::update_credits`.
+    pub(crate) fn update_credits(&self, qpn: Qpn, credits: Option<u32>) -> Result<(), DeviceError> {
+        self.strategy.update_credits(qpn, credits)?;
+        notify_ready(&self.ready);
+        Ok(())
+    }
+}
+
+/// Count a change which may give the strategy a descriptor to pop, and wake up `forward_to_card`.
+fn notify_ready(ready: &(Mutex<u64>, Condvar)) {
+    let (lock, cond) = ready;
+    let mut count = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    *count = count.wrapping_add(1);
+    drop(count);
+    cond.notify_all();
+}
+
+/// The ring of the card which `forward_to_card` writes the work descriptors to
+#[cfg(feature = "scheduler")]
+pub(crate) trait ToCardWorkRing {
+    /// Write `desc` to the ring, and return the position in the ring after it.
+    fn push(&mut self, desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError>;
+
+    /// Whether the card has read all the descriptors before the position `pos`.
+    fn is_consumed(&mut self, pos: usize) -> Result<bool, DeviceError>;
+}
+
+/// Pass the descriptors popped from `scheduler` to the card through `ring`, forever.
+///
+/// A descriptor which the ring has no room for is pushed again once the card consumes some descriptors,
+/// so it is never dropped, and the descriptors after it wait behind it. Until then the scheduler rejects
+/// the new work requests with `DeviceError::RingFull`, so the callers back off instead of queueing more
+/// descriptors behind the full ring, and the ring is retried with a backoff up to
+/// `RING_FULL_MAX_BACKOFF`.
+///
+/// The acknowledge buffer slot of an ack is kept until the card reads the descriptor, which is checked
+/// before every push. While the scheduler has nothing to pop, the thread sleeps until a descriptor
+/// comes, waking up every `IDLE_WAIT` to check the slots.
+#[cfg(feature = "scheduler")]
+pub(crate) fn forward_to_card<R: ToCardWorkRing>(scheduler: &Arc<DescriptorScheduler>, ring: &mut R) -> ! {
+    let mut pending = None;
+    let mut in_flight_slots = VecDeque::new();
+    let mut backoff: Option<Duration> = None;
+    loop {
+        release_ack_slots(ring, &mut in_flight_slots);
+        let desc = if let Some(desc) = pending.take() {
+            desc
+        } else {
+            let seen = scheduler.ready_count();
+            match scheduler.pop() {
+                Ok(Some(desc)) => desc,
+                Ok(None) => {
+                    scheduler.wait_ready(seen, IDLE_WAIT);
+                    continue;
+                }
+                Err(e) => {
+                    error!("scheduler pop failed: {e}");
+                    scheduler.wait_ready(seen, IDLE_WAIT);
+                    continue;
+                }
+            }
+        };
+        match ring.push(&desc) {
+            Ok(pos) => {
+                if backoff.take().is_some() {
+                    scheduler.set_full_ring(None);
+                }
+                if let ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+                    ack_slot: Some(slot),
+                    ..
+                }) = desc
+                {
+                    in_flight_slots.push_back((pos, slot));
+                }
+            }
+            Err(DeviceError::RingFull(name)) => {
+                pending = Some(desc);
+                let wait = if let Some(wait) = backoff {
+                    RING_FULL_MAX_BACKOFF.min(wait.saturating_mul(2))
+                } else {
+                    scheduler.set_full_ring(Some(name));
+                    RING_FULL_MIN_BACKOFF
+                };
+                backoff = Some(wait);
+                sleep(wait);
+            }
+            Err(e) => error!("push to to_card_work_rb failed: {e}"),
+        }
+    }
+}
+
+/// Release the acknowledge buffer slots of the descriptors that the card has read, in the order they
+/// are written to `ring`.
+#[cfg(feature = "scheduler")]
+fn release_ack_slots<R: ToCardWorkRing>(ring: &mut R, slots: &mut VecDeque<(usize, Arc<Slot>)>) {
+    while let Some(&(pos, _)) = slots.front() {
+        match ring.is_consumed(pos) {
+            Ok(true) => {
+                let _: Option<(usize, Arc<Slot>)> = slots.pop_front();
+            }
+            Ok(false) => break,
+            Err(e) => {
+                error!("failed to read the tail of to_card_work_rb: {e}");
+                break;
+            }
+        }
     }
 }
 
 impl Drop for DescriptorScheduler {
     fn drop(&mut self) {
         self.stop_flag.store(true, Ordering::Relaxed);
+        self.notify_room();
         if let Some(thread) =  self.thread_handler.take(){
             if let Err(e) = thread.join(){
                 error!("Failed to join the WorkDescPoller thread: {:?}", e);
@@ -121,6 +398,12 @@ impl Drop for DescriptorScheduler {
 
 impl ToCardRb<ToCardWorkRbDesc> for DescriptorScheduler {
     fn push(&self, desc: ToCardWorkRbDesc) -> Result<(), DeviceError> {
+        if let Some(ring) = &*self.full_ring.lock().unwrap_or_else(PoisonError::into_inner) {
+            return Err(DeviceError::RingFull(ring.clone()));
+        }
+        if self.strategy.is_full(desc.common().dqpn) {
+            return Err(DeviceError::SchedulerFull);
+        }
         self.sender
             .send(desc)
             .map_err(|e| DeviceError::Scheduler(e.to_string()))
@@ -137,11 +420,7 @@ fn get_first_schedule_segment_length(va: u64) -> u32 {
 }
 
 fn get_to_card_desc_common(desc: &ToCardWorkRbDesc) -> &ToCardWorkRbDescCommon {
-    match desc {
-        ToCardWorkRbDesc::Read(req) => &req.common,
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => &req.common,
-        ToCardWorkRbDesc::WriteWithImm(req) => &req.common,
-    }
+    desc.common()
 }
 
 // We allow indexing_slicing because
@@ -189,50 +468,54 @@ fn cut_from_sgl(mut length: u32, origin_sgl: &mut SGList) -> SGList {
     unreachable!("The length is too long");
 }
 
-fn get_total_len(desc: &ToCardWorkRbDesc) -> u32 {
-    match desc {
-        ToCardWorkRbDesc::Read(req) => req.common.total_len,
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => req.common.total_len,
-        ToCardWorkRbDesc::WriteWithImm(req) => req.common.total_len,
-    }
-}
-
-/// Split the descriptor into multiple descriptors if it is greater than the `SCHEDULER_SIZE` size.
+/// Split the descriptor into multiple descriptors if its payload is greater than the `SCHEDULER_SIZE` size.
+///
+/// The payload is gathered from all the sges of the descriptor. The `total_len` can't be used here, because
+/// the first descriptor of a message chained by several descriptors carries the length of the whole message.
 #[allow(clippy::linkedlist)]
 pub(crate) fn split_descriptor(desc: ToCardWorkRbDesc) -> LinkedList<ToCardWorkRbDesc> {
-    let is_read = matches!(desc, ToCardWorkRbDesc::Read(_));
-    let total_len = get_total_len(&desc);
-    #[allow(clippy::cast_possible_truncation)]
-    if is_read || total_len < SCHEDULER_SIZE as u32 {
+    let Some(mut sg_list) = payload_sg_list(&desc).filter(|sgl| sgl.remain_length() >= SCHEDULER_SIZE_U32) else {
         let mut list = LinkedList::new();
         list.push_back(desc);
         return list;
-    }
+    };
+    let payload_len = sg_list.remain_length();
 
-    let (raddr, pmtu, psn, sge) = match &desc {
+    let (raddr, pmtu, psn, total_len, is_first, is_last) = match &desc {
         ToCardWorkRbDesc::Read(_) => unreachable!(),
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => {
-            (req.common.raddr, req.common.pmtu, req.common.psn, req.sge0)
-        }
-        ToCardWorkRbDesc::WriteWithImm(req) => {
-            (req.common.raddr, req.common.pmtu, req.common.psn, req.sge0)
-        }
+        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => (
+            req.common.raddr,
+            req.common.pmtu,
+            req.common.psn,
+            req.common.total_len,
+            req.is_first,
+            req.is_last,
+        ),
+        ToCardWorkRbDesc::WriteWithImm(req) => (
+            req.common.raddr,
+            req.common.pmtu,
+            req.common.psn,
+            req.common.total_len,
+            req.is_first,
+            req.is_last,
+        ),
     };
 
-    let mut sg_list = SGList::new_from_sge(sge);
-
     let mut descs = LinkedList::new();
-    let mut this_length = get_first_schedule_segment_length(raddr);
-    let mut remain_data_length = total_len;
+    let mut this_length = get_first_schedule_segment_length(raddr).min(payload_len);
+    let mut remain_data_length = payload_len;
     let mut current_va = raddr;
     let mut base_psn = psn;
     while remain_data_length > 0 {
         let mut new_desc = desc.clone();
-        let new_sge = cut_from_sgl(this_length, &mut sg_list).data[0];
+        let (sge0, sge1, sge2, sge3) = cut_from_sgl(this_length, &mut sg_list).into_four_sges();
         match &mut new_desc {
             ToCardWorkRbDesc::Read(_) => unreachable!(),
             ToCardWorkRbDesc::Write(ref mut req) | ToCardWorkRbDesc::ReadResp(ref mut req) => {
-                req.sge0 = new_sge;
+                req.sge0 = sge0;
+                req.sge1 = sge1;
+                req.sge2 = sge2;
+                req.sge3 = sge3;
                 req.common.total_len = this_length;
                 req.common.raddr = current_va;
                 req.common.psn = base_psn;
@@ -240,7 +523,10 @@ pub(crate) fn split_descriptor(desc: ToCardWorkRbDesc) -> LinkedList<ToCardWorkR
                 req.is_last = false;
             }
             ToCardWorkRbDesc::WriteWithImm(ref mut req) => {
-                req.sge0 = new_sge;
+                req.sge0 = sge0;
+                req.sge1 = sge1;
+                req.sge2 = sge2;
+                req.sge3 = sge3;
                 req.common.total_len = this_length;
                 req.common.raddr = current_va;
                 req.common.psn = base_psn;
@@ -258,16 +544,16 @@ pub(crate) fn split_descriptor(desc: ToCardWorkRbDesc) -> LinkedList<ToCardWorkR
             remain_data_length
         };
     }
-    // The above code guarantee there at least 2 descriptors in the list
+    // The first and the last descriptors inherit the position of the original descriptor in the message
     if let Some(req) = descs.front_mut() {
         match req {
             ToCardWorkRbDesc::Read(_) => unreachable!(),
             ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => {
-                req.is_first = true;
+                req.is_first = is_first;
                 req.common.total_len = total_len;
             }
             ToCardWorkRbDesc::WriteWithImm(req) => {
-                req.is_first = true;
+                req.is_first = is_first;
                 req.common.total_len = total_len;
             }
         }
@@ -277,10 +563,10 @@ pub(crate) fn split_descriptor(desc: ToCardWorkRbDesc) -> LinkedList<ToCardWorkR
         match req {
             ToCardWorkRbDesc::Read(_) => unreachable!(),
             ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => {
-                req.is_last = true;
+                req.is_last = is_last;
             }
             ToCardWorkRbDesc::WriteWithImm(req) => {
-                req.is_last = true;
+                req.is_last = is_last;
             }
         }
     }
@@ -288,6 +574,75 @@ pub(crate) fn split_descriptor(desc: ToCardWorkRbDesc) -> LinkedList<ToCardWorkR
     descs
 }
 
+/// The sges of a write or read response descriptor. A read request carries no payload.
+fn payload_sg_list(desc: &ToCardWorkRbDesc) -> Option<SGList> {
+    match desc {
+        ToCardWorkRbDesc::Read(_) => None,
+        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => Some(
+            SGList::new_with_sge_list(req.sge0, req.sge1, req.sge2, req.sge3),
+        ),
+        ToCardWorkRbDesc::WriteWithImm(req) => Some(SGList::new_with_sge_list(
+            req.sge0, req.sge1, req.sge2, req.sge3,
+        )),
+    }
+}
+
+/// The number of packets sent for a descriptor
+pub(crate) fn descriptor_packet_cnt(desc: &ToCardWorkRbDesc) -> u32 {
+    let common = desc.common();
+    payload_sg_list(desc).map_or(1, |sg_list| {
+        calculate_packet_cnt(common.pmtu, common.raddr, sg_list.remain_length())
+    })
+}
+
+/// Resume a write or read response descriptor from its `skip`th packet, for a retransmission whose
+/// packets before it have been received by the remote.
+///
+/// The resumed descriptor is never the first one of the message. Returns `None` for a read request, or
+/// if the descriptor has no more than `skip` packets.
+pub(crate) fn resume_descriptor(desc: &ToCardWorkRbDesc, skip: u32) -> Option<ToCardWorkRbDesc> {
+    if skip == 0 {
+        return Some(desc.clone());
+    }
+    let mut sg_list = payload_sg_list(desc)?;
+    let common = desc.common();
+    let payload_len = sg_list.remain_length();
+    let offset = PmtuFragments::new(common.raddr, payload_len, common.pmtu)
+        .nth(usize::try_from(skip).ok()?)?
+        .offset;
+    let _: SGList = cut_from_sgl(offset, &mut sg_list);
+    let remain_len = payload_len.wrapping_sub(offset);
+    let (sge0, sge1, sge2, sge3) = cut_from_sgl(remain_len, &mut sg_list).into_four_sges();
+    let raddr = common.raddr.wrapping_add(u64::from(offset));
+    let psn = common.psn.wrapping_add(skip);
+
+    let mut resumed = desc.clone();
+    match &mut resumed {
+        ToCardWorkRbDesc::Read(_) => return None,
+        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => {
+            req.sge0 = sge0;
+            req.sge1 = sge1;
+            req.sge2 = sge2;
+            req.sge3 = sge3;
+            req.common.total_len = remain_len;
+            req.common.raddr = raddr;
+            req.common.psn = psn;
+            req.is_first = false;
+        }
+        ToCardWorkRbDesc::WriteWithImm(req) => {
+            req.sge0 = sge0;
+            req.sge1 = sge1;
+            req.sge2 = sge2;
+            req.sge3 = sge3;
+            req.common.total_len = remain_len;
+            req.common.raddr = raddr;
+            req.common.psn = psn;
+            req.is_first = false;
+        }
+    }
+    Some(resumed)
+}
+
 /// Recalculate the PSN of the descriptor
 ///
 /// # Example
@@ -316,15 +671,15 @@ mod test {
 
     use eui48::MacAddress;
 
-    use crate::device::scheduler::SCHEDULER_SIZE;
+    use crate::device::scheduler::{SchedulerStrategy, SCHEDULER_SIZE};
     use crate::device::{
-        ToCardCtrlRbDescSge, ToCardRb, ToCardWorkRbDesc, ToCardWorkRbDescCommon,
-        ToCardWorkRbDescWrite,
+        DeviceError, ToCardCtrlRbDescSge, ToCardRb, ToCardWorkRbDesc,
+        ToCardWorkRbDescWriteBuilder,
     };
 
-    use crate::types::{Key, MemAccessTypeFlag, Msn, Psn, Qpn};
+    use crate::types::{Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, Qpn};
 
-    use super::{SGList, MAX_SGL_LENGTH};
+    use super::SGList;
 
     pub(crate) struct SGListBuilder {
         sg_list: Vec<ToCardCtrlRbDescSge>,
@@ -348,13 +703,6 @@ mod test {
                 sg_list.data[sg_list.len as usize] = *sge;
                 sg_list.len += 1;
             }
-            while sg_list.len < MAX_SGL_LENGTH.try_into().unwrap() {
-                sg_list.data[sg_list.len as usize] = ToCardCtrlRbDescSge {
-                    addr: 0,
-                    len: 0,
-                    key: Key::default(),
-                };
-            }
             sg_list
         }
     }
@@ -395,31 +743,13 @@ mod test {
         let length = 1024 * 36; // should cut into 3 segments: 29k - 32k, 32k - 64k, 64k-65k
         let strategy = super::round_robin::RoundRobinStrategy::new();
         let scheduler = Arc::new(super::DescriptorScheduler::new(Arc::new(strategy)));
-        let desc = ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
-            common: ToCardWorkRbDescCommon {
-                total_len: length,
-                raddr: va,
-                rkey: Key::default(),
-                dqp_ip: Ipv4Addr::LOCALHOST,
-                dqpn: Qpn::new(2),
-                mac_addr: MacAddress::default(),
-                pmtu: crate::types::Pmtu::Mtu4096,
-                flags: MemAccessTypeFlag::empty(),
-                qp_type: crate::types::QpType::Rc,
-                psn: Psn::new(0),
-                msn: Msn::new(0x27),
-            },
-            is_last: true,
-            is_first: true,
-            sge0: ToCardCtrlRbDescSge {
-                addr: 0,
-                len: length,
-                key: Key::new(3),
-            },
-            sge1: None,
-            sge2: None,
-            sge3: None,
-        });
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_raddr(va)
+            .with_dqpn(Qpn::new(2))
+            .with_pmtu(Pmtu::Mtu4096)
+            .with_msn(Msn::new(0x27))
+            .with_sge(0, length, Key::new(3))
+            .build();
         scheduler.push(desc).unwrap();
         // schedule the thread;
         sleep(std::time::Duration::from_millis(1));
@@ -460,4 +790,204 @@ mod test {
         assert!(!desc3.is_first);
         assert!(desc3.is_last);
     }
+
+    #[test]
+    fn test_scheduler_full() {
+        let strategy = Arc::new(super::round_robin::RoundRobinStrategy::with_limits(1, Some(1)));
+        let scheduler = Arc::new(super::DescriptorScheduler::new(Arc::<
+            super::round_robin::RoundRobinStrategy,
+        >::clone(&strategy)));
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_dqpn(Qpn::new(2))
+            .with_sge(0, 512, Key::new(3))
+            .build();
+        scheduler.push(desc.clone()).unwrap();
+        // wait for the scheduling thread to queue the descriptor
+        while !strategy.is_full(Qpn::new(2)) {
+            sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(matches!(
+            scheduler.push(desc.clone()),
+            Err(DeviceError::SchedulerFull)
+        ));
+
+        assert!(scheduler.pop().unwrap().is_some());
+        scheduler.push(desc).unwrap();
+    }
+
+    #[cfg(feature = "scheduler")]
+    #[test]
+    fn test_forward_to_full_ring() {
+        let scheduler = Arc::new(super::DescriptorScheduler::new(Arc::new(
+            super::round_robin::RoundRobinStrategy::new(),
+        )));
+        for psn in 0..3 {
+            let desc = ToCardWorkRbDescWriteBuilder::new()
+                .with_dqpn(Qpn::new(2))
+                .with_psn(Psn::new(psn))
+                .with_sge(0, 512, Key::new(3))
+                .build();
+            scheduler.push(desc).unwrap();
+        }
+        // the ring is full at every other push
+        struct HalfFullRing {
+            attempt: u32,
+            sender: crossbeam_channel::Sender<u32>,
+        }
+        impl super::ToCardWorkRing for HalfFullRing {
+            fn push(&mut self, desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError> {
+                self.attempt += 1;
+                if self.attempt % 2 == 1 {
+                    return Err(DeviceError::RingFull("ring".to_owned()));
+                }
+                self.sender.send(desc.common().psn.get()).unwrap();
+                Ok(0)
+            }
+
+            fn is_consumed(&mut self, _pos: usize) -> Result<bool, DeviceError> {
+                Ok(true)
+            }
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let forwarded = Arc::clone(&scheduler);
+        let _: std::thread::JoinHandle<_> = std::thread::spawn(move || {
+            super::forward_to_card(&forwarded, &mut HalfFullRing { attempt: 0, sender })
+        });
+        let timeout = std::time::Duration::from_secs(5);
+        let psns: Vec<_> = (0..3).map(|_| receiver.recv_timeout(timeout).unwrap()).collect();
+        assert_eq!(psns, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "scheduler")]
+    #[test]
+    fn test_forward_releases_ack_slots() {
+        use crate::responser::AcknowledgeBuffer;
+        use crate::HugePage;
+
+        let size = AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
+        let ack_buf = AcknowledgeBuffer::new_with_buf(
+            HugePage::new_or_fallback(size).unwrap(),
+            size,
+            Key::new(0),
+        );
+        let scheduler = Arc::new(super::DescriptorScheduler::new(Arc::new(
+            super::round_robin::RoundRobinStrategy::new(),
+        )));
+        // the ack holding the only slot of the buffer is followed by other descriptors
+        for psn in 0..3 {
+            let mut desc = ToCardWorkRbDescWriteBuilder::new()
+                .with_dqpn(Qpn::new(2))
+                .with_psn(Psn::new(psn))
+                .with_sge(0, 512, Key::new(3))
+                .build();
+            if let ToCardWorkRbDesc::Write(write) = &mut desc {
+                write.ack_slot = (psn == 0).then(|| Arc::new(ack_buf.acquire_slot().unwrap()));
+            }
+            scheduler.push(desc).unwrap();
+        }
+        // the card reads every descriptor at once, and the ring reports whether the slot is free
+        // when the next one comes
+        struct ReadAtOnceRing {
+            ack_buf: Arc<AcknowledgeBuffer>,
+            sender: crossbeam_channel::Sender<bool>,
+        }
+        impl super::ToCardWorkRing for ReadAtOnceRing {
+            fn push(&mut self, _desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError> {
+                self.sender.send(self.ack_buf.try_acquire_slot().is_some()).unwrap();
+                Ok(0)
+            }
+
+            fn is_consumed(&mut self, _pos: usize) -> Result<bool, DeviceError> {
+                Ok(true)
+            }
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let ring = ReadAtOnceRing {
+            ack_buf: Arc::clone(&ack_buf),
+            sender,
+        };
+        let forwarded = Arc::clone(&scheduler);
+        let _: std::thread::JoinHandle<_> = std::thread::spawn(move || {
+            let mut ring = ring;
+            super::forward_to_card(&forwarded, &mut ring)
+        });
+        let timeout = std::time::Duration::from_secs(5);
+        let free: Vec<_> = (0..3).map(|_| receiver.recv_timeout(timeout).unwrap()).collect();
+        assert_eq!(free, [false, true, true]);
+    }
+
+    #[test]
+    fn test_resume_descriptor() {
+        // 4 packets of 1024 bytes, from two sges of 1536 and 2560 bytes
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_raddr(0x1000)
+            .with_dqpn(Qpn::new(2))
+            .with_psn(Psn::new(10))
+            .with_msn(Msn::new(0x27))
+            .with_sge(0x8000, 1536, Key::new(3))
+            .with_sge(0x9000, 2560, Key::new(3))
+            .build();
+        assert_eq!(super::descriptor_packet_cnt(&desc), 4);
+        assert!(super::resume_descriptor(&desc, 4).is_none());
+
+        let resumed = match super::resume_descriptor(&desc, 1).unwrap() {
+            ToCardWorkRbDesc::Write(req) => req,
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::WriteWithImm(_)
+            | ToCardWorkRbDesc::ReadResp(_) => unreachable!(),
+        };
+        assert_eq!(resumed.common.psn, Psn::new(11));
+        assert_eq!(resumed.common.raddr, 0x1400);
+        assert_eq!(resumed.common.total_len, 3072);
+        assert!(!resumed.is_first);
+        assert!(resumed.is_last);
+        assert_eq!((resumed.sge0.addr, resumed.sge0.len), (0x8400, 512));
+        let sge1 = resumed.sge1.unwrap();
+        assert_eq!((sge1.addr, sge1.len), (0x9000, 2560));
+        assert!(resumed.sge2.is_none());
+    }
+
+    #[test]
+    fn test_write_builder() {
+        let mac_addr = MacAddress::new([0x02, 0, 0, 0, 0, 0x01]);
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_raddr(0x2000)
+            .with_rkey(Key::new(7))
+            .with_dqp_ip(Ipv4Addr::new(10, 0, 0, 2))
+            .with_dqpn(Qpn::new(3))
+            .with_mac_addr(mac_addr)
+            .with_pmtu(Pmtu::Mtu2048)
+            .with_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .with_qp_type(QpType::Uc)
+            .with_psn(Psn::new(100))
+            .with_msn(Msn::new(5))
+            .with_sge(0x8000, 512, Key::new(1))
+            .with_sge(0x9000, 256, Key::new(1))
+            .build();
+        let common = super::get_to_card_desc_common(&desc);
+        assert_eq!(common.total_len, 768);
+        assert_eq!(common.raddr, 0x2000);
+        assert_eq!(common.rkey, Key::new(7));
+        assert_eq!(common.dqp_ip, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(common.dqpn, Qpn::new(3));
+        assert_eq!(common.mac_addr, mac_addr);
+        assert!(matches!(common.pmtu, Pmtu::Mtu2048));
+        assert_eq!(common.flags.bits(), MemAccessTypeFlag::IbvAccessRemoteWrite.bits());
+        assert!(matches!(common.qp_type, QpType::Uc));
+        assert_eq!(common.psn, Psn::new(100));
+        assert_eq!(common.msn, Msn::new(5));
+        let ToCardWorkRbDesc::Write(write) = desc else {
+            panic!("not a write descriptor");
+        };
+        assert!(write.is_first && write.is_last);
+        assert_eq!((write.sge0.addr, write.sge0.len), (0x8000, 512));
+        assert_eq!(write.sge1.map(|sge| sge.addr), Some(0x9000));
+        assert!(write.sge2.is_none() && write.sge3.is_none());
+
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_total_len(4096)
+            .with_position(false, true)
+            .build();
+        assert_eq!(super::get_to_card_desc_common(&desc).total_len, 4096);
+    }
 }
@@ -1,22 +1,59 @@
-use std::{collections::LinkedList, sync::Mutex};
+use std::{
+    collections::{HashMap, LinkedList},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
 
 use crate::device::{DeviceError, ToCardWorkRbDesc};
 
 use crate::types::Qpn;
 
-use super::SchedulerStrategy;
+use super::{get_to_card_desc_common, SchedulerStrategy};
 
 /// The round-robin strategy for the scheduler.
+///
+/// At most `max_burst` descriptors are popped from a QP in a row, then the strategy rotates to the next QP.
+/// With a `capacity`, the strategy is full once it queues `capacity` descriptors, and rejects the pushes
+/// until some of them are popped.
+/// A QP whose next descriptor needs a credit is skipped while it has no credit left.
+/// A pushed list with a descriptor of another QP is rejected, since it would break the order of both QPs.
 #[allow(clippy::module_name_repetitions, clippy::linkedlist)]
 #[derive(Debug)]
 pub(crate) struct RoundRobinStrategy {
     queue: Mutex<LinkedList<(u32, LinkedList<ToCardWorkRbDesc>)>>,
+    max_burst: usize,
+    /// The number of descriptors popped from the front QP in a row. It's only updated with the `queue` locked.
+    burst: AtomicUsize,
+    capacity: Option<usize>,
+    /// The number of queued descriptors. It's only updated with the `queue` locked.
+    len: AtomicUsize,
+    /// The credits of the QPs limited by the remote. It's only locked with the `queue` locked.
+    credits: Mutex<HashMap<u32, u32>>,
 }
 
 impl RoundRobinStrategy {
     pub(crate) fn new() -> Self {
+        Self::with_max_burst(1)
+    }
+
+    /// Create a strategy which pops at most `max_burst` descriptors from a QP before rotating to the next one.
+    /// A `max_burst` of 0 is treated as 1.
+    pub(crate) fn with_max_burst(max_burst: usize) -> Self {
+        Self::with_limits(max_burst, None)
+    }
+
+    /// Create a strategy like `with_max_burst`, which queues at most `capacity` descriptors.
+    /// A `capacity` of `None` is unbounded.
+    pub(crate) fn with_limits(max_burst: usize, capacity: Option<usize>) -> Self {
         Self {
             queue: Mutex::new(LinkedList::new()),
+            max_burst: max_burst.max(1),
+            burst: AtomicUsize::new(0),
+            capacity,
+            len: AtomicUsize::new(0),
+            credits: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -33,23 +70,35 @@ unsafe impl Sync for RoundRobinStrategy {}
 
 impl SchedulerStrategy for RoundRobinStrategy {
     fn push(&self, qpn: Qpn, desc: LinkedList<ToCardWorkRbDesc>) -> Result<(), DeviceError> {
-        for i in self
+        // a descriptor of another QP would be sent in the order of `qpn`, so it's rejected as a whole
+        if let Some(other) = desc
+            .iter()
+            .map(|d| get_to_card_desc_common(d).dqpn)
+            .find(|dqpn| *dqpn != qpn)
+        {
+            return Err(DeviceError::Scheduler(format!(
+                "a descriptor of qpn {} is pushed to qpn {}",
+                other.get(),
+                qpn.get()
+            )));
+        }
+        let mut guard = self
             .queue
             .lock()
-            .map_err(|_| DeviceError::LockPoisoned("scheduler queue lock".to_owned()))?
-            .iter_mut()
-        {
+            .map_err(|_| DeviceError::LockPoisoned("scheduler queue lock".to_owned()))?;
+        if self.is_full(qpn) {
+            return Err(DeviceError::SchedulerFull);
+        }
+        let _: usize = self.len.fetch_add(desc.len(), Ordering::Relaxed);
+
+        for i in guard.iter_mut() {
             // merge the descriptor if the qpn is already in the queue
             if i.0 == qpn.get() {
                 i.1.extend(desc);
                 return Ok(());
             }
         }
-
-        self.queue
-            .lock()
-            .map_err(|_| DeviceError::LockPoisoned("scheduler queue lock".to_owned()))?
-            .push_back((qpn.get(), desc));
+        guard.push_back((qpn.get(), desc));
         Ok(())
     }
 
@@ -59,15 +108,55 @@ impl SchedulerStrategy for RoundRobinStrategy {
             .queue
             .lock()
             .map_err(|_| DeviceError::LockPoisoned("scheduler queue lock".to_owned()))?;
+        let mut credits = self
+            .credits
+            .lock()
+            .map_err(|_| DeviceError::LockPoisoned("scheduler credits lock".to_owned()))?;
+
+        // skip the QPs waiting for credits
+        let is_ready = |(qpn, list): &(u32, LinkedList<ToCardWorkRbDesc>)| {
+            list.front().is_some_and(|desc| {
+                !desc.consumes_recv_wqe()
+                    || match credits.get(qpn) {
+                        Some(credit) => *credit > 0,
+                        None => true,
+                    }
+            })
+        };
+        let Some(skipped) = guard.iter().position(is_ready) else {
+            return Ok(None);
+        };
+        for _ in 0..skipped {
+            self.burst.store(0, Ordering::Relaxed);
+            if let Some(waiting) = guard.pop_front() {
+                guard.push_back(waiting);
+            }
+        }
 
-        let desc = if let Some((_, list)) = guard.front_mut() {
+        let desc = if let Some((qpn, list)) = guard.front_mut() {
             // the front_mut is existed,so the pop_front will not return None
-            list.pop_front().unwrap()
+            let desc = list.pop_front().unwrap();
+            if desc.consumes_recv_wqe() {
+                if let Some(credit) = credits.get_mut(qpn) {
+                    *credit = credit.saturating_sub(1);
+                }
+            }
+            desc
         } else {
             // otherwise it will return None
             return Ok(None);
         };
+        let _: usize = self.len.fetch_sub(1, Ordering::Relaxed);
+
+        let burst = self.burst.load(Ordering::Relaxed).wrapping_add(1);
+        let is_drained = guard.front().is_some_and(|(_, list)| list.is_empty());
+        if burst < self.max_burst && !is_drained {
+            self.burst.store(burst, Ordering::Relaxed);
+            return Ok(Some(desc));
+        }
 
+        // rotate to the next QP
+        self.burst.store(0, Ordering::Relaxed);
         // the front_mut is existed,so the pop_front will not return None
         let (qpn, list) = guard.pop_front().unwrap();
         if !list.is_empty() {
@@ -75,53 +164,58 @@ impl SchedulerStrategy for RoundRobinStrategy {
         }
         Ok(Some(desc))
     }
+
+    fn max_burst(&self) -> usize {
+        self.max_burst
+    }
+
+    fn is_full(&self, _qpn: Qpn) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.len.load(Ordering::Relaxed) >= capacity)
+    }
+
+    fn update_credits(&self, qpn: Qpn, credits: Option<u32>) -> Result<(), DeviceError> {
+        let mut guard = self
+            .credits
+            .lock()
+            .map_err(|_| DeviceError::LockPoisoned("scheduler credits lock".to_owned()))?;
+        match credits {
+            Some(credits) => {
+                let _: Option<u32> = guard.insert(qpn.get(), credits);
+            }
+            None => {
+                let _: Option<u32> = guard.remove(&qpn.get());
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::LinkedList, net::Ipv4Addr};
-
-    use eui48::MacAddress;
+    use std::collections::LinkedList;
 
     use crate::{
         device::{
             scheduler::{
                 get_to_card_desc_common, round_robin::RoundRobinStrategy, SchedulerStrategy,
             },
-            ToCardCtrlRbDescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon, ToCardWorkRbDescWrite,
+            DeviceError, ToCardWorkRbDesc, ToCardWorkRbDescWriteBuilder,
+            ToCardWorkRbDescWriteWithImm,
         },
-        types::{Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, Qpn},
+        types::{Key, Psn, Qpn},
     };
 
     pub(crate) fn generate_random_descriptors(
         qpn: u32,
         num: usize,
     ) -> LinkedList<ToCardWorkRbDesc> {
-        let desc = ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
-            common: ToCardWorkRbDescCommon {
-                total_len: 512,
-                raddr: 0x0,
-                rkey: Key::new(1234_u32),
-                dqp_ip: Ipv4Addr::new(127, 0, 0, 1),
-                dqpn: Qpn::new(qpn),
-                mac_addr: MacAddress::default(),
-                pmtu: Pmtu::Mtu1024,
-                flags: MemAccessTypeFlag::IbvAccessNoFlags,
-                qp_type: QpType::Rc,
-                psn: Psn::new(1234),
-                msn: Msn::new(0),
-            },
-            is_last: true,
-            is_first: true,
-            sge0: ToCardCtrlRbDescSge {
-                addr: 0x1000,
-                len: 512,
-                key: Key::new(0x1234_u32),
-            },
-            sge1: None,
-            sge2: None,
-            sge3: None,
-        });
+        let desc = ToCardWorkRbDescWriteBuilder::new()
+            .with_rkey(Key::new(1234_u32))
+            .with_dqpn(Qpn::new(qpn))
+            .with_psn(Psn::new(1234))
+            .with_sge(0x1000, 512, Key::new(0x1234_u32))
+            .build();
         let mut ret = LinkedList::new();
         for _ in 0..num {
             ret.push_back(desc.clone());
@@ -163,4 +257,127 @@ mod tests {
             assert_eq!(item.get(), result_dqpn);
         }
     }
+
+    #[test]
+    fn test_round_robin_max_burst() {
+        let round_robin = RoundRobinStrategy::with_max_burst(2);
+        assert_eq!(round_robin.max_burst(), 2);
+        let qpn1 = Qpn::new(1);
+        let qpn2 = Qpn::new(2);
+        round_robin
+            .push(qpn1, generate_random_descriptors(1, 10))
+            .unwrap();
+        round_robin
+            .push(qpn2, generate_random_descriptors(2, 3))
+            .unwrap();
+        let result_dqpns = [1, 1, 2, 2, 1, 1, 2, 1, 1, 1, 1, 1, 1];
+        for result_dqpn in result_dqpns {
+            let desc = round_robin.pop().unwrap().unwrap();
+            let item = get_to_card_desc_common(&desc).dqpn;
+            assert_eq!(item.get(), result_dqpn);
+        }
+        assert!(round_robin.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_round_robin_capacity() {
+        let round_robin = RoundRobinStrategy::with_limits(1, Some(4));
+        let qpn1 = Qpn::new(1);
+        let qpn2 = Qpn::new(2);
+        round_robin
+            .push(qpn1, generate_random_descriptors(1, 2))
+            .unwrap();
+        assert!(!round_robin.is_full(qpn1));
+        round_robin
+            .push(qpn2, generate_random_descriptors(2, 2))
+            .unwrap();
+        assert!(round_robin.is_full(qpn1));
+        assert!(matches!(
+            round_robin.push(qpn1, generate_random_descriptors(1, 1)),
+            Err(DeviceError::SchedulerFull)
+        ));
+
+        let desc = round_robin.pop().unwrap().unwrap();
+        assert_eq!(get_to_card_desc_common(&desc).dqpn.get(), 1);
+        assert!(!round_robin.is_full(qpn1));
+        round_robin
+            .push(qpn1, generate_random_descriptors(1, 1))
+            .unwrap();
+        let result_dqpns = [2, 1, 2, 1];
+        for result_dqpn in result_dqpns {
+            let desc = round_robin.pop().unwrap().unwrap();
+            assert_eq!(get_to_card_desc_common(&desc).dqpn.get(), result_dqpn);
+        }
+        assert!(round_robin.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_round_robin_mismatched_qpn() {
+        let round_robin = RoundRobinStrategy::new();
+        let mut descs = generate_random_descriptors(1, 2);
+        descs.append(&mut generate_random_descriptors(2, 1));
+        assert!(matches!(
+            round_robin.push(Qpn::new(1), descs),
+            Err(DeviceError::Scheduler(_))
+        ));
+        // nothing of the rejected list is queued
+        assert!(round_robin.pop().unwrap().is_none());
+        round_robin
+            .push(Qpn::new(1), generate_random_descriptors(1, 1))
+            .unwrap();
+        assert!(round_robin.pop().unwrap().is_some());
+    }
+
+    /// A write with immediate data to `qpn`, which consumes a receive WQE of the remote
+    fn generate_write_with_imm(qpn: u32) -> LinkedList<ToCardWorkRbDesc> {
+        let ToCardWorkRbDesc::Write(write) = generate_random_descriptors(qpn, 1).pop_front().unwrap()
+        else {
+            unreachable!()
+        };
+        LinkedList::from([ToCardWorkRbDesc::WriteWithImm(ToCardWorkRbDescWriteWithImm {
+            common: write.common,
+            is_last: write.is_last,
+            is_first: write.is_first,
+            imm: 0x1234,
+            sge0: write.sge0,
+            sge1: write.sge1,
+            sge2: write.sge2,
+            sge3: write.sge3,
+        })])
+    }
+
+    #[test]
+    fn test_round_robin_credits() {
+        let round_robin = RoundRobinStrategy::new();
+        let qpn1 = Qpn::new(1);
+        let qpn2 = Qpn::new(2);
+        // the remote of qpn1 has a single receive WQE
+        round_robin.update_credits(qpn1, Some(1)).unwrap();
+        round_robin.push(qpn1, generate_write_with_imm(1)).unwrap();
+        round_robin.push(qpn1, generate_write_with_imm(1)).unwrap();
+        round_robin
+            .push(qpn2, generate_random_descriptors(2, 2))
+            .unwrap();
+
+        // the second write with immediate data is held, and the writes of qpn2 go on
+        let result_dqpns = [1, 2, 2];
+        for result_dqpn in result_dqpns {
+            let desc = round_robin.pop().unwrap().unwrap();
+            assert_eq!(get_to_card_desc_common(&desc).dqpn.get(), result_dqpn);
+        }
+        assert!(round_robin.pop().unwrap().is_none());
+
+        // the first one completes, and the ACK of it refreshes the credits
+        round_robin.update_credits(qpn1, Some(1)).unwrap();
+        let desc = round_robin.pop().unwrap().unwrap();
+        assert!(matches!(desc, ToCardWorkRbDesc::WriteWithImm(_)));
+        assert!(round_robin.pop().unwrap().is_none());
+
+        // the descriptor held for credits is released once the remote stops limiting the QP
+        round_robin.update_credits(qpn1, Some(0)).unwrap();
+        round_robin.push(qpn1, generate_write_with_imm(1)).unwrap();
+        assert!(round_robin.pop().unwrap().is_none());
+        round_robin.update_credits(qpn1, None).unwrap();
+        assert!(round_robin.pop().unwrap().is_some());
+    }
 }
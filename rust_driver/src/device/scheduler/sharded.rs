@@ -0,0 +1,254 @@
+use std::{
+    collections::LinkedList,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::device::{DeviceError, ToCardWorkRbDesc};
+
+use crate::types::Qpn;
+
+use super::{round_robin::RoundRobinStrategy, SchedulerStrategy};
+
+/// The multiplier of the Fibonacci hashing, which spreads the consecutive qpns over the shards
+const QPN_HASH_MULTIPLIER: u32 = 0x9e37_79b9;
+
+/// A strategy spreading the QPs over several inner strategies, the shards.
+///
+/// A QP is always hashed to the same shard, so its descriptors keep their order. Every shard is locked
+/// on its own, and the concurrent `pop`s start from different shards, so the workers popping at the same
+/// time drain the shards in parallel.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub struct ShardedScheduler {
+    shards: Vec<Arc<dyn SchedulerStrategy>>,
+    /// The shard `pop` starts looking from, rotated on every pop so that no shard starves
+    next: AtomicUsize,
+}
+
+impl ShardedScheduler {
+    /// Create a scheduler of `shard_cnt` shards, each of them created by `new_shard`.
+    /// A `shard_cnt` of 0 is treated as 1.
+    pub fn new<F: FnMut() -> Arc<dyn SchedulerStrategy>>(shard_cnt: usize, new_shard: F) -> Self {
+        Self {
+            shards: std::iter::repeat_with(new_shard)
+                .take(shard_cnt.max(1))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a scheduler of `shard_cnt` round-robin shards. A `shard_cnt` of 0 is treated as 1.
+    #[must_use]
+    pub fn round_robin(shard_cnt: usize) -> Self {
+        Self::new(shard_cnt, || Arc::new(RoundRobinStrategy::new()))
+    }
+
+    /// The number of shards
+    #[must_use]
+    pub fn shard_cnt(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The shard the descriptors of the QP `qpn` are pushed to.
+    ///
+    /// The high bits of the Fibonacci hash are the well mixed ones, so the hash is scaled to the shard
+    /// count instead of taking its remainder.
+    #[must_use]
+    pub fn shard_of(&self, qpn: Qpn) -> usize {
+        let hash = u64::from(qpn.get().wrapping_mul(QPN_HASH_MULTIPLIER));
+        let shard_cnt = u64::try_from(self.shards.len()).unwrap_or(u64::MAX);
+        // the product of a u32 and the shard count is shifted back under the shard count
+        usize::try_from(hash.saturating_mul(shard_cnt) >> u32::BITS).unwrap_or_default()
+    }
+
+    fn shard(&self, qpn: Qpn) -> Result<&Arc<dyn SchedulerStrategy>, DeviceError> {
+        self.shards
+            .get(self.shard_of(qpn))
+            .ok_or_else(|| DeviceError::Scheduler("the scheduler has no shard".to_owned()))
+    }
+}
+
+impl SchedulerStrategy for ShardedScheduler {
+    fn push(&self, qpn: Qpn, desc: LinkedList<ToCardWorkRbDesc>) -> Result<(), DeviceError> {
+        self.shard(qpn)?.push(qpn, desc)
+    }
+
+    fn pop(&self) -> Result<Option<ToCardWorkRbDesc>, DeviceError> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.shards.len() {
+            let index = start
+                .wrapping_add(offset)
+                .checked_rem(self.shards.len())
+                .unwrap_or_default();
+            let Some(shard) = self.shards.get(index) else {
+                continue;
+            };
+            if let Some(desc) = shard.pop()? {
+                return Ok(Some(desc));
+            }
+        }
+        Ok(None)
+    }
+
+    fn max_burst(&self) -> usize {
+        self.shards.first().map_or(1, |shard| shard.max_burst())
+    }
+
+    fn is_full(&self, qpn: Qpn) -> bool {
+        self.shard(qpn).is_ok_and(|shard| shard.is_full(qpn))
+    }
+
+    fn update_credits(&self, qpn: Qpn, credits: Option<u32>) -> Result<(), DeviceError> {
+        self.shard(qpn)?.update_credits(qpn, credits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::LinkedList,
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    use crate::{
+        device::{
+            scheduler::{
+                get_to_card_desc_common, round_robin::RoundRobinStrategy, SchedulerStrategy,
+            },
+            DeviceError, ToCardWorkRbDesc, ToCardWorkRbDescWriteBuilder,
+        },
+        types::{Key, Psn, Qpn},
+    };
+
+    use super::ShardedScheduler;
+
+    /// `cnt` writes to the QP `qpn`, with the psns from 0
+    fn descriptors(qpn: Qpn, cnt: u32) -> LinkedList<ToCardWorkRbDesc> {
+        (0..cnt)
+            .map(|psn| {
+                ToCardWorkRbDescWriteBuilder::new()
+                    .with_rkey(Key::new(1234_u32))
+                    .with_dqpn(qpn)
+                    .with_psn(Psn::new(psn))
+                    .with_sge(0x1000, 512, Key::new(0x1234_u32))
+                    .build()
+            })
+            .collect()
+    }
+
+    /// A round-robin strategy whose `pop` waits for the other shards to pop at the same time
+    #[derive(Debug)]
+    struct RendezvousStrategy {
+        inner: RoundRobinStrategy,
+        barrier: Arc<Barrier>,
+    }
+
+    impl SchedulerStrategy for RendezvousStrategy {
+        fn push(&self, qpn: Qpn, desc: LinkedList<ToCardWorkRbDesc>) -> Result<(), DeviceError> {
+            self.inner.push(qpn, desc)
+        }
+
+        fn pop(&self) -> Result<Option<ToCardWorkRbDesc>, DeviceError> {
+            let _ = self.barrier.wait();
+            self.inner.pop()
+        }
+    }
+
+    #[test]
+    fn test_sharded_scheduler() {
+        let scheduler = ShardedScheduler::round_robin(4);
+        assert_eq!(scheduler.shard_cnt(), 4);
+        assert_eq!(ShardedScheduler::round_robin(0).shard_cnt(), 1);
+
+        // the descriptors of a QP all land in its shard, in order
+        let qpn = Qpn::new(7);
+        let shard = scheduler.shard_of(qpn);
+        scheduler.push(qpn, descriptors(qpn, 3)).unwrap();
+        scheduler.push(qpn, descriptors(qpn, 2)).unwrap();
+        for other in (0..4).filter(|other| *other != shard) {
+            assert!(scheduler.shards[other].pop().unwrap().is_none());
+        }
+        let psns: Vec<_> = std::iter::from_fn(|| scheduler.shards[shard].pop().unwrap())
+            .map(|desc| get_to_card_desc_common(&desc).psn.get())
+            .collect();
+        assert_eq!(psns, [0, 1, 2, 0, 1]);
+
+        // the consecutive qpns are spread over all the shards
+        let mut used = [false; 4];
+        for qpn in 2..10 {
+            used[scheduler.shard_of(Qpn::new(qpn))] = true;
+        }
+        assert_eq!(used, [true; 4]);
+
+        // `pop` drains every shard
+        scheduler.push(Qpn::new(2), descriptors(Qpn::new(2), 2)).unwrap();
+        scheduler.push(Qpn::new(3), descriptors(Qpn::new(3), 2)).unwrap();
+        assert_eq!(std::iter::from_fn(|| scheduler.pop().unwrap()).count(), 4);
+    }
+
+    #[test]
+    fn test_sharded_scheduler_full_shard() {
+        let scheduler =
+            ShardedScheduler::new(2, || Arc::new(RoundRobinStrategy::with_limits(1, Some(2))));
+        let (full, other) = (Qpn::new(2), Qpn::new(3));
+        assert_ne!(scheduler.shard_of(full), scheduler.shard_of(other));
+        scheduler.push(full, descriptors(full, 2)).unwrap();
+
+        // only the QPs of the full shard are held back
+        assert!(scheduler.is_full(full));
+        assert!(matches!(
+            scheduler.push(full, descriptors(full, 1)),
+            Err(DeviceError::SchedulerFull)
+        ));
+        assert!(!scheduler.is_full(other));
+        scheduler.push(other, descriptors(other, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_scheduler_parallel() {
+        // each pop waits for a pop of the other shard, so the test only completes if the concurrent
+        // pops drain the shards at the same time
+        let barrier = Arc::new(Barrier::new(2));
+        let scheduler = Arc::new(ShardedScheduler::new(2, || {
+            Arc::new(RendezvousStrategy {
+                inner: RoundRobinStrategy::new(),
+                barrier: Arc::clone(&barrier),
+            })
+        }));
+        let qpns = [Qpn::new(2), Qpn::new(3)];
+        assert_ne!(scheduler.shard_of(qpns[0]), scheduler.shard_of(qpns[1]));
+        for qpn in qpns {
+            scheduler.push(qpn, descriptors(qpn, 4)).unwrap();
+        }
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let scheduler = Arc::clone(&scheduler);
+                thread::spawn(move || {
+                    (0..4)
+                        .map(|_| scheduler.pop().unwrap().unwrap())
+                        .map(|desc| {
+                            let common = get_to_card_desc_common(&desc);
+                            (common.dqpn.get(), common.psn.get())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut popped: Vec<_> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        // every descriptor is popped once, the order of a QP is kept by its shard
+        popped.sort_unstable();
+        let expected: Vec<_> = qpns
+            .iter()
+            .flat_map(|qpn| (0..4).map(|psn| (qpn.get(), psn)))
+            .collect();
+        assert_eq!(popped, expected);
+    }
+}
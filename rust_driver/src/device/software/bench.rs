@@ -0,0 +1,127 @@
+//! Sends for the net agent benchmarks, enabled by the `bench` feature
+use std::net::Ipv4Addr;
+
+use crate::{
+    device::{
+        software::{
+            net_agent::{udp_agent::UDPSendAgent, NetSendAgent},
+            types::{
+                Key, Metadata, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+                RdmaMessageMetaCommon, RethHeader,
+            },
+        },
+        ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
+    },
+    types::Psn,
+};
+
+const BENCH_PORT: u16 = 4791;
+
+/// The packets of a write, sent to the loopback address by a UDP agent
+#[derive(Debug)]
+pub struct WriteSender {
+    agent: UDPSendAgent,
+    messages: Vec<RdmaMessage>,
+    /// The payload of the messages points into it
+    _buf: Vec<u8>,
+    /// The payload of a packet is copied into it before sending, like the path before the sges
+    /// pointed into the MR
+    staging: Vec<u8>,
+}
+
+impl WriteSender {
+    /// Prepare a write of `len` bytes, cut into packets of `pmtu` bytes
+    ///
+    /// # Panics
+    /// Opening the raw socket requires `CAP_NET_RAW`, it panics without it.
+    #[must_use]
+    pub fn new(len: usize, pmtu: usize) -> Self {
+        let agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, BENCH_PORT)
+            .unwrap_or_else(|e| panic!("failed to open the raw socket: {e}"));
+        let buf = vec![0xa5_u8; len];
+        // the payload of each packet is a view into the buffer, like a write from a registered MR
+        let messages = (0..len)
+            .step_by(pmtu)
+            .enumerate()
+            .map(|(idx, offset)| {
+                let payload_len = pmtu.min(len.wrapping_sub(offset));
+                let data = buf.as_ptr().wrapping_add(offset);
+                (idx, PayloadInfo::new_with_data(data, payload_len))
+            })
+            .map(|(idx, payload)| RdmaMessage {
+                meta_data: Metadata::General(RdmaGeneralMeta {
+                    common_meta: RdmaMessageMetaCommon {
+                        tran_type: ToHostWorkRbDescTransType::Rc,
+                        opcode: ToHostWorkRbDescOpcode::RdmaWriteMiddle,
+                        solicited: false,
+                        pkey: PKey::new(0),
+                        dqpn: Qpn::new(3),
+                        ack_req: false,
+                        #[allow(clippy::cast_possible_truncation)]
+                        psn: Psn::new(idx as u32),
+                    },
+                    reth: RethHeader {
+                        va: 0,
+                        rkey: Key::new(0),
+                        #[allow(clippy::cast_possible_truncation)]
+                        len: len as u32,
+                    },
+                    imm: None,
+                    secondary_reth: None,
+                }),
+                payload,
+            })
+            .collect();
+        Self {
+            agent,
+            messages,
+            _buf: buf,
+            staging: vec![0; pmtu],
+        }
+    }
+
+    /// Send the packets, copying the payload into each frame
+    ///
+    /// # Panics
+    /// It panics if a send fails.
+    pub fn send_copied(&self) {
+        for message in &self.messages {
+            self.agent
+                .send(Ipv4Addr::LOCALHOST, BENCH_PORT, message)
+                .unwrap_or_else(|e| panic!("failed to send: {e}"));
+        }
+    }
+
+    /// Copy the payload of each packet into a staging buffer, then send it with the payload gathered
+    /// from the staging buffer by the kernel
+    ///
+    /// # Panics
+    /// It panics if a send fails.
+    pub fn send_staged(&mut self) {
+        for message in &self.messages {
+            message.payload.copy_to(self.staging.as_mut_ptr());
+            let staged = RdmaMessage {
+                meta_data: message.meta_data.clone(),
+                payload: PayloadInfo::new_with_data(
+                    self.staging.as_ptr(),
+                    message.payload.get_length(),
+                ),
+            };
+            self.agent
+                .send_vectored(Ipv4Addr::LOCALHOST, BENCH_PORT, &staged)
+                .unwrap_or_else(|e| panic!("failed to send: {e}"));
+        }
+    }
+
+    /// Send the packets, with the payload gathered by the kernel
+    ///
+    /// # Panics
+    /// It panics if a send fails.
+    pub fn send_vectored(&self) {
+        for message in &self.messages {
+            self.agent
+                .send_vectored(Ipv4Addr::LOCALHOST, BENCH_PORT, message)
+                .unwrap_or_else(|e| panic!("failed to send: {e}"));
+        }
+    }
+}
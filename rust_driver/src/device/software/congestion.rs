@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{logic::BlueRdmaLogicError, types::Qpn};
+
+/// The rate a QP sends at without congestion, in bytes per second. It is 100Gbps.
+pub(super) const LINE_RATE: u64 = 12_500_000_000;
+/// The max number of times the rate is halved, so the rate never goes below `LINE_RATE >> MAX_RATE_CUTS`.
+const MAX_RATE_CUTS: u32 = 10;
+/// The rate of a QP is doubled after every period without a CNP, until it reaches `LINE_RATE` again.
+const RATE_RECOVERY_PERIOD: Duration = Duration::from_millis(10);
+/// At most one CNP is sent to a QP in the interval.
+const CNP_INTERVAL: Duration = Duration::from_micros(50);
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The reduced rate of a QP that has received CNPs
+#[derive(Debug)]
+struct QpRate {
+    /// bytes per second
+    rate: u64,
+    /// when the rate was cut last time
+    cut_at: Instant,
+    /// the earliest time to send the next descriptor of the QP
+    next_send: Instant,
+}
+
+impl QpRate {
+    /// The rate recovered from the last cut
+    fn recovered(&self, now: Instant) -> u64 {
+        let periods = now
+            .duration_since(self.cut_at)
+            .as_nanos()
+            .checked_div(RATE_RECOVERY_PERIOD.as_nanos())
+            .unwrap_or(u128::MAX);
+        match u32::try_from(periods) {
+            Ok(periods) if periods < MAX_RATE_CUTS => {
+                self.rate.wrapping_shl(periods).min(LINE_RATE)
+            }
+            _ => LINE_RATE,
+        }
+    }
+}
+
+/// A simplified DCQCN congestion control of `RoCEv2`.
+///
+/// As the notification point, it decides whether to send a CNP for a packet marked with ECN-CE.
+/// As the reaction point, it halves the sending rate of a QP on every CNP and paces the descriptors of
+/// the QP at that rate, by telling when each of them can be sent. The rate recovers when no more CNP arrives.
+#[derive(Debug)]
+pub(crate) struct CongestionControl {
+    rates: Mutex<HashMap<Qpn, QpRate>>,
+    cnp_sent_at: Mutex<HashMap<Qpn, Instant>>,
+}
+
+impl CongestionControl {
+    pub(crate) fn new() -> Self {
+        Self {
+            rates: Mutex::new(HashMap::new()),
+            cnp_sent_at: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a CNP should be sent to `qpn` for a congested packet. It returns `true` at most once
+    /// in `CNP_INTERVAL` for the same QP.
+    pub(crate) fn should_notify(&self, qpn: Qpn) -> Result<bool, BlueRdmaLogicError> {
+        let mut cnp_sent_at = self.cnp_sent_at.lock()?;
+        let now = Instant::now();
+        match cnp_sent_at.get(&qpn) {
+            Some(sent_at) if now.duration_since(*sent_at) < CNP_INTERVAL => Ok(false),
+            Some(_) | None => {
+                let _: Option<Instant> = cnp_sent_at.insert(qpn, now);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Halve the sending rate of `qpn` on a received CNP.
+    pub(crate) fn on_cnp(&self, qpn: Qpn) -> Result<(), BlueRdmaLogicError> {
+        let mut rates = self.rates.lock()?;
+        let now = Instant::now();
+        let min_rate = LINE_RATE.wrapping_shr(MAX_RATE_CUTS);
+        let rate = rates.entry(qpn).or_insert(QpRate {
+            rate: LINE_RATE,
+            cut_at: now,
+            next_send: now,
+        });
+        rate.rate = rate.recovered(now).wrapping_shr(1).max(min_rate);
+        rate.cut_at = now;
+        log::debug!("CNP received, the rate of {qpn:?} is cut to {} B/s", rate.rate);
+        Ok(())
+    }
+
+    /// Reserve the time to send `len` bytes of `qpn` at the current rate of it, and return when they
+    /// can be sent. It is now for a QP without congestion.
+    pub(crate) fn pace(&self, qpn: Qpn, len: u32) -> Result<Instant, BlueRdmaLogicError> {
+        let mut rates = self.rates.lock()?;
+        let now = Instant::now();
+        let Some(rate) = rates.get_mut(&qpn) else {
+            return Ok(now);
+        };
+        let current = rate.recovered(now);
+        if current >= LINE_RATE {
+            let _: Option<QpRate> = rates.remove(&qpn);
+            return Ok(now);
+        }
+        let start = rate.next_send.max(now);
+        let cost = u64::from(len)
+            .wrapping_mul(NANOS_PER_SEC)
+            .checked_div(current)
+            .unwrap_or(0);
+        rate.next_send = start
+            .checked_add(Duration::from_nanos(cost))
+            .unwrap_or(start);
+        Ok(start)
+    }
+
+    /// The current sending rate of `qpn`, in bytes per second
+    #[cfg(test)]
+    pub(crate) fn rate(&self, qpn: Qpn) -> u64 {
+        self.rates
+            .lock()
+            .unwrap()
+            .get(&qpn)
+            .map_or(LINE_RATE, |rate| rate.recovered(Instant::now()))
+    }
+}
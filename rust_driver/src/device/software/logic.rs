@@ -2,31 +2,105 @@ use thiserror::Error;
 
 use crate::{
     device::{
+        scheduler::credit::{decode_aeth_credit, AETH_CREDIT_INVALID},
         ToCardCtrlRbDesc, ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostCtrlRbDescCommon,
         ToHostCtrlRbDescQpManagement, ToHostCtrlRbDescSetNetworkParam,
         ToHostCtrlRbDescSetRawPacketReceiveMeta, ToHostCtrlRbDescUpdateMrTable,
         ToHostCtrlRbDescUpdatePageTable, ToHostWorkRbDesc, ToHostWorkRbDescAck,
-        ToHostWorkRbDescAethCode, ToHostWorkRbDescCommon, ToHostWorkRbDescOpcode,
-        ToHostWorkRbDescRead, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType,
+        ToHostWorkRbDescAethCode, ToHostWorkRbDescCommon, ToHostWorkRbDescNack, ToHostWorkRbDescOpcode,
+        ToHostWorkRbDescRead, ToHostWorkRbDescRecv, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType,
         ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm,
     },
-    types::{MemAccessTypeFlag, Msn, Pmtu, Psn, QpType},
-    utils::get_first_packet_max_length,
+    path_mtu::PMTUS_DESCENDING,
+    trace::enter_span,
+    types::{MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, ReadOverlapPolicy},
+    utils::{calculate_packet_cnt, get_first_packet_max_length, PmtuFragments},
+    DEFAULT_RMDA_PORT,
 };
 
 use super::{
+    congestion::CongestionControl,
     net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
+    packet::RDMA_PAYLOAD_ALIGNMENT,
+    retransmission::{decode_rnr_timer, Retransmission, Sequence},
     types::{
-        Key, Metadata, PDHandle, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
-        RdmaMessageMetaCommon, RethHeader, ToCardDescriptor, ToCardReadDescriptor,
-        ToCardWriteDescriptor,
+        AethHeader, Key, Metadata, PDHandle, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+        RdmaMessageMetaCommon, RdmaSendMeta, RethHeader, SGList, SGListElementWithKey, ToCardDescriptor,
+        ToCardReadDescriptor, ToCardWriteDescriptor,
     },
 };
 use std::{
-    collections::HashMap,
-    sync::{Arc, PoisonError, RwLock},
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Condvar, Mutex, PoisonError, RwLock,
+    },
+    time::Duration,
 };
 
+/// The `P_Key` of a CNP, which is the default partition
+const CNP_PKEY: u16 = 0xffff;
+/// The AETH value of a NAK for a PSN sequence error
+const NAK_PSN_SEQUENCE_ERROR: u8 = 0;
+/// The AETH value of a NAK for an invalid request, e.g. a SEND longer than the receive buffer
+const NAK_INVALID_REQUEST: u8 = 1;
+/// The AETH value of a NAK for a remote access error
+const NAK_REMOTE_ACCESS_ERROR: u8 = 2;
+
+/// A queue of the descriptors to the host, whose consumer can wait for the next descriptor.
+#[derive(Debug)]
+pub(crate) struct ToHostQueue<T> {
+    descs: Mutex<VecDeque<T>>,
+    pushed: Condvar,
+}
+
+impl<T> ToHostQueue<T> {
+    fn new() -> Self {
+        Self {
+            descs: Mutex::new(VecDeque::new()),
+            pushed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn push(&self, desc: T) {
+        // a panic while holding the lock leaves the queue intact
+        self.descs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(desc);
+        self.pushed.notify_one();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pop(&self) -> Option<T> {
+        self.descs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.descs.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pop a descriptor, waiting at most `timeout` for one to be pushed.
+    pub(crate) fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let guard = self.descs.lock().unwrap_or_else(PoisonError::into_inner);
+        let (mut guard, _) = self
+            .pushed
+            .wait_timeout_while(guard, timeout, |descs| descs.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        guard.pop_front()
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 struct QueuePairInner {
@@ -34,6 +108,8 @@ struct QueuePairInner {
     qp_type: QpType,
     qp_access_flags: MemAccessTypeFlag,
     pdkey: PDHandle,
+    rnr_retry: u8,
+    min_rnr_timer: u8,
 }
 
 /// The hardware queue pair context
@@ -41,6 +117,8 @@ struct QueuePairInner {
 struct QueuePair {
     #[allow(dead_code)]
     inner: QueuePairInner,
+    /// The MSN of the last message the QP has received as the responder, carried in its ACKs and NAKs
+    msn: AtomicU16,
 }
 
 /// The hardware memory region context
@@ -51,10 +129,30 @@ struct MemoryRegion {
     acc_flags: MemAccessTypeFlag,
     pdkey: PDHandle,
     addr: u64,
+    /// The virtual address `addr` is mapped at
+    va: u64,
     len: usize,
     pgt_offset: u32,
 }
 
+impl MemoryRegion {
+    /// Translate an address in `[addr, addr + len)` to the virtual address in the process
+    fn to_local(&self, addr: u64) -> u64 {
+        self.va.wrapping_add(addr.wrapping_sub(self.addr))
+    }
+
+    /// Translate the address of the `len` bytes at `addr` like `to_local`.
+    ///
+    /// Returns `None` if the bytes are not in the MR.
+    fn to_local_range(&self, addr: u64, len: usize) -> Option<u64> {
+        let offset = usize::try_from(addr.checked_sub(self.addr)?).ok()?;
+        if self.va == 0 || offset.checked_add(len)? > self.len {
+            return None;
+        }
+        Some(self.to_local(addr))
+    }
+}
+
 /// The simulating hardware logic of `BlueRDMA`
 ///
 /// Typically, the logic needs a `NetSendAgent` and a `NetReceiveAgent` to send and receive packets.
@@ -65,8 +163,29 @@ pub(crate) struct BlueRDMALogic {
     mr_rkey_table: RwLock<HashMap<Key, Arc<RwLock<MemoryRegion>>>>,
     qp_table: RwLock<HashMap<Qpn, Arc<QueuePair>>>,
     net_send_agent: Arc<dyn NetSendAgent>,
-    to_host_data_descriptor_queue: Arc<crossbeam_queue::SegQueue<ToHostWorkRbDesc>>,
-    to_host_ctrl_descriptor_queue: Arc<crossbeam_queue::SegQueue<ToHostCtrlRbDesc>>,
+    to_host_data_descriptor_queue: Arc<ToHostQueue<ToHostWorkRbDesc>>,
+    to_host_ctrl_descriptor_queue: ToHostQueue<ToHostCtrlRbDesc>,
+    read_overlap_policy: RwLock<ReadOverlapPolicy>,
+    congestion_control: CongestionControl,
+    retransmission: Retransmission,
+    /// the credits advertised by the ACKs, which are taken by the scheduler
+    credit_updates: crossbeam_queue::SegQueue<(Qpn, Option<u32>)>,
+    /// the receive buffers posted to the QPs, each of them is a list of SGEs. A QP without an entry
+    /// has no receive queue, so the messages consuming a receive buffer are not limited.
+    recv_buffers: Mutex<HashMap<Qpn, VecDeque<Vec<SGListElementWithKey>>>>,
+    /// the receive buffers posted to the shared receive queues, by their srqn
+    srqs: Mutex<HashMap<u32, VecDeque<Vec<SGListElementWithKey>>>>,
+    /// the shared receive queue each attached QP draws its receive buffers from, instead of its own
+    qp_srqs: RwLock<HashMap<Qpn, u32>>,
+    /// the QP on the remote the packets of each QP are addressed to. A QP without an entry talks to
+    /// the remote QP of the same number.
+    peer_qpns: RwLock<HashMap<Qpn, Qpn>>,
+    /// the payload of the SENDs in progress, which is delivered once the last packet arrives
+    send_assembly: Mutex<HashMap<Qpn, Vec<u8>>>,
+    /// the address of the device itself
+    local_addr: Option<Ipv4Addr>,
+    /// whether the RC writes to `local_addr` are copied to their MRs without the network
+    local_copy: AtomicBool,
 }
 
 #[derive(Error, Debug)]
@@ -77,6 +196,28 @@ pub(crate) enum BlueRdmaLogicError {
     // QpTypeToTransTypeError(#[from] QpTypeToTransTypeError),
     #[error("Raw packet length is too long. Pmtu is `{0}`, length is `{1}`")]
     RawPacketLengthTooLong(u32, u32),
+    #[error("A datagram is sent in a single packet. Pmtu is `{0}`, length is `{1}`")]
+    DatagramTooLong(u32, u32),
+    #[error("The source `{0:?}` and the sink `{1:?}` of a read request overlap")]
+    OverlappingBuffers(RethHeader, RethHeader),
+    #[error("The receive buffer of `{0:?}` can not hold a message of `{1}` bytes")]
+    RecvBufferTooSmall(Qpn, usize),
+    #[error("The SEND of `{0:?}` has not started, its first packet is missing")]
+    SendNotStarted(Qpn),
+    #[error("`{0:?}` has no receive buffer")]
+    NoRecvBuffer(Qpn),
+    #[error("The opcode `{0:?}` is not expected in the header")]
+    UnexpectedOpcode(ToHostWorkRbDescOpcode),
+    #[error("The AETH of `{0:?}` carries the reserved code")]
+    ReservedAethCode(Qpn),
+    #[error("The sge of `{2}` bytes at `{1:#x}` runs out of the MR `{0:?}`")]
+    SgeOutOfMr(Key, u64, u32),
+    #[error("The key `{0:?}` does not match the MR registered at its index")]
+    InvalidKey(Key),
+    #[error("The SRQ `{0}` does not exist")]
+    SrqNotFound(u32),
+    #[error("The SRQ `{0}` already exists")]
+    SrqExists(u32),
     #[error("Poison error")]
     Poison,
     #[error("Unreachable")]
@@ -96,18 +237,187 @@ impl BlueRDMALogic {
             mr_rkey_table: RwLock::new(HashMap::new()),
             qp_table: RwLock::new(HashMap::new()),
             net_send_agent: net_sender,
-            to_host_data_descriptor_queue: Arc::new(crossbeam_queue::SegQueue::new()),
-            to_host_ctrl_descriptor_queue: Arc::new(crossbeam_queue::SegQueue::new()),
+            to_host_data_descriptor_queue: Arc::new(ToHostQueue::new()),
+            to_host_ctrl_descriptor_queue: ToHostQueue::new(),
+            read_overlap_policy: RwLock::new(ReadOverlapPolicy::default()),
+            congestion_control: CongestionControl::new(),
+            retransmission: Retransmission::new(),
+            credit_updates: crossbeam_queue::SegQueue::new(),
+            recv_buffers: Mutex::new(HashMap::new()),
+            srqs: Mutex::new(HashMap::new()),
+            qp_srqs: RwLock::new(HashMap::new()),
+            peer_qpns: RwLock::new(HashMap::new()),
+            send_assembly: Mutex::new(HashMap::new()),
+            local_addr: None,
+            local_copy: AtomicBool::new(false),
         }
     }
 
-    /// Get the queue that contains the received meta descriptor
-    pub(crate) fn get_to_host_descriptor_queue(
+    /// Get the congestion control, which paces the QPs that have received CNPs
+    pub(crate) fn congestion_control(&self) -> &CongestionControl {
+        &self.congestion_control
+    }
+
+    /// Get the loss recovery of RC QPs, which holds the descriptors to resend
+    pub(crate) fn retransmission(&self) -> &Retransmission {
+        &self.retransmission
+    }
+
+    /// Send the packets the send agent has held back, since there is nothing else to send.
+    pub(crate) fn flush_send_agent(&self) -> Result<(), BlueRdmaLogicError> {
+        self.net_send_agent.flush()?;
+        Ok(())
+    }
+
+    /// Take the credits of a QP advertised by an ACK, `None` if the remote does not limit the QP.
+    pub(crate) fn pop_credit_update(&self) -> Option<(Qpn, Option<u32>)> {
+        self.credit_updates.pop()
+    }
+
+    /// Post a receive buffer made of `sges` to `qpn`. It is consumed by a write with immediate data, or
+    /// filled in order by `fill_recv_buffer` with a SEND.
+    ///
+    /// The messages consuming a receive buffer are answered with RNR NAKs while the QP has none.
+    #[allow(dead_code)]
+    pub(crate) fn post_recv(
+        &self,
+        qpn: Qpn,
+        sges: &[SGListElementWithKey],
+    ) -> Result<(), BlueRdmaLogicError> {
+        let mut recv_buffers = self.recv_buffers.lock()?;
+        recv_buffers.entry(qpn).or_default().push_back(sges.to_vec());
+        Ok(())
+    }
+
+    /// Create the shared receive queue `srqn`, which has no receive buffer yet.
+    pub(crate) fn create_srq(&self, srqn: u32) -> Result<(), BlueRdmaLogicError> {
+        match self.srqs.lock()?.entry(srqn) {
+            Entry::Occupied(_) => Err(BlueRdmaLogicError::SrqExists(srqn)),
+            Entry::Vacant(entry) => {
+                let _: &mut VecDeque<_> = entry.insert(VecDeque::new());
+                Ok(())
+            }
+        }
+    }
+
+    /// Destroy the shared receive queue `srqn` and drop its receive buffers. The QPs attached to it
+    /// are detached.
+    pub(crate) fn destroy_srq(&self, srqn: u32) -> Result<(), BlueRdmaLogicError> {
+        let _: VecDeque<_> = self
+            .srqs
+            .lock()?
+            .remove(&srqn)
+            .ok_or(BlueRdmaLogicError::SrqNotFound(srqn))?;
+        self.qp_srqs.write()?.retain(|_, attached| *attached != srqn);
+        Ok(())
+    }
+
+    /// Let `qpn` draw its receive buffers from the shared receive queue `srqn`. The receive buffers
+    /// posted to the QP itself are ignored afterwards.
+    pub(crate) fn attach_srq(&self, qpn: Qpn, srqn: u32) -> Result<(), BlueRdmaLogicError> {
+        if !self.srqs.lock()?.contains_key(&srqn) {
+            return Err(BlueRdmaLogicError::SrqNotFound(srqn));
+        }
+        let _: Option<u32> = self.qp_srqs.write()?.insert(qpn, srqn);
+        Ok(())
+    }
+
+    /// Post a receive buffer made of `sges` to the shared receive queue `srqn`, which is consumed by
+    /// the first message to any QP attached to it.
+    pub(crate) fn post_srq_recv(
+        &self,
+        srqn: u32,
+        sges: &[SGListElementWithKey],
+    ) -> Result<(), BlueRdmaLogicError> {
+        self.srqs
+            .lock()?
+            .get_mut(&srqn)
+            .ok_or(BlueRdmaLogicError::SrqNotFound(srqn))?
+            .push_back(sges.to_vec());
+        Ok(())
+    }
+
+    /// The shared receive queue `qpn` is attached to, if any
+    /// Address the packets of `qpn`, including its ACKs, NAKs and CNPs, to the QP `peer_qpn` on the
+    /// remote. The mapping is kept when the QP context is rebuilt, and dropped by mapping the QP to
+    /// itself.
+    pub(crate) fn set_peer_qpn(&self, qpn: Qpn, peer_qpn: Qpn) -> Result<(), BlueRdmaLogicError> {
+        let mut peer_qpns = self.peer_qpns.write()?;
+        if peer_qpn == qpn {
+            let _: Option<Qpn> = peer_qpns.remove(&qpn);
+        } else {
+            let _: Option<Qpn> = peer_qpns.insert(qpn, peer_qpn);
+        }
+        Ok(())
+    }
+
+    /// The QP on the remote the packets of `qpn` are addressed to
+    fn peer_qpn(&self, qpn: Qpn) -> Result<Qpn, BlueRdmaLogicError> {
+        Ok(self.peer_qpns.read()?.get(&qpn).copied().unwrap_or(qpn))
+    }
+
+    fn srq_of(&self, qpn: Qpn) -> Result<Option<u32>, BlueRdmaLogicError> {
+        Ok(self.qp_srqs.read()?.get(&qpn).copied())
+    }
+
+    /// Copy `payload` into the next receive buffer of `qpn`, filling its SGEs in order. Returns `false`
+    /// if the QP has no receive buffer. A QP attached to a shared receive queue takes the buffer from
+    /// the queue.
+    ///
+    /// A receive buffer too small for the payload is consumed without being written, and
+    /// `BlueRdmaLogicError::RecvBufferTooSmall` is returned.
+    pub(crate) fn fill_recv_buffer(
         &self,
-    ) -> Arc<crossbeam_queue::SegQueue<ToHostWorkRbDesc>> {
-        Arc::<crossbeam_queue::SegQueue<ToHostWorkRbDesc>>::clone(
-            &self.to_host_data_descriptor_queue,
-        )
+        qpn: Qpn,
+        payload: &PayloadInfo,
+    ) -> Result<bool, BlueRdmaLogicError> {
+        let sges = match self.srq_of(qpn)? {
+            Some(srqn) => self.srqs.lock()?.get_mut(&srqn).and_then(VecDeque::pop_front),
+            None => self
+                .recv_buffers
+                .lock()?
+                .get_mut(&qpn)
+                .and_then(VecDeque::pop_front),
+        };
+        let Some(sges) = sges else {
+            return Ok(false);
+        };
+        if payload.scatter_to(&sges) {
+            Ok(true)
+        } else {
+            Err(BlueRdmaLogicError::RecvBufferTooSmall(
+                qpn,
+                payload.get_length(),
+            ))
+        }
+    }
+
+    /// Enable or disable the loss recovery of the RC QPs, see `Retransmission`.
+    pub(crate) fn set_retransmission(&mut self, enabled: bool) {
+        self.retransmission.set_enabled(enabled);
+    }
+
+    /// Set the address of the device itself.
+    pub(crate) fn set_local_addr(&mut self, addr: Ipv4Addr) {
+        self.local_addr = Some(addr);
+    }
+
+    /// Copy the RC writes of the device to itself into their MRs directly, see `copy_local_write`.
+    pub(crate) fn set_local_copy(&self, enabled: bool) {
+        self.local_copy.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set the behavior when the source and the sink of a read request overlap.
+    pub(crate) fn set_read_overlap_policy(&self, policy: ReadOverlapPolicy) {
+        *self
+            .read_overlap_policy
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+
+    /// Get the queue that contains the received meta descriptor
+    pub(crate) fn get_to_host_descriptor_queue(&self) -> Arc<ToHostQueue<ToHostWorkRbDesc>> {
+        Arc::<ToHostQueue<ToHostWorkRbDesc>>::clone(&self.to_host_data_descriptor_queue)
     }
 
     fn send_raw_packet(&self, mut desc: ToCardDescriptor) -> Result<(), BlueRdmaLogicError> {
@@ -132,6 +442,7 @@ impl BlueRDMALogic {
         mut meta_data: RdmaGeneralMeta,
     ) -> Result<(), BlueRdmaLogicError> {
         // RdmaWriteOnly or RdmaWriteOnlyWithImmediate
+        let payload_len = req.sg_list.get_total_length();
         let payload = req.sg_list.cut_all_levels();
 
         // if it's a RdmaWriteOnlyWithImmediate, add the immediate data
@@ -141,7 +452,7 @@ impl BlueRDMALogic {
         meta_data.reth.len = if meta_data.common_meta.opcode.is_first() {
             req.common.total_len
         } else {
-            req.sg_list.get_total_length()
+            payload_len
         };
 
         let msg = RdmaMessage {
@@ -149,7 +460,38 @@ impl BlueRDMALogic {
             payload,
         };
 
-        self.net_send_agent.send(req.common.dqp_ip, 4791, &msg)?;
+        self.net_send_agent
+            .send_vectored(req.common.dqp_ip, DEFAULT_RMDA_PORT, &msg)?;
+        Ok(())
+    }
+
+    /// Send the payload of a UD descriptor as a SEND of a single packet. A UD QP addresses no remote
+    /// memory, so the payload fills the next receive buffer of the QP on the device it's sent to, or
+    /// on every member of the multicast group it's sent to.
+    fn send_datagram(
+        &self,
+        mut req: ToCardWriteDescriptor,
+        mut common_meta: RdmaMessageMetaCommon,
+    ) -> Result<(), BlueRdmaLogicError> {
+        let pmtu = u32::from(&req.common.pmtu);
+        let total_length = req.sg_list.get_total_length();
+        if total_length > pmtu {
+            return Err(BlueRdmaLogicError::DatagramTooLong(pmtu, total_length));
+        }
+        common_meta.opcode = if req.has_imm() {
+            ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+        } else {
+            ToHostWorkRbDescOpcode::SendOnly
+        };
+        let msg = RdmaMessage {
+            meta_data: Metadata::Send(RdmaSendMeta {
+                common_meta,
+                imm: req.imm,
+            }),
+            payload: req.sg_list.cut_all_levels(),
+        };
+        self.net_send_agent
+            .send_vectored(req.common.dqp_ip, DEFAULT_RMDA_PORT, &msg)?;
         Ok(())
     }
 
@@ -179,12 +521,20 @@ impl BlueRDMALogic {
             payload: PayloadInfo::new(),
         };
 
-        self.net_send_agent.send(req.common.dqp_ip, 4791, &msg)?;
+        self.net_send_agent.send_vectored(req.common.dqp_ip, 4791, &msg)?;
         Ok(())
     }
 
     /// Convert a `ToCardWorkRbDesc` to a `RdmaMessage` and call the `net_send_agent` to send through the network.
     pub(crate) fn send(&self, desc: ToCardWorkRbDesc) -> Result<(), BlueRdmaLogicError> {
+        enter_span!(
+            "transmit",
+            qpn = desc.common().dqpn.get(),
+            opcode = ?desc.opcode(),
+            psn = desc.common().psn.get(),
+            byte_len = desc.common().total_len
+        );
+        self.retransmission.on_sent(&desc)?;
         let desc = ToCardDescriptor::from(desc);
         // if it's a raw packet, send it directly
         if desc.is_raw_packet() {
@@ -199,7 +549,7 @@ impl BlueRDMALogic {
                 solicited: false,
                 // We use the pkey to store msn
                 pkey: PKey::new(common.msn.get()),
-                dqpn: Qpn::new(common.dqpn.get()),
+                dqpn: self.peer_qpn(Qpn::new(common.dqpn.get()))?,
                 ack_req: false,
                 psn: Psn::new(common.psn.get()),
             }
@@ -209,6 +559,13 @@ impl BlueRDMALogic {
         match desc {
             ToCardDescriptor::Write(mut req) => {
                 log::info!("{:?}", req);
+                self.translate_sg_list(&mut req.sg_list)?;
+                if matches!(req.common.qp_type, QpType::Ud) {
+                    return self.send_datagram(req, common_meta);
+                }
+                if self.copy_local_write(&mut req)? {
+                    return Ok(());
+                }
                 let pmtu = u32::from(&req.common.pmtu);
                 let first_packet_max_length = get_first_packet_max_length(req.common.raddr, pmtu);
 
@@ -228,67 +585,35 @@ impl BlueRDMALogic {
                     return self.send_write_only_packet(req, meta_data);
                 }
                 // othetrwise send the data in multiple packets
-                // we specifically handle the first and last packet
                 // The first va might not align to pmtu
-                let mut cur_va = req.common.raddr;
-                let mut cur_len = sge_total_length;
-                let mut psn = req.common.psn;
-
-                // since the packet size is larger than first_packet_max_length, first_packet_length should equals
-                // to first_packet_max_length
-                let first_packet_length = first_packet_max_length;
-
-                let payload = req.sg_list.cut(first_packet_length)?;
-                meta_data.common_meta.opcode = req.write_first_opcode();
-                meta_data.reth.len = if meta_data.common_meta.opcode.is_first() {
-                    req.common.total_len
-                } else {
-                    first_packet_length
-                };
-                meta_data.reth.va = cur_va;
-                let msg = RdmaMessage {
-                    meta_data: Metadata::General(meta_data.clone()),
-                    payload,
-                };
-                cur_len -= first_packet_length;
-                psn = psn.wrapping_add(1);
-                cur_va = cur_va.wrapping_add(u64::from(first_packet_length));
-                self.net_send_agent.send(req.common.dqp_ip, 4791, &msg)?;
-
-                // send the middle packets
-                meta_data.reth.len = pmtu;
-                while cur_len > pmtu {
-                    let middle_payload = req.sg_list.cut(pmtu)?;
-                    meta_data.common_meta.opcode = req.write_middle_opcode();
-                    meta_data.reth.va = cur_va;
-                    meta_data.common_meta.psn = psn;
-                    let middle_msg = RdmaMessage {
+                let fragments =
+                    PmtuFragments::new(req.common.raddr, sge_total_length, req.common.pmtu);
+                for (psn_offset, fragment) in (0_u32..).zip(fragments) {
+                    let payload = req.sg_list.cut(fragment.len)?;
+                    let (opcode, imm) = if fragment.is_first {
+                        (req.write_first_opcode(), None)
+                    } else if fragment.is_last {
+                        // The last packet may be with immediate data
+                        req.write_last_opcode_with_imm()
+                    } else {
+                        (req.write_middle_opcode(), None)
+                    };
+                    meta_data.reth.len = if opcode.is_first() {
+                        req.common.total_len
+                    } else {
+                        fragment.len
+                    };
+                    meta_data.common_meta.opcode = opcode;
+                    meta_data.common_meta.psn = req.common.psn.wrapping_add(psn_offset);
+                    meta_data.imm = imm;
+                    meta_data.reth.va = req.common.raddr.wrapping_add(u64::from(fragment.offset));
+                    let msg = RdmaMessage {
                         meta_data: Metadata::General(meta_data.clone()),
-                        payload: middle_payload,
+                        payload,
                     };
-                    cur_len -= pmtu;
-                    psn = psn.wrapping_add(1);
-                    cur_va = cur_va.wrapping_add(u64::from(pmtu));
                     self.net_send_agent
-                        .send(req.common.dqp_ip, 4791, &middle_msg)?;
+                        .send_vectored(req.common.dqp_ip, DEFAULT_RMDA_PORT, &msg)?;
                 }
-
-                // cur_len <= pmtu, send last packet
-                let last_payload = req.sg_list.cut(cur_len)?;
-
-                // The last packet may be with immediate data
-                let (opcode, imm) = req.write_last_opcode_with_imm();
-                meta_data.common_meta.opcode = opcode;
-                meta_data.common_meta.psn = psn;
-                meta_data.imm = imm;
-                meta_data.reth.va = cur_va;
-                meta_data.reth.len = cur_len;
-                let last_msg = RdmaMessage {
-                    meta_data: Metadata::General(meta_data),
-                    payload: last_payload,
-                };
-                self.net_send_agent
-                    .send(req.common.dqp_ip, 4791, &last_msg)?;
             }
             ToCardDescriptor::Read(req) => {
                 self.send_read_packet(&req, common_meta)?;
@@ -297,8 +622,110 @@ impl BlueRDMALogic {
         Ok(())
     }
 
-    pub(crate) fn get_update_result(&self) -> Option<ToHostCtrlRbDesc> {
-        self.to_host_ctrl_descriptor_queue.pop()
+    /// Copy the payload of a RC write to the device itself into its MR at once, instead of splitting
+    /// it into packets. Returns `false` if the write is sent through the network instead, because the
+    /// local copy is disabled, or the packets before it are still on the network.
+    ///
+    /// The receiving side gets the descriptor of every packet the write would be split into, so the
+    /// host acknowledges it like a write from the network, and a write without access to the MR is
+    /// answered with a NAK of remote access error. The acknowledges are sent through the network,
+    /// so the receiving side never runs on the sending thread.
+    fn copy_local_write(&self, req: &mut ToCardWriteDescriptor) -> Result<bool, BlueRdmaLogicError> {
+        let common = &req.common;
+        let is_local = self.local_copy.load(Ordering::Relaxed)
+            && matches!(common.qp_type, QpType::Rc)
+            && self.local_addr == Some(common.dqp_ip);
+        // the writes with immediate data consume a receive buffer, which is left to the network path
+        if !is_local || req.is_resp() || req.has_imm() {
+            return Ok(false);
+        }
+        let dqpn = Qpn::new(common.dqpn.get());
+        // the QP is its own peer here, a write to another QP of the device goes through the network
+        if self.peer_qpn(dqpn)? != dqpn {
+            return Ok(false);
+        }
+        let len = req.sg_list.get_total_length();
+        let packet_cnt = calculate_packet_cnt(common.pmtu, common.raddr, len);
+        if !self
+            .retransmission
+            .claim_sequence(dqpn, common.psn, packet_cnt, req.is_first)?
+        {
+            return Ok(false);
+        }
+        let mut meta = RdmaMessageMetaCommon {
+            tran_type: ToHostWorkRbDescTransType::Rc,
+            opcode: req.write_first_opcode(),
+            solicited: false,
+            // We use the pkey to store msn
+            pkey: PKey::new(common.msn.get()),
+            dqpn,
+            ack_req: false,
+            psn: common.psn,
+        };
+        let rkey = Key::new(common.rkey.get());
+        let needed_permissions = MemAccessTypeFlag::IbvAccessRemoteWrite;
+        let mut status = self.validate_rkey(rkey, needed_permissions, common.raddr, len)?;
+        if req.is_first {
+            self.set_responder_msn(dqpn, common.msn.get())?;
+            // the first packet checks the whole message, like `check_access`
+            let message_status =
+                self.validate_rkey(rkey, needed_permissions, common.raddr, common.total_len)?;
+            if !message_status.is_ok() {
+                status = message_status;
+                let nak = ToHostWorkRbDescAethCode::Nak;
+                let addr = common.dqp_ip;
+                self.send_acknowledge(addr, &meta, common.psn, nak, NAK_REMOTE_ACCESS_ERROR)?;
+            }
+        }
+        if status.is_ok() {
+            let local_va = self.to_local_addr(rkey, common.raddr)?;
+            req.sg_list.cut_all_levels().copy_to(local_va as *mut u8);
+        }
+
+        let fragments = PmtuFragments::new(req.common.raddr, len, req.common.pmtu);
+        for (psn_offset, fragment) in (0_u32..).zip(fragments) {
+            meta.opcode = match (fragment.is_first, fragment.is_last) {
+                (true, true) => req.write_only_opcode_with_imm().0,
+                (true, false) => req.write_first_opcode(),
+                (false, true) => req.write_last_opcode_with_imm().0,
+                (false, false) => req.write_middle_opcode(),
+            };
+            let pad_cnt = RDMA_PAYLOAD_ALIGNMENT
+                .wrapping_sub(fragment.len as usize % RDMA_PAYLOAD_ALIGNMENT)
+                % RDMA_PAYLOAD_ALIGNMENT;
+            let desc = ToHostWorkRbDescWriteOrReadResp {
+                common: ToHostWorkRbDescCommon {
+                    status,
+                    trans: ToHostWorkRbDescTransType::Rc,
+                    dqpn: req.common.dqpn,
+                    #[allow(clippy::cast_possible_truncation)]
+                    pad_cnt: pad_cnt as u8,
+                    msn: req.common.msn,
+                    expected_psn: Psn::new(0),
+                },
+                is_read_resp: false,
+                write_type: meta
+                    .opcode
+                    .write_type()
+                    .unwrap_or(ToHostWorkRbDescWriteType::Only),
+                psn: req.common.psn.wrapping_add(psn_offset),
+                addr: req.common.raddr.wrapping_add(u64::from(fragment.offset)),
+                len: if meta.opcode.is_first() {
+                    req.common.total_len
+                } else {
+                    fragment.len
+                },
+                key: rkey.into(),
+            };
+            self.to_host_data_descriptor_queue
+                .push(ToHostWorkRbDesc::WriteOrReadResp(desc));
+        }
+        Ok(true)
+    }
+
+    /// Wait at most `timeout` for the result of an update
+    pub(crate) fn get_update_result(&self, timeout: Duration) -> Option<ToHostCtrlRbDesc> {
+        self.to_host_ctrl_descriptor_queue.pop_timeout(timeout)
     }
 
     pub(crate) fn update(&self, desc: ToCardCtrlRbDesc) -> Result<(), BlueRdmaLogicError> {
@@ -311,6 +738,8 @@ impl BlueRDMALogic {
                     qp_type: desc.qp_type,
                     qp_access_flags: desc.rq_acc_flags,
                     pdkey: PDHandle::new(desc.pd_hdl),
+                    rnr_retry: desc.rnr_retry,
+                    min_rnr_timer: desc.min_rnr_timer,
                 };
                 let is_success = if desc.is_valid {
                     // create
@@ -319,7 +748,10 @@ impl BlueRDMALogic {
                         false
                     } else {
                         // otherwise insert a new qp context
-                        let qp = Arc::new(QueuePair { inner: qp_inner });
+                        let qp = Arc::new(QueuePair {
+                            inner: qp_inner,
+                            msn: AtomicU16::new(0),
+                        });
                         // we have ensured that the qpn is not exists.
                         let _: Option<Arc<QueuePair>> = qp_table.insert(qpn, qp);
                         true
@@ -329,6 +761,7 @@ impl BlueRDMALogic {
                     if qp_table.get(&qpn).is_some() {
                         // exist
                         let _: Option<Arc<QueuePair>> = qp_table.remove(&qpn);
+                        let _: Option<u32> = self.qp_srqs.write()?.remove(&qpn);
                         true
                     } else {
                         false
@@ -350,6 +783,7 @@ impl BlueRDMALogic {
                     acc_flags: desc.acc_flags,
                     pdkey: PDHandle::new(desc.pd_hdl),
                     addr: desc.addr,
+                    va: desc.va,
                     len: desc.len as usize,
                     pgt_offset: desc.pgt_offset,
                 };
@@ -433,6 +867,497 @@ impl BlueRDMALogic {
         }
         Ok(ToHostWorkRbDescStatus::Normal)
     }
+
+    /// Translate the address `addr` of the MR `key` to the virtual address the payload is copied to or from.
+    ///
+    /// An address of an unknown key is returned as it is.
+    fn to_local_addr(&self, key: Key, addr: u64) -> Result<u64, BlueRdmaLogicError> {
+        let mr_rkey_table = self.mr_rkey_table.read()?;
+        match mr_rkey_table.get(&key) {
+            Some(mr) => Ok(mr.read()?.to_local(addr)),
+            None => Ok(addr),
+        }
+    }
+
+    /// Copy the `payload` of a packet to the memory its RETH points to.
+    ///
+    /// Returns `false` if the payload is dropped, e.g. because its length does not match the RETH.
+    fn write_payload(&self, header: &RdmaGeneralMeta, payload: &PayloadInfo) -> bool {
+        // Only the first packet carries the length of the whole message in its RETH, and ends at the
+        // first pmtu boundary after its address. The others carry the length of their own payload.
+        let expected_len = if header.common_meta.opcode.is_first() {
+            match self.ends_at_pmtu_boundary(header, payload.get_length()) {
+                Ok(true) => payload.get_length(),
+                Ok(false) => {
+                    log::error!(
+                        "Dropping a first packet of {} bytes to {:#x}, which does not end at a pmtu boundary",
+                        payload.get_length(),
+                        header.reth.va
+                    );
+                    return false;
+                }
+                Err(e) => {
+                    log::error!("Failed to look up the pmtu of {:?}: {e}", header.common_meta.dqpn);
+                    return false;
+                }
+            }
+        } else {
+            header.reth.len as usize
+        };
+        if let Err(e) = payload.validate_against(expected_len) {
+            log::error!(
+                "Dropping a packet of {} segments: {e}",
+                payload.segment_count()
+            );
+            return false;
+        }
+        let va = header.reth.va;
+        let Ok(local_va) = self.to_local_addr(header.reth.rkey, va) else {
+            log::error!("Failed to translate the address {va:#x}");
+            return false;
+        };
+        payload.copy_to(local_va as *mut u8);
+        true
+    }
+
+    /// Whether the first packet of a message, which carries `len` bytes, ends at the boundary of a pmtu
+    /// no larger than the one of its QP. The sender may downgrade the pmtu of the QP to fit the path
+    /// MTU, and a QP unknown to the device accepts any pmtu.
+    fn ends_at_pmtu_boundary(
+        &self,
+        header: &RdmaGeneralMeta,
+        len: usize,
+    ) -> Result<bool, BlueRdmaLogicError> {
+        let qp_pmtu = self
+            .qp_table
+            .read()?
+            .get(&header.common_meta.dqpn)
+            .map_or(Pmtu::Mtu4096, |qp| qp.inner.pmtu);
+        // the packet is followed by the other packets of the message
+        Ok(len < header.reth.len as usize
+            && PMTUS_DESCENDING
+                .iter()
+                .filter(|pmtu| u32::from(*pmtu) <= u32::from(&qp_pmtu))
+                .any(|pmtu| {
+                    get_first_packet_max_length(header.reth.va, u32::from(pmtu)) as usize == len
+                }))
+    }
+
+    /// Point the sges to the memory of their MRs, which the payload is gathered from without copying.
+    ///
+    /// The sges of the zero key, which stands for no MR, keep their addresses. An sge running past
+    /// the end of its MR is rejected with `BlueRdmaLogicError::SgeOutOfMr`, and an sge of any other
+    /// key that is not registered, like a stale or forged one, is rejected with
+    /// `BlueRdmaLogicError::InvalidKey`.
+    fn translate_sg_list(&self, sg_list: &mut SGList) -> Result<(), BlueRdmaLogicError> {
+        let mr_rkey_table = self.mr_rkey_table.read()?;
+        for sge in sg_list.data.iter_mut().take(sg_list.len as usize) {
+            let Some(mr) = mr_rkey_table.get(&sge.key) else {
+                if sge.key == Key::default() {
+                    continue;
+                }
+                return Err(BlueRdmaLogicError::InvalidKey(sge.key));
+            };
+            sge.addr = mr
+                .read()?
+                .to_local_range(sge.addr, sge.len as usize)
+                .ok_or(BlueRdmaLogicError::SgeOutOfMr(sge.key, sge.addr, sge.len))?;
+        }
+        Ok(())
+    }
+
+    /// Send a CNP back to `dest_addr` for the QP `dqpn`, which has received a packet marked with ECN-CE.
+    fn send_cnp(&self, dest_addr: Ipv4Addr, dqpn: Qpn) -> Result<(), BlueRdmaLogicError> {
+        if !self.congestion_control.should_notify(dqpn)? {
+            return Ok(());
+        }
+        let msg = RdmaMessage {
+            meta_data: Metadata::Cnp(RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Cnp,
+                opcode: ToHostWorkRbDescOpcode::Cnp,
+                solicited: false,
+                pkey: PKey::new(CNP_PKEY),
+                dqpn: self.peer_qpn(dqpn)?,
+                ack_req: false,
+                psn: Psn::default(),
+            }),
+            payload: PayloadInfo::new(),
+        };
+        self.net_send_agent.send_vectored(dest_addr, DEFAULT_RMDA_PORT, &msg)?;
+        Ok(())
+    }
+
+    /// Send an ACK or NAK of `psn` back to `dest_addr`, which is the sender of `meta`.
+    fn send_acknowledge(
+        &self,
+        dest_addr: Ipv4Addr,
+        meta: &RdmaMessageMetaCommon,
+        psn: Psn,
+        aeth_code: ToHostWorkRbDescAethCode,
+        aeth_value: u8,
+    ) -> Result<(), BlueRdmaLogicError> {
+        let responder_msn = self.responder_msn(meta.dqpn)?;
+        let msg = RdmaMessage {
+            meta_data: Metadata::Acknowledge(AethHeader {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::Acknowledge,
+                    solicited: false,
+                    pkey: meta.pkey,
+                    dqpn: self.peer_qpn(meta.dqpn)?,
+                    ack_req: false,
+                    psn,
+                },
+                aeth_code,
+                aeth_value,
+                msn: responder_msn,
+            }),
+            payload: PayloadInfo::new(),
+        };
+        self.net_send_agent.send_vectored(dest_addr, DEFAULT_RMDA_PORT, &msg)?;
+        Ok(())
+    }
+
+    /// Handle an ACK or NAK. Returns the descriptor to the host, or `None` if the device handles it itself.
+    fn recv_acknowledge(
+        &self,
+        header: &AethHeader,
+        mut common: ToHostWorkRbDescCommon,
+    ) -> Result<Option<ToHostWorkRbDesc>, BlueRdmaLogicError> {
+        common.status = ToHostWorkRbDescStatus::Normal;
+        let dqpn = header.common_meta.dqpn;
+        let psn = header.common_meta.psn;
+        match header.aeth_code {
+            ToHostWorkRbDescAethCode::Ack => {
+                if let Err(e) = self.retransmission.on_ack(dqpn, psn) {
+                    log::error!("Failed to release the acknowledged descriptors: {e}");
+                }
+                self.credit_updates
+                    .push((dqpn, decode_aeth_credit(header.aeth_value)));
+                Ok(Some(ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
+                    common,
+                    #[allow(clippy::cast_possible_truncation)]
+                    msn: crate::types::Msn::new(header.msn as u16), // msn is u16 currently. So we can just truncate it.
+                    value: header.aeth_value,
+                    psn: crate::types::Psn::new(psn.get()),
+                })))
+            }
+            // The lost packets are resent by the device itself, the host does not see the NAK
+            ToHostWorkRbDescAethCode::Nak
+                if header.aeth_value == NAK_PSN_SEQUENCE_ERROR
+                    && self.retransmission.is_enabled() =>
+            {
+                if let Err(e) = self.retransmission.on_nak(dqpn, psn) {
+                    log::error!("Failed to resend from {psn:?}: {e}");
+                }
+                Ok(None)
+            }
+            // The remote has no receive buffer, the message is resent by the device after the timer in
+            // the AETH. The host only sees the RNR NAK once the retries are used up.
+            ToHostWorkRbDescAethCode::Rnr if self.retry_on_rnr(dqpn, psn, header.aeth_value) => Ok(None),
+            // the operation fails, the host completes it with the reason in the AETH
+            ToHostWorkRbDescAethCode::Nak | ToHostWorkRbDescAethCode::Rnr => {
+                Ok(Some(ToHostWorkRbDesc::Nack(ToHostWorkRbDescNack {
+                    common,
+                    #[allow(clippy::cast_possible_truncation)]
+                    msn: crate::types::Msn::new(header.msn as u16), // msn is u16 currently. So we can just truncate it.
+                    code: header.aeth_code.clone(),
+                    value: header.aeth_value,
+                    lost_psn: Psn::new(psn.get())..Psn::new(psn.get()),
+                })))
+            }
+            ToHostWorkRbDescAethCode::Rsvd => Err(BlueRdmaLogicError::ReservedAethCode(dqpn)),
+        }
+    }
+
+    /// The `rnr_retry` and `min_rnr_timer` of the QP `qpn`. A QP unknown to the device is not retried.
+    fn rnr_params(&self, qpn: Qpn) -> Result<(u8, u8), BlueRdmaLogicError> {
+        let qp_table = self.qp_table.read()?;
+        Ok(qp_table
+            .get(&qpn)
+            .map_or((0, 0), |qp| (qp.inner.rnr_retry, qp.inner.min_rnr_timer)))
+    }
+
+    /// Record `msn` as the MSN of the QP `qpn`, which has started to receive the message.
+    fn set_responder_msn(&self, qpn: Qpn, msn: u16) -> Result<(), BlueRdmaLogicError> {
+        if let Some(qp) = self.qp_table.read()?.get(&qpn) {
+            qp.msn.store(msn, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// The MSN the QP `qpn` acknowledges with. A QP unknown to the device does not track it, and uses 0.
+    fn responder_msn(&self, qpn: Qpn) -> Result<u32, BlueRdmaLogicError> {
+        let qp_table = self.qp_table.read()?;
+        Ok(qp_table
+            .get(&qpn)
+            .map_or(0, |qp| u32::from(qp.msn.load(Ordering::Relaxed))))
+    }
+
+    /// Resend the message of `dqpn` at `psn` after the `timer` of its RNR NAK. Returns `false` if the
+    /// retries of the QP are used up.
+    fn retry_on_rnr(&self, dqpn: Qpn, psn: Psn, timer: u8) -> bool {
+        let retried = self.rnr_params(dqpn).and_then(|(rnr_retry, _)| {
+            self.retransmission
+                .on_rnr_nak(dqpn, psn, rnr_retry, decode_rnr_timer(timer))
+        });
+        retried.unwrap_or_else(|e| {
+            log::error!("Failed to resend from {psn:?} after the RNR NAK: {e}");
+            false
+        })
+    }
+
+    /// Consume a receive buffer of `qpn`. Returns `false` if the QP has a receive queue, but no
+    /// receive buffer in it.
+    fn consume_recv_buffer(&self, qpn: Qpn) -> Result<bool, BlueRdmaLogicError> {
+        if let Some(srqn) = self.srq_of(qpn)? {
+            let mut srqs = self.srqs.lock()?;
+            return Ok(srqs.get_mut(&srqn).and_then(VecDeque::pop_front).is_some());
+        }
+        let mut recv_buffers = self.recv_buffers.lock()?;
+        Ok(match recv_buffers.get_mut(&qpn) {
+            Some(posted) => posted.pop_front().is_some(),
+            None => true,
+        })
+    }
+
+    /// The length of the next receive buffer of `qpn`, `None` if it has none. A QP attached to a shared
+    /// receive queue takes the buffer from the queue.
+    fn next_recv_buffer_len(&self, qpn: Qpn) -> Result<Option<usize>, BlueRdmaLogicError> {
+        let len = |sges: &Vec<SGListElementWithKey>| {
+            sges.iter()
+                .fold(0_usize, |sum, sge| sum.saturating_add(sge.len as usize))
+        };
+        Ok(match self.srq_of(qpn)? {
+            Some(srqn) => self.srqs.lock()?.get(&srqn).and_then(VecDeque::front).map(len),
+            None => self
+                .recv_buffers
+                .lock()?
+                .get(&qpn)
+                .and_then(VecDeque::front)
+                .map(len),
+        })
+    }
+
+    /// Check the receive buffer of a message consuming one. If the QP has none, the packet is dropped and
+    /// answered with an RNR NAK, so the sender resends it later. Returns whether the packet is accepted.
+    fn check_recv_buffer(&self, src_addr: Ipv4Addr, meta: &RdmaMessageMetaCommon) -> bool {
+        if !meta.opcode.has_immediate() {
+            return true;
+        }
+        match self.consume_recv_buffer(meta.dqpn) {
+            Ok(true) => true,
+            Ok(false) => {
+                self.send_rnr_nak(src_addr, meta);
+                false
+            }
+            Err(e) => {
+                log::error!("Failed to consume the receive buffer: {e}");
+                false
+            }
+        }
+    }
+
+    /// Check the receive buffer a SEND is delivered to once its last packet arrives, which is taken only
+    /// then. Like `check_recv_buffer`, the first packet of the SEND is answered with an RNR NAK if the QP
+    /// has no receive buffer.
+    fn check_send_buffer(&self, src_addr: Ipv4Addr, meta: &RdmaMessageMetaCommon) -> bool {
+        match self.next_recv_buffer_len(meta.dqpn) {
+            Ok(Some(_)) => true,
+            Ok(None) => {
+                self.send_rnr_nak(src_addr, meta);
+                false
+            }
+            Err(e) => {
+                log::error!("Failed to check the receive buffer: {e}");
+                false
+            }
+        }
+    }
+
+    /// Answer the packet `meta`, which finds no receive buffer, with an RNR NAK and expect it again.
+    fn send_rnr_nak(&self, src_addr: Ipv4Addr, meta: &RdmaMessageMetaCommon) {
+        log::debug!("{:?} has no receive buffer for {:?}", meta.dqpn, meta.psn);
+        // the packet is expected again when it is resent
+        if let Err(e) = self.retransmission.rewind_sequence(meta.dqpn, meta.psn) {
+            log::error!("Failed to rewind the expected PSN: {e}");
+        }
+        let rnr = ToHostWorkRbDescAethCode::Rnr;
+        let timer = self.rnr_params(meta.dqpn).map_or(0, |(_, timer)| timer);
+        if let Err(e) = self.send_acknowledge(src_addr, meta, meta.psn, rnr, timer) {
+            log::error!("Failed to send the RNR NAK to {src_addr}: {e}");
+        }
+    }
+
+    /// Check the MR a request targets. If the rkey is invalid, the MR does not permit the request, or the
+    /// request runs out of the MR, it's answered with a NAK of remote access error, so the sender
+    /// completes it with the error. The memory is left untouched by `recv`.
+    ///
+    /// Only the first packet of a message is checked, so a message is answered with one NAK.
+    fn check_access(&self, src_addr: Ipv4Addr, header: &RdmaGeneralMeta) {
+        let meta = &header.common_meta;
+        if !meta.opcode.expects_reth() {
+            return;
+        }
+        let reth = &header.reth;
+        match self.validate_rkey(reth.rkey, header.needed_permissions(), reth.va, reth.len) {
+            Ok(status) if !status.is_ok() => {
+                log::debug!(
+                    "{:?} has no access to {:?} for {:?}: {status:?}",
+                    meta.dqpn,
+                    reth.rkey,
+                    meta.opcode
+                );
+                let nak = ToHostWorkRbDescAethCode::Nak;
+                let value = NAK_REMOTE_ACCESS_ERROR;
+                if let Err(e) = self.send_acknowledge(src_addr, meta, meta.psn, nak, value) {
+                    log::error!("Failed to send the NAK to {src_addr}: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to validate the rkey: {e}"),
+        }
+    }
+
+    /// Append the `payload` of a SEND packet to the SEND in progress on `qpn`. Returns the whole
+    /// message once the packet ends it.
+    ///
+    /// The message is held until it's delivered, so it can not grow beyond the next receive buffer of the
+    /// QP. A SEND that does is dropped with `BlueRdmaLogicError::RecvBufferTooSmall`.
+    fn assemble_send(
+        &self,
+        qpn: Qpn,
+        opcode: &ToHostWorkRbDescOpcode,
+        payload: &PayloadInfo,
+    ) -> Result<Option<Vec<u8>>, BlueRdmaLogicError> {
+        let limit = self
+            .next_recv_buffer_len(qpn)?
+            .ok_or(BlueRdmaLogicError::NoRecvBuffer(qpn))?;
+        let mut send_assembly = self.send_assembly.lock()?;
+        let starts = opcode.is_first()
+            || matches!(
+                opcode,
+                ToHostWorkRbDescOpcode::SendOnly | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            );
+        let mut message = if starts {
+            if send_assembly.remove(&qpn).is_some() {
+                log::error!("{qpn:?} starts a new SEND before the last one ends, which is dropped");
+            }
+            Vec::with_capacity(payload.get_length())
+        } else {
+            send_assembly
+                .remove(&qpn)
+                .ok_or(BlueRdmaLogicError::SendNotStarted(qpn))?
+        };
+        let len = message.len().saturating_add(payload.get_length());
+        if len > limit {
+            return Err(BlueRdmaLogicError::RecvBufferTooSmall(qpn, len));
+        }
+        for segment in payload.segments() {
+            message.extend_from_slice(segment);
+        }
+        if opcode.is_first() || *opcode == ToHostWorkRbDescOpcode::SendMiddle {
+            let _: Option<Vec<u8>> = send_assembly.insert(qpn, message);
+            return Ok(None);
+        }
+        Ok(Some(message))
+    }
+
+    /// Assemble the packets of a SEND from `src_addr`, and deliver the message to the next receive buffer
+    /// of the QP once its last packet arrives. The host is notified of the delivered message with
+    /// `common`, and an RC SEND is acknowledged.
+    ///
+    /// An RC SEND longer than the receive buffer is answered with a NAK of invalid request.
+    fn recv_send(
+        &self,
+        src_addr: Ipv4Addr,
+        header: &RdmaSendMeta,
+        payload: &PayloadInfo,
+        mut common: ToHostWorkRbDescCommon,
+    ) {
+        let meta = &header.common_meta;
+        let qpn = meta.dqpn;
+        let is_rc = matches!(meta.tran_type, ToHostWorkRbDescTransType::Rc);
+        let delivered = self
+            .assemble_send(qpn, &meta.opcode, payload)
+            .and_then(|message| match message {
+                Some(message) => self.deliver_send(qpn, &message).map(|()| Some(message.len())),
+                None => Ok(None),
+            });
+        match delivered {
+            Ok(None) => {}
+            Ok(Some(len)) => {
+                if is_rc {
+                    let ack = ToHostWorkRbDescAethCode::Ack;
+                    // the receive queue does not limit the sender
+                    let credit = AETH_CREDIT_INVALID;
+                    if let Err(e) = self.send_acknowledge(src_addr, meta, meta.psn, ack, credit) {
+                        log::error!("Failed to send the ACK to {src_addr}: {e}");
+                    }
+                }
+                common.status = ToHostWorkRbDescStatus::Normal;
+                #[allow(clippy::cast_possible_truncation)]
+                self.to_host_data_descriptor_queue
+                    .push(ToHostWorkRbDesc::Recv(ToHostWorkRbDescRecv {
+                        common,
+                        psn: meta.psn,
+                        len: len as u32, // the receive buffer is no longer than u32::MAX
+                        imm: header.imm,
+                    }));
+            }
+            Err(e) => {
+                log::error!("Failed to receive the SEND: {e}");
+                if is_rc && matches!(e, BlueRdmaLogicError::RecvBufferTooSmall(..)) {
+                    let nak = ToHostWorkRbDescAethCode::Nak;
+                    let value = NAK_INVALID_REQUEST;
+                    if let Err(err) = self.send_acknowledge(src_addr, meta, meta.psn, nak, value) {
+                        log::error!("Failed to send the NAK to {src_addr}: {err}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deliver the assembled SEND `message` to the next receive buffer of `qpn`.
+    fn deliver_send(&self, qpn: Qpn, message: &[u8]) -> Result<(), BlueRdmaLogicError> {
+        let delivered = PayloadInfo::new_with_data(message.as_ptr(), message.len());
+        if self.fill_recv_buffer(qpn, &delivered)? {
+            Ok(())
+        } else {
+            Err(BlueRdmaLogicError::NoRecvBuffer(qpn))
+        }
+    }
+
+    /// Slow down the QP `dqpn`, whose packets have experienced congestion.
+    fn handle_cnp(&self, dqpn: Qpn) {
+        if let Err(e) = self.congestion_control.on_cnp(dqpn) {
+            log::error!("Failed to handle the CNP: {e}");
+        }
+    }
+
+    /// Check the source and the sink of a read request against the `read_overlap_policy`.
+    fn check_read_overlap(
+        &self,
+        src: &RethHeader,
+        sink: &RethHeader,
+    ) -> Result<(), BlueRdmaLogicError> {
+        if !src.is_overlapped_with(sink) {
+            return Ok(());
+        }
+        match *self
+            .read_overlap_policy
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            ReadOverlapPolicy::Memmove => {
+                log::debug!("read request with overlapping buffers, fallback to memmove");
+                Ok(())
+            }
+            ReadOverlapPolicy::Reject => {
+                Err(BlueRdmaLogicError::OverlappingBuffers(*src, *sink))
+            }
+        }
+    }
 }
 
 unsafe impl Send for BlueRDMALogic {}
@@ -452,7 +1377,123 @@ fn recv_default_meta(message: &RdmaMessage) -> ToHostWorkRbDescCommon {
 }
 
 impl NetReceiveLogic<'_> for BlueRDMALogic {
-    fn recv(&self, message: &mut RdmaMessage) {
+    fn congestion_experienced(&self, src_addr: Ipv4Addr, message: &RdmaMessage) {
+        let dqpn = message.meta_data.common_meta().dqpn;
+        if let Err(e) = self.send_cnp(src_addr, dqpn) {
+            log::error!("Failed to send the CNP to {src_addr}: {e}");
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn check_sequence(&self, src_addr: Ipv4Addr, message: &RdmaMessage) -> bool {
+        // a SEND has no RETH to check the access of
+        let (meta, general) = match &message.meta_data {
+            Metadata::General(header) => (&header.common_meta, Some(header)),
+            Metadata::Send(header) => (&header.common_meta, None),
+            Metadata::Acknowledge(_) | Metadata::Cnp(_) => return true,
+        };
+        if !matches!(meta.tran_type, ToHostWorkRbDescTransType::Rc) {
+            return true;
+        }
+        let starts_message = !meta.opcode.is_middle() && !meta.opcode.is_last();
+        match self
+            .retransmission
+            .check_sequence(meta.dqpn, meta.psn, starts_message)
+        {
+            Ok(Sequence::InOrder) => {
+                // We use the pkey to store msn
+                if starts_message {
+                    if let Err(e) = self.set_responder_msn(meta.dqpn, meta.pkey.get()) {
+                        log::error!("Failed to update the MSN of {:?}: {e}", meta.dqpn);
+                    }
+                }
+                let accepted = match general {
+                    Some(header) => {
+                        let accepted = self.check_recv_buffer(src_addr, meta);
+                        if accepted {
+                            self.check_access(src_addr, header);
+                        }
+                        accepted
+                    }
+                    None => !starts_message || self.check_send_buffer(src_addr, meta),
+                };
+                // the read request is done once its whole response is received
+                let ends_read_resp = matches!(
+                    meta.opcode,
+                    ToHostWorkRbDescOpcode::RdmaReadResponseLast
+                        | ToHostWorkRbDescOpcode::RdmaReadResponseOnly
+                );
+                if ends_read_resp {
+                    if let Err(e) = self.retransmission.on_read_resp(meta.dqpn) {
+                        log::error!("Failed to release the read request: {e}");
+                    }
+                }
+                accepted
+            }
+            // The sender has not seen the ACK, so it is sent again. The payload has been written, and
+            // writing it again may break the data the host has written since then.
+            // Only the last packet of a message is acknowledged, since the ACK completes the message.
+            Ok(Sequence::Duplicate { last_in_order }) => {
+                log::debug!("{:?} receives a duplicate {:?}", meta.dqpn, meta.psn);
+                // The requester has lost the response of a read request it resends, which is resent
+                // instead of executing the request again. The read responses are never acknowledged.
+                if meta.opcode == ToHostWorkRbDescOpcode::RdmaReadRequest {
+                    match self.retransmission.on_duplicate_read(meta.dqpn, meta.pkey.get()) {
+                        Ok(true) => {}
+                        Ok(false) => log::debug!("the read response of {:?} is not kept", meta.psn),
+                        Err(e) => log::error!("Failed to resend the read response: {e}"),
+                    }
+                    return false;
+                }
+                if meta.opcode.is_read_resp() {
+                    return false;
+                }
+                let ends_message = !matches!(
+                    meta.opcode,
+                    ToHostWorkRbDescOpcode::RdmaWriteFirst
+                        | ToHostWorkRbDescOpcode::RdmaWriteMiddle
+                        | ToHostWorkRbDescOpcode::SendFirst
+                        | ToHostWorkRbDescOpcode::SendMiddle
+                );
+                if !ends_message {
+                    return false;
+                }
+                let ack = ToHostWorkRbDescAethCode::Ack;
+                // there is no receive queue to limit the sender
+                let credit = AETH_CREDIT_INVALID;
+                if let Err(e) = self.send_acknowledge(src_addr, meta, last_in_order, ack, credit) {
+                    log::error!("Failed to send the ACK to {src_addr}: {e}");
+                }
+                false
+            }
+            Ok(Sequence::OutOfOrder {
+                expected,
+                should_nak,
+            }) => {
+                log::debug!("{:?} expects {expected:?}, but {:?} is received", meta.dqpn, meta.psn);
+                if should_nak {
+                    let nak = ToHostWorkRbDescAethCode::Nak;
+                    let value = NAK_PSN_SEQUENCE_ERROR;
+                    if let Err(e) = self.send_acknowledge(src_addr, meta, expected, nak, value) {
+                        log::error!("Failed to send the NAK to {src_addr}: {e}");
+                    }
+                }
+                false
+            }
+            // nothing is known to NAK, the sender resends the message once it times out
+            Ok(Sequence::Unstarted) => {
+                log::debug!("{:?} starts in the middle of a message at {:?}", meta.dqpn, meta.psn);
+                false
+            }
+            Err(e) => {
+                log::error!("Failed to check the PSN: {e}");
+                false
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn recv(&self, src_addr: Ipv4Addr, message: &mut RdmaMessage) {
         let meta = &message.meta_data;
         let mut common = recv_default_meta(message);
         let descriptor = match meta {
@@ -468,8 +1509,11 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                 };
 
                 // Copy the payload to the memory
-                if status.is_ok() && header.has_payload() {
-                    message.payload.copy_to(va as *mut u8);
+                if status.is_ok()
+                    && header.has_payload()
+                    && !self.write_payload(header, &message.payload)
+                {
+                    return;
                 }
 
                 // The default value will not be used since the `write_type` will only appear
@@ -481,7 +1525,7 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                     .unwrap_or(ToHostWorkRbDescWriteType::Only);
 
                 common.status = status;
-                let is_read_resp = header.common_meta.opcode.is_resp();
+                let is_read_resp = header.common_meta.opcode.is_read_resp();
 
                 // Write a descriptor to host
                 match header.common_meta.opcode {
@@ -523,6 +1567,12 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                             log::error!("The secondary reth is not found");
                             return;
                         };
+                        if common.status.is_ok() {
+                            if let Err(e) = self.check_read_overlap(&header.reth, &sec_reth) {
+                                log::error!("{e}");
+                                common.status = ToHostWorkRbDescStatus::InvMrRegion;
+                            }
+                        }
                         ToHostWorkRbDesc::Read(ToHostWorkRbDescRead {
                             common,
                             len: header.reth.len,
@@ -532,29 +1582,34 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                             rkey: sec_reth.rkey.into(),
                         })
                     }
-                    ToHostWorkRbDescOpcode::Acknowledge => {
-                        unimplemented!()
+                    // they are parsed into the other headers
+                    ToHostWorkRbDescOpcode::SendFirst
+                    | ToHostWorkRbDescOpcode::SendMiddle
+                    | ToHostWorkRbDescOpcode::SendLast
+                    | ToHostWorkRbDescOpcode::SendLastWithImmediate
+                    | ToHostWorkRbDescOpcode::SendOnly
+                    | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+                    | ToHostWorkRbDescOpcode::Acknowledge
+                    | ToHostWorkRbDescOpcode::Cnp => {
+                        let opcode = header.common_meta.opcode.clone();
+                        log::error!("{}", BlueRdmaLogicError::UnexpectedOpcode(opcode));
+                        return;
                     }
                 }
             }
-            Metadata::Acknowledge(header) => {
-                common.status = ToHostWorkRbDescStatus::Normal;
-                match header.aeth_code {
-                    ToHostWorkRbDescAethCode::Ack => ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
-                        common,
-                        #[allow(clippy::cast_possible_truncation)]
-                        msn: crate::types::Msn::new(header.msn as u16), // msn is u16 currently. So we can just truncate it.
-                        value: header.aeth_value,
-                        psn: crate::types::Psn::new(header.common_meta.psn.get()),
-                    }),
-                    ToHostWorkRbDescAethCode::Rnr
-                    | ToHostWorkRbDescAethCode::Rsvd
-                    | ToHostWorkRbDescAethCode::Nak => {
-                        // just ignore
-                        unimplemented!()
-                    }
+            Metadata::Acknowledge(header) => match self.recv_acknowledge(header, common) {
+                Ok(Some(descriptor)) => descriptor,
+                Ok(None) => return,
+                Err(e) => {
+                    log::error!("Failed to handle the acknowledge: {e}");
+                    return;
                 }
+            },
+            Metadata::Send(header) => {
+                return self.recv_send(src_addr, header, &message.payload, common)
             }
+            // A CNP is handled by the device itself, the host does not see it
+            Metadata::Cnp(header) => return self.handle_cnp(header.dqpn),
         };
 
         // push the descriptor to the ring buffer
@@ -569,42 +1624,42 @@ mod tests {
     use crate::{
         device::{
             software::{
-                net_agent::{NetAgentError, NetSendAgent},
-                types::{Key, PayloadInfo, Qpn, RdmaMessage},
+                net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
+                types::{
+                    Key, Metadata, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+                    RdmaMessageMetaCommon, RethHeader,
+                },
             },
             ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement,
-            ToCardCtrlRbDescUpdateMrTable,
+            ToCardCtrlRbDescSge, ToCardCtrlRbDescUpdateMrTable, ToHostWorkRbDesc, ToHostWorkRbDescOpcode,
+            ToHostWorkRbDescStatus, ToHostWorkRbDescTransType,
         },
-        types::{MemAccessTypeFlag, Pmtu, QpType},
+        types::{MemAccessTypeFlag, Pmtu, Psn, QpType},
     };
 
-    use super::BlueRDMALogic;
+    use super::{BlueRDMALogic, BlueRdmaLogicError, ReadOverlapPolicy, SGList};
+
+    #[derive(Debug)]
+    struct DummpyProxy;
+
+    impl NetSendAgent for DummpyProxy {
+        fn send(&self, _: Ipv4Addr, _: u16, _message: &RdmaMessage) -> Result<(), NetAgentError> {
+            Ok(())
+        }
+
+        fn send_raw(
+            &self,
+            _: Ipv4Addr,
+            _: u16,
+            _payload: &PayloadInfo,
+        ) -> Result<(), NetAgentError> {
+            Ok(())
+        }
+    }
 
     // test update mr table, qp table
     #[test]
     fn test_logic_update() {
-        #[derive(Debug)]
-        struct DummpyProxy;
-
-        impl NetSendAgent for DummpyProxy {
-            fn send(
-                &self,
-                _: Ipv4Addr,
-                _: u16,
-                _message: &RdmaMessage,
-            ) -> Result<(), NetAgentError> {
-                Ok(())
-            }
-
-            fn send_raw(
-                &self,
-                _: Ipv4Addr,
-                _: u16,
-                _payload: &PayloadInfo,
-            ) -> Result<(), NetAgentError> {
-                Ok(())
-            }
-        }
         let agent = Arc::new(DummpyProxy);
         let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent));
         // test updating qp
@@ -617,6 +1672,8 @@ mod tests {
                 qp_type: QpType::Rc,
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
                 pmtu: Pmtu::Mtu1024,
+                rnr_retry: 0,
+                min_rnr_timer: 0,
             });
             logic.update(desc).unwrap();
             {
@@ -639,6 +1696,8 @@ mod tests {
                 qp_type: QpType::Rc,
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
                 pmtu: Pmtu::Mtu2048,
+                rnr_retry: 0,
+                min_rnr_timer: 0,
             });
             logic.update(desc).unwrap();
             {
@@ -658,6 +1717,7 @@ mod tests {
             let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
                 common: ToCardCtrlRbDescCommon { op_id: 0 },
                 addr: 0x1234567812345678,
+                va: 0x1234567812345678,
                 len: 1024 * 16,
                 key: crate::types::Key::new(1234),
                 pd_hdl: 0,
@@ -682,6 +1742,7 @@ mod tests {
             let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
                 common: ToCardCtrlRbDescCommon { op_id: 0 },
                 addr: 0x1234567812345678,
+                va: 0x1234567812345678,
                 len: 1024 * 24,
                 key: crate::types::Key::new(1234),
                 pd_hdl: 0,
@@ -707,4 +1768,202 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_translate_sg_list() {
+        let logic = BlueRDMALogic::new(Arc::new(DummpyProxy));
+        let buf = [0u8; 64];
+        let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
+            common: ToCardCtrlRbDescCommon { op_id: 0 },
+            addr: 0x1000,
+            va: buf.as_ptr() as u64,
+            len: 64,
+            key: crate::types::Key::new(7),
+            pd_hdl: 0,
+            acc_flags: MemAccessTypeFlag::IbvAccessLocalWrite,
+            pgt_offset: 0,
+        });
+        logic.update(desc).unwrap();
+        let sg_list_with_key = |addr: u64, len: u32, key: crate::types::Key| {
+            SGList::new_with_sge(ToCardCtrlRbDescSge { addr, len, key })
+        };
+        let sg_list_of = |addr: u64, len: u32| sg_list_with_key(addr, len, crate::types::Key::new(7));
+
+        // the sge points to the memory of the MR
+        let mut sg_list = sg_list_of(0x1010, 48);
+        logic.translate_sg_list(&mut sg_list).unwrap();
+        assert_eq!(sg_list.data[0].addr, buf[16..].as_ptr() as u64);
+
+        // an sge running past the end of the MR is rejected
+        let mut sg_list = sg_list_of(0x1010, 49);
+        assert!(matches!(
+            logic.translate_sg_list(&mut sg_list),
+            Err(BlueRdmaLogicError::SgeOutOfMr(_, 0x1010, 49))
+        ));
+
+        // a key with the index of the MR but another secret is rejected
+        let forged = crate::types::Key::from_index_secret(0, 8);
+        let mut sg_list = sg_list_with_key(0x1010, 48, forged);
+        assert!(matches!(
+            logic.translate_sg_list(&mut sg_list),
+            Err(BlueRdmaLogicError::InvalidKey(key)) if key == forged.into()
+        ));
+        // and so is a key of an empty index
+        let empty = crate::types::Key::from_index_secret(1, 7);
+        let mut sg_list = sg_list_with_key(0x1010, 48, empty);
+        assert!(matches!(
+            logic.translate_sg_list(&mut sg_list),
+            Err(BlueRdmaLogicError::InvalidKey(key)) if key == empty.into()
+        ));
+        // while the zero key stands for no MR and keeps its address
+        let mut sg_list = sg_list_with_key(0x1010, 48, crate::types::Key::default());
+        logic.translate_sg_list(&mut sg_list).unwrap();
+        assert_eq!(sg_list.data[0].addr, 0x1010);
+    }
+
+    /// Receive the first packet of a 4096 bytes write to 100 bytes after a boundary of pmtu 1024,
+    /// which carries `len` bytes to a QP of pmtu 1024. Returns whether the payload is written.
+    fn recv_first_write(len: usize) -> bool {
+        let logic = BlueRDMALogic::new(Arc::new(DummpyProxy));
+        let buf = vec![0u8; 8192];
+        logic
+            .update(ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
+                common: ToCardCtrlRbDescCommon { op_id: 0 },
+                addr: buf.as_ptr() as u64,
+                va: buf.as_ptr() as u64,
+                len: buf.len() as u32,
+                key: crate::types::Key::new(1234),
+                pd_hdl: 0,
+                acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
+                pgt_offset: 0,
+            }))
+            .unwrap();
+        logic
+            .update(ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
+                common: ToCardCtrlRbDescCommon { op_id: 0 },
+                is_valid: true,
+                qpn: crate::Qpn::new(3),
+                pd_hdl: 0,
+                qp_type: QpType::Rc,
+                rq_acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
+                pmtu: Pmtu::Mtu1024,
+                rnr_retry: 0,
+                min_rnr_timer: 0,
+            }))
+            .unwrap();
+        let data = vec![0xa5_u8; len];
+        let mut message = RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::RdmaWriteFirst,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(0),
+                },
+                reth: RethHeader {
+                    va: ((buf.as_ptr() as u64 + 1023) & !1023) + 100,
+                    rkey: Key::new(1234),
+                    len: 4096,
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload: PayloadInfo::new_with_data(data.as_ptr(), len),
+        };
+        logic.recv(Ipv4Addr::LOCALHOST, &mut message);
+        let written = buf.iter().filter(|byte| **byte == 0xa5).count();
+        let reported = matches!(
+            logic.get_to_host_descriptor_queue().pop(),
+            Some(ToHostWorkRbDesc::WriteOrReadResp(_))
+        );
+        assert_eq!(reported, written != 0);
+        written == len
+    }
+
+    #[test]
+    fn test_recv_first_packet_len() {
+        // the first packet ends at the pmtu boundary after the address
+        assert!(recv_first_write(924));
+        // or at the boundary of a smaller pmtu the sender downgrades to
+        assert!(recv_first_write(412));
+        assert!(recv_first_write(156));
+        // a short or a long first packet is dropped, as well as one of a larger pmtu than the QP's
+        assert!(!recv_first_write(900));
+        assert!(!recv_first_write(1024));
+        assert!(!recv_first_write(2048 - 100));
+    }
+
+    fn recv_overlapped_read(policy: ReadOverlapPolicy, buf: &[u8]) -> ToHostWorkRbDescStatus {
+        let logic = BlueRDMALogic::new(Arc::new(DummpyProxy));
+        logic.set_read_overlap_policy(policy);
+        let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
+            common: ToCardCtrlRbDescCommon { op_id: 0 },
+            addr: buf.as_ptr() as u64,
+            va: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            key: crate::types::Key::new(1234),
+            pd_hdl: 0,
+            acc_flags: MemAccessTypeFlag::IbvAccessRemoteRead,
+            pgt_offset: 0,
+        });
+        logic.update(desc).unwrap();
+
+        // the sink starts in the middle of the source
+        let mut message = RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::RdmaReadRequest,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(0),
+                },
+                reth: RethHeader {
+                    va: buf.as_ptr() as u64,
+                    rkey: Key::new(1234),
+                    len: 32,
+                },
+                imm: None,
+                secondary_reth: Some(RethHeader {
+                    va: buf.as_ptr() as u64 + 16,
+                    rkey: Key::new(1234),
+                    len: 32,
+                }),
+            }),
+            payload: PayloadInfo::new(),
+        };
+        logic.recv(Ipv4Addr::LOCALHOST, &mut message);
+        let Some(ToHostWorkRbDesc::Read(read)) = logic.get_to_host_descriptor_queue().pop() else {
+            panic!("expect a read descriptor");
+        };
+        read.common.status
+    }
+
+    #[test]
+    fn test_read_overlap_memmove() {
+        let mut buf: Vec<u8> = (0..64).collect();
+        let status = recv_overlapped_read(ReadOverlapPolicy::Memmove, &buf);
+        assert!(status.is_ok(), "overlapping read should be accepted");
+
+        // the read response is copied with memmove semantics
+        let payload = PayloadInfo::new_with_data(buf.as_ptr(), 32);
+        payload.copy_to(buf[16..].as_mut_ptr());
+        let expected: Vec<u8> = (0..16).chain(0..32).chain(48..64).collect();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_read_overlap_reject() {
+        let buf = vec![0u8; 64];
+        let status = recv_overlapped_read(ReadOverlapPolicy::Reject, &buf);
+        assert!(
+            matches!(status, ToHostWorkRbDescStatus::InvMrRegion),
+            "overlapping read should be rejected"
+        );
+    }
 }
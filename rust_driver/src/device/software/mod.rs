@@ -1,32 +1,61 @@
 use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
     error::Error,
-    net::Ipv4Addr,
-    sync::{Arc, atomic::{AtomicBool, Ordering}},
-    thread::{sleep, spawn, JoinHandle},
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    os::fd::OwnedFd,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use crossbeam_queue::SegQueue;
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use eui48::MacAddress;
 use log::debug;
+use socket2::Socket;
 
 use self::{
-    logic::{BlueRDMALogic, BlueRdmaLogicError},
-    net_agent::udp_agent::{UDPReceiveAgent, UDPSendAgent},
+    logic::{BlueRDMALogic, BlueRdmaLogicError, ToHostQueue},
+    net_agent::{
+        fault_agent::{FaultySendAgent, PayloadCorrupter},
+        loopback_agent::{LoopbackReceiveAgent, LoopbackSendAgent},
+        udp_agent::{UDPReceiveAgent, UDPSendAgent},
+        NetSendAgent,
+    },
+    types::{Key, Qpn, SGListElementWithKey},
 };
 
 use super::{
-    scheduler::{round_robin::RoundRobinStrategy, DescriptorScheduler},
-    DeviceAdaptor, DeviceError, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc, ToHostCtrlRbDesc,
-    ToHostRb, ToHostWorkRbDesc,
+    scheduler::{DescriptorScheduler, SchedulerStrategy},
+    DeviceAdaptor, DeviceError, PhysAddrResolver, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc,
+    ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescOpcode,
+};
+use crate::{
+    types::{EcnCodepoint, ReadOverlapPolicy, Sge, UdpChecksumMode, PAGE_SIZE},
+    utils::stop_thread,
 };
 
+/// sends for the net agent benchmarks
+#[cfg(feature = "bench")]
+pub mod bench;
+mod congestion;
 mod logic;
 mod net_agent;
 mod packet;
 mod packet_processor;
+mod retransmission;
 #[cfg(test)]
 pub(crate) mod tests;
 mod types;
 
+pub use net_agent::{fault_agent::FaultInjector, loopback_agent::MemoryFabric};
+pub use packet_processor::IcrcConfig;
+pub(crate) use net_agent::{udp_agent::NET_SERVER_BUF_SIZE, FrameChecks};
+
+/// The smallest page size of a MR. The software device never walks the page table, so any
+/// power of two from here up to `PAGE_SIZE` works.
+const MIN_PAGE_SIZE: usize = 4096;
+
 /// An software device implementation of the device.
 ///
 /// # Examples:
@@ -42,73 +71,438 @@ mod types;
 #[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) struct SoftwareDevice {
-    recv_agent: UDPReceiveAgent,
+    net_agents: NetAgents,
     device: Arc<BlueRDMALogic>,
     stop_flag : Arc<AtomicBool>,
-    polling_thread: Option<JoinHandle<()>>,
+    polling_thread: Mutex<Option<JoinHandle<()>>>,
+    /// The threads sending the descriptors popped by the polling thread, if there are more than one
+    send_workers: Mutex<Vec<JoinHandle<()>>>,
     to_card_work_rb: ToCardWorkRb,
     to_host_work_rb: ToHostWorkRb,
 }
 
+/// The net agents a software device sends and receives the packets with
+#[derive(Debug)]
+enum NetAgents {
+    /// Raw sockets, which need `CAP_NET_RAW`
+    Udp {
+        recv_agent: UDPReceiveAgent,
+        send_agent: Arc<UDPSendAgent>,
+        /// The agents receiving the packets of the joined multicast groups
+        mcast_agents: Mutex<HashMap<Ipv4Addr, UDPReceiveAgent>>,
+        addr: Ipv4Addr,
+        port: u16,
+        /// The checks of the received frames, which the multicast agents make as well
+        checks: FrameChecks,
+    },
+    /// The in-process network, see `MemoryFabric`
+    Loopback {
+        recv_agent: LoopbackReceiveAgent,
+        send_agent: Arc<LoopbackSendAgent>,
+    },
+}
+
+impl NetAgents {
+    /// Stop receiving, including the packets of the joined multicast groups.
+    fn stop(&self) -> Result<(), DeviceError> {
+        match self {
+            Self::Udp {
+                recv_agent,
+                mcast_agents,
+                ..
+            } => {
+                let mcast_agents = std::mem::take(
+                    &mut *mcast_agents
+                        .lock()
+                        .map_err(|_| DeviceError::LockPoisoned("multicast agents lock".to_owned()))?,
+                );
+                for agent in mcast_agents.values() {
+                    agent.stop().map_err(|e| DeviceError::Device(e.to_string()))?;
+                }
+                recv_agent.stop()
+            }
+            Self::Loopback { recv_agent, .. } => recv_agent.stop(),
+        }
+        .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+}
+
+/// The raw sockets of a software device opened by another process, see `Transport::SoftwareSockets`
+#[derive(Debug)]
+pub(crate) struct UdpSockets {
+    /// The socket sending the packets, an IPv4 raw socket of UDP with `IP_HDRINCL` set
+    pub(crate) send: Socket,
+    /// The socket receiving the packets, an IPv4 raw socket of UDP bound to the device address
+    pub(crate) recv: Socket,
+}
+
+impl UdpSockets {
+    /// Duplicate the fds of the sockets, leaving the original ones to the caller.
+    pub(crate) fn dup(send: &OwnedFd, recv: &OwnedFd) -> io::Result<Self> {
+        Ok(Self {
+            send: send.try_clone()?.into(),
+            recv: recv.try_clone()?.into(),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 struct ToCardWorkRb(Arc<DescriptorScheduler>);
 
 #[derive(Debug, Clone)]
-struct ToHostWorkRb(Arc<SegQueue<ToHostWorkRbDesc>>);
+struct ToHostWorkRb(Arc<ToHostQueue<ToHostWorkRbDesc>>);
 
 impl SoftwareDevice {
     /// Initializing an software device.
-    pub(crate) fn init(addr: Ipv4Addr, port: u16) -> Result<Self, Box<dyn Error>> {
-        let send_agent = UDPSendAgent::new(addr, port)?;
-        let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
-        // The strategy is a global singleton, so we leak it
-        let round_robin = Arc::new(RoundRobinStrategy::new());
-        let scheduler = DescriptorScheduler::new(round_robin);
+    #[cfg(test)]
+    pub(crate) fn init(
+        addr: Ipv4Addr,
+        port: u16,
+        strategy: Arc<dyn SchedulerStrategy>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::init_with_options(
+            SocketAddrV4::new(addr, port),
+            None,
+            strategy,
+            1,
+            NET_SERVER_BUF_SIZE,
+            None,
+            true,
+            FrameChecks::default(),
+        )
+    }
+
+    /// Initializing an software device which sends the scheduled descriptors with `send_workers`
+    /// threads, and receives the packets into a buffer of `recv_buf_size` bytes.
+    ///
+    /// The QPs are sharded over the workers by their qpn, so the descriptors of a QP are sent by the
+    /// same worker in the order they are scheduled. A `send_workers` of 0 is treated as 1.
+    /// The sent packets suffer the faults of `faults`, and the lost ones are resent if
+    /// `retransmission` is set. The received frames go through the checks of `checks`, and the sent
+    /// ones carry the ICRC computed with the config of it.
+    ///
+    /// The device opens its raw sockets on `local`, unless `sockets` are passed in.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn init_with_options(
+        local: SocketAddrV4,
+        sockets: Option<UdpSockets>,
+        strategy: Arc<dyn SchedulerStrategy>,
+        send_workers: usize,
+        recv_buf_size: usize,
+        faults: Option<FaultInjector>,
+        retransmission: bool,
+        checks: FrameChecks,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (addr, port) = (*local.ip(), local.port());
+        let (mut send_agent, recv_socket) = match sockets {
+            Some(UdpSockets { send, recv }) => {
+                (UDPSendAgent::from_socket(send, addr, port)?, Some(recv))
+            }
+            None => (UDPSendAgent::new(addr, port)?, None),
+        };
+        send_agent.corrupter = faults.as_ref().and_then(PayloadCorrupter::new);
+        send_agent.icrc = checks.icrc;
+        let send_agent = Arc::new(send_agent);
+        let device = new_logic(
+            addr,
+            Arc::<UDPSendAgent>::clone(&send_agent),
+            faults,
+            retransmission,
+        );
+        let receiver = Arc::<BlueRDMALogic>::clone(&device);
+        let recv_agent = match recv_socket {
+            Some(socket) => {
+                UDPReceiveAgent::from_socket(receiver, socket, recv_buf_size, None, checks)?
+            }
+            None => UDPReceiveAgent::with_options(
+                receiver,
+                addr,
+                port,
+                recv_buf_size,
+                None,
+                None,
+                checks,
+            )?,
+        };
+        let net_agents = NetAgents::Udp {
+            recv_agent,
+            send_agent,
+            mcast_agents: Mutex::new(HashMap::new()),
+            addr,
+            port,
+            checks,
+        };
+        Ok(Self::with_logic(device, net_agents, strategy, send_workers))
+    }
+
+    /// Initializing a software device on the in-process network.
+    ///
+    /// The device only talks to the loopback devices in the same process, without any socket, so it
+    /// needs no privilege.
+    #[cfg(test)]
+    pub(crate) fn init_loopback(
+        addr: Ipv4Addr,
+        port: u16,
+        strategy: Arc<dyn SchedulerStrategy>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let fabric = Arc::clone(MemoryFabric::global());
+        Self::init_on_fabric(
+            fabric,
+            addr,
+            MacAddress::nil(),
+            port,
+            strategy,
+            None,
+            true,
+            FrameChecks::default(),
+        )
+    }
+
+    /// Initializing a software device on `fabric`, registered with `addr` and `mac`.
+    ///
+    /// The device only talks to the devices on the same fabric, without any socket, so it needs no
+    /// privilege. `faults`, `retransmission` and `checks` are the ones of `init_with_options`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn init_on_fabric(
+        fabric: Arc<MemoryFabric>,
+        addr: Ipv4Addr,
+        mac: MacAddress,
+        port: u16,
+        strategy: Arc<dyn SchedulerStrategy>,
+        faults: Option<FaultInjector>,
+        retransmission: bool,
+        checks: FrameChecks,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut send_agent = LoopbackSendAgent::on_fabric(Arc::clone(&fabric), addr, port);
+        send_agent.corrupter = faults.as_ref().and_then(PayloadCorrupter::new);
+        send_agent.icrc = checks.icrc;
+        let send_agent = Arc::new(send_agent);
+        let device = new_logic(
+            addr,
+            Arc::<LoopbackSendAgent>::clone(&send_agent),
+            faults,
+            retransmission,
+        );
+        let recv_agent = LoopbackReceiveAgent::on_fabric(
+            fabric,
+            Arc::<BlueRDMALogic>::clone(&device),
+            addr,
+            mac,
+            checks,
+        )?;
+        let net_agents = NetAgents::Loopback {
+            recv_agent,
+            send_agent,
+        };
+        Ok(Self::with_logic(device, net_agents, strategy, 1))
+    }
+
+    /// Start the thread that passes the scheduled descriptors to `device`, through `send_workers`
+    /// threads if there are more than one.
+    fn with_logic(
+        device: Arc<BlueRDMALogic>,
+        net_agents: NetAgents,
+        strategy: Arc<dyn SchedulerStrategy>,
+        send_workers: usize,
+    ) -> Self {
+        let scheduler = DescriptorScheduler::new(strategy);
         let scheduler = Arc::new(scheduler);
         let to_host_queue = device.get_to_host_descriptor_queue();
-        let recv_agent = UDPReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&device), addr, port)?;
 
         let this_scheduler = Arc::<DescriptorScheduler>::clone(&scheduler);
         let this_device = Arc::<BlueRDMALogic>::clone(&device);
 
         let stop_flag = Arc::new(AtomicBool::new(false));
         let thread_stop_flag = Arc::clone(&stop_flag);
+        let (worker_senders, send_workers) = spawn_send_workers(&device, send_workers);
 
-        let polling_thread = spawn(move || 
+        let polling_thread = spawn(move || {
+            let mut paced = PacedSends::default();
             while !thread_stop_flag.load(Ordering::Relaxed) {
-            match this_scheduler.pop() {
-                Ok(result) => {
-                    if let Some(to_card_ctrl_rb_desc) = result {
-                        let _: Result<(), BlueRdmaLogicError> =
-                            this_device.send(to_card_ctrl_rb_desc);
+                paced.send_ready(&this_device);
+                // the lost descriptors are scheduled again as the new ones
+                while let Some(desc) = this_device.retransmission().pop_resend() {
+                    if let Err(e) = this_scheduler.push(desc) {
+                        log::error!("failed to schedule the resent descriptor: {e:?}");
+                    }
+                }
+                while let Some((qpn, credits)) = this_device.pop_credit_update() {
+                    let qpn = crate::types::Qpn::new(qpn.get());
+                    if let Err(e) = this_scheduler.update_credits(qpn, credits) {
+                        log::error!("failed to update the credits of {qpn:?}: {e:?}");
                     }
                 }
-                Err(e) => {
-                    log::error!("polling scheduler thread error: {:?}", e);
+                match this_scheduler.pop() {
+                    Ok(None) => {
+                        if let Err(e) = this_device.flush_send_agent() {
+                            log::error!("failed to send the held packets: {e:?}");
+                        }
+                    }
+                    Ok(result) => {
+                        if let Some(to_card_ctrl_rb_desc) = result {
+                            if worker_senders.is_empty() {
+                                paced.send_or_hold(&this_device, to_card_ctrl_rb_desc);
+                            } else {
+                                // the descriptors of a QP always go to the same worker, which keeps their order
+                                let shard = usize::try_from(to_card_ctrl_rb_desc.common().dqpn.get())
+                                    .unwrap_or_default()
+                                    .checked_rem(worker_senders.len())
+                                    .unwrap_or_default();
+                                if let Some(sender) = worker_senders.get(shard) {
+                                    if sender.send(to_card_ctrl_rb_desc).is_err() {
+                                        log::error!("the send worker {shard} has exited");
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("polling scheduler thread error: {:?}", e);
+                    }
                 }
             }
         });
         let to_card_work_rb = ToCardWorkRb(scheduler);
-        Ok(Self {
-            recv_agent,
-            polling_thread : Some(polling_thread),
+        Self {
+            net_agents,
+            polling_thread : Mutex::new(Some(polling_thread)),
+            send_workers: Mutex::new(send_workers),
             device,
             to_card_work_rb,
             to_host_work_rb: ToHostWorkRb(to_host_queue),
             stop_flag
-        })
+        }
+    }
+
+    fn udp_send_agent(&self) -> Result<&UDPSendAgent, DeviceError> {
+        match &self.net_agents {
+            NetAgents::Udp { send_agent, .. } => Ok(send_agent),
+            NetAgents::Loopback { .. } => Err(DeviceError::Device(
+                "the loopback device does not build the IP header".to_owned(),
+            )),
+        }
     }
 }
 
 impl Drop for SoftwareDevice {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) = self.polling_thread.take() {
-            if let Err(e) = thread.join() {
-                log::error!("Failed to join the polling thread: {:?}", e);
+        if let Err(e) = self.shutdown() {
+            log::error!("Failed to shut down the software device: {e:?}");
+        }
+    }
+}
+
+/// Create the logic of the device at `addr`, sending the packets through `send_agent`, which suffer
+/// the faults of `faults`. The lost packets are resent if `retransmission` is set.
+///
+/// The RC writes of the device to itself go through `send_agent` as well, until
+/// `BlueRDMALogic::set_local_copy` enables copying them directly.
+fn new_logic(
+    addr: Ipv4Addr,
+    send_agent: Arc<dyn NetSendAgent + Send + Sync>,
+    faults: Option<FaultInjector>,
+    retransmission: bool,
+) -> Arc<BlueRDMALogic> {
+    let send_agent: Arc<dyn NetSendAgent> = match faults {
+        Some(faults) => Arc::new(FaultySendAgent::new(send_agent, faults)),
+        None => send_agent,
+    };
+    let mut logic = BlueRDMALogic::new(send_agent);
+    logic.set_retransmission(retransmission);
+    logic.set_local_addr(addr);
+    Arc::new(logic)
+}
+
+/// The scheduled descriptors held back by the congestion control of their QPs, owned by a sending
+/// thread.
+///
+/// A slowed down QP does not block the sending thread. Its descriptors wait here in order, while the
+/// descriptors of the other QPs are sent.
+#[derive(Debug, Default)]
+struct PacedSends {
+    held: HashMap<Qpn, VecDeque<(Instant, ToCardWorkRbDesc)>>,
+}
+
+impl PacedSends {
+    /// Send `desc` now if its QP is not slowed down, or hold it back until it can be sent.
+    fn send_or_hold(&mut self, device: &BlueRDMALogic, desc: ToCardWorkRbDesc) {
+        let common = desc.common();
+        let qpn = Qpn::new(common.dqpn.get());
+        let send_at = device
+            .congestion_control()
+            .pace(qpn, common.total_len)
+            .unwrap_or_else(|e| {
+                log::error!("failed to pace the descriptor: {e:?}");
+                Instant::now()
+            });
+        match self.held.entry(qpn) {
+            // the descriptors of a QP are never reordered
+            Entry::Occupied(mut held) => held.get_mut().push_back((send_at, desc)),
+            Entry::Vacant(_) if send_at <= Instant::now() => send_desc(device, desc),
+            Entry::Vacant(held) => {
+                let _: &mut VecDeque<_> = held.insert(VecDeque::from([(send_at, desc)]));
             }
         }
     }
+
+    /// Send the held descriptors that can be sent now.
+    fn send_ready(&mut self, device: &BlueRDMALogic) {
+        let now = Instant::now();
+        self.held.retain(|_, held| {
+            while held.front().is_some_and(|(send_at, _)| *send_at <= now) {
+                if let Some((_, desc)) = held.pop_front() {
+                    send_desc(device, desc);
+                }
+            }
+            !held.is_empty()
+        });
+    }
+
+    /// When the next held descriptor can be sent, if there is one.
+    fn next_send_at(&self) -> Option<Instant> {
+        self.held
+            .values()
+            .filter_map(|held| held.front().map(|(send_at, _)| *send_at))
+            .min()
+    }
+}
+
+fn send_desc(device: &BlueRDMALogic, desc: ToCardWorkRbDesc) {
+    let _: Result<(), BlueRdmaLogicError> = device.send(desc);
+}
+
+/// Start `count` threads sending the descriptors passed to their channels.
+///
+/// No thread is started for a `count` of 0 or 1, the polling thread sends the descriptors by itself.
+fn spawn_send_workers(
+    device: &Arc<BlueRDMALogic>,
+    count: usize,
+) -> (Vec<Sender<ToCardWorkRbDesc>>, Vec<JoinHandle<()>>) {
+    if count <= 1 {
+        return (Vec::new(), Vec::new());
+    }
+    (0..count)
+        .map(|_| {
+            let (sender, receiver) = crossbeam_channel::unbounded::<ToCardWorkRbDesc>();
+            let device = Arc::<BlueRDMALogic>::clone(device);
+            let thread = spawn(move || {
+                let mut paced = PacedSends::default();
+                loop {
+                    paced.send_ready(&device);
+                    let next = match paced.next_send_at() {
+                        Some(send_at) => receiver.recv_deadline(send_at),
+                        None => receiver.recv().map_err(RecvTimeoutError::from),
+                    };
+                    match next {
+                        Ok(desc) => paced.send_or_hold(&device, desc),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            });
+            (sender, thread)
+        })
+        .unzip()
 }
 
 impl DeviceAdaptor for SoftwareDevice {
@@ -136,6 +530,180 @@ impl DeviceAdaptor for SoftwareDevice {
         todo!()
     }
 
+    fn requires_hugepages(&self) -> bool {
+        false
+    }
+
+    fn opcode_counters(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        match &self.net_agents {
+            NetAgents::Udp { recv_agent, .. } => recv_agent.opcode_counters(),
+            NetAgents::Loopback { recv_agent, .. } => recv_agent.opcode_counters(),
+        }
+    }
+
+    fn set_traffic_class(&self, dscp: u8, ecn: EcnCodepoint) -> Result<(), DeviceError> {
+        match &self.net_agents {
+            NetAgents::Udp { send_agent, .. } => send_agent.set_traffic_class(dscp, ecn),
+            NetAgents::Loopback { send_agent, .. } => send_agent.set_traffic_class(dscp, ecn),
+        }
+        Ok(())
+    }
+
+    fn set_udp_checksum_mode(&self, mode: UdpChecksumMode) -> Result<(), DeviceError> {
+        if mode == UdpChecksumMode::Passthrough {
+            return Err(DeviceError::Device(
+                "no NIC fills the UDP checksum of the software device".to_owned(),
+            ));
+        }
+        self.udp_send_agent()?.set_udp_checksum_mode(mode);
+        Ok(())
+    }
+
+    fn set_link_mtu(&self, link_mtu: Option<usize>) -> Result<(), DeviceError> {
+        self.udp_send_agent()?
+            .set_link_mtu(link_mtu)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn set_read_overlap_policy(&self, policy: ReadOverlapPolicy) -> Result<(), DeviceError> {
+        self.device.set_read_overlap_policy(policy);
+        Ok(())
+    }
+
+    fn set_local_copy(&self, enabled: bool) -> Result<(), DeviceError> {
+        self.device.set_local_copy(enabled);
+        Ok(())
+    }
+
+    fn set_vlan(
+        &self,
+        ifname: &str,
+        vid: u16,
+        pcp: u8,
+        src_mac: MacAddress,
+        dest_mac: MacAddress,
+    ) -> Result<(), DeviceError> {
+        self.udp_send_agent()?
+            .set_vlan(ifname, vid, pcp, src_mac, dest_mac)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn set_peer_qpn(
+        &self,
+        qpn: crate::types::Qpn,
+        peer_qpn: crate::types::Qpn,
+    ) -> Result<(), DeviceError> {
+        self.device
+            .set_peer_qpn(Qpn::new(qpn.get()), Qpn::new(peer_qpn.get()))
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn join_multicast(&self, group: Ipv4Addr) -> Result<(), DeviceError> {
+        let NetAgents::Udp {
+            mcast_agents,
+            addr,
+            port,
+            checks,
+            ..
+        } = &self.net_agents
+        else {
+            return Err(DeviceError::Device(
+                "the loopback device does not support multicast".to_owned(),
+            ));
+        };
+        let mut mcast_agents = mcast_agents
+            .lock()
+            .map_err(|_| DeviceError::LockPoisoned("multicast agents lock".to_owned()))?;
+        if let Entry::Vacant(entry) = mcast_agents.entry(group) {
+            let agent = UDPReceiveAgent::with_multicast_group(
+                Arc::<BlueRDMALogic>::clone(&self.device),
+                group,
+                *addr,
+                *port,
+                None,
+                *checks,
+            )
+            .map_err(|e| DeviceError::Device(e.to_string()))?;
+            let _: &mut UDPReceiveAgent = entry.insert(agent);
+        }
+        Ok(())
+    }
+
+    fn leave_multicast(&self, group: Ipv4Addr) -> Result<(), DeviceError> {
+        let NetAgents::Udp { mcast_agents, .. } = &self.net_agents else {
+            return Err(DeviceError::Device(
+                "the loopback device does not support multicast".to_owned(),
+            ));
+        };
+        // dropping the agent leaves the group
+        let _: Option<UDPReceiveAgent> = mcast_agents
+            .lock()
+            .map_err(|_| DeviceError::LockPoisoned("multicast agents lock".to_owned()))?
+            .remove(&group);
+        Ok(())
+    }
+
+    fn create_srq(&self, srqn: u32) -> Result<(), DeviceError> {
+        self.device
+            .create_srq(srqn)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn destroy_srq(&self, srqn: u32) -> Result<(), DeviceError> {
+        self.device
+            .destroy_srq(srqn)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn attach_srq(&self, qpn: crate::types::Qpn, srqn: u32) -> Result<(), DeviceError> {
+        self.device
+            .attach_srq(Qpn::new(qpn.get()), srqn)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn post_srq_recv(&self, srqn: u32, sges: &[Sge]) -> Result<(), DeviceError> {
+        let sges: Vec<_> = sges
+            .iter()
+            .map(|sge| SGListElementWithKey {
+                addr: sge.addr,
+                len: sge.len,
+                key: Key::new(sge.key.get()),
+            })
+            .collect();
+        self.device
+            .post_srq_recv(srqn, &sges)
+            .map_err(|e| DeviceError::Device(e.to_string()))
+    }
+
+    fn shutdown(&self) -> Result<(), DeviceError> {
+        let mut result = Ok(());
+        if !stop_thread(&self.stop_flag, &self.polling_thread) {
+            result = Err(DeviceError::Device("failed to join the polling thread".to_owned()));
+        }
+        // the workers exit once the polling thread drops their channels
+        let send_workers = std::mem::take(
+            &mut *self
+                .send_workers
+                .lock()
+                .map_err(|_| DeviceError::LockPoisoned("send workers lock".to_owned()))?,
+        );
+        for thread in send_workers {
+            if thread.join().is_err() {
+                result = Err(DeviceError::Device("failed to join a send worker".to_owned()));
+            }
+        }
+        self.net_agents.stop()?;
+        result
+    }
+
+    fn is_page_size_supported(&self, pg_size: u32) -> bool {
+        usize::try_from(pg_size).is_ok_and(|size| {
+            size.is_power_of_two() && (MIN_PAGE_SIZE..=PAGE_SIZE).contains(&size)
+        })
+    }
+}
+
+impl PhysAddrResolver for SoftwareDevice {
     fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
         Ok(virt_addr)
     }
@@ -150,24 +718,14 @@ impl ToCardRb<ToCardCtrlRbDesc> for BlueRDMALogic {
 }
 
 impl ToHostRb<ToHostCtrlRbDesc> for BlueRDMALogic {
-    fn pop(&self) -> Result<ToHostCtrlRbDesc, DeviceError> {
-        loop {
-            if let Some(desc) = self.get_update_result() {
-                return Ok(desc);
-            }
-            sleep(std::time::Duration::from_millis(1));
-        }
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostCtrlRbDesc>, DeviceError> {
+        Ok(self.get_update_result(timeout))
     }
 }
 
 impl ToHostRb<ToHostWorkRbDesc> for ToHostWorkRb {
-    fn pop(&self) -> Result<ToHostWorkRbDesc, DeviceError> {
-        loop {
-            if let Some(desc) = self.0.pop() {
-                return Ok(desc);
-            }
-            sleep(std::time::Duration::from_millis(1));
-        }
+    fn pop(&self, timeout: Duration) -> Result<Option<ToHostWorkRbDesc>, DeviceError> {
+        Ok(self.0.pop_timeout(timeout))
     }
 }
 
@@ -0,0 +1,529 @@
+use std::{
+    collections::VecDeque,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+};
+
+use log::debug;
+use rand::{rngs::StdRng, seq::index, Rng as _, SeedableRng as _};
+
+use crate::device::software::{
+    packet::ICRC_SIZE,
+    types::{Metadata, PayloadInfo, RdmaMessage},
+};
+
+use super::{NetAgentError, NetSendAgent};
+
+/// The faults a software device injects into the packets it sends, to exercise the loss recovery
+/// of the RC QPs.
+///
+/// Every packet is dropped with the probability `drop_prob`. The packets left are held back in a
+/// window of `reorder_window` packets, from which a random one is sent once the window is full, so
+/// a packet may overtake up to `reorder_window` packets before it. The held packets are sent in
+/// order once the device has nothing else to send.
+///
+/// With `with_corruption`, the payload of a packet is corrupted with the probability `corrupt_prob`
+/// after the packet is framed, by flipping a bit in `corrupt_bytes` of its bytes. The ICRC no longer
+/// matches, so the receiver drops the packet as if it was lost.
+///
+/// The faults are drawn from random generators seeded with `seed`, so a run can be reproduced.
+///
+/// The fields are private, so a probability is always clamped by the constructors.
+///
+/// ```rust,ignore
+/// let device = DeviceBuilder::new(&network)
+///     .transport(Transport::Loopback)
+///     .fault_injector(FaultInjector::new(0.1, 4, 42))
+///     .build()?;
+/// ```
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultInjector {
+    /// The probability a packet is dropped, from 0 to 1
+    drop_prob: f64,
+    /// The number of packets a packet may overtake, 0 keeps the packets in order
+    reorder_window: usize,
+    /// The seed of the random generator drawing the faults
+    seed: u64,
+    /// The probability the payload of a packet is corrupted, from 0 to 1
+    corrupt_prob: f64,
+    /// The number of payload bytes a bit is flipped in when a packet is corrupted
+    corrupt_bytes: usize,
+}
+
+impl FaultInjector {
+    /// Drop a packet with the probability `drop_prob`, and reorder the packets in a window of
+    /// `reorder_window` packets. A `drop_prob` out of 0 to 1 is clamped into it.
+    #[must_use]
+    pub fn new(drop_prob: f64, reorder_window: usize, seed: u64) -> Self {
+        Self {
+            drop_prob: clamp_prob(drop_prob),
+            reorder_window,
+            seed,
+            corrupt_prob: 0.0,
+            corrupt_bytes: 0,
+        }
+    }
+
+    /// Corrupt the payload of a packet with the probability `corrupt_prob`, by flipping a bit in
+    /// `corrupt_bytes` of its bytes, or all of them if the payload is shorter. A `corrupt_prob` out
+    /// of 0 to 1 is clamped into it.
+    #[must_use]
+    pub fn with_corruption(mut self, corrupt_prob: f64, corrupt_bytes: usize) -> Self {
+        self.corrupt_prob = clamp_prob(corrupt_prob);
+        self.corrupt_bytes = corrupt_bytes;
+        self
+    }
+
+    /// Draw the faults from random generators seeded with `seed` instead
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The seed of the random generators drawing the faults
+    #[must_use]
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+/// Clamp a probability into 0 to 1, and take NaN as 0
+fn clamp_prob(prob: f64) -> f64 {
+    if prob.is_nan() {
+        0.0_f64
+    } else {
+        prob.clamp(0.0_f64, 1.0_f64)
+    }
+}
+
+/// Corrupts the payload of the framed packets as a `FaultInjector` configures.
+///
+/// The send agents apply it after the frame is written and before it's sent, so that the ICRC of a
+/// corrupted packet no longer matches.
+#[derive(Debug)]
+pub(crate) struct PayloadCorrupter {
+    prob: f64,
+    bytes: usize,
+    rng: Mutex<StdRng>,
+}
+
+impl PayloadCorrupter {
+    /// The corrupter of `injector`, `None` if it corrupts nothing.
+    pub(crate) fn new(injector: &FaultInjector) -> Option<Self> {
+        (injector.corrupt_prob > 0.0_f64 && injector.corrupt_bytes > 0).then(|| Self {
+            prob: injector.corrupt_prob,
+            bytes: injector.corrupt_bytes,
+            rng: Mutex::new(StdRng::seed_from_u64(injector.seed)),
+        })
+    }
+
+    /// Maybe corrupt the payload of `payload`, a packet framed in `frame`, by flipping a random bit
+    /// of some random bytes of it. The payload is followed by its padding and the ICRC at the end of
+    /// the frame. Return whether the frame is corrupted.
+    pub(crate) fn corrupt(
+        &self,
+        frame: &mut [u8],
+        payload: &PayloadInfo,
+    ) -> Result<bool, NetAgentError> {
+        let start = frame
+            .len()
+            .saturating_sub(ICRC_SIZE)
+            .saturating_sub(payload.with_pad_length());
+        let end = start.wrapping_add(payload.get_length());
+        let Some(bytes) = frame.get_mut(start..end) else {
+            return Ok(false);
+        };
+        if bytes.is_empty() {
+            return Ok(false);
+        }
+        let mut rng = self
+            .rng
+            .lock()
+            .map_err(|_| NetAgentError::LockPoisoned("payload corrupter lock"))?;
+        if !rng.gen_bool(self.prob) {
+            return Ok(false);
+        }
+        let amount = self.bytes.min(bytes.len());
+        for idx in index::sample(&mut *rng, bytes.len(), amount) {
+            if let Some(byte) = bytes.get_mut(idx) {
+                *byte ^= 1_u8.wrapping_shl(rng.gen_range(0..8));
+            }
+        }
+        debug!("the payload of a packet is corrupted by the fault injector");
+        Ok(true)
+    }
+}
+
+/// A packet held back in the reorder window. The payload is copied, since the memory it points to
+/// may be reused once the send returns.
+#[derive(Debug)]
+struct HeldPacket {
+    dest_addr: Ipv4Addr,
+    dest_port: u16,
+    /// `None` for a raw packet
+    meta_data: Option<Metadata>,
+    payload: Vec<u8>,
+}
+
+/// The random generator and the reorder window of a `FaultySendAgent`
+#[derive(Debug)]
+struct FaultState {
+    rng: StdRng,
+    held: VecDeque<HeldPacket>,
+}
+
+/// A send agent which injects the faults of a `FaultInjector` into the packets before passing them
+/// to the agent it wraps.
+#[derive(Debug)]
+pub(crate) struct FaultySendAgent {
+    inner: Arc<dyn NetSendAgent + Send + Sync>,
+    injector: FaultInjector,
+    state: Mutex<FaultState>,
+}
+
+impl FaultySendAgent {
+    /// Inject the faults of `injector` into the packets sent by `inner`.
+    pub(crate) fn new(inner: Arc<dyn NetSendAgent + Send + Sync>, injector: FaultInjector) -> Self {
+        Self {
+            inner,
+            injector,
+            state: Mutex::new(FaultState {
+                rng: StdRng::seed_from_u64(injector.seed),
+                held: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Drop the packet, or hold it back in the reorder window and send the one taken out of the
+    /// window if it's full. `send` sends the packet at once if the window is empty.
+    fn inject(
+        &self,
+        packet: impl FnOnce() -> HeldPacket,
+        send: impl FnOnce() -> Result<(), NetAgentError>,
+    ) -> Result<(), NetAgentError> {
+        let released = {
+            let mut state = self
+                .state
+                .lock()
+                .map_err(|_| NetAgentError::LockPoisoned("fault injector lock"))?;
+            if state.rng.gen_bool(self.injector.drop_prob) {
+                debug!("a packet is dropped by the fault injector");
+                return Ok(());
+            }
+            if self.injector.reorder_window == 0 {
+                None
+            } else {
+                state.held.push_back(packet());
+                let held_cnt = state.held.len();
+                if held_cnt > self.injector.reorder_window {
+                    let idx = state.rng.gen_range(0..held_cnt);
+                    state.held.remove(idx)
+                } else {
+                    return Ok(());
+                }
+            }
+        };
+        match released {
+            Some(held) => self.send_held(&held),
+            None => send(),
+        }
+    }
+
+    fn send_held(&self, packet: &HeldPacket) -> Result<(), NetAgentError> {
+        let payload = PayloadInfo::new_with_data(packet.payload.as_ptr(), packet.payload.len());
+        match &packet.meta_data {
+            Some(meta_data) => {
+                let message = RdmaMessage {
+                    meta_data: meta_data.clone(),
+                    payload,
+                };
+                self.inner
+                    .send(packet.dest_addr, packet.dest_port, &message)
+            }
+            None => self
+                .inner
+                .send_raw(packet.dest_addr, packet.dest_port, &payload),
+        }
+    }
+}
+
+/// Copy the payload into a buffer of its own
+fn copy_payload(payload: &PayloadInfo) -> Vec<u8> {
+    payload.segments().collect::<Vec<_>>().concat()
+}
+
+impl NetSendAgent for FaultySendAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        self.inject(
+            || HeldPacket {
+                dest_addr,
+                dest_port,
+                meta_data: Some(message.meta_data.clone()),
+                payload: copy_payload(&message.payload),
+            },
+            || self.inner.send(dest_addr, dest_port, message),
+        )
+    }
+
+    fn send_raw(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        payload: &PayloadInfo,
+    ) -> Result<(), NetAgentError> {
+        self.inject(
+            || HeldPacket {
+                dest_addr,
+                dest_port,
+                meta_data: None,
+                payload: copy_payload(payload),
+            },
+            || self.inner.send_raw(dest_addr, dest_port, payload),
+        )
+    }
+
+    fn send_vectored(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        self.inject(
+            || HeldPacket {
+                dest_addr,
+                dest_port,
+                meta_data: Some(message.meta_data.clone()),
+                payload: copy_payload(&message.payload),
+            },
+            || self.inner.send_vectored(dest_addr, dest_port, message),
+        )
+    }
+
+    /// Send all the held packets, even if some of them fail, and return the first error.
+    fn flush(&self) -> Result<(), NetAgentError> {
+        let held = std::mem::take(
+            &mut self
+                .state
+                .lock()
+                .map_err(|_| NetAgentError::LockPoisoned("fault injector lock"))?
+                .held,
+        );
+        held.iter()
+            .map(|packet| self.send_held(packet))
+            .fold(Ok(()), Result::and)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use crate::{
+        device::{
+            software::{
+                net_agent::{
+                    deliver_frame, FrameChecks, NetAgentError, NetReceiveLogic, NetSendAgent,
+                    OpcodeCounters,
+                },
+                packet_processor::{is_icrc_valid, IcrcConfig, PacketWriter},
+                types::{
+                    Key, Metadata, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+                    RdmaMessageMetaCommon, RethHeader,
+                },
+            },
+            ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
+        },
+        types::Psn,
+    };
+
+    use super::{FaultInjector, FaultySendAgent, PayloadCorrupter};
+
+    /// Keep the first byte of every raw packet
+    #[derive(Debug, Default)]
+    struct RecordAgent(Mutex<Vec<u8>>);
+
+    impl NetSendAgent for RecordAgent {
+        fn send(&self, _: Ipv4Addr, _: u16, _: &RdmaMessage) -> Result<(), NetAgentError> {
+            unimplemented!("only raw packets are sent")
+        }
+
+        fn send_raw(&self, _: Ipv4Addr, _: u16, payload: &PayloadInfo) -> Result<(), NetAgentError> {
+            self.0.lock().unwrap().push(payload.direct_data_ptr().unwrap()[0]);
+            Ok(())
+        }
+    }
+
+    /// Send 64 raw packets through the faults of `injector`, and return the ones delivered in order
+    fn deliver(injector: FaultInjector) -> Vec<u8> {
+        let record = Arc::new(RecordAgent::default());
+        let agent = FaultySendAgent::new(Arc::<RecordAgent>::clone(&record), injector);
+        for i in 0..64_u8 {
+            let data = [i; 8];
+            let payload = PayloadInfo::new_with_data(data.as_ptr(), data.len());
+            agent.send_raw(Ipv4Addr::LOCALHOST, 4791, &payload).unwrap();
+        }
+        agent.flush().unwrap();
+        let delivered = record.0.lock().unwrap().clone();
+        delivered
+    }
+
+    /// Keep the first byte of every raw packet like `RecordAgent`, and fail the odd ones
+    #[derive(Debug, Default)]
+    struct FailOddAgent(RecordAgent);
+
+    impl NetSendAgent for FailOddAgent {
+        fn send(&self, _: Ipv4Addr, _: u16, _: &RdmaMessage) -> Result<(), NetAgentError> {
+            unimplemented!("only raw packets are sent")
+        }
+
+        fn send_raw(
+            &self,
+            addr: Ipv4Addr,
+            port: u16,
+            payload: &PayloadInfo,
+        ) -> Result<(), NetAgentError> {
+            self.0.send_raw(addr, port, payload)?;
+            let first = payload.direct_data_ptr().unwrap()[0];
+            if first % 2 == 1 {
+                return Err(NetAgentError::DatagramTooLarge(first.into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_after_failure() {
+        let inner = Arc::new(FailOddAgent::default());
+        let agent = FaultySendAgent::new(
+            Arc::<FailOddAgent>::clone(&inner),
+            FaultInjector::new(0.0, 4, 1),
+        );
+        // the window holds all of them
+        for i in 0..4_u8 {
+            let data = [i; 8];
+            let payload = PayloadInfo::new_with_data(data.as_ptr(), data.len());
+            agent.send_raw(Ipv4Addr::LOCALHOST, 4791, &payload).unwrap();
+        }
+        assert!(inner.0 .0.lock().unwrap().is_empty());
+        // the packets after a failed one are still sent, and the first error is returned
+        let err = agent.flush().unwrap_err();
+        assert!(matches!(err, NetAgentError::DatagramTooLarge(1)), "{err:?}");
+        assert_eq!(*inner.0 .0.lock().unwrap(), [0, 1, 2, 3]);
+        agent.flush().unwrap();
+    }
+
+    #[test]
+    fn test_fault_injector() {
+        assert_eq!(deliver(FaultInjector::new(0.0, 0, 1)), (0..64).collect::<Vec<_>>());
+        assert!(deliver(FaultInjector::new(1.0, 4, 1)).is_empty());
+
+        let dropped = deliver(FaultInjector::new(0.2, 0, 1));
+        assert!(dropped.len() < 64);
+        assert!(dropped.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let reordered = deliver(FaultInjector::new(0.0, 4, 1));
+        assert_ne!(reordered, (0..64).collect::<Vec<_>>());
+        let mut sorted = reordered.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..64).collect::<Vec<_>>());
+        // the same seed draws the same faults
+        assert_eq!(deliver(FaultInjector::new(0.0, 4, 1)), reordered);
+        assert_eq!(deliver(FaultInjector::new(0.0, 4, 2).with_seed(1)), reordered);
+
+        // the probabilities out of 0 to 1 are clamped, and stay so with another seed
+        assert!(deliver(FaultInjector::new(2.0, 0, 1).with_seed(3)).is_empty());
+        let all = deliver(FaultInjector::new(f64::NAN, 0, 1).with_seed(3));
+        assert_eq!(all, (0..64).collect::<Vec<_>>());
+    }
+
+    /// Count the received packets
+    #[derive(Debug, Default)]
+    struct CountReceiver(AtomicUsize);
+
+    impl NetReceiveLogic<'_> for CountReceiver {
+        fn recv(&self, _: Ipv4Addr, _: &mut RdmaMessage) {
+            let _: usize = self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_payload_corrupter() {
+        assert!(PayloadCorrupter::new(&FaultInjector::new(0.5, 0, 1)).is_none());
+        assert!(PayloadCorrupter::new(&FaultInjector::new(0.0, 0, 1).with_corruption(1.0, 0)).is_none());
+
+        // a payload with 2 bytes of padding
+        let data = [0x5a_u8; 30];
+        let payload = PayloadInfo::new_with_data(data.as_ptr(), data.len());
+        let message = RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::RdmaWriteOnly,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(0),
+                },
+                reth: RethHeader {
+                    va: 0x1000,
+                    rkey: Key::new(0x1234),
+                    len: 30,
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload,
+        };
+        let mut buf = [0u8; 256];
+        let total_length = PacketWriter::new(&mut buf)
+            .src_addr(Ipv4Addr::new(127, 0, 0, 1))
+            .src_port(4791)
+            .dest_addr(Ipv4Addr::new(127, 0, 0, 2))
+            .dest_port(4791)
+            .ip_id(1)
+            .message(&message)
+            .write()
+            .unwrap();
+        let frame = buf[..total_length].to_vec();
+
+        let corrupter =
+            PayloadCorrupter::new(&FaultInjector::new(0.0, 0, 1).with_corruption(1.0, 4)).unwrap();
+        let mut corrupted = frame.clone();
+        assert!(corrupter.corrupt(&mut corrupted, &message.payload).unwrap());
+        let changed: Vec<_> = (0..frame.len()).filter(|&i| frame[i] != corrupted[i]).collect();
+        assert_eq!(changed.len(), 4);
+        // only the payload is corrupted, not its padding or the ICRC
+        let payload_start = total_length - 4 - 32;
+        assert!(changed.iter().all(|&i| (payload_start..payload_start + 30).contains(&i)));
+        assert!(
+            !is_icrc_valid(&mut corrupted.clone(), IcrcConfig::default())
+                .unwrap()
+                .is_valid()
+        );
+
+        // the receiver drops the corrupted packet and takes the intact one
+        let receiver = CountReceiver::default();
+        let counters = OpcodeCounters::new();
+        deliver_frame(&mut corrupted, &receiver, &counters, FrameChecks::default());
+        assert_eq!(receiver.0.load(Ordering::Relaxed), 0);
+        deliver_frame(&mut frame.clone(), &receiver, &counters, FrameChecks::default());
+        assert_eq!(receiver.0.load(Ordering::Relaxed), 1);
+
+        // a packet without payload is left intact
+        assert!(!corrupter.corrupt(&mut frame.clone(), &PayloadInfo::new()).unwrap());
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use eui48::MacAddress;
+use log::{debug, error};
+
+use crate::{
+    device::{
+        software::{
+            packet_processor::{IcrcConfig, PacketWriter},
+            types::{PayloadInfo, RdmaMessage},
+        },
+        ToHostWorkRbDescOpcode,
+    },
+    types::EcnCodepoint,
+    utils::stop_thread,
+};
+
+use super::{
+    deliver_frame,
+    fault_agent::PayloadCorrupter,
+    reseal_icrc,
+    udp_agent::{NET_SERVER_BUF_SIZE, NET_SERVER_MIN_BUF_SIZE},
+    FrameChecks, NetAgentError, NetReceiveLogic, NetSendAgent, OpcodeCounters,
+};
+
+/// How long the listen thread blocks on an empty queue before rechecking the stop flag.
+const LOOPBACK_READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// An endpoint of a `MemoryFabric`
+#[derive(Debug)]
+struct Endpoint {
+    mac: MacAddress,
+    queue: Sender<Vec<u8>>,
+}
+
+/// An in-process network of software devices, which hands the frames sent to an address to the
+/// device registered with it, without any socket.
+///
+/// The devices on different fabrics can't reach each other, so the tests can build several isolated
+/// networks, even with the same addresses. The devices of `Transport::Loopback` share a fabric of
+/// the process.
+///
+/// ```rust,ignore
+/// let fabric = Arc::new(MemoryFabric::new());
+/// let device = DeviceBuilder::new(&network)
+///     .transport(Transport::Fabric { fabric: Arc::clone(&fabric) })
+///     .build()?;
+/// assert_eq!(fabric.mac_of(network.ipaddr), Some(network.macaddr));
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryFabric {
+    endpoints: Mutex<HashMap<Ipv4Addr, Endpoint>>,
+}
+
+impl MemoryFabric {
+    /// Create a fabric without any device.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fabric shared by the loopback devices of the process.
+    pub(crate) fn global() -> &'static Arc<Self> {
+        static GLOBAL: OnceLock<Arc<MemoryFabric>> = OnceLock::new();
+        GLOBAL.get_or_init(|| Arc::new(Self::new()))
+    }
+
+    /// The mac address of the device registered with `addr`, if there is one.
+    #[must_use]
+    pub fn mac_of(&self, addr: Ipv4Addr) -> Option<MacAddress> {
+        self.endpoints
+            .lock()
+            .ok()
+            .and_then(|endpoints| endpoints.get(&addr).map(|endpoint| endpoint.mac))
+    }
+
+    /// The number of devices registered with the fabric.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.endpoints.lock().map_or(0, |endpoints| endpoints.len())
+    }
+
+    /// Whether no device is registered with the fabric.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn register(
+        &self,
+        addr: Ipv4Addr,
+        mac: MacAddress,
+        queue: Sender<Vec<u8>>,
+    ) -> Result<(), NetAgentError> {
+        let mut endpoints = self
+            .endpoints
+            .lock()
+            .map_err(|_| NetAgentError::LockPoisoned("memory fabric lock"))?;
+        if endpoints.contains_key(&addr) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+        }
+        let _: Option<Endpoint> = endpoints.insert(addr, Endpoint { mac, queue });
+        Ok(())
+    }
+
+    fn unregister(&self, addr: Ipv4Addr) -> Result<(), NetAgentError> {
+        let _: Option<Endpoint> = self
+            .endpoints
+            .lock()
+            .map_err(|_| NetAgentError::LockPoisoned("memory fabric lock"))?
+            .remove(&addr);
+        Ok(())
+    }
+
+    fn send_frame(&self, dest_addr: Ipv4Addr, frame: Vec<u8>) -> Result<(), NetAgentError> {
+        let endpoints = self
+            .endpoints
+            .lock()
+            .map_err(|_| NetAgentError::LockPoisoned("memory fabric lock"))?;
+        match endpoints.get(&dest_addr) {
+            Some(endpoint) => {
+                // the receive agent is removed from the fabric before its queue is dropped
+                let _: Result<(), _> = endpoint.queue.send(frame);
+            }
+            None => debug!("no device at {dest_addr} on the fabric, the frame is dropped"),
+        }
+        Ok(())
+    }
+}
+
+/// A receive agent of a `MemoryFabric`. It takes the frames sent to its address by the
+/// `LoopbackSendAgent`s on the same fabric, without any socket.
+#[derive(Debug)]
+pub(crate) struct LoopbackReceiveAgent {
+    fabric: Arc<MemoryFabric>,
+    addr: Ipv4Addr,
+    listen_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    stop_flag: Arc<AtomicBool>,
+    opcode_counters: Arc<OpcodeCounters>,
+    /// the number of frames to receive before the one to drop, 0 if no frame is dropped
+    drop_countdown: Arc<AtomicU32>,
+}
+
+/// A send agent of a `MemoryFabric`. The frames are built as the `UDPSendAgent` does, then
+/// handed to the receive agent of the destination address directly.
+///
+/// Like a real network, the frames to an address without a receive agent are dropped.
+#[derive(Debug)]
+pub(crate) struct LoopbackSendAgent {
+    fabric: Arc<MemoryFabric>,
+    src_addr: Ipv4Addr,
+    src_port: u16,
+    sending_id_counter: AtomicU16,
+    /// the DSCP in the higher 6 bits and the ECN in the lower 2 bits, as the traffic class of IPv4
+    dscp_ecn: AtomicU8,
+    /// How the ICRC of every sent frame is computed
+    pub(crate) icrc: IcrcConfig,
+    /// Corrupts the payload of the written frames if set
+    pub(crate) corrupter: Option<PayloadCorrupter>,
+}
+
+impl LoopbackReceiveAgent {
+    /// Create a receive agent of `addr` on the fabric of the loopback devices.
+    #[cfg(test)]
+    pub(crate) fn new(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        addr: Ipv4Addr,
+    ) -> Result<Self, NetAgentError> {
+        let fabric = Arc::clone(MemoryFabric::global());
+        Self::on_fabric(fabric, receiver, addr, MacAddress::nil(), FrameChecks::default())
+    }
+
+    /// Create a receive agent of `addr` on `fabric`, registered with `mac`.
+    ///
+    /// The received frames are dropped if they fail the ICRC check, unless `checks` accepts them.
+    pub(crate) fn on_fabric(
+        fabric: Arc<MemoryFabric>,
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        addr: Ipv4Addr,
+        mac: MacAddress,
+        checks: FrameChecks,
+    ) -> Result<Self, NetAgentError> {
+        let (sender, frames) = crossbeam_channel::unbounded();
+        fabric.register(addr, mac, sender)?;
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let thread_stop_flag = Arc::clone(&stop_flag);
+        let opcode_counters = Arc::new(OpcodeCounters::new());
+        let thread_opcode_counters = Arc::clone(&opcode_counters);
+        let drop_countdown = Arc::new(AtomicU32::new(0));
+        let thread_drop_countdown = Arc::clone(&drop_countdown);
+        let listen_thread = Mutex::new(Some(thread::spawn(move || {
+            Self::listen(
+                &frames,
+                &*receiver,
+                &thread_opcode_counters,
+                &thread_stop_flag,
+                &thread_drop_countdown,
+                checks,
+            );
+        })));
+        Ok(Self {
+            fabric,
+            addr,
+            listen_thread,
+            stop_flag,
+            opcode_counters,
+            drop_countdown,
+        })
+    }
+
+    fn listen(
+        frames: &Receiver<Vec<u8>>,
+        receiver: &dyn for<'a> NetReceiveLogic<'a>,
+        opcode_counters: &OpcodeCounters,
+        stop_flag: &AtomicBool,
+        drop_countdown: &AtomicU32,
+        checks: FrameChecks,
+    ) {
+        while !stop_flag.load(Ordering::Relaxed) {
+            match frames.recv_timeout(LOOPBACK_READ_TIMEOUT) {
+                Ok(mut frame) => {
+                    if frame.len() < NET_SERVER_MIN_BUF_SIZE {
+                        error!("Packet too short");
+                        continue;
+                    }
+                    let countdown =
+                        drop_countdown.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                            n.checked_sub(1)
+                        });
+                    if countdown == Ok(1) {
+                        debug!("a frame is dropped on purpose");
+                        continue;
+                    }
+                    deliver_frame(&mut frame, receiver, opcode_counters, checks);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Drop the `n`th frame received from now on, as if it was lost in the network. 0 drops nothing.
+    #[allow(dead_code)]
+    pub(crate) fn drop_nth_frame(&self, n: u32) {
+        self.drop_countdown.store(n, Ordering::Relaxed);
+    }
+
+    /// The number of received packets of each opcode.
+    pub(crate) fn opcode_counters(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        self.opcode_counters.snapshot()
+    }
+
+    /// Leave the fabric and stop the listen thread. Stopping a stopped agent is a no-op.
+    pub(crate) fn stop(&self) -> Result<(), NetAgentError> {
+        self.fabric.unregister(self.addr)?;
+        if stop_thread(&self.stop_flag, &self.listen_thread) {
+            Ok(())
+        } else {
+            Err(NetAgentError::ThreadJoinFailed("loopback listen agent"))
+        }
+    }
+}
+
+impl Drop for LoopbackReceiveAgent {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the loopback receive agent of {}: {e:?}", self.addr);
+        }
+    }
+}
+
+impl LoopbackSendAgent {
+    /// Create a send agent of `src_addr` on the fabric of the loopback devices.
+    #[cfg(test)]
+    pub(crate) fn new(src_addr: Ipv4Addr, src_port: u16) -> Self {
+        Self::on_fabric(Arc::clone(MemoryFabric::global()), src_addr, src_port)
+    }
+
+    /// Create a send agent of `src_addr` on `fabric`.
+    pub(crate) fn on_fabric(fabric: Arc<MemoryFabric>, src_addr: Ipv4Addr, src_port: u16) -> Self {
+        Self {
+            fabric,
+            src_addr,
+            src_port,
+            sending_id_counter: AtomicU16::new(0),
+            dscp_ecn: AtomicU8::new(0),
+            icrc: IcrcConfig::default(),
+            corrupter: None,
+        }
+    }
+
+    /// Mark all the packets sent afterwards with `dscp` and `ecn`. The higher 2 bits of `dscp` are ignored.
+    pub(crate) fn set_traffic_class(&self, dscp: u8, ecn: EcnCodepoint) {
+        self.dscp_ecn
+            .store(dscp.wrapping_shl(2) | ecn.bits(), Ordering::Relaxed);
+    }
+}
+
+impl NetSendAgent for LoopbackSendAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        let mut buf = vec![0u8; NET_SERVER_BUF_SIZE];
+        let ip_id = self.sending_id_counter.fetch_add(1, Ordering::Relaxed);
+        let dscp_ecn = self.dscp_ecn.load(Ordering::Relaxed);
+        let total_length = PacketWriter::new(&mut buf)
+            .src_addr(self.src_addr)
+            .src_port(self.src_port)
+            .dest_addr(dest_addr)
+            .dest_port(dest_port)
+            .ip_id(ip_id)
+            .dscp(dscp_ecn.wrapping_shr(2))
+            .ecn(EcnCodepoint::from_bits(dscp_ecn))
+            .icrc(self.icrc)
+            .message(message)
+            .write()?;
+        buf.truncate(total_length);
+        if let Some(corrupter) = &self.corrupter {
+            let _: bool = corrupter.corrupt(&mut buf, &message.payload)?;
+        }
+        self.fabric.send_frame(dest_addr, buf)
+    }
+
+    fn send_raw(
+        &self,
+        dest_addr: Ipv4Addr,
+        _dest_port: u16,
+        payload: &PayloadInfo,
+    ) -> Result<(), NetAgentError> {
+        let buf = payload
+            .direct_data_ptr()
+            .ok_or(NetAgentError::InvalidRdmaMessage(
+                "PayloadInfo should have at least one item".to_owned(),
+            ))?;
+        let frame = reseal_icrc(buf, self.icrc).unwrap_or_else(|| buf.to_vec());
+        self.fabric.send_frame(dest_addr, frame)
+    }
+}
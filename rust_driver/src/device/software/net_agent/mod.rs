@@ -1,18 +1,64 @@
-use std::{fmt::Debug, net::Ipv4Addr};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    mem::size_of,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+use log::{debug, error};
 use thiserror::Error;
 
+use crate::{device::ToHostWorkRbDescOpcode, types::EcnCodepoint};
+
 use super::{
-    packet::PacketError,
-    packet_processor::PacketProcessorError,
+    packet::{IpUdpHeaders, PacketError, ICRC_SIZE},
+    packet_processor::{
+        compute_icrc, compute_udp_checksum, is_icrc_valid, IcrcConfig, PacketProcessor,
+        PacketProcessorError,
+    },
     types::{PayloadInfo, RdmaMessage},
 };
 use std::io;
 
+pub(crate) mod fault_agent;
+pub(crate) mod loopback_agent;
 pub(crate) mod udp_agent;
 
+/// The opcode byte in BTH, since the opcode of a CNP includes the transaction type
+const OPCODE_COUNTERS_SIZE: usize = 256;
+
+/// The direction of a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// The frame is sent by the send agent
+    Tx,
+    /// The frame is received by the receive agent
+    Rx,
+}
+
+/// A callback that is invoked with every frame sent or received by the net agents.
+///
+/// The frame is the full on-wire bytes, including the IP header, the UDP header and the ICRC, so it can
+/// be dumped to a pcap file directly.
+pub(crate) type CaptureHook = Arc<dyn Fn(&[u8], Direction) + Send + Sync>;
+
 pub(crate) trait NetReceiveLogic<'a>: Send + Sync + Debug {
-    fn recv(&self, message: &mut RdmaMessage);
+    /// Handle the message received from `src_addr`, which has passed `check_sequence`.
+    fn recv(&self, src_addr: Ipv4Addr, message: &mut RdmaMessage);
+
+    /// Called before `recv` if the IP header of the message is marked with ECN-CE by the network.
+    /// `src_addr` is the source of the congested packet.
+    fn congestion_experienced(&self, _src_addr: Ipv4Addr, _message: &RdmaMessage) {}
+
+    /// Called before `recv` to check the PSN of the message, which is dropped if it returns `false`.
+    /// `src_addr` is the source of the packet.
+    fn check_sequence(&self, _src_addr: Ipv4Addr, _message: &RdmaMessage) -> bool {
+        true
+    }
 }
 
 pub(crate) trait NetSendAgent: Debug {
@@ -29,6 +75,173 @@ pub(crate) trait NetSendAgent: Debug {
         dest_port: u16,
         payload: &PayloadInfo,
     ) -> Result<(), NetAgentError>;
+
+    /// Send the message like `send`, but without copying the payload into the frame.
+    ///
+    /// The agents able to gather the segments of the payload, e.g. with `sendmsg`, override it. It
+    /// falls back to `send` by default.
+    fn send_vectored(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        self.send(dest_addr, dest_port, message)
+    }
+
+    /// Send the packets the agent has held back, called when the device has nothing else to send.
+    ///
+    /// Only the agents delaying the packets, e.g. to reorder them, override it.
+    fn flush(&self) -> Result<(), NetAgentError> {
+        Ok(())
+    }
+}
+
+/// The checks a receive agent makes on every frame before passing it to the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameChecks {
+    /// Log the frames failing the ICRC check, but still deliver them instead of dropping them.
+    /// It helps diagnosing a peer computing the ICRC differently.
+    pub(crate) accept_invalid_icrc: bool,
+    /// Deliver the frames whose UDP checksum is zero, which means the sender does not compute it.
+    /// Most `RoCEv2` peers leave it zero. A nonzero UDP checksum is always checked.
+    pub(crate) accept_zero_udp_checksum: bool,
+    /// How the ICRC of the frames is computed, which is the `RoCEv2` standard by default
+    pub(crate) icrc: IcrcConfig,
+}
+
+impl Default for FrameChecks {
+    fn default() -> Self {
+        Self {
+            accept_invalid_icrc: false,
+            accept_zero_udp_checksum: true,
+            icrc: IcrcConfig::default(),
+        }
+    }
+}
+
+/// Whether the UDP checksum of a received frame is accepted
+fn is_udp_checksum_accepted(frame: &[u8], accept_zero: bool) -> bool {
+    let found = IpUdpHeaders::from_bytes(frame).udp_header.get_checksum();
+    if found == 0 {
+        if !accept_zero {
+            debug!("drop a frame without the UDP checksum");
+        }
+        return accept_zero;
+    }
+    match compute_udp_checksum(frame) {
+        Ok(expected) if expected == found => true,
+        Ok(expected) => {
+            error!("UDP checksum check failed, expected {expected:#06x}, found {found:#06x}");
+            false
+        }
+        Err(e) => {
+            error!("UDP checksum check failed {e:?}");
+            false
+        }
+    }
+}
+
+/// Recompute the ICRC of a raw `frame` with `config`, since the raw packets like the acknowledges
+/// are built with the standard ICRC. Returns `None` if the frame can be sent as it is, i.e. `config`
+/// is the standard one or the frame is too short to be a `RoCEv2` packet.
+pub(crate) fn reseal_icrc(frame: &[u8], config: IcrcConfig) -> Option<Vec<u8>> {
+    if config == IcrcConfig::default() || frame.len() < udp_agent::NET_SERVER_MIN_BUF_SIZE {
+        return None;
+    }
+    let mut frame = frame.to_vec();
+    let icrc = compute_icrc(&frame, config);
+    let start = frame.len().wrapping_sub(ICRC_SIZE);
+    frame.get_mut(start..)?.copy_from_slice(&icrc.to_le_bytes());
+    Some(frame)
+}
+
+/// Check the UDP checksum and the ICRC of a received frame, then pass the RDMA message in it to `receiver`.
+///
+/// The frame should be at least `udp_agent::NET_SERVER_MIN_BUF_SIZE` bytes. The frames failing the
+/// checks are dropped, unless `checks` accepts them.
+pub(crate) fn deliver_frame(
+    frame: &mut [u8],
+    receiver: &dyn for<'a> NetReceiveLogic<'a>,
+    opcode_counters: &OpcodeCounters,
+    checks: FrameChecks,
+) {
+    if !is_udp_checksum_accepted(frame, checks.accept_zero_udp_checksum) {
+        return;
+    }
+    match is_icrc_valid(frame, checks.icrc) {
+        Ok(check) => {
+            if !check.is_valid() {
+                error!(
+                    "ICRC check failed, expected {:#010x}, found {:#010x}",
+                    check.expected, check.found
+                );
+                debug!("the frame failing the ICRC check {frame:?}");
+                if !checks.accept_invalid_icrc {
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            error!("ICRC check failed {e:?}");
+            return;
+        }
+    }
+    let ip_header = IpUdpHeaders::from_bytes(frame).ip_header;
+    // skip the ip header and udp header and the icrc
+    let offset = size_of::<IpUdpHeaders>();
+    let end = frame.len().wrapping_sub(ICRC_SIZE);
+    let Some(received_data) = frame.get(offset..end) else {
+        error!("Packet too short");
+        return;
+    };
+    let mut message = match PacketProcessor::to_rdma_message(received_data) {
+        Ok(message) => message,
+        Err(e) => {
+            error!("Drop malformed packet: {e}");
+            return;
+        }
+    };
+    let opcode = message.meta_data.get_opcode();
+    opcode_counters.increase(&opcode);
+    if ip_header.get_ecn() == EcnCodepoint::Ce && opcode != ToHostWorkRbDescOpcode::Cnp {
+        receiver.congestion_experienced(ip_header.get_source(), &message);
+    }
+    if !receiver.check_sequence(ip_header.get_source(), &message) {
+        return;
+    }
+    receiver.recv(ip_header.get_source(), &mut message);
+}
+
+/// The number of received packets of each opcode.
+#[derive(Debug)]
+pub(crate) struct OpcodeCounters([AtomicU64; OPCODE_COUNTERS_SIZE]);
+
+impl OpcodeCounters {
+    pub(crate) fn new() -> Self {
+        Self(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+
+    /// Count a received packet with `opcode`.
+    pub(crate) fn increase(&self, opcode: &ToHostWorkRbDescOpcode) {
+        if let Some(counter) = self.0.get(opcode.clone() as usize) {
+            let _: u64 = counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take a snapshot of the counters. Opcodes that have never been received are omitted.
+    pub(crate) fn snapshot(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(opcode, counter)| {
+                let count = counter.load(Ordering::Relaxed);
+                let opcode = u8::try_from(opcode).ok()?;
+                let opcode = ToHostWorkRbDescOpcode::try_from(opcode).ok()?;
+                (count != 0).then_some((opcode, count))
+            })
+            .collect()
+    }
 }
 
 #[derive(Error, Debug)]
@@ -42,8 +255,29 @@ pub(crate) enum NetAgentError {
     PacketProcess(#[from] PacketProcessorError),
     #[error("setsockopt failed, errno: {0}")]
     SetSockOptFailed(i32),
-    #[error("Expected {0} bytes, but sended {1} bytes")]
-    WrongBytesSending(usize, usize),
+    /// A raw socket sends a datagram whole or not at all, so the rest of it can not be sent
+    /// afterwards. The datagram is lost, like a packet dropped by the network.
+    #[error("datagram of {0} bytes is truncated to {1} bytes by the socket and dropped")]
+    TruncatedDatagram(usize, usize),
+    /// Resending does not help, the packets should be made smaller.
+    #[error("datagram of {0} bytes is larger than the socket or the link sends, lower the pmtu or set a link mtu")]
+    DatagramTooLarge(usize),
+    /// The socket send buffer stays full during all the attempts. It is transient, the send can be
+    /// retried later.
+    #[error("no room in the socket send buffer for a datagram of {0} bytes after {1} attempts, raise SO_SNDBUF or send slower")]
+    SendBufferFull(usize, u32),
     #[error("Invalid RDMA message :{0}")]
     InvalidRdmaMessage(String),
+    #[error("Buffer size {0} is too small, at least {1} bytes are required")]
+    BufferTooSmall(usize, usize),
+    #[error("Mutex lock {0} poisoned")]
+    LockPoisoned(&'static str),
+    #[error("failed to join the {0} thread")]
+    ThreadJoinFailed(&'static str),
+    /// The socket handed to an agent is not the kind of raw socket the agent opens itself
+    #[error("invalid socket: {0}")]
+    InvalidSocket(&'static str),
+    /// The raw sockets can only be opened by root or with `CAP_NET_RAW`
+    #[error("opening a raw socket requires CAP_NET_RAW, run as root or grant it with `sudo setcap cap_net_raw+ep <binary>`")]
+    InsufficientPrivileges,
 }
@@ -1,47 +1,157 @@
 use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fmt,
+    io::{self, IoSlice},
     mem::{size_of, MaybeUninit},
     net::{Ipv4Addr, SocketAddrV4},
     os::fd::AsRawFd,
     sync::{
-        atomic::{AtomicBool, AtomicU16, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU16, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
-use log::{error, info};
+use eui48::MacAddress;
+use log::{debug, error, info};
 use socket2::{Domain, Protocol, Socket, Type};
 
-use crate::device::software::{
-    packet::{CommonPacketHeader, IpUdpHeaders, ICRC_SIZE},
-    packet_processor::{is_icrc_valid, PacketProcessor, PacketWriter},
-    types::{PayloadInfo, RdmaMessage},
+use crate::{
+    device::{
+        software::{
+            packet::{CommonPacketHeader, Ipv4Header, VlanEthernetHeaders, ICRC_SIZE},
+            packet_processor::{
+                write_vlan_ethernet_header, IcrcConfig, PacketProcessorError, PacketWriter,
+            },
+            types::{PayloadInfo, RdmaMessage},
+        },
+        ToHostWorkRbDescOpcode,
+    },
+    types::{EcnCodepoint, UdpChecksumMode},
+    utils::stop_thread,
 };
 
-use super::{NetAgentError, NetReceiveLogic, NetSendAgent};
+use super::{
+    deliver_frame, fault_agent::PayloadCorrupter, reseal_icrc, CaptureHook, Direction,
+    FrameChecks, NetAgentError, NetReceiveLogic, NetSendAgent, OpcodeCounters,
+};
 
 pub(crate) const NET_SERVER_BUF_SIZE: usize = 8192;
 
+/// The buffer holding the headers of a vectored send: the Ethernet header, the IP and UDP headers and
+/// the largest RDMA headers.
+const NET_SEND_HEADERS_BUF_SIZE: usize = 128;
+
+/// How long the listen thread blocks on an idle socket before rechecking the stop flag.
+/// It bounds the time `UDPReceiveAgent::drop` waits for the thread to exit.
+const NET_SERVER_READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// The default number of attempts of a send, including the first one.
+pub(crate) const NET_SEND_MAX_ATTEMPTS: u32 = 5;
+
+/// The backoff before the first retry of a send. It doubles on each further retry.
+const NET_SEND_INITIAL_BACKOFF: Duration = Duration::from_micros(10);
+
+/// The smallest receiving buffer that can hold a packet with the common headers and the ICRC.
+pub(crate) const NET_SERVER_MIN_BUF_SIZE: usize = size_of::<CommonPacketHeader>() + ICRC_SIZE;
+
+/// How long the fragments of an IP packet are kept waiting for the rest of them.
+const IP_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The max bytes of the fragments kept waiting for the rest of their packets. The oldest packets
+/// are dropped to make room for the new fragments.
+const IP_REASSEMBLY_MAX_BYTES: usize = 0x10_0000;
+
+/// The largest IP packet, which bounds the data of the fragments of a packet
+const IPV4_MAX_PACKET_SIZE: usize = 0xffff;
+
+/// The smallest link MTU of IPv4, which every host should be able to send through
+const IPV4_MIN_LINK_MTU: usize = 68;
+
 /// A single thread udp server that listens to the corresponding port and calls the `recv` method of the receiver when a message is received.
 #[derive(Debug)]
 pub(crate) struct UDPReceiveAgent {
-    listen_thread: Option<thread::JoinHandle<()>>,
+    listen_thread: Mutex<Option<thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
+    #[allow(dead_code)]
+    buf_size: usize,
+    opcode_counters: Arc<OpcodeCounters>,
 }
 
 /// A udp client that sends messages to the corresponding address and port.
-#[derive(Debug)]
 pub(crate) struct UDPSendAgent {
     sender: Socket,
     sending_id_counter: AtomicU16,
     src_addr: Ipv4Addr,
     src_port: u16,
+    /// The max number of attempts of a send when `send_to` fails with a transient error
+    pub(crate) max_send_attempts: u32,
+    /// Invoked with every sent frame if set
+    pub(crate) capture_hook: Option<CaptureHook>,
+    /// How the UDP checksum of every sent packet is filled
+    udp_checksum_mode: RwLock<UdpChecksumMode>,
+    /// How the ICRC of every sent packet is computed
+    pub(crate) icrc: IcrcConfig,
+    /// The DSCP and ECN byte of the IP header of every sent packet
+    dscp_ecn: AtomicU8,
+    /// Send 802.1Q tagged frames instead of IP packets if set
+    vlan: RwLock<Option<VlanSender>>,
+    /// Split the packets larger than it into IP fragments, or 0 if the packets are never split
+    link_mtu: AtomicUsize,
+    /// Corrupts the payload of the written packets if set. The packets split into fragments are
+    /// sent intact.
+    pub(crate) corrupter: Option<PayloadCorrupter>,
+}
+
+/// An `AF_PACKET` socket bound to a network interface, and the Ethernet header of the frames it sends.
+#[derive(Debug)]
+struct VlanSender {
+    socket: Socket,
+    vid: u16,
+    pcp: u8,
+    src_mac: MacAddress,
+    dest_mac: MacAddress,
+}
+
+impl fmt::Debug for UDPSendAgent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UDPSendAgent")
+            .field("sender", &self.sender)
+            .field("sending_id_counter", &self.sending_id_counter)
+            .field("src_addr", &self.src_addr)
+            .field("src_port", &self.src_port)
+            .field("max_send_attempts", &self.max_send_attempts)
+            .field("capture_hook", &self.capture_hook.is_some())
+            .field("udp_checksum_mode", &self.udp_checksum_mode)
+            .field("icrc", &self.icrc)
+            .field("dscp_ecn", &self.dscp_ecn)
+            .field("vlan", &self.vlan)
+            .field("link_mtu", &self.link_mtu())
+            .field("corrupter", &self.corrupter)
+            .finish()
+    }
 }
 
 impl UDPSendAgent {
+    /// Create a send agent whose IP identification starts from a random value.
     #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
     pub(crate) fn new(src_addr: Ipv4Addr, src_port: u16) -> Result<Self, NetAgentError> {
-        let sender = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
+        Self::with_initial_ip_id(src_addr, src_port, random_ip_id())
+    }
+
+    /// Create a send agent whose first packet has the IP identification `ip_id`, and the following
+    /// ones count up from it.
+    ///
+    /// With a fixed `ip_id`, the sent frames are the same across runs, e.g. for comparing captures.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn with_initial_ip_id(
+        src_addr: Ipv4Addr,
+        src_port: u16,
+        ip_id: u16,
+    ) -> Result<Self, NetAgentError> {
+        let sender = open_raw_socket(Domain::IPV4, Some(Protocol::UDP))?;
         let fd = sender.as_raw_fd();
         unsafe {
             let on = 1i32;
@@ -57,90 +167,705 @@ impl UDPSendAgent {
                 return Err(NetAgentError::SetSockOptFailed(ret));
             }
         }
+        Ok(Self::with_socket(sender, src_addr, src_port, ip_id))
+    }
 
-        // We can use the `rand` crate as well.
+    /// Create a send agent over `sender`, which is opened by another process and passed to this
+    /// one, e.g. by a privileged helper, since opening a raw socket requires `CAP_NET_RAW`.
+    ///
+    /// `sender` should be an IPv4 raw socket of UDP with `IP_HDRINCL` set, as the one `new` opens.
+    pub(crate) fn from_socket(
+        sender: Socket,
+        src_addr: Ipv4Addr,
+        src_port: u16,
+    ) -> Result<Self, NetAgentError> {
+        check_raw_udp_socket(&sender)?;
+        if !sender.header_included_v4()? {
+            return Err(NetAgentError::InvalidSocket("IP_HDRINCL is not set"));
+        }
+        Ok(Self::with_socket(sender, src_addr, src_port, random_ip_id()))
+    }
 
-        let time_in_number = unsafe { libc::time(std::ptr::null_mut()) as u32 };
-        unsafe {
-            libc::srand(time_in_number);
+    fn with_socket(sender: Socket, src_addr: Ipv4Addr, src_port: u16, ip_id: u16) -> Self {
+        // the packets sent to a multicast group leave through the interface of the device address
+        if let Err(e) = sender.set_multicast_if_v4(&src_addr) {
+            debug!("failed to send the multicast packets from {src_addr}: {e}");
         }
-        let rand_val = unsafe { libc::rand() };
-        // just truncation here, we don't care its exact value.
-        let sending_id = AtomicU16::new(rand_val as u16);
-        Ok(Self {
+
+        Self {
             sender,
-            sending_id_counter: sending_id,
+            sending_id_counter: AtomicU16::new(ip_id),
             src_addr,
             src_port,
-        })
+            max_send_attempts: NET_SEND_MAX_ATTEMPTS,
+            udp_checksum_mode: RwLock::new(UdpChecksumMode::default()),
+            icrc: IcrcConfig::default(),
+            capture_hook: None,
+            dscp_ecn: AtomicU8::new(0),
+            vlan: RwLock::new(None),
+            link_mtu: AtomicUsize::new(0),
+            corrupter: None,
+        }
+    }
+
+    /// Mark all the packets sent afterwards with `dscp` and `ecn`. The higher 2 bits of `dscp` are ignored.
+    pub(crate) fn set_traffic_class(&self, dscp: u8, ecn: EcnCodepoint) {
+        self.dscp_ecn
+            .store(dscp.wrapping_shl(2) | ecn.bits(), Ordering::Relaxed);
+    }
+
+    /// Split the packets sent afterwards which are larger than `link_mtu` into IP fragments, or
+    /// never split them if it's `None`.
+    ///
+    /// A `link_mtu` below the minimum of IPv4, 68 bytes, is rejected.
+    pub(crate) fn set_link_mtu(&self, link_mtu: Option<usize>) -> Result<(), NetAgentError> {
+        if let Some(mtu) = link_mtu.filter(|mtu| *mtu < IPV4_MIN_LINK_MTU) {
+            return Err(PacketProcessorError::LinkMtuTooSmall(mtu).into());
+        }
+        self.link_mtu.store(link_mtu.unwrap_or(0), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The link MTU the packets larger than it are split at, if any
+    fn link_mtu(&self) -> Option<usize> {
+        Some(self.link_mtu.load(Ordering::Relaxed)).filter(|mtu| *mtu != 0)
+    }
+
+    /// Fill the UDP checksum of all the packets sent afterwards with `mode`.
+    pub(crate) fn set_udp_checksum_mode(&self, mode: UdpChecksumMode) {
+        *self
+            .udp_checksum_mode
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = mode;
+    }
+
+    /// Send all the packets afterwards as 802.1Q tagged Ethernet frames through the network
+    /// interface `ifname`, with an `AF_PACKET` raw socket.
+    ///
+    /// As the frames skip the routing of the kernel, `dest_mac` should be the MAC of the next hop.
+    /// Opening the socket requires `CAP_NET_RAW`. Until this is called, the agent sends IP packets
+    /// through the `AF_INET` raw socket.
+    pub(crate) fn set_vlan(
+        &self,
+        ifname: &str,
+        vid: u16,
+        pcp: u8,
+        src_mac: MacAddress,
+        dest_mac: MacAddress,
+    ) -> Result<(), NetAgentError> {
+        let socket = open_packet_socket(ifname)?;
+        *self
+            .vlan
+            .write()
+            .map_err(|_| NetAgentError::LockPoisoned("vlan lock"))? = Some(VlanSender {
+            socket,
+            vid,
+            pcp,
+            src_mac,
+            dest_mac,
+        });
+        Ok(())
+    }
+
+    /// Send a frame with the `AF_PACKET` socket if `vlan` is set, otherwise send the IP packet to `dest_addr`.
+    fn send_frame(
+        &self,
+        vlan: Option<&VlanSender>,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        frame: &[u8],
+    ) -> Result<(), NetAgentError> {
+        self.send_frame_vectored(vlan, dest_addr, dest_port, &[IoSlice::new(frame)])
+    }
+
+    /// Send a frame gathered from `segments` like `send_frame`.
+    fn send_frame_vectored(
+        &self,
+        vlan: Option<&VlanSender>,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        segments: &[IoSlice<'_>],
+    ) -> Result<(), NetAgentError> {
+        let frame_length = segments
+            .iter()
+            .fold(0_usize, |sum, segment| sum.wrapping_add(segment.len()));
+        let result = send_with_retry(self.max_send_attempts, || match vlan {
+            Some(vlan) => vlan.socket.send_vectored(segments),
+            None => self.sender.send_to_vectored(
+                segments,
+                &SocketAddrV4::new(dest_addr, dest_port).into(),
+            ),
+        });
+        check_datagram_sent(frame_length, self.max_send_attempts, result)?;
+        if let Some(hook) = &self.capture_hook {
+            if let [frame] = segments {
+                hook(frame, Direction::Tx);
+            } else {
+                let frame: Vec<&[u8]> = segments.iter().map(|segment| &**segment).collect();
+                hook(&frame.concat(), Direction::Tx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill a writer with the addresses and the options of the agent for sending `message`.
+    fn prepare_writer<'buf, 'message>(
+        &self,
+        buf: &'buf mut [u8],
+        vlan: Option<&VlanSender>,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &'message RdmaMessage,
+    ) -> PacketWriter<'buf, 'message> {
+        let ip_id = self
+            .sending_id_counter
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dscp_ecn = self.dscp_ecn.load(Ordering::Relaxed);
+        let mut writer = PacketWriter::new(buf);
+        let _: &mut PacketWriter<'_, '_> = writer
+            .src_addr(self.src_addr)
+            .src_port(self.src_port)
+            .dest_addr(dest_addr)
+            .dest_port(dest_port)
+            .ip_id(ip_id)
+            .dscp(dscp_ecn.wrapping_shr(2))
+            .ecn(EcnCodepoint::from_bits(dscp_ecn))
+            .udp_checksum(
+                *self
+                    .udp_checksum_mode
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner),
+            )
+            .icrc(self.icrc)
+            .message(message);
+        if let Some(vlan) = vlan {
+            let _: &mut PacketWriter<'_, '_> = writer
+                .vlan(vlan.vid, vlan.pcp)
+                .src_mac(vlan.src_mac)
+                .dest_mac(vlan.dest_mac);
+        }
+        writer
+    }
+}
+
+/// Open an `AF_PACKET` raw socket that sends frames through the network interface `ifname`.
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+fn open_packet_socket(ifname: &str) -> Result<Socket, NetAgentError> {
+    let name = CString::new(ifname).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: `name` is a nul terminated string
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // the protocol is 0, so that the socket does not receive any frame
+    let socket = open_raw_socket(Domain::PACKET, None)?;
+    let addr = libc::sockaddr_ll {
+        sll_family: libc::AF_PACKET as u16, // AF_PACKET fits in u16
+        sll_protocol: 0,
+        sll_ifindex: ifindex as i32, // the interface index is a positive int in kernel
+        sll_hatype: 0,
+        sll_pkttype: 0,
+        sll_halen: 0,
+        sll_addr: [0; 8],
+    };
+    // SAFETY: `addr` is a valid `sockaddr_ll` and the length matches
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            std::ptr::addr_of!(addr).cast::<libc::sockaddr>(),
+            size_of::<libc::sockaddr_ll>() as u32, // the size of sockaddr_ll is a u32 value
+        )
+    };
+    if ret != 0_i32 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+/// A random IP identification for the first packet of a send agent
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn random_ip_id() -> u16 {
+    // We can use the `rand` crate as well.
+
+    let time_in_number = unsafe { libc::time(std::ptr::null_mut()) as u32 };
+    unsafe {
+        libc::srand(time_in_number);
+    }
+    let rand_val = unsafe { libc::rand() };
+    // just truncation here, we don't care its exact value.
+    rand_val as u16
+}
+
+/// Open a raw socket, which fails with `NetAgentError::InsufficientPrivileges` without `CAP_NET_RAW`.
+fn open_raw_socket(domain: Domain, protocol: Option<Protocol>) -> Result<Socket, NetAgentError> {
+    Socket::new(domain, Type::RAW, protocol).map_err(raw_socket_error)
+}
+
+/// Tell the missing privilege apart from the other failures of opening a raw socket.
+fn raw_socket_error(err: io::Error) -> NetAgentError {
+    if err.raw_os_error() == Some(libc::EPERM) {
+        NetAgentError::InsufficientPrivileges
+    } else {
+        err.into()
+    }
+}
+
+/// Check that `socket` is an IPv4 raw socket of UDP, the kind of socket the agents open.
+fn check_raw_udp_socket(socket: &Socket) -> Result<(), NetAgentError> {
+    if socket.domain()? != Domain::IPV4 {
+        return Err(NetAgentError::InvalidSocket("not an IPv4 socket"));
+    }
+    if socket.r#type()? != Type::RAW {
+        return Err(NetAgentError::InvalidSocket("not a raw socket"));
+    }
+    if socket.protocol()? != Some(Protocol::UDP) {
+        return Err(NetAgentError::InvalidSocket("not a socket of UDP"));
+    }
+    Ok(())
+}
+
+/// Whether a failed send is likely to succeed when retried later.
+fn is_transient_error(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Call `send` until it succeeds or `max_attempts` attempts are made.
+///
+/// Only the transient errors are retried, with an exponential backoff. Other errors are returned immediately.
+fn send_with_retry(
+    max_attempts: u32,
+    mut send: impl FnMut() -> io::Result<usize>,
+) -> io::Result<usize> {
+    let mut backoff = NET_SEND_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match send() {
+            Err(e) if attempt < max_attempts && is_transient_error(&e) => {
+                debug!("send failed: {e}, retry in {backoff:?}");
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+                attempt = attempt.saturating_add(1);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Classify the result of sending a datagram of `frame_length` bytes with `send_with_retry`.
+fn check_datagram_sent(
+    frame_length: usize,
+    max_attempts: u32,
+    result: io::Result<usize>,
+) -> Result<(), NetAgentError> {
+    match result {
+        Ok(sent) if sent == frame_length => Ok(()),
+        Ok(sent) => Err(NetAgentError::TruncatedDatagram(frame_length, sent)),
+        Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+            Err(NetAgentError::DatagramTooLarge(frame_length))
+        }
+        Err(e) if is_transient_error(&e) => {
+            Err(NetAgentError::SendBufferFull(frame_length, max_attempts))
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
 impl UDPReceiveAgent {
+    #[cfg(test)]
     pub(crate) fn new(
         receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
         addr: Ipv4Addr,
         port: u16,
     ) -> Result<Self, NetAgentError> {
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        let thread_stop_flag = Arc::clone(&stop_flag);
+        Self::with_buffer_size(receiver, addr, port, NET_SERVER_BUF_SIZE)
+    }
+
+    /// Create a receive agent whose receiving buffer is `buf_size` bytes.
+    ///
+    /// The buffer size limits the largest packet the agent can receive, so it should be large enough
+    /// to hold a full pmtu payload plus the headers.
+    #[cfg(test)]
+    pub(crate) fn with_buffer_size(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        addr: Ipv4Addr,
+        port: u16,
+        buf_size: usize,
+    ) -> Result<Self, NetAgentError> {
+        Self::with_options(
+            receiver,
+            addr,
+            port,
+            buf_size,
+            None,
+            None,
+            FrameChecks::default(),
+        )
+    }
 
-        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
+    /// Create a receive agent whose receiving buffer is `buf_size` bytes, and optionally bind the
+    /// socket to the network interface `ifname` with `SO_BINDTODEVICE`.
+    ///
+    /// Binding to an interface keeps the agent from picking up packets of the other interfaces on
+    /// a multi-homed host. Like the rest of the raw socket path, it requires `CAP_NET_RAW`.
+    ///
+    /// If `capture_hook` is set, it is invoked with every received frame before the ICRC check.
+    ///
+    /// The received frames are dropped if they fail the UDP checksum or the ICRC check, unless `checks`
+    /// accepts them.
+    pub(crate) fn with_options(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        addr: Ipv4Addr,
+        port: u16,
+        buf_size: usize,
+        ifname: Option<&str>,
+        capture_hook: Option<CaptureHook>,
+        checks: FrameChecks,
+    ) -> Result<Self, NetAgentError> {
+        if buf_size < NET_SERVER_MIN_BUF_SIZE {
+            return Err(NetAgentError::BufferTooSmall(
+                buf_size,
+                NET_SERVER_MIN_BUF_SIZE,
+            ));
+        }
+        let socket = open_raw_socket(Domain::IPV4, Some(Protocol::UDP))?;
+        if let Some(ifname) = ifname {
+            bind_to_device(&socket, ifname)?;
+        }
+        socket.set_read_timeout(Some(NET_SERVER_READ_TIMEOUT))?;
         let addr = SocketAddrV4::new(addr, port);
         socket.bind(&addr.into())?;
         info!("UDP server started at {}:{}", addr.ip(), addr.port());
+        Ok(Self::listen(receiver, socket, buf_size, capture_hook, checks))
+    }
+
+    /// Create a receive agent over `socket`, which is opened by another process and passed to this
+    /// one, like `UDPSendAgent::from_socket`.
+    ///
+    /// `socket` should be an IPv4 raw socket of UDP, bound to the address to receive the packets of.
+    /// The other arguments are the same as `with_options`.
+    pub(crate) fn from_socket(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        socket: Socket,
+        buf_size: usize,
+        capture_hook: Option<CaptureHook>,
+        checks: FrameChecks,
+    ) -> Result<Self, NetAgentError> {
+        if buf_size < NET_SERVER_MIN_BUF_SIZE {
+            return Err(NetAgentError::BufferTooSmall(
+                buf_size,
+                NET_SERVER_MIN_BUF_SIZE,
+            ));
+        }
+        check_raw_udp_socket(&socket)?;
+        socket.set_read_timeout(Some(NET_SERVER_READ_TIMEOUT))?;
+        Ok(Self::listen(receiver, socket, buf_size, capture_hook, checks))
+    }
+
+    /// Create a receive agent of the packets sent to the multicast `group`.
+    ///
+    /// The socket joins the group on the interface of `interface_addr` and is bound to the group
+    /// address, so it only picks up the packets of the group. Dropping the agent closes the socket,
+    /// which leaves the group. `capture_hook` and `checks` are the ones of `with_options`.
+    pub(crate) fn with_multicast_group(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        group: Ipv4Addr,
+        interface_addr: Ipv4Addr,
+        port: u16,
+        capture_hook: Option<CaptureHook>,
+        checks: FrameChecks,
+    ) -> Result<Self, NetAgentError> {
+        let socket = open_raw_socket(Domain::IPV4, Some(Protocol::UDP))?;
+        socket.join_multicast_v4(&group, &interface_addr)?;
+        socket.set_read_timeout(Some(NET_SERVER_READ_TIMEOUT))?;
+        socket.bind(&SocketAddrV4::new(group, port).into())?;
+        info!("UDP server joined {group} on {interface_addr}");
+        Ok(Self::listen(
+            receiver,
+            socket,
+            NET_SERVER_BUF_SIZE,
+            capture_hook,
+            checks,
+        ))
+    }
+
+    /// Start the thread that passes the packets received by `socket` to `receiver`.
+    fn listen(
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        socket: Socket,
+        buf_size: usize,
+        capture_hook: Option<CaptureHook>,
+        checks: FrameChecks,
+    ) -> Self {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let thread_stop_flag = Arc::clone(&stop_flag);
+        let opcode_counters = Arc::new(OpcodeCounters::new());
+        let thread_opcode_counters = Arc::clone(&opcode_counters);
         let listen_thread = Some(thread::spawn(move || {
-            let mut buf = [MaybeUninit::<u8>::uninit(); NET_SERVER_BUF_SIZE];
+            let mut buf = vec![MaybeUninit::<u8>::uninit(); buf_size];
+            let mut reassembler =
+                IpReassembler::new(IP_REASSEMBLY_TIMEOUT, IP_REASSEMBLY_MAX_BYTES);
             while !thread_stop_flag.load(Ordering::Relaxed) {
+                reassembler.expire(Instant::now());
                 if let Ok((length, _src)) = socket.recv_from(&mut buf) {
-                    #[allow(clippy::arithmetic_side_effects)]
-                    if length < size_of::<CommonPacketHeader>() + 4 {
-                        error!("Packet too short");
-                        continue;
-                    }
                     // SAFETY: `recv_from` ensures that the buffer is filled with `length` bytes.
                     let received_data = unsafe {
                         std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), length)
                     };
-
-                    match is_icrc_valid(received_data) {
-                        Ok(is_valid) =>{
-                            if !is_valid {
-                                error!("ICRC check failed {:?}", received_data);
-                                continue;
-                            }
-                        }
-                        Err(e) => {
-                            error!("ICRC check failed {:?}", e);
-                            continue;
-                        }
+                    if let Some(hook) = &capture_hook {
+                        hook(received_data, Direction::Rx);
                     }
-                    // skip the ip header and udp header and the icrc
-                    let offset = size_of::<IpUdpHeaders>();
-
-                    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-                    // if we pass the CRC check, it should be ok
-                    let received_data = &received_data[offset..length - ICRC_SIZE];
-                    if let Ok(mut message) = PacketProcessor::to_rdma_message(received_data) {
-                        receiver.recv(&mut message);
+                    let mut whole_packet;
+                    let frame = if is_ip_fragment(received_data) {
+                        let Some(packet) = reassembler.push(received_data, Instant::now()) else {
+                            continue;
+                        };
+                        whole_packet = packet;
+                        whole_packet.as_mut_slice()
+                    } else {
+                        received_data
+                    };
+                    if frame.len() < NET_SERVER_MIN_BUF_SIZE {
+                        error!("Packet too short");
+                        continue;
                     }
+                    deliver_frame(
+                        frame,
+                        &*receiver,
+                        &thread_opcode_counters,
+                        checks,
+                    );
                 }
             }
         }));
-        Ok(Self {
-            listen_thread,
+        Self {
+            listen_thread: Mutex::new(listen_thread),
             stop_flag,
-        })
+            buf_size,
+            opcode_counters,
+        }
+    }
+
+    /// The number of received packets of each opcode.
+    pub(crate) fn opcode_counters(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        self.opcode_counters.snapshot()
+    }
+
+    /// Stop the listen thread, which exits within `NET_SERVER_READ_TIMEOUT`. Stopping a stopped agent
+    /// is a no-op.
+    pub(crate) fn stop(&self) -> Result<(), NetAgentError> {
+        if stop_thread(&self.stop_flag, &self.listen_thread) {
+            Ok(())
+        } else {
+            Err(NetAgentError::ThreadJoinFailed("udp listen agent"))
+        }
+    }
+}
+
+/// Whether the IP packet is a fragment of a larger one
+fn is_ip_fragment(packet: &[u8]) -> bool {
+    Ipv4Header::from_bytes(packet).is_some_and(Ipv4Header::is_fragment)
+}
+
+/// The key of the fragments of an IP packet: the source, the identification and the protocol
+type FragmentKey = (Ipv4Addr, u16, u8);
+
+/// Reassembles the fragmented IP packets, keyed by the source, the identification and the protocol.
+#[derive(Debug)]
+struct IpReassembler {
+    pending: HashMap<FragmentKey, FragmentSet>,
+    /// The incomplete packets are dropped after it
+    timeout: Duration,
+    /// The bytes of all the pending fragments
+    pending_bytes: usize,
+    /// The max of `pending_bytes`, the oldest packets are dropped beyond it
+    max_bytes: usize,
+}
+
+/// The received fragments of an IP packet
+#[derive(Debug)]
+struct FragmentSet {
+    /// The IP header of the first fragment
+    header: Option<Vec<u8>>,
+    /// The data of the fragments, keyed by their offsets in the data of the packet
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// The length of the data of the packet, known once the last fragment arrives
+    data_length: Option<usize>,
+    /// When the first fragment arrived
+    created_at: Instant,
+    /// The bytes of the header and the data kept
+    bytes: usize,
+}
+
+impl FragmentSet {
+    fn new(now: Instant) -> Self {
+        Self {
+            header: None,
+            fragments: BTreeMap::new(),
+            data_length: None,
+            created_at: now,
+            bytes: 0,
+        }
+    }
+
+    /// Build the packet if all of its data has arrived.
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let header = self.header.as_ref()?;
+        let data_length = self.data_length?;
+        let packet_length = header.len().wrapping_add(data_length);
+        let mut packet = Vec::with_capacity(packet_length);
+        packet.extend_from_slice(header);
+        let mut covered = 0_usize;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return None;
+            }
+            // a fragment overlapping the previous ones only adds its tail
+            let end = offset.wrapping_add(data.len());
+            if end > covered {
+                packet.extend_from_slice(data.get(covered.wrapping_sub(*offset)..)?);
+                covered = end;
+            }
+        }
+        if covered != data_length {
+            return None;
+        }
+        let ip_header = Ipv4Header::from_bytes_mut(&mut packet)?;
+        ip_header.set_total_length(u16::try_from(packet_length).ok()?);
+        ip_header.set_flags_fragment_offset(0);
+        ip_header.update_checksum();
+        Some(packet)
     }
 }
 
+impl IpReassembler {
+    fn new(timeout: Duration, max_bytes: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+            pending_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Keep the fragment, and return the reassembled packet if it is the last missing one.
+    fn push(&mut self, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let header_length = size_of::<Ipv4Header>();
+        let Some(ip_header) = Ipv4Header::from_bytes(fragment) else {
+            error!("IP fragment too short");
+            return None;
+        };
+        let total_length = usize::from(ip_header.get_total_length());
+        let Some(data) = fragment.get(header_length..total_length) else {
+            error!("IP fragment shorter than its total length {total_length}");
+            return None;
+        };
+        let key = (
+            ip_header.get_source(),
+            ip_header.get_identification(),
+            ip_header.protocol,
+        );
+        let offset = ip_header.get_fragment_offset();
+        let is_last = !ip_header.is_more_fragments();
+        let end = offset.wrapping_add(data.len());
+        if end > IPV4_MAX_PACKET_SIZE.wrapping_sub(header_length) {
+            error!("IP fragment ends at {end}, beyond the largest packet");
+            return None;
+        }
+        if !self.make_room(header_length.wrapping_add(data.len())) {
+            error!("IP fragment of {} bytes does not fit in the reassembly buffer", data.len());
+            return None;
+        }
+        let set = self
+            .pending
+            .entry(key)
+            .or_insert_with(|| FragmentSet::new(now));
+        let mut added = data.len();
+        if offset == 0 && set.header.is_none() {
+            set.header = fragment.get(..header_length).map(<[u8]>::to_vec);
+            added = added.wrapping_add(header_length);
+        }
+        if is_last {
+            set.data_length = Some(end);
+        }
+        let replaced = set
+            .fragments
+            .insert(offset, data.to_vec())
+            .map_or(0, |replaced| replaced.len());
+        set.bytes = set.bytes.wrapping_add(added).wrapping_sub(replaced);
+        self.pending_bytes = self.pending_bytes.wrapping_add(added).wrapping_sub(replaced);
+        let packet = set.assemble()?;
+        let _: Option<FragmentSet> = self.remove(key);
+        Some(packet)
+    }
+
+    /// Drop the oldest packets until `size` more bytes fit in `max_bytes`. Returns `false` if they
+    /// never fit.
+    fn make_room(&mut self, size: usize) -> bool {
+        while self.pending_bytes.saturating_add(size) > self.max_bytes {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|&(_, set)| set.created_at)
+                .map(|(key, _)| *key);
+            let Some(oldest) = oldest else {
+                return false;
+            };
+            debug!("drop the incomplete IP packet {oldest:?} to make room for the new fragments");
+            let _: Option<FragmentSet> = self.remove(oldest);
+        }
+        true
+    }
+
+    /// Remove the fragments of the packet of `key`
+    fn remove(&mut self, key: FragmentKey) -> Option<FragmentSet> {
+        let set = self.pending.remove(&key)?;
+        self.pending_bytes = self.pending_bytes.saturating_sub(set.bytes);
+        Some(set)
+    }
+
+    /// Drop the packets whose fragments have not all arrived within the timeout.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let pending_bytes = &mut self.pending_bytes;
+        self.pending.retain(|key, set| {
+            let is_alive = now.saturating_duration_since(set.created_at) < timeout;
+            if !is_alive {
+                debug!("drop the incomplete IP packet {key:?}");
+                *pending_bytes = pending_bytes.saturating_sub(set.bytes);
+            }
+            is_alive
+        });
+    }
+}
+
+/// Bind the socket to the network interface `ifname` with `SO_BINDTODEVICE`.
+#[allow(clippy::cast_possible_truncation)]
+fn bind_to_device(socket: &Socket, ifname: &str) -> Result<(), NetAgentError> {
+    // The interface name should leave space for the trailing nul.
+    if ifname.len() >= libc::IFNAMSIZ {
+        return Err(NetAgentError::SetSockOptFailed(libc::EINVAL));
+    }
+    let fd = socket.as_raw_fd();
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            ifname.as_ptr().cast::<libc::c_void>(),
+            ifname.len() as u32, // the length is less than IFNAMSIZ
+        )
+    };
+    if ret != 0_i32 {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(ret);
+        return Err(NetAgentError::SetSockOptFailed(errno));
+    }
+    Ok(())
+}
+
 impl Drop for UDPReceiveAgent {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) =  self.listen_thread.take(){
-            if let Err(e) = thread.join(){
-                error!("Failed to join the udp listen agent thread: {:?}", e);
-            }
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the udp receive agent: {e:?}");
         }
     }
 }
@@ -153,30 +878,58 @@ impl NetSendAgent for UDPSendAgent {
         message: &RdmaMessage,
     ) -> Result<(), NetAgentError> {
         let mut buf = [0u8; NET_SERVER_BUF_SIZE];
-        let src_addr = self.src_addr;
-        let src_port = self.src_port;
-        let ip_id = self
-            .sending_id_counter
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-        let total_length = PacketWriter::new(&mut buf)
-            .src_addr(src_addr)
-            .src_port(src_port)
-            .dest_addr(dest_addr)
-            .dest_port(dest_port)
-            .ip_id(ip_id)
-            .message(message)
-            .write()?;
+        let vlan = self
+            .vlan
+            .read()
+            .map_err(|_| NetAgentError::LockPoisoned("vlan lock"))?;
+        let mut writer =
+            self.prepare_writer(&mut buf, vlan.as_ref(), dest_addr, dest_port, message);
+        if let Some(link_mtu) = self.link_mtu() {
+            for frame in writer.link_mtu(link_mtu).write_fragments()? {
+                self.send_frame(vlan.as_ref(), dest_addr, dest_port, &frame)?;
+            }
+            return Ok(());
+        }
+        let total_length = writer.write()?;
         #[allow(clippy::indexing_slicing)]
         // We are sure that the total_length is less than the buffer size.
-        let sended_size = self.sender.send_to(
-            &buf[0..total_length],
-            &SocketAddrV4::new(dest_addr, dest_port).into(),
-        )?;
-        if total_length != sended_size {
-            return Err(NetAgentError::WrongBytesSending(total_length, sended_size));
+        let frame = &mut buf[0..total_length];
+        if let Some(corrupter) = &self.corrupter {
+            let _: bool = corrupter.corrupt(frame, &message.payload)?;
         }
-        Ok(())
+        self.send_frame(vlan.as_ref(), dest_addr, dest_port, frame)
+    }
+
+    /// Gather the headers, the segments of the payload and the ICRC with `sendmsg`.
+    ///
+    /// It falls back to `send` with the `link_mtu`, since the fragments are built in a buffer anyway,
+    /// and with the `corrupter`, which must not touch the memory the payload is gathered from.
+    fn send_vectored(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        if self.link_mtu().is_some() || self.corrupter.is_some() {
+            return self.send(dest_addr, dest_port, message);
+        }
+        let mut buf = [0u8; NET_SEND_HEADERS_BUF_SIZE];
+        let vlan = self
+            .vlan
+            .read()
+            .map_err(|_| NetAgentError::LockPoisoned("vlan lock"))?;
+        let mut writer =
+            self.prepare_writer(&mut buf, vlan.as_ref(), dest_addr, dest_port, message);
+        let (headers_length, trailer) = writer.write_vectored()?;
+        #[allow(clippy::indexing_slicing)]
+        // the headers are written into the buffer, so their length is less than the buffer size
+        let headers = &buf[..headers_length];
+        let segments: Vec<IoSlice<'_>> = std::iter::once(headers)
+            .chain(message.payload.segments())
+            .chain(std::iter::once(trailer.as_slice()))
+            .map(IoSlice::new)
+            .collect();
+        self.send_frame_vectored(vlan.as_ref(), dest_addr, dest_port, &segments)
     }
 
     fn send_raw(
@@ -190,21 +943,77 @@ impl NetSendAgent for UDPSendAgent {
             .ok_or(NetAgentError::InvalidRdmaMessage(
                 "PayloadInfo should have at least one item".to_owned(),
             ))?;
-        let sended_size = self
-            .sender
-            .send_to(buf, &SocketAddrV4::new(dest_addr, dest_port).into())?;
-        if buf.len() != sended_size {
-            return Err(NetAgentError::WrongBytesSending(buf.len(), sended_size));
-        }
-        Ok(())
+        let resealed = reseal_icrc(buf, self.icrc);
+        let buf = resealed.as_deref().unwrap_or(buf);
+        let vlan = self
+            .vlan
+            .read()
+            .map_err(|_| NetAgentError::LockPoisoned("vlan lock"))?;
+        let tagged_frame;
+        let frame = match vlan.as_ref() {
+            Some(vlan) => {
+                let l2_length = size_of::<VlanEthernetHeaders>();
+                let mut frame = vec![0u8; l2_length.wrapping_add(buf.len())];
+                write_vlan_ethernet_header(
+                    &mut frame,
+                    vlan.src_mac,
+                    vlan.dest_mac,
+                    vlan.vid,
+                    vlan.pcp,
+                );
+                #[allow(clippy::indexing_slicing)]
+                // the frame is allocated with the length of the Ethernet header and the packet
+                frame[l2_length..].copy_from_slice(buf);
+                tagged_frame = frame;
+                tagged_frame.as_slice()
+            }
+            None => buf,
+        };
+        self.send_frame(vlan.as_ref(), dest_addr, dest_port, frame)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{Arc, Mutex};
+    use std::{
+        io,
+        mem::{size_of, MaybeUninit},
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use eui48::MacAddress;
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use crate::{
+        device::{
+            software::{
+                net_agent::{
+                    CaptureHook, Direction, FrameChecks, NetAgentError, NetReceiveLogic,
+                    NetSendAgent,
+                },
+                packet::{Ipv4Header, VlanEthernetHeaders},
+                packet_processor::{is_icrc_valid, IcrcConfig, PacketWriter},
+                types::{
+                    Key, Metadata, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+                    RdmaMessageMetaCommon, RethHeader,
+                },
+            },
+            ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
+        },
+        types::{Psn, UdpChecksumMode},
+    };
+
+    use super::{
+        check_datagram_sent, raw_socket_error, send_with_retry, IpReassembler, UDPReceiveAgent,
+        UDPSendAgent, VlanSender, NET_SEND_MAX_ATTEMPTS, NET_SERVER_BUF_SIZE,
+        NET_SERVER_MIN_BUF_SIZE,
+    };
 
-    use crate::device::software::{net_agent::NetReceiveLogic, types::RdmaMessage};
     #[derive(Debug)]
     struct DummyNetReceiveLogic {
         packets: Arc<Mutex<Vec<RdmaMessage>>>,
@@ -213,9 +1022,449 @@ mod tests {
     unsafe impl Send for DummyNetReceiveLogic {}
 
     impl NetReceiveLogic<'_> for DummyNetReceiveLogic {
-        fn recv(&self, msg: &mut RdmaMessage) {
+        fn recv(&self, _: Ipv4Addr, msg: &mut RdmaMessage) {
             let new_msg = msg.clone();
             self.packets.lock().unwrap().push(new_msg);
         }
     }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_buffer_size_too_small() {
+        let receiver = Arc::new(DummyNetReceiveLogic {
+            packets: Arc::new(Mutex::new(Vec::new())),
+        });
+        let result = UDPReceiveAgent::with_buffer_size(
+            receiver,
+            Ipv4Addr::LOCALHOST,
+            4791,
+            NET_SERVER_MIN_BUF_SIZE - 1,
+        );
+        assert!(matches!(
+            result,
+            Err(NetAgentError::BufferTooSmall(size, NET_SERVER_MIN_BUF_SIZE)) if size == NET_SERVER_MIN_BUF_SIZE - 1
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_bind_to_invalid_device() {
+        let receiver = Arc::new(DummyNetReceiveLogic {
+            packets: Arc::new(Mutex::new(Vec::new())),
+        });
+        let result = UDPReceiveAgent::with_options(
+            receiver,
+            Ipv4Addr::LOCALHOST,
+            4791,
+            NET_SERVER_BUF_SIZE,
+            Some("nonexistent0"),
+            None,
+            FrameChecks::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(NetAgentError::SetSockOptFailed(libc::ENODEV))
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_agents_from_socket() {
+        let addr = Ipv4Addr::new(127, 0, 0, 67);
+        let raw_socket = || Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP)).unwrap();
+
+        // the sockets are opened ahead, as a privileged helper does
+        let send_socket = raw_socket();
+        send_socket.set_header_included_v4(true).unwrap();
+        let recv_socket = raw_socket();
+        recv_socket
+            .bind(&SocketAddrV4::new(addr, 4791).into())
+            .unwrap();
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let receiver = Arc::new(DummyNetReceiveLogic {
+            packets: Arc::clone(&packets),
+        });
+        let _recv_agent = UDPReceiveAgent::from_socket(
+            receiver,
+            recv_socket,
+            NET_SERVER_BUF_SIZE,
+            None,
+            FrameChecks::default(),
+        )
+        .unwrap();
+        let send_agent = UDPSendAgent::from_socket(send_socket, addr, 4791).unwrap();
+        let data = [0x5a_u8; 64];
+        let msg = write_only_message(PayloadInfo::new_with_data(data.as_ptr(), data.len()));
+        send_agent.send(addr, 4791, &msg).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while packets.lock().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let packets = packets.lock().unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].payload.get_length(), data.len());
+
+        // the sockets the agents can not send or receive the packets with are rejected
+        let err = UDPSendAgent::from_socket(raw_socket(), addr, 4791).unwrap_err();
+        assert!(matches!(err, NetAgentError::InvalidSocket(_)), "{err:?}");
+        let udp_socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        let err = UDPSendAgent::from_socket(udp_socket, addr, 4791).unwrap_err();
+        assert!(matches!(err, NetAgentError::InvalidSocket(_)), "{err:?}");
+        let icmp_socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).unwrap();
+        let receiver = Arc::new(DummyNetReceiveLogic {
+            packets: Arc::new(Mutex::new(Vec::new())),
+        });
+        let result = UDPReceiveAgent::from_socket(
+            receiver,
+            icmp_socket,
+            NET_SERVER_BUF_SIZE,
+            None,
+            FrameChecks::default(),
+        );
+        assert!(matches!(result, Err(NetAgentError::InvalidSocket(_))));
+    }
+
+    #[test]
+    fn test_raw_socket_permission_error() {
+        let err = raw_socket_error(io::Error::from_raw_os_error(libc::EPERM));
+        assert!(matches!(err, NetAgentError::InsufficientPrivileges));
+        assert!(err.to_string().contains("CAP_NET_RAW"));
+        let err = raw_socket_error(io::Error::from_raw_os_error(libc::EMFILE));
+        assert!(matches!(err, NetAgentError::Io(_)), "{err:?}");
+    }
+
+    #[test]
+    #[ignore = "only fails without CAP_NET_RAW, run it as an unprivileged user"]
+    fn test_unprivileged_agents() {
+        let err = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap_err();
+        assert!(matches!(err, NetAgentError::InsufficientPrivileges), "{err:?}");
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_drop_idle_agent() {
+        let receiver = Arc::new(DummyNetReceiveLogic {
+            packets: Arc::new(Mutex::new(Vec::new())),
+        });
+        let agent = UDPReceiveAgent::new(receiver, Ipv4Addr::LOCALHOST, 4791).unwrap();
+        let start = Instant::now();
+        drop(agent);
+        assert!(
+            start.elapsed() < Duration::from_millis(100),
+            "dropping an idle agent should not block"
+        );
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_accept_invalid_icrc() {
+        let addr = Ipv4Addr::new(127, 0, 0, 13);
+        // IP/UDP/BTH(opcode='RC_RDMA_WRITE_MIDDLE', dqpn=3)/Raw(bytes([0]*64)) with a broken ICRC
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x6c, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 0x7f, 0x00,
+            0x00, 0x0d, 0x7f, 0x00, 0x00, 0x0d, 0x12, 0xb7, 0x12, 0xb7, 0x00, 0x58, 0x00, 0x00,
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        ];
+        packet.extend_from_slice(&[0u8; 64]);
+        packet.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let send_agent = UDPSendAgent::new(addr, 4791).unwrap();
+        let payload = PayloadInfo::new_with_data(packet.as_ptr(), packet.len());
+
+        for accept_invalid_icrc in [false, true] {
+            let packets = Arc::new(Mutex::new(Vec::new()));
+            let receiver = Arc::new(DummyNetReceiveLogic {
+                packets: Arc::clone(&packets),
+            });
+            let _agent = UDPReceiveAgent::with_options(
+                receiver,
+                addr,
+                4791,
+                NET_SERVER_BUF_SIZE,
+                None,
+                None,
+                FrameChecks {
+                    accept_invalid_icrc,
+                    ..FrameChecks::default()
+                },
+            )
+            .unwrap();
+            send_agent.send_raw(addr, 4791, &payload).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(packets.lock().unwrap().len(), usize::from(accept_invalid_icrc));
+        }
+    }
+
+    /// A write only message of `payload` to the QP 3
+    fn write_only_message(payload: PayloadInfo) -> RdmaMessage {
+        let len = payload.get_length() as u32;
+        RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::RdmaWriteOnly,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(0),
+                },
+                reth: RethHeader {
+                    va: 0x1000,
+                    rkey: Key::new(0x1234),
+                    len,
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_ip_reassembly() {
+        let data: Vec<u8> = (0..1024_u32).map(|i| i as u8).collect();
+        let msg = write_only_message(PayloadInfo::new_with_data(data.as_ptr(), data.len()));
+        // the whole packet of `ip_id`, and its fragments over a 576 bytes link
+        let packet_of = |ip_id: u16| {
+            let mut buf = [0u8; NET_SERVER_BUF_SIZE];
+            let mut writer = PacketWriter::new(&mut buf);
+            let _: &mut PacketWriter<'_, '_> = writer
+                .src_addr(Ipv4Addr::new(127, 0, 0, 1))
+                .src_port(4791)
+                .dest_addr(Ipv4Addr::new(127, 0, 0, 2))
+                .dest_port(4791)
+                .ip_id(ip_id)
+                .message(&msg);
+            let packet = writer.write_fragments().unwrap().remove(0);
+            (packet, writer.link_mtu(576).write_fragments().unwrap())
+        };
+        let (packet, fragments) = packet_of(7);
+        assert_eq!(fragments.len(), 2);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 576));
+        let offsets: Vec<_> = fragments
+            .iter()
+            .map(|fragment| {
+                let header = Ipv4Header::from_bytes(fragment).unwrap();
+                (header.get_fragment_offset(), header.is_more_fragments())
+            })
+            .collect();
+        assert_eq!(offsets, [(0, true), (552, false)]);
+
+        // the fragments are reassembled in any order
+        let now = Instant::now();
+        let mut reassembler = IpReassembler::new(Duration::from_secs(1), 1024 * 1024);
+        assert!(reassembler.push(&fragments[1], now).is_none());
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), packet);
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+
+        // an incomplete packet is dropped after the timeout
+        assert!(reassembler.push(&fragments[0], now).is_none());
+        reassembler.expire(now + Duration::from_secs(1));
+        assert_eq!(reassembler.pending_bytes, 0);
+        assert!(reassembler.push(&fragments[1], now).is_none());
+
+        // the oldest incomplete packet is dropped to keep the fragments within the budget
+        let (other_packet, other_fragments) = packet_of(8);
+        let max_bytes = fragments[0].len() + fragments[1].len();
+        let mut reassembler = IpReassembler::new(Duration::from_secs(1), max_bytes);
+        assert!(reassembler.push(&fragments[0], now).is_none());
+        let later = now + Duration::from_millis(1);
+        assert!(reassembler.push(&other_fragments[0], later).is_none());
+        assert_eq!(reassembler.pending.len(), 1);
+        assert!(reassembler.pending_bytes <= max_bytes);
+        assert_eq!(reassembler.push(&other_fragments[1], later).unwrap(), other_packet);
+        assert!(reassembler.push(&fragments[1], later).is_none());
+        assert!(reassembler.pending_bytes <= max_bytes);
+    }
+
+    #[test]
+    fn test_send_vectored() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let hook_frames = Arc::clone(&frames);
+        let hook: CaptureHook = Arc::new(move |frame: &[u8], _: Direction| {
+            hook_frames.lock().unwrap().push(frame.to_vec());
+        });
+        // segments of odd lengths, so that the words of the checksum span the segments
+        let data: Vec<u8> = (0..1063_u32).map(|i| (i % 251) as u8).collect();
+        let mut payload = PayloadInfo::new();
+        payload.add(data.as_ptr(), 3);
+        payload.add(data[3..].as_ptr(), 999);
+        payload.add(data[1002..].as_ptr(), 61);
+        let msg = write_only_message(payload);
+        for mode in [UdpChecksumMode::Zero, UdpChecksumMode::Computed] {
+            let frames_of = |vectored: bool| {
+                let mut agent =
+                    UDPSendAgent::with_initial_ip_id(Ipv4Addr::LOCALHOST, 4791, 1).unwrap();
+                agent.set_udp_checksum_mode(mode);
+                agent.capture_hook = Some(Arc::clone(&hook));
+                if vectored {
+                    agent.send_vectored(Ipv4Addr::LOCALHOST, 4791, &msg).unwrap();
+                } else {
+                    agent.send(Ipv4Addr::LOCALHOST, 4791, &msg).unwrap();
+                }
+                frames.lock().unwrap().pop().unwrap()
+            };
+            let frame = frames_of(true);
+            assert_eq!(frame, frames_of(false), "{mode:?}");
+            assert!(is_icrc_valid(&mut frame.clone(), IcrcConfig::default())
+                .unwrap()
+                .is_valid());
+        }
+    }
+
+    #[test]
+    fn test_send_errors() {
+        // a datagram larger than an IP packet is rejected by the kernel
+        let agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+        let mut packet = vec![0u8; 70000];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&u16::MAX.to_be_bytes());
+        let payload = PayloadInfo::new_with_data(packet.as_ptr(), packet.len());
+        let err = agent.send_raw(Ipv4Addr::LOCALHOST, 4791, &payload).unwrap_err();
+        assert!(matches!(err, NetAgentError::DatagramTooLarge(70000)), "{err:?}");
+        assert!(err.to_string().contains("lower the pmtu"));
+
+        // a short write is not retried, the rest of a datagram can not be sent
+        let err = check_datagram_sent(100, NET_SEND_MAX_ATTEMPTS, Ok(60)).unwrap_err();
+        assert!(matches!(err, NetAgentError::TruncatedDatagram(100, 60)), "{err:?}");
+
+        // the frames sent to a datagram socket whose peer reads nothing take up its send buffer, and
+        // a full one is reported with the attempts made
+        let (socket, peer) = Socket::pair(Domain::UNIX, Type::DGRAM, None).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket.set_send_buffer_size(4096).unwrap();
+        *agent.vlan.write().unwrap() = Some(VlanSender {
+            socket,
+            vid: 1,
+            pcp: 0,
+            src_mac: MacAddress::default(),
+            dest_mac: MacAddress::default(),
+        });
+        let packet = [0u8; 1000];
+        let payload = PayloadInfo::new_with_data(packet.as_ptr(), packet.len());
+        let frame_length = size_of::<VlanEthernetHeaders>() + packet.len();
+        let mut sent = 0;
+        let err = loop {
+            match agent.send_raw(Ipv4Addr::LOCALHOST, 4791, &payload) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+            assert!(sent < 1000, "the send buffer is never full");
+        };
+        assert!(sent > 0);
+        assert!(
+            matches!(err, NetAgentError::SendBufferFull(len, NET_SEND_MAX_ATTEMPTS) if len == frame_length),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("SO_SNDBUF"));
+
+        // it is transient, a frame read by the peer makes room for another one
+        let mut buf = [MaybeUninit::<u8>::uninit(); 2048];
+        assert_eq!(peer.recv(&mut buf).unwrap(), frame_length);
+        agent.send_raw(Ipv4Addr::LOCALHOST, 4791, &payload).unwrap();
+
+        assert!(check_datagram_sent(100, NET_SEND_MAX_ATTEMPTS, Ok(100)).is_ok());
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // DummyNetReceiveLogic is marked as Send and Sync manually
+    fn test_multicast_group() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let quirky = IcrcConfig::new(0xedb8_8320, 0);
+        // the members check the frames with the ICRC of their own, and capture all of them
+        let members: Vec<_> = [IcrcConfig::default(), quirky]
+            .into_iter()
+            .map(|icrc| {
+                let packets = Arc::new(Mutex::new(Vec::new()));
+                let receiver = Arc::new(DummyNetReceiveLogic {
+                    packets: Arc::clone(&packets),
+                });
+                let captured = Arc::new(AtomicUsize::new(0));
+                let hook_captured = Arc::clone(&captured);
+                let hook: CaptureHook = Arc::new(move |_: &[u8], _: Direction| {
+                    let _: usize = hook_captured.fetch_add(1, Ordering::Relaxed);
+                });
+                let checks = FrameChecks {
+                    icrc,
+                    ..FrameChecks::default()
+                };
+                let agent = UDPReceiveAgent::with_multicast_group(
+                    receiver,
+                    group,
+                    Ipv4Addr::LOCALHOST,
+                    4791,
+                    Some(hook),
+                    checks,
+                )
+                .unwrap();
+                (agent, packets, captured)
+            })
+            .collect();
+
+        // both members receive every send to the group, and only deliver the frames passing their
+        // checks, in the order they are sent
+        let default_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+        let mut quirky_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+        quirky_agent.icrc = quirky;
+        let data = [0x5a_u8; 64];
+        for (agent, len) in [
+            (&default_agent, 64),
+            (&quirky_agent, 32),
+            (&default_agent, 16),
+            (&quirky_agent, 8),
+        ] {
+            let msg = write_only_message(PayloadInfo::new_with_data(data.as_ptr(), len));
+            agent.send(group, 4791, &msg).unwrap();
+        }
+        for ((_agent, packets, captured), expected) in members.iter().zip([[64, 16], [32, 8]]) {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while packets.lock().unwrap().len() < expected.len() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            let lens: Vec<_> = packets
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|packet| packet.payload.get_length())
+                .collect();
+            assert_eq!(lens, expected);
+            assert!(captured.load(Ordering::Relaxed) >= 3);
+        }
+    }
+
+    #[test]
+    fn test_send_retry() {
+        // fail with a transient error twice, then succeed
+        let mut calls = 0;
+        let result = send_with_retry(NET_SEND_MAX_ATTEMPTS, || {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from_raw_os_error(libc::ENOBUFS))
+            } else {
+                Ok(64)
+            }
+        });
+        assert_eq!(result.unwrap(), 64);
+        assert_eq!(calls, 3);
+
+        // give up after max attempts
+        let mut calls = 0;
+        let result = send_with_retry(NET_SEND_MAX_ATTEMPTS, || {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(calls, NET_SEND_MAX_ATTEMPTS);
+
+        // non-transient errors fail fast
+        let mut calls = 0;
+        let result = send_with_retry(NET_SEND_MAX_ATTEMPTS, || {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EPERM));
+        assert_eq!(calls, 1);
+    }
 }
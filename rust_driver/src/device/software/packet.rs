@@ -7,16 +7,17 @@ use std::{
     net::Ipv4Addr,
 };
 
+use eui48::MacAddress;
 use thiserror::Error;
 
 use crate::{
     device::{ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType},
-    types::QpType,
+    types::{EcnCodepoint, QpType},
 };
 
 use super::types::{
     AethHeader, Metadata, PayloadInfo, RdmaGeneralMeta, RdmaMessage, RdmaMessageMetaCommon,
-    RethHeader,
+    RdmaSendMeta, RethHeader,
 };
 
 pub(crate) const ICRC_SIZE: usize = 4;
@@ -25,6 +26,14 @@ pub(crate) const IPV4_DEFAULT_DSCP_AND_ECN: u8 = 0;
 pub(crate) const IPV4_PROTOCOL_UDP: u8 = 0x11;
 pub(crate) const IPV4_DEFAULT_TTL: u8 = 64;
 pub(crate) const RDMA_PAYLOAD_ALIGNMENT: usize = 4;
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+/// The "more fragments" flag of the IPv4 header
+pub(crate) const IPV4_FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+/// The fragment offset of the IPv4 header, in units of 8 bytes
+pub(crate) const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+/// The fragment offsets are in units of 8 bytes
+pub(crate) const IPV4_FRAGMENT_UNIT: usize = 8;
 
 const BTH_OPCODE_MASK: u8 = 0x1F;
 const BTH_TRANSACTION_TYPE_MASK: u8 = 0xE0;
@@ -40,6 +49,10 @@ const AETH_CODE_MASK: u8 = 0x60;
 const AETH_CODE_SHIFT: usize = 5;
 const AETH_VALUE_MASK: u8 = 0x1F;
 const AETH_MSN_MASK: u32 = 0x00FF_FFFF;
+const VLAN_VID_MASK: u16 = 0x0FFF;
+const VLAN_PCP_MASK: u8 = 0x07;
+const VLAN_PCP_SHIFT: u32 = 13;
+const CNP_RESERVED_SIZE: usize = 16;
 
 /// Base Transport Header of RDMA over Ethernet
 #[derive(Clone, Copy)]
@@ -64,8 +77,13 @@ impl BTH {
         (self.tran_type_and_opcode & BTH_TRANSACTION_TYPE_MASK) >> BTH_TRANSACTION_TYPE_SHIFT
     }
 
+    /// The opcode of the packet. It is the whole byte for a CNP, see `ToHostWorkRbDescOpcode::Cnp`.
     pub(crate) fn get_opcode(&self) -> u8 {
-        self.tran_type_and_opcode & BTH_OPCODE_MASK
+        if self.get_transaction_type() == ToHostWorkRbDescTransType::Cnp as u8 {
+            self.tran_type_and_opcode
+        } else {
+            self.tran_type_and_opcode & BTH_OPCODE_MASK
+        }
     }
 
     pub(crate) fn get_solicited(&self) -> bool {
@@ -76,10 +94,21 @@ impl BTH {
         (self.flags & BTH_FLAGS_PAD_CNT_MASK) >> BTH_FLAGS_PAD_CNT_SHIFT
     }
 
-    #[allow(clippy::arithmetic_side_effects)]// pad_cnt is derived from payload_length,and will always be less than payload_length
-    pub(crate) fn get_packet_real_length(&self, payload_length: usize) -> usize {
+    /// The payload length without the trailing pad bytes.
+    ///
+    /// A malformed packet may carry a `pad_cnt` larger than its payload, which is reported
+    /// as `PacketError::InvalidPadCount`.
+    pub(crate) fn get_packet_real_length(
+        &self,
+        payload_length: usize,
+    ) -> Result<usize, PacketError> {
         let pad_cnt: usize = self.get_pad_cnt().into();
-        payload_length - pad_cnt
+        payload_length
+            .checked_sub(pad_cnt)
+            .ok_or(PacketError::InvalidPadCount {
+                pad_cnt,
+                payload_length,
+            })
     }
 
     pub(crate) fn get_pkey(&self) -> u16 {
@@ -122,8 +151,8 @@ impl BTH {
 
     #[allow(clippy::cast_possible_truncation)]
     pub(crate) fn set_pad_cnt(&mut self, pad_cnt: usize) {
-        self.flags =
-            (self.flags & !BTH_FLAGS_PAD_CNT_MASK) | ((pad_cnt as u8) << BTH_FLAGS_PAD_CNT_SHIFT);
+        self.flags = (self.flags & !BTH_FLAGS_PAD_CNT_MASK)
+            | (((pad_cnt as u8) << BTH_FLAGS_PAD_CNT_SHIFT) & BTH_FLAGS_PAD_CNT_MASK);
     }
 
     pub(crate) fn set_pkey(&mut self, pkey: u16) {
@@ -247,8 +276,9 @@ impl AETH {
         u32::from_be_bytes([0, self.value[1], self.value[2], self.value[3]])
     }
 
+    /// Set the code and the value. The code wraps modulo `MAX_AETH_CODE` and the value is truncated to 5 bits.
     pub(crate) fn set_aeth_code_and_value(&mut self, code: u8, value: u8) {
-        self.value[0] = (code % MAX_AETH_CODE) << AETH_CODE_SHIFT | value;
+        self.value[0] = (code % MAX_AETH_CODE) << AETH_CODE_SHIFT | (value & AETH_VALUE_MASK);
     }
 
     pub(crate) fn set_msn(&mut self, msn: u32) {
@@ -259,6 +289,10 @@ impl AETH {
 }
 
 /// The `imm` of RDMA protocol
+///
+/// The immediate data is opaque to the transport, so it's carried byte for byte. The driver keeps it
+/// as a `u32` whose big-endian bytes are the bytes on the wire, which is what a peer reads with
+/// `ntohl`, like the `imm_data` of the verbs.
 pub(crate) struct Immediate([u8; 4]);
 
 impl Immediate {
@@ -271,10 +305,20 @@ impl Immediate {
     }
 }
 
+/// The length of the bytes behind a header of type `H` in a packet of `buf_size` bytes
+fn payload_length_of<H>(buf_size: usize) -> Result<usize, PacketError> {
+    buf_size
+        .checked_sub(size_of::<H>())
+        .ok_or(PacketError::PacketTooShort(buf_size))
+}
+
 /// Rdma packet header trait.
 ///
 /// We use trait instead of enum because the `enum` requires additional space to store the variant.
 pub(crate) trait RdmaPacketHeader: Sized {
+    /// Whether the header ends with the immediate data
+    const HAS_IMMEDIATE: bool = false;
+
     /// Get the pointer to the payload data
     ///
     /// The payload is just behind the header, so we can get the pointer to the payload data by adding 1 to the header pointer.
@@ -309,7 +353,7 @@ impl RdmaPacketHeader for RdmaHeaderReqBthReth {
     fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
         let payload_length = self
             .bth
-            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
         Ok(RdmaMessage {
             meta_data: Metadata::General(RdmaGeneralMeta::new_from_packet(
                 &self.bth, &self.reth, None, None,
@@ -326,7 +370,9 @@ impl RdmaPacketHeader for RdmaHeaderReqBthReth {
                 self.reth.set_from_reth_header(&header.reth);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
         }
     }
 }
@@ -343,7 +389,7 @@ impl RdmaPacketHeader for RdmaHeaderReqBthDoubleReth {
     fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
         let payload_length = self
             .bth
-            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
         Ok(RdmaMessage {
             meta_data: Metadata::General(RdmaGeneralMeta::new_from_packet(
                 &self.bth,
@@ -368,7 +414,9 @@ impl RdmaPacketHeader for RdmaHeaderReqBthDoubleReth {
                 self.secondary_reth.set_from_reth_header(sec_reth);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
         }
     }
 }
@@ -382,10 +430,12 @@ pub(crate) struct RdmaHeaderReqBthRethImm {
 }
 
 impl RdmaPacketHeader for RdmaHeaderReqBthRethImm {
+    const HAS_IMMEDIATE: bool = true;
+
     fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
         let payload_length = self
             .bth
-            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
         Ok(RdmaMessage {
             meta_data: Metadata::General(RdmaGeneralMeta::new_from_packet(
                 &self.bth,
@@ -407,7 +457,77 @@ impl RdmaPacketHeader for RdmaHeaderReqBthRethImm {
                     .set(header.imm.ok_or(PacketError::InvalidMetadataType)?);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
+        }
+    }
+}
+
+/// A composite packet header layout of a SEND, which only contains the BTH
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderReqBth {
+    pub(crate) bth: BTH,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBth {
+    fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        let payload_length = self
+            .bth
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
+        Ok(RdmaMessage {
+            meta_data: Metadata::Send(RdmaSendMeta::new_from_packet(&self.bth, None)?),
+            payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::Send(header) => {
+                self.bth
+                    .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_) | Metadata::Acknowledge(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
+        }
+    }
+}
+
+/// A composite packet header layout of a SEND with immediate data, which contains the BTH and the
+/// Immediate.
+#[repr(C)]
+pub(crate) struct RdmaHeaderReqBthImm {
+    pub(crate) bth: BTH,
+    pub(crate) imm: Immediate,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBthImm {
+    const HAS_IMMEDIATE: bool = true;
+
+    fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        let payload_length = self
+            .bth
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
+        Ok(RdmaMessage {
+            meta_data: Metadata::Send(RdmaSendMeta::new_from_packet(&self.bth, Some(&self.imm))?),
+            payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::Send(header) => {
+                self.bth
+                    .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
+                self.imm
+                    .set(header.imm.ok_or(PacketError::InvalidMetadataType)?);
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_) | Metadata::Acknowledge(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
         }
     }
 }
@@ -423,7 +543,7 @@ impl RdmaPacketHeader for RdmaHeaderRespBthAeth {
     fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
         let payload_length = self
             .bth
-            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+            .get_packet_real_length(payload_length_of::<Self>(buf_size)?)?;
         Ok(RdmaMessage {
             meta_data: Metadata::Acknowledge(AethHeader::new_from_packet(&self.bth, &self.aeth)?),
             payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
@@ -440,23 +560,41 @@ impl RdmaPacketHeader for RdmaHeaderRespBthAeth {
                 self.aeth.set_msn(header.msn);
                 Ok(size_of::<Self>())
             }
-            Metadata::General(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::General(_) | Metadata::Send(_) | Metadata::Cnp(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
         }
     }
 }
 
-pub(crate) type RdmaWriteFirstHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaWriteMiddleHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaWriteLastHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaWriteLastWithImmediateHeader = RdmaHeaderReqBthRethImm;
-pub(crate) type RdmaWriteOnlyHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaWriteOnlyWithImmediateHeader = RdmaHeaderReqBthRethImm;
-pub(crate) type RdmaReadRequestHeader = RdmaHeaderReqBthDoubleReth;
-pub(crate) type RdmaReadResponseFirstHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaReadResponseMiddleHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaReadResponseLastHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaReadResponseOnlyHeader = RdmaHeaderReqBthReth;
-pub(crate) type RdmaAcknowledgeHeader = RdmaHeaderRespBthAeth;
+/// A composite packet header layout of a CNP, which is a BTH followed by 16 reserved bytes.
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderCnp {
+    pub(crate) bth: BTH,
+    reserved: [u8; CNP_RESERVED_SIZE],
+}
+
+impl RdmaPacketHeader for RdmaHeaderCnp {
+    fn to_rdma_message(&self, _buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        Ok(RdmaMessage {
+            meta_data: Metadata::Cnp(RdmaMessageMetaCommon::try_from(&self.bth)?),
+            payload: PayloadInfo::new(),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::Cnp(common_meta) => {
+                self.bth.set_from_common_meta(common_meta, 0);
+                self.reserved = [0; CNP_RESERVED_SIZE];
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_) | Metadata::Acknowledge(_) | Metadata::Send(_) => {
+                Err(PacketError::InvalidMetadataType)
+            }
+        }
+    }
+}
 
 /// The IPv4 header
 #[derive(Clone, Copy)]
@@ -475,6 +613,25 @@ pub(crate) struct Ipv4Header {
 }
 
 impl Ipv4Header {
+    /// View the head of `bytes` as an IPv4 header, or `None` if `bytes` is too short to hold one
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<&Self> {
+        if bytes.len() < size_of::<Self>() {
+            return None;
+        }
+        // SAFETY: the header is packed of bytes, so it has no alignment or invalid value, and the
+        // buffer holds the whole of it
+        Some(unsafe { &*bytes.as_ptr().cast::<Self>() })
+    }
+
+    /// Like `from_bytes`, but the header can be modified in place
+    pub(crate) fn from_bytes_mut(bytes: &mut [u8]) -> Option<&mut Self> {
+        if bytes.len() < size_of::<Self>() {
+            return None;
+        }
+        // SAFETY: the same as `from_bytes`, and the header borrows the buffer exclusively
+        Some(unsafe { &mut *bytes.as_mut_ptr().cast::<Self>() })
+    }
+
     /// set default `version_header_len`,`dscp_ecn`,`ttl` and `protocol`.
     pub(crate) fn set_default_header(&mut self) {
         self.version_header_len = IPV4_DEFAULT_VERSION_AND_HEADER_LENGTH;
@@ -501,6 +658,86 @@ impl Ipv4Header {
     pub(crate) fn set_destination(&mut self, destination: Ipv4Addr) {
         self.destination = destination.octets();
     }
+
+    pub(crate) fn get_total_length(&self) -> u16 {
+        u16::from_be_bytes(self.total_length)
+    }
+
+    pub(crate) fn get_checksum(&self) -> u16 {
+        u16::from_be_bytes(self.checksum)
+    }
+
+    /// The one's complement checksum of the header, taking the checksum field as zero.
+    pub(crate) fn compute_checksum(&self) -> u16 {
+        let [src0, src1, src2, src3] = self.source;
+        let [dst0, dst1, dst2, dst3] = self.destination;
+        let words = [
+            [self.version_header_len, self.dscp_ecn],
+            self.total_length,
+            self.identification,
+            self.flags_fragment_offset,
+            [self.ttl, self.protocol],
+            [src0, src1],
+            [src2, src3],
+            [dst0, dst1],
+            [dst2, dst3],
+        ];
+        let mut sum = words
+            .iter()
+            .fold(0_u32, |sum, word| sum.wrapping_add(u32::from(u16::from_be_bytes(*word))));
+        while sum > 0xffff {
+            sum = (sum & 0xffff).wrapping_add(sum >> 16_i32);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        // the carries are folded, so the sum fits in u16
+        !(sum as u16)
+    }
+
+    /// Fill the checksum of the header. It should be the last step after all the fields are set.
+    pub(crate) fn update_checksum(&mut self) {
+        self.set_checksum(self.compute_checksum());
+    }
+
+    /// Whether the checksum of the header matches the other fields
+    pub(crate) fn verify_checksum(&self) -> bool {
+        self.get_checksum() == self.compute_checksum()
+    }
+
+    pub(crate) fn get_identification(&self) -> u16 {
+        u16::from_be_bytes(self.identification)
+    }
+
+    pub(crate) fn get_flags_fragment_offset(&self) -> u16 {
+        u16::from_be_bytes(self.flags_fragment_offset)
+    }
+
+    /// Whether the packet is a fragment, i.e. more fragments follow it or it is not the first one.
+    pub(crate) fn is_fragment(&self) -> bool {
+        self.get_flags_fragment_offset() & (IPV4_FLAG_MORE_FRAGMENTS | IPV4_FRAGMENT_OFFSET_MASK) != 0
+    }
+
+    /// Whether more fragments of the packet follow this one
+    pub(crate) fn is_more_fragments(&self) -> bool {
+        self.get_flags_fragment_offset() & IPV4_FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// The offset of the fragment data in the original packet data, in bytes
+    pub(crate) fn get_fragment_offset(&self) -> usize {
+        usize::from(self.get_flags_fragment_offset() & IPV4_FRAGMENT_OFFSET_MASK)
+            .wrapping_mul(IPV4_FRAGMENT_UNIT)
+    }
+
+    pub(crate) fn get_source(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.source)
+    }
+
+    pub(crate) fn get_destination(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.destination)
+    }
+
+    pub(crate) fn get_ecn(&self) -> EcnCodepoint {
+        EcnCodepoint::from_bits(self.dscp_ecn)
+    }
 }
 
 /// The UDP Header
@@ -529,6 +766,14 @@ impl UdpHeader {
     pub(crate) fn set_checksum(&mut self, checksum: u16) {
         self.checksum = checksum.to_be_bytes();
     }
+
+    pub(crate) fn get_length(self) -> u16 {
+        u16::from_be_bytes(self.length)
+    }
+
+    pub(crate) fn get_checksum(self) -> u16 {
+        u16::from_be_bytes(self.checksum)
+    }
 }
 
 /// A composite packet header layout that contains the Ipv4 header and the Udp header.
@@ -562,6 +807,63 @@ impl CommonPacketHeader {
     }
 }
 
+/// The Ethernet II header
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub(crate) struct EthernetHeader {
+    dst_mac: [u8; 6],
+    src_mac: [u8; 6],
+    ether_type: [u8; 2],
+}
+
+impl EthernetHeader {
+    pub(crate) fn set_dst_mac(&mut self, mac: MacAddress) {
+        self.dst_mac = mac.to_array();
+    }
+    pub(crate) fn set_src_mac(&mut self, mac: MacAddress) {
+        self.src_mac = mac.to_array();
+    }
+    pub(crate) fn set_ether_type(&mut self, ether_type: u16) {
+        self.ether_type = ether_type.to_be_bytes();
+    }
+}
+
+/// The 802.1Q tag. It follows an Ethernet header whose ether type is `ETHERTYPE_VLAN`,
+/// and carries the ether type of the encapsulated packet.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub(crate) struct VlanTag {
+    tci: [u8; 2],
+    ether_type: [u8; 2],
+}
+
+impl VlanTag {
+    /// Set the tag control information. The bits of `vid` and `pcp` out of range are ignored,
+    /// and the drop eligible indicator is always cleared.
+    pub(crate) fn set_tci(&mut self, vid: u16, pcp: u8) {
+        let pcp = u16::from(pcp & VLAN_PCP_MASK);
+        self.tci = (pcp.wrapping_shl(VLAN_PCP_SHIFT) | (vid & VLAN_VID_MASK)).to_be_bytes();
+    }
+    pub(crate) fn set_ether_type(&mut self, ether_type: u16) {
+        self.ether_type = ether_type.to_be_bytes();
+    }
+}
+
+/// A composite frame header layout that contains the Ethernet header and the 802.1Q tag.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub(crate) struct VlanEthernetHeaders {
+    pub(crate) eth_header: EthernetHeader,
+    pub(crate) vlan_tag: VlanTag,
+}
+
+impl VlanEthernetHeaders {
+    #[allow(clippy::transmute_ptr_to_ref)]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> &'static mut Self {
+        unsafe { transmute(bytes.as_ptr()) }
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
 #[derive(Error, Debug)]
 pub(crate) enum PacketError {
@@ -575,6 +877,15 @@ pub(crate) enum PacketError {
     FailedToConvertAethCode(#[from] num_enum::TryFromPrimitiveError<ToHostWorkRbDescAethCode>),
     #[error("Invalid Metadata type")]
     InvalidMetadataType,
+    #[error("Packet of {0} bytes is shorter than its header")]
+    PacketTooShort(usize),
+    #[error("Pad count {pad_cnt} exceeds the payload length {payload_length}")]
+    InvalidPadCount {
+        pad_cnt: usize,
+        payload_length: usize,
+    },
+    #[error("Payload of {actual} bytes does not match the expected length {expected}")]
+    PayloadLengthMismatch { expected: usize, actual: usize },
 }
 
 impl From<QpType> for ToHostWorkRbDescTransType {
@@ -1,136 +1,140 @@
 use std::{mem::size_of, net::Ipv4Addr};
 
+use eui48::MacAddress;
 use thiserror::Error;
 
-use crate::device::ToHostWorkRbDescOpcode;
+use crate::{
+    device::ToHostWorkRbDescOpcode,
+    types::{EcnCodepoint, UdpChecksumMode},
+};
 
 use super::{
     packet::{
-        CommonPacketHeader, IpUdpHeaders, Ipv4Header, PacketError, RdmaAcknowledgeHeader,
-        RdmaPacketHeader, RdmaReadRequestHeader, RdmaReadResponseFirstHeader,
-        RdmaReadResponseLastHeader, RdmaReadResponseMiddleHeader, RdmaReadResponseOnlyHeader,
-        RdmaWriteFirstHeader, RdmaWriteLastHeader, RdmaWriteLastWithImmediateHeader,
-        RdmaWriteMiddleHeader, RdmaWriteOnlyHeader, RdmaWriteOnlyWithImmediateHeader, BTH,
-        ICRC_SIZE,
+        CommonPacketHeader, Immediate, IpUdpHeaders, Ipv4Header, PacketError, RdmaHeaderCnp,
+        RdmaHeaderReqBth, RdmaHeaderReqBthDoubleReth, RdmaHeaderReqBthImm, RdmaHeaderReqBthReth,
+        RdmaHeaderReqBthRethImm, RdmaHeaderRespBthAeth, RdmaPacketHeader,
+        VlanEthernetHeaders, BTH, ETHERTYPE_IPV4, ETHERTYPE_VLAN, ICRC_SIZE, IPV4_DEFAULT_TTL,
+        IPV4_FLAG_MORE_FRAGMENTS, IPV4_FRAGMENT_UNIT, IPV4_PROTOCOL_UDP, RDMA_PAYLOAD_ALIGNMENT,
     },
-    types::RdmaMessage,
+    types::{Metadata, RdmaMessage},
 };
 
 pub(crate) struct PacketProcessor;
 
 impl PacketProcessor {
     pub(crate) fn to_rdma_message(buf: &[u8]) -> Result<RdmaMessage, PacketError> {
-        let opcode = ToHostWorkRbDescOpcode::try_from(BTH::from_bytes(buf).get_opcode());
-        match opcode {
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteFirst) => {
-                let header = RdmaWriteFirstHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteMiddle) => {
-                let header = RdmaWriteMiddleHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteLast) => {
-                let header = RdmaWriteLastHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate) => {
-                let header = RdmaWriteLastWithImmediateHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteOnly) => {
-                let header = RdmaWriteOnlyHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate) => {
-                let header = RdmaWriteOnlyWithImmediateHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaReadRequest) => {
-                let header = RdmaReadRequestHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaReadResponseFirst) => {
-                let header = RdmaReadResponseFirstHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaReadResponseMiddle) => {
-                let header = RdmaReadResponseMiddleHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaReadResponseLast) => {
-                let header = RdmaReadResponseLastHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::RdmaReadResponseOnly) => {
-                let header = RdmaReadResponseOnlyHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Ok(ToHostWorkRbDescOpcode::Acknowledge) => {
-                let header = RdmaAcknowledgeHeader::from_bytes(buf);
-                Ok(header.to_rdma_message(buf.len())?)
-            }
-            Err(_) => Err(PacketError::InvalidOpcode),
+        if buf.len() < size_of::<BTH>() {
+            return Err(PacketError::PacketTooShort(buf.len()));
         }
+        let opcode = ToHostWorkRbDescOpcode::try_from(BTH::from_bytes(buf).get_opcode())
+            .map_err(|_| PacketError::InvalidOpcode)?;
+        let message = match HeaderLayout::of(&opcode) {
+            HeaderLayout::Bth => Self::parse::<RdmaHeaderReqBth>(&opcode, buf),
+            HeaderLayout::BthImm => Self::parse::<RdmaHeaderReqBthImm>(&opcode, buf),
+            HeaderLayout::BthReth => Self::parse::<RdmaHeaderReqBthReth>(&opcode, buf),
+            HeaderLayout::BthRethImm => Self::parse::<RdmaHeaderReqBthRethImm>(&opcode, buf),
+            HeaderLayout::BthDoubleReth => Self::parse::<RdmaHeaderReqBthDoubleReth>(&opcode, buf),
+            HeaderLayout::BthAeth => Self::parse::<RdmaHeaderRespBthAeth>(&opcode, buf),
+            HeaderLayout::Cnp => Self::parse::<RdmaHeaderCnp>(&opcode, buf),
+        }?;
+        Self::check_read_response(&message)?;
+        Ok(message)
+    }
+
+    /// Parse the packet with the header `H` selected by `opcode`, which carries the immediate data
+    /// exactly when the opcode does.
+    fn parse<H: RdmaPacketHeader + 'static>(
+        opcode: &ToHostWorkRbDescOpcode,
+        buf: &[u8],
+    ) -> Result<RdmaMessage, PacketError> {
+        if H::HAS_IMMEDIATE != opcode.has_immediate() {
+            return Err(PacketError::InvalidOpcode);
+        }
+        H::from_bytes(buf).to_rdma_message(buf.len())
+    }
+
+    /// Reject a read response carrying the immediate data, which its opcode does not allow.
+    ///
+    /// Except the first one, a read response carries the length of its own payload in the RETH, so
+    /// the immediate data shows up as the extra bytes before the payload. They would be written to
+    /// the local memory as the payload otherwise.
+    fn check_read_response(message: &RdmaMessage) -> Result<(), PacketError> {
+        let Metadata::General(ref header) = message.meta_data else {
+            return Ok(());
+        };
+        let opcode = &header.common_meta.opcode;
+        if !opcode.is_read_resp() || opcode.is_first() {
+            return Ok(());
+        }
+        let carries_immediate = message
+            .payload
+            .get_length()
+            .checked_sub(header.reth.len as usize)
+            == Some(size_of::<Immediate>());
+        if carries_immediate {
+            return Err(PacketError::InvalidOpcode);
+        }
+        Ok(())
     }
 
     pub(crate) fn set_from_rdma_message(
         buf: &mut [u8],
         message: &RdmaMessage,
     ) -> Result<usize, PacketError> {
-        match message.meta_data.get_opcode() {
-            ToHostWorkRbDescOpcode::RdmaWriteFirst => {
-                let header = RdmaWriteFirstHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaWriteMiddle => {
-                let header = RdmaWriteMiddleHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaWriteLast => {
-                let header = RdmaWriteLastHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate => {
-                let header = RdmaWriteLastWithImmediateHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaWriteOnly => {
-                let header = RdmaWriteOnlyHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate => {
-                let header = RdmaWriteOnlyWithImmediateHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::RdmaReadRequest => {
-                let header = RdmaReadRequestHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
+        match HeaderLayout::of(&message.meta_data.get_opcode()) {
+            HeaderLayout::Bth => RdmaHeaderReqBth::from_bytes(buf).set_from_rdma_message(message),
+            HeaderLayout::BthImm => {
+                RdmaHeaderReqBthImm::from_bytes(buf).set_from_rdma_message(message)
             }
-            ToHostWorkRbDescOpcode::RdmaReadResponseFirst => {
-                let header = RdmaReadResponseFirstHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
+            HeaderLayout::BthReth => {
+                RdmaHeaderReqBthReth::from_bytes(buf).set_from_rdma_message(message)
             }
-            ToHostWorkRbDescOpcode::RdmaReadResponseMiddle => {
-                let header = RdmaReadResponseMiddleHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
+            HeaderLayout::BthRethImm => {
+                RdmaHeaderReqBthRethImm::from_bytes(buf).set_from_rdma_message(message)
             }
-            ToHostWorkRbDescOpcode::RdmaReadResponseLast => {
-                let header = RdmaReadResponseLastHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
+            HeaderLayout::BthDoubleReth => {
+                RdmaHeaderReqBthDoubleReth::from_bytes(buf).set_from_rdma_message(message)
             }
-            ToHostWorkRbDescOpcode::RdmaReadResponseOnly => {
-                let header = RdmaReadResponseOnlyHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
-            }
-            ToHostWorkRbDescOpcode::Acknowledge => {
-                let header = RdmaAcknowledgeHeader::from_bytes(buf);
-                Ok(header.set_from_rdma_message(message)?)
+            HeaderLayout::BthAeth => {
+                RdmaHeaderRespBthAeth::from_bytes(buf).set_from_rdma_message(message)
             }
+            HeaderLayout::Cnp => RdmaHeaderCnp::from_bytes(buf).set_from_rdma_message(message),
         }
     }
 }
 
+/// The headers following the BTH in a packet, selected by the classification of its opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderLayout {
+    Bth,
+    BthImm,
+    BthReth,
+    BthRethImm,
+    BthDoubleReth,
+    BthAeth,
+    Cnp,
+}
+
+impl HeaderLayout {
+    fn of(opcode: &ToHostWorkRbDescOpcode) -> Self {
+        let has_immediate = opcode.has_immediate();
+        if opcode.is_send() {
+            return if has_immediate { Self::BthImm } else { Self::Bth };
+        }
+        // the packets built by this driver carry the RETH in every write and read response packet
+        if opcode.write_type().is_some() {
+            return if has_immediate { Self::BthRethImm } else { Self::BthReth };
+        }
+        if *opcode == ToHostWorkRbDescOpcode::RdmaReadRequest {
+            return Self::BthDoubleReth;
+        }
+        if *opcode == ToHostWorkRbDescOpcode::Acknowledge {
+            return Self::BthAeth;
+        }
+        Self::Cnp
+    }
+}
+
 #[allow(variant_size_differences)]
 #[derive(Error, Debug)]
 pub(crate) enum PacketProcessorError {
@@ -146,12 +150,136 @@ pub(crate) enum PacketProcessorError {
     MissingMessage,
     #[error("missing ip identification")]
     MissingIpId,
+    #[error("missing src_mac")]
+    MissingSrcMac,
+    #[error("missing dest_mac")]
+    MissingDestMac,
     #[error("Needs a buffer of at least {0} bytes")]
     BufferNotLargeEnough(usize),
     #[error("packet error")]
     PacketError(#[from] PacketError),
     #[error("Length too long :{0}")]
     LengthTooLong(usize),
+    #[error("link mtu {0} can not hold a fragment")]
+    LinkMtuTooSmall(usize),
+}
+
+/// The reflected polynomial of the CRC32 in IEEE 802.3, which the ICRC of `RoCEv2` uses
+const ROCE_ICRC_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// The initial value of the CRC register of the ICRC of `RoCEv2`
+const ROCE_ICRC_SEED: u32 = 0xffff_ffff;
+
+/// The CRC32 parameters of the ICRC. The default is the `RoCEv2` standard.
+///
+/// Another config is only for the interop experiments against the peers computing the ICRC
+/// differently, whose packets would otherwise be dropped. The CRC is always computed LSB first,
+/// and its result is inverted, as the standard one.
+///
+/// ```rust,ignore
+/// let device = DeviceBuilder::new(&network)
+///     .transport(Transport::Loopback)
+///     .icrc(IcrcConfig::new(0xedb8_8320, 0))
+///     .build()?;
+/// ```
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcrcConfig {
+    /// The polynomial in the reflected form, e.g. `0xedb88320` for the standard one
+    pub polynomial: u32,
+    /// The initial value of the CRC register
+    pub seed: u32,
+}
+
+impl IcrcConfig {
+    /// Create a config of the reflected `polynomial` and the initial value `seed`
+    #[must_use]
+    pub fn new(polynomial: u32, seed: u32) -> Self {
+        Self { polynomial, seed }
+    }
+}
+
+impl Default for IcrcConfig {
+    fn default() -> Self {
+        Self {
+            polynomial: ROCE_ICRC_POLYNOMIAL,
+            seed: ROCE_ICRC_SEED,
+        }
+    }
+}
+
+/// Computes the CRC32 of an `IcrcConfig`
+enum IcrcHasher {
+    /// The standard config, with the table driven implementation of `crc32fast`
+    Standard(crc32fast::Hasher),
+    /// Any other config, computed bitwise since it is not on the fast path
+    Custom { polynomial: u32, crc: u32 },
+}
+
+impl IcrcHasher {
+    fn new(config: IcrcConfig) -> Self {
+        if config == IcrcConfig::default() {
+            Self::Standard(crc32fast::Hasher::new())
+        } else {
+            Self::Custom {
+                polynomial: config.polynomial,
+                crc: config.seed,
+            }
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Self::Standard(ref mut hasher) => hasher.update(data),
+            Self::Custom {
+                polynomial,
+                ref mut crc,
+            } => {
+                for byte in data {
+                    *crc ^= u32::from(*byte);
+                    for _ in 0..8_u8 {
+                        *crc = if *crc & 1 == 1 {
+                            (*crc >> 1_u32) ^ polynomial
+                        } else {
+                            *crc >> 1_u32
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    fn finalize(self) -> u32 {
+        match self {
+            Self::Standard(hasher) => hasher.finalize(),
+            Self::Custom { crc, .. } => !crc,
+        }
+    }
+}
+
+/// The lengths of a packet whose headers are written
+struct PacketLayout {
+    /// The length of the Ethernet header before the IP packet, or 0 without the `vlan` option
+    l2_length: usize,
+    /// The length of the IP, UDP and RDMA headers
+    header_length: usize,
+    /// The length of the IP packet
+    total_length: usize,
+    /// The UDP checksum in the buffer before the headers are written
+    passthrough_checksum: u16,
+}
+
+/// The bytes after the payload of a packet: the pad bytes and the ICRC
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PacketTrailer {
+    bytes: [u8; RDMA_PAYLOAD_ALIGNMENT + ICRC_SIZE],
+    length: usize,
+}
+
+impl PacketTrailer {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        self.bytes.get(..self.length).unwrap_or(&self.bytes)
+    }
 }
 
 /// A builder for writing a packet
@@ -163,6 +291,15 @@ pub(crate) struct PacketWriter<'buf, 'message> {
     dest_port: Option<u16>,
     message: Option<&'message RdmaMessage>,
     ip_id: Option<u16>,
+    dscp: u8,
+    ecn: EcnCodepoint,
+    ttl: u8,
+    vlan: Option<(u16, u8)>,
+    src_mac: Option<MacAddress>,
+    dest_mac: Option<MacAddress>,
+    udp_checksum: UdpChecksumMode,
+    link_mtu: Option<usize>,
+    icrc: IcrcConfig,
 }
 
 impl<'buf, 'message> PacketWriter<'buf, 'message> {
@@ -175,6 +312,15 @@ impl<'buf, 'message> PacketWriter<'buf, 'message> {
             dest_port: None,
             message: None,
             ip_id: None,
+            dscp: 0,
+            ecn: EcnCodepoint::NotEct,
+            ttl: IPV4_DEFAULT_TTL,
+            vlan: None,
+            src_mac: None,
+            dest_mac: None,
+            udp_checksum: UdpChecksumMode::Zero,
+            link_mtu: None,
+            icrc: IcrcConfig::default(),
         }
     }
 
@@ -208,41 +354,202 @@ impl<'buf, 'message> PacketWriter<'buf, 'message> {
         new
     }
 
+    /// Set the 6 bits DSCP of the IP header. The higher bits are ignored.
+    pub(crate) fn dscp(&mut self, dscp: u8) -> &mut Self {
+        let new = self;
+        new.dscp = dscp;
+        new
+    }
+
+    /// Set the ECN codepoint of the IP header
+    pub(crate) fn ecn(&mut self, ecn: EcnCodepoint) -> &mut Self {
+        let new = self;
+        new.ecn = ecn;
+        new
+    }
+
+    /// Set the TTL of the IP header, `IPV4_DEFAULT_TTL` by default.
+    #[cfg(test)]
+    pub(crate) fn ttl(&mut self, ttl: u8) -> &mut Self {
+        let new = self;
+        new.ttl = ttl;
+        new
+    }
+
+    /// Prepend an Ethernet header with an 802.1Q tag of `vid` and `pcp` to the IP packet.
+    ///
+    /// The `src_mac` and the `dest_mac` are required then. Without this option, the writer only
+    /// writes the IP packet as before.
+    pub(crate) fn vlan(&mut self, vid: u16, pcp: u8) -> &mut Self {
+        let new = self;
+        new.vlan = Some((vid, pcp));
+        new
+    }
+
+    /// Set the source MAC of the Ethernet header. It is only used with the `vlan` option.
+    pub(crate) fn src_mac(&mut self, mac: MacAddress) -> &mut Self {
+        let new = self;
+        new.src_mac = Some(mac);
+        new
+    }
+
+    /// Set the destination MAC of the Ethernet header. It is only used with the `vlan` option.
+    pub(crate) fn dest_mac(&mut self, mac: MacAddress) -> &mut Self {
+        let new = self;
+        new.dest_mac = Some(mac);
+        new
+    }
+
+    /// Set how the UDP checksum is filled. It is zero by default.
+    pub(crate) fn udp_checksum(&mut self, mode: UdpChecksumMode) -> &mut Self {
+        let new = self;
+        new.udp_checksum = mode;
+        new
+    }
+
+    /// Set how the ICRC is computed. It is the `RoCEv2` standard by default.
+    pub(crate) fn icrc(&mut self, config: IcrcConfig) -> &mut Self {
+        let new = self;
+        new.icrc = config;
+        new
+    }
+
+    /// Set the largest IP packet the link carries. It is only used by `write_fragments`.
+    pub(crate) fn link_mtu(&mut self, mtu: usize) -> &mut Self {
+        let new = self;
+        new.link_mtu = Some(mtu);
+        new
+    }
+
     pub(crate) fn message(&mut self, message: &'message RdmaMessage) -> &mut Self {
         let new = self;
         new.message = Some(message);
         new
     }
 
+    /// Write the packet to the buffer, and return the length of the written frame.
+    ///
+    /// With the `vlan` option, the frame starts with the Ethernet header, and the ICRC only covers
+    /// the IP packet after it.
     pub(crate) fn write(&mut self) -> Result<usize, PacketProcessorError> {
+        let layout = self.write_headers()?;
+        let message = self.message.ok_or(PacketProcessorError::MissingMessage)?;
+        let total_length = layout.total_length;
+        let buf = self
+            .buf
+            .get_mut(layout.l2_length..)
+            .and_then(|buf| buf.get_mut(..total_length))
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(total_length))?;
+
+        // write the payload
+        let payload_buf = buf
+            .get_mut(layout.header_length..)
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(total_length))?;
+        message.payload.copy_to(payload_buf.as_mut_ptr());
+
+        // compute icrc
+        let (packet, icrc_buf) = buf.split_at_mut(total_length.wrapping_sub(ICRC_SIZE));
+        icrc_buf.copy_from_slice(&compute_icrc_vectored(packet, [], self.icrc).to_le_bytes());
+
+        // the UDP checksum covers the ICRC, and the ICRC masks the UDP checksum
+        let udp_checksum = match self.udp_checksum {
+            UdpChecksumMode::Zero => 0,
+            UdpChecksumMode::Computed => compute_udp_checksum(buf)?,
+            UdpChecksumMode::Passthrough => layout.passthrough_checksum,
+        };
+        IpUdpHeaders::from_bytes(buf)
+            .udp_header
+            .set_checksum(udp_checksum);
+        Ok(total_length.wrapping_add(layout.l2_length))
+    }
+
+    /// Write the headers of the packet to the buffer like `write`, but leave the payload in the
+    /// memory of the message, e.g. for the kernel to gather it with `sendmsg`.
+    ///
+    /// Return the length of the headers in the buffer, and the trailer of the packet. The frame is the
+    /// headers, the segments of the payload and the trailer in order.
+    pub(crate) fn write_vectored(
+        &mut self,
+    ) -> Result<(usize, PacketTrailer), PacketProcessorError> {
+        let layout = self.write_headers()?;
+        let message = self.message.ok_or(PacketProcessorError::MissingMessage)?;
+        let headers_length = layout.l2_length.wrapping_add(layout.header_length);
+        let buf = self
+            .buf
+            .get_mut(layout.l2_length..headers_length)
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(headers_length))?;
+
+        let pad = [0u8; RDMA_PAYLOAD_ALIGNMENT];
+        let pad = pad
+            .get(..message.payload.get_pad_cnt())
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(RDMA_PAYLOAD_ALIGNMENT))?;
+        let icrc = compute_icrc_vectored(
+            buf,
+            message.payload.segments().chain([pad]),
+            self.icrc,
+        );
+        let mut trailer = PacketTrailer {
+            bytes: [0u8; RDMA_PAYLOAD_ALIGNMENT + ICRC_SIZE],
+            length: pad.len().wrapping_add(ICRC_SIZE),
+        };
+        #[allow(clippy::indexing_slicing)]
+        // the pad is shorter than `RDMA_PAYLOAD_ALIGNMENT`
+        trailer.bytes[pad.len()..trailer.length].copy_from_slice(&icrc.to_le_bytes());
+
+        let udp_checksum = match self.udp_checksum {
+            UdpChecksumMode::Zero => 0,
+            UdpChecksumMode::Computed => compute_udp_checksum_vectored(
+                buf,
+                message.payload.segments().chain([trailer.as_slice()]),
+            )?,
+            UdpChecksumMode::Passthrough => layout.passthrough_checksum,
+        };
+        IpUdpHeaders::from_bytes(buf)
+            .udp_header
+            .set_checksum(udp_checksum);
+        Ok((headers_length, trailer))
+    }
+
+    /// Write the Ethernet header, the IP and UDP headers and the RDMA headers, leaving the UDP
+    /// checksum for the caller.
+    fn write_headers(&mut self) -> Result<PacketLayout, PacketProcessorError> {
+        let l2_header = match self.vlan {
+            Some((vid, pcp)) => {
+                let src_mac = self.src_mac.ok_or(PacketProcessorError::MissingSrcMac)?;
+                let dest_mac = self.dest_mac.ok_or(PacketProcessorError::MissingDestMac)?;
+                Some((src_mac, dest_mac, vid, pcp))
+            }
+            None => None,
+        };
+        let l2_length = if l2_header.is_some() {
+            size_of::<VlanEthernetHeaders>()
+        } else {
+            0
+        };
+        let buf = self
+            .buf
+            .get_mut(l2_length..)
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(l2_length))?;
+
         // advance `size_of::<IpUdpHeaders>()` to write the rdma header
         let net_packet_offset = size_of::<IpUdpHeaders>();
         let message = self.message.ok_or(PacketProcessorError::MissingMessage)?;
         // write the rdma header
-        let rdma_header_buf = self.buf.get_mut(net_packet_offset..).ok_or(
+        let rdma_header_buf = buf.get_mut(net_packet_offset..).ok_or(
             PacketProcessorError::BufferNotLargeEnough(net_packet_offset),
         )?;
         let rdma_header_length = PacketProcessor::set_from_rdma_message(rdma_header_buf, message)?;
 
         // get the total length(include the ip,udp header and the icrc)
-        let total_length = size_of::<IpUdpHeaders>()
-            .wrapping_add(rdma_header_length)
+        let header_length = net_packet_offset.wrapping_add(rdma_header_length);
+        let total_length = header_length
             .wrapping_add(message.payload.with_pad_length())
             .wrapping_add(ICRC_SIZE);
         let total_length_in_u16 = u16::try_from(total_length)
             .map_err(|_| PacketProcessorError::LengthTooLong(total_length))?;
 
-        // write the payload
-        let header_offset = size_of::<IpUdpHeaders>().wrapping_add(rdma_header_length);
-        let header_buf =
-            self.buf
-                .get_mut(header_offset..)
-                .ok_or(PacketProcessorError::BufferNotLargeEnough(
-                    net_packet_offset,
-                ))?;
-        message.payload.copy_to(header_buf.as_mut_ptr());
-
         // write the ip,udp header
+        let passthrough_checksum = IpUdpHeaders::from_bytes(buf).udp_header.get_checksum();
         let ip_id = self.ip_id.ok_or(PacketProcessorError::MissingIpId)?;
         let src_addr = self.src_addr.ok_or(PacketProcessorError::MissingSrcAddr)?;
         let src_port = self.src_port.ok_or(PacketProcessorError::MissingSrcPort)?;
@@ -253,7 +560,7 @@ impl<'buf, 'message> PacketWriter<'buf, 'message> {
             .dest_port
             .ok_or(PacketProcessorError::MissingDestPort)?;
         write_ip_udp_header(
-            self.buf,
+            buf,
             src_addr,
             src_port,
             dest_addr,
@@ -261,35 +568,129 @@ impl<'buf, 'message> PacketWriter<'buf, 'message> {
             total_length_in_u16,
             ip_id,
         );
-        // compute icrc
-        let icrc_buf = self
-            .buf
-            .get(0..total_length)
-            .ok_or(PacketProcessorError::BufferNotLargeEnough(total_length))?;
-        if total_length < ICRC_SIZE {
-            return Err(PacketProcessorError::BufferNotLargeEnough(ICRC_SIZE));
+        let ip_header = &mut IpUdpHeaders::from_bytes(buf).ip_header;
+        ip_header.dscp_ecn = self.dscp.wrapping_shl(2) | self.ecn.bits();
+        ip_header.ttl = self.ttl;
+        // the checksum covers all the fields above, so it is always the last one to fill
+        ip_header.update_checksum();
+        debug_assert!(
+            ip_header.verify_checksum(),
+            "the IPv4 header checksum is invalid"
+        );
+
+        if let Some((src_mac, dest_mac, vid, pcp)) = l2_header {
+            write_vlan_ethernet_header(self.buf, src_mac, dest_mac, vid, pcp);
         }
-        let icrc = compute_icrc(icrc_buf).to_le_bytes();
-        #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-        // we have checked it is large enough
-        self.buf[total_length - ICRC_SIZE..total_length].copy_from_slice(&icrc);
-        Ok(total_length)
+        Ok(PacketLayout {
+            l2_length,
+            header_length,
+            total_length,
+            passthrough_checksum,
+        })
+    }
+
+    /// Write the packet like `write`, and split it into IP fragments if it exceeds the `link_mtu`.
+    ///
+    /// Return the frames to send in order. Each fragment is prepended with the Ethernet header with
+    /// the `vlan` option. Without the `link_mtu` option, the packet is the only frame.
+    pub(crate) fn write_fragments(&mut self) -> Result<Vec<Vec<u8>>, PacketProcessorError> {
+        let frame_length = self.write()?;
+        let l2_length = if self.vlan.is_some() {
+            size_of::<VlanEthernetHeaders>()
+        } else {
+            0
+        };
+        let frame = self
+            .buf
+            .get(..frame_length)
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(frame_length))?;
+        let (l2_header, packet) = frame.split_at(l2_length);
+        let link_mtu = match self.link_mtu {
+            Some(link_mtu) if packet.len() > link_mtu => link_mtu,
+            _ => return Ok(vec![frame.to_vec()]),
+        };
+        Ok(fragment_ipv4(packet, link_mtu)?
+            .into_iter()
+            .map(|fragment| [l2_header, &fragment].concat())
+            .collect())
     }
 }
 
-/// Assume the buffer is a packet, compute the icrc
+/// Split the IP packet into fragments of at most `link_mtu` bytes.
+///
+/// The fragments keep the IP header of the packet, with their own total length, fragment offset and
+/// the MF flag set on all but the last one. The packet should not have IP options.
+pub(crate) fn fragment_ipv4(
+    packet: &[u8],
+    link_mtu: usize,
+) -> Result<Vec<Vec<u8>>, PacketProcessorError> {
+    let header_length = size_of::<Ipv4Header>();
+    if packet.len() < header_length {
+        return Err(PacketProcessorError::BufferNotLargeEnough(header_length));
+    }
+    let (header, data) = packet.split_at(header_length);
+    // the data of all but the last fragment is a multiple of 8 bytes
+    let max_data_length = link_mtu.saturating_sub(header_length) & !(IPV4_FRAGMENT_UNIT - 1);
+    if max_data_length == 0 {
+        return Err(PacketProcessorError::LinkMtuTooSmall(link_mtu));
+    }
+    let mut fragments = Vec::with_capacity(data.len().div_ceil(max_data_length));
+    let mut offset = 0_usize;
+    for chunk in data.chunks(max_data_length) {
+        let fragment_length = header_length.wrapping_add(chunk.len());
+        let mut fragment = Vec::with_capacity(fragment_length);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(chunk);
+        offset = offset.wrapping_add(chunk.len());
+        let more_fragments = if offset < data.len() {
+            IPV4_FLAG_MORE_FRAGMENTS
+        } else {
+            0
+        };
+        let ip_header = Ipv4Header::from_bytes_mut(&mut fragment)
+            .ok_or(PacketProcessorError::BufferNotLargeEnough(header_length))?;
+        #[allow(clippy::cast_possible_truncation)]
+        // the fragment is shorter than the packet, whose length fits in u16
+        ip_header.set_total_length(fragment_length as u16);
+        #[allow(clippy::cast_possible_truncation)]
+        // the offset of the data fits in the 13 bits field in units of 8 bytes
+        ip_header.set_flags_fragment_offset(
+            more_fragments
+                | (offset.wrapping_sub(chunk.len()) / IPV4_FRAGMENT_UNIT) as u16,
+        );
+        // the total length and the fragment offset changed
+        ip_header.update_checksum();
+        fragments.push(fragment);
+    }
+    Ok(fragments)
+}
+
+/// Assume the buffer is a packet, compute the icrc with `config`
 /// Return a u32 of the icrc
 ///
 /// # Panic
 /// The function made an assumption that the buffer is a valid RDMA packet, in other words,
 /// it should at least contain the common header, ip header, udp header, bth header and the icrc.
 #[allow(clippy::indexing_slicing)]
-pub(crate) fn compute_icrc(data: &[u8]) -> u32 {
-    let mut hasher = crc32fast::Hasher::new();
+pub(crate) fn compute_icrc(data: &[u8], config: IcrcConfig) -> u32 {
+    compute_icrc_vectored(&data[..data.len().wrapping_sub(ICRC_SIZE)], [], config)
+}
+
+/// Compute the icrc of a packet split into the `headers`, and the `rest` of the packet before the icrc.
+///
+/// # Panic
+/// The `headers` should at least contain the ip header, udp header and bth header.
+#[allow(clippy::indexing_slicing)]
+pub(crate) fn compute_icrc_vectored<'a>(
+    headers: &'a [u8],
+    rest: impl IntoIterator<Item = &'a [u8]>,
+    config: IcrcConfig,
+) -> u32 {
+    let mut hasher = IcrcHasher::new(config);
     let prefix = [0xffu8; 8];
     hasher.update(&prefix);
 
-    let mut common_hdr = *CommonPacketHeader::from_bytes(data);
+    let mut common_hdr = *CommonPacketHeader::from_bytes(headers);
 
     common_hdr.net_header.ip_header.dscp_ecn = 0xff;
     common_hdr.net_header.ip_header.ttl = 0xff;
@@ -307,11 +708,94 @@ pub(crate) fn compute_icrc(data: &[u8]) -> u32 {
     };
     hasher.update(common_hdr_bytes);
     // the rest of header and payload
-    hasher.update(&data[size_of::<CommonPacketHeader>()..data.len().wrapping_sub(ICRC_SIZE)]);
+    hasher.update(&headers[size_of::<CommonPacketHeader>()..]);
+    for segment in rest {
+        hasher.update(segment);
+    }
 
     hasher.finalize()
 }
 
+/// Compute the UDP checksum of an IP packet, over the pseudo header and the UDP datagram.
+///
+/// The checksum field in the packet is taken as zero. A computed zero is returned as `0xffff`, since
+/// a zero on the wire means the checksum is not computed.
+pub(crate) fn compute_udp_checksum(packet: &[u8]) -> Result<u16, PacketProcessorError> {
+    if packet.len() < size_of::<IpUdpHeaders>() {
+        return Err(PacketProcessorError::BufferNotLargeEnough(
+            size_of::<IpUdpHeaders>(),
+        ));
+    }
+    let udp_length = IpUdpHeaders::from_bytes(packet).udp_header.get_length();
+    let udp_end = size_of::<Ipv4Header>().wrapping_add(usize::from(udp_length));
+    let packet = packet
+        .get(..udp_end)
+        .ok_or(PacketProcessorError::BufferNotLargeEnough(udp_end))?;
+    compute_udp_checksum_vectored(packet, [])
+}
+
+/// Compute the UDP checksum of an IP packet split into the `headers`, which holds at least the IP
+/// and UDP headers, and the `rest` of the packet.
+///
+/// The segments may have odd lengths. They are summed as if they were one buffer.
+pub(crate) fn compute_udp_checksum_vectored<'a>(
+    headers: &'a [u8],
+    rest: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<u16, PacketProcessorError> {
+    let udp_offset = size_of::<Ipv4Header>();
+    if headers.len() < size_of::<IpUdpHeaders>() {
+        return Err(PacketProcessorError::BufferNotLargeEnough(
+            size_of::<IpUdpHeaders>(),
+        ));
+    }
+    let ip_udp_headers = IpUdpHeaders::from_bytes(headers);
+    let udp_length = ip_udp_headers.udp_header.get_length();
+
+    // the pseudo header: the source and destination addresses, the protocol and the UDP length
+    let mut sum = [
+        u32::from(ip_udp_headers.ip_header.get_source()),
+        u32::from(ip_udp_headers.ip_header.get_destination()),
+    ]
+    .into_iter()
+    .fold(0_u64, |sum, addr| {
+        sum.wrapping_add(u64::from(addr >> 16_u32))
+            .wrapping_add(u64::from(addr & u32::from(u16::MAX)))
+    })
+    .wrapping_add(u64::from(IPV4_PROTOCOL_UDP))
+    .wrapping_add(u64::from(udp_length));
+    // the high byte of a word split between two segments
+    let mut odd_byte = None;
+    let datagram = headers.get(udp_offset..).unwrap_or_default();
+    for mut segment in std::iter::once(datagram).chain(rest) {
+        if let Some(high) = odd_byte.take() {
+            let Some((low, tail)) = segment.split_first() else {
+                odd_byte = Some(high);
+                continue;
+            };
+            sum = sum.wrapping_add(u64::from(u16::from_be_bytes([high, *low])));
+            segment = tail;
+        }
+        let mut words = segment.chunks_exact(2);
+        for word in &mut words {
+            if let [high, low] = *word {
+                sum = sum.wrapping_add(u64::from(u16::from_be_bytes([high, low])));
+            }
+        }
+        odd_byte = words.remainder().first().copied();
+    }
+    if let Some(high) = odd_byte {
+        sum = sum.wrapping_add(u64::from(u16::from_be_bytes([high, 0])));
+    }
+    // take the checksum field as zero
+    sum = sum.wrapping_sub(u64::from(ip_udp_headers.udp_header.get_checksum()));
+    while sum > u64::from(u16::MAX) {
+        sum = (sum & u64::from(u16::MAX)).wrapping_add(sum >> 16_u32);
+    }
+    #[allow(clippy::cast_possible_truncation)] // the sum is folded into 16 bits
+    let checksum = !(sum as u16);
+    Ok(if checksum == 0 { 0xffff } else { checksum })
+}
+
 /// Write the ip and udp header to the buffer
 ///
 /// # Panic
@@ -343,14 +827,54 @@ pub(crate) fn write_ip_udp_header(
     common_hdr.udp_header.set_checksum(0);
 }
 
-/// Assume the buffer is a packet, check if the icrc is valid
-/// Return a bool if the icrc is valid
+/// Write the Ethernet header with an 802.1Q tag of `vid` and `pcp` to the buffer.
+/// The encapsulated packet is an IPv4 packet.
+///
+/// # Panic
+/// the buffer should be large enough to hold the Ethernet header and the tag
+pub(crate) fn write_vlan_ethernet_header(
+    buf: &mut [u8],
+    src_mac: MacAddress,
+    dest_mac: MacAddress,
+    vid: u16,
+    pcp: u8,
+) {
+    let headers = VlanEthernetHeaders::from_bytes(buf);
+    headers.eth_header.set_dst_mac(dest_mac);
+    headers.eth_header.set_src_mac(src_mac);
+    headers.eth_header.set_ether_type(ETHERTYPE_VLAN);
+    headers.vlan_tag.set_tci(vid, pcp);
+    headers.vlan_tag.set_ether_type(ETHERTYPE_IPV4);
+}
+
+/// The result of checking the icrc of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IcrcCheck {
+    /// whether the icrc in the packet is the same as the computed one
+    pub(crate) valid: bool,
+    /// the icrc computed from the packet
+    pub(crate) expected: u32,
+    /// the icrc carried by the packet
+    pub(crate) found: u32,
+}
+
+impl IcrcCheck {
+    pub(crate) fn is_valid(&self) -> bool {
+        self.valid
+    }
+}
+
+/// Assume the buffer is a packet, check if the icrc is valid under `config`
+/// Return the computed icrc and the one in the packet
 ///
 /// # Panic
 /// The function made an assumption that the buffer is a valid RDMA packet, in other words,
 /// it should at least contain the common header, ip header, udp header, bth header and the icrc.
 #[allow(clippy::indexing_slicing)]
-pub(crate) fn is_icrc_valid(received_data: &mut [u8]) -> Result<bool, PacketProcessorError> {
+pub(crate) fn is_icrc_valid(
+    received_data: &mut [u8],
+    config: IcrcConfig,
+) -> Result<IcrcCheck, PacketProcessorError> {
     let length = received_data.len();
     // chcek the icrc
     let icrc_array: [u8; 4] = match received_data[length.wrapping_sub(ICRC_SIZE)..length].try_into() {
@@ -360,13 +884,54 @@ pub(crate) fn is_icrc_valid(received_data: &mut [u8]) -> Result<bool, PacketProc
     };
     let origin_icrc = u32::from_le_bytes(icrc_array);
     received_data[length.wrapping_sub(ICRC_SIZE)..length].copy_from_slice(&[0u8; 4]);
-    let our_icrc = compute_icrc(received_data);
-    Ok(our_icrc == origin_icrc)
+    let our_icrc = compute_icrc(received_data, config);
+    Ok(IcrcCheck {
+        valid: our_icrc == origin_icrc,
+        expected: our_icrc,
+        found: origin_icrc,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::device::software::packet_processor::compute_icrc;
+    use crate::device::{
+        software::packet_processor::{compute_icrc, is_icrc_valid, HeaderLayout, IcrcConfig},
+        ToHostWorkRbDescOpcode,
+    };
+
+    #[test]
+    fn test_header_layout() {
+        use HeaderLayout::{Bth, BthAeth, BthDoubleReth, BthImm, BthReth, BthRethImm};
+        use ToHostWorkRbDescOpcode::*;
+        let table = [
+            (SendFirst, Bth),
+            (SendMiddle, Bth),
+            (SendLast, Bth),
+            (SendLastWithImmediate, BthImm),
+            (SendOnly, Bth),
+            (SendOnlyWithImmediate, BthImm),
+            (RdmaWriteFirst, BthReth),
+            (RdmaWriteMiddle, BthReth),
+            (RdmaWriteLast, BthReth),
+            (RdmaWriteLastWithImmediate, BthRethImm),
+            (RdmaWriteOnly, BthReth),
+            (RdmaWriteOnlyWithImmediate, BthRethImm),
+            (RdmaReadRequest, BthDoubleReth),
+            (RdmaReadResponseFirst, BthReth),
+            (RdmaReadResponseMiddle, BthReth),
+            (RdmaReadResponseLast, BthReth),
+            (RdmaReadResponseOnly, BthReth),
+            (Acknowledge, BthAeth),
+            (Cnp, HeaderLayout::Cnp),
+        ];
+        let covered = (0..=u8::MAX)
+            .filter_map(|value| ToHostWorkRbDescOpcode::try_from(value).ok())
+            .all(|opcode| table.iter().any(|row| row.0 == opcode));
+        assert!(covered);
+        for (opcode, layout) in table {
+            assert_eq!(HeaderLayout::of(&opcode), layout, "{opcode:?}");
+        }
+    }
 
     #[test]
     fn test_computing_icrc() {
@@ -392,7 +957,7 @@ mod tests {
             0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
             0xff, 0xff, 0xc6, 0x87, 0x22, 0x98,
         ];
-        let icrc = compute_icrc(&buf);
+        let icrc = compute_icrc(&buf, IcrcConfig::default());
         assert!(
             icrc == u32::from_le_bytes([0xc6, 0x87, 0x22, 0x98]),
             "icrc: {:x}",
@@ -404,7 +969,70 @@ mod tests {
             183, 0, 32, 0, 0, 17, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0,
         ];
-        let icrc = compute_icrc(&buf);
+        let icrc = compute_icrc(&buf, IcrcConfig::default());
         assert_eq!(icrc, u32::from_le_bytes([64, 33, 163, 207]));
     }
+
+    #[test]
+    fn test_icrc_mismatch() {
+        let mut buf = [
+            69, 0, 0, 0, 0, 0, 0, 0, 64, 17, 124, 232, 127, 0, 0, 3, 127, 0, 0, 2, 18, 183, 18,
+            183, 0, 32, 0, 0, 17, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 33,
+            163, 207,
+        ];
+        let check = is_icrc_valid(&mut buf, IcrcConfig::default()).unwrap();
+        assert!(check.is_valid());
+
+        // the icrc in a packet is little endian, a sender writing it in big endian is caught
+        buf[48..].copy_from_slice(&[207, 163, 33, 64]);
+        let check = is_icrc_valid(&mut buf, IcrcConfig::default()).unwrap();
+        assert!(!check.is_valid());
+        assert_eq!(check.expected, u32::from_le_bytes([64, 33, 163, 207]));
+        assert_eq!(check.found, u32::from_be_bytes([64, 33, 163, 207]));
+    }
+
+    #[test]
+    fn test_icrc_config() {
+        let mut buf = [
+            69, 0, 0, 0, 0, 0, 0, 0, 64, 17, 124, 232, 127, 0, 0, 3, 127, 0, 0, 2, 18, 183, 18,
+            183, 0, 32, 0, 0, 17, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 33,
+            163, 207,
+        ];
+        let known_good = u32::from_le_bytes([64, 33, 163, 207]);
+        let standard = IcrcConfig {
+            polynomial: 0xedb8_8320,
+            seed: 0xffff_ffff,
+        };
+        assert_eq!(IcrcConfig::default(), standard);
+        assert_eq!(compute_icrc(&buf, IcrcConfig::default()), known_good);
+
+        // the bitwise implementation of the custom configs agrees with the standard one
+        let mut hasher = super::IcrcHasher::Custom {
+            polynomial: standard.polynomial,
+            crc: standard.seed,
+        };
+        let mut reference = super::IcrcHasher::new(standard);
+        assert!(matches!(reference, super::IcrcHasher::Standard(_)));
+        for chunk in buf.chunks(7) {
+            hasher.update(chunk);
+            reference.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), reference.finalize());
+
+        // the packets of a peer with another seed only pass the check with the same config
+        let quirky = IcrcConfig {
+            seed: 0,
+            ..IcrcConfig::default()
+        };
+        let icrc = compute_icrc(&buf, quirky);
+        assert_ne!(icrc, known_good);
+        buf[48..].copy_from_slice(&icrc.to_le_bytes());
+        assert!(
+            !is_icrc_valid(&mut buf, IcrcConfig::default())
+                .unwrap()
+                .is_valid()
+        );
+        buf[48..].copy_from_slice(&icrc.to_le_bytes());
+        assert!(is_icrc_valid(&mut buf, quirky).unwrap().is_valid());
+    }
 }
@@ -0,0 +1,437 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crossbeam_queue::SegQueue;
+
+use crate::{
+    device::{
+        scheduler::{descriptor_packet_cnt, resume_descriptor},
+        ToCardWorkRbDesc,
+    },
+    types::{Psn, QpType, MIN_RNR_TIMER_MAX, RNR_RETRY_INFINITE},
+};
+
+use super::{logic::BlueRdmaLogicError, types::Qpn};
+
+/// The wait of every `Qp::min_rnr_timer` in microseconds, see table 45 of the IB spec
+#[allow(clippy::decimal_literal_representation)]
+const RNR_TIMER_MICROS: [u64; 32] = [
+    655_360, 10, 20, 30, 40, 60, 80, 120, 160, 240, 320, 480, 640, 960, 1280, 1920, 2560, 3840, 5120,
+    7680, 10240, 15360, 20480, 30720, 40960, 61440, 81920, 122_880, 163_840, 245_760, 327_680,
+    491_520,
+];
+
+/// The time without any progress of a QP, after which its unacknowledged descriptors are resent. It
+/// recovers the losses no NAK reports, such as the last packet of a message, a read response, or the
+/// ACK or NAK itself.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The read responses a QP keeps for the resent read requests, like the responder resources of the IB
+/// spec. The older ones are dropped.
+const KEPT_READ_RESP_CNT: usize = 16;
+
+/// Decode the timer of an RNR NAK, which is the time to wait before resending the message.
+pub(crate) fn decode_rnr_timer(value: u8) -> Duration {
+    let micros = RNR_TIMER_MICROS
+        .get(usize::from(value & MIN_RNR_TIMER_MAX))
+        .copied()
+        .unwrap_or_default();
+    Duration::from_micros(micros)
+}
+
+/// The result of checking the PSN of a received packet against the expected one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sequence {
+    /// The expected packet, or the first packet of the QP if it starts a message
+    InOrder,
+    /// A packet before the expected one, which has been received. `last_in_order` is the PSN before the
+    /// expected one.
+    Duplicate { last_in_order: Psn },
+    /// Some packets before it are lost, the first of them is `expected`.
+    ///
+    /// `should_nak` is only set for the first packet after the gap, so the gap is reported once.
+    OutOfOrder { expected: Psn, should_nak: bool },
+    /// The first packet of the QP is in the middle of a message, so the packets before it are lost.
+    Unstarted,
+}
+
+/// The next PSN a QP expects to receive
+#[derive(Debug)]
+struct ExpectedPsn {
+    psn: Psn,
+    /// a NAK has been sent for the current gap
+    nak_sent: bool,
+}
+
+/// A descriptor that is sent but not acknowledged yet
+#[derive(Debug)]
+struct SentDescriptor {
+    desc: ToCardWorkRbDesc,
+    packet_cnt: u32,
+}
+
+/// The sent descriptors of a QP that are not acknowledged yet
+#[derive(Debug)]
+struct SentQueue {
+    descs: VecDeque<SentDescriptor>,
+    /// the last time a descriptor is sent for the first time, or an ACK or a read response is received
+    progress_at: Instant,
+}
+
+impl SentDescriptor {
+    fn first_psn(&self) -> Psn {
+        self.desc.common().psn
+    }
+
+    fn last_psn(&self) -> Psn {
+        self.first_psn()
+            .wrapping_add(self.packet_cnt.wrapping_sub(1))
+    }
+
+    fn is_read(&self) -> bool {
+        matches!(self.desc, ToCardWorkRbDesc::Read(_))
+    }
+}
+
+/// The loss recovery of RC QPs.
+///
+/// As a receiver, it tracks the expected PSN of every QP. The packets after a gap are dropped, and a
+/// NAK of PSN sequence error is sent for the first of them. Since only the packets in order are
+/// accepted, every PSN in the half of the PSN space before the expected one has been received, so such a
+/// packet is a duplicate, which is dropped and acknowledged again.
+/// As a sender, it keeps the sent writes until they are acknowledged, and the read requests until the
+/// last packet of their responses is received. On a NAK, they are resent from the PSN in it. On an RNR
+/// NAK, they are resent once the timer in it expires, until the retries of the QP are used up. With a
+/// retransmit timeout, they are also resent once the QP makes no progress for that long. The resent
+/// descriptors are taken by the polling thread and scheduled again.
+///
+/// As the read responses are not acknowledged, the last `KEPT_READ_RESP_CNT` ones of every QP are kept
+/// instead. They are resent from the PSN of a NAK like the writes, and a duplicate read request, which
+/// means the requester has lost its response, resends the whole response.
+///
+/// Once the loss recovery is disabled, the lost descriptors are not resent, and the NAKs fail them.
+#[derive(Debug)]
+pub(crate) struct Retransmission {
+    enabled: bool,
+    timeout: Option<Duration>,
+    expected_psn: Mutex<HashMap<Qpn, ExpectedPsn>>,
+    unacked: Mutex<HashMap<Qpn, SentQueue>>,
+    /// the last read responses sent by every QP
+    read_resps: Mutex<HashMap<Qpn, VecDeque<SentDescriptor>>>,
+    resend_queue: SegQueue<ToCardWorkRbDesc>,
+    /// the RNR NAKs received by every QP since its last ACK
+    rnr_cnt: Mutex<HashMap<Qpn, u8>>,
+    /// the descriptors to resend after an RNR NAK, with the time the NAK is received and its timer
+    rnr_delayed: Mutex<VecDeque<(Instant, Duration, ToCardWorkRbDesc)>>,
+}
+
+impl Retransmission {
+    /// Create the loss recovery resending the lost descriptors on NAKs, without a retransmit timeout.
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: true,
+            timeout: None,
+            expected_psn: Mutex::new(HashMap::new()),
+            unacked: Mutex::new(HashMap::new()),
+            read_resps: Mutex::new(HashMap::new()),
+            resend_queue: SegQueue::new(),
+            rnr_cnt: Mutex::new(HashMap::new()),
+            rnr_delayed: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Enable the loss recovery with the retransmit timeout, or disable it.
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.timeout = enabled.then_some(RETRANSMIT_TIMEOUT);
+    }
+
+    /// Whether the lost descriptors are resent
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check the PSN of a packet received by `qpn`, and move the expected PSN forward if it is in order.
+    ///
+    /// The first packet of a QP is in order if it `starts_message`.
+    pub(crate) fn check_sequence(
+        &self,
+        qpn: Qpn,
+        psn: Psn,
+        starts_message: bool,
+    ) -> Result<Sequence, BlueRdmaLogicError> {
+        let mut expected_psn = self.expected_psn.lock()?;
+        let Some(expected) = expected_psn.get_mut(&qpn) else {
+            if !starts_message {
+                return Ok(Sequence::Unstarted);
+            }
+            let _: Option<ExpectedPsn> = expected_psn.insert(
+                qpn,
+                ExpectedPsn {
+                    psn: psn.wrapping_add(1),
+                    nak_sent: false,
+                },
+            );
+            return Ok(Sequence::InOrder);
+        };
+        if psn == expected.psn {
+            expected.psn = psn.wrapping_add(1);
+            expected.nak_sent = false;
+            Ok(Sequence::InOrder)
+        } else if psn.is_after(expected.psn) {
+            let should_nak = !expected.nak_sent;
+            expected.nak_sent = true;
+            Ok(Sequence::OutOfOrder {
+                expected: expected.psn,
+                should_nak,
+            })
+        } else {
+            Ok(Sequence::Duplicate {
+                last_in_order: expected.psn.wrapping_sub(1),
+            })
+        }
+    }
+
+    /// Expect `psn` of `qpn` again, whose packet is accepted in order but dropped afterwards.
+    pub(crate) fn rewind_sequence(&self, qpn: Qpn, psn: Psn) -> Result<(), BlueRdmaLogicError> {
+        let mut expected_psn = self.expected_psn.lock()?;
+        let _: Option<ExpectedPsn> = expected_psn.insert(
+            qpn,
+            ExpectedPsn {
+                psn,
+                nak_sent: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Take the `packet_cnt` psns from `psn` of `qpn` for the packets received without the network,
+    /// so the packets after them are expected next. Returns `false` and takes nothing if `psn` is not
+    /// the expected one, e.g. the packets before it are still on the network.
+    pub(crate) fn claim_sequence(
+        &self,
+        qpn: Qpn,
+        psn: Psn,
+        packet_cnt: u32,
+        starts_message: bool,
+    ) -> Result<bool, BlueRdmaLogicError> {
+        let mut expected_psn = self.expected_psn.lock()?;
+        let in_order = expected_psn
+            .get(&qpn)
+            .map_or(starts_message, |expected| expected.psn == psn);
+        if in_order {
+            let _: Option<ExpectedPsn> = expected_psn.insert(
+                qpn,
+                ExpectedPsn {
+                    psn: psn.wrapping_add(packet_cnt),
+                    nak_sent: false,
+                },
+            );
+        }
+        Ok(in_order)
+    }
+
+    /// Keep a sent descriptor of a RC QP until it is acknowledged, or among the last read responses.
+    ///
+    /// A resent descriptor is not kept again, because the original one is still kept.
+    pub(crate) fn on_sent(&self, desc: &ToCardWorkRbDesc) -> Result<(), BlueRdmaLogicError> {
+        let common = desc.common();
+        if !self.enabled || !matches!(common.qp_type, QpType::Rc) {
+            return Ok(());
+        }
+        let sent = SentDescriptor {
+            desc: desc.clone(),
+            packet_cnt: descriptor_packet_cnt(desc),
+        };
+        if matches!(desc, ToCardWorkRbDesc::ReadResp(_)) {
+            let mut read_resps = self.read_resps.lock()?;
+            let kept = read_resps.entry(Qpn::new(common.dqpn.get())).or_default();
+            let is_new = kept
+                .back()
+                .map_or(true, |last| sent.last_psn().is_after(last.last_psn()));
+            if is_new {
+                kept.push_back(sent);
+                if kept.len() > KEPT_READ_RESP_CNT {
+                    let _: Option<SentDescriptor> = kept.pop_front();
+                }
+            }
+            return Ok(());
+        }
+        let mut unacked = self.unacked.lock()?;
+        let queue = unacked
+            .entry(Qpn::new(common.dqpn.get()))
+            .or_insert_with(|| SentQueue {
+                descs: VecDeque::new(),
+                progress_at: Instant::now(),
+            });
+        let is_new = match queue.descs.back() {
+            Some(last) => sent.last_psn().is_after(last.last_psn()),
+            None => true,
+        };
+        if is_new {
+            queue.descs.push_back(sent);
+            queue.progress_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Release the writes of `qpn` up to `psn`, which are acknowledged by the remote.
+    ///
+    /// The read requests are kept until their responses are received, since the ACK only means the
+    /// remote has received them.
+    pub(crate) fn on_ack(&self, qpn: Qpn, psn: Psn) -> Result<(), BlueRdmaLogicError> {
+        let _: Option<u8> = self.rnr_cnt.lock()?.remove(&qpn);
+        let mut unacked = self.unacked.lock()?;
+        if let Some(queue) = unacked.get_mut(&qpn) {
+            queue
+                .descs
+                .retain(|sent| sent.is_read() || sent.last_psn().is_after(psn));
+            queue.progress_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Release the oldest read request of `qpn`, whose response has been received.
+    pub(crate) fn on_read_resp(&self, qpn: Qpn) -> Result<(), BlueRdmaLogicError> {
+        let mut unacked = self.unacked.lock()?;
+        if let Some(queue) = unacked.get_mut(&qpn) {
+            if let Some(idx) = queue.descs.iter().position(SentDescriptor::is_read) {
+                let _: Option<SentDescriptor> = queue.descs.remove(idx);
+            }
+            queue.progress_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Resend the whole response of the read request of `qpn` with `msn`, which the requester has
+    /// sent again. Returns `false` if the response is not kept.
+    pub(crate) fn on_duplicate_read(&self, qpn: Qpn, msn: u16) -> Result<bool, BlueRdmaLogicError> {
+        let read_resps = self.read_resps.lock()?;
+        let resp = read_resps
+            .get(&qpn)
+            .and_then(|kept| kept.iter().find(|sent| sent.desc.common().msn.get() == msn));
+        if let Some(resp) = resp {
+            log::debug!("resend the read response of {qpn:?} from {:?}", resp.first_psn());
+            self.resend_queue.push(resp.desc.clone());
+        }
+        Ok(resp.is_some())
+    }
+
+    /// Resend the descriptors of `qpn` from `psn`, which is the first packet lost on the remote.
+    pub(crate) fn on_nak(&self, qpn: Qpn, psn: Psn) -> Result<(), BlueRdmaLogicError> {
+        let descs = self.resume_from(qpn, psn)?;
+        if descs.is_empty() {
+            log::warn!("NAK of {qpn:?} at {psn:?}, but no sent descriptor can be resent");
+        } else {
+            log::debug!("resend {} descriptors of {qpn:?} from {psn:?}", descs.len());
+        }
+        for desc in descs {
+            self.resend_queue.push(desc);
+        }
+        Ok(())
+    }
+
+    /// Resend the descriptors of `qpn` from `psn` after `timer`, because the remote has no receive buffer
+    /// for the message at `psn`.
+    ///
+    /// Returns `false` without resending if the QP has already resent `rnr_retry` times since its last
+    /// ACK, so the message fails.
+    pub(crate) fn on_rnr_nak(
+        &self,
+        qpn: Qpn,
+        psn: Psn,
+        rnr_retry: u8,
+        timer: Duration,
+    ) -> Result<bool, BlueRdmaLogicError> {
+        {
+            let mut rnr_cnt = self.rnr_cnt.lock()?;
+            let cnt = rnr_cnt.entry(qpn).or_insert(0);
+            if rnr_retry != RNR_RETRY_INFINITE && *cnt >= rnr_retry {
+                let _: Option<u8> = rnr_cnt.remove(&qpn);
+                return Ok(false);
+            }
+            *cnt = cnt.saturating_add(1);
+        }
+        let now = Instant::now();
+        let descs = self.resume_from(qpn, psn)?;
+        log::debug!("resend {} descriptors of {qpn:?} from {psn:?} after {timer:?}", descs.len());
+        self.rnr_delayed
+            .lock()?
+            .extend(descs.into_iter().map(|desc| (now, timer, desc)));
+        Ok(true)
+    }
+
+    /// The sent descriptors of `qpn` resumed from `psn`, in the order of their PSNs
+    fn resume_from(&self, qpn: Qpn, psn: Psn) -> Result<Vec<ToCardWorkRbDesc>, BlueRdmaLogicError> {
+        let unacked = self.unacked.lock()?;
+        let read_resps = self.read_resps.lock()?;
+        let mut sents: Vec<&SentDescriptor> = unacked
+            .get(&qpn)
+            .into_iter()
+            .flat_map(|queue| &queue.descs)
+            .chain(read_resps.get(&qpn).into_iter().flatten())
+            .filter(|sent| !psn.is_after(sent.last_psn()))
+            .collect();
+        sents.sort_by_key(|sent| psn.distance_to(sent.first_psn()));
+        let descs = sents
+            .into_iter()
+            .filter_map(|sent| {
+                let skip = if psn.is_after(sent.first_psn()) {
+                    psn.wrapping_abs(sent.first_psn())
+                } else {
+                    0
+                };
+                resume_descriptor(&sent.desc, skip)
+            })
+            .collect();
+        Ok(descs)
+    }
+
+    /// Resend the unacknowledged descriptors of the QPs without any progress for the retransmit timeout.
+    fn resend_timed_out(&self, timeout: Duration) -> Result<(), BlueRdmaLogicError> {
+        let now = Instant::now();
+        let mut unacked = self.unacked.lock()?;
+        for (qpn, queue) in unacked.iter_mut() {
+            let Some(front) = queue.descs.front() else {
+                continue;
+            };
+            if now.duration_since(queue.progress_at) < timeout {
+                continue;
+            }
+            log::debug!(
+                "no progress of {qpn:?} for {timeout:?}, resend {} descriptors from {:?}",
+                queue.descs.len(),
+                front.first_psn()
+            );
+            queue.progress_at = now;
+            for sent in &queue.descs {
+                self.resend_queue.push(sent.desc.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a descriptor to resend, the ones delayed by RNR NAKs are taken once their timers expire.
+    pub(crate) fn pop_resend(&self) -> Option<ToCardWorkRbDesc> {
+        if let Some(desc) = self.resend_queue.pop() {
+            return Some(desc);
+        }
+        if let Some(timeout) = self.timeout {
+            if let Err(e) = self.resend_timed_out(timeout) {
+                log::error!("Failed to resend the timed out descriptors: {e}");
+            }
+            if let Some(desc) = self.resend_queue.pop() {
+                return Some(desc);
+            }
+        }
+        let mut rnr_delayed = self.rnr_delayed.lock().ok()?;
+        let now = Instant::now();
+        let idx = rnr_delayed
+            .iter()
+            .position(|(nak_at, timer, _)| now.duration_since(*nak_at) >= *timer)?;
+        rnr_delayed.remove(idx).map(|(_, _, desc)| desc)
+    }
+}
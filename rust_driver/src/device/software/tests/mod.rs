@@ -15,9 +15,11 @@ use crate::{
 use super::types::{Key, SGList, SGListElementWithKey};
 
 mod test_device;
+mod test_header_accessors;
 mod test_logic;
 mod test_packet;
 mod test_utils;
+mod test_wire;
 
 pub(crate) struct SGListBuilder {
     sg_list: Vec<SGListElementWithKey>,
@@ -62,6 +64,7 @@ pub(crate) struct ToCardWorkRbDescBuilder {
     pmtu: Option<Pmtu>,
     qp_type: Option<QpType>,
     psn: Option<u32>,
+    msn: Option<u16>,
     flags: Option<MemAccessTypeFlag>,
     is_first: Option<bool>,
     is_last: Option<bool>,
@@ -80,6 +83,7 @@ impl ToCardWorkRbDescBuilder {
             pmtu: None,
             qp_type: Some(QpType::Rc),
             psn: Some(0),
+            msn: Some(0),
             flags: Some(MemAccessTypeFlag::empty()),
             is_first: Some(true),
             is_last: Some(true),
@@ -137,6 +141,11 @@ impl ToCardWorkRbDescBuilder {
         self
     }
 
+    pub(crate) fn with_msn(&mut self, msn: u16) -> &mut Self {
+        self.msn = Some(msn);
+        self
+    }
+
     pub(crate) fn with_flags(&mut self, flags: MemAccessTypeFlag) -> &mut Self {
         self.flags = Some(flags);
         self
@@ -164,7 +173,7 @@ impl ToCardWorkRbDescBuilder {
             flags: self.flags.unwrap(),
             dqp_ip: Ipv4Addr::LOCALHOST,
             mac_addr: MacAddress::default(),
-            msn: crate::types::Msn::new(0),
+            msn: crate::types::Msn::new(self.msn.unwrap()),
         };
         let (sge0, sge1, sge2, sge3) = self.sg_list.take().unwrap().into_four_sges();
         match self.opcode.clone().unwrap() {
@@ -176,6 +185,8 @@ impl ToCardWorkRbDescBuilder {
                 sge1,
                 sge2,
                 sge3,
+                ack_slot: None,
+                staged: None,
             }),
             ToCardWorkRbDescOpcode::Read => {
                 ToCardWorkRbDesc::Read(ToCardWorkRbDescRead { common, sge: sge0 })
@@ -200,6 +211,8 @@ impl ToCardWorkRbDescBuilder {
                 sge1,
                 sge2,
                 sge3,
+                ack_slot: None,
+                staged: None,
             }),
         }
     }
@@ -223,6 +236,8 @@ pub(crate) struct ToCardCtrlRbDescBuilder {
     qp_type: Option<QpType>,
     rq_acc_flags: Option<MemAccessTypeFlag>,
     pmtu: Option<Pmtu>,
+    rnr_retry: Option<u8>,
+    min_rnr_timer: Option<u8>,
 }
 
 impl ToCardCtrlRbDescBuilder {
@@ -241,6 +256,8 @@ impl ToCardCtrlRbDescBuilder {
             qp_type: None,
             rq_acc_flags: Some(MemAccessTypeFlag::empty()),
             pmtu: None,
+            rnr_retry: Some(0),
+            min_rnr_timer: Some(0),
         }
     }
 
@@ -305,6 +322,16 @@ impl ToCardCtrlRbDescBuilder {
         self
     }
 
+    pub(crate) fn with_rnr_retry(&mut self, rnr_retry: u8) -> &mut Self {
+        self.rnr_retry = Some(rnr_retry);
+        self
+    }
+
+    pub(crate) fn with_min_rnr_timer(&mut self, min_rnr_timer: u8) -> &mut Self {
+        self.min_rnr_timer = Some(min_rnr_timer);
+        self
+    }
+
     pub(crate) fn build(&self) -> ToCardCtrlRbDesc {
         let common = ToCardCtrlRbDescCommon {
             op_id: self.op_id.unwrap(),
@@ -314,6 +341,7 @@ impl ToCardCtrlRbDescBuilder {
                 ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
                     common,
                     addr: self.addr.unwrap(),
+                    va: self.addr.unwrap(),
                     len: self.len.unwrap(),
                     key: crate::types::Key::new(self.key.unwrap()),
                     pd_hdl: self.pd_hdl.unwrap(),
@@ -330,6 +358,8 @@ impl ToCardCtrlRbDescBuilder {
                     qp_type: self.qp_type.unwrap(),
                     rq_acc_flags: self.rq_acc_flags.unwrap(),
                     pmtu: self.pmtu.unwrap(),
+                    rnr_retry: self.rnr_retry.unwrap(),
+                    min_rnr_timer: self.min_rnr_timer.unwrap(),
                 })
             }
         }
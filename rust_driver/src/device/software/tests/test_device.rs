@@ -1,5 +1,10 @@
+use eui48::MacAddress;
 use serial_test::serial;
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 use std::{sync::Arc, thread::sleep, time::Duration};
 
 use super::SGListBuilder;
@@ -8,13 +13,26 @@ use super::ToCardCtrlRbDescBuilderType::UpdateMrTable;
 use crate::device::software::tests::ToCardWorkRbDescBuilder;
 use crate::device::ToHostWorkRbDescWriteType;
 use crate::device::{
+    scheduler::round_robin::RoundRobinStrategy,
     software::{
-        logic::BlueRDMALogic,
-        net_agent::udp_agent::{UDPReceiveAgent, UDPSendAgent},
+        congestion::LINE_RATE,
+        logic::{BlueRDMALogic, ToHostQueue},
+        net_agent::{
+            loopback_agent::{LoopbackReceiveAgent, LoopbackSendAgent},
+            udp_agent::{UDPReceiveAgent, UDPSendAgent, NET_SERVER_BUF_SIZE},
+            CaptureHook, Direction, FrameChecks, NetAgentError, NetSendAgent,
+        },
+        packet_processor::{is_icrc_valid, IcrcConfig},
+        types::{PayloadInfo, Qpn, RdmaMessage},
+        NetAgents, PacedSends,
     },
-    DeviceAdaptor, SoftwareDevice, ToCardWorkRbDescOpcode, ToHostWorkRbDesc,
+    DeviceAdaptor, SoftwareDevice, ToCardWorkRbDesc, ToCardWorkRbDescOpcode, ToHostRb,
+    ToHostWorkRbDesc, ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode,
 };
-use crate::types::{MemAccessTypeFlag, Pmtu, QpType};
+use crate::types::{
+    EcnCodepoint, MemAccessTypeFlag, Pmtu, QpType, UdpChecksumMode, RNR_RETRY_INFINITE,
+};
+use crate::AlignedMemory;
 
 use super::ToCardCtrlRbDescBuilder;
 
@@ -110,7 +128,8 @@ fn test_device_read_and_write() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         let q2 = device.get_to_host_descriptor_queue().pop().unwrap();
         match q2 {
@@ -123,7 +142,8 @@ fn test_device_read_and_write() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
@@ -153,7 +173,7 @@ fn test_device_read_and_write() {
             .with_pmtu(Pmtu::Mtu512)
             .with_flags(MemAccessTypeFlag::empty())
             .with_qp_type(QpType::Rc)
-            .with_psn(1234)
+            .with_psn(1236)
             .with_dqpn(dqpn)
             .with_sg_list(SGListBuilder::new().with_sge(src_addr, 1024, 0_u32).build())
             .build();
@@ -171,7 +191,8 @@ fn test_device_read_and_write() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         let q2 = device.get_to_host_descriptor_queue().pop().unwrap();
         match q2 {
@@ -183,7 +204,8 @@ fn test_device_read_and_write() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         let q3 = device.get_to_host_descriptor_queue().pop().unwrap();
         match q3 {
@@ -195,7 +217,8 @@ fn test_device_read_and_write() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
@@ -300,10 +323,1053 @@ fn test_device_read_and_write() {
     // }
 }
 
+#[test]
+#[serial]
+fn test_opcode_counters() {
+    let send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+    let recv_agent = UDPReceiveAgent::new(
+        Arc::<BlueRDMALogic>::clone(&device),
+        Ipv4Addr::LOCALHOST,
+        4791,
+    )
+    .unwrap();
+    let src_buf = [1u8; 4096];
+    let src_addr = src_buf.as_ptr() as u64;
+    let pmtu = 512;
+
+    // a write of 3 packets: first, middle and last
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_last(true)
+        .with_is_first(true)
+        .with_total_len(3 * pmtu)
+        .with_raddr(0)
+        .with_rkey(0)
+        .with_dqpn(5)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(0)
+        .with_sg_list(SGListBuilder::new().with_sge(src_addr, 3 * pmtu, 0_u32).build())
+        .build();
+    device.send(desc).unwrap();
+
+    // two single packet writes
+    for psn in 3..5 {
+        let desc = ToCardWorkRbDescBuilder::default()
+            .with_opcode(ToCardWorkRbDescOpcode::Write)
+            .with_is_last(true)
+            .with_is_first(true)
+            .with_total_len(64)
+            .with_raddr(0)
+            .with_rkey(0)
+            .with_dqpn(5)
+            .with_pmtu(Pmtu::Mtu512)
+            .with_qp_type(QpType::Rc)
+            .with_psn(psn)
+            .with_sg_list(SGListBuilder::new().with_sge(src_addr, 64, 0_u32).build())
+            .build();
+        device.send(desc).unwrap();
+    }
+
+    // a read request
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Read)
+        .with_total_len(64)
+        .with_raddr(0)
+        .with_rkey(0)
+        .with_dqpn(5)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(5)
+        .with_sg_list(SGListBuilder::new().with_sge(src_addr, 64, 0_u32).build())
+        .build();
+    device.send(desc).unwrap();
+
+    sleep(Duration::from_millis(100));
+    let counters = recv_agent.opcode_counters();
+    assert_eq!(counters.len(), 6);
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::RdmaWriteFirst], 1);
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::RdmaWriteMiddle], 1);
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::RdmaWriteLast], 1);
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::RdmaWriteOnly], 2);
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::RdmaReadRequest], 1);
+    // every message to the unregistered key is answered with a NAK
+    assert_eq!(counters[&ToHostWorkRbDescOpcode::Acknowledge], 4);
+}
+
+#[test]
+#[serial]
+fn test_capture_hook() {
+    let (frame_sender, frame_receiver) = crossbeam_channel::unbounded();
+    let hook: CaptureHook = Arc::new(move |frame: &[u8], direction: Direction| {
+        frame_sender.send((frame.to_vec(), direction)).unwrap();
+    });
+    let mut send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    send_agent.capture_hook = Some(Arc::clone(&hook));
+    let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+    let _recv_agent = UDPReceiveAgent::with_options(
+        Arc::<BlueRDMALogic>::clone(&device),
+        Ipv4Addr::LOCALHOST,
+        4791,
+        NET_SERVER_BUF_SIZE,
+        None,
+        Some(hook),
+        FrameChecks::default(),
+    )
+    .unwrap();
+    let src_buf = [1u8; 64];
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_total_len(64)
+        .with_raddr(0)
+        .with_rkey(0)
+        .with_dqpn(5)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, 64, 0_u32)
+                .build(),
+        )
+        .build();
+    device.send(desc).unwrap();
+
+    // the frame is captured once when it's sent, and once when it's received
+    let frames: Vec<_> = (0..2)
+        .map(|_| frame_receiver.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect();
+    let (tx, rx): (Vec<_>, Vec<_>) = frames.iter().partition(|(_, d)| *d == Direction::Tx);
+    assert_eq!(tx.len(), 1);
+    assert_eq!(rx.len(), 1);
+    // the on-wire bytes should be captured in both directions, from the IP header to the ICRC.
+    // The IP checksum is filled by the kernel, so only the bytes after the IP header are compared.
+    assert_eq!(tx[0].0.len(), rx[0].0.len());
+    assert_eq!(tx[0].0[0] >> 4, 4);
+    assert_eq!(tx[0].0[20..], rx[0].0[20..]);
+}
+
+/// Write 1024 bytes to the device itself, with the local copy enabled if `local_copy` is set.
+/// Returns the written memory, the number of captured frames and the descriptors to the host.
+fn write_to_self(local_copy: bool, rkey: u32) -> (Vec<u8>, usize, Vec<ToHostWorkRbDesc>) {
+    let frames = Arc::new(Mutex::new(0_usize));
+    let hook_frames = Arc::clone(&frames);
+    let hook: CaptureHook = Arc::new(move |_: &[u8], _: Direction| {
+        *hook_frames.lock().unwrap() += 1;
+    });
+    let mut send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    send_agent.capture_hook = Some(Arc::clone(&hook));
+    let mut device = BlueRDMALogic::new(Arc::new(send_agent));
+    device.set_local_addr(Ipv4Addr::LOCALHOST);
+    device.set_local_copy(local_copy);
+    let device = Arc::new(device);
+    let _recv_agent = UDPReceiveAgent::with_options(
+        Arc::<BlueRDMALogic>::clone(&device),
+        Ipv4Addr::LOCALHOST,
+        4791,
+        NET_SERVER_BUF_SIZE,
+        None,
+        Some(hook),
+        FrameChecks::default(),
+    )
+    .unwrap();
+
+    let src_buf: Vec<u8> = (0..1024_u32).map(|i| i as u8).collect();
+    // the buffer is page aligned, so the packets of every run start at the same offsets
+    let mut dest_buf = AlignedMemory::new(2048).unwrap();
+    let dest_addr = dest_buf.as_mut_ptr() as u64;
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(dest_addr)
+        .with_len(2048)
+        .with_key(1234)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    device.update(mr_desc).unwrap();
+    let desc = ToCardCtrlRbDescBuilder::new(QpManagement)
+        .with_is_valid(true)
+        .with_qpn(5)
+        .with_pd_hdl(0)
+        .with_rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .build();
+    device.update(desc).unwrap();
+
+    // the unaligned write takes 3 packets
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(1024)
+        .with_raddr(dest_addr + 5)
+        .with_rkey(rkey)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(100)
+        .with_dqpn(5)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, 1024, 0_u32)
+                .build(),
+        )
+        .build();
+    device.send(desc).unwrap();
+    sleep(Duration::from_millis(100));
+
+    let queue = device.get_to_host_descriptor_queue();
+    // the addresses are made relative to the destination buffer, so the descriptors of two runs compare
+    let descs = std::iter::from_fn(|| queue.pop())
+        .map(|mut desc| {
+            if let ToHostWorkRbDesc::WriteOrReadResp(write) = &mut desc {
+                write.addr -= dest_addr;
+            }
+            desc
+        })
+        .collect();
+    let frames = *frames.lock().unwrap();
+    (dest_buf[5..1029].to_vec(), frames, descs)
+}
+
+#[test]
+#[serial]
+fn test_local_write() {
+    let (networked, frames, networked_descs) = write_to_self(false, 1234);
+    assert!(frames > 0);
+    assert_eq!(networked_descs.len(), 3);
+
+    // the host gets the same descriptors of the 3 packets, and acknowledges the write by itself
+    let (local, frames, descs) = write_to_self(true, 1234);
+    assert_eq!(local, networked);
+    assert_eq!(local, (0..1024_u32).map(|i| i as u8).collect::<Vec<_>>());
+    assert_eq!(frames, 0);
+    assert_eq!(format!("{descs:?}"), format!("{networked_descs:?}"));
+    let ToHostWorkRbDesc::WriteOrReadResp(last) = &descs[2] else {
+        panic!("unexpected descriptor");
+    };
+    assert_eq!(last.common.dqpn.get(), 5);
+    assert_eq!(last.psn.get(), 102);
+
+    // a write to an unknown MR is rejected without touching the memory, and answered with a NAK of
+    // remote access error. Only the NAK travels the network, and it's captured when sent and received.
+    let (_, _, networked_descs) = write_to_self(false, 4321);
+    let (local, frames, descs) = write_to_self(true, 4321);
+    assert!(local.iter().all(|byte| *byte == 0));
+    assert_eq!(frames, 2);
+    let is_nack = |desc: &&ToHostWorkRbDesc| matches!(desc, ToHostWorkRbDesc::Nack(_));
+    let (nacks, writes): (Vec<_>, Vec<_>) = descs.iter().partition(is_nack);
+    let (networked_nacks, networked_writes): (Vec<_>, Vec<_>) =
+        networked_descs.iter().partition(is_nack);
+    assert_eq!(format!("{writes:?}"), format!("{networked_writes:?}"));
+    assert_eq!(format!("{nacks:?}"), format!("{networked_nacks:?}"));
+    assert!(writes.iter().all(|desc| matches!(
+        desc,
+        ToHostWorkRbDesc::WriteOrReadResp(write) if !write.common.status.is_ok()
+    )));
+    let [ToHostWorkRbDesc::Nack(nack)] = &nacks[..] else {
+        panic!("unexpected descriptors");
+    };
+    assert!(matches!(nack.code, ToHostWorkRbDescAethCode::Nak));
+    assert_eq!(nack.value, 2);
+}
+
+#[test]
+#[serial]
+fn test_udp_checksum() {
+    // a receiver rejecting the zero UDP checksum only takes the packets with a computed one
+    for (mode, delivered) in [(UdpChecksumMode::Zero, 0), (UdpChecksumMode::Computed, 1)] {
+        let send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+        send_agent.set_udp_checksum_mode(mode);
+        let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+        let recv_agent = UDPReceiveAgent::with_options(
+            Arc::<BlueRDMALogic>::clone(&device),
+            Ipv4Addr::LOCALHOST,
+            4791,
+            NET_SERVER_BUF_SIZE,
+            None,
+            None,
+            FrameChecks {
+                accept_zero_udp_checksum: false,
+                ..FrameChecks::default()
+            },
+        )
+        .unwrap();
+        let src_buf = [1u8; 64];
+        let desc = ToCardWorkRbDescBuilder::default()
+            .with_opcode(ToCardWorkRbDescOpcode::Write)
+            .with_total_len(64)
+            .with_raddr(0)
+            .with_rkey(0)
+            .with_dqpn(5)
+            .with_pmtu(Pmtu::Mtu512)
+            .with_qp_type(QpType::Rc)
+            .with_sg_list(
+                SGListBuilder::new()
+                    .with_sge(src_buf.as_ptr() as u64, 64, 0_u32)
+                    .build(),
+            )
+            .build();
+        device.send(desc).unwrap();
+        sleep(Duration::from_millis(100));
+
+        let counters = recv_agent.opcode_counters();
+        let received = counters
+            .get(&ToHostWorkRbDescOpcode::RdmaWriteOnly)
+            .copied()
+            .unwrap_or(0);
+        assert_eq!(received, delivered, "{mode:?}");
+    }
+}
+
+/// Register an aligned buffer of `len` bytes on `logic` as the MR of `rkey` the remote can write
+fn reg_remote_write_mr(logic: &BlueRDMALogic, len: u32, rkey: u32) -> AlignedMemory {
+    let mut buf = AlignedMemory::new(len as usize).unwrap();
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(buf.as_mut_ptr() as u64)
+        .with_len(len)
+        .with_key(rkey)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    logic.update(mr_desc).unwrap();
+    buf
+}
+
+/// A RC write of `src_buf` as a single message to the MR of `rkey` at `raddr`
+fn rc_write_only(src_buf: &[u8], raddr: u64, rkey: u32, pmtu: Pmtu) -> ToCardWorkRbDesc {
+    ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(src_buf.len() as u32)
+        .with_raddr(raddr)
+        .with_rkey(rkey)
+        .with_dqpn(5)
+        .with_pmtu(pmtu)
+        .with_qp_type(QpType::Rc)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, src_buf.len() as u32, 0_u32)
+                .build(),
+        )
+        .build()
+}
+
+#[test]
+#[serial]
+fn test_ip_fragmentation() {
+    let sent_frames = Arc::new(Mutex::new(0_usize));
+    let hook_frames = Arc::clone(&sent_frames);
+    let hook: CaptureHook = Arc::new(move |_: &[u8], direction: Direction| {
+        if direction == Direction::Tx {
+            *hook_frames.lock().unwrap() += 1;
+        }
+    });
+    // the 1024 bytes payload does not fit in a 576 bytes link mtu
+    let mut send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    assert!(send_agent.set_link_mtu(Some(67)).is_err());
+    send_agent.set_link_mtu(Some(576)).unwrap();
+    send_agent.capture_hook = Some(hook);
+    let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+    let recv_agent = UDPReceiveAgent::new(
+        Arc::<BlueRDMALogic>::clone(&device),
+        Ipv4Addr::LOCALHOST,
+        4791,
+    )
+    .unwrap();
+    let rkey = 1234_u32;
+    let dest_buf = reg_remote_write_mr(&device, 1024, rkey);
+    let src_buf = [1u8; 1024];
+    let desc = rc_write_only(&src_buf, dest_buf.as_ptr() as u64, rkey, Pmtu::Mtu1024);
+    device.send(desc).unwrap();
+
+    // the frames are sent before `send` returns, and reassembled into one write by the listen
+    // thread
+    assert_eq!(*sent_frames.lock().unwrap(), 2);
+    assert!(wait_for_descriptors(&device.get_to_host_descriptor_queue(), 1));
+    assert_eq!(recv_agent.opcode_counters()[&ToHostWorkRbDescOpcode::RdmaWriteOnly], 1);
+    assert_eq!(dest_buf[..], src_buf);
+}
+
+#[test]
+#[serial]
+fn test_initial_ip_id() {
+    let capture = || {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let hook_frames = Arc::clone(&frames);
+        let hook: CaptureHook = Arc::new(move |frame: &[u8], direction: Direction| {
+            if direction == Direction::Tx {
+                hook_frames.lock().unwrap().push(frame.to_vec());
+            }
+        });
+        let mut send_agent =
+            UDPSendAgent::with_initial_ip_id(Ipv4Addr::LOCALHOST, 4791, 0xfffe).unwrap();
+        send_agent.capture_hook = Some(hook);
+        let device = BlueRDMALogic::new(Arc::new(send_agent));
+        let src_buf = [1u8; 1500];
+        let desc = ToCardWorkRbDescBuilder::default()
+            .with_opcode(ToCardWorkRbDescOpcode::Write)
+            .with_total_len(1500)
+            .with_raddr(0)
+            .with_rkey(0)
+            .with_dqpn(5)
+            .with_pmtu(Pmtu::Mtu512)
+            .with_qp_type(QpType::Rc)
+            .with_sg_list(
+                SGListBuilder::new()
+                    .with_sge(src_buf.as_ptr() as u64, 1500, 0_u32)
+                    .build(),
+            )
+            .build();
+        device.send(desc).unwrap();
+        let frames = frames.lock().unwrap().clone();
+        frames
+    };
+
+    // the same message is framed into the same bytes by two agents with the same initial IP id
+    let frames = capture();
+    assert_eq!(frames, capture());
+    let ip_ids: Vec<_> = frames
+        .iter()
+        .map(|frame| u16::from_be_bytes([frame[4], frame[5]]))
+        .collect();
+    assert_eq!(ip_ids, [0xfffe, 0xffff, 0]);
+}
+
+#[test]
+#[serial]
+fn test_traffic_class() {
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let hook_frames = Arc::clone(&frames);
+    let hook: CaptureHook = Arc::new(move |frame: &[u8], direction: Direction| {
+        if direction == Direction::Rx {
+            hook_frames.lock().unwrap().push(frame.to_vec());
+        }
+    });
+    let send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    // DSCP EF with ECN-capable transport
+    send_agent.set_traffic_class(46, EcnCodepoint::Ect0);
+    let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+    let _recv_agent = UDPReceiveAgent::with_options(
+        Arc::<BlueRDMALogic>::clone(&device),
+        Ipv4Addr::LOCALHOST,
+        4791,
+        NET_SERVER_BUF_SIZE,
+        None,
+        Some(hook),
+        FrameChecks::default(),
+    )
+    .unwrap();
+    let rkey = 1234_u32;
+    let dest_buf = reg_remote_write_mr(&device, 64, rkey);
+    let src_buf = [1u8; 64];
+    let desc = rc_write_only(&src_buf, dest_buf.as_ptr() as u64, rkey, Pmtu::Mtu512);
+    device.send(desc).unwrap();
+    assert!(wait_for_descriptors(&device.get_to_host_descriptor_queue(), 1));
+    assert_eq!(dest_buf[..], src_buf);
+
+    let mut frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 1);
+    let frame = &mut frames[0];
+    assert_eq!(frame[1], (46 << 2) | 0b10);
+    // the ECN bits are masked when computing the ICRC
+    assert!(is_icrc_valid(frame, IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+}
+
+#[test]
+#[serial]
+fn test_vlan() {
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let hook_frames = Arc::clone(&frames);
+    let hook: CaptureHook = Arc::new(move |frame: &[u8], direction: Direction| {
+        if direction == Direction::Tx {
+            hook_frames.lock().unwrap().push(frame.to_vec());
+        }
+    });
+    let mut send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    send_agent.capture_hook = Some(hook);
+    let src_mac = MacAddress::new([0x02, 0, 0, 0, 0, 0x01]);
+    let dest_mac = MacAddress::new([0x02, 0, 0, 0, 0, 0x02]);
+    send_agent.set_vlan("lo", 100, 5, src_mac, dest_mac).unwrap();
+    let device = Arc::new(BlueRDMALogic::new(Arc::new(send_agent)));
+    let src_buf = [1u8; 64];
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_total_len(64)
+        .with_raddr(0)
+        .with_rkey(0)
+        .with_dqpn(5)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, 64, 0_u32)
+                .build(),
+        )
+        .build();
+    device.send(desc).unwrap();
+
+    let mut frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 1);
+    let frame = &mut frames[0];
+    assert_eq!(frame[0..6], dest_mac.to_array());
+    assert_eq!(frame[6..12], src_mac.to_array());
+    // TPID, then the TCI with pcp 5 and vid 100, then the ether type of IPv4
+    assert_eq!(frame[12..18], [0x81, 0x00, 0xa0, 0x64, 0x08, 0x00]);
+    assert_eq!(frame[18] >> 4, 4);
+    // the ICRC only covers the IP packet
+    assert!(is_icrc_valid(&mut frame[18..], IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+}
+
+#[test]
+#[serial]
+fn test_cnp() {
+    // `ToCardWorkRbDescBuilder` sends to `Ipv4Addr::LOCALHOST`, which is the receiver
+    let sender_addr = Ipv4Addr::new(127, 0, 0, 16);
+    let send_agent = Arc::new(LoopbackSendAgent::new(sender_addr, 4791));
+    // as if the packets had passed a congested switch
+    send_agent.set_traffic_class(0, EcnCodepoint::Ce);
+    let sender = Arc::new(BlueRDMALogic::new(send_agent));
+    let sender_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&sender), sender_addr).unwrap();
+    let receiver = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let receiver_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&receiver), Ipv4Addr::LOCALHOST)
+            .unwrap();
+
+    let qpn = Qpn::new(5);
+    assert_eq!(sender.congestion_control().rate(qpn), LINE_RATE);
+    let rkey = 1234_u32;
+    let dest_buf = reg_remote_write_mr(&receiver, 64, rkey);
+    let src_buf = [1u8; 64];
+    let desc = rc_write_only(&src_buf, dest_buf.as_ptr() as u64, rkey, Pmtu::Mtu512);
+    sender.send(desc).unwrap();
+
+    // the rate recovers in a few milliseconds, so check it as soon as the CNP is handled
+    let is_slowed_down = (0..1000).any(|_| {
+        let slowed = sender.congestion_control().rate(qpn) < LINE_RATE;
+        if !slowed {
+            sleep(Duration::from_millis(1));
+        }
+        slowed
+    });
+    assert!(is_slowed_down);
+    assert_eq!(receiver_agent.opcode_counters()[&ToHostWorkRbDescOpcode::RdmaWriteOnly], 1);
+    assert_eq!(sender_agent.opcode_counters()[&ToHostWorkRbDescOpcode::Cnp], 1);
+    assert!(wait_for_descriptors(&receiver.get_to_host_descriptor_queue(), 1));
+    assert_eq!(dest_buf[..], src_buf);
+    // a CNP is never reported to the host
+    assert!(sender.get_to_host_descriptor_queue().is_empty());
+}
+
+#[test]
+#[serial]
+fn test_paced_sends() {
+    let logic = BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::new(127, 0, 0, 17),
+        4791,
+    )));
+    let slowed_qpn = Qpn::new(5);
+    let other_qpn = Qpn::new(6);
+    // cut the rate to the min one, so a page of the QP takes hundreds of microseconds
+    for _ in 0..10 {
+        logic.congestion_control().on_cnp(slowed_qpn).unwrap();
+    }
+    let src_buf = [1u8; 4096];
+    let write = |qpn: Qpn| {
+        ToCardWorkRbDescBuilder::default()
+            .with_opcode(ToCardWorkRbDescOpcode::Write)
+            .with_total_len(4096)
+            .with_raddr(0)
+            .with_rkey(0)
+            .with_dqpn(qpn.get())
+            .with_pmtu(Pmtu::Mtu4096)
+            .with_qp_type(QpType::Rc)
+            .with_sg_list(
+                SGListBuilder::new()
+                    .with_sge(src_buf.as_ptr() as u64, 4096, 0_u32)
+                    .build(),
+            )
+            .build()
+    };
+
+    let mut paced = PacedSends::default();
+    paced.send_or_hold(&logic, write(slowed_qpn));
+    paced.send_or_hold(&logic, write(slowed_qpn));
+    // the other QP is not held back by the slowed one
+    paced.send_or_hold(&logic, write(other_qpn));
+    assert_eq!(paced.held.keys().collect::<Vec<_>>(), [&slowed_qpn]);
+    assert_eq!(paced.held[&slowed_qpn].len(), 1);
+
+    let send_at = paced.next_send_at().unwrap();
+    sleep(send_at.saturating_duration_since(Instant::now()));
+    paced.send_ready(&logic);
+    assert!(paced.held.is_empty());
+    assert!(paced.next_send_at().is_none());
+}
+
+#[test]
+#[serial]
+fn test_retransmit_on_psn_gap() {
+    // `ToCardWorkRbDescBuilder` sends to `Ipv4Addr::LOCALHOST`, which is the receiver
+    let sender = SoftwareDevice::init_loopback(
+        Ipv4Addr::new(127, 0, 0, 17),
+        4791,
+        Arc::new(RoundRobinStrategy::new()),
+    )
+    .unwrap();
+    let receiver = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let receiver_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&receiver), Ipv4Addr::LOCALHOST)
+            .unwrap();
+    let rkey = 1234_u32;
+    let src_buf: Vec<u8> = (0..2048_u32).map(|i| i as u8).collect();
+    // align the destination to pmtu, so the message is 4 packets
+    let dest_buf = vec![0u8; 4096];
+    let dest_addr = (dest_buf.as_ptr() as u64 + 511) & !511;
+    let dest_offset = (dest_addr - dest_buf.as_ptr() as u64) as usize;
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(dest_addr)
+        .with_len(2048)
+        .with_key(rkey)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    receiver.update(mr_desc).unwrap();
+
+    // the second of the 4 packets is lost
+    receiver_agent.drop_nth_frame(2);
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(2048)
+        .with_raddr(dest_addr)
+        .with_rkey(rkey)
+        .with_dqpn(6)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(100)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, 2048, 0_u32)
+                .build(),
+        )
+        .build();
+    sender.to_card_work_rb().push(desc).unwrap();
+
+    let to_host_queue = receiver.get_to_host_descriptor_queue();
+    let is_complete = (0..1000).any(|_| {
+        let complete = to_host_queue.len() >= 4;
+        if !complete {
+            sleep(Duration::from_millis(1));
+        }
+        complete
+    });
+    assert!(is_complete);
+    assert_eq!(dest_buf[dest_offset..dest_offset + 2048], src_buf);
+    // the packets after the gap are dropped until the lost one is resent
+    let psns: Vec<u32> = std::iter::from_fn(|| to_host_queue.pop())
+        .map(|desc| match desc {
+            ToHostWorkRbDesc::WriteOrReadResp(data) => data.psn.get(),
+            ToHostWorkRbDesc::Read(_)
+            | ToHostWorkRbDesc::WriteWithImm(_)
+            | ToHostWorkRbDesc::Ack(_)
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+        })
+        .collect();
+    assert_eq!(psns, [100, 101, 102, 103]);
+}
+
+/// Pop a descriptor from `rb`, waiting up to a second for it
+fn pop_descriptor(rb: &dyn ToHostRb<ToHostWorkRbDesc>) -> ToHostWorkRbDesc {
+    rb.pop(Duration::from_secs(1))
+        .unwrap()
+        .expect("no descriptor is received")
+}
+
+/// Wait until `queue` has at least `len` descriptors
+fn wait_for_descriptors(queue: &ToHostQueue<ToHostWorkRbDesc>, len: usize) -> bool {
+    wait_until(|| queue.len() >= len)
+}
+
+/// Poll `ready` for up to a second, and return whether it turned true
+fn wait_until(mut ready: impl FnMut() -> bool) -> bool {
+    (0..1000).any(|_| {
+        let is_ready = ready();
+        if !is_ready {
+            sleep(Duration::from_millis(1));
+        }
+        is_ready
+    })
+}
+
+#[test]
+#[serial]
+fn test_duplicate_write() {
+    // `ToCardWorkRbDescBuilder` sends to `Ipv4Addr::LOCALHOST`, which is the receiver
+    let sender_addr = Ipv4Addr::new(127, 0, 0, 18);
+    let sender = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        sender_addr,
+        4791,
+    ))));
+    let sender_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&sender), sender_addr).unwrap();
+    let receiver = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let _receiver_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&receiver), Ipv4Addr::LOCALHOST)
+            .unwrap();
+    let rkey = 1234_u32;
+    let mut src_buf = [1u8; 64];
+    // aligned, so that the write is a single packet
+    let mut dest_buf = AlignedMemory::new(64).unwrap();
+    let dest_addr = dest_buf.as_mut_ptr() as u64;
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(dest_addr)
+        .with_len(64)
+        .with_key(rkey)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    receiver.update(mr_desc).unwrap();
+
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(64)
+        .with_raddr(dest_addr)
+        .with_rkey(rkey)
+        .with_dqpn(7)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(7)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src_buf.as_ptr() as u64, 64, 0_u32)
+                .build(),
+        )
+        .build();
+    sender.send(desc.clone()).unwrap();
+    let receiver_queue = receiver.get_to_host_descriptor_queue();
+    assert!(wait_for_descriptors(&receiver_queue, 1));
+
+    // resend the same packet with another payload, as if the ACK was lost
+    src_buf.fill(2);
+    sender.send(desc).unwrap();
+    let sender_queue = sender.get_to_host_descriptor_queue();
+    assert!(wait_for_descriptors(&sender_queue, 1));
+    match sender_queue.pop().unwrap() {
+        ToHostWorkRbDesc::Ack(ack) => assert_eq!(ack.psn.get(), 7),
+        ToHostWorkRbDesc::Read(_)
+        | ToHostWorkRbDesc::WriteOrReadResp(_)
+        | ToHostWorkRbDesc::WriteWithImm(_)
+        | ToHostWorkRbDesc::Nack(_)
+        | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+    }
+    assert_eq!(sender_agent.opcode_counters()[&ToHostWorkRbDescOpcode::Acknowledge], 1);
+    // the duplicate is neither written to the memory nor reported to the host
+    assert_eq!(dest_buf[..], [1u8; 64]);
+    assert_eq!(receiver_queue.len(), 1);
+}
+
+/// Resend the descriptors of `sender` until `is_done`, or give up after a second
+fn resend_until(sender: &BlueRDMALogic, is_done: impl Fn() -> bool) -> bool {
+    (0..1000).any(|_| {
+        while let Some(desc) = sender.retransmission().pop_resend() {
+            sender.send(desc).unwrap();
+        }
+        let done = is_done();
+        if !done {
+            sleep(Duration::from_millis(1));
+        }
+        done
+    })
+}
+
+#[test]
+#[serial]
+fn test_rnr_retry() {
+    // `ToCardWorkRbDescBuilder` sends to `Ipv4Addr::LOCALHOST`, which is the receiver
+    let sender_addr = Ipv4Addr::new(127, 0, 0, 19);
+    let sender = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        sender_addr,
+        4791,
+    ))));
+    let sender_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&sender), sender_addr).unwrap();
+    let receiver = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let _receiver_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&receiver), Ipv4Addr::LOCALHOST)
+            .unwrap();
+    let rkey = 1234_u32;
+    let src_buf = [1u8; 64];
+    // align the destination to pmtu, so every write is a single packet
+    let dest_buf = vec![0u8; 1024];
+    let dest_addr = (dest_buf.as_ptr() as u64 + 511) & !511;
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(dest_addr)
+        .with_len(64)
+        .with_key(rkey)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    receiver.update(mr_desc).unwrap();
+    // QP 8 retries without limit, QP 9 gives up after a retry. The receiver asks to wait 10.24 ms.
+    for (qpn, rnr_retry) in [(8, RNR_RETRY_INFINITE), (9, 1)] {
+        let mut qp_desc = ToCardCtrlRbDescBuilder::new(QpManagement);
+        let _ = qp_desc
+            .with_is_valid(true)
+            .with_qpn(qpn)
+            .with_pd_hdl(0)
+            .with_qp_type(QpType::Rc)
+            .with_pmtu(Pmtu::Mtu512);
+        sender.update(qp_desc.with_rnr_retry(rnr_retry).build()).unwrap();
+        receiver.update(qp_desc.with_min_rnr_timer(20).build()).unwrap();
+        receiver.post_recv(Qpn::new(qpn), &[]).unwrap();
+    }
+    let write_with_imm = |qpn: u32, psn: u32| {
+        ToCardWorkRbDescBuilder::default()
+            .with_opcode(ToCardWorkRbDescOpcode::WriteWithImm)
+            .with_is_first(true)
+            .with_is_last(true)
+            .with_total_len(64)
+            .with_raddr(dest_addr)
+            .with_rkey(rkey)
+            .with_dqpn(qpn)
+            .with_pmtu(Pmtu::Mtu512)
+            .with_qp_type(QpType::Rc)
+            .with_psn(psn)
+            .with_msn(psn.try_into().unwrap())
+            .with_imm(psn)
+            .with_sg_list(
+                SGListBuilder::new()
+                    .with_sge(src_buf.as_ptr() as u64, 64, 0_u32)
+                    .build(),
+            )
+            .build()
+    };
+    let rnr_nak_cnt = || {
+        sender_agent
+            .opcode_counters()
+            .get(&ToHostWorkRbDescOpcode::Acknowledge)
+            .copied()
+            .unwrap_or(0)
+    };
+
+    // the posted receive buffers are consumed
+    sender.send(write_with_imm(8, 10)).unwrap();
+    sender.send(write_with_imm(9, 10)).unwrap();
+    let receiver_queue = receiver.get_to_host_descriptor_queue();
+    assert!(wait_for_descriptors(&receiver_queue, 2));
+    while receiver_queue.pop().is_some() {}
+
+    // QP 8 has no receive buffer, the write is resent once the receiver posts one
+    sender.send(write_with_imm(8, 11)).unwrap();
+    let is_rejected = (0..1000).any(|_| {
+        let rejected = rnr_nak_cnt() == 1;
+        if !rejected {
+            sleep(Duration::from_millis(1));
+        }
+        rejected
+    });
+    assert!(is_rejected);
+    receiver.post_recv(Qpn::new(8), &[]).unwrap();
+    assert!(resend_until(&sender, || !receiver_queue.is_empty()));
+    match receiver_queue.pop().unwrap() {
+        ToHostWorkRbDesc::WriteWithImm(write) => {
+            assert_eq!(write.common.dqpn.get(), 8);
+            assert_eq!(write.psn.get(), 11);
+            assert_eq!(write.imm, 11);
+        }
+        ToHostWorkRbDesc::Read(_)
+        | ToHostWorkRbDesc::WriteOrReadResp(_)
+        | ToHostWorkRbDesc::Ack(_)
+        | ToHostWorkRbDesc::Nack(_)
+        | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+    }
+    let sender_queue = sender.get_to_host_descriptor_queue();
+    assert!(sender_queue.is_empty());
+
+    // QP 9 is answered with RNR NAKs again after its retry, so the host sees the RNR NAK
+    sender.send(write_with_imm(9, 11)).unwrap();
+    assert!(resend_until(&sender, || !sender_queue.is_empty()));
+    match sender_queue.pop().unwrap() {
+        ToHostWorkRbDesc::Nack(nack) => {
+            assert_eq!(nack.common.dqpn.get(), 9);
+            assert_eq!(nack.code, ToHostWorkRbDescAethCode::Rnr);
+            // the RNR NAK carries the MSN of the message the responder QP has started
+            assert_eq!(nack.msn.get(), 11);
+            assert_eq!(nack.lost_psn.start.get(), 11);
+        }
+        ToHostWorkRbDesc::Read(_)
+        | ToHostWorkRbDesc::WriteOrReadResp(_)
+        | ToHostWorkRbDesc::WriteWithImm(_)
+        | ToHostWorkRbDesc::Ack(_)
+        | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+    }
+    assert_eq!(rnr_nak_cnt(), 3);
+    assert!(receiver_queue.is_empty());
+}
+
+#[test]
+#[serial]
+fn test_resend_read() {
+    // `ToCardWorkRbDescBuilder` sends to `Ipv4Addr::LOCALHOST`, which is the responder
+    let requester_addr = Ipv4Addr::new(127, 0, 0, 20);
+    let mut requester = BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(requester_addr, 4791)));
+    // resend the read request once it times out
+    requester.set_retransmission(true);
+    let requester = Arc::new(requester);
+    let requester_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&requester), requester_addr).unwrap();
+    let responder = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let responder_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&responder), Ipv4Addr::LOCALHOST)
+            .unwrap();
+    let (src_key, dest_key) = (1234_u32, 4321_u32);
+    let mut src_buf = AlignedMemory::new(64).unwrap();
+    src_buf.fill(3);
+    let src_addr = src_buf.as_ptr() as u64;
+    // aligned, so that the response is a single packet
+    let mut dest_buf = AlignedMemory::new(64).unwrap();
+    let dest_addr = dest_buf.as_mut_ptr() as u64;
+    for (logic, addr, key, acc_flags) in [
+        (&responder, src_addr, src_key, MemAccessTypeFlag::IbvAccessRemoteRead),
+        (&requester, dest_addr, dest_key, MemAccessTypeFlag::IbvAccessRemoteWrite),
+    ] {
+        let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+            .with_addr(addr)
+            .with_len(64)
+            .with_key(key)
+            .with_pd_hdl(0)
+            .with_acc_flags(acc_flags)
+            .with_pgt_offset(0)
+            .build();
+        logic.update(mr_desc).unwrap();
+    }
+    let resend_until = |is_done: &dyn Fn() -> bool| {
+        (0..1000).any(|_| {
+            for logic in [&requester, &responder] {
+                while let Some(desc) = logic.retransmission().pop_resend() {
+                    logic.send(desc).unwrap();
+                }
+            }
+            let done = is_done();
+            if !done {
+                sleep(Duration::from_millis(1));
+            }
+            done
+        })
+    };
+
+    // the read request is lost, and resent once it times out
+    responder_agent.drop_nth_frame(1);
+    let read = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Read)
+        .with_total_len(64)
+        .with_raddr(src_addr)
+        .with_rkey(src_key)
+        .with_dqpn(10)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(20)
+        .with_msn(20)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(dest_addr, 64, dest_key)
+                .build(),
+        )
+        .build();
+    requester.send(read).unwrap();
+    let responder_queue = responder.get_to_host_descriptor_queue();
+    assert!(resend_until(&|| !responder_queue.is_empty()));
+    match responder_queue.pop().unwrap() {
+        ToHostWorkRbDesc::Read(read) => {
+            assert!(read.common.status.is_ok());
+            assert_eq!(read.common.msn.get(), 20);
+        }
+        ToHostWorkRbDesc::WriteOrReadResp(_)
+        | ToHostWorkRbDesc::WriteWithImm(_)
+        | ToHostWorkRbDesc::Ack(_)
+        | ToHostWorkRbDesc::Nack(_)
+        | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+    }
+
+    // the response is lost, the request times out again, and the responder resends the response
+    // instead of executing the request again
+    requester_agent.drop_nth_frame(1);
+    let mut resp = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::ReadResp)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(64)
+        .with_raddr(dest_addr)
+        .with_rkey(dest_key)
+        .with_dqpn(10)
+        .with_pmtu(Pmtu::Mtu512)
+        .with_qp_type(QpType::Rc)
+        .with_psn(50)
+        .with_msn(20)
+        .with_sg_list(SGListBuilder::new().with_sge(src_addr, 64, 0_u32).build())
+        .build();
+    if let ToCardWorkRbDesc::ReadResp(resp) = &mut resp {
+        resp.common.dqp_ip = requester_addr;
+    }
+    responder.send(resp).unwrap();
+    let requester_queue = requester.get_to_host_descriptor_queue();
+    assert!(resend_until(&|| !requester_queue.is_empty()));
+    match requester_queue.pop().unwrap() {
+        ToHostWorkRbDesc::WriteOrReadResp(resp) => {
+            assert!(resp.is_read_resp);
+            assert_eq!(resp.psn.get(), 50);
+        }
+        ToHostWorkRbDesc::Read(_)
+        | ToHostWorkRbDesc::WriteWithImm(_)
+        | ToHostWorkRbDesc::Ack(_)
+        | ToHostWorkRbDesc::Nack(_)
+        | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+    }
+    assert_eq!(dest_buf[..], [3u8; 64]);
+    // the duplicate request is neither reported to the host nor acknowledged
+    assert!(responder_queue.is_empty());
+    assert!(requester_queue.is_empty());
+}
+
 #[test]
 #[serial]
 fn test_software_device() {
-    let device = SoftwareDevice::init(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    let device = SoftwareDevice::init(
+        Ipv4Addr::LOCALHOST,
+        4791,
+        Arc::new(RoundRobinStrategy::new()),
+    )
+    .unwrap();
     let mr1_rkey = 1234_u32;
     let mr2_rkey = 4321_u32;
     let dqpn = 5;
@@ -378,7 +1444,7 @@ fn test_software_device() {
         let to_host_work_rb = device.to_host_work_rb();
         // sync the sending packet
         sleep(Duration::from_millis(time_to_wait_in_mill));
-        let q1 = to_host_work_rb.pop().unwrap();
+        let q1 = pop_descriptor(&*to_host_work_rb);
         match q1 {
             ToHostWorkRbDesc::WriteOrReadResp(data) => {
                 assert_eq!(data.common.dqpn.get(), dqpn);
@@ -387,9 +1453,10 @@ fn test_software_device() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
-        let q2 = to_host_work_rb.pop().unwrap();
+        let q2 = pop_descriptor(&*to_host_work_rb);
         match q2 {
             ToHostWorkRbDesc::WriteOrReadResp(data) => {
                 assert_eq!(data.common.dqpn.get(), dqpn);
@@ -400,7 +1467,8 @@ fn test_software_device() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         // assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
@@ -431,7 +1499,7 @@ fn test_software_device() {
             .with_pmtu(Pmtu::Mtu512)
             .with_flags(MemAccessTypeFlag::empty())
             .with_qp_type(QpType::Rc)
-            .with_psn(1234)
+            .with_psn(1236)
             .with_dqpn(dqpn)
             .with_sg_list(SGListBuilder::new().with_sge(src_addr, 1024, 0_u32).build())
             .build();
@@ -439,7 +1507,7 @@ fn test_software_device() {
         to_card_work_rb.push(desc).unwrap();
         // sync the sending packet
         sleep(Duration::from_millis(time_to_wait_in_mill));
-        let q1 = to_host_work_rb.pop().unwrap();
+        let q1 = pop_descriptor(&*to_host_work_rb);
         match q1 {
             ToHostWorkRbDesc::WriteOrReadResp(data) => {
                 assert_eq!(data.common.dqpn.get(), dqpn);
@@ -449,9 +1517,10 @@ fn test_software_device() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
-        let q2 = to_host_work_rb.pop().unwrap();
+        let q2 = pop_descriptor(&*to_host_work_rb);
         match q2 {
             ToHostWorkRbDesc::WriteOrReadResp(data) => {
                 assert_eq!(data.common.dqpn.get(), dqpn);
@@ -461,9 +1530,10 @@ fn test_software_device() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
-        let q3 = to_host_work_rb.pop().unwrap();
+        let q3 = pop_descriptor(&*to_host_work_rb);
         match q3 {
             ToHostWorkRbDesc::WriteOrReadResp(data) => {
                 assert_eq!(data.common.dqpn.get(), dqpn);
@@ -473,7 +1543,8 @@ fn test_software_device() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Nack(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Nack(_)
+            | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
         }
         // assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
@@ -579,3 +1650,211 @@ fn test_software_device() {
     //     );
     // }
 }
+
+/// A loopback send agent which takes `delay` to send every packet, like a busy socket. It records
+/// the QPNs and PSNs of the sent packets in order, and the most packets being sent at the same time.
+#[derive(Debug)]
+struct SlowSendAgent {
+    inner: Arc<LoopbackSendAgent>,
+    delay: Duration,
+    sending: AtomicUsize,
+    max_sending: AtomicUsize,
+    sent: Mutex<Vec<(u32, u32)>>,
+}
+
+impl SlowSendAgent {
+    fn new(inner: Arc<LoopbackSendAgent>, delay: Duration) -> Self {
+        Self {
+            inner,
+            delay,
+            sending: AtomicUsize::new(0),
+            max_sending: AtomicUsize::new(0),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The index of the packet of `dqpn` with `psn` in the sent packets
+    fn sent_index(&self, dqpn: u32, psn: u32) -> usize {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|sent| *sent == (dqpn, psn))
+            .unwrap()
+    }
+}
+
+impl NetSendAgent for SlowSendAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        let sending = self.sending.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_sending.fetch_max(sending, Ordering::SeqCst);
+        sleep(self.delay);
+        let common = message.meta_data.common_meta();
+        self.sent.lock().unwrap().push((common.dqpn.get(), common.psn.get()));
+        let result = self.inner.send(dest_addr, dest_port, message);
+        self.sending.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    fn send_raw(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        payload: &PayloadInfo,
+    ) -> Result<(), NetAgentError> {
+        self.inner.send_raw(dest_addr, dest_port, payload)
+    }
+}
+
+/// Start the receiver at `Ipv4Addr::LOCALHOST` with a MR of `len` bytes at `addr`, which
+/// `ToCardWorkRbDescBuilder` sends to.
+fn start_receiver(addr: u64, len: u32, rkey: u32) -> (Arc<BlueRDMALogic>, LoopbackReceiveAgent) {
+    let receiver = Arc::new(BlueRDMALogic::new(Arc::new(LoopbackSendAgent::new(
+        Ipv4Addr::LOCALHOST,
+        4791,
+    ))));
+    let receiver_agent =
+        LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&receiver), Ipv4Addr::LOCALHOST)
+            .unwrap();
+    let mr_desc = ToCardCtrlRbDescBuilder::new(UpdateMrTable)
+        .with_addr(addr)
+        .with_len(len)
+        .with_key(rkey)
+        .with_pd_hdl(0)
+        .with_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+        .with_pgt_offset(0)
+        .build();
+    receiver.update(mr_desc).unwrap();
+    (receiver, receiver_agent)
+}
+
+/// Start the sender at 127.0.0.20, sending through a `SlowSendAgent` with `delay` by `send_workers`
+/// workers.
+fn start_slow_sender(send_workers: usize, delay: Duration) -> (SoftwareDevice, Arc<SlowSendAgent>) {
+    let addr = Ipv4Addr::new(127, 0, 0, 20);
+    let send_agent = Arc::new(LoopbackSendAgent::new(addr, 4791));
+    let slow_agent = Arc::new(SlowSendAgent::new(Arc::clone(&send_agent), delay));
+    let logic = Arc::new(BlueRDMALogic::new(Arc::<SlowSendAgent>::clone(&slow_agent)));
+    let recv_agent = LoopbackReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&logic), addr).unwrap();
+    let sender = SoftwareDevice::with_logic(
+        logic,
+        NetAgents::Loopback {
+            recv_agent,
+            send_agent,
+        },
+        Arc::new(RoundRobinStrategy::new()),
+        send_workers,
+    );
+    (sender, slow_agent)
+}
+
+/// A RC write of `len` bytes from `src` to `dest` on `dqpn`, starting from `psn`
+fn rc_write(dqpn: u32, psn: u32, pmtu: Pmtu, src: &[u8], dest: u64, rkey: u32) -> ToCardWorkRbDesc {
+    #[allow(clippy::cast_possible_truncation)]
+    let len = src.len() as u32;
+    ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_is_first(true)
+        .with_is_last(true)
+        .with_total_len(len)
+        .with_raddr(dest)
+        .with_rkey(rkey)
+        .with_dqpn(dqpn)
+        .with_pmtu(pmtu)
+        .with_qp_type(QpType::Rc)
+        .with_psn(psn)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src.as_ptr() as u64, len, 0_u32)
+                .build(),
+        )
+        .build()
+}
+
+#[test]
+#[serial]
+fn test_send_workers() {
+    const QP_CNT: u32 = 4;
+    const PACKET_CNT: u32 = 8;
+    let rkey = 1234_u32;
+    let src_buf = vec![0x5a_u8; (PACKET_CNT * 512) as usize];
+    let dest_buf = vec![0u8; 8192];
+    let dest_addr = (dest_buf.as_ptr() as u64 + 511) & !511;
+
+    // send a write of 8 packets on each of 4 QPs, and return the most packets sent at the same time
+    let send_writes = |send_workers: usize| {
+        let (receiver, _receiver_agent) = start_receiver(dest_addr, 4096, rkey);
+        let (sender, slow_agent) = start_slow_sender(send_workers, Duration::from_millis(2));
+        for dqpn in 4..4 + QP_CNT {
+            let desc = rc_write(dqpn, 0, Pmtu::Mtu512, &src_buf, dest_addr, rkey);
+            sender.to_card_work_rb().push(desc).unwrap();
+        }
+        let to_host_queue = receiver.get_to_host_descriptor_queue();
+        assert!(wait_for_descriptors(&to_host_queue, (QP_CNT * PACKET_CNT) as usize));
+
+        // the packets of every QP arrive in order, or the ones after a gap would be dropped
+        let mut psns: HashMap<u32, Vec<u32>> = HashMap::new();
+        while let Some(desc) = to_host_queue.pop() {
+            match desc {
+                ToHostWorkRbDesc::WriteOrReadResp(data) => {
+                    psns.entry(data.common.dqpn.get()).or_default().push(data.psn.get());
+                }
+                ToHostWorkRbDesc::Read(_)
+                | ToHostWorkRbDesc::WriteWithImm(_)
+                | ToHostWorkRbDesc::Ack(_)
+                | ToHostWorkRbDesc::Nack(_)
+                | ToHostWorkRbDesc::Recv(_) => panic!("unexpected descriptor"),
+            }
+        }
+        assert_eq!(psns.len(), QP_CNT as usize);
+        for qp_psns in psns.values() {
+            assert_eq!(*qp_psns, (0..PACKET_CNT).collect::<Vec<_>>());
+        }
+        slow_agent.max_sending.load(Ordering::SeqCst)
+    };
+
+    // the polling thread sends one packet at a time
+    assert_eq!(send_writes(1), 1);
+    // the 4 QPs are sent by 4 workers at the same time
+    let max_sending = send_writes(4);
+    assert!(max_sending > 1, "at most {max_sending} packets are sent at the same time");
+}
+
+#[test]
+#[serial]
+fn test_send_workers_pacing() {
+    const PACKET_CNT: u32 = 32;
+    let rkey = 1234_u32;
+    let src_buf = vec![0x5a_u8; (PACKET_CNT * 4096) as usize];
+    let dest_buf = vec![0u8; src_buf.len() + 4096];
+    let dest_addr = (dest_buf.as_ptr() as u64 + 4095) & !4095;
+    let (receiver, _receiver_agent) = start_receiver(dest_addr, PACKET_CNT * 4096, rkey);
+    // the unpaced write takes several milliseconds to send
+    let (sender, slow_agent) = start_slow_sender(4, Duration::from_micros(200));
+    // both QPs are sent by the first of the 4 workers
+    let slowed_qpn = 4;
+    let other_qpn = 8;
+    // cut the rate to the min one, so the write of the QP is paced over about 10 milliseconds
+    for _ in 0..10 {
+        sender.device.congestion_control().on_cnp(Qpn::new(slowed_qpn)).unwrap();
+    }
+
+    let to_host_queue = receiver.get_to_host_descriptor_queue();
+    let desc = rc_write(slowed_qpn, 0, Pmtu::Mtu4096, &src_buf, dest_addr, rkey);
+    sender.to_card_work_rb().push(desc).unwrap();
+    assert!(wait_for_descriptors(&to_host_queue, 1));
+    let desc = rc_write(other_qpn, 0, Pmtu::Mtu4096, &src_buf[..4096], dest_addr, rkey);
+    sender.to_card_work_rb().push(desc).unwrap();
+    assert!(wait_for_descriptors(&to_host_queue, (PACKET_CNT + 1) as usize));
+
+    // the worker holds back the paced write, instead of sending it before the later one of the other
+    // QP
+    let other = slow_agent.sent_index(other_qpn, 0);
+    let slowed_last = slow_agent.sent_index(slowed_qpn, PACKET_CNT - 1);
+    assert!(other < slowed_last, "{:?}", slow_agent.sent.lock().unwrap());
+}
@@ -0,0 +1,244 @@
+//! Round-trip tests for the header field accessors.
+//!
+//! Every setter is checked against its getter over boundary values of the field, and against
+//! the big-endian wire bytes, so that masking and shifting bugs show up regardless of the host
+//! endianness. Neighbouring fields sharing a byte are checked to be left untouched.
+
+use std::mem::size_of;
+
+use crate::device::software::packet::{
+    RdmaHeaderReqBthRethImm, RdmaPacketHeader, AETH, BTH, RETH,
+};
+use crate::device::{ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType};
+
+const U24_MAX: u32 = 0x00FF_FFFF;
+
+const U16_VALUES: [u16; 6] = [0, 1, 0x00FF, 0x0100, 0x8000, u16::MAX];
+const U24_VALUES: [u32; 7] = [0, 1, 0xFF, 0x0100, 0x01_0000, 0x80_0000, U24_MAX];
+const U32_VALUES: [u32; 7] = [0, 1, 0xFF, 0x0100, 0x0001_0000, 0x8000_0000, u32::MAX];
+const U64_VALUES: [u64; 6] = [0, 1, 0xFF, 0x1_0000_0000, 0x8000_0000_0000_0000, u64::MAX];
+
+#[test]
+fn test_bth_opcode_and_type() {
+    let opcodes = [
+        ToHostWorkRbDescOpcode::RdmaWriteFirst,
+        ToHostWorkRbDescOpcode::RdmaWriteMiddle,
+        ToHostWorkRbDescOpcode::RdmaWriteLast,
+        ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate,
+        ToHostWorkRbDescOpcode::RdmaWriteOnly,
+        ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate,
+        ToHostWorkRbDescOpcode::RdmaReadRequest,
+        ToHostWorkRbDescOpcode::RdmaReadResponseFirst,
+        ToHostWorkRbDescOpcode::RdmaReadResponseMiddle,
+        ToHostWorkRbDescOpcode::RdmaReadResponseLast,
+        ToHostWorkRbDescOpcode::RdmaReadResponseOnly,
+        ToHostWorkRbDescOpcode::Acknowledge,
+    ];
+    let tran_types = [
+        ToHostWorkRbDescTransType::Rc,
+        ToHostWorkRbDescTransType::Uc,
+        ToHostWorkRbDescTransType::Rd,
+        ToHostWorkRbDescTransType::Ud,
+        ToHostWorkRbDescTransType::Xrc,
+    ];
+    for opcode in opcodes {
+        for tran_type in tran_types {
+            let buf = [0xffu8; size_of::<BTH>()];
+            let bth = BTH::from_bytes(&buf);
+            bth.set_opcode_and_type(opcode.clone(), tran_type);
+            assert_eq!(bth.get_opcode(), opcode.clone() as u8);
+            assert_eq!(bth.get_transaction_type(), tran_type as u8);
+            assert_eq!(buf[0], (tran_type as u8) << 5 | opcode.clone() as u8);
+            assert_eq!(buf[1..], [0xff; 11]);
+        }
+    }
+
+    // a CNP uses the whole byte as its opcode
+    let buf = [0u8; size_of::<BTH>()];
+    let bth = BTH::from_bytes(&buf);
+    bth.set_opcode_and_type(ToHostWorkRbDescOpcode::Cnp, ToHostWorkRbDescTransType::Cnp);
+    assert_eq!(bth.get_opcode(), ToHostWorkRbDescOpcode::Cnp as u8);
+    assert_eq!(
+        bth.get_transaction_type(),
+        ToHostWorkRbDescTransType::Cnp as u8
+    );
+}
+
+#[test]
+fn test_bth_flags() {
+    for fill in [0u8, 0xff] {
+        for solicited in [false, true] {
+            for pad_cnt in 0..4 {
+                let buf = [fill; size_of::<BTH>()];
+                let bth = BTH::from_bytes(&buf);
+                bth.set_flags_solicited(solicited);
+                bth.set_pad_cnt(pad_cnt);
+                assert_eq!(bth.get_solicited(), solicited);
+                assert_eq!(usize::from(bth.get_pad_cnt()), pad_cnt);
+                // the reserved bits are kept
+                assert_eq!(buf[1] & 0x1f, fill & 0x1f);
+                assert_eq!(buf[0], fill);
+                assert_eq!(buf[2..], [fill; 10]);
+            }
+        }
+    }
+
+    // an out of range pad count is truncated to its 2 bits instead of setting the solicited bit
+    let buf = [0u8; size_of::<BTH>()];
+    let bth = BTH::from_bytes(&buf);
+    bth.set_pad_cnt(4);
+    assert_eq!(bth.get_pad_cnt(), 0);
+    assert!(!bth.get_solicited());
+    bth.set_pad_cnt(7);
+    assert_eq!(bth.get_pad_cnt(), 3);
+    assert!(!bth.get_solicited());
+}
+
+#[test]
+fn test_bth_pkey() {
+    for pkey in U16_VALUES {
+        let buf = [0xffu8; size_of::<BTH>()];
+        let bth = BTH::from_bytes(&buf);
+        bth.set_pkey(pkey);
+        assert_eq!(bth.get_pkey(), pkey);
+        assert_eq!(buf[2..4], pkey.to_be_bytes());
+        assert_eq!(buf[..2], [0xff; 2]);
+        assert_eq!(buf[4..], [0xff; 8]);
+    }
+}
+
+#[test]
+fn test_bth_destination_qpn() {
+    for qpn in U24_VALUES {
+        let buf = [0u8; size_of::<BTH>()];
+        let bth = BTH::from_bytes(&buf);
+        bth.set_destination_qpn(qpn);
+        assert_eq!(bth.get_destination_qpn(), qpn);
+        assert_eq!(buf[5..8], qpn.to_be_bytes()[1..]);
+    }
+
+    // the upper byte of a qpn is dropped
+    let buf = [0u8; size_of::<BTH>()];
+    let bth = BTH::from_bytes(&buf);
+    bth.set_destination_qpn(0xAB12_3456);
+    assert_eq!(bth.get_destination_qpn(), 0x12_3456);
+    assert_eq!(buf[4], 0);
+}
+
+#[test]
+fn test_bth_psn_and_ack_req() {
+    for psn in U24_VALUES {
+        for ack_req in [false, true] {
+            for fill in [0u8, 0xff] {
+                let buf = [fill; size_of::<BTH>()];
+                let bth = BTH::from_bytes(&buf);
+                // the psn and the ack_req share a word, set them in both orders
+                bth.set_ack_req(ack_req);
+                bth.set_psn(psn);
+                assert_eq!(bth.get_psn(), psn);
+                assert_eq!(bth.get_ack_req(), ack_req);
+                bth.set_psn(psn);
+                bth.set_ack_req(ack_req);
+                assert_eq!(bth.get_psn(), psn);
+                assert_eq!(bth.get_ack_req(), ack_req);
+                assert_eq!(buf[9..12], psn.to_be_bytes()[1..]);
+                assert_eq!(buf[..8], [fill; 8]);
+            }
+        }
+    }
+
+    // the psn is 24 bits wide, the upper byte is dropped instead of touching the ack_req
+    let buf = [0u8; size_of::<BTH>()];
+    let bth = BTH::from_bytes(&buf);
+    bth.set_psn(u32::MAX);
+    assert_eq!(bth.get_psn(), U24_MAX);
+    assert!(!bth.get_ack_req());
+}
+
+#[test]
+fn test_reth_fields() {
+    for va in U64_VALUES {
+        let buf = [0u8; size_of::<RETH>()];
+        let reth = RETH::from_bytes(&buf);
+        reth.set_va(va);
+        assert_eq!(reth.get_va(), va);
+        assert_eq!(buf[..8], va.to_be_bytes());
+        assert_eq!(buf[8..], [0; 8]);
+    }
+    for value in U32_VALUES {
+        let buf = [0u8; size_of::<RETH>()];
+        let reth = RETH::from_bytes(&buf);
+        reth.set_rkey(value);
+        assert_eq!(reth.get_rkey(), value);
+        assert_eq!(buf[8..12], value.to_be_bytes());
+        reth.set_dlen(value);
+        assert_eq!(reth.get_dlen(), value);
+        assert_eq!(buf[12..], value.to_be_bytes());
+        assert_eq!(buf[..8], [0; 8]);
+    }
+}
+
+#[test]
+fn test_aeth_code_and_value() {
+    for code in 0..4 {
+        for value in 0..32 {
+            for msn in [0, U24_MAX] {
+                let buf = [0u8; size_of::<AETH>()];
+                let aeth = AETH::from_bytes(&buf);
+                aeth.set_msn(msn);
+                aeth.set_aeth_code_and_value(code, value);
+                assert_eq!(aeth.get_aeth_code(), code);
+                assert_eq!(aeth.get_aeth_value(), value);
+                assert_eq!(aeth.get_msn(), msn);
+                assert_eq!(buf[0], code << 5 | value);
+            }
+        }
+    }
+
+    // an out of range code wraps modulo 4 rather than being rejected
+    let buf = [0u8; size_of::<AETH>()];
+    let aeth = AETH::from_bytes(&buf);
+    for code in 4..=u8::MAX {
+        aeth.set_aeth_code_and_value(code, 0);
+        assert_eq!(aeth.get_aeth_code(), code % 4);
+    }
+
+    // an out of range value is truncated to its 5 bits instead of changing the code
+    aeth.set_aeth_code_and_value(1, 0xff);
+    assert_eq!(aeth.get_aeth_code(), 1);
+    assert_eq!(aeth.get_aeth_value(), 0x1f);
+    assert_eq!(buf[0] & 0x80, 0);
+}
+
+#[test]
+fn test_aeth_msn() {
+    for msn in U24_VALUES {
+        let buf = [0u8; size_of::<AETH>()];
+        let aeth = AETH::from_bytes(&buf);
+        aeth.set_aeth_code_and_value(3, 0x1f);
+        aeth.set_msn(msn);
+        assert_eq!(aeth.get_msn(), msn);
+        assert_eq!(buf[1..], msn.to_be_bytes()[1..]);
+        assert_eq!(aeth.get_aeth_code(), 3);
+        assert_eq!(aeth.get_aeth_value(), 0x1f);
+    }
+
+    // the upper byte of a msn is dropped
+    let buf = [0u8; size_of::<AETH>()];
+    let aeth = AETH::from_bytes(&buf);
+    aeth.set_msn(u32::MAX);
+    assert_eq!(aeth.get_msn(), U24_MAX);
+    assert_eq!(buf[0], 0);
+}
+
+#[test]
+fn test_immediate() {
+    for imm in U32_VALUES {
+        let buf = [0u8; size_of::<RdmaHeaderReqBthRethImm>()];
+        let header = RdmaHeaderReqBthRethImm::from_bytes(&buf);
+        header.imm.set(imm);
+        assert_eq!(header.imm.get(), imm);
+        let offset = size_of::<BTH>() + size_of::<RETH>();
+        assert_eq!(buf[offset..], imm.to_be_bytes());
+    }
+}
@@ -1,16 +1,23 @@
-use std::{cell::RefCell, collections::LinkedList, net::Ipv4Addr, sync::Arc};
+use std::{cell::RefCell, collections::LinkedList, mem::size_of, net::Ipv4Addr, sync::Arc};
 
 use crate::{
     device::{
         software::{
-            logic::BlueRDMALogic,
-            net_agent::{NetAgentError, NetSendAgent},
+            logic::{BlueRDMALogic, BlueRdmaLogicError},
+            net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
+            packet::BTH,
+            packet_processor::PacketProcessor,
             tests::{SGListBuilder, ToCardWorkRbDescBuilder},
-            types::{Metadata, PayloadInfo, RdmaMessage},
+            types::{
+                Key, Metadata, PKey, PayloadInfo, Qpn, RdmaMessage, RdmaMessageMetaCommon,
+                RdmaSendMeta, SGListElementWithKey,
+            },
         },
-        ToCardWorkRbDescOpcode, ToHostWorkRbDescOpcode,
+        scheduler::credit::AETH_CREDIT_INVALID,
+        ToCardWorkRbDescOpcode, ToHostWorkRbDesc, ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode,
+        ToHostWorkRbDescTransType,
     },
-    types::{Pmtu, QpType},
+    types::{Pmtu, Psn, QpType},
 };
 
 #[derive(Debug)]
@@ -59,7 +66,7 @@ fn test_logic_send() {
             .with_dqpn(12)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0x1000, 512, 0x1234_u32)
+                    .with_sge(0x1000, 512, 0_u32)
                     .build(),
             )
             .build();
@@ -87,8 +94,8 @@ fn test_logic_send() {
             .with_dqpn(12)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0x1000, 256, 0x1234_u32)
-                    .with_sge(0x2000, 768, 0x1234_u32)
+                    .with_sge(0x1000, 256, 0_u32)
+                    .with_sge(0x2000, 768, 0_u32)
                     .build(),
             )
             .build();
@@ -120,7 +127,7 @@ fn test_logic_send() {
             .with_dqpn(12)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0x1000, 4096, 0x1234_u32)
+                    .with_sge(0x1000, 4096, 0_u32)
                     .build(),
             )
             .build();
@@ -170,7 +177,7 @@ fn test_logic_send() {
             .with_dqpn(12)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0x1000, 4096, 0x1234_u32)
+                    .with_sge(0x1000, 4096, 0_u32)
                     .build(),
             )
             .build();
@@ -228,7 +235,7 @@ fn test_logic_send() {
             .with_dqpn(12)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0x1000, 20, 0x1234_u32)
+                    .with_sge(0x1000, 20, 0_u32)
                     .build(),
             )
             .build();
@@ -243,7 +250,7 @@ fn test_logic_send() {
             Metadata::General(meta) => {
                 assert_eq!(meta.imm.unwrap(), 0x1234);
             }
-            _ => unreachable!(),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => unreachable!(),
         }
     }
 
@@ -280,7 +287,7 @@ fn test_logic_send() {
                 assert_eq!(secondary_reth.len, 1024);
                 assert_eq!(secondary_reth.rkey.get(), 4567);
             }
-            _ => unreachable!(),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => unreachable!(),
         }
     }
 
@@ -297,7 +304,7 @@ fn test_logic_send() {
             .with_is_last(false)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0, 1024 * 32, 0x1234_u32)
+                    .with_sge(0, 1024 * 32, 0_u32)
                     .build(),
             )
             .build();
@@ -312,7 +319,7 @@ fn test_logic_send() {
             .with_is_first(false)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0, 1024 * 32, 0x1234_u32)
+                    .with_sge(0, 1024 * 32, 0_u32)
                     .build(),
             )
             .build();
@@ -328,7 +335,7 @@ fn test_logic_send() {
         assert_eq!(message.payload.get_length(), 4096);
         let meta = match message.meta_data {
             Metadata::General(meta) => meta,
-            _ => unreachable!(),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => unreachable!(),
         };
         assert_eq!(meta.common_meta.psn.get(), 0,);
         assert_eq!(meta.reth.va, 0);
@@ -365,7 +372,7 @@ fn test_logic_send() {
             .with_is_last(false)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(0, 1024, 0x1234_u32)
+                    .with_sge(0, 1024, 0_u32)
                     .build(),
             )
             .build();
@@ -380,7 +387,7 @@ fn test_logic_send() {
             .with_is_first(false)
             .with_sg_list(
                 SGListBuilder::new()
-                    .with_sge(1024 * 32, 1024 * 32, 0x1234_u32)
+                    .with_sge(1024 * 32, 1024 * 32, 0_u32)
                     .build(),
             )
             .build();
@@ -396,7 +403,7 @@ fn test_logic_send() {
         assert_eq!(message.payload.get_length(), 1024);
         let meta = match message.meta_data {
             Metadata::General(meta) => meta,
-            _ => unreachable!(),
+            Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => unreachable!(),
         };
         assert_eq!(meta.reth.va, 1024 * 31);
         assert_eq!(meta.reth.len, 1024 * 33);
@@ -454,3 +461,278 @@ fn test_logic_send_raw() {
         assert_eq!(payload.get_sg_list()[3].data, 7000 as *const u8);
     }
 }
+
+#[test]
+fn test_logic_scatter_recv() {
+    let agent = Arc::new(DummpyProxy::new());
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent));
+    let qpn = Qpn::new(5);
+    let sge = |buf: *mut u8, len: u32| SGListElementWithKey {
+        addr: buf as u64,
+        len,
+        key: Key::new(0),
+    };
+
+    // a header buffer of 16 bytes followed by a payload buffer, the payload arrives in two pieces
+    let mut header_buf = [0u8; 32];
+    let mut payload_buf = [0u8; 64];
+    logic
+        .post_recv(
+            qpn,
+            &[sge(header_buf.as_mut_ptr(), 16), sge(payload_buf.as_mut_ptr(), 64)],
+        )
+        .unwrap();
+    let data: Vec<u8> = (1..=50).collect();
+    let mut payload = PayloadInfo::new();
+    payload.add(data.as_ptr(), 10);
+    payload.add(data[10..].as_ptr(), 40);
+    assert!(logic.fill_recv_buffer(qpn, &payload).unwrap());
+    assert_eq!(header_buf[..16], data[..16]);
+    assert_eq!(header_buf[16..], [0; 16]);
+    assert_eq!(payload_buf[..34], data[16..]);
+    assert_eq!(payload_buf[34..], [0; 30]);
+
+    // the receive queue is empty
+    assert!(!logic.fill_recv_buffer(qpn, &payload).unwrap());
+
+    // the receive buffer is too small, it is consumed without being written
+    let mut small_buf = [0u8; 32];
+    logic
+        .post_recv(
+            qpn,
+            &[sge(small_buf.as_mut_ptr(), 16), sge(small_buf[16..].as_mut_ptr(), 16)],
+        )
+        .unwrap();
+    assert!(matches!(
+        logic.fill_recv_buffer(qpn, &payload),
+        Err(BlueRdmaLogicError::RecvBufferTooSmall(_, 50))
+    ));
+    assert_eq!(small_buf, [0; 32]);
+    assert!(!logic.fill_recv_buffer(qpn, &payload).unwrap());
+}
+
+#[test]
+fn test_logic_recv_multi_packet_send() {
+    let agent = Arc::new(DummpyProxy::new());
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent));
+    let qpn = Qpn::new(5);
+    const PMTU: usize = 4096;
+    const LEN: usize = 3 * PMTU;
+    let mut recv_buf = vec![0u8; LEN];
+    logic
+        .post_recv(
+            qpn,
+            &[SGListElementWithKey {
+                addr: recv_buf.as_mut_ptr() as u64,
+                len: LEN as u32,
+                key: Key::new(0),
+            }],
+        )
+        .unwrap();
+
+    // a SEND of 12KB over Mtu4096 is cut into the first, the middle and the last packets
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let opcodes = [
+        ToHostWorkRbDescOpcode::SendFirst,
+        ToHostWorkRbDescOpcode::SendMiddle,
+        ToHostWorkRbDescOpcode::SendLast,
+    ];
+    for (i, (opcode, fragment)) in opcodes.into_iter().zip(data.chunks(PMTU)).enumerate() {
+        let message = RdmaMessage {
+            meta_data: Metadata::Send(RdmaSendMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: opcode.clone(),
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: qpn,
+                    ack_req: false,
+                    psn: Psn::new(i as u32),
+                },
+                imm: None,
+            }),
+            payload: PayloadInfo::new_with_data(fragment.as_ptr(), fragment.len()),
+        };
+        let mut packet = vec![0u8; 64 + PMTU];
+        let header_len = PacketProcessor::set_from_rdma_message(&mut packet, &message).unwrap();
+        // a SEND carries no RETH
+        assert_eq!(header_len, size_of::<BTH>());
+        packet.truncate(header_len);
+        packet.extend_from_slice(fragment);
+
+        let mut received = PacketProcessor::to_rdma_message(&packet).unwrap();
+        assert_eq!(received.meta_data.get_opcode(), opcode);
+        assert_eq!(received.payload.get_length(), PMTU);
+        logic.recv(Ipv4Addr::LOCALHOST, &mut received);
+        // the message is delivered once its last packet arrives
+        let delivered = recv_buf.iter().any(|b| *b != 0);
+        assert_eq!(delivered, opcode == ToHostWorkRbDescOpcode::SendLast);
+    }
+    assert_eq!(recv_buf, data);
+    // the RC SEND is acknowledged at its last packet, and completed to the host
+    let acks: Vec<_> = std::mem::take(&mut *agent.message.borrow_mut()).into_iter().collect();
+    let [ack] = &acks[..] else {
+        panic!("expect one ACK, got {acks:?}");
+    };
+    let Metadata::Acknowledge(aeth) = &ack.meta_data else {
+        panic!("expect an ACK, got {ack:?}");
+    };
+    assert_eq!(aeth.aeth_code, ToHostWorkRbDescAethCode::Ack);
+    assert_eq!(aeth.common_meta.psn, Psn::new(2));
+    let Some(ToHostWorkRbDesc::Recv(recv)) = logic.get_to_host_descriptor_queue().pop() else {
+        panic!("expect a receive descriptor");
+    };
+    assert_eq!(recv.len, LEN as u32);
+    assert_eq!(recv.psn, Psn::new(2));
+    assert!(recv.common.status.is_ok());
+    // the receive buffer is consumed
+    let only = PayloadInfo::new_with_data(data.as_ptr(), 1);
+    assert!(!logic.fill_recv_buffer(qpn, &only).unwrap());
+}
+
+#[test]
+fn test_logic_recv_rc_send_sequence() {
+    let agent = Arc::new(DummpyProxy::new());
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent));
+    let qpn = Qpn::new(5);
+    let src = Ipv4Addr::LOCALHOST;
+    let data = [7u8; 32];
+    let send = |opcode: ToHostWorkRbDescOpcode, psn: u32, payload: &[u8]| RdmaMessage {
+        meta_data: Metadata::Send(RdmaSendMeta {
+            common_meta: RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Rc,
+                opcode,
+                solicited: false,
+                pkey: PKey::new(0),
+                dqpn: qpn,
+                ack_req: false,
+                psn: Psn::new(psn),
+            },
+            imm: None,
+        }),
+        payload: PayloadInfo::new_with_data(payload.as_ptr(), payload.len()),
+    };
+    let take_aeth = || {
+        let messages: Vec<_> = std::mem::take(&mut *agent.message.borrow_mut()).into_iter().collect();
+        let [message] = &messages[..] else {
+            panic!("expect one acknowledge, got {messages:?}");
+        };
+        let Metadata::Acknowledge(aeth) = &message.meta_data else {
+            panic!("expect an acknowledge, got {message:?}");
+        };
+        (aeth.aeth_code.clone(), aeth.aeth_value, aeth.common_meta.psn)
+    };
+
+    // the QP has no receive buffer, so the SEND is answered with an RNR NAK and expected again
+    let mut message = send(ToHostWorkRbDescOpcode::SendOnly, 0, &data);
+    assert!(!logic.check_sequence(src, &message));
+    assert_eq!(take_aeth().0, ToHostWorkRbDescAethCode::Rnr);
+
+    let mut recv_buf = [0u8; 48];
+    let sge = SGListElementWithKey {
+        addr: recv_buf.as_mut_ptr() as u64,
+        len: 48,
+        key: Key::new(0),
+    };
+    logic.post_recv(qpn, &[sge]).unwrap();
+    assert!(logic.check_sequence(src, &message));
+    logic.recv(src, &mut message);
+    assert_eq!(take_aeth(), (ToHostWorkRbDescAethCode::Ack, AETH_CREDIT_INVALID, Psn::new(0)));
+    assert_eq!(recv_buf[..32], data);
+
+    // the duplicate is acknowledged again without being delivered
+    let message = send(ToHostWorkRbDescOpcode::SendOnly, 0, &data);
+    assert!(!logic.check_sequence(src, &message));
+    assert_eq!(take_aeth(), (ToHostWorkRbDescAethCode::Ack, AETH_CREDIT_INVALID, Psn::new(0)));
+    let queue = logic.get_to_host_descriptor_queue();
+    assert!(matches!(queue.pop(), Some(ToHostWorkRbDesc::Recv(_))));
+    assert!(queue.pop().is_none());
+
+    // a SEND growing beyond the receive buffer is dropped with a NAK, and the buffer is kept
+    let mut other_buf = [0u8; 48];
+    let sge = SGListElementWithKey {
+        addr: other_buf.as_mut_ptr() as u64,
+        len: 48,
+        key: Key::new(0),
+    };
+    logic.post_recv(qpn, &[sge]).unwrap();
+    for (opcode, psn) in [
+        (ToHostWorkRbDescOpcode::SendFirst, 1),
+        (ToHostWorkRbDescOpcode::SendMiddle, 2),
+    ] {
+        let mut message = send(opcode, psn, &data);
+        assert!(logic.check_sequence(src, &message));
+        logic.recv(src, &mut message);
+    }
+    assert_eq!(take_aeth(), (ToHostWorkRbDescAethCode::Nak, 1, Psn::new(2)));
+    // the rest of the dropped SEND is not answered again
+    let mut message = send(ToHostWorkRbDescOpcode::SendLast, 3, &data);
+    assert!(logic.check_sequence(src, &message));
+    logic.recv(src, &mut message);
+    assert!(agent.message.borrow().is_empty());
+    assert!(queue.pop().is_none());
+    assert_eq!(other_buf, [0; 48]);
+}
+
+#[test]
+fn test_logic_xrc_srq() {
+    let agent = Arc::new(DummpyProxy::new());
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent));
+    let srqn = 7;
+    let (qpn_a, qpn_b) = (Qpn::new(5), Qpn::new(6));
+    let sge = |buf: &mut [u8; 16]| SGListElementWithKey {
+        addr: buf.as_mut_ptr() as u64,
+        len: 16,
+        key: Key::new(0),
+    };
+    let send_only = |qpn: Qpn, data: &[u8; 16]| RdmaMessage {
+        meta_data: Metadata::Send(RdmaSendMeta {
+            common_meta: RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Xrc,
+                opcode: ToHostWorkRbDescOpcode::SendOnly,
+                solicited: false,
+                pkey: PKey::new(0),
+                dqpn: qpn,
+                ack_req: false,
+                psn: Psn::new(0),
+            },
+            imm: None,
+        }),
+        payload: PayloadInfo::new_with_data(data.as_ptr(), data.len()),
+    };
+
+    assert!(matches!(
+        logic.attach_srq(qpn_a, srqn),
+        Err(BlueRdmaLogicError::SrqNotFound(7))
+    ));
+    logic.create_srq(srqn).unwrap();
+    assert!(matches!(logic.create_srq(srqn), Err(BlueRdmaLogicError::SrqExists(7))));
+    logic.attach_srq(qpn_a, srqn).unwrap();
+    logic.attach_srq(qpn_b, srqn).unwrap();
+    let mut shared_bufs = [[0u8; 16]; 3];
+    for buf in &mut shared_bufs {
+        logic.post_srq_recv(srqn, &[sge(buf)]).unwrap();
+    }
+    // the buffer posted to the qp itself is ignored while it's attached
+    let mut own_buf = [0u8; 16];
+    logic.post_recv(qpn_a, &[sge(&mut own_buf)]).unwrap();
+
+    // the SENDs to both qps draw from the shared buffers in the order they arrive
+    for (qpn, byte) in [(qpn_a, 1), (qpn_b, 2), (qpn_a, 3)] {
+        logic.recv(Ipv4Addr::LOCALHOST, &mut send_only(qpn, &[byte; 16]));
+    }
+    assert_eq!(shared_bufs, [[1; 16], [2; 16], [3; 16]]);
+    assert_eq!(own_buf, [0; 16]);
+    let data = [4u8; 16];
+    let payload = PayloadInfo::new_with_data(data.as_ptr(), data.len());
+    assert!(!logic.fill_recv_buffer(qpn_b, &payload).unwrap());
+
+    // a destroyed srq detaches its qps, which take their own buffers again
+    logic.destroy_srq(srqn).unwrap();
+    assert!(matches!(
+        logic.post_srq_recv(srqn, &[]),
+        Err(BlueRdmaLogicError::SrqNotFound(7))
+    ));
+    logic.recv(Ipv4Addr::LOCALHOST, &mut send_only(qpn_a, &data));
+    assert_eq!(own_buf, data);
+}
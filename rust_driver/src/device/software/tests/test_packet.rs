@@ -1,10 +1,17 @@
 use std::mem::size_of;
+use std::net::Ipv4Addr;
 
 use crate::device::software::packet::Immediate;
+use crate::device::software::packet::Ipv4Header;
+use crate::device::software::packet::PacketError;
 use crate::device::software::packet::AETH;
 use crate::device::software::packet::BTH;
 use crate::device::software::packet::RETH;
+use crate::device::software::packet_processor::compute_udp_checksum;
+use crate::device::software::packet_processor::IcrcConfig;
+use crate::device::software::packet_processor::is_icrc_valid;
 use crate::device::software::packet_processor::PacketProcessor;
+use crate::device::software::packet_processor::PacketWriter;
 use crate::device::software::types::Key;
 use crate::device::software::types::Metadata;
 use crate::device::software::types::PKey;
@@ -17,6 +24,7 @@ use crate::device::software::types::RethHeader;
 use crate::device::ToHostWorkRbDescOpcode;
 use crate::device::ToHostWorkRbDescTransType;
 use crate::types::Psn;
+use crate::types::UdpChecksumMode;
 
 const BTH_SIZE: usize = size_of::<BTH>();
 const RETH_SIZE: usize = size_of::<RETH>();
@@ -63,7 +71,7 @@ fn test_header_bth_reth() {
             assert_eq!(header.reth.len, 1);
             assert_eq!(message.payload.get_length(), 512);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -89,7 +97,8 @@ fn test_header_bth_reth_imm() {
     reth.set_rkey(0x12345678);
     reth.set_dlen(0x12345678);
     let imm = &mut buf[BTH_SIZE + RETH_SIZE..BTH_SIZE + RETH_SIZE + IMM_SIZE];
-    imm.copy_from_slice(&[1u8; IMM_SIZE]);
+    // an asymmetric value tells the byte orders apart
+    imm.copy_from_slice(&[1, 2, 3, 4]);
     let message = PacketProcessor::to_rdma_message(&buf).unwrap();
     let meta = &message.meta_data;
     match meta {
@@ -111,14 +120,16 @@ fn test_header_bth_reth_imm() {
             assert_eq!(header.reth.rkey.get(), 0x12345678);
             assert_eq!(header.reth.len, 0x12345678);
             assert_eq!(message.payload.get_length(), 512);
-            assert_eq!(header.imm.unwrap(), u32::from_le_bytes([1u8; IMM_SIZE]));
+            assert_eq!(header.imm.unwrap(), 0x0102_0304);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + IMM_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
     assert!(size == BTH_SIZE + RETH_SIZE + IMM_SIZE);
     assert!(buf[..size] == new_buf[..size]);
+    // the immediate goes back on the wire in network byte order
+    assert_eq!(new_buf[BTH_SIZE + RETH_SIZE..size], [1, 2, 3, 4]);
 }
 
 #[test]
@@ -168,7 +179,7 @@ fn test_header_bth_reth_reth() {
             assert_eq!(secondary_reth.rkey.get(), 0x12345678);
             assert_eq!(secondary_reth.len, 0x12345678);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_) | Metadata::Send(_) | Metadata::Cnp(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + RETH_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -212,7 +223,7 @@ fn test_header_bth_aeth() {
             assert_eq!(header.aeth_code.clone() as u8, 2);
             assert_eq!(header.aeth_value, 5);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::General(_) | Metadata::Send(_) | Metadata::Cnp(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + AETH_SIZE];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -253,6 +264,36 @@ fn test_payload_copy_to() {
     }
 }
 
+#[test]
+fn test_payload_validate_against() {
+    let src_buf1 = [1u8; 128];
+    let src_buf2 = [2u8; 64];
+    let src_buf3 = [3u8; 32];
+    let mut payload = PayloadInfo::new();
+    payload.add(src_buf1.as_ptr(), src_buf1.len());
+    payload.add(src_buf2.as_ptr(), src_buf2.len());
+    payload.add(src_buf3.as_ptr(), src_buf3.len());
+    assert_eq!(payload.segment_count(), 3);
+    assert!(payload.validate_against(224).is_ok());
+
+    // a truncated payload
+    assert!(matches!(
+        payload.validate_against(256),
+        Err(PacketError::PayloadLengthMismatch {
+            expected: 256,
+            actual: 224
+        })
+    ));
+    // an over-long payload
+    assert!(matches!(
+        payload.validate_against(192),
+        Err(PacketError::PayloadLengthMismatch {
+            expected: 192,
+            actual: 224
+        })
+    ));
+}
+
 #[test]
 fn test_pkt_processor_to_buf() {
     let mut payload = PayloadInfo::new();
@@ -299,3 +340,214 @@ fn test_pkt_processor_to_buf() {
     assert_eq!(reth.get_rkey(), 0x12345678);
     assert_eq!(reth.get_dlen(), 0x12345678);
 }
+
+#[test]
+fn test_packet_writer_udp_checksum() {
+    // the UDP checksum is the 4th word of the UDP header, after the 20 bytes IP header
+    const CHECKSUM_OFFSET: usize = 26;
+    let msg = RdmaMessage {
+        meta_data: Metadata::Cnp(RdmaMessageMetaCommon {
+            tran_type: ToHostWorkRbDescTransType::Cnp,
+            opcode: ToHostWorkRbDescOpcode::Cnp,
+            solicited: false,
+            pkey: PKey::new(0),
+            dqpn: Qpn::new(3),
+            ack_req: false,
+            psn: Psn::new(0),
+        }),
+        payload: PayloadInfo::new(),
+    };
+    let write = |buf: &mut [u8], mode: UdpChecksumMode| {
+        PacketWriter::new(buf)
+            .src_addr(Ipv4Addr::new(127, 0, 0, 1))
+            .src_port(4791)
+            .dest_addr(Ipv4Addr::new(127, 0, 0, 2))
+            .dest_port(4791)
+            .ip_id(1)
+            .udp_checksum(mode)
+            .message(&msg)
+            .write()
+            .unwrap()
+    };
+
+    // zero by default, even if the buffer is dirty
+    let mut buf = [0xffu8; 128];
+    let size = write(&mut buf, UdpChecksumMode::default());
+    assert_eq!(buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2], [0, 0]);
+    assert!(is_icrc_valid(&mut buf[..size], IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+
+    // the one's complement sum of the pseudo header and the datagram with the checksum is 0xffff
+    let mut buf = [0u8; 128];
+    let size = write(&mut buf, UdpChecksumMode::Computed);
+    let checksum = u16::from_be_bytes([buf[CHECKSUM_OFFSET], buf[CHECKSUM_OFFSET + 1]]);
+    assert_ne!(checksum, 0);
+    assert_eq!(compute_udp_checksum(&buf[..size]).unwrap(), checksum);
+    let pseudo_header = [127, 0, 0, 1, 127, 0, 0, 2, 0, 17, 0, (size - 20) as u8];
+    let mut sum = pseudo_header
+        .chunks(2)
+        .chain(buf[20..size].chunks(2))
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(sum, 0xffff);
+    assert!(is_icrc_valid(&mut buf[..size], IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+
+    // the checksum in the buffer is kept
+    let mut buf = [0u8; 128];
+    buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&[0xbe, 0xef]);
+    let size = write(&mut buf, UdpChecksumMode::Passthrough);
+    assert_eq!(buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2], [0xbe, 0xef]);
+    assert!(is_icrc_valid(&mut buf[..size], IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+}
+
+#[test]
+fn test_packet_writer_ipv4_checksum() {
+    let msg = RdmaMessage {
+        meta_data: Metadata::Cnp(RdmaMessageMetaCommon {
+            tran_type: ToHostWorkRbDescTransType::Cnp,
+            opcode: ToHostWorkRbDescOpcode::Cnp,
+            solicited: false,
+            pkey: PKey::new(0),
+            dqpn: Qpn::new(3),
+            ack_req: false,
+            psn: Psn::new(0),
+        }),
+        payload: PayloadInfo::new(),
+    };
+    // the checksum is filled after the TTL, the DSCP and the ECN, even if the buffer is dirty
+    let mut buf = [0xffu8; 128];
+    let size = PacketWriter::new(&mut buf)
+        .src_addr(Ipv4Addr::new(192, 168, 0, 1))
+        .src_port(4791)
+        .dest_addr(Ipv4Addr::new(192, 168, 0, 2))
+        .dest_port(4791)
+        .ip_id(0x1234)
+        .dscp(26)
+        .ttl(3)
+        .message(&msg)
+        .write()
+        .unwrap();
+    let ip_header = Ipv4Header::from_bytes(&buf).unwrap();
+    assert_eq!(ip_header.ttl, 3);
+    assert!(ip_header.verify_checksum());
+    // the one's complement sum of the header with the checksum is 0xffff
+    let mut sum = buf[..20]
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(sum, 0xffff);
+    assert!(is_icrc_valid(&mut buf[..size], IcrcConfig::default())
+        .unwrap()
+        .is_valid());
+
+    // any change of the header breaks the checksum
+    Ipv4Header::from_bytes_mut(&mut buf).unwrap().ttl = 4;
+    assert!(!Ipv4Header::from_bytes(&buf).unwrap().verify_checksum());
+}
+
+#[test]
+fn test_pkt_processor_malformed_length() {
+    let msg = RdmaMessage {
+        meta_data: Metadata::General(RdmaGeneralMeta {
+            common_meta: RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Rc,
+                opcode: ToHostWorkRbDescOpcode::RdmaWriteOnly,
+                solicited: false,
+                pkey: PKey::new(0),
+                dqpn: Qpn::new(3),
+                ack_req: false,
+                psn: Psn::new(0),
+            },
+            reth: RethHeader {
+                va: 0x1000,
+                rkey: Key::new(0x1234),
+                len: 4,
+            },
+            imm: None,
+            secondary_reth: None,
+        }),
+        payload: PayloadInfo::new(),
+    };
+    let mut buf = [0u8; 64];
+    let size = PacketProcessor::set_from_rdma_message(&mut buf, &msg).unwrap();
+    let packet_size = size + 4;
+    let message = PacketProcessor::to_rdma_message(&buf[..packet_size]).unwrap();
+    assert_eq!(message.payload.get_length(), 4);
+
+    // the pad count is within the payload
+    BTH::from_bytes(&buf).set_pad_cnt(3);
+    let message = PacketProcessor::to_rdma_message(&buf[..packet_size]).unwrap();
+    assert_eq!(message.payload.get_length(), 1);
+
+    // the pad count exceeds the payload
+    let result = PacketProcessor::to_rdma_message(&buf[..size + 2]);
+    assert!(matches!(
+        result,
+        Err(PacketError::InvalidPadCount {
+            pad_cnt: 3,
+            payload_length: 2
+        })
+    ));
+
+    // the buffer is shorter than the header
+    let result = PacketProcessor::to_rdma_message(&buf[..size - 1]);
+    assert!(matches!(result, Err(PacketError::PacketTooShort(len)) if len == size - 1));
+    let result = PacketProcessor::to_rdma_message(&buf[..BTH_SIZE - 1]);
+    assert!(matches!(result, Err(PacketError::PacketTooShort(len)) if len == BTH_SIZE - 1));
+}
+
+#[test]
+fn test_pkt_processor_read_response_with_imm() {
+    const LEN: usize = 8;
+    let msg = RdmaMessage {
+        meta_data: Metadata::General(RdmaGeneralMeta {
+            common_meta: RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Rc,
+                opcode: ToHostWorkRbDescOpcode::RdmaReadResponseOnly,
+                solicited: false,
+                pkey: PKey::new(0),
+                dqpn: Qpn::new(3),
+                ack_req: false,
+                psn: Psn::new(0),
+            },
+            reth: RethHeader {
+                va: 0x1000,
+                rkey: Key::new(0x1234),
+                len: LEN as u32,
+            },
+            imm: None,
+            secondary_reth: None,
+        }),
+        payload: PayloadInfo::new(),
+    };
+    let mut buf = [0u8; 64];
+    let size = PacketProcessor::set_from_rdma_message(&mut buf, &msg).unwrap();
+    buf[size..size + LEN].fill(0xab);
+    let message = PacketProcessor::to_rdma_message(&buf[..size + LEN]).unwrap();
+    assert_eq!(message.payload.get_length(), LEN);
+
+    // the immediate data between the RETH and the payload is not taken as the payload
+    buf.copy_within(size..size + LEN, size + IMM_SIZE);
+    buf[size..size + IMM_SIZE].copy_from_slice(&0xdeadbeef_u32.to_be_bytes());
+    let result = PacketProcessor::to_rdma_message(&buf[..size + IMM_SIZE + LEN]);
+    assert!(matches!(result, Err(PacketError::InvalidOpcode)));
+
+    // so are the last packets of a multi-packet read response
+    BTH::from_bytes(&buf).set_opcode_and_type(
+        ToHostWorkRbDescOpcode::RdmaReadResponseLast,
+        ToHostWorkRbDescTransType::Rc,
+    );
+    let result = PacketProcessor::to_rdma_message(&buf[..size + IMM_SIZE + LEN]);
+    assert!(matches!(result, Err(PacketError::InvalidOpcode)));
+}
@@ -0,0 +1,247 @@
+//! Golden on-wire bytes of the packets the software device sends for a work descriptor.
+//!
+//! The hardware is expected to put the same bytes on the wire, so a change of the framing shows up
+//! here as a mismatch against the golden vectors rather than as an interop failure.
+
+use std::{
+    net::Ipv4Addr,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    device::{
+        software::{
+            logic::BlueRDMALogic,
+            net_agent::{NetAgentError, NetSendAgent},
+            packet_processor::PacketWriter,
+            tests::{SGListBuilder, ToCardWorkRbDescBuilder},
+            types::{PayloadInfo, RdmaMessage},
+        },
+        ToCardWorkRbDesc, ToCardWorkRbDescOpcode,
+    },
+    types::{Pmtu, UdpChecksumMode},
+};
+
+const SRC_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const SRC_PORT: u16 = 4791;
+
+/// Frame the messages as the UDP agent does and keep the frames
+#[derive(Debug, Default)]
+struct CaptureAgent {
+    frames: Mutex<Vec<Vec<u8>>>,
+    ip_id: AtomicU16,
+}
+
+impl NetSendAgent for CaptureAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        let mut buf = [0u8; 8192];
+        let mut writer = PacketWriter::new(&mut buf);
+        let _: &mut PacketWriter<'_, '_> = writer
+            .src_addr(SRC_ADDR)
+            .src_port(SRC_PORT)
+            .dest_addr(dest_addr)
+            .dest_port(dest_port)
+            .ip_id(self.ip_id.fetch_add(1, Ordering::Relaxed))
+            .udp_checksum(UdpChecksumMode::default())
+            .message(message);
+        let len = writer.write()?;
+        self.frames.lock().unwrap().push(buf[..len].to_vec());
+        Ok(())
+    }
+
+    fn send_raw(&self, _: Ipv4Addr, _: u16, _: &PayloadInfo) -> Result<(), NetAgentError> {
+        unimplemented!("raw packets are not framed by the device")
+    }
+}
+
+/// The expected bytes of a frame: everything before the payload, the range of the source buffer
+/// carried as the payload, and the pad bytes followed by the ICRC.
+///
+/// The headers are written as `IPv4 UDP BTH ...`, one group per header.
+struct Golden {
+    headers: &'static str,
+    payload: Range<usize>,
+    trailer: &'static str,
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s.bytes().filter(u8::is_ascii_hexdigit).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+fn capture(desc: ToCardWorkRbDesc) -> Vec<Vec<u8>> {
+    let agent = Arc::new(CaptureAgent::default());
+    let logic = BlueRDMALogic::new(Arc::<CaptureAgent>::clone(&agent));
+    logic.send(desc).unwrap();
+    let frames = agent.frames.lock().unwrap().clone();
+    frames
+}
+
+fn assert_frames(frames: &[Vec<u8>], src: &[u8], goldens: &[Golden]) {
+    assert_eq!(frames.len(), goldens.len());
+    for (idx, (frame, golden)) in frames.iter().zip(goldens).enumerate() {
+        let headers = hex(golden.headers);
+        let trailer = hex(golden.trailer);
+        let payload = &src[golden.payload.clone()];
+        assert_eq!(
+            frame.len(),
+            headers.len() + payload.len() + trailer.len(),
+            "length of frame {idx}"
+        );
+        let (frame_headers, rest) = frame.split_at(headers.len());
+        let (frame_payload, frame_trailer) = rest.split_at(payload.len());
+        assert_eq!(frame_headers, headers, "headers of frame {idx}");
+        assert_eq!(frame_payload, payload, "payload of frame {idx}");
+        assert_eq!(frame_trailer, trailer, "trailer of frame {idx}");
+    }
+}
+
+fn source_buffer() -> Vec<u8> {
+    (0..1024_u32).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn test_wire_write_only() {
+    let src = source_buffer();
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_total_len(64)
+        .with_raddr(0x1234_5678_9abc_0000)
+        .with_rkey(0x0102_0304)
+        .with_pmtu(Pmtu::Mtu256)
+        .with_psn(0x12_3456)
+        .with_dqpn(3)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src.as_ptr() as u64, 64, 0_u32)
+                .build(),
+        )
+        .build();
+    let frames = capture(desc);
+    assert_frames(
+        &frames,
+        &src,
+        &[
+            Golden {
+                headers: "4500007c0000000040113ac7c0a800017f000001 12b712b700680000 0a0000000000000300123456 123456789abc00000102030400000040",
+                payload: 0..64,
+                trailer: "a1a8621d",
+            },
+        ],
+    );
+}
+
+#[test]
+fn test_wire_write_multi_packet() {
+    let src = source_buffer();
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Write)
+        .with_total_len(600)
+        .with_raddr(0x10_0000)
+        .with_rkey(0x0a0b_0c0d)
+        .with_pmtu(Pmtu::Mtu256)
+        .with_psn(0xff_fffe)
+        .with_dqpn(0x12_3456)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src.as_ptr() as u64, 600, 0_u32)
+                .build(),
+        )
+        .build();
+    let frames = capture(desc);
+    assert_frames(
+        &frames,
+        &src,
+        &[
+            Golden {
+                headers: "4500013c0000000040113a07c0a800017f000001 12b712b701280000 060000000012345600fffffe 00000000001000000a0b0c0d00000258",
+                payload: 0..256,
+                trailer: "0f7a03ee",
+            },
+            Golden {
+                headers: "4500013c0001000040113a06c0a800017f000001 12b712b701280000 070000000012345600ffffff 00000000001001000a0b0c0d00000100",
+                payload: 256..512,
+                trailer: "c1f1a067",
+            },
+            Golden {
+                headers: "450000940002000040113aadc0a800017f000001 12b712b700800000 080000000012345600000000 00000000001002000a0b0c0d00000058",
+                payload: 512..600,
+                trailer: "b0f4d719",
+            },
+        ],
+    );
+}
+
+#[test]
+fn test_wire_write_with_imm() {
+    let src = source_buffer();
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::WriteWithImm)
+        .with_total_len(30)
+        .with_raddr(0x2000)
+        .with_rkey(0x1111_2222)
+        .with_pmtu(Pmtu::Mtu1024)
+        .with_psn(7)
+        .with_dqpn(9)
+        .with_imm(0xdead_beef)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(src.as_ptr() as u64, 30, 0_u32)
+                .build(),
+        )
+        .build();
+    let frames = capture(desc);
+    assert_frames(
+        &frames,
+        &src,
+        &[
+            Golden {
+                headers: "450000600000000040113ae3c0a800017f000001 12b712b7004c0000 0b4000000000000900000007 0000000000002000111122220000001e deadbeef",
+                payload: 0..30,
+                trailer: "0000 e079be87",
+            },
+        ],
+    );
+}
+
+#[test]
+fn test_wire_read_request() {
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::Read)
+        .with_total_len(4096)
+        .with_raddr(0x3000)
+        .with_rkey(0x3333_4444)
+        .with_pmtu(Pmtu::Mtu1024)
+        .with_psn(100)
+        .with_dqpn(5)
+        .with_sg_list(
+            SGListBuilder::new()
+                .with_sge(0x7f00_0000_1000, 4096, 0x5555_6666_u32)
+                .build(),
+        )
+        .build();
+    let frames = capture(desc);
+    assert_frames(
+        &frames,
+        &[],
+        &[
+            Golden {
+                headers: "4500004c0000000040113af7c0a800017f000001 12b712b700380000 0c0000000000000500000064 00000000000030003333444400001000 00007f00000010005555666600001000",
+                payload: 0..0,
+                trailer: "c1aa3a40",
+            },
+        ],
+    );
+}
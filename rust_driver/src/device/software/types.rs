@@ -1,7 +1,8 @@
 use crate::{
     device::{
-        ToCardCtrlRbDescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon, ToCardWorkRbDescOpcode,
-        ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
+        StagedPayload, ToCardCtrlRbDescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon,
+        ToCardWorkRbDescOpcode, ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode,
+        ToHostWorkRbDescTransType,
     },
     types::{MemAccessTypeFlag, Psn, QpType},
 };
@@ -101,6 +102,12 @@ pub(crate) enum Metadata {
 
     /// Acknowledge message
     Acknowledge(AethHeader),
+
+    /// SEND, which is delivered to a receive buffer instead of a remote address
+    Send(RdmaSendMeta),
+
+    /// Congestion notification packet
+    Cnp(RdmaMessageMetaCommon),
 }
 
 impl Metadata {
@@ -108,6 +115,8 @@ impl Metadata {
         match self {
             Metadata::General(header) => header.common_meta.opcode.clone(),
             Metadata::Acknowledge(header) => header.common_meta.opcode.clone(),
+            Metadata::Send(header) => header.common_meta.opcode.clone(),
+            Metadata::Cnp(common_meta) => common_meta.opcode.clone(),
         }
     }
 
@@ -115,6 +124,8 @@ impl Metadata {
         match self {
             Metadata::General(header) => &header.common_meta,
             Metadata::Acknowledge(header) => &header.common_meta,
+            Metadata::Send(header) => &header.common_meta,
+            Metadata::Cnp(common_meta) => common_meta,
         }
     }
 }
@@ -148,7 +159,6 @@ impl PayloadInfo {
         }
     }
 
-    #[cfg(test)]
     pub(crate) fn get_length(&self) -> usize {
         self.total_len
     }
@@ -171,15 +181,42 @@ impl PayloadInfo {
         self.total_len = self.total_len.wrapping_add(len);
     }
 
+    /// The number of segments the payload is assembled from
+    pub(crate) fn segment_count(&self) -> usize {
+        self.sg_list.len()
+    }
+
+    /// Check that the segments of the payload add up to `expected_len` bytes.
+    ///
+    /// Catches truncated or over-long payloads before they are copied to the host memory.
+    pub(crate) fn validate_against(&self, expected_len: usize) -> Result<(), PacketError> {
+        let actual = self
+            .sg_list
+            .iter()
+            .try_fold(0_usize, |sum, element| sum.checked_add(element.len))
+            .unwrap_or(usize::MAX);
+        if actual != self.total_len || actual != expected_len {
+            return Err(PacketError::PayloadLengthMismatch {
+                expected: expected_len,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     pub(crate) fn get_sg_list(&self) -> &Vec<SGListElement> {
         &self.sg_list
     }
 
+    /// Copy the payload to `dst`.
+    ///
+    /// The copy has `memmove` semantics: in the loopback case the payload may point into the
+    /// same memory region as `dst`, so the source and destination are allowed to overlap.
     pub(crate) fn copy_to(&self, mut dst: *mut u8) {
         for element in &self.sg_list {
             unsafe {
-                std::ptr::copy_nonoverlapping(element.data, dst, element.len);
+                std::ptr::copy(element.data, dst, element.len);
             }
             unsafe {
                 dst = dst.add(element.len);
@@ -187,6 +224,58 @@ impl PayloadInfo {
         }
     }
 
+    /// Copy the payload into `sges`, filling each element before moving to the next one.
+    ///
+    /// Returns `false` without copying anything if the elements are too small to hold the payload.
+    pub(crate) fn scatter_to(&self, sges: &[SGListElementWithKey]) -> bool {
+        let capacity = sges
+            .iter()
+            .fold(0_usize, |sum, sge| sum.saturating_add(sge.len as usize));
+        if capacity < self.total_len {
+            return false;
+        }
+        let mut sges = sges.iter().map(|sge| (sge.addr as *mut u8, sge.len as usize));
+        let (mut dst, mut room) = (std::ptr::null_mut(), 0_usize);
+        for element in &self.sg_list {
+            let (mut src, mut left) = (element.data, element.len);
+            while left > 0 {
+                while room == 0 {
+                    // the capacity is checked above, so the elements never run out here
+                    let Some((next_dst, next_room)) = sges.next() else {
+                        return false;
+                    };
+                    dst = next_dst;
+                    room = next_room;
+                }
+                let len = left.min(room);
+                unsafe {
+                    std::ptr::copy(src, dst, len);
+                }
+                unsafe {
+                    src = src.add(len);
+                }
+                unsafe {
+                    dst = dst.add(len);
+                }
+                left = left.wrapping_sub(len);
+                room = room.wrapping_sub(len);
+            }
+        }
+        true
+    }
+
+    /// The segments of the payload, in order
+    pub(crate) fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.sg_list.iter().map(|element| {
+            // an empty element may carry a null pointer
+            if element.len == 0 {
+                return &[][..];
+            }
+            // SAFETY: the element points to `len` bytes that outlive the payload
+            unsafe { std::slice::from_raw_parts(element.data, element.len) }
+        })
+    }
+
     /// Get the first and only element of the scatter-gather list.
     /// Note that you should only use this function when you are sure that the payload only contains one element.
     pub(crate) fn direct_data_ptr(&self) -> Option<&[u8]> {
@@ -210,6 +299,18 @@ pub(crate) struct RethHeader {
     pub(crate) len: u32,
 }
 
+impl RethHeader {
+    /// Check whether the memory range described by `self` overlaps with the one of `other`.
+    pub(crate) fn is_overlapped_with(&self, other: &RethHeader) -> bool {
+        if self.len == 0 || other.len == 0 {
+            return false;
+        }
+        let self_end = self.va.saturating_add(u64::from(self.len));
+        let other_end = other.va.saturating_add(u64::from(other.len));
+        self.va < other_end && other.va < self_end
+    }
+}
+
 impl From<&RETH> for RethHeader {
     fn from(reth: &RETH) -> Self {
         RethHeader {
@@ -303,6 +404,22 @@ impl RdmaGeneralMeta {
         }
     }
 }
+/// The metadata of a SEND packet, which has no RETH
+#[derive(Debug, Clone)]
+pub(crate) struct RdmaSendMeta {
+    pub(crate) common_meta: RdmaMessageMetaCommon,
+    pub(crate) imm: Option<u32>,
+}
+
+impl RdmaSendMeta {
+    pub(crate) fn new_from_packet(bth: &BTH, imm: Option<&Immediate>) -> Result<Self, PacketError> {
+        Ok(RdmaSendMeta {
+            common_meta: RdmaMessageMetaCommon::try_from(bth)?,
+            imm: imm.map(Immediate::get),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct AethHeader {
     pub(crate) common_meta: RdmaMessageMetaCommon,
@@ -556,6 +673,8 @@ pub(crate) struct ToCardWriteDescriptor {
     pub(crate) is_first: bool,
     pub(crate) is_last: bool,
     pub(crate) sg_list: SGList,
+    /// Keep the payload gathered by the driver, which `sg_list` may point to, until the packets are sent
+    pub(crate) _staged: Option<StagedPayload>,
 }
 
 impl ToCardWriteDescriptor {
@@ -574,13 +693,15 @@ impl ToCardWriteDescriptor {
             } else {
                 (ToHostWorkRbDescOpcode::RdmaWriteFirst, None)
             }
-        } else {
-            // self.is_last = True
+        } else if self.is_last {
             match (self.is_resp(), self.has_imm()) {
                 (true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseLast, None),
                 (false, true) => (ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate, self.imm),
                 (false, false) => (ToHostWorkRbDescOpcode::RdmaWriteLast, None),
             }
+        } else {
+            // a descriptor in the middle of a message chained by several descriptors
+            (self.write_middle_opcode(), None)
         }
     }
 
@@ -636,6 +757,7 @@ impl From<ToCardWorkRbDesc> for ToCardDescriptor {
                 is_last: desc.is_last,
                 imm: None,
                 sg_list: SGList::new_with_sge_list(desc.sge0, desc.sge1, desc.sge2, desc.sge3),
+                _staged: desc.staged,
             }),
             ToCardWorkRbDesc::Read(desc) => ToCardDescriptor::Read(ToCardReadDescriptor {
                 common: desc.common,
@@ -649,6 +771,7 @@ impl From<ToCardWorkRbDesc> for ToCardDescriptor {
                     is_last: desc.is_last,
                     imm: Some(desc.imm),
                     sg_list: SGList::new_with_sge_list(desc.sge0, desc.sge1, desc.sge2, desc.sge3),
+                    _staged: None,
                 })
             }
             ToCardWorkRbDesc::ReadResp(desc) => ToCardDescriptor::Write(ToCardWriteDescriptor {
@@ -658,6 +781,7 @@ impl From<ToCardWorkRbDesc> for ToCardDescriptor {
                 is_last: desc.is_last,
                 imm: None,
                 sg_list: SGList::new_with_sge_list(desc.sge0, desc.sge1, desc.sge2, desc.sge3),
+                _staged: desc.staged,
             }),
         }
     }
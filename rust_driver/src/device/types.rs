@@ -5,13 +5,14 @@ use crate::{
         CmdQueueReqDescSetRawPacketReceiveMeta, CmdQueueReqDescUpdateMrTable,
         CmdQueueReqDescUpdatePGT, MeatReportQueueDescFragSecondaryRETH,
     },
+    responser::Slot,
     types::{Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, Qpn, Sge},
     utils::u8_slice_to_u64,
     Error,
 };
 use eui48::MacAddress;
 use num_enum::TryFromPrimitive;
-use std::{net::Ipv4Addr, ops::Range};
+use std::{fmt, net::Ipv4Addr, ops::Range, sync::Arc};
 
 use super::descriptor::{
     CmdQueueDescCommonHead, MeatReportQueueDescBthReth, MeatReportQueueDescFragAETH,
@@ -29,6 +30,44 @@ pub(crate) enum ToCardCtrlRbDesc {
     SetRawPacketReceiveMeta(ToCardCtrlRbDescSetRawPacketReceiveMeta),
 }
 
+/// A short summary of the descriptor for the errors and the logs, without the fields of no interest
+impl fmt::Display for ToCardCtrlRbDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToCardCtrlRbDesc::UpdateMrTable(desc) => write!(
+                f,
+                "update mr table of key {:#x} at {:#x}, len {:#x}, pgt offset {}",
+                desc.key.get(),
+                desc.addr,
+                desc.len,
+                desc.pgt_offset
+            ),
+            ToCardCtrlRbDesc::UpdatePageTable(desc) => write!(
+                f,
+                "update {} page table entries at {} from {:#x}",
+                desc.pgte_cnt, desc.pgt_idx, desc.start_addr
+            ),
+            ToCardCtrlRbDesc::QpManagement(desc) => write!(
+                f,
+                "{} qp {}",
+                if desc.is_valid { "create" } else { "destroy" },
+                desc.qpn.get()
+            ),
+            ToCardCtrlRbDesc::SetNetworkParam(desc) => write!(
+                f,
+                "set network param of {}/{}, gateway {}",
+                desc.ipaddr, desc.netmask, desc.gateway
+            ),
+            ToCardCtrlRbDesc::SetRawPacketReceiveMeta(desc) => write!(
+                f,
+                "set raw packet receive meta at {:#x} of key {:#x}",
+                desc.base_write_addr,
+                desc.key.get()
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum ToHostCtrlRbDesc {
     UpdateMrTable(ToHostCtrlRbDescUpdateMrTable),
@@ -38,11 +77,17 @@ pub(crate) enum ToHostCtrlRbDesc {
     SetRawPacketReceiveMeta(ToHostCtrlRbDescSetRawPacketReceiveMeta),
 }
 
+/// A work descriptor sent to the card, which is scheduled by a `SchedulerStrategy`.
+#[non_exhaustive]
 #[derive(Clone, Debug)]
-pub(crate) enum ToCardWorkRbDesc {
+pub enum ToCardWorkRbDesc {
+    /// RDMA read request
     Read(ToCardWorkRbDescRead),
+    /// RDMA write
     Write(ToCardWorkRbDescWrite),
+    /// RDMA write with immediate data
     WriteWithImm(ToCardWorkRbDescWriteWithImm),
+    /// RDMA read response
     ReadResp(ToCardWorkRbDescWrite),
 }
 
@@ -53,6 +98,7 @@ pub(crate) enum ToHostWorkRbDesc {
     WriteWithImm(ToHostWorkRbDescWriteWithImm),
     Ack(ToHostWorkRbDescAck),
     Nack(ToHostWorkRbDescNack),
+    Recv(ToHostWorkRbDescRecv),
 }
 
 impl ToHostWorkRbDesc {
@@ -63,6 +109,7 @@ impl ToHostWorkRbDesc {
             ToHostWorkRbDesc::WriteWithImm(desc) => &desc.common.status,
             ToHostWorkRbDesc::Ack(desc) => &desc.common.status,
             ToHostWorkRbDesc::Nack(desc) => &desc.common.status,
+            ToHostWorkRbDesc::Recv(desc) => &desc.common.status,
         }
     }
 }
@@ -75,7 +122,11 @@ pub(crate) struct ToCardCtrlRbDescCommon {
 #[derive(Debug)]
 pub(crate) struct ToCardCtrlRbDescUpdateMrTable {
     pub(crate) common: ToCardCtrlRbDescCommon,
+    /// The base of the addresses the requests use to access the MR, i.e. the IOVA
     pub(crate) addr: u64,
+    /// The virtual address the MR is mapped at in the process. The hardware translates `addr` through
+    /// the page table instead, only the software device accesses the memory by it.
+    pub(crate) va: u64,
     pub(crate) len: u32,
     pub(crate) key: Key,
     pub(crate) pd_hdl: u32,
@@ -100,6 +151,8 @@ pub(crate) struct ToCardCtrlRbDescQpManagement {
     pub(crate) qp_type: QpType,
     pub(crate) rq_acc_flags: MemAccessTypeFlag,
     pub(crate) pmtu: Pmtu,
+    pub(crate) rnr_retry: u8,
+    pub(crate) min_rnr_timer: u8,
 }
 
 #[derive(Debug)]
@@ -164,14 +217,16 @@ pub(crate) struct ToCardWorkRbDescCommon {
     pub(crate) msn: Msn,
 }
 
+/// The work descriptor of a RDMA read request
 #[derive(Clone, Debug)]
-pub(crate) struct ToCardWorkRbDescRead {
+pub struct ToCardWorkRbDescRead {
     pub(crate) common: ToCardWorkRbDescCommon,
     pub(crate) sge: ToCardCtrlRbDescSge,
 }
 
+/// The work descriptor of a RDMA write or read response
 #[derive(Clone, Debug)]
-pub(crate) struct ToCardWorkRbDescWrite {
+pub struct ToCardWorkRbDescWrite {
     pub(crate) common: ToCardWorkRbDescCommon,
     pub(crate) is_last: bool,
     pub(crate) is_first: bool,
@@ -179,10 +234,21 @@ pub(crate) struct ToCardWorkRbDescWrite {
     pub(crate) sge1: Option<ToCardCtrlRbDescSge>,
     pub(crate) sge2: Option<ToCardCtrlRbDescSge>,
     pub(crate) sge3: Option<ToCardCtrlRbDescSge>,
+    /// The acknowledge buffer slot holding the payload of an ack, released after the descriptor is
+    /// sent. Without the scheduler, it is released once the descriptor is written to the ring.
+    #[cfg_attr(not(feature = "scheduler"), allow(dead_code))]
+    pub(crate) ack_slot: Option<Arc<Slot>>,
+    /// The payload the driver gathers from the sges too many for a descriptor, which the sges of
+    /// the descriptor point to.
+    pub(crate) staged: Option<StagedPayload>,
 }
 
+/// The buffers holding the payload of the sges gathered by the driver, see `Device::write_gather`
+pub(crate) type StagedPayload = Arc<[Box<[u8]>]>;
+
+/// The work descriptor of a RDMA write with immediate data
 #[derive(Clone, Debug)]
-pub(crate) struct ToCardWorkRbDescWriteWithImm {
+pub struct ToCardWorkRbDescWriteWithImm {
     pub(crate) common: ToCardWorkRbDescCommon,
     pub(crate) is_last: bool,
     pub(crate) is_first: bool,
@@ -254,10 +320,24 @@ pub(crate) struct ToHostWorkRbDescAck {
 pub(crate) struct ToHostWorkRbDescNack {
     pub(crate) common: ToHostWorkRbDescCommon,
     pub(crate) msn: Msn,
+    /// `Nak`, or `Rnr` if the remote has no receive buffer
+    pub(crate) code: ToHostWorkRbDescAethCode,
     pub(crate) value: u8,
     pub(crate) lost_psn: Range<Psn>,
 }
 
+/// A SEND delivered to a receive buffer of the QP
+#[allow(unused)]
+#[derive(Debug)]
+pub(crate) struct ToHostWorkRbDescRecv {
+    pub(crate) common: ToHostWorkRbDescCommon,
+    /// The PSN of the last packet of the SEND
+    pub(crate) psn: Psn,
+    /// The length of the whole message
+    pub(crate) len: u32,
+    pub(crate) imm: Option<u32>,
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 pub(crate) struct ToCardCtrlRbDescSge {
     pub(crate) addr: u64,
@@ -265,7 +345,7 @@ pub(crate) struct ToCardCtrlRbDescSge {
     pub(crate) key: Key,
 }
 
-#[derive(TryFromPrimitive, Debug)]
+#[derive(TryFromPrimitive, Debug, Clone, Copy)]
 #[repr(u8)]
 pub(crate) enum ToHostWorkRbDescStatus {
     Normal = 1,
@@ -277,7 +357,7 @@ pub(crate) enum ToHostWorkRbDescStatus {
 }
 
 impl ToHostWorkRbDescStatus {
-    pub(crate) fn is_ok(&self) -> bool {
+    pub(crate) fn is_ok(self) -> bool {
         matches!(self, ToHostWorkRbDescStatus::Normal)
     }
 }
@@ -344,15 +424,11 @@ pub(crate) enum ToCardWorkRbDescOpcode {
     ReadResp = 12, // Not defined in rdma-core
 }
 
-#[derive(TryFromPrimitive, PartialEq, Eq, Debug, Clone)]
+/// The opcode of a received RDMA packet, as carried in the BTH.
+#[non_exhaustive]
+#[derive(TryFromPrimitive, PartialEq, Eq, Hash, Debug, Clone)]
 #[repr(u8)]
-pub(crate) enum ToHostWorkRbDescOpcode {
-    // SendFirst = 0x00,
-    // SendMiddle = 0x01,
-    // SendLast = 0x02,
-    // SendLastWithImmediate = 0x03,
-    // SendOnly = 0x04,
-    // SendOnlyWithImmediate = 0x05,
+pub enum ToHostWorkRbDescOpcode {
     // RdmaWriteFirst = 0x06,
     // RdmaWriteMiddle = 0x07,
     // RdmaWriteLast = 0x08,
@@ -367,26 +443,59 @@ pub(crate) enum ToHostWorkRbDescOpcode {
     // Resync = 0x15,
     // SendLastWithInvalidate = 0x16,
     // SendOnlyWithInvalidate = 0x17,
+    /// The first packet of a multi-packet SEND
+    SendFirst = 0x00,
+    /// A middle packet of a multi-packet SEND
+    SendMiddle = 0x01,
+    /// The last packet of a multi-packet SEND
+    SendLast = 0x02,
+    /// The last packet of a multi-packet SEND with immediate data
+    SendLastWithImmediate = 0x03,
+    /// A single packet SEND
+    SendOnly = 0x04,
+    /// A single packet SEND with immediate data
+    SendOnlyWithImmediate = 0x05,
+    /// The first packet of a multi-packet RDMA write
     RdmaWriteFirst = 0x06,
+    /// A middle packet of a multi-packet RDMA write
     RdmaWriteMiddle = 0x07,
+    /// The last packet of a multi-packet RDMA write
     RdmaWriteLast = 0x08,
+    /// The last packet of a multi-packet RDMA write with immediate data
     RdmaWriteLastWithImmediate = 0x09,
+    /// A single packet RDMA write
     RdmaWriteOnly = 0x0a,
+    /// A single packet RDMA write with immediate data
     RdmaWriteOnlyWithImmediate = 0x0b,
+    /// The first packet of a multi-packet read response
     RdmaReadResponseFirst = 0x0d,
+    /// A middle packet of a multi-packet read response
     RdmaReadResponseMiddle = 0x0e,
+    /// The last packet of a multi-packet read response
     RdmaReadResponseLast = 0x0f,
+    /// A single packet read response
     RdmaReadResponseOnly = 0x10,
+    /// A read request
     RdmaReadRequest = 0x0c,
+    /// An ACK or NAK
     Acknowledge = 0x11,
+    /// A congestion notification packet of `RoCEv2`. It is the whole opcode byte, because the opcode of
+    /// a CNP is told by its transaction type rather than the lower 5 bits.
+    Cnp = 0x81,
 }
 
 impl ToHostWorkRbDescOpcode {
     pub(crate) fn is_first(&self) -> bool {
         match self {
-            ToHostWorkRbDescOpcode::RdmaWriteFirst
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::RdmaWriteFirst
             | ToHostWorkRbDescOpcode::RdmaReadResponseFirst => true,
-            ToHostWorkRbDescOpcode::RdmaWriteMiddle
+            ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteMiddle
             | ToHostWorkRbDescOpcode::RdmaWriteLast
             | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
             | ToHostWorkRbDescOpcode::RdmaWriteOnly
@@ -395,17 +504,78 @@ impl ToHostWorkRbDescOpcode {
             | ToHostWorkRbDescOpcode::RdmaReadResponseLast
             | ToHostWorkRbDescOpcode::RdmaReadResponseOnly
             | ToHostWorkRbDescOpcode::RdmaReadRequest
-            | ToHostWorkRbDescOpcode::Acknowledge => false,
+            | ToHostWorkRbDescOpcode::Acknowledge
+            | ToHostWorkRbDescOpcode::Cnp => false,
         }
     }
 
-    pub(crate) fn is_resp(&self) -> bool {
+    /// Whether the packet is in the middle of a message, neither its first nor its last packet.
+    pub(crate) fn is_middle(&self) -> bool {
         matches!(
             self,
-            ToHostWorkRbDescOpcode::RdmaReadResponseFirst
+            ToHostWorkRbDescOpcode::SendMiddle
+                | ToHostWorkRbDescOpcode::RdmaWriteMiddle
                 | ToHostWorkRbDescOpcode::RdmaReadResponseMiddle
-                | ToHostWorkRbDescOpcode::RdmaReadResponseLast
-                | ToHostWorkRbDescOpcode::RdmaReadResponseOnly
+        )
+    }
+
+    pub(crate) fn is_last(&self) -> bool {
+        match self {
+            ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteLast
+            | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaReadResponseLast => true,
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteFirst
+            | ToHostWorkRbDescOpcode::RdmaWriteMiddle
+            | ToHostWorkRbDescOpcode::RdmaWriteOnly
+            | ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaReadResponseFirst
+            | ToHostWorkRbDescOpcode::RdmaReadResponseMiddle
+            | ToHostWorkRbDescOpcode::RdmaReadResponseOnly
+            | ToHostWorkRbDescOpcode::RdmaReadRequest
+            | ToHostWorkRbDescOpcode::Acknowledge
+            | ToHostWorkRbDescOpcode::Cnp => false,
+        }
+    }
+
+    /// Whether the packet carries immediate data, which consumes a receive buffer.
+    pub(crate) fn has_immediate(&self) -> bool {
+        matches!(
+            self,
+            ToHostWorkRbDescOpcode::SendLastWithImmediate
+                | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+                | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
+                | ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
+        )
+    }
+
+    /// Whether the packet carries a RETH as defined by the spec, i.e. it starts a request on a remote
+    /// MR. The packets built by this driver also carry the RETH in the other write and read response
+    /// packets, so this does not tell the header layout.
+    pub(crate) fn expects_reth(&self) -> bool {
+        matches!(
+            self,
+            ToHostWorkRbDescOpcode::RdmaWriteFirst
+                | ToHostWorkRbDescOpcode::RdmaWriteOnly
+                | ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
+                | ToHostWorkRbDescOpcode::RdmaReadRequest
+        )
+    }
+
+    pub(crate) fn is_send(&self) -> bool {
+        matches!(
+            self,
+            ToHostWorkRbDescOpcode::SendFirst
+                | ToHostWorkRbDescOpcode::SendMiddle
+                | ToHostWorkRbDescOpcode::SendLast
+                | ToHostWorkRbDescOpcode::SendLastWithImmediate
+                | ToHostWorkRbDescOpcode::SendOnly
+                | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
         )
     }
 
@@ -435,7 +605,15 @@ impl ToHostWorkRbDescOpcode {
             ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
             | ToHostWorkRbDescOpcode::RdmaWriteOnly
             | ToHostWorkRbDescOpcode::RdmaReadResponseOnly => Some(ToHostWorkRbDescWriteType::Only),
-            ToHostWorkRbDescOpcode::RdmaReadRequest | ToHostWorkRbDescOpcode::Acknowledge => None,
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaReadRequest
+            | ToHostWorkRbDescOpcode::Acknowledge
+            | ToHostWorkRbDescOpcode::Cnp => None,
         }
     }
 }
@@ -539,6 +717,8 @@ impl ToCardCtrlRbDesc {
             seg0.set_qp_type(desc.qp_type as u64);
             seg0.set_rq_access_flags(desc.rq_acc_flags.bits().into());
             seg0.set_pmtu(desc.pmtu as u64);
+            // The hardware has no field for `rnr_retry` and `min_rnr_timer` yet, only the software
+            // device retries on RNR NAKs.
         }
 
         fn write_set_network_param(dst: &mut [u8], desc: &ToCardCtrlRbDescSetNetworkParam) {
@@ -655,29 +835,92 @@ impl ToHostCtrlRbDesc {
 }
 
 impl ToCardWorkRbDesc {
+    pub(crate) fn common(&self) -> &ToCardWorkRbDescCommon {
+        match self {
+            ToCardWorkRbDesc::Read(desc) => &desc.common,
+            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => &desc.common,
+            ToCardWorkRbDesc::WriteWithImm(desc) => &desc.common,
+        }
+    }
+
+    pub(crate) fn common_mut(&mut self) -> &mut ToCardWorkRbDescCommon {
+        match self {
+            ToCardWorkRbDesc::Read(desc) => &mut desc.common,
+            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => &mut desc.common,
+            ToCardWorkRbDesc::WriteWithImm(desc) => &mut desc.common,
+        }
+    }
+
+    /// The destination QP of the descriptor
+    #[must_use]
+    pub fn dqpn(&self) -> Qpn {
+        self.common().dqpn
+    }
+
+    /// The PSN of the first packet of the descriptor
+    #[must_use]
+    pub fn psn(&self) -> Psn {
+        self.common().psn
+    }
+
+    /// The length of the payload in bytes
+    #[must_use]
+    pub fn total_len(&self) -> u32 {
+        self.common().total_len
+    }
+
+    /// Whether the descriptor carries the first packet of its message
+    #[must_use]
+    pub fn is_first(&self) -> bool {
+        match self {
+            ToCardWorkRbDesc::Read(_) => true,
+            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => desc.is_first,
+            ToCardWorkRbDesc::WriteWithImm(desc) => desc.is_first,
+        }
+    }
+
+    /// Whether the descriptor carries the last packet of its message
+    #[must_use]
+    pub fn is_last(&self) -> bool {
+        match self {
+            ToCardWorkRbDesc::Read(_) => true,
+            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => desc.is_last,
+            ToCardWorkRbDesc::WriteWithImm(desc) => desc.is_last,
+        }
+    }
+
+    /// Whether the descriptor consumes a receive WQE of the remote QP, so it needs a credit to be
+    /// sent.
+    ///
+    /// A message consumes a WQE once, so only the first descriptor of it needs a credit.
+    #[must_use]
+    pub fn consumes_recv_wqe(&self) -> bool {
+        match self {
+            ToCardWorkRbDesc::WriteWithImm(desc) => desc.is_first,
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::Write(_)
+            | ToCardWorkRbDesc::ReadResp(_) => false,
+        }
+    }
+
+    pub(crate) fn opcode(&self) -> ToCardWorkRbDescOpcode {
+        match self {
+            ToCardWorkRbDesc::Read(_) => ToCardWorkRbDescOpcode::Read,
+            ToCardWorkRbDesc::Write(_) => ToCardWorkRbDescOpcode::Write,
+            ToCardWorkRbDesc::WriteWithImm(_) => ToCardWorkRbDescOpcode::WriteWithImm,
+            ToCardWorkRbDesc::ReadResp(_) => ToCardWorkRbDescOpcode::ReadResp,
+        }
+    }
+
     pub(super) fn write_0(&self, dst: &mut [u8]) {
-        let (common, opcode, is_first, is_last) = match self {
-            ToCardWorkRbDesc::Read(desc) => {
-                (&desc.common, ToCardWorkRbDescOpcode::Read, true, true)
+        let common = self.common();
+        let opcode = self.opcode();
+        let (is_first, is_last) = match self {
+            ToCardWorkRbDesc::Read(_) => (true, true),
+            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => {
+                (desc.is_first, desc.is_last)
             }
-            ToCardWorkRbDesc::Write(desc) => (
-                &desc.common,
-                ToCardWorkRbDescOpcode::Write,
-                desc.is_first,
-                desc.is_last,
-            ),
-            ToCardWorkRbDesc::WriteWithImm(desc) => (
-                &desc.common,
-                ToCardWorkRbDescOpcode::WriteWithImm,
-                desc.is_first,
-                desc.is_last,
-            ),
-            ToCardWorkRbDesc::ReadResp(desc) => (
-                &desc.common,
-                ToCardWorkRbDescOpcode::ReadResp,
-                desc.is_first,
-                desc.is_last,
-            ),
+            ToCardWorkRbDesc::WriteWithImm(desc) => (desc.is_first, desc.is_last),
         };
 
         let mut head = SendQueueDescCommonHead(dst);
@@ -1057,18 +1300,34 @@ impl ToHostWorkRbDesc {
                             psn,
                         }))
                     }
-                    ToHostWorkRbDescAethCode::Nak => {
+                    ToHostWorkRbDescAethCode::Nak | ToHostWorkRbDescAethCode::Rnr => {
                         Ok(ToHostWorkRbDesc::Nack(ToHostWorkRbDescNack {
                             common,
                             msn: msn_in_ack,
+                            code,
                             value,
                             lost_psn: psn..last_psn,
                         }))
                     }
-                    ToHostWorkRbDescAethCode::Rnr => unimplemented!(),
-                    ToHostWorkRbDescAethCode::Rsvd => unimplemented!(),
+                    ToHostWorkRbDescAethCode::Rsvd => Err(ToHostWorkRbDescError::DeviceError(
+                        DeviceError::ParseDesc("the reserved AETH code is reported".to_owned()),
+                    )),
                 }
             }
+            // the card handles the CNPs itself, and the 5 bits opcode can not carry one
+            ToHostWorkRbDescOpcode::Cnp => Err(ToHostWorkRbDescError::DeviceError(
+                DeviceError::ParseDesc("a CNP should not be reported".to_owned()),
+            )),
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate => {
+                Err(ToHostWorkRbDescError::DeviceError(DeviceError::ParseDesc(format!(
+                    "the descriptor of {opcode:?} is not supported"
+                ))))
+            }
         }
     }
 
@@ -1079,7 +1338,8 @@ impl ToHostWorkRbDesc {
             ToHostWorkRbDesc::WriteOrReadResp(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Nack(_)
-            | ToHostWorkRbDesc::Ack(_) => 1,
+            | ToHostWorkRbDesc::Ack(_)
+            | ToHostWorkRbDesc::Recv(_) => 1,
         }
     }
 }
@@ -1112,7 +1372,8 @@ impl IncompleteToHostWorkRbDesc {
             ToHostWorkRbDesc::WriteOrReadResp(_)
             | ToHostWorkRbDesc::Nack(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
-            | ToHostWorkRbDesc::Ack(_) => unreachable!(),
+            | ToHostWorkRbDesc::Ack(_)
+            | ToHostWorkRbDesc::Recv(_) => unreachable!(),
         }
     }
 }
@@ -1122,6 +1383,10 @@ pub(crate) struct ToCardWorkRbDescBuilder {
     common: Option<ToCardWorkRbDescCommon>,
     seg_list: Vec<Sge>,
     imm: Option<u32>,
+    is_first: bool,
+    is_last: bool,
+    ack_slot: Option<Slot>,
+    staged: Option<StagedPayload>,
 }
 
 impl ToCardWorkRbDescBuilder {
@@ -1131,6 +1396,10 @@ impl ToCardWorkRbDescBuilder {
             common: None,
             seg_list: Vec::new(),
             imm: None,
+            is_first: true,
+            is_last: true,
+            ack_slot: None,
+            staged: None,
         }
     }
 
@@ -1140,6 +1409,10 @@ impl ToCardWorkRbDescBuilder {
             common: None,
             seg_list: Vec::new(),
             imm: None,
+            is_first: true,
+            is_last: true,
+            ack_slot: None,
+            staged: None,
         }
     }
 
@@ -1149,6 +1422,10 @@ impl ToCardWorkRbDescBuilder {
             common: None,
             seg_list: Vec::new(),
             imm: None,
+            is_first: true,
+            is_last: true,
+            ack_slot: None,
+            staged: None,
         }
     }
 
@@ -1162,43 +1439,64 @@ impl ToCardWorkRbDescBuilder {
         self
     }
 
-    pub(crate) fn build(mut self) -> Result<ToCardWorkRbDesc, Error> {
+    /// Set the position of the descriptor in a message chained by several descriptors.
+    /// A descriptor is both the first and the last one by default.
+    pub(crate) fn with_position(mut self, is_first: bool, is_last: bool) -> Self {
+        self.is_first = is_first;
+        self.is_last = is_last;
+        self
+    }
+
+    /// Keep the acknowledge buffer `slot` that the sge points to until the descriptor is sent.
+    pub(crate) fn with_ack_slot(mut self, slot: Slot) -> Self {
+        self.ack_slot = Some(slot);
+        self
+    }
+
+    /// Keep the `staged` payload that the sges point to as long as the descriptor.
+    pub(crate) fn with_staged(mut self, staged: StagedPayload) -> Self {
+        self.staged = Some(staged);
+        self
+    }
+
+    pub(crate) fn build(self) -> Result<ToCardWorkRbDesc, Error> {
         let common = self
             .common
             .ok_or_else(|| Error::BuildDescFailed("common"))?;
+        let mut seg_list = self.seg_list.into_iter();
         match self.type_ {
             ToCardWorkRbDescOpcode::Write => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = seg_list
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
+                let sge1 = seg_list.next();
+                let sge2 = seg_list.next();
+                let sge3 = seg_list.next();
                 Ok(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
                     common,
-                    is_last: true,
-                    is_first: true,
+                    is_last: self.is_last,
+                    is_first: self.is_first,
                     sge0: sge0.into(),
                     sge1: sge1.map(bitfield::Into::into),
                     sge2: sge2.map(bitfield::Into::into),
                     sge3: sge3.map(bitfield::Into::into),
+                    ack_slot: self.ack_slot.map(Arc::new),
+                    staged: self.staged,
                 }))
             }
             ToCardWorkRbDescOpcode::WriteWithImm => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = seg_list
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
+                let sge1 = seg_list.next();
+                let sge2 = seg_list.next();
+                let sge3 = seg_list.next();
                 let imm = self.imm.ok_or_else(|| Error::BuildDescFailed("imm"))?;
                 Ok(ToCardWorkRbDesc::WriteWithImm(
                     ToCardWorkRbDescWriteWithImm {
                         common,
-                        is_last: true,
-                        is_first: true,
+                        is_last: self.is_last,
+                        is_first: self.is_first,
                         imm,
                         sge0: sge0.into(),
                         sge1: sge1.map(bitfield::Into::into),
@@ -1208,9 +1506,8 @@ impl ToCardWorkRbDescBuilder {
                 ))
             }
             ToCardWorkRbDescOpcode::Read => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = seg_list
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
                 Ok(ToCardWorkRbDesc::Read(ToCardWorkRbDescRead {
                     common,
@@ -1218,37 +1515,240 @@ impl ToCardWorkRbDescBuilder {
                 }))
             }
             ToCardWorkRbDescOpcode::ReadResp => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = seg_list
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
+                let sge1 = seg_list.next();
+                let sge2 = seg_list.next();
+                let sge3 = seg_list.next();
                 Ok(ToCardWorkRbDesc::ReadResp(ToCardWorkRbDescWrite {
                     common,
-                    is_last: true,
-                    is_first: true,
+                    is_last: self.is_last,
+                    is_first: self.is_first,
                     sge0: sge0.into(),
                     sge1: sge1.map(bitfield::Into::into),
                     sge2: sge2.map(bitfield::Into::into),
                     sge3: sge3.map(bitfield::Into::into),
+                    ack_slot: None,
+                    staged: None,
                 }))
             }
         }
     }
 }
 
+/// A builder of `ToCardWorkRbDesc::Write` with typed setters.
+///
+/// By default, it builds a single descriptor RC write to QP 0 at localhost, whose total length is the
+/// sum of the sges.
+#[cfg(any(test, feature = "bench"))]
+pub(crate) struct ToCardWorkRbDescWriteBuilder {
+    common: ToCardWorkRbDescCommon,
+    total_len: Option<u32>,
+    is_first: bool,
+    is_last: bool,
+    sg_list: Vec<ToCardCtrlRbDescSge>,
+}
+
+#[cfg(any(test, feature = "bench"))]
+#[cfg_attr(not(test), allow(dead_code))]
+impl ToCardWorkRbDescWriteBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            common: ToCardWorkRbDescCommon {
+                total_len: 0,
+                raddr: 0,
+                rkey: Key::default(),
+                dqp_ip: Ipv4Addr::LOCALHOST,
+                dqpn: Qpn::default(),
+                mac_addr: MacAddress::default(),
+                pmtu: Pmtu::Mtu1024,
+                flags: MemAccessTypeFlag::IbvAccessNoFlags,
+                qp_type: QpType::Rc,
+                psn: Psn::default(),
+                msn: Msn::default(),
+            },
+            total_len: None,
+            is_first: true,
+            is_last: true,
+            sg_list: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_total_len(mut self, total_len: u32) -> Self {
+        self.total_len = Some(total_len);
+        self
+    }
+
+    pub(crate) fn with_raddr(mut self, raddr: u64) -> Self {
+        self.common.raddr = raddr;
+        self
+    }
+
+    pub(crate) fn with_rkey(mut self, rkey: Key) -> Self {
+        self.common.rkey = rkey;
+        self
+    }
+
+    pub(crate) fn with_dqp_ip(mut self, dqp_ip: Ipv4Addr) -> Self {
+        self.common.dqp_ip = dqp_ip;
+        self
+    }
+
+    pub(crate) fn with_dqpn(mut self, dqpn: Qpn) -> Self {
+        self.common.dqpn = dqpn;
+        self
+    }
+
+    pub(crate) fn with_mac_addr(mut self, mac_addr: MacAddress) -> Self {
+        self.common.mac_addr = mac_addr;
+        self
+    }
+
+    pub(crate) fn with_pmtu(mut self, pmtu: Pmtu) -> Self {
+        self.common.pmtu = pmtu;
+        self
+    }
+
+    pub(crate) fn with_flags(mut self, flags: MemAccessTypeFlag) -> Self {
+        self.common.flags = flags;
+        self
+    }
+
+    pub(crate) fn with_qp_type(mut self, qp_type: QpType) -> Self {
+        self.common.qp_type = qp_type;
+        self
+    }
+
+    pub(crate) fn with_psn(mut self, psn: Psn) -> Self {
+        self.common.psn = psn;
+        self
+    }
+
+    pub(crate) fn with_msn(mut self, msn: Msn) -> Self {
+        self.common.msn = msn;
+        self
+    }
+
+    /// Set the position of the descriptor in a message chained by several descriptors.
+    pub(crate) fn with_position(mut self, is_first: bool, is_last: bool) -> Self {
+        self.is_first = is_first;
+        self.is_last = is_last;
+        self
+    }
+
+    /// Append a sge. A descriptor carries 4 sges at most.
+    pub(crate) fn with_sge(mut self, addr: u64, len: u32, key: Key) -> Self {
+        self.sg_list.push(ToCardCtrlRbDescSge { addr, len, key });
+        self
+    }
+
+    /// # Panic
+    /// There should be no more than 4 sges.
+    pub(crate) fn build(self) -> ToCardWorkRbDesc {
+        assert!(self.sg_list.len() <= 4, "too many sges");
+        let total_len = self.total_len.unwrap_or_else(|| {
+            self.sg_list
+                .iter()
+                .fold(0_u32, |total, sge| total.wrapping_add(sge.len))
+        });
+        let mut sg_list = self.sg_list.into_iter();
+        ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+            common: ToCardWorkRbDescCommon {
+                total_len,
+                ..self.common
+            },
+            is_last: self.is_last,
+            is_first: self.is_first,
+            sge0: sg_list.next().unwrap_or_default(),
+            sge1: sg_list.next(),
+            sge2: sg_list.next(),
+            sge3: sg_list.next(),
+            ack_slot: None,
+            staged: None,
+        })
+    }
+}
+
+/// The error reported by the device adaptors and the scheduler
+#[non_exhaustive]
 #[derive(Debug, Error)]
-pub(crate) enum DeviceError {
+pub enum DeviceError {
+    /// Device internal error
     #[error("device error : {0}")]
     Device(String),
+    /// Ringbuffer overflow
     #[error("Overflow")]
     Overflow,
+    /// The ring buffer can't take the descriptor until the card consumes some of the queued ones
+    #[error("Ring buffer {0} is full")]
+    RingFull(String),
+    /// Lock poisoned
     #[error("Lock poisoned : {0}")]
     LockPoisoned(String),
+    /// Scheduler error
     #[error("Scheduler : {0}")]
     Scheduler(String),
+    /// The scheduler has queued too many descriptors to take more
+    #[error("Scheduler is full")]
+    SchedulerFull,
+    /// Failed to parse a descriptor
     #[error("Parse descriptor error : {0}")]
     ParseDesc(String),
+    /// All the slots of the acknowledge buffer are taken by the acknowledges in flight
+    #[error("Acknowledge buffer is full")]
+    AckBufferFull,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToHostWorkRbDescOpcode, ToHostWorkRbDescWriteType};
+
+    #[test]
+    fn test_opcode_classification() {
+        use ToHostWorkRbDescOpcode::*;
+        use ToHostWorkRbDescWriteType::{First, Last, Middle, Only};
+        // opcode, is_first, is_last, has_immediate, expects_reth, is_read_resp, write_type
+        let table = [
+            (SendFirst, true, false, false, false, false, None),
+            (SendMiddle, false, false, false, false, false, None),
+            (SendLast, false, true, false, false, false, None),
+            (SendLastWithImmediate, false, true, true, false, false, None),
+            (SendOnly, false, false, false, false, false, None),
+            (SendOnlyWithImmediate, false, false, true, false, false, None),
+            (RdmaWriteFirst, true, false, false, true, false, Some(First)),
+            (RdmaWriteMiddle, false, false, false, false, false, Some(Middle)),
+            (RdmaWriteLast, false, true, false, false, false, Some(Last)),
+            (RdmaWriteLastWithImmediate, false, true, true, false, false, Some(Last)),
+            (RdmaWriteOnly, false, false, false, true, false, Some(Only)),
+            (RdmaWriteOnlyWithImmediate, false, false, true, true, false, Some(Only)),
+            (RdmaReadResponseFirst, true, false, false, false, true, Some(First)),
+            (RdmaReadResponseMiddle, false, false, false, false, true, Some(Middle)),
+            (RdmaReadResponseLast, false, true, false, false, true, Some(Last)),
+            (RdmaReadResponseOnly, false, false, false, false, true, Some(Only)),
+            (RdmaReadRequest, false, false, false, true, false, None),
+            (Acknowledge, false, false, false, false, false, None),
+            (Cnp, false, false, false, false, false, None),
+        ];
+        // every opcode is in the table
+        let covered = (0..=u8::MAX)
+            .filter_map(|value| ToHostWorkRbDescOpcode::try_from(value).ok())
+            .all(|opcode| table.iter().any(|row| row.0 == opcode));
+        assert!(covered);
+        for (opcode, first, last, imm, reth, read_resp, write_type) in table {
+            assert_eq!(opcode.is_first(), first, "{opcode:?}");
+            assert_eq!(opcode.is_last(), last, "{opcode:?}");
+            let name = format!("{opcode:?}");
+            assert_eq!(opcode.is_middle(), name.ends_with("Middle"), "{opcode:?}");
+            assert_eq!(opcode.is_send(), name.starts_with("Send"), "{opcode:?}");
+            assert_eq!(opcode.has_immediate(), imm, "{opcode:?}");
+            assert_eq!(opcode.expects_reth(), reth, "{opcode:?}");
+            assert_eq!(opcode.is_read_resp(), read_resp, "{opcode:?}");
+            assert_eq!(
+                format!("{:?}", opcode.write_type()),
+                format!("{write_type:?}"),
+                "{opcode:?}"
+            );
+        }
+    }
 }
@@ -140,35 +140,49 @@
 )]
 use crate::{
     device::{
-        DeviceAdaptor, EmulatedDevice, HardwareDevice, SoftwareDevice, ToCardCtrlRbDesc,
-        ToCardWorkRbDescCommon,
+        DeviceAdaptor, PhysAddrResolver, ToCardCtrlRbDesc, ToCardWorkRbDescCommon,
     },
     mr::{MrCtx, MrPgt},
     pd::PdCtx,
 };
 use device::{
-    scheduler::{round_robin::RoundRobinStrategy, DescriptorScheduler}, ToCardCtrlRbDescCommon, ToCardCtrlRbDescSetNetworkParam, ToCardCtrlRbDescSge, ToCardWorkRbDesc, ToCardWorkRbDescBuilder
+    StagedPayload, ToCardCtrlRbDescCommon, ToCardCtrlRbDescSetNetworkParam, ToCardCtrlRbDescSge,
+    ToCardWorkRbDescBuilder
 };
+use eui48::MacAddress;
 use log::debug;
-use op_ctx::{CtrlOpCtx, ReadOpCtx, WriteOpCtx};
+use op_ctx::{
+    CompletionStatus, CtrlOpCtx, CtxStatus, IssuedCtrlOp, OpDeadlines, OpResult, PendingOp, PendingOpMap, ReadOpCtx,
+    RepeatedOps, WriteOpCtx,
+};
+use path_mtu::PathMtuTable;
 use pkt_checker::PacketChecker;
 use poll::{ctrl::{ControlPoller, ControlPollerContext}, work::{WorkDescPoller, WorkDescPollerContext}};
-use qp::QpContext;
-use recv_pkt_map::RecvPktMap;
+use qp::{QpContext, QP_MAX_CNT, QP_RESERVED_CNT};
+use recv_pkt_map::{RecvPktMap, RecvPktMaps};
 use responser::DescResponser;
+use srq::SrqCtx;
 
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr},
     sync::{
-        atomic::{AtomicU16, AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, Mutex, OnceLock, RwLock,
     },
+    time::{Duration, Instant},
 };
 use thiserror::Error;
-use types::{Key, MemAccessTypeFlag, Msn, Psn, Qpn, RdmaDeviceNetworkParam, Sge};
-use utils::calculate_packet_cnt;
+use trace::enter_span;
+use types::{
+    DeviceCaps, EcnCodepoint, Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpState, QpType, Qpn,
+    RdmaDeviceNetworkParam, ReadOverlapPolicy, Sge, UdpChecksumMode, WriteRequest,
+};
+use utils::{calculate_packet_cnt, split_sge_list, PmtuFragments};
 
+/// a structured logger, enabled by the `logger` feature
+#[cfg(feature = "logger")]
+pub mod logger;
 /// memory region
 pub mod mr;
 /// op context for user to track the status of the write/read/control operation
@@ -177,11 +191,17 @@ pub mod op_ctx;
 pub mod pd;
 /// queue pair related structs and functions
 pub mod qp;
+/// shared receive queue
+pub mod srq;
 /// types exported to user
 pub mod types;
 
+/// the builder of the device, which collects the tunables of the device
+mod builder;
 /// adaptor device: hardware, software, emulated
 mod device;
+/// the link MTUs of the destinations, which clamp the pmtu of the QPs
+mod path_mtu;
 /// pakcet check thread: checking if the packet is received correctly
 mod pkt_checker;
 /// poll thread: polling the work descriptor and control descriptor
@@ -190,17 +210,50 @@ mod poll;
 mod recv_pkt_map;
 /// responser thread: sending the response(read resp or ack) to the device
 mod responser;
+/// tracing spans around each operation, enabled by the `tracing` feature
+mod trace;
 /// utility functions
 mod utils;
 
-pub use crate::{mr::Mr, pd::Pd};
+pub use crate::{
+    builder::{DeviceBuilder, Transport},
+    mr::{Mr, RegMrRequest},
+    pd::Pd,
+    srq::Srq,
+};
+/// the snapshot of the MR page table, enabled by the `debug-introspection` feature
+#[cfg(feature = "debug-introspection")]
+pub use crate::mr::PageTableDump;
+pub use device::{
+    scheduler::{sharded::ShardedScheduler, SchedulerStrategy},
+    software::{FaultInjector, IcrcConfig, MemoryFabric},
+    DeviceError, ToCardWorkRbDesc, ToCardWorkRbDescRead, ToCardWorkRbDescWrite,
+    ToCardWorkRbDescWriteWithImm, ToHostWorkRbDescOpcode,
+};
 pub use types::Error;
-pub use utils::HugePage;
+pub use utils::{numa_node_of_addr, AlignedMemory, HugePage, HugePageBacking, HugePageSize};
+/// the helpers of the scheduler benchmarks, enabled by the `bench` feature
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use device::scheduler::bench;
+/// the helpers of the net agent benchmarks, enabled by the `bench` feature
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use device::software::bench as net_bench;
 
-const MR_KEY_IDX_BIT_CNT: usize = 8;
 const MR_TABLE_SIZE: usize = 64;
+/// The MRs registered by the driver itself, i.e. the acknowledge buffer
+const RESERVED_MR_CNT: usize = 1;
 const MR_PGT_SIZE: usize = 1024;
 const DEFAULT_RMDA_PORT : u16 = 4791;
+/// The max number of sges in a work descriptor
+const MAX_SGE_PER_DESC: usize = 4;
+/// The max value of the 6 bits DSCP
+const MAX_DSCP: u8 = 0x3f;
+/// The max usable VLAN id, 0xfff is reserved
+const MAX_VLAN_ID: u16 = 0xffe;
+/// The max value of the 3 bits VLAN priority
+const MAX_VLAN_PCP: u8 = 7;
 
 /// A user space RDMA device.
 /// 
@@ -215,17 +268,30 @@ struct DeviceInner<D: ?Sized> {
     pd: Mutex<HashMap<Pd, PdCtx>>,
     mr_table: Mutex<[Option<MrCtx>; MR_TABLE_SIZE]>,
     qp_table: Arc<RwLock<HashMap<Qpn, QpContext>>>,
+    // serializes the changes to the existing qps, so `qp_table` is not held while they wait for the
+    // control ops
+    qp_modify_lock: Mutex<()>,
+    // the qps attached to each multicast group
+    mcast_groups: Mutex<HashMap<Ipv4Addr, HashSet<Qpn>>>,
+    srq_table: Mutex<HashMap<Srq, SrqCtx>>,
     mr_pgt: Mutex<MrPgt>,
-    read_op_ctx_map: Arc<RwLock<HashMap<Msn, ReadOpCtx>>>,
-    write_op_ctx_map: Arc<RwLock<HashMap<Msn, WriteOpCtx>>>,
+    read_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+    write_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+    // the deadlines of the operations in `read_op_ctx_map` and `write_op_ctx_map`
+    read_deadlines: Arc<OpDeadlines>,
+    write_deadlines: Arc<OpDeadlines>,
     ctrl_op_ctx_map: Arc<RwLock<HashMap<u32, CtrlOpCtx>>>,
     next_ctrl_op_id: AtomicU32,
-    next_msn: AtomicU16,
     responser: OnceLock<DescResponser>,
     work_desc_poller: OnceLock<WorkDescPoller>,
     pkt_checker_thread: OnceLock<PacketChecker>,
     ctrl_desc_poller : OnceLock<ControlPoller>,
+    // set by `Device::shutdown`, the operations fail with `Error::DeviceClosed` afterwards
+    closed: AtomicBool,
     local_network : RdmaDeviceNetworkParam,
+    path_mtus: Arc<PathMtuTable>,
+    // overrides the adaptor to translate the addresses of the page table entries
+    phys_addr_resolver: OnceLock<Arc<dyn PhysAddrResolver>>,
     adaptor: D,
 }
 
@@ -235,64 +301,78 @@ impl Device {
     ///
     /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
     pub fn new_hardware(network: &RdmaDeviceNetworkParam,device_name : String) -> Result<Self, Error> {
-        let qp_table = Arc::new(RwLock::new(HashMap::new()));
-        let round_robin = Arc::new(RoundRobinStrategy::new());
-        let scheduler = Arc::new(DescriptorScheduler::new(round_robin));
-        let adaptor = HardwareDevice::init(device_name,scheduler).map_err(|e| Error::Device(Box::new(e)))?;
-
-        let inner = Arc::new(DeviceInner {
-            pd: Mutex::new(HashMap::new()),
-            mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-            qp_table,
-            mr_pgt: Mutex::new(MrPgt::new()),
-            read_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            write_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            next_ctrl_op_id: AtomicU32::new(0),
-            next_msn: AtomicU16::new(0),
-            adaptor,
-            responser: OnceLock::new(),
-            pkt_checker_thread: OnceLock::new(),
-            work_desc_poller: OnceLock::new(),
-            ctrl_desc_poller : OnceLock::new(),
-            local_network : *network,
-        });
-
-        let dev = Self(inner);
-        dev.init()?;
-
-        Ok(dev)
+        DeviceBuilder::new(network)
+            .transport(Transport::Hardware { device_name })
+            .build()
     }
 
     /// # Errors
     ///
     /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
     pub fn new_software(network: &RdmaDeviceNetworkParam) -> Result<Self, Error> {
-        let qp_table = Arc::new(RwLock::new(HashMap::new()));
-        let adaptor = SoftwareDevice::init(network.ipaddr,DEFAULT_RMDA_PORT).map_err(Error::Device)?;
+        DeviceBuilder::new(network).build()
+    }
 
-        let inner = Arc::new(DeviceInner {
-            pd: Mutex::new(HashMap::new()),
-            mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-            qp_table,
-            mr_pgt: Mutex::new(MrPgt::new()),
-            read_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            write_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-            next_ctrl_op_id: AtomicU32::new(0),
-            next_msn: AtomicU16::new(0),
-            responser: OnceLock::new(),
-            work_desc_poller: OnceLock::new(),
-            pkt_checker_thread: OnceLock::new(),
-            ctrl_desc_poller : OnceLock::new(),
-            local_network : *network,
-            adaptor,
-        });
+    /// Create a software device which schedules the work descriptors with `scheduler`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
+    pub fn new_software_with_scheduler(
+        network: &RdmaDeviceNetworkParam,
+        scheduler: Arc<dyn SchedulerStrategy>,
+    ) -> Result<Self, Error> {
+        DeviceBuilder::new(network).scheduler(scheduler).build()
+    }
 
-        let dev = Self(inner);
-        dev.init()?;
+    /// Create a software device which schedules the work descriptors with `scheduler`, and sends them
+    /// with `send_workers` threads.
+    ///
+    /// The QPs are sharded over the workers by their qpn, so the descriptors of a QP keep their order
+    /// while the QPs are sent concurrently. A `send_workers` of 0 is treated as 1.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
+    pub fn new_software_with_send_workers(
+        network: &RdmaDeviceNetworkParam,
+        scheduler: Arc<dyn SchedulerStrategy>,
+        send_workers: usize,
+    ) -> Result<Self, Error> {
+        DeviceBuilder::new(network)
+            .scheduler(scheduler)
+            .send_workers(send_workers)
+            .build()
+    }
 
-        Ok(dev)
+    /// Create a software device on the in-process loopback network.
+    ///
+    /// It needs no raw socket and therefore no privilege, but it can only reach the other loopback
+    /// devices in the same process, addressed by their `network.ipaddr`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if another loopback device owns the address or the device failed to init.
+    pub fn new_software_loopback(network: &RdmaDeviceNetworkParam) -> Result<Self, Error> {
+        DeviceBuilder::new(network)
+            .transport(Transport::Loopback)
+            .build()
+    }
+
+    /// Create a software device on the in-process loopback network which schedules the work
+    /// descriptors with `scheduler`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if another loopback device owns the address or the device failed to init.
+    pub fn new_software_loopback_with_scheduler(
+        network: &RdmaDeviceNetworkParam,
+        scheduler: Arc<dyn SchedulerStrategy>,
+    ) -> Result<Self, Error> {
+        DeviceBuilder::new(network)
+            .transport(Transport::Loopback)
+            .scheduler(scheduler)
+            .build()
     }
 
     /// # Errors
@@ -303,38 +383,68 @@ impl Device {
         heap_mem_start_addr: usize,
         network: &RdmaDeviceNetworkParam,
     ) -> Result<Self, Error> {
-        let qp_table = Arc::new(RwLock::new(HashMap::new()));
-        #[cfg(feature = "scheduler")]
-        let adaptor = {
-            let round_robin = Arc::new(RoundRobinStrategy::new());
-            let scheduler = Arc::new(DescriptorScheduler::new(round_robin));
-            EmulatedDevice::init(rpc_server_addr, heap_mem_start_addr,scheduler).map_err(|e| Error::Device(Box::new(e)))?
-        };
-        #[cfg(not(feature = "scheduler"))]
-        let adaptor =
-            EmulatedDevice::init(rpc_server_addr, heap_mem_start_addr).map_err(|e| Error::Device(Box::new(e)))?;
+        DeviceBuilder::new(network)
+            .transport(Transport::Emulated {
+                rpc_server_addr,
+                heap_mem_start_addr,
+            })
+            .build()
+    }
+
+    /// Create an emulated device which schedules the work descriptors with `scheduler`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
+    #[cfg(feature = "scheduler")]
+    pub fn new_emulated_with_scheduler(
+        rpc_server_addr: SocketAddr,
+        heap_mem_start_addr: usize,
+        network: &RdmaDeviceNetworkParam,
+        scheduler: Arc<dyn SchedulerStrategy>,
+    ) -> Result<Self, Error> {
+        DeviceBuilder::new(network)
+            .transport(Transport::Emulated {
+                rpc_server_addr,
+                heap_mem_start_addr,
+            })
+            .scheduler(scheduler)
+            .build()
+    }
 
+    fn new_with_adaptor<D: DeviceAdaptor + 'static>(
+        adaptor: D,
+        network: &RdmaDeviceNetworkParam,
+        ack_buf_size: usize,
+        probe_path_mtu: bool,
+    ) -> Result<Self, Error> {
         let inner = Arc::new(DeviceInner {
             pd: Mutex::new(HashMap::new()),
             mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-            qp_table,
+            qp_table: Arc::new(RwLock::new(HashMap::new())),
+            qp_modify_lock: Mutex::new(()),
+            mcast_groups: Mutex::new(HashMap::new()),
+            srq_table: Mutex::new(HashMap::new()),
             mr_pgt: Mutex::new(MrPgt::new()),
             read_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
             write_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
+            read_deadlines: Arc::default(),
+            write_deadlines: Arc::default(),
             ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
             next_ctrl_op_id: AtomicU32::new(0),
-            next_msn: AtomicU16::new(0),
             responser: OnceLock::new(),
             work_desc_poller: OnceLock::new(),
             pkt_checker_thread: OnceLock::new(),
             ctrl_desc_poller : OnceLock::new(),
+            closed: AtomicBool::new(false),
             local_network : *network,
+            path_mtus: Arc::new(PathMtuTable::new(probe_path_mtu)),
+            phys_addr_resolver: OnceLock::new(),
             adaptor,
         });
 
         let dev = Self(inner);
-
-        dev.init()?;
+        dev.init(ack_buf_size)?;
 
         Ok(dev)
     }
@@ -344,6 +454,9 @@ impl Device {
     /// # Errors
     ///
     /// Will return `Err` if:
+    /// * the device is shut down
+    /// * the qp is not found or not in `QpState::Rts`
+    /// * the qp is neither `QpType::Rc` nor `QpType::Uc`
     /// * lock poisoned
     /// * failed to create a descriptor
     /// * failed to send a descriptor
@@ -356,11 +469,49 @@ impl Device {
         flags: MemAccessTypeFlag,
         sge0: Sge
     ) -> Result<WriteOpCtx, Error> {
-        let msn = self.get_msn();
-        let common = {
-            let total_len = sge0.len;
+        self.write_gather(dqpn, raddr, rkey, flags, &[sge0])
+    }
+
+    /// RDMA write operation which gathers the payload from a scatter list of any length
+    ///
+    /// A work descriptor carries at most 4 sges, so the message is sent by several chained descriptors
+    /// if there are more sges. If more than 4 sges fall in a packet, the payload of the ones from the
+    /// 4th on is copied into a buffer of the driver, which is sent as one sge.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the device is shut down
+    /// * `sges` is empty, or the total length of `sges` overflows `u32`
+    /// * a sge exceeds the MR of its key
+    /// * the qp is not found or not in `QpState::Rts`
+    /// * the qp is neither `QpType::Rc` nor `QpType::Uc`
+    /// * lock poisoned
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    /// * failed to create a operation context
+    #[allow(clippy::too_many_lines)]
+    pub fn write_gather(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: MemAccessTypeFlag,
+        sges: &[Sge],
+    ) -> Result<WriteOpCtx, Error> {
+        self.check_open()?;
+        let total_len = self.write_message_len(sges)?;
+        self.probe_path_mtu(dqpn)?;
+        let ctx = WriteOpCtx::new_running();
+        let (common, packet_cnt, ack_timeout, staged) = {
             let qp_guard = self.0.qp_table.read().map_err(|_| Error::LockPoisoned("qp table lock"))?;
             let qp = qp_guard.get(&dqpn).ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?;
+            qp.check_one_sided_op("RDMA write")?;
+            if qp.state != QpState::Rts {
+                return Err(Error::Invalid(format!("{dqpn:?} in state {:?}", qp.state)));
+            }
+            let pmtu = self.0.path_mtus.clamp(qp.dqp_ip, qp.pmtu)?;
+            let (sge_lists, staged) = self.descriptor_sge_lists(sges, raddr, pmtu)?;
             let mut common = ToCardWorkRbDescCommon {
                 total_len,
                 raddr,
@@ -368,44 +519,329 @@ impl Device {
                 dqp_ip: qp.dqp_ip,
                 dqpn: qp.qpn,
                 mac_addr: qp.dqp_mac_addr,
-                pmtu: qp.pmtu,
+                pmtu,
                 flags,
                 qp_type: qp.qp_type,
                 psn: Psn::default(),
-                msn,
+                msn: qp.next_msn(),
             };
-            let packet_cnt = calculate_packet_cnt(qp.pmtu, raddr, total_len);
-            let first_pkt_psn = {
-                let mut send_psn = qp.sending_psn.lock().map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
-                let first_pkt_psn = *send_psn;
-                *send_psn = send_psn.wrapping_add(packet_cnt);
-                first_pkt_psn
-            };
-            common.psn = first_pkt_psn;
-            common
+            let packet_cnt = calculate_packet_cnt(pmtu, raddr, total_len);
+            let descs = build_write_descs(&common, &sge_lists, staged.as_ref())?;
+            let mut next_psn = qp
+                .sending_psn
+                .lock()
+                .map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
+            common.psn = *next_psn;
+            ctx.mark_submitted();
+            self.push_write(qp, &mut next_psn, common.msn, &descs, packet_cnt)?;
+            (common, packet_cnt, qp.ack_timeout, staged)
         };
 
-        let desc = ToCardWorkRbDescBuilder::new_write()
-            .with_common(common)
-            .with_sge(sge0)
-            .build()?;
-        self.send_work_desc(desc)?;
+        let msn = common.msn;
+        // unreliable transports will not receive an ACK, so the write finishes once it is sent
+        let wait_for_ack = !matches!(common.qp_type, QpType::Uc);
+        enter_span!(
+            "submit",
+            qpn = common.dqpn.get(),
+            opcode = ?device::ToCardWorkRbDescOpcode::Write,
+            psn = common.psn.get(),
+            msn = msn.get(),
+            byte_len = total_len
+        );
+        #[cfg(feature = "tracing")]
+        let ack_wait_span = tracing::trace_span!(
+            "ack_wait",
+            qpn = common.dqpn.get(),
+            psn = common.psn.get(),
+            msn = msn.get(),
+            byte_len = total_len
+        );
 
-        let ctx = WriteOpCtx::new_running();
+        if !wait_for_ack {
+            let opcode = if packet_cnt == 1 {
+                ToHostWorkRbDescOpcode::RdmaWriteOnly
+            } else {
+                ToHostWorkRbDescOpcode::RdmaWriteLast
+            };
+            ctx.set_result(OpResult {
+                byte_len: total_len,
+                opcode,
+                status: CompletionStatus::Success,
+            })?;
+            return Ok(ctx);
+        }
+        #[cfg(feature = "tracing")]
+        ctx.attach_span(ack_wait_span)?;
+        ctx.reclaim_on_cancel(&self.0.write_op_ctx_map, (dqpn, msn), PendingOp::ctx)?;
 
+        let op = PendingOp::new(ctx.clone(), total_len)
+            .with_timeout(ack_timeout)
+            .with_packets(common.psn, PmtuFragments::new(raddr, total_len, common.pmtu))
+            .with_staged(staged);
+        let deadline = op.deadline();
         self.0
             .write_op_ctx_map
             .write()
             .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?
-            .insert(msn, ctx.clone()).map_or_else(||Ok(()),|_|Err(Error::CreateOpCtxFailed))?;
+            .insert((dqpn, msn), op).map_or_else(||Ok(()),|_|Err(Error::CreateOpCtxFailed))?;
+        self.0.write_deadlines.insert((dqpn, msn), deadline)?;
+        Ok(ctx)
+    }
+
+    /// RDMA write operation which writes the same message `count` times
+    ///
+    /// The descriptors of the message are built once, and every write only advances their psn and
+    /// msn. The returned context completes once all the writes are acknowledged, with the length of
+    /// all of them, or as soon as one of them fails.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` for the reasons of `write_gather`, or if `count` is zero, more than the
+    /// msns a qp can tell apart, or the writes add up to more than `u32::MAX` bytes.
+    #[allow(clippy::too_many_lines)]
+    pub fn write_repeated(&self, request: &WriteRequest, count: u32) -> Result<WriteOpCtx, Error> {
+        self.check_open()?;
+        if count == 0 || count > u32::from(u16::MAX) {
+            return Err(Error::Invalid(format!("repeat count {count}")));
+        }
+        let WriteRequest {
+            dqpn,
+            raddr,
+            rkey,
+            flags,
+            ref sges,
+        } = *request;
+        let total_len = self.write_message_len(sges)?;
+        self.probe_path_mtu(dqpn)?;
+        let qp_guard = self.0.qp_table.read().map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        let qp = qp_guard.get(&dqpn).ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?;
+        qp.check_one_sided_op("RDMA write")?;
+        if qp.state != QpState::Rts {
+            return Err(Error::Invalid(format!("{dqpn:?} in state {:?}", qp.state)));
+        }
+        let pmtu = self.0.path_mtus.clamp(qp.dqp_ip, qp.pmtu)?;
+        let (sge_lists, staged) = self.descriptor_sge_lists(sges, raddr, pmtu)?;
+        let packet_cnt = calculate_packet_cnt(pmtu, raddr, total_len);
+        let _: u32 = packet_cnt
+            .checked_mul(count)
+            .ok_or_else(|| Error::Invalid(format!("{count} writes of {packet_cnt} packets")))?;
+        let total_byte_len = total_len
+            .checked_mul(count)
+            .ok_or_else(|| Error::Invalid(format!("{count} writes of {total_len} bytes")))?;
+        let msns: Vec<Msn> = (0..count).map(|_| qp.next_msn()).collect();
+        let common = ToCardWorkRbDescCommon {
+            total_len,
+            raddr,
+            rkey,
+            dqp_ip: qp.dqp_ip,
+            dqpn: qp.qpn,
+            mac_addr: qp.dqp_mac_addr,
+            pmtu,
+            flags,
+            qp_type: qp.qp_type,
+            psn: Psn::default(),
+            msn: Msn::default(),
+        };
+        // every write pushes the same descriptors with its own psns and msn
+        let descs = build_write_descs(&common, &sge_lists, staged.as_ref())?;
+        // the writes are pending with their psns before they are pushed, so no other operation of
+        // the qp takes a psn until all of them are pushed
+        let mut next_psn = qp
+            .sending_psn
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
+
+        enter_span!(
+            "submit",
+            qpn = common.dqpn.get(),
+            opcode = ?device::ToCardWorkRbDescOpcode::Write,
+            psn = next_psn.get(),
+            count = count,
+            byte_len = total_byte_len
+        );
+        let ctx = WriteOpCtx::new_running();
+        // unreliable transports will not receive an ACK, so the writes finish once they are sent
+        let wait_for_ack = !matches!(common.qp_type, QpType::Uc);
+        let keys: Vec<(Qpn, Msn)> = msns.iter().map(|msn| (dqpn, *msn)).collect();
+        if wait_for_ack {
+            #[cfg(feature = "tracing")]
+            ctx.attach_span(tracing::trace_span!(
+                "ack_wait",
+                qpn = common.dqpn.get(),
+                psn = next_psn.get(),
+                count = count,
+                byte_len = total_byte_len
+            ))?;
+            ctx.reclaim_all_on_cancel(&self.0.write_op_ctx_map, keys.clone(), PendingOp::ctx)?;
+            // a write may be acknowledged as soon as it is sent, so all of them are pending before
+            let repeated = Arc::new(RepeatedOps::new(count));
+            let mut map = self
+                .0
+                .write_op_ctx_map
+                .write()
+                .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?;
+            if keys.iter().any(|key| map.contains_key(key)) {
+                return Err(Error::CreateOpCtxFailed);
+            }
+            let mut psn = *next_psn;
+            for key in &keys {
+                let op = PendingOp::new(ctx.clone(), total_len)
+                    .with_timeout(qp.ack_timeout)
+                    .with_repeated(Arc::clone(&repeated))
+                    .with_packets(psn, PmtuFragments::new(raddr, total_len, common.pmtu))
+                    .with_staged(staged.clone());
+                self.0.write_deadlines.insert(*key, op.deadline())?;
+                let _: Option<PendingOp> = map.insert(*key, op);
+                psn = psn.wrapping_add(packet_cnt);
+            }
+        }
+
+        let mut sent = Ok(());
+        ctx.mark_submitted();
+        for msn in msns {
+            sent = self.push_write(qp, &mut next_psn, msn, &descs, packet_cnt);
+            if sent.is_err() {
+                break;
+            }
+        }
+        drop(next_psn);
+        drop(qp_guard);
+        if let Err(err) = sent {
+            if wait_for_ack {
+                let mut map = self
+                    .0
+                    .write_op_ctx_map
+                    .write()
+                    .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?;
+                for key in &keys {
+                    let _: Option<PendingOp> = map.remove(key);
+                }
+            }
+            return Err(err);
+        }
+        if !wait_for_ack {
+            let opcode = if packet_cnt == 1 {
+                ToHostWorkRbDescOpcode::RdmaWriteOnly
+            } else {
+                ToHostWorkRbDescOpcode::RdmaWriteLast
+            };
+            ctx.set_result(OpResult {
+                byte_len: total_byte_len,
+                opcode,
+                status: CompletionStatus::Success,
+            })?;
+        }
         Ok(ctx)
     }
 
+    /// Push the write descriptors `descs` of the message `msn` to the card, numbering its `packet_cnt`
+    /// packets from `next_psn`, the locked `sending_psn` of `qp`. The psns of `descs` are taken as the
+    /// offsets from the first packet, see `build_write_descs`.
+    ///
+    /// `next_psn` only advances over the pushed descriptors, so a failed push leaves no hole in the
+    /// psns of the qp, and the write is no longer tracked for an ACK.
+    fn push_write(
+        &self,
+        qp: &QpContext,
+        next_psn: &mut Psn,
+        msn: Msn,
+        descs: &[ToCardWorkRbDesc],
+        packet_cnt: u32,
+    ) -> Result<(), Error> {
+        let first_psn = *next_psn;
+        // unreliable transports will not receive an ACK, so there is nothing to track
+        let tracked = !matches!(qp.qp_type, QpType::Uc);
+        let psn_tracker = || {
+            qp.psn_tracker
+                .lock()
+                .map_err(|_| Error::LockPoisoned("qp context psn tracker lock"))
+        };
+        if tracked {
+            psn_tracker()?.on_send(msn, first_psn, packet_cnt);
+        }
+        let ring = self.0.adaptor.to_card_work_rb();
+        let mut pushed_cnt = 0_u32;
+        for desc in descs {
+            let mut desc = desc.clone();
+            let common = desc.common_mut();
+            common.psn = first_psn.wrapping_add(common.psn.get());
+            common.msn = msn;
+            let desc_packet_cnt = device::scheduler::descriptor_packet_cnt(&desc);
+            if let Err(err) = ring.push(desc) {
+                *next_psn = next_psn.wrapping_add(pushed_cnt);
+                if tracked {
+                    psn_tracker()?.on_nack(msn);
+                }
+                return Err(push_error(err));
+            }
+            pushed_cnt = pushed_cnt.wrapping_add(desc_packet_cnt);
+        }
+        *next_psn = next_psn.wrapping_add(packet_cnt);
+        Ok(())
+    }
+
+    /// Split `sges` into the sge lists of the descriptors of a write to `raddr`, see `split_sge_list`.
+    ///
+    /// The sges of a list from the `MAX_SGE_PER_DESC`th on are gathered into one sge, whose payload is
+    /// copied into a buffer of the driver. The buffers are returned to be kept with the descriptors.
+    fn descriptor_sge_lists(
+        &self,
+        sges: &[Sge],
+        raddr: u64,
+        pmtu: Pmtu,
+    ) -> Result<(Vec<Vec<Sge>>, Option<StagedPayload>), Error> {
+        let mut sge_lists = split_sge_list(sges, raddr, pmtu, MAX_SGE_PER_DESC);
+        let mut staged = Vec::new();
+        for sge_list in sge_lists.iter_mut().filter(|list| list.len() > MAX_SGE_PER_DESC) {
+            let gathered = sge_list.split_off(MAX_SGE_PER_DESC.wrapping_sub(1));
+            let buf = self.stage_sges(&gathered)?;
+            // the gathered sges do not reach the next pmtu boundary
+            let len = u32::try_from(buf.len())
+                .map_err(|_| Error::Invalid(format!("{} gathered bytes", buf.len())))?;
+            sge_list.push(Sge::new(buf.as_ptr() as u64, len, Key::default()));
+            staged.push(buf);
+        }
+        Ok((sge_lists, (!staged.is_empty()).then(|| StagedPayload::from(staged))))
+    }
+
+    /// The length of a write message gathered from `sges`, which must be in the bounds of their MRs
+    fn write_message_len(&self, sges: &[Sge]) -> Result<u32, Error> {
+        if sges.is_empty() {
+            return Err(Error::Invalid("empty sge list".to_owned()));
+        }
+        let total_len = sges
+            .iter()
+            .try_fold(0_u32, |acc, sge| acc.checked_add(sge.len))
+            .ok_or_else(|| Error::Invalid("total length of sges overflows u32".to_owned()))?;
+        self.check_sge_bounds(sges)?;
+        Ok(total_len)
+    }
+
+    /// Probe the link MTU to the destination of `dqpn` if it's due, see `PathMtuTable::probe`. The qp
+    /// table is only locked to look up the destination.
+    fn probe_path_mtu(&self, dqpn: Qpn) -> Result<(), Error> {
+        if !self.0.path_mtus.is_probing() {
+            return Ok(());
+        }
+        let dest = self
+            .0
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?
+            .get(&dqpn)
+            .map(|qp| qp.dqp_ip);
+        dest.map_or(Ok(()), |dqp_ip| self.0.path_mtus.probe(dqp_ip))
+    }
+
     /// RDMA read operation
     /// 
     /// # Errors
     ///
     /// Will return `Err` if:
+    /// * the device is shut down
+    /// * the sge exceeds the MR of its key
+    /// * the qp is not found or not in `QpState::Rts`
+    /// * the qp is neither `QpType::Rc` nor `QpType::Uc`
     /// * lock poisoned
     /// * failed to create a read descriptor
     /// * failed to send a read descriptor
@@ -418,82 +854,427 @@ impl Device {
         flags: MemAccessTypeFlag,
         sge: Sge,
     ) -> Result<ReadOpCtx, Error> {
-        let msn = self.get_msn();
-        let common = {
-            let total_len = sge.len;
+        self.check_open()?;
+        self.check_sge_bounds(&[sge])?;
+        self.probe_path_mtu(dqpn)?;
+        let total_len = sge.len;
+        let ctx = ReadOpCtx::new_running();
+        let (common, ack_timeout) = {
             let qp_guard = self.0.qp_table.read().map_err(|_| Error::LockPoisoned("qp table lock"))?;
             let qp = qp_guard.get(&dqpn).ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?;
-            let mut common = ToCardWorkRbDescCommon {
+            qp.check_one_sided_op("RDMA read")?;
+            if qp.state != QpState::Rts {
+                return Err(Error::Invalid(format!("{dqpn:?} in state {:?}", qp.state)));
+            }
+            let pmtu = self.0.path_mtus.clamp(qp.dqp_ip, qp.pmtu)?;
+            let mut send_psn = qp.sending_psn.lock().map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
+            let common = ToCardWorkRbDescCommon {
                 total_len,
                 raddr,
                 rkey,
                 dqp_ip: qp.dqp_ip,
                 dqpn: qp.qpn,
                 mac_addr: qp.dqp_mac_addr,
-                pmtu: qp.pmtu,
+                pmtu,
                 flags,
                 qp_type: qp.qp_type,
-                psn: Psn::default(),
-                msn,
-            };
-            let first_pkt_psn = {
-                let mut send_psn = qp.sending_psn.lock().map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
-                let first_pkt_psn = *send_psn;
-                *send_psn = send_psn.wrapping_add(1);
-                first_pkt_psn
+                psn: *send_psn,
+                msn: qp.next_msn(),
             };
-            common.psn = first_pkt_psn;
-            common
+            let desc = ToCardWorkRbDescBuilder::new_read()
+                .with_common(common.clone())
+                .with_sge(sge)
+                .build()?;
+            enter_span!(
+                "submit",
+                qpn = common.dqpn.get(),
+                opcode = ?desc.opcode(),
+                psn = common.psn.get(),
+                msn = common.msn.get(),
+                byte_len = total_len
+            );
+            ctx.mark_submitted();
+            // the psn is taken only once the request is pushed, so a failed push leaves no hole
+            self.0.adaptor.to_card_work_rb().push(desc).map_err(push_error)?;
+            *send_psn = send_psn.wrapping_add(1);
+            (common, qp.ack_timeout)
         };
+        let msn = common.msn;
 
-        let desc = ToCardWorkRbDescBuilder::new_read()
-            .with_common(common)
-            .with_sge(sge)
-            .build()?;
-        self.send_work_desc(desc)?;
-
-        let ctx = WriteOpCtx::new_running();
+        ctx.reclaim_on_cancel(&self.0.read_op_ctx_map, (dqpn, msn), PendingOp::ctx)?;
+        let op = PendingOp::new(ctx.clone(), total_len).with_timeout(ack_timeout);
+        let deadline = op.deadline();
         self.0
             .read_op_ctx_map
             .write()
             .map_err(|_| Error::LockPoisoned("read_op_ctx_map lock"))?
-            .insert(msn, ctx.clone()).map_or_else(||Ok(()),|_|Err(Error::CreateOpCtxFailed))?;
+            .insert((dqpn, msn), op).map_or_else(||Ok(()),|_|Err(Error::CreateOpCtxFailed))?;
+        self.0.read_deadlines.insert((dqpn, msn), deadline)?;
 
         Ok(ctx)
     }
 
-    fn do_ctrl_op(&self, id: u32, desc: ToCardCtrlRbDesc) -> Result<CtrlOpCtx, Error> {
-        // save operation context for unparking
-        let ctrl_ctx = {
-            let mut ctx = self.0.ctrl_op_ctx_map.write().map_err(|_| Error::LockPoisoned("ctrl_op_ctx_map lock"))?;
-            let ctrl_ctx = CtrlOpCtx::new_running();
-
-            if ctx.insert(id, ctrl_ctx.clone()).is_some() {
-                return Err(Error::CreateOpCtxFailed);
+    /// UD send operation, which sends the payload of `sge` from the qp `qpn` to `dest_ip` in a single
+    /// packet
+    ///
+    /// `dest_ip` is a device, or a multicast group which the payload is delivered to every member of, see
+    /// `Device::attach_mcast`. The payload fills the next receive buffer of the qp `qpn` on the
+    /// receiving device. A unicast send goes to the mac of the qp's destination. A UD send is never
+    /// acknowledged, so the returned context completes once the send is pushed to the card.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the device is shut down
+    /// * the sge exceeds the MR of its key
+    /// * the qp is not found or not in `QpState::Rts`
+    /// * the qp is not a `QpType::Ud` qp
+    /// * the payload does not fit in a single packet of the pmtu of the qp
+    /// * lock poisoned
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    pub fn send_ud(&self, qpn: Qpn, dest_ip: Ipv4Addr, sge: Sge) -> Result<WriteOpCtx, Error> {
+        self.check_open()?;
+        self.check_sge_bounds(&[sge])?;
+        let total_len = sge.len;
+        let ctx = WriteOpCtx::new_running();
+        {
+            let qp_guard = self.0.qp_table.read().map_err(|_| Error::LockPoisoned("qp table lock"))?;
+            let qp = qp_guard.get(&qpn).ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            if !matches!(qp.qp_type, QpType::Ud) {
+                return Err(Error::InvalidQpTypeForOp {
+                    qpn,
+                    qp_type: qp.qp_type,
+                    op: "UD send",
+                });
+            }
+            if qp.state != QpState::Rts {
+                return Err(Error::Invalid(format!("{qpn:?} in state {:?}", qp.state)));
             }
+            let pmtu = self.0.path_mtus.clamp(dest_ip, qp.pmtu)?;
+            if total_len > u32::from(&pmtu) {
+                return Err(Error::Invalid(format!(
+                    "UD send of {total_len} bytes over {pmtu:?}"
+                )));
+            }
+            let mac_addr = if dest_ip.is_multicast() {
+                multicast_mac(dest_ip)
+            } else {
+                qp.dqp_mac_addr
+            };
+            let mut send_psn = qp.sending_psn.lock().map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
+            let common = ToCardWorkRbDescCommon {
+                total_len,
+                raddr: 0,
+                rkey: Key::default(),
+                dqp_ip: dest_ip,
+                dqpn: qp.qpn,
+                mac_addr,
+                pmtu,
+                flags: MemAccessTypeFlag::IbvAccessNoFlags,
+                qp_type: qp.qp_type,
+                psn: *send_psn,
+                msn: qp.next_msn(),
+            };
+            let desc = ToCardWorkRbDescBuilder::new_write()
+                .with_common(common)
+                .with_sge(sge)
+                .build()?;
+            enter_span!(
+                "submit",
+                qpn = qpn.get(),
+                opcode = ?desc.opcode(),
+                psn = send_psn.get(),
+                byte_len = total_len
+            );
+            ctx.mark_submitted();
+            self.0.adaptor.to_card_work_rb().push(desc).map_err(push_error)?;
+            *send_psn = send_psn.wrapping_add(1);
+        }
+        ctx.set_result(OpResult {
+            byte_len: total_len,
+            opcode: ToHostWorkRbDescOpcode::SendOnly,
+            status: CompletionStatus::Success,
+        })?;
+        Ok(ctx)
+    }
 
-            ctrl_ctx
-        };
+    /// Wait for the writes and reads of all QPs that are waiting for their responses, e.g. before dropping
+    /// the device.
+    ///
+    /// The operations issued after it is called are not waited for.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * some operations are still running after `timeout`
+    pub fn drain(&self, timeout: Duration) -> Result<(), Error> {
+        self.drain_ops(None, timeout)
+    }
 
-        // send desc to device
+    /// Wait for the writes and reads of `qpn` that are waiting for their responses.
+    ///
+    /// The operations issued after it is called are not waited for.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * some operations are still running after `timeout`
+    pub fn drain_qp(&self, qpn: Qpn, timeout: Duration) -> Result<(), Error> {
+        self.drain_ops(Some(qpn), timeout)
+    }
+
+    /// Wait for the pending operations of `qpn`, or of all QPs if it is `None`.
+    ///
+    /// All the waiters of a context are notified, so the users waiting on them are still woken up.
+    fn drain_ops(&self, qpn: Option<Qpn>, timeout: Duration) -> Result<(), Error> {
+        let mut ctxs = Vec::new();
+        for (map, lock_name) in [
+            (&self.0.write_op_ctx_map, "write_op_ctx_map lock"),
+            (&self.0.read_op_ctx_map, "read_op_ctx_map lock"),
+        ] {
+            let guard = map.read().map_err(|_| Error::LockPoisoned(lock_name))?;
+            ctxs.extend(
+                guard
+                    .iter()
+                    .filter(|((op_qpn, _), _)| qpn.is_none() || qpn == Some(*op_qpn))
+                    .map(|(_, op)| op.ctx().clone()),
+            );
+        }
+        let deadline = Instant::now().checked_add(timeout);
+        let mut statuses = Vec::with_capacity(ctxs.len());
+        for ctx in ctxs {
+            let remaining = deadline.map_or(timeout, |at| at.saturating_duration_since(Instant::now()));
+            statuses.push(ctx.wait_timeout(remaining)?);
+        }
+        let running = statuses
+            .iter()
+            .filter(|status| matches!(status, CtxStatus::TimedOut))
+            .count();
+        if running > 0 {
+            return Err(Error::DrainTimedOut(running));
+        }
+        Ok(())
+    }
+
+    /// Stop the threads of the device and wait for them to exit, instead of relying on the drop order.
+    ///
+    /// The threads sending to the card are stopped first, then the pollers, and the adaptor at last.
+    /// The operations issued afterwards fail with `Error::DeviceClosed`. Shutting down twice is a no-op.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a thread failed to join, naming the first one. The other threads are still
+    /// stopped.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        if self.0.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        // the responser exits once the packet checker and the work descriptor poller drop its queue
+        let results = [
+            self.0.pkt_checker_thread.get().map(PacketChecker::stop),
+            self.0.work_desc_poller.get().map(WorkDescPoller::stop),
+            self.0.responser.get().map(DescResponser::stop),
+            self.0.ctrl_desc_poller.get().map(ControlPoller::stop),
+            Some(self.0.adaptor.shutdown().map_err(|e| Error::Device(Box::new(e)))),
+        ];
+        // the global logger is never dropped, so the records buffered by it are written out here
+        log::logger().flush();
+        results.into_iter().flatten().collect()
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        if self.0.closed.load(Ordering::Acquire) {
+            return Err(Error::DeviceClosed);
+        }
+        Ok(())
+    }
+
+    /// The number of received packets of each opcode.
+    ///
+    /// Only the software device tracks the received packets, other devices return an empty map.
+    #[must_use]
+    pub fn opcode_counters(&self) -> HashMap<ToHostWorkRbDescOpcode, u64> {
+        self.0.adaptor.opcode_counters()
+    }
+
+    /// The resources and the features of the device, which can be used to size the resource pools of
+    /// the application.
+    #[must_use]
+    pub fn query_caps(&self) -> DeviceCaps {
+        let page_sizes = (0..u32::BITS)
+            .map(|shift| 1_u32 << shift)
+            .filter(|pg_size| self.0.adaptor.is_page_size_supported(*pg_size))
+            .collect();
+        DeviceCaps {
+            max_mr: MR_TABLE_SIZE.saturating_sub(RESERVED_MR_CNT),
+            max_pgt_entries: MR_PGT_SIZE,
+            max_qp: QP_MAX_CNT.saturating_sub(QP_RESERVED_CNT),
+            pmtus: vec![Pmtu::Mtu256, Pmtu::Mtu512, Pmtu::Mtu1024, Pmtu::Mtu2048, Pmtu::Mtu4096],
+            page_sizes,
+            atomics: false,
+            ud: false,
+        }
+    }
+
+    /// Mark all the packets sent afterwards with the 6 bits `dscp` and the `ecn` codepoint in the IP header.
+    ///
+    /// Only the software device supports it. The ICRC is not affected since it masks the field.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `dscp` is greater than 63, or the adaptor does not support it.
+    pub fn set_traffic_class(&self, dscp: u8, ecn: EcnCodepoint) -> Result<(), Error> {
+        if dscp > MAX_DSCP {
+            return Err(Error::Invalid(format!("dscp {dscp}")));
+        }
         self.0
             .adaptor
-            .to_card_ctrl_rb()
-            .push(desc)
-            .map_err(|_| Error::DeviceBusy)?;
+            .set_traffic_class(dscp, ecn)
+            .map_err(|e| Error::Device(Box::new(e)))
+    }
 
-        Ok(ctrl_ctx)
+    /// Fill the UDP checksum of all the packets sent afterwards with `mode`, `UdpChecksumMode::Zero`
+    /// by default. Computing it helps the peers that reject the zero checksum.
+    ///
+    /// Only the software device over UDP supports it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the adaptor does not support it or the `mode`.
+    pub fn set_udp_checksum_mode(&self, mode: UdpChecksumMode) -> Result<(), Error> {
+        self.0
+            .adaptor
+            .set_udp_checksum_mode(mode)
+            .map_err(|e| Error::Device(Box::new(e)))
     }
 
-    fn get_ctrl_op_id(&self) -> u32 {
-        self.0.next_ctrl_op_id.fetch_add(1, Ordering::AcqRel)
+    /// Handle the read requests received afterwards whose source and sink overlap with `policy`,
+    /// `ReadOverlapPolicy::Memmove` by default.
+    ///
+    /// Only the software device supports it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the adaptor does not support it.
+    pub fn set_read_overlap_policy(&self, policy: ReadOverlapPolicy) -> Result<(), Error> {
+        self.0
+            .adaptor
+            .set_read_overlap_policy(policy)
+            .map_err(|e| Error::Device(Box::new(e)))
     }
 
-    fn get_msn(&self) -> Msn {
-        Msn::new(self.0.next_msn.fetch_add(1, Ordering::AcqRel))
+    /// Split all the packets sent afterwards which are larger than `link_mtu` into IP fragments, or
+    /// never split them if it's `None`, which is the default. It lets a pmtu larger than the link
+    /// MTU reach the peers reassembling the fragments.
+    ///
+    /// Only the software device over UDP supports it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `link_mtu` is below the minimum of IPv4, 68 bytes, or the adaptor does
+    /// not support it.
+    pub fn set_link_mtu(&self, link_mtu: Option<usize>) -> Result<(), Error> {
+        self.0
+            .adaptor
+            .set_link_mtu(link_mtu)
+            .map_err(|e| Error::Device(Box::new(e)))
     }
 
-    fn init(&self) -> Result<(), Error> {
+    /// Send all the packets afterwards as Ethernet frames tagged with the VLAN id `vid` and the
+    /// priority `pcp`, through the network interface `ifname`.
+    ///
+    /// The frames bypass the routing of the kernel, so `next_hop_mac` should be the MAC of the
+    /// peer or the gateway. The source MAC is the one of the device. Only the software device
+    /// supports it, and it needs `CAP_NET_RAW` to open the `AF_PACKET` socket. Without calling it,
+    /// the packets are sent untagged as before.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `vid` or `pcp` is out of range, the socket failed to open, or the adaptor
+    /// does not support it.
+    pub fn set_vlan(
+        &self,
+        ifname: &str,
+        vid: u16,
+        pcp: u8,
+        next_hop_mac: MacAddress,
+    ) -> Result<(), Error> {
+        if vid > MAX_VLAN_ID {
+            return Err(Error::Invalid(format!("vlan id {vid}")));
+        }
+        if pcp > MAX_VLAN_PCP {
+            return Err(Error::Invalid(format!("vlan pcp {pcp}")));
+        }
+        self.0
+            .adaptor
+            .set_vlan(ifname, vid, pcp, self.0.local_network.macaddr, next_hop_mac)
+            .map_err(|e| Error::Device(Box::new(e)))
+    }
+
+    /// Set the link MTU of the path to `dest`, which replaces the probed one.
+    ///
+    /// The packets to `dest` are segmented by the largest pmtu up to the one of their QP which fits
+    /// in `mtu` with the headers, and a warning is logged when the pmtu of a QP is downgraded. The
+    /// responder reassembles the messages by the same pmtu, so the path MTU should be set on both
+    /// sides.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if lock poisoned.
+    pub fn set_path_mtu(&self, dest: Ipv4Addr, mtu: u16) -> Result<(), Error> {
+        self.0.path_mtus.set(dest, mtu)
+    }
+
+    fn do_ctrl_op(&self, id: u32, desc: ToCardCtrlRbDesc) -> Result<IssuedCtrlOp, Error> {
+        self.check_open()?;
+        let summary = desc.to_string();
+        // save operation context for unparking
+        let ctrl_ctx = {
+            let mut ctx = self.0.ctrl_op_ctx_map.write().map_err(|_| Error::LockPoisoned("ctrl_op_ctx_map lock"))?;
+            let ctrl_ctx = CtrlOpCtx::new_running();
+            ctrl_ctx.reclaim_on_cancel(&self.0.ctrl_op_ctx_map, id, |op| op)?;
+
+            if ctx.insert(id, ctrl_ctx.clone()).is_some() {
+                return Err(Error::CreateOpCtxFailed);
+            }
+
+            ctrl_ctx
+        };
+
+        // send desc to device
+        if let Err(e) = self.0.adaptor.to_card_ctrl_rb().push(desc) {
+            // nobody will answer the operation, drop its context
+            let _: Option<CtrlOpCtx> = self
+                .0
+                .ctrl_op_ctx_map
+                .write()
+                .map_err(|_| Error::LockPoisoned("ctrl_op_ctx_map lock"))?
+                .remove(&id);
+            if let DeviceError::RingFull(ring) = e {
+                return Err(Error::RingFull(ring));
+            }
+            return Err(Error::CtrlOpFailed {
+                op_id: id,
+                desc: summary,
+                source: e,
+            });
+        }
+
+        Ok(IssuedCtrlOp {
+            op_id: id,
+            desc: summary,
+            ctx: ctrl_ctx,
+        })
+    }
+
+    fn get_ctrl_op_id(&self) -> u32 {
+        self.0.next_ctrl_op_id.fetch_add(1, Ordering::AcqRel)
+    }
+
+    fn init(&self, ack_buf_size: usize) -> Result<(), Error> {
         let (send_queue, rece_queue) = std::sync::mpsc::channel();
         let recv_pkt_map = Arc::new(RwLock::new(HashMap::new()));
         debug!("==============1");
@@ -506,23 +1287,24 @@ impl Device {
         self.0.ctrl_desc_poller.set(ctrl_desc_poller).map_err(|_|Error::DoubleInit("ctrl_desc_poller has been set".to_owned()))?;
         debug!("==============2");
         // enable responser module
-        let ack_buf = self.init_ack_buf()?;
+        let ack_buf = self.init_ack_buf(ack_buf_size)?;
         debug!("==============2-1");
         let responser = DescResponser::new(
             Arc::new(self.clone()),
             rece_queue,
-            ack_buf,
             Arc::<RwLock<HashMap<Qpn, QpContext>>>::clone(&self.0.qp_table),
+            Arc::<PathMtuTable>::clone(&self.0.path_mtus),
         );
         self.0.responser.set(responser).map_err(|_|Error::DoubleInit("responser has been set".to_owned()))?;
         debug!("==============3");
         // enable work desc poller module.
         let work_desc_poller_ctx = WorkDescPollerContext{
             work_rb : self.0.adaptor.to_host_work_rb(),
-            recv_pkt_map : Arc::<RwLock<HashMap<Msn, Arc<Mutex<RecvPktMap>>>>>::clone(&recv_pkt_map),
+            recv_pkt_map : Arc::<RwLock<RecvPktMaps>>::clone(&recv_pkt_map),
             qp_table : Arc::<RwLock<HashMap<Qpn, QpContext>>>::clone(&self.0.qp_table),
+            path_mtus : Arc::<PathMtuTable>::clone(&self.0.path_mtus),
             sending_queue : send_queue.clone(),
-            write_op_ctx_map : Arc::<RwLock<HashMap<Msn, WriteOpCtx>>>::clone(&self.0.write_op_ctx_map),
+            write_op_ctx_map : Arc::<RwLock<PendingOpMap>>::clone(&self.0.write_op_ctx_map),
         };
         let work_desc_poller = WorkDescPoller::new(work_desc_poller_ctx);
         self.0.work_desc_poller.set(work_desc_poller).map_err(|_|Error::DoubleInit("work descriptor poller has been set".to_owned()))?;
@@ -530,8 +1312,12 @@ impl Device {
         // enable packet checker module
         let pkt_checker_thread = PacketChecker::new(
             send_queue,
+            ack_buf,
             recv_pkt_map,
-            Arc::<RwLock<HashMap<Msn, ReadOpCtx>>>::clone(&self.0.read_op_ctx_map),
+            Arc::<RwLock<PendingOpMap>>::clone(&self.0.read_op_ctx_map),
+            Arc::<RwLock<PendingOpMap>>::clone(&self.0.write_op_ctx_map),
+            Arc::<OpDeadlines>::clone(&self.0.read_deadlines),
+            Arc::<OpDeadlines>::clone(&self.0.write_deadlines),
         );
         self.0.pkt_checker_thread.set(pkt_checker_thread).map_err(|_|Error::DoubleInit("packet checker has been set".to_owned()))?;
         debug!("==============5");
@@ -550,12 +1336,7 @@ impl Device {
             ipaddr: network.ipaddr,
             macaddr: network.macaddr,
         });
-        let ctx = self.do_ctrl_op(op_id, desc)?;
-        let is_success = ctx.wait_result()?.ok_or_else(||Error::SetCtxResultFailed)?;
-        if !is_success {
-            return Err(Error::DeviceReturnFailed("Network param"));
-        };
-        Ok(())
+        self.do_ctrl_op(op_id, desc)?.wait_success("Network param")
     }
 }
 
@@ -576,11 +1357,2639 @@ pub(crate) trait WorkDescriptorSender: Send + Sync {
 
 impl WorkDescriptorSender for Device {
     fn send_work_desc(&self, desc: ToCardWorkRbDesc) -> Result<(), Error> {
+        self.check_open()?;
+        // The descriptor is segmented by its pmtu, so a different pmtu from the QP corrupts the packets
+        let common = desc.common();
+        if let Some(qp) = self
+            .0
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?
+            .get(&common.dqpn)
+        {
+            let expected = self.0.path_mtus.clamp(qp.dqp_ip, qp.pmtu)?;
+            if expected != common.pmtu {
+                return Err(Error::PmtuMismatch {
+                    qpn: common.dqpn,
+                    expected,
+                    actual: common.pmtu,
+                });
+            }
+        }
         self.0
             .adaptor
             .to_card_work_rb()
             .push(desc)
-            .map_err(|_| Error::DeviceBusy)?;
+            .map_err(push_error)?;
         Ok(())
     }
 }
+
+/// Build the write descriptors of the message of `common`, whose payload is split into `sge_lists`
+/// by `split_sge_list`.
+///
+/// The first descriptor carries the length of the whole message, the others carry their own length.
+/// Except for the last one, every descriptor ends at a pmtu boundary, so the psn of a descriptor is
+/// the psn of the message plus the packet count before it.
+fn build_write_descs(
+    common: &ToCardWorkRbDescCommon,
+    sge_lists: &[Vec<Sge>],
+    staged: Option<&StagedPayload>,
+) -> Result<Vec<ToCardWorkRbDesc>, Error> {
+    let mut descs = Vec::with_capacity(sge_lists.len());
+    let last_idx = sge_lists.len().wrapping_sub(1);
+    let mut offset = 0_u32;
+    for (idx, sge_list) in sge_lists.iter().enumerate() {
+        // the length of a part is less than `total_len`, which does not overflow
+        let len = sge_list.iter().fold(0_u32, |acc, sge| acc.wrapping_add(sge.len));
+        let mut desc_common = common.clone();
+        if idx != 0 {
+            desc_common.total_len = len;
+            desc_common.raddr = common.raddr.wrapping_add(u64::from(offset));
+            desc_common.psn = common
+                .psn
+                .wrapping_add(calculate_packet_cnt(common.pmtu, common.raddr, offset));
+        }
+        let mut builder = sge_list
+            .iter()
+            .copied()
+            .fold(
+                ToCardWorkRbDescBuilder::new_write().with_common(desc_common),
+                ToCardWorkRbDescBuilder::with_sge,
+            )
+            .with_position(idx == 0, idx == last_idx);
+        if let Some(staged) = staged {
+            builder = builder.with_staged(Arc::clone(staged));
+        }
+        descs.push(builder.build()?);
+        offset = offset.wrapping_add(len);
+    }
+    Ok(descs)
+}
+
+/// The mac a multicast group is sent to, which carries the low 23 bits of the group address
+fn multicast_mac(group: Ipv4Addr) -> MacAddress {
+    let [_, second, third, fourth] = group.octets();
+    MacAddress::new([0x01, 0x00, 0x5e, second & 0x7f, third, fourth])
+}
+
+/// Tell a full ring buffer apart from the other failures of pushing a descriptor to the card
+fn push_error(err: DeviceError) -> Error {
+    match err {
+        DeviceError::RingFull(ring) => Error::RingFull(ring),
+        DeviceError::Overflow | DeviceError::SchedulerFull | DeviceError::AckBufferFull => {
+            Error::DeviceBusy
+        }
+        DeviceError::Device(_)
+        | DeviceError::LockPoisoned(_)
+        | DeviceError::Scheduler(_)
+        | DeviceError::ParseDesc(_) => Error::Device(Box::new(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{alloc_zeroed, dealloc, Layout},
+        collections::LinkedList,
+        net::{Ipv4Addr, SocketAddrV4},
+        slice::from_raw_parts,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use eui48::MacAddress;
+    use serial_test::serial;
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use crate::{
+        device::{
+            scheduler::{round_robin::RoundRobinStrategy, DescriptorScheduler},
+            software::{SoftwareDevice, NET_SERVER_BUF_SIZE},
+            DeviceAdaptor,
+            PhysAddrResolver, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDescBuilder,
+            ToCardWorkRbDescCommon, ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc,
+            ToHostWorkRbDescOpcode,
+        },
+        mr::ACKNOWLEDGE_BUFFER_SIZE,
+        op_ctx::{CompletionStatus, CtxStatus},
+        responser::AcknowledgeBuffer,
+        types::{
+            Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
+            RdmaDeviceNetworkParamBuilder, Sge, WriteRequest, PAGE_SIZE,
+        },
+        utils::calculate_packet_cnt,
+        AlignedMemory, Device, HugePage, DeviceBuilder, DeviceError, Error, Mr, Pd, RegMrRequest, SchedulerStrategy, ToCardWorkRbDesc,
+        FaultInjector, IcrcConfig, MemoryFabric, Transport, WorkDescriptorSender,
+    };
+    #[cfg(feature = "scheduler")]
+    use crate::device::scheduler::{forward_to_card, ToCardWorkRing};
+
+    /// Resolve every address to the virtual address plus a fixed offset
+    #[derive(Debug)]
+    struct OffsetResolver(usize);
+
+    impl PhysAddrResolver for OffsetResolver {
+        fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
+            Ok(virt_addr.wrapping_add(self.0))
+        }
+    }
+
+    /// Count the pushed descriptors and schedule them in round robin
+    #[derive(Debug, Default)]
+    struct CountingStrategy {
+        inner: RoundRobinStrategy,
+        pushed: Mutex<usize>,
+        pushed_cond: Condvar,
+        /// The QP, length and position of every pushed descriptor, read by the public accessors
+        seen: Mutex<Vec<(Qpn, u32, bool, bool)>>,
+    }
+
+    impl CountingStrategy {
+        /// Wait up to `timeout` for `cnt` descriptors to be pushed, and return the number pushed
+        fn wait_pushed(&self, cnt: usize, timeout: Duration) -> usize {
+            let pushed = self.pushed.lock().unwrap();
+            let (pushed, _) = self
+                .pushed_cond
+                .wait_timeout_while(pushed, timeout, |pushed| *pushed < cnt)
+                .unwrap();
+            *pushed
+        }
+    }
+
+    impl SchedulerStrategy for CountingStrategy {
+        fn push(&self, qpn: Qpn, desc: LinkedList<ToCardWorkRbDesc>) -> Result<(), DeviceError> {
+            self.seen
+                .lock()
+                .unwrap()
+                .extend(desc.iter().map(|d| (d.dqpn(), d.total_len(), d.is_first(), d.is_last())));
+            *self.pushed.lock().unwrap() += desc.len();
+            self.pushed_cond.notify_all();
+            self.inner.push(qpn, desc)
+        }
+
+        fn pop(&self) -> Result<Option<ToCardWorkRbDesc>, DeviceError> {
+            self.inner.pop()
+        }
+    }
+
+    /// A to-card ring buffer which is full while `full` is set
+    struct SaturatedRb<D> {
+        inner: Arc<dyn ToCardRb<D>>,
+        full: Arc<AtomicBool>,
+        name: &'static str,
+    }
+
+    impl<D> ToCardRb<D> for SaturatedRb<D> {
+        fn push(&self, desc: D) -> Result<(), DeviceError> {
+            if self.full.load(Ordering::Acquire) {
+                return Err(DeviceError::RingFull(self.name.to_owned()));
+            }
+            self.inner.push(desc)
+        }
+    }
+
+    /// The work ring of a card fed by `forward_to_card`, which is full while `full` is set
+    #[cfg(feature = "scheduler")]
+    struct SaturatedCardRing {
+        inner: Arc<SoftwareDevice>,
+        full: Arc<AtomicBool>,
+        /// Notified whenever a descriptor is rejected for the full ring
+        rejected: crossbeam_channel::Sender<()>,
+    }
+
+    #[cfg(feature = "scheduler")]
+    impl ToCardWorkRing for SaturatedCardRing {
+        fn push(&mut self, desc: &ToCardWorkRbDesc) -> Result<usize, DeviceError> {
+            if self.full.load(Ordering::Acquire) {
+                let _: Result<(), _> = self.rejected.send(());
+                return Err(DeviceError::RingFull("work".to_owned()));
+            }
+            self.inner.to_card_work_rb().push(desc.clone())?;
+            Ok(0)
+        }
+
+        fn is_consumed(&mut self, _pos: usize) -> Result<bool, DeviceError> {
+            Ok(true)
+        }
+    }
+
+    /// A software device whose to-card ring buffers can be saturated
+    #[derive(Debug)]
+    struct SaturatedAdaptor {
+        inner: Arc<SoftwareDevice>,
+        full: Arc<AtomicBool>,
+        /// The scheduler passing the work descriptors to a card ring, instead of the work ring of
+        /// `inner`
+        card: Option<Arc<DescriptorScheduler>>,
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    impl DeviceAdaptor for SaturatedAdaptor {
+        fn to_card_ctrl_rb(&self) -> Arc<dyn ToCardRb<ToCardCtrlRbDesc>> {
+            Arc::new(SaturatedRb {
+                inner: self.inner.to_card_ctrl_rb(),
+                full: Arc::clone(&self.full),
+                name: "ctrl",
+            })
+        }
+
+        fn to_host_ctrl_rb(&self) -> Arc<dyn ToHostRb<ToHostCtrlRbDesc>> {
+            self.inner.to_host_ctrl_rb()
+        }
+
+        fn to_card_work_rb(&self) -> Arc<dyn ToCardRb<ToCardWorkRbDesc>> {
+            if let Some(card) = &self.card {
+                return Arc::<DescriptorScheduler>::clone(card);
+            }
+            Arc::new(SaturatedRb {
+                inner: self.inner.to_card_work_rb(),
+                full: Arc::clone(&self.full),
+                name: "work",
+            })
+        }
+
+        fn to_host_work_rb(&self) -> Arc<dyn ToHostRb<ToHostWorkRbDesc>> {
+            self.inner.to_host_work_rb()
+        }
+
+        fn read_csr(&self, addr: usize) -> Result<u32, DeviceError> {
+            self.inner.read_csr(addr)
+        }
+
+        fn write_csr(&self, addr: usize, data: u32) -> Result<(), DeviceError> {
+            self.inner.write_csr(addr, data)
+        }
+
+        fn requires_hugepages(&self) -> bool {
+            self.inner.requires_hugepages()
+        }
+    }
+
+    impl PhysAddrResolver for SaturatedAdaptor {
+        fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
+            self.inner.get_phys_addr(virt_addr)
+        }
+    }
+
+    /// A to-host work ring buffer which reports `cut` bytes less for every read response packet
+    struct ShortReadRb {
+        inner: Arc<dyn ToHostRb<ToHostWorkRbDesc>>,
+        cut: Arc<AtomicU32>,
+    }
+
+    impl ToHostRb<ToHostWorkRbDesc> for ShortReadRb {
+        fn pop(&self, timeout: Duration) -> Result<Option<ToHostWorkRbDesc>, DeviceError> {
+            let mut desc = self.inner.pop(timeout)?;
+            if let Some(ToHostWorkRbDesc::WriteOrReadResp(resp)) = &mut desc {
+                if resp.is_read_resp {
+                    resp.len = resp.len.saturating_sub(self.cut.load(Ordering::Acquire));
+                }
+            }
+            Ok(desc)
+        }
+    }
+
+    /// A software device whose read responses carry less data than reported by the card
+    #[derive(Debug)]
+    struct ShortReadAdaptor {
+        inner: Arc<SoftwareDevice>,
+        cut: Arc<AtomicU32>,
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    impl DeviceAdaptor for ShortReadAdaptor {
+        fn to_card_ctrl_rb(&self) -> Arc<dyn ToCardRb<ToCardCtrlRbDesc>> {
+            self.inner.to_card_ctrl_rb()
+        }
+
+        fn to_host_ctrl_rb(&self) -> Arc<dyn ToHostRb<ToHostCtrlRbDesc>> {
+            self.inner.to_host_ctrl_rb()
+        }
+
+        fn to_card_work_rb(&self) -> Arc<dyn ToCardRb<ToCardWorkRbDesc>> {
+            self.inner.to_card_work_rb()
+        }
+
+        fn to_host_work_rb(&self) -> Arc<dyn ToHostRb<ToHostWorkRbDesc>> {
+            Arc::new(ShortReadRb {
+                inner: self.inner.to_host_work_rb(),
+                cut: Arc::clone(&self.cut),
+            })
+        }
+
+        fn read_csr(&self, addr: usize) -> Result<u32, DeviceError> {
+            self.inner.read_csr(addr)
+        }
+
+        fn write_csr(&self, addr: usize, data: u32) -> Result<(), DeviceError> {
+            self.inner.write_csr(addr, data)
+        }
+
+        fn requires_hugepages(&self) -> bool {
+            self.inner.requires_hugepages()
+        }
+    }
+
+    impl PhysAddrResolver for ShortReadAdaptor {
+        fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError> {
+            self.inner.get_phys_addr(virt_addr)
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_custom_scheduler() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 7))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let strategy = Arc::new(CountingStrategy::default());
+        let dev =
+            Device::new_software_with_scheduler(&network, Arc::<CountingStrategy>::clone(&strategy))
+                .unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Uc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 8))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let ctx = dev
+            .write(qpn, 0, Key::new(0), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        ctx.wait().unwrap();
+        // the descriptor is pushed by the scheduler thread
+        assert_eq!(strategy.wait_pushed(1, Duration::from_secs(1)), 1);
+        assert_eq!(*strategy.seen.lock().unwrap(), [(qpn, 64, true, true)]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_link_mtu() {
+        let network = |host| {
+            RdmaDeviceNetworkParamBuilder::default()
+                .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                .macaddr(MacAddress::default())
+                .build()
+                .unwrap()
+        };
+        let dev = DeviceBuilder::new(&network(71)).link_mtu(576).build().unwrap();
+        assert!(matches!(dev.set_link_mtu(Some(67)), Err(Error::Device(_))));
+        dev.set_link_mtu(None).unwrap();
+        dev.shutdown().unwrap();
+        assert!(DeviceBuilder::new(&network(71)).link_mtu(67).build().is_err());
+
+        // the loopback device hands the packets over whole
+        let dev = Device::new_software_loopback(&network(72)).unwrap();
+        assert!(dev.set_link_mtu(Some(576)).is_err());
+        dev.shutdown().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_query_caps() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 39))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let caps = dev.query_caps();
+        assert_eq!(caps.page_sizes.first(), Some(&4096));
+        assert_eq!(caps.page_sizes.last(), Some(&(PAGE_SIZE as u32)));
+        assert!(!caps.atomics && !caps.ud);
+
+        // the same memory can be registered by several MRs, until the MR table is full
+        let pd = dev.alloc_pd().unwrap();
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        let reg = || dev.reg_mr(pd, buf.as_ptr() as u64, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag);
+        let mrs: Vec<_> = std::iter::from_fn(|| reg().ok()).collect();
+        assert_eq!(mrs.len(), caps.max_mr);
+        assert!(matches!(reg(), Err(Error::ResourceNoAvailable(_))));
+        for mr in mrs {
+            dev.dereg_mr(mr).unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_reg_mr_batch() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 40))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let buf = AlignedMemory::new(2 * PAGE_SIZE).unwrap();
+        let addr = buf.as_ptr() as u64;
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        let requests = [
+            RegMrRequest::new(pd, addr, 2 * PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag),
+            RegMrRequest::new(pd, addr, 3 * 4096, 4096, access_flag).with_iova(0),
+            RegMrRequest::new(pd, addr + 4096, 4096, 4096, access_flag),
+        ];
+        // the index, iova, len and page table entries of the registered MRs
+        let entry_of = |mr: &Mr| {
+            let mr_table = dev.0.mr_table.lock().unwrap();
+            let ctx = mr_table.iter().flatten().find(|ctx| ctx.key == mr.get_key()).unwrap();
+            let pgte_cnt = ctx.len.div_ceil(ctx.pg_size) as usize;
+            let pgt = dev.0.mr_pgt.lock().unwrap().entries(ctx.pgt_offset, pgte_cnt).to_vec();
+            (mr.get_key().index(), ctx.iova, ctx.len, pgt)
+        };
+
+        let mrs: Vec<_> = requests
+            .iter()
+            .map(|request| dev.reg_mr_batch(&[*request]).unwrap().pop().unwrap().unwrap())
+            .collect();
+        let expected: Vec<_> = mrs.iter().map(entry_of).collect();
+        for mr in mrs {
+            dev.dereg_mr(mr).unwrap();
+        }
+
+        let mrs: Vec<_> = dev
+            .reg_mr_batch(&requests)
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(mrs.iter().map(entry_of).collect::<Vec<_>>(), expected);
+        for mr in mrs {
+            dev.dereg_mr(mr).unwrap();
+        }
+
+        // a failed registration does not affect the others
+        let invalid = RegMrRequest::new(pd, addr, 4096, 3000, access_flag);
+        let results = dev.reg_mr_batch(&[requests[0], invalid, requests[2]]).unwrap();
+        assert!(matches!(
+            results.as_slice(),
+            [Ok(_), Err(Error::Invalid(_)), Ok(_)]
+        ));
+        for mr in results.into_iter().flatten() {
+            dev.dereg_mr(mr).unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_mr_key_index() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 59))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software_loopback(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        let reg = || {
+            dev.reg_mr(pd, buf.as_ptr() as u64, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+                .unwrap()
+        };
+        // the key of a MR points at its slot of the MR table
+        let slot_key = |key: Key| {
+            let mr_table = dev.0.mr_table.lock().unwrap();
+            mr_table[key.index() as usize].as_ref().map(|ctx| ctx.key)
+        };
+        let mut mrs: Vec<_> = (0..4).map(|_| reg()).collect();
+        for mr in &mrs {
+            assert_eq!(slot_key(mr.get_key()), Some(mr.get_key()));
+        }
+
+        // `dereg_mr` frees the slot `reg_mr` put the MR in, which is taken by the next MR
+        let mr = mrs.remove(1);
+        let key = mr.get_key();
+        dev.dereg_mr(mr).unwrap();
+        assert_eq!(slot_key(key), None);
+        let mr = reg();
+        assert_eq!(mr.get_key().index(), key.index());
+        assert_eq!(slot_key(mr.get_key()), Some(mr.get_key()));
+        mrs.push(mr);
+
+        for mr in mrs {
+            let key = mr.get_key();
+            dev.dereg_mr(mr).unwrap();
+            assert_eq!(slot_key(key), None);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_mr_key_secret() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 60))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software_loopback(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        let (mr, buf) = dev.alloc_and_reg_mr(pd, 4096, access_flag).unwrap();
+        let key = mr.get_key();
+        // the right index with a wrong secret, like a stale key of a reused slot
+        let forged = Key::from_index_secret(key.index(), key.secret() ^ 1);
+
+        let sge = Sge::new(buf.as_ptr() as u64, 64, forged);
+        assert!(matches!(
+            dev.write(Qpn::new(3), 0, Key::new(0), MemAccessTypeFlag::empty(), sge),
+            Err(Error::InvalidKey(k)) if k == forged
+        ));
+        assert!(matches!(
+            dev.dereg_mr(Mr { key: forged }),
+            Err(Error::InvalidKey(k)) if k == forged
+        ));
+        // the registered MR is intact
+        let listed = dev.list_mrs(pd).unwrap();
+        assert_eq!(listed.iter().map(Mr::get_key).collect::<Vec<_>>(), [key]);
+        dev.dereg_mr(mr).unwrap();
+        assert!(matches!(dev.dereg_mr(mr), Err(Error::InvalidKey(_))));
+        // the key of the deregistered MR points at an empty slot
+        let sge = Sge::new(buf.as_ptr() as u64, 64, key);
+        assert!(matches!(
+            dev.write(Qpn::new(3), 0, Key::new(0), MemAccessTypeFlag::empty(), sge),
+            Err(Error::InvalidKey(k)) if k == key
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_alloc_and_reg_mr() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 20))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite;
+        let (mr, buf) = dev.alloc_and_reg_mr(pd, 3 * 4096, access_flag).unwrap();
+        assert_eq!(buf.as_ptr() as usize % PAGE_SIZE, 0);
+        assert_eq!(buf.len(), 3 * 4096);
+        assert!(buf.iter().all(|b| *b == 0));
+        dev.dereg_mr(mr).unwrap();
+        drop(buf);
+
+        assert!(matches!(
+            dev.alloc_and_reg_mr(pd, 0, access_flag),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            dev.alloc_and_reg_mr(pd, 1 << 32, access_flag),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_mrs() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 44))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let other_pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        let mrs: Vec<_> = (0..3)
+            .map(|_| dev.alloc_and_reg_mr(pd, 4096, access_flag).unwrap())
+            .collect();
+        let (other_mr, _other_buf) = dev.alloc_and_reg_mr(other_pd, 4096, access_flag).unwrap();
+
+        let sorted_keys = |mrs: &mut dyn Iterator<Item = Mr>| {
+            let mut keys: Vec<_> = mrs.map(|mr| mr.get_key().get()).collect();
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(
+            sorted_keys(&mut dev.list_mrs(pd).unwrap().into_iter()),
+            sorted_keys(&mut mrs.iter().map(|(mr, _)| *mr))
+        );
+
+        for (mr, _) in &mrs[1..] {
+            dev.dereg_mr(*mr).unwrap();
+        }
+        let listed = dev.list_mrs(pd).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].get_key().get(), mrs[0].0.get_key().get());
+
+        // the listed mrs are enough to release the pd
+        for mr in listed {
+            dev.dereg_mr(mr).unwrap();
+        }
+        dev.dealloc_pd(pd).unwrap();
+        assert!(matches!(dev.list_mrs(pd), Err(Error::Invalid(_))));
+        dev.dereg_mr(other_mr).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_dealloc_pd() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 45))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let (mr, _buf) = dev
+            .alloc_and_reg_mr(pd, 4096, MemAccessTypeFlag::IbvAccessLocalWrite)
+            .unwrap();
+
+        assert!(matches!(dev.dealloc_pd(pd), Err(Error::PdInUse(_))));
+        // the failed dealloc keeps the pd usable
+        assert_eq!(dev.list_mrs(pd).unwrap().len(), 1);
+
+        dev.dereg_mr(mr).unwrap();
+        dev.dealloc_pd(pd).unwrap();
+        assert!(matches!(dev.dealloc_pd(pd), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_unaligned_phys_addr() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 29))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        dev.set_phys_addr_resolver(Arc::new(OffsetResolver(8))).unwrap();
+        assert!(dev
+            .set_phys_addr_resolver(Arc::new(OffsetResolver(0)))
+            .is_err());
+
+        let buf = AlignedMemory::new(2 * PAGE_SIZE).unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite;
+        // the page table entries of a failed registration are released, so it never runs out
+        for _ in 0..crate::MR_PGT_SIZE {
+            let result = dev.reg_mr(
+                pd,
+                buf.as_ptr() as u64,
+                buf.len() as u32,
+                PAGE_SIZE as u32,
+                access_flag,
+            );
+            assert!(
+                matches!(result, Err(Error::AddressNotAlign("pa", pa)) if pa == buf.as_ptr() as usize + 8)
+            );
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_reg_mr_small_pages() {
+        const SMALL_PAGE_SIZE: usize = 4096;
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 30))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        dev.set_phys_addr_resolver(Arc::new(OffsetResolver(SMALL_PAGE_SIZE)))
+            .unwrap();
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite;
+
+        // a buffer aligned to 4KB but not to 2MB
+        let addr = buf.as_ptr() as usize + SMALL_PAGE_SIZE;
+        let len = 3 * SMALL_PAGE_SIZE as u32 - 100;
+        let mr = dev
+            .reg_mr(pd, addr as u64, len, SMALL_PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let pgt_offset = dev
+            .0
+            .mr_table
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .find(|ctx| ctx.key.get() == mr.get_key().get())
+            .unwrap()
+            .pgt_offset;
+        let expected: Vec<u64> = (0..3)
+            .map(|i| (addr + (i + 1) * SMALL_PAGE_SIZE) as u64)
+            .collect();
+        assert_eq!(
+            dev.0.mr_pgt.lock().unwrap().entries(pgt_offset, 3),
+            expected.as_slice()
+        );
+        dev.dereg_mr(mr).unwrap();
+
+        // the buffer is not aligned to the page size
+        assert!(matches!(
+            dev.reg_mr(pd, addr as u64, len, PAGE_SIZE as u32, access_flag),
+            Err(Error::AddressNotAlign("va", _))
+        ));
+        // the page size is not a power of two or out of the supported range
+        for pg_size in [3000, 2048, 2 * PAGE_SIZE as u32] {
+            assert!(matches!(
+                dev.reg_mr(pd, addr as u64, len, pg_size, access_flag),
+                Err(Error::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_sge_out_of_bounds() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 23))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let (mr, buf) = dev.alloc_and_reg_mr(pd, 4096, access_flag).unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let addr = buf.as_ptr() as u64;
+        let overrun = Sge::new(addr + 2048, 4096, mr.get_key());
+        let underrun = Sge::new(addr - 1, 64, mr.get_key());
+        for sge in [overrun, underrun] {
+            assert!(matches!(
+                dev.write(qpn, addr, mr.get_key(), MemAccessTypeFlag::empty(), sge),
+                Err(Error::SgeOutOfBounds { .. })
+            ));
+            assert!(matches!(
+                dev.read(qpn, addr, mr.get_key(), MemAccessTypeFlag::empty(), sge),
+                Err(Error::SgeOutOfBounds { .. })
+            ));
+        }
+        let valid = Sge::new(addr + 2048, 2048, mr.get_key());
+        assert!(matches!(
+            dev.write_gather(qpn, addr, mr.get_key(), MemAccessTypeFlag::empty(), &[valid, overrun]),
+            Err(Error::SgeOutOfBounds { .. })
+        ));
+        let unregistered = Sge::new(addr, 64, Key::new(u32::MAX));
+        assert!(matches!(
+            dev.write(qpn, addr, mr.get_key(), MemAccessTypeFlag::empty(), unregistered),
+            Err(Error::InvalidKey(_))
+        ));
+        let ctx = dev
+            .write(qpn, addr, mr.get_key(), MemAccessTypeFlag::empty(), valid)
+            .unwrap();
+        ctx.wait().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_write_gather() {
+        // write to the device itself
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 6))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let buf = unsafe { alloc_zeroed(layout) };
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let mr = dev
+            .reg_mr(pd, buf as u64, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        // 10 segments of 4KB with a 4KB hole between each other
+        const SEG_LEN: usize = 4096;
+        let sges: Vec<Sge> = (0..10)
+            .map(|i| {
+                let addr = buf as u64 + (i * 2 * SEG_LEN) as u64;
+                unsafe { std::ptr::write_bytes(addr as *mut u8, i as u8 + 1, SEG_LEN); }
+                Sge::new(addr, SEG_LEN as u32, mr.get_key())
+            })
+            .collect();
+        // the remote address is not aligned to pmtu, so the segments are cut at the descriptor boundaries
+        let dst_offset = PAGE_SIZE / 2 + 100;
+        let ctx = dev
+            .write_gather(
+                qpn,
+                buf as u64 + dst_offset as u64,
+                mr.get_key(),
+                MemAccessTypeFlag::empty(),
+                &sges,
+            )
+            .unwrap();
+        ctx.wait().unwrap();
+
+        let dst_ptr = unsafe { buf.add(dst_offset) };
+        let dst = unsafe { from_raw_parts(dst_ptr, 10 * SEG_LEN) };
+        for (i, seg) in dst.chunks(SEG_LEN).enumerate() {
+            assert!(
+                seg.iter().all(|b| *b == i as u8 + 1),
+                "segment {i} is not assembled correctly"
+            );
+        }
+
+        // 300 sges of 10 bytes, more than 4 of them fall in a packet
+        const SMALL_LEN: usize = 10;
+        let src_offset = 0x30000;
+        let small_sges: Vec<Sge> = (0..300)
+            .map(|i| {
+                let addr = buf as u64 + (src_offset + i * 2 * SMALL_LEN) as u64;
+                unsafe { std::ptr::write_bytes(addr as *mut u8, i as u8, SMALL_LEN); }
+                Sge::new(addr, SMALL_LEN as u32, mr.get_key())
+            })
+            .collect();
+        let dst_offset = 0x40000 + 300;
+        let ctx = dev
+            .write_gather(
+                qpn,
+                buf as u64 + dst_offset as u64,
+                mr.get_key(),
+                MemAccessTypeFlag::empty(),
+                &small_sges,
+            )
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert_eq!(result.byte_len, 300 * SMALL_LEN as u32);
+        let dst_ptr = buf.wrapping_add(dst_offset);
+        let dst = unsafe { from_raw_parts(dst_ptr, 300 * SMALL_LEN) };
+        for (i, seg) in dst.chunks(SMALL_LEN).enumerate() {
+            assert!(seg.iter().all(|b| *b == i as u8), "small segment {i} is out of order");
+        }
+
+        let overflow = [Sge::new(buf as u64, u32::MAX, mr.get_key()), sges[0]];
+        assert!(matches!(
+            dev.write_gather(qpn, buf as u64, mr.get_key(), MemAccessTypeFlag::empty(), &overflow),
+            Err(Error::Invalid(_))
+        ));
+        unsafe {
+            dealloc(buf, layout);
+        }
+    }
+
+    /// A device of `loopback_pair`, with a PD and a MR on it
+    struct LoopbackCard {
+        dev: Device,
+        pd: Pd,
+        mr: Mr,
+        buf: AlignedMemory,
+    }
+
+    impl Drop for LoopbackCard {
+        fn drop(&mut self) {
+            // dropping a device does not stop its threads, which may still resend from the buffer
+            let _: Result<(), Error> = self.dev.shutdown();
+        }
+    }
+
+    /// The network of a test device at `127.0.0.{host}`
+    fn loopback_network(host: u8) -> RdmaDeviceNetworkParam {
+        RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap()
+    }
+
+    /// Two software loopback devices at `hosts`, connected by the RC QP 3 of `pmtu`. The device `i`
+    /// has a MR of `len` bytes, which its QP accepts the accesses of `acc_flags[i]` to.
+    fn loopback_pair(
+        hosts: [u8; 2],
+        len: usize,
+        acc_flags: [MemAccessTypeFlag; 2],
+        pmtu: Pmtu,
+    ) -> [LoopbackCard; 2] {
+        let networks = hosts.map(loopback_network);
+        [0, 1].map(|i| {
+            let remote = &networks[1 - i];
+            let dev = Device::new_software_loopback(&networks[i]).unwrap();
+            let pd = dev.alloc_pd().unwrap();
+            let (mr, buf) = dev.alloc_and_reg_mr(pd, len, acc_flags[i]).unwrap();
+            let qp = QpBuilder::default()
+                .pd(pd)
+                .qpn(Qpn::new(3))
+                .qp_type(QpType::Rc)
+                .rq_acc_flags(acc_flags[i])
+                .pmtu(pmtu)
+                .dqp_ip(remote.ipaddr)
+                .dqp_mac(remote.macaddr)
+                .build()
+                .unwrap();
+            dev.create_qp(&qp).unwrap();
+            LoopbackCard { dev, pd, mr, buf }
+        })
+    }
+
+    #[test]
+    #[serial]
+    fn test_loopback_write() {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let mut cards = loopback_pair([14, 15], PAGE_SIZE, [access_flag; 2], Pmtu::Mtu4096);
+        assert!(matches!(
+            Device::new_software_loopback(&loopback_network(14)),
+            Err(Error::Device(_))
+        ));
+
+        const SEND_LEN: usize = 8192;
+        for (i, byte) in cards[0].buf[..SEND_LEN].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let [a, b] = &cards;
+        let sge = Sge::new(a.buf.as_ptr() as u64, SEND_LEN as u32, a.mr.get_key());
+        let ctx = a
+            .dev
+            .write(qpn, b.buf.as_ptr() as u64, b.mr.get_key(), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.byte_len, SEND_LEN as u32);
+        assert_eq!(result.opcode, ToHostWorkRbDescOpcode::Acknowledge);
+        assert_eq!(b.buf[..SEND_LEN], a.buf[..SEND_LEN]);
+        // the latency covers the write and its ACK
+        let latency = ctx.latency().unwrap();
+        assert!(latency > Duration::ZERO && latency < Duration::from_secs(5), "{latency:?}");
+    }
+
+    #[test]
+    #[serial]
+    fn test_loopback_write_small_pmtu() {
+        for (pmtu, hosts) in [(Pmtu::Mtu256, [33, 34]), (Pmtu::Mtu512, [35, 36])] {
+            loopback_write_with_pmtu(pmtu, hosts);
+        }
+    }
+
+    /// Write 4KB to an unaligned remote address, which is fragmented into many packets by a small pmtu
+    fn loopback_write_with_pmtu(pmtu: Pmtu, hosts: [u8; 2]) {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let mut cards = loopback_pair(hosts, 2 * PAGE_SIZE, [access_flag; 2], pmtu);
+
+        const SEND_LEN: usize = 4096;
+        const OFFSET: usize = 100;
+        for (i, byte) in cards[0].buf[..SEND_LEN].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let [a, b] = &cards;
+        let sge = Sge::new(a.buf.as_ptr() as u64, SEND_LEN as u32, a.mr.get_key());
+        let raddr = b.buf.as_ptr() as u64 + OFFSET as u64;
+        let ctx = a
+            .dev
+            .write(qpn, raddr, b.mr.get_key(), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success, "{pmtu:?}");
+        assert_eq!(result.byte_len, SEND_LEN as u32);
+        assert_eq!(b.buf[OFFSET..OFFSET + SEND_LEN], a.buf[..SEND_LEN], "{pmtu:?}");
+        assert!(b.buf[..OFFSET].iter().all(|byte| *byte == 0));
+        assert!(b.buf[OFFSET + SEND_LEN..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    #[serial]
+    fn test_loopback_write_iova() {
+        let networks: Vec<_> = [37, 38]
+            .into_iter()
+            .map(|host| {
+                RdmaDeviceNetworkParamBuilder::default()
+                    .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                    .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                    .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                    .macaddr(MacAddress::default())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        // the sender gathers from a MR based at the 16th page, and the receiver is zero-based
+        let iovas = [16 * PAGE_SIZE as u64, 0];
+        let mut cards: Vec<_> = networks
+            .iter()
+            .zip(networks.iter().rev())
+            .zip(iovas)
+            .map(|((local, remote), iova)| {
+                let dev = Device::new_software_loopback(local).unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+                let mr = dev
+                    .reg_mr_iova(pd, buf.as_ptr() as u64, iova, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+                    .unwrap();
+                let qp = QpBuilder::default()
+                    .pd(pd)
+                    .qpn(qpn)
+                    .qp_type(QpType::Rc)
+                    .rq_acc_flags(access_flag)
+                    .pmtu(Pmtu::Mtu1024)
+                    .dqp_ip(remote.ipaddr)
+                    .dqp_mac(remote.macaddr)
+                    .build()
+                    .unwrap();
+                dev.create_qp(&qp).unwrap();
+                (dev, mr, buf)
+            })
+            .collect();
+
+        const SEND_LEN: usize = 2000;
+        const OFFSET: usize = 0x100;
+        for (i, byte) in cards[0].2[..SEND_LEN].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let (dev_a, mr_a, buf_a) = &cards[0];
+        let (_, mr_b, buf_b) = &cards[1];
+        let sge = Sge::new(iovas[0], SEND_LEN as u32, mr_a.get_key());
+        let ctx = dev_a
+            .write(qpn, OFFSET as u64, mr_b.get_key(), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert_eq!(buf_b[OFFSET..OFFSET + SEND_LEN], buf_a[..SEND_LEN]);
+        assert!(buf_b[..OFFSET].iter().all(|b| *b == 0));
+
+        // the sges are checked against the iova range
+        let sge = Sge::new(buf_a.as_ptr() as u64, SEND_LEN as u32, mr_a.get_key());
+        assert!(matches!(
+            dev_a.write(qpn, 0, mr_b.get_key(), MemAccessTypeFlag::IbvAccessNoFlags, sge),
+            Err(Error::SgeOutOfBounds { .. })
+        ));
+        // dropping a device does not stop its threads, which may still resend from the buffers
+        for (dev, _, _) in &cards {
+            dev.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_remote_access_error() {
+        // the MR of b only permits local writes
+        let acc_flags = [
+            MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite,
+            MemAccessTypeFlag::IbvAccessLocalWrite,
+        ];
+        let qpn = Qpn::new(3);
+        let mut cards = loopback_pair([21, 22], 4096, acc_flags, Pmtu::Mtu4096);
+
+        const SEND_LEN: usize = 1024;
+        cards[0].buf[..SEND_LEN].fill(0xab);
+        let [a, b] = &cards;
+        let sge = Sge::new(a.buf.as_ptr() as u64, SEND_LEN as u32, a.mr.get_key());
+        let ctx = a
+            .dev
+            .write(qpn, b.buf.as_ptr() as u64, b.mr.get_key(), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::RemoteAccessError);
+        assert!(b.buf.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    #[serial]
+    fn test_ack_timeout() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 24))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        // nothing listens on the remote address, so the writes are never acknowledged
+        let qps: Vec<_> = [(Qpn::new(5), None), (Qpn::new(6), Some(Duration::from_millis(50)))]
+            .into_iter()
+            .map(|(qpn, ack_timeout)| {
+                let qp = QpBuilder::default()
+                    .pd(pd)
+                    .qpn(qpn)
+                    .qp_type(QpType::Rc)
+                    .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+                    .pmtu(Pmtu::Mtu1024)
+                    .dqp_ip(Ipv4Addr::new(127, 0, 0, 25))
+                    .dqp_mac(MacAddress::default())
+                    .ack_timeout(ack_timeout)
+                    .build()
+                    .unwrap();
+                dev.create_qp(&qp).unwrap();
+                qpn
+            })
+            .collect();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        let ctx = dev.write(qps[0], 0, Key::new(0), flags, sge).unwrap();
+        let start = Instant::now();
+        let status = ctx.wait_timeout(Duration::from_millis(50)).unwrap();
+        assert!(matches!(status, CtxStatus::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(ctx.get_result().is_none());
+
+        let ctx = dev.write(qps[1], 0, Key::new(0), flags, sge).unwrap();
+        let status = ctx.wait_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(status, CtxStatus::Finished));
+        let result = ctx.get_result().unwrap();
+        assert_eq!(result.status, CompletionStatus::TimedOut);
+        assert_eq!(result.byte_len, 64);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cancel() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 41))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        // nothing listens on the remote address, so the write is never acknowledged
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 25))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let ctx = dev
+            .write(qpn, 0, Key::new(0), MemAccessTypeFlag::IbvAccessNoFlags, sge)
+            .unwrap();
+        assert_eq!(dev.0.write_op_ctx_map.read().unwrap().len(), 1);
+
+        let waiter = {
+            let ctx = ctx.clone();
+            std::thread::spawn(move || ctx.wait_timeout(Duration::from_secs(5)).unwrap())
+        };
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(ctx.cancel().unwrap());
+        assert!(matches!(waiter.join().unwrap(), CtxStatus::Cancelled));
+        assert!(dev.0.write_op_ctx_map.read().unwrap().is_empty());
+        ctx.wait().unwrap();
+        assert!(matches!(
+            ctx.wait_timeout(Duration::ZERO).unwrap(),
+            CtxStatus::Cancelled
+        ));
+        assert!(ctx.get_result().is_none());
+        // a cancelled operation can not be cancelled again
+        assert!(!ctx.cancel().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_drain() {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let mut cards = loopback_pair([26, 27], 4 * 4096, [access_flag; 2], Pmtu::Mtu4096);
+
+        const SEG_LEN: usize = 4096;
+        for (i, seg) in cards[0].buf.chunks_mut(SEG_LEN).enumerate() {
+            seg.fill(i as u8 + 1);
+        }
+        let [a, b] = &cards;
+        let (dev_a, mr_a, buf_a) = (&a.dev, &a.mr, &a.buf);
+        let (mr_b, buf_b) = (&b.mr, &b.buf);
+        // the writes are not waited for one by one
+        let mut ctxs: Vec<_> = (0..4)
+            .map(|i| {
+                let offset = (i * SEG_LEN) as u64;
+                let sge = Sge::new(buf_a.as_ptr() as u64 + offset, SEG_LEN as u32, mr_a.get_key());
+                dev_a
+                    .write(qpn, buf_b.as_ptr() as u64 + offset, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+                    .unwrap()
+            })
+            .collect();
+        // a user waiting on one of them is still woken up
+        let waited = ctxs.pop().unwrap();
+        let waiter = std::thread::spawn(move || waited.wait_result().unwrap().is_some());
+        std::thread::sleep(Duration::from_millis(10));
+        dev_a.drain(Duration::from_secs(5)).unwrap();
+        assert!(ctxs.iter().all(|ctx| ctx.get_result().is_some()));
+        assert!(waiter.join().unwrap());
+        assert_eq!(&buf_a[..], &buf_b[..]);
+
+        // a QP whose writes are never acknowledged can't be drained, the others can
+        let lost_qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(a.pd)
+            .qpn(lost_qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu4096)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 28))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev_a.create_qp(&qp).unwrap();
+        let sge = Sge::new(buf_a.as_ptr() as u64, 64, mr_a.get_key());
+        let _ctx = dev_a
+            .write(lost_qpn, 0, Key::new(0), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        assert!(matches!(
+            dev_a.drain_qp(lost_qpn, Duration::from_millis(50)),
+            Err(Error::DrainTimedOut(1))
+        ));
+        dev_a.drain_qp(qpn, Duration::from_millis(50)).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_path_mtu() {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let hosts = [57, 58];
+        let mut cards = loopback_pair(hosts, 2 * LEN, [access_flag; 2], Pmtu::Mtu4096);
+        for (card, remote) in cards.iter().zip(hosts.into_iter().rev()) {
+            // a standard ethernet frame only fits the pmtu of 1024
+            card.dev.set_path_mtu(Ipv4Addr::new(127, 0, 0, remote), 1500).unwrap();
+        }
+
+        const LEN: usize = 8192;
+        for (i, byte) in cards[0].buf[..LEN].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let [a, b] = &cards;
+        let (dev_a, mr_a, buf_a) = (&a.dev, &a.mr, &a.buf);
+        let (dev_b, mr_b, buf_b) = (&b.dev, &b.mr, &b.buf);
+        let sge = Sge::new(buf_a.as_ptr() as u64, LEN as u32, mr_a.get_key());
+        let ctx = dev_a
+            .write(qpn, buf_b.as_ptr() as u64, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert_eq!(buf_b[..LEN], buf_a[..LEN]);
+        // the page aligned write is segmented into 8 packets by the pmtu of the path, rather than
+        // into 2 packets by the one of the QP
+        assert_eq!(buf_b.as_ptr() as usize % 4096, 0);
+        let counters = dev_b.opcode_counters();
+        let write_cnt: u64 = [
+            ToHostWorkRbDescOpcode::RdmaWriteFirst,
+            ToHostWorkRbDescOpcode::RdmaWriteMiddle,
+            ToHostWorkRbDescOpcode::RdmaWriteLast,
+            ToHostWorkRbDescOpcode::RdmaWriteOnly,
+        ]
+        .iter()
+        .filter_map(|opcode| counters.get(opcode))
+        .sum();
+        assert_eq!(write_cnt, 8);
+        assert_eq!(dev_a.qp_psn_stats(qpn).unwrap().next_psn, Psn::new(8));
+
+        // the read response is segmented by the same pmtu on the way back
+        cards[1].buf[LEN..2 * LEN].fill(0x5a);
+        let [a, b] = &cards;
+        let (dev_a, mr_a, buf_a) = (&a.dev, &a.mr, &a.buf);
+        let (mr_b, buf_b) = (&b.mr, &b.buf);
+        let sge = Sge::new(buf_a.as_ptr() as u64 + LEN as u64, LEN as u32, mr_a.get_key());
+        let raddr = buf_b.as_ptr() as u64 + LEN as u64;
+        let ctx = dev_a
+            .read(qpn, raddr, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert!(buf_a[LEN..2 * LEN].iter().all(|byte| *byte == 0x5a));
+    }
+
+    #[test]
+    #[serial]
+    fn test_write_repeated() {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let mut cards = loopback_pair([61, 62], LEN, [access_flag; 2], Pmtu::Mtu1024);
+
+        const LEN: usize = 4096;
+        const COUNT: u32 = 8;
+        for (i, byte) in cards[0].buf.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let [a, b] = &cards;
+        let (dev_a, mr_a, buf_a) = (&a.dev, &a.mr, &a.buf);
+        let (mr_b, buf_b) = (&b.mr, &b.buf);
+        let raddr = buf_b.as_ptr() as u64;
+        let sges = [
+            Sge::new(buf_a.as_ptr() as u64, 1000, mr_a.get_key()),
+            Sge::new(buf_a.as_ptr() as u64 + 1000, LEN as u32 - 1000, mr_a.get_key()),
+        ];
+        let request = WriteRequest::new(qpn, raddr, mr_b.get_key(), MemAccessTypeFlag::empty(), &sges);
+        assert!(matches!(dev_a.write_repeated(&request, 0), Err(Error::Invalid(_))));
+
+        let ctx = dev_a.write_repeated(&request, COUNT).unwrap();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert_eq!(result.byte_len, LEN as u32 * COUNT);
+        assert_eq!(buf_b[..], buf_a[..]);
+        // the context completes with the last write, when every write has been acknowledged
+        assert!(dev_a.0.write_op_ctx_map.read().unwrap().is_empty());
+        let packet_cnt = calculate_packet_cnt(Pmtu::Mtu1024, raddr, LEN as u32);
+        assert_eq!(dev_a.qp_psn_stats(qpn).unwrap().next_psn, Psn::new(packet_cnt * COUNT));
+        assert_eq!(ctx.get_result().unwrap().byte_len, LEN as u32 * COUNT);
+    }
+
+    #[test]
+    #[serial]
+    fn test_qp_psn_stats() {
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let cards = loopback_pair([49, 50], 4 * 4096, [access_flag; 2], Pmtu::Mtu4096);
+        let [a, b] = &cards;
+        let (dev_a, mr_a, buf_a) = (&a.dev, &a.mr, &a.buf);
+        let (mr_b, buf_b) = (&b.mr, &b.buf);
+        let stats = dev_a.qp_psn_stats(qpn).unwrap();
+        assert_eq!((stats.next_psn, stats.acked_psn, stats.outstanding), (Psn::new(0), None, 0));
+        assert!(matches!(dev_a.qp_psn_stats(Qpn::new(4)), Err(Error::Invalid(_))));
+
+        // the writes of 2 packets each are not waited for one by one
+        const LEN: usize = 8192;
+        let _ctxs: Vec<_> = (0..2)
+            .map(|i| {
+                let offset = (i * LEN) as u64;
+                let sge = Sge::new(buf_a.as_ptr() as u64 + offset, LEN as u32, mr_a.get_key());
+                dev_a
+                    .write(qpn, buf_b.as_ptr() as u64 + offset, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+                    .unwrap()
+            })
+            .collect();
+        let stats = dev_a.qp_psn_stats(qpn).unwrap();
+        assert_eq!(stats.next_psn, Psn::new(4));
+        dev_a.drain(Duration::from_secs(5)).unwrap();
+        let stats = dev_a.qp_psn_stats(qpn).unwrap();
+        assert_eq!((stats.next_psn, stats.acked_psn, stats.outstanding), (Psn::new(4), Some(Psn::new(3)), 0));
+
+        // the writes of a QP without a remote are never acknowledged, so every one adds its packets
+        let lost_qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(a.pd)
+            .qpn(lost_qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu4096)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 51))
+            .dqp_mac(MacAddress::default())
+            .ack_timeout(Some(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        dev_a.create_qp(&qp).unwrap();
+        let sge = Sge::new(buf_a.as_ptr() as u64, LEN as u32, mr_a.get_key());
+        let ctxs: Vec<_> = (1..=2_u32)
+            .map(|i| {
+                let ctx = dev_a
+                    .write(lost_qpn, 0, Key::new(0), MemAccessTypeFlag::empty(), sge)
+                    .unwrap();
+                let stats = dev_a.qp_psn_stats(lost_qpn).unwrap();
+                assert_eq!((stats.next_psn, stats.outstanding), (Psn::new(2 * i), 2 * i));
+                ctx
+            })
+            .collect();
+        for ctx in ctxs {
+            let status = ctx.wait_timeout(Duration::from_secs(5)).unwrap();
+            assert!(matches!(status, CtxStatus::Finished));
+            assert_eq!(ctx.get_result().unwrap().status, CompletionStatus::TimedOut);
+        }
+        let stats = dev_a.qp_psn_stats(lost_qpn).unwrap();
+        assert_eq!((stats.next_psn, stats.acked_psn, stats.outstanding), (Psn::new(4), None, 4));
+    }
+
+    #[test]
+    #[serial]
+    fn test_pmtu_mismatch() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 9))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(6);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let buf = [0u8; 64];
+        let desc = ToCardWorkRbDescBuilder::new_write()
+            .with_common(ToCardWorkRbDescCommon {
+                total_len: 64,
+                raddr: 0,
+                rkey: Key::new(0),
+                dqp_ip: network.ipaddr,
+                dqpn: qpn,
+                mac_addr: network.macaddr,
+                pmtu: Pmtu::Mtu4096,
+                flags: MemAccessTypeFlag::IbvAccessNoFlags,
+                qp_type: QpType::Rc,
+                psn: Psn::default(),
+                msn: Msn::default(),
+            })
+            .with_sge(Sge::new(buf.as_ptr() as u64, 64, Key::new(0)))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            dev.send_work_desc(desc),
+            Err(Error::PmtuMismatch {
+                expected: Pmtu::Mtu1024,
+                actual: Pmtu::Mtu4096,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_attach_mcast() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 42))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let mgid = Ipv4Addr::new(239, 1, 2, 42);
+        let qp_of = |qpn: Qpn, qp_type: QpType| {
+            QpBuilder::default()
+                .pd(pd)
+                .qpn(qpn)
+                .qp_type(qp_type)
+                .rq_acc_flags(MemAccessTypeFlag::IbvAccessNoFlags)
+                .pmtu(Pmtu::Mtu1024)
+                .dqp_ip(network.ipaddr)
+                .dqp_mac(MacAddress::default())
+                .build()
+                .unwrap()
+        };
+        let (ud_qpn, rc_qpn) = (Qpn::new(5), Qpn::new(6));
+        dev.create_qp(&qp_of(ud_qpn, QpType::Ud)).unwrap();
+        dev.create_qp(&qp_of(rc_qpn, QpType::Rc)).unwrap();
+
+        assert!(matches!(
+            dev.attach_mcast(ud_qpn, network.ipaddr),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            dev.attach_mcast(rc_qpn, mgid),
+            Err(Error::InvalidQpTypeForOp { qp_type: QpType::Rc, .. })
+        ));
+        assert!(matches!(
+            dev.attach_mcast(Qpn::new(7), mgid),
+            Err(Error::Invalid(_))
+        ));
+
+        dev.attach_mcast(ud_qpn, mgid).unwrap();
+        dev.attach_mcast(ud_qpn, mgid).unwrap();
+        assert_eq!(dev.0.mcast_groups.lock().unwrap()[&mgid].len(), 1);
+        dev.detach_mcast(ud_qpn, mgid).unwrap();
+        assert!(dev.0.mcast_groups.lock().unwrap().is_empty());
+        assert!(matches!(
+            dev.detach_mcast(ud_qpn, mgid),
+            Err(Error::Invalid(_))
+        ));
+
+        // destroying a qp detaches it from its groups
+        dev.attach_mcast(ud_qpn, mgid).unwrap();
+        dev.destroy_qp(ud_qpn).unwrap();
+        assert!(dev.0.mcast_groups.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_send_ud_mcast() {
+        let network_of = |last: u8| {
+            RdmaDeviceNetworkParamBuilder::default()
+                .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                .ipaddr(Ipv4Addr::new(127, 0, 0, last))
+                .macaddr(MacAddress::default())
+                .build()
+                .unwrap()
+        };
+        let mgid = Ipv4Addr::new(239, 1, 2, 73);
+        let qpn = Qpn::new(5);
+        let ud_qp_of = |dev: &Device, pd: Pd| {
+            let qp = QpBuilder::default()
+                .pd(pd)
+                .qpn(qpn)
+                .qp_type(QpType::Ud)
+                .rq_acc_flags(MemAccessTypeFlag::IbvAccessLocalWrite)
+                .pmtu(Pmtu::Mtu1024)
+                .dqp_ip(Ipv4Addr::new(127, 0, 0, 1))
+                .dqp_mac(MacAddress::default())
+                .build()
+                .unwrap();
+            dev.create_qp(&qp).unwrap();
+        };
+
+        // two members of the group, whose qps take the receive buffers of a srq
+        let members: Vec<_> = [73, 74]
+            .into_iter()
+            .map(|last| {
+                let dev = Device::new_software(&network_of(last)).unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                ud_qp_of(&dev, pd);
+                let srq = dev.create_srq().unwrap();
+                dev.attach_srq(qpn, srq).unwrap();
+                let (mr, buf) = dev
+                    .alloc_and_reg_mr(pd, 4096, MemAccessTypeFlag::IbvAccessLocalWrite)
+                    .unwrap();
+                let sge = Sge::new(buf.as_ptr() as u64, 64, mr.get_key());
+                dev.post_srq_recv(srq, &[sge]).unwrap();
+                dev.attach_mcast(qpn, mgid).unwrap();
+                (dev, buf)
+            })
+            .collect();
+
+        let sender = Device::new_software(&network_of(75)).unwrap();
+        let pd = sender.alloc_pd().unwrap();
+        ud_qp_of(&sender, pd);
+        let (mr, mut buf) = sender
+            .alloc_and_reg_mr(pd, 4096, MemAccessTypeFlag::IbvAccessLocalWrite)
+            .unwrap();
+        let data: Vec<u8> = (0..64_u8).collect();
+        buf[..64].copy_from_slice(&data);
+        let too_long = Sge::new(buf.as_ptr() as u64, 2048, mr.get_key());
+        assert!(matches!(sender.send_ud(qpn, mgid, too_long), Err(Error::Invalid(_))));
+
+        // a single send to the group reaches both members
+        let sge = Sge::new(buf.as_ptr() as u64, 64, mr.get_key());
+        let ctx = sender.send_ud(qpn, mgid, sge).unwrap();
+        assert_eq!(ctx.wait_result().unwrap().unwrap().byte_len, 64);
+        for (dev, buf) in &members {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while buf[..64] != data[..] && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(buf[..64], data[..]);
+            dev.shutdown().unwrap();
+        }
+        sender.shutdown().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_ctrl_op_error_context() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 66))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software_loopback(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(Qpn::new(5))
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessNoFlags)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+        // the device refuses to create the qp twice
+        let err = dev.create_qp(&qp).unwrap_err();
+        let Error::DeviceReturnFailed { op, op_id, ref desc } = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(op, "create qp");
+        assert_eq!(desc, "create qp 5");
+        assert!(err.to_string().contains(&format!("op_id {op_id}")));
+        dev.shutdown().unwrap();
+
+        // a command failing to reach the device keeps the failure as its source
+        let err = Error::CtrlOpFailed {
+            op_id: 3,
+            desc: "destroy qp 5".to_owned(),
+            source: DeviceError::Device("ring buffer lost".to_owned()),
+        };
+        assert!(err.to_string().contains("op_id 3: destroy qp 5"));
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(source.to_string().contains("ring buffer lost"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_attach_srq() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 65))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software_loopback(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qp_of = |qpn: Qpn, qp_type: QpType| {
+            QpBuilder::default()
+                .pd(pd)
+                .qpn(qpn)
+                .qp_type(qp_type)
+                .rq_acc_flags(MemAccessTypeFlag::IbvAccessNoFlags)
+                .pmtu(Pmtu::Mtu1024)
+                .dqp_ip(network.ipaddr)
+                .dqp_mac(MacAddress::default())
+                .build()
+                .unwrap()
+        };
+        let (qpn_a, qpn_b, rc_qpn) = (Qpn::new(5), Qpn::new(6), Qpn::new(7));
+        dev.create_qp(&qp_of(qpn_a, QpType::XrcRecv)).unwrap();
+        dev.create_qp(&qp_of(qpn_b, QpType::XrcRecv)).unwrap();
+        dev.create_qp(&qp_of(rc_qpn, QpType::Rc)).unwrap();
+        let srq = dev.create_srq().unwrap();
+        let other_srq = dev.create_srq().unwrap();
+        assert!(srq.srqn() < 1 << 24);
+
+        assert!(matches!(
+            dev.attach_srq(rc_qpn, srq),
+            Err(Error::InvalidQpTypeForOp { qp_type: QpType::Rc, .. })
+        ));
+        assert!(matches!(dev.attach_srq(Qpn::new(8), srq), Err(Error::Invalid(_))));
+        dev.attach_srq(qpn_a, srq).unwrap();
+        dev.attach_srq(qpn_b, srq).unwrap();
+        assert!(matches!(dev.attach_srq(qpn_a, other_srq), Err(Error::Invalid(_))));
+
+        // the buffers must lie in a registered Mr
+        let (mr, buf) = dev
+            .alloc_and_reg_mr(pd, 4096, MemAccessTypeFlag::IbvAccessLocalWrite)
+            .unwrap();
+        let unregistered = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        assert!(matches!(
+            dev.post_srq_recv(srq, &[unregistered]),
+            Err(Error::InvalidKey(_))
+        ));
+        let out_of_mr = Sge::new(buf.as_ptr() as u64 + 4064, 64, mr.get_key());
+        assert!(matches!(
+            dev.post_srq_recv(srq, &[out_of_mr]),
+            Err(Error::SgeOutOfBounds { .. })
+        ));
+        let sge = Sge::new(buf.as_ptr() as u64, 64, mr.get_key());
+        dev.post_srq_recv(srq, &[sge]).unwrap();
+
+        // a srq can't be destroyed until its qps are destroyed
+        assert!(matches!(dev.destroy_srq(srq), Err(Error::Invalid(_))));
+        dev.destroy_qp(qpn_a).unwrap();
+        dev.destroy_qp(qpn_b).unwrap();
+        dev.destroy_srq(srq).unwrap();
+        assert!(matches!(dev.post_srq_recv(srq, &[sge]), Err(Error::Invalid(_))));
+        assert!(matches!(dev.destroy_srq(srq), Err(Error::Invalid(_))));
+        dev.destroy_srq(other_srq).unwrap();
+        dev.dereg_mr(mr).unwrap();
+        dev.shutdown().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_ring_full() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 43))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let full = Arc::new(AtomicBool::new(false));
+        let inner = SoftwareDevice::init(
+            network.ipaddr,
+            super::DEFAULT_RMDA_PORT,
+            Arc::new(RoundRobinStrategy::new()),
+        )
+        .unwrap();
+        let adaptor = SaturatedAdaptor {
+            inner: Arc::new(inner),
+            full: Arc::clone(&full),
+            card: None,
+        };
+        let dev = Device::new_with_adaptor(adaptor, &network, ACKNOWLEDGE_BUFFER_SIZE, false).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        full.store(true, Ordering::Release);
+
+        // the ctrl op fails at once instead of waiting for a response which never comes
+        let pending = dev.0.ctrl_op_ctx_map.read().unwrap().len();
+        assert!(matches!(dev.create_qp(&qp), Err(Error::RingFull(ring)) if ring == "ctrl"));
+        assert_eq!(dev.0.ctrl_op_ctx_map.read().unwrap().len(), pending);
+
+        let buf = [0u8; 64];
+        let desc = ToCardWorkRbDescBuilder::new_write()
+            .with_common(ToCardWorkRbDescCommon {
+                total_len: 64,
+                raddr: 0,
+                rkey: Key::new(0),
+                dqp_ip: network.ipaddr,
+                dqpn: qpn,
+                mac_addr: network.macaddr,
+                pmtu: Pmtu::Mtu1024,
+                flags: MemAccessTypeFlag::IbvAccessNoFlags,
+                qp_type: QpType::Rc,
+                psn: Psn::default(),
+                msn: Msn::default(),
+            })
+            .with_sge(Sge::new(buf.as_ptr() as u64, 64, Key::new(0)))
+            .build()
+            .unwrap();
+        assert!(matches!(dev.send_work_desc(desc), Err(Error::RingFull(ring)) if ring == "work"));
+
+        // the device is usable again once the ring has room
+        full.store(false, Ordering::Release);
+        dev.create_qp(&qp).unwrap();
+
+        // the failed operations take no psn, so the next one follows the last sent packet
+        let access = MemAccessTypeFlag::IbvAccessLocalWrite
+            | MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite;
+        let (mr, mem) = dev.alloc_and_reg_mr(pd, 4096, access).unwrap();
+        let addr = mem.as_ptr() as u64;
+        let sge = Sge::new(addr, 64, mr.get_key());
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        full.store(true, Ordering::Release);
+        assert!(matches!(
+            dev.write(qpn, addr + 2048, mr.get_key(), flags, sge),
+            Err(Error::RingFull(ring)) if ring == "work"
+        ));
+        assert!(matches!(
+            dev.read(qpn, addr + 2048, mr.get_key(), flags, sge),
+            Err(Error::RingFull(ring)) if ring == "work"
+        ));
+        let request = WriteRequest::new(qpn, addr + 2048, mr.get_key(), flags, &[sge]);
+        assert!(matches!(
+            dev.write_repeated(&request, 3),
+            Err(Error::RingFull(ring)) if ring == "work"
+        ));
+        let stats = dev.qp_psn_stats(qpn).unwrap();
+        assert_eq!(stats.next_psn, Psn::new(0));
+        assert_eq!(stats.outstanding, 0);
+        full.store(false, Ordering::Release);
+        let ctx = dev.write(qpn, addr + 2048, mr.get_key(), flags, sge).unwrap();
+        ctx.wait().unwrap();
+        assert_eq!(dev.qp_psn_stats(qpn).unwrap().next_psn, Psn::new(1));
+        dev.dereg_mr(mr).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_short_read_response() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 78))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let cut = Arc::new(AtomicU32::new(0));
+        let inner = SoftwareDevice::init(
+            network.ipaddr,
+            super::DEFAULT_RMDA_PORT,
+            Arc::new(RoundRobinStrategy::new()),
+        )
+        .unwrap();
+        let adaptor = ShortReadAdaptor {
+            inner: Arc::new(inner),
+            cut: Arc::clone(&cut),
+        };
+        let dev = Device::new_with_adaptor(adaptor, &network, ACKNOWLEDGE_BUFFER_SIZE, false).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        let access = MemAccessTypeFlag::IbvAccessLocalWrite
+            | MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite;
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+        let (mr, mem) = dev.alloc_and_reg_mr(pd, 4 * 4096, access).unwrap();
+        let addr = mem.as_ptr() as u64;
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+
+        // the responses of a single packet and of several packets, every packet of which is cut short
+        // while `cut` is set
+        for (len, cut_len, status, received) in [
+            (64, 0, CompletionStatus::Success, 64),
+            (64, 32, CompletionStatus::LocalLengthError, 32),
+            (4096, 0, CompletionStatus::Success, 4096),
+            (4096, 10, CompletionStatus::LocalLengthError, 4096 - 3 * 10),
+        ] {
+            cut.store(cut_len, Ordering::Release);
+            let sge = Sge::new(addr, len, mr.get_key());
+            let ctx = dev.read(qpn, addr + 8192, mr.get_key(), flags, sge).unwrap();
+            let result = ctx.wait_result().unwrap().unwrap();
+            assert_eq!((result.status, result.byte_len), (status, received), "{len} bytes");
+        }
+        dev.dereg_mr(mr).unwrap();
+        dev.shutdown().unwrap();
+    }
+
+    #[cfg(feature = "scheduler")]
+    #[test]
+    #[serial]
+    fn test_card_ring_full() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 69))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let full = Arc::new(AtomicBool::new(false));
+        let inner = Arc::new(
+            SoftwareDevice::init(
+                network.ipaddr,
+                super::DEFAULT_RMDA_PORT,
+                Arc::new(RoundRobinStrategy::new()),
+            )
+            .unwrap(),
+        );
+        let card = Arc::new(DescriptorScheduler::new(Arc::new(RoundRobinStrategy::new())));
+        let (rejected, rejections) = crossbeam_channel::unbounded();
+        let mut ring = SaturatedCardRing {
+            inner: Arc::clone(&inner),
+            full: Arc::clone(&full),
+            rejected,
+        };
+        let forwarded = Arc::clone(&card);
+        let _: std::thread::JoinHandle<_> =
+            std::thread::spawn(move || forward_to_card(&forwarded, &mut ring));
+        let adaptor = SaturatedAdaptor {
+            inner,
+            full: Arc::new(AtomicBool::new(false)),
+            card: Some(card),
+        };
+        let dev = Device::new_with_adaptor(adaptor, &network, ACKNOWLEDGE_BUFFER_SIZE, false).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        let access = MemAccessTypeFlag::IbvAccessLocalWrite | MemAccessTypeFlag::IbvAccessRemoteWrite;
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+        let (mr, mem) = dev.alloc_and_reg_mr(pd, 4096, access).unwrap();
+        let addr = mem.as_ptr() as u64;
+        let sge = Sge::new(addr, 64, mr.get_key());
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+
+        // the write taken before the card ring is found full waits in the scheduler, the later ones
+        // fail instead of piling up behind it
+        full.store(true, Ordering::Release);
+        let ctx = dev.write(qpn, addr + 2048, mr.get_key(), flags, sge).unwrap();
+        // the ring is retried after the scheduler is told it's full
+        for _ in 0..2 {
+            rejections.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(matches!(
+            dev.write(qpn, addr + 2048, mr.get_key(), flags, sge),
+            Err(Error::RingFull(ring)) if ring == "work"
+        ));
+
+        // the waiting write is sent once the card has room, then the new ones are taken again
+        full.store(false, Ordering::Release);
+        ctx.wait().unwrap();
+        dev.write(qpn, addr + 2048, mr.get_key(), flags, sge).unwrap().wait().unwrap();
+        dev.dereg_mr(mr).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_shutdown() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 46))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software_loopback(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(5);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        dev.shutdown().unwrap();
+        // shutting down again is a no-op
+        dev.shutdown().unwrap();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        assert!(matches!(
+            dev.write(qpn, 0, Key::new(0), flags, sge),
+            Err(Error::DeviceClosed)
+        ));
+        assert!(matches!(
+            dev.read(qpn, 0, Key::new(0), flags, sge),
+            Err(Error::DeviceClosed)
+        ));
+        assert!(matches!(
+            dev.alloc_and_reg_mr(pd, 4096, MemAccessTypeFlag::IbvAccessLocalWrite),
+            Err(Error::DeviceClosed)
+        ));
+
+        // the stopped device has left the in-process network, so its address can be taken again
+        let dev = Device::new_software_loopback(&network).unwrap();
+        dev.shutdown().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_dump_page_table() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 47))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessLocalWrite;
+        // the acknowledge buffer is registered by the device itself
+        let initial = dev.dump_page_table().unwrap();
+        let free_cnt = |dump: &crate::mr::PageTableDump| -> usize {
+            dump.free_blocks.iter().map(|&(_, len)| len).sum()
+        };
+        assert_eq!(initial.entries.len() + free_cnt(&initial), crate::MR_PGT_SIZE);
+
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let addr = buf.as_ptr() as u64;
+        let mr1 = dev.reg_mr(pd, addr, 2 * 4096, 4096, access_flag).unwrap();
+        let mr2 = dev.reg_mr(pd, addr, 4096, 4096, access_flag).unwrap();
+        let dump = dev.dump_page_table().unwrap();
+        assert_eq!(dump.entries.len(), initial.entries.len() + 3);
+        assert_eq!(dump.entries.len() + free_cnt(&dump), crate::MR_PGT_SIZE);
+        // the entries of the new MRs point into the buffer
+        let new_entries: Vec<_> = dump
+            .entries
+            .iter()
+            .filter(|entry| !initial.entries.contains(entry))
+            .map(|&(_, addr)| addr)
+            .collect();
+        assert_eq!(new_entries, [addr, addr + 4096, addr]);
+
+        dev.dereg_mr(mr1).unwrap();
+        let dump = dev.dump_page_table().unwrap();
+        assert_eq!(dump.entries.len(), initial.entries.len() + 1);
+        assert!(dump.free_blocks.iter().any(|&(_, len)| len == 2));
+        dev.dereg_mr(mr2).unwrap();
+        let dump = dev.dump_page_table().unwrap();
+        assert_eq!(dump.entries, initial.entries);
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_builder() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 48))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = DeviceBuilder::new(&network)
+            .transport(Transport::Software)
+            .scheduler(Arc::new(RoundRobinStrategy::new()))
+            .send_workers(2)
+            .recv_buffer_size(2 * NET_SERVER_BUF_SIZE)
+            .ack_buffer_size(ACKNOWLEDGE_BUFFER_SIZE)
+            .build()
+            .unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let addr = buf.as_ptr() as u64;
+        let mr = dev
+            .reg_mr(pd, addr, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        // write the first 4KB of the buffer to the second 4KB
+        const LEN: usize = 4096;
+        unsafe { std::ptr::write_bytes(addr as *mut u8, 0x5a, LEN); }
+        let sge = Sge::new(addr, LEN as u32, mr.get_key());
+        let ctx = dev
+            .write(qpn, addr + LEN as u64, mr.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        ctx.wait().unwrap();
+        let dst = unsafe { from_raw_parts(addr as *const u8, 2 * LEN) };
+        assert!(dst[LEN..].iter().all(|b| *b == 0x5a));
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_builder_local_copy() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 70))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = DeviceBuilder::new(&network)
+            .transport(Transport::Software)
+            .local_copy(true)
+            .build()
+            .unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let addr = buf.as_ptr() as u64;
+        let mr = dev
+            .reg_mr(pd, addr, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        const LEN: usize = 4096;
+        unsafe { std::ptr::write_bytes(addr as *mut u8, 0xa5, LEN); }
+        let sge = Sge::new(addr, LEN as u32, mr.get_key());
+        let ctx = dev
+            .write(qpn, addr + LEN as u64, mr.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        ctx.wait().unwrap();
+        let dst = unsafe { from_raw_parts(addr as *const u8, 2 * LEN) };
+        assert!(dst[LEN..].iter().all(|b| *b == 0xa5));
+
+        // only the acknowledges crossed the network, the payload was copied directly. A slow run
+        // may resend the write on the retransmit timeout, which is acknowledged again
+        let counters = dev.opcode_counters();
+        assert!(counters.contains_key(&ToHostWorkRbDescOpcode::Acknowledge));
+        assert_eq!(counters.len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_from_sockets() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 68))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        // the sockets are opened ahead, as a privileged helper does
+        let send_socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP)).unwrap();
+        send_socket.set_header_included_v4(true).unwrap();
+        let recv_socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP)).unwrap();
+        recv_socket
+            .bind(&SocketAddrV4::new(network.ipaddr, super::DEFAULT_RMDA_PORT).into())
+            .unwrap();
+        let dev = DeviceBuilder::new(&network)
+            .transport(Transport::SoftwareSockets {
+                send_socket: Arc::new(send_socket.into()),
+                recv_socket: Arc::new(recv_socket.into()),
+            })
+            .max_burst(4)
+            .scheduler_capacity(64)
+            .build()
+            .unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let buf = AlignedMemory::new(PAGE_SIZE).unwrap();
+        let addr = buf.as_ptr() as u64;
+        let mr = dev
+            .reg_mr(pd, addr, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        // write the first 1KB of the buffer to the second 1KB
+        const LEN: usize = 1024;
+        unsafe { std::ptr::write_bytes(addr as *mut u8, 0x5a, LEN); }
+        let sge = Sge::new(addr, LEN as u32, mr.get_key());
+        let ctx = dev
+            .write(qpn, addr + LEN as u64, mr.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        ctx.wait().unwrap();
+        let dst = unsafe { from_raw_parts(addr as *const u8, 2 * LEN) };
+        assert!(dst[LEN..].iter().all(|b| *b == 0x5a));
+
+        // a socket without IP_HDRINCL can not send the packets the device builds
+        let plain_socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP)).unwrap();
+        let recv_socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP)).unwrap();
+        let result = DeviceBuilder::new(&network)
+            .transport(Transport::SoftwareSockets {
+                send_socket: Arc::new(plain_socket.into()),
+                recv_socket: Arc::new(recv_socket.into()),
+            })
+            .build();
+        assert!(matches!(result, Err(Error::Device(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_ack_buffer_size() {
+        const SLOT_SIZE: usize = AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
+        const QP_CNT: usize = 8;
+        const LEN: usize = 4096;
+        let networks: Vec<_> = [52, 53]
+            .into_iter()
+            .map(|host| {
+                RdmaDeviceNetworkParamBuilder::default()
+                    .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                    .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                    .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                    .macaddr(MacAddress::default())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        // the size is a nonzero multiple of the slot size
+        for size in [0, SLOT_SIZE + 1] {
+            let result = DeviceBuilder::new(&networks[0])
+                .transport(Transport::Loopback)
+                .ack_buffer_size(size)
+                .build();
+            assert!(matches!(result, Err(Error::Invalid(_))), "{size}");
+        }
+
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpns: Vec<_> = (0..QP_CNT).map(|i| Qpn::new(3 + i as u32)).collect();
+        let mut cards: Vec<_> = networks
+            .iter()
+            .zip(networks.iter().rev())
+            .map(|(local, remote)| {
+                let dev = DeviceBuilder::new(local)
+                    .transport(Transport::Loopback)
+                    .ack_buffer_size(QP_CNT * SLOT_SIZE)
+                    .build()
+                    .unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                let (mr, buf) = dev.alloc_and_reg_mr(pd, QP_CNT * LEN, access_flag).unwrap();
+                for qpn in &qpns {
+                    let qp = QpBuilder::default()
+                        .pd(pd)
+                        .qpn(*qpn)
+                        .qp_type(QpType::Rc)
+                        .rq_acc_flags(access_flag)
+                        .pmtu(Pmtu::Mtu4096)
+                        .dqp_ip(remote.ipaddr)
+                        .dqp_mac(remote.macaddr)
+                        .build()
+                        .unwrap();
+                    dev.create_qp(&qp).unwrap();
+                }
+                (dev, mr, buf)
+            })
+            .collect();
+        for (i, chunk) in cards[0].2.chunks_mut(LEN).enumerate() {
+            chunk.fill(i as u8 + 1);
+        }
+
+        // every QP waits for its ACK at the same time
+        let (dev_a, mr_a, buf_a) = &cards[0];
+        let (_, mr_b, buf_b) = &cards[1];
+        let ctxs: Vec<_> = qpns
+            .iter()
+            .enumerate()
+            .map(|(i, qpn)| {
+                let offset = (i * LEN) as u64;
+                let sge = Sge::new(buf_a.as_ptr() as u64 + offset, LEN as u32, mr_a.get_key());
+                dev_a
+                    .write(*qpn, buf_b.as_ptr() as u64 + offset, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+                    .unwrap()
+            })
+            .collect();
+        for ctx in ctxs {
+            let result = ctx.wait_result().unwrap().unwrap();
+            assert_eq!(result.status, CompletionStatus::Success);
+        }
+        assert_eq!(&buf_a[..], &buf_b[..]);
+
+        // an outstanding ACK holds its slot, so a larger buffer takes more of them at the same time
+        let small = dev_a.init_ack_buf(2 * SLOT_SIZE).unwrap();
+        let slots: Vec<_> = (0..QP_CNT).map_while(|_| small.try_acquire_slot()).collect();
+        assert_eq!(slots.len(), 2);
+        let large = dev_a.init_ack_buf(QP_CNT * SLOT_SIZE).unwrap();
+        let slots: Vec<_> = (0..QP_CNT).map_while(|_| large.try_acquire_slot()).collect();
+        assert_eq!(slots.len(), QP_CNT);
+        assert!(large.try_acquire_slot().is_none());
+    }
+
+    #[test]
+    fn test_ack_buffer_full() {
+        const SLOT_CNT: usize = 4;
+        let size = SLOT_CNT * AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
+        let ack_buf = AcknowledgeBuffer::new_with_buf(
+            HugePage::new_or_fallback(size).unwrap(),
+            size,
+            Key::new(0),
+        );
+        let mut slots: Vec<_> = (0..SLOT_CNT).map(|_| ack_buf.acquire_slot().unwrap()).collect();
+        assert!(ack_buf.try_acquire_slot().is_none());
+        assert!(matches!(ack_buf.acquire_slot(), Err(DeviceError::AckBufferFull)));
+
+        // a released slot is handed out again
+        drop(slots.pop());
+        slots.push(ack_buf.acquire_slot().unwrap());
+        assert!(ack_buf.try_acquire_slot().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_memory_fabric() {
+        const LEN: usize = 4096;
+        const DEV_CNT: usize = 3;
+        let networks: Vec<_> = [54, 55, 56]
+            .into_iter()
+            .map(|host| {
+                RdmaDeviceNetworkParamBuilder::default()
+                    .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                    .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                    .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                    .macaddr(MacAddress::new([0x02, 0, 0, 0, 0, host]))
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        // the QP between the devices i and j, the same on both sides
+        let qpn_of = |i: usize, j: usize| Qpn::new(3 + (i + j) as u32);
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let fabric = Arc::new(MemoryFabric::new());
+        let mut cards: Vec<_> = networks
+            .iter()
+            .enumerate()
+            .map(|(i, local)| {
+                let dev = DeviceBuilder::new(local)
+                    .transport(Transport::Fabric {
+                        fabric: Arc::clone(&fabric),
+                    })
+                    .build()
+                    .unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                let (mr, buf) = dev.alloc_and_reg_mr(pd, DEV_CNT * LEN, access_flag).unwrap();
+                for (j, remote) in networks.iter().enumerate().filter(|(j, _)| *j != i) {
+                    let qp = QpBuilder::default()
+                        .pd(pd)
+                        .qpn(qpn_of(i, j))
+                        .qp_type(QpType::Rc)
+                        .rq_acc_flags(access_flag)
+                        .pmtu(Pmtu::Mtu4096)
+                        .dqp_ip(remote.ipaddr)
+                        .dqp_mac(fabric.mac_of(remote.ipaddr).unwrap_or(remote.macaddr))
+                        .build()
+                        .unwrap();
+                    dev.create_qp(&qp).unwrap();
+                }
+                (dev, mr, buf)
+            })
+            .collect();
+        assert_eq!(fabric.len(), DEV_CNT);
+        assert_eq!(fabric.mac_of(networks[1].ipaddr), Some(networks[1].macaddr));
+
+        // an address is taken once per fabric, and the fabrics are isolated from each other
+        let result = DeviceBuilder::new(&networks[0])
+            .transport(Transport::Fabric {
+                fabric: Arc::clone(&fabric),
+            })
+            .build();
+        assert!(matches!(result, Err(Error::Device(_))));
+        let other_fabric = Arc::new(MemoryFabric::new());
+        let stranger = DeviceBuilder::new(&networks[0])
+            .transport(Transport::Fabric {
+                fabric: Arc::clone(&other_fabric),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(fabric.len(), DEV_CNT);
+        assert_eq!(other_fabric.len(), 1);
+
+        // the device i writes its chunk into the chunk i of every other device
+        for (i, (_, _, buf)) in cards.iter_mut().enumerate() {
+            buf[i * LEN..(i + 1) * LEN].fill(i as u8 + 1);
+        }
+        let mut ctxs = Vec::new();
+        for (i, (dev, mr, buf)) in cards.iter().enumerate() {
+            let offset = (i * LEN) as u64;
+            let sge = Sge::new(buf.as_ptr() as u64 + offset, LEN as u32, mr.get_key());
+            for (j, (_, remote_mr, remote_buf)) in cards.iter().enumerate().filter(|(j, _)| *j != i) {
+                let ctx = dev
+                    .write(
+                        qpn_of(i, j),
+                        remote_buf.as_ptr() as u64 + offset,
+                        remote_mr.get_key(),
+                        MemAccessTypeFlag::empty(),
+                        sge,
+                    )
+                    .unwrap();
+                ctxs.push(ctx);
+            }
+        }
+        for ctx in ctxs {
+            let result = ctx.wait_result().unwrap().unwrap();
+            assert_eq!(result.status, CompletionStatus::Success);
+        }
+        for (j, (_, _, buf)) in cards.iter().enumerate() {
+            for (i, chunk) in buf.chunks(LEN).enumerate() {
+                assert!(chunk.iter().all(|b| *b == i as u8 + 1), "chunk {i} of device {j}");
+            }
+        }
+        // the device of the same address on another fabric misses the traffic to that address
+        let received = |dev: &Device| dev.opcode_counters().values().sum::<u64>();
+        assert!(received(&cards[0].0) > 0);
+        assert_eq!(received(&stranger), 0);
+        // a device leaves its fabric once shut down
+        stranger.shutdown().unwrap();
+        assert!(other_fabric.is_empty());
+        for (dev, _, _) in &cards {
+            dev.shutdown().unwrap();
+        }
+        assert!(fabric.is_empty());
+    }
+
+    /// Write 256 KiB between two devices injecting `faults` into their packets, each with a seed of
+    /// its own, and return the status of the write and whether the data arrives intact. The builder
+    /// of the `i`th device is passed through `configure(i, builder)` before it's built.
+    fn write_with_faults(
+        faults: FaultInjector,
+        retransmission: bool,
+        configure: &dyn Fn(usize, DeviceBuilder) -> DeviceBuilder,
+    ) -> (CtxStatus, Option<CompletionStatus>, bool) {
+        const LEN: usize = 256 * 1024;
+        let networks: Vec<_> = [63, 64]
+            .into_iter()
+            .map(|host| {
+                RdmaDeviceNetworkParamBuilder::default()
+                    .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                    .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                    .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                    .macaddr(MacAddress::default())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteWrite | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let qpn = Qpn::new(3);
+        let fabric = Arc::new(MemoryFabric::new());
+        let mut cards: Vec<_> = networks
+            .iter()
+            .zip(networks.iter().rev())
+            .enumerate()
+            .map(|(i, (local, remote))| {
+                let builder = DeviceBuilder::new(local)
+                    .transport(Transport::Fabric {
+                        fabric: Arc::clone(&fabric),
+                    })
+                    .fault_injector(faults.with_seed(faults.seed() + i as u64))
+                    .retransmission(retransmission);
+                let dev = configure(i, builder).build().unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                let (mr, buf) = dev.alloc_and_reg_mr(pd, LEN, access_flag).unwrap();
+                // a write which is never resent fails once its ACK is overdue
+                let ack_timeout = (!retransmission).then_some(Duration::from_secs(1));
+                let qp = QpBuilder::default()
+                    .pd(pd)
+                    .qpn(qpn)
+                    .qp_type(QpType::Rc)
+                    .rq_acc_flags(access_flag)
+                    .pmtu(Pmtu::Mtu1024)
+                    .dqp_ip(remote.ipaddr)
+                    .dqp_mac(remote.macaddr)
+                    .ack_timeout(ack_timeout)
+                    .build()
+                    .unwrap();
+                dev.create_qp(&qp).unwrap();
+                (dev, mr, buf)
+            })
+            .collect();
+
+        for (i, byte) in cards[0].2.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let (dev_a, mr_a, buf_a) = &cards[0];
+        let (_, mr_b, buf_b) = &cards[1];
+        let sge = Sge::new(buf_a.as_ptr() as u64, LEN as u32, mr_a.get_key());
+        let ctx = dev_a
+            .write(qpn, buf_b.as_ptr() as u64, mr_b.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        let status = ctx.wait_timeout(Duration::from_secs(30)).unwrap();
+        let result = ctx.get_result().map(|result| result.status);
+        let intact = buf_a[..] == buf_b[..];
+        for (dev, _, _) in &cards {
+            dev.shutdown().unwrap();
+        }
+        (status, result, intact)
+    }
+
+    #[test]
+    #[serial]
+    fn test_fault_injection() {
+        // the lost and the reordered packets are resent until the write completes
+        let faults = FaultInjector::new(0.1, 2, 7);
+        let (status, result, intact) = write_with_faults(faults, true, &|_, builder| builder);
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(result, Some(CompletionStatus::Success));
+        assert!(intact);
+
+        // without the retransmission, the write fails with the first lost packet
+        let (status, result, _) = write_with_faults(faults, false, &|_, builder| builder);
+        assert!(matches!(status, CtxStatus::Finished));
+        assert!(matches!(
+            result,
+            Some(CompletionStatus::SequenceError | CompletionStatus::TimedOut)
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_payload_corruption() {
+        // the corrupted packets fail the ICRC check and are dropped, then resent intact
+        let faults = FaultInjector::new(0.0, 0, 11).with_corruption(0.1, 3);
+        let (status, result, intact) = write_with_faults(faults, true, &|_, builder| builder);
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(result, Some(CompletionStatus::Success));
+        assert!(intact);
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_invalid_icrc() {
+        // the corrupted packets are delivered as they are, so the write completes without any resend
+        // but the data is not intact
+        let faults = FaultInjector::new(0.0, 0, 11).with_corruption(0.1, 3);
+        let (status, result, intact) =
+            write_with_faults(faults, false, &|_, builder| builder.accept_invalid_icrc(true));
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(result, Some(CompletionStatus::Success));
+        assert!(!intact);
+    }
+
+    #[test]
+    #[serial]
+    fn test_custom_icrc() {
+        // the packets and the acknowledges carry the ICRC of the custom config, so the write is never
+        // resent
+        let quirky = IcrcConfig::new(0xedb8_8320, 0);
+        let faults = FaultInjector::new(0.0, 0, 13);
+        let (status, result, intact) =
+            write_with_faults(faults, false, &|_, builder| builder.icrc(quirky));
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(result, Some(CompletionStatus::Success));
+        assert!(intact);
+
+        // a device of the standard ICRC drops all the packets of the other one
+        let (status, result, _) = write_with_faults(faults, false, &|i, builder| {
+            if i == 0 {
+                builder.icrc(quirky)
+            } else {
+                builder
+            }
+        });
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(result, Some(CompletionStatus::TimedOut));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::Instant,
+};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use thiserror::Error;
+
+/// A logger that prints the elapsed time, the level, the thread name and the target of each record,
+/// so that the logs of several devices in one process can be told apart.
+///
+/// The records are printed to stdout, and appended to a file if it is given. The file is buffered, and
+/// flushed when the logger is dropped or flushed. As the global logger is never dropped,
+/// `Device::shutdown` flushes it.
+#[derive(Debug)]
+pub struct Logger {
+    start: Instant,
+    level: LevelFilter,
+    file: Option<Mutex<BufWriter<File>>>,
+}
+
+/// Error type of initializing the logger
+#[non_exhaustive]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Error)]
+pub enum LoggerError {
+    /// Failed to open the log file
+    #[error("failed to open the log file")]
+    Io(#[from] io::Error),
+
+    /// A logger has been set
+    #[error("failed to set the logger")]
+    SetLogger(#[from] SetLoggerError),
+}
+
+impl Logger {
+    /// Create a logger printing the records up to `level` to stdout.
+    #[must_use]
+    pub fn new(level: LevelFilter) -> Self {
+        Self {
+            start: Instant::now(),
+            level,
+            file: None,
+        }
+    }
+
+    /// Create a logger printing the records up to `level` to stdout and the file at `path`.
+    ///
+    /// The file is created if it does not exist, otherwise the records are appended to it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file can not be opened.
+    pub fn with_file<P: AsRef<Path>>(level: LevelFilter, path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(BufWriter::new(file))),
+            ..Self::new(level)
+        })
+    }
+
+    fn format(&self, record: &Record<'_>) -> String {
+        let elapsed = self.start.elapsed();
+        let current = thread::current();
+        format!(
+            "[{:>5}.{:06}] {:<5} [{}] {}: {}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros(),
+            record.level(),
+            current.name().unwrap_or("<unnamed>"),
+            record.target(),
+            record.args()
+        )
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        // a poisoned lock or a failed write can not be logged, so they are ignored
+        let _: io::Result<()> = writeln!(io::stdout().lock(), "{line}");
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _: io::Result<()> = writeln!(file, "{line}");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _: io::Result<()> = io::stdout().lock().flush();
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _: io::Result<()> = file.flush();
+            }
+        }
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Set a `Logger` printing the records up to `level` as the global logger.
+/// If `path` is given, the records are appended to the file as well.
+///
+/// # Errors
+///
+/// Will return `Err` if the file can not be opened, or a global logger has been set.
+pub fn init_logging(level: LevelFilter, path: Option<&Path>) -> Result<(), LoggerError> {
+    let logger = match path {
+        Some(path) => Logger::with_file(level, path)?,
+        None => Logger::new(level),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use log::{Level, LevelFilter, Log, Record};
+
+    use super::Logger;
+
+    #[test]
+    fn test_log_to_file() {
+        let path = std::env::temp_dir().join(format!("rdma-logger-{}.log", std::process::id()));
+        let logger = Logger::with_file(LevelFilter::Info, &path).unwrap();
+        thread::Builder::new()
+            .name("logger-test".to_owned())
+            .spawn(move || {
+                for (level, msg) in [(Level::Info, "qp created"), (Level::Debug, "filtered")] {
+                    logger.log(
+                        &Record::builder()
+                            .level(level)
+                            .target("open_rdma_driver::qp")
+                            .args(format_args!("{msg}"))
+                            .build(),
+                    );
+                }
+                // the buffered records are written out when the logger is dropped
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("INFO  [logger-test] open_rdma_driver::qp: qp created"));
+    }
+}
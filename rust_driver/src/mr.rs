@@ -4,25 +4,28 @@ use crate::{
         ToCardCtrlRbDescUpdatePageTable,
     },
     responser::AcknowledgeBuffer,
-    types::{Key, MemAccessTypeFlag, PAGE_SIZE},
-    utils::HugePage,
+    op_ctx::IssuedCtrlOp,
+    pd::PdCtx,
+    types::{Key, MemAccessTypeFlag, Sge, PAGE_SIZE},
+    utils::{AlignedMemory, HugePage, HugePageBacking},
     Device, Error, Pd,
 };
-use log::debug;
+use log::{debug, error, warn};
 use rand::RngCore as _;
 use std::{
+    collections::HashMap,
     hash::{Hash, Hasher},
-    mem, ptr,
+    ptr,
     sync::Arc,
 };
 
 const ACKNOWLEDGE_BUFFER_SLOT_CNT: usize = 1024;
-const ACKNOWLEDGE_BUFFER_SIZE: usize =
+pub(crate) const ACKNOWLEDGE_BUFFER_SIZE: usize =
     ACKNOWLEDGE_BUFFER_SLOT_CNT * AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
 
 /// Memory Region
 ///
-/// User use `Device::alloc_mr(..)` to allocate a `Mr` and use `Device::dereg_mr(..)` to deallocate a `Mr`.
+/// User use `Device::reg_mr(..)` or `Device::alloc_and_reg_mr(..)` to allocate a `Mr` and use `Device::dereg_mr(..)` to deallocate a `Mr`.
 #[derive(Debug, Clone, Copy)]
 pub struct Mr {
     pub(crate) key: Key,
@@ -36,12 +39,53 @@ impl Mr {
     }
 }
 
+/// A registration of `Device::reg_mr_batch`
+#[derive(Debug, Clone, Copy)]
+pub struct RegMrRequest {
+    pd: Pd,
+    va: u64,
+    iova: u64,
+    len: u32,
+    pg_size: u32,
+    acc_flags: MemAccessTypeFlag,
+}
+
+impl RegMrRequest {
+    /// Register the `len` bytes at `va`, see `Device::reg_mr`
+    #[must_use]
+    pub fn new(pd: Pd, va: u64, len: u32, pg_size: u32, acc_flags: MemAccessTypeFlag) -> Self {
+        Self {
+            pd,
+            va,
+            iova: va,
+            len,
+            pg_size,
+            acc_flags,
+        }
+    }
+
+    /// Access the Mr by the addresses from `iova`, see `Device::reg_mr_iova`
+    #[must_use]
+    pub fn with_iova(mut self, iova: u64) -> Self {
+        self.iova = iova;
+        self
+    }
+}
+
+/// A registration whose control operations are submitted to the card
+struct PendingMr {
+    mr_idx: usize,
+    mr_ctx: MrCtx,
+    update_pgt_op: IssuedCtrlOp,
+    update_mr_op: IssuedCtrlOp,
+}
+
 #[derive(Debug)]
 pub(crate) struct MrCtx {
-    #[allow(unused)]
     pub(crate) key: Key,
     pub(crate) pd: Pd,
-    #[allow(unused)]
+    /// The base of the addresses the requests and the sges use to access the MR
+    pub(crate) iova: u64,
     pub(crate) va: u64,
     pub(crate) len: u32,
     #[allow(unused)]
@@ -63,8 +107,86 @@ struct MrPgtFreeBlk {
     next: *mut Self,
 }
 
+/// A snapshot of the MR page table, taken by `Device::dump_page_table` for debugging.
+#[cfg(any(test, feature = "debug-introspection"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PageTableDump {
+    /// The (index, physical address) pairs of the allocated entries, in the order of the index
+    pub entries: Vec<(usize, u64)>,
+    /// The (offset, len) pairs of the free blocks, in the order of the free list
+    pub free_blocks: Vec<(usize, usize)>,
+}
+
 impl Device {
-    fn register_page_table(&self, addr: u64, length: u32, pg_size: u32) -> Result<usize, Error> {
+    /// Take a snapshot of the MR page table, to check it after a series of `reg_mr` and `dereg_mr`.
+    ///
+    /// Only available with the `debug-introspection` feature.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the page table lock is poisoned.
+    #[cfg(any(test, feature = "debug-introspection"))]
+    pub fn dump_page_table(&self) -> Result<PageTableDump, Error> {
+        let mr_pgt = self
+            .0
+            .mr_pgt
+            .lock()
+            .map_err(|_| Error::LockPoisoned("mr_pgt lock"))?;
+        let free_blocks = mr_pgt.free_blocks();
+        let is_free = |idx: usize| {
+            free_blocks
+                .iter()
+                .any(|&(offset, len)| (offset..offset.saturating_add(len)).contains(&idx))
+        };
+        let entries = mr_pgt
+            .table
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| !is_free(idx))
+            .map(|(idx, addr)| (idx, *addr))
+            .collect();
+        Ok(PageTableDump {
+            entries,
+            free_blocks,
+        })
+    }
+
+    /// Use `resolver` instead of the adaptor to translate the addresses of the page table entries.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a resolver has already been set.
+    #[cfg(test)]
+    pub(crate) fn set_phys_addr_resolver(
+        &self,
+        resolver: Arc<dyn crate::device::PhysAddrResolver>,
+    ) -> Result<(), Error> {
+        self.0
+            .phys_addr_resolver
+            .set(resolver)
+            .map_err(|_| Error::Invalid("physical address resolver already set".to_owned()))
+    }
+
+    fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, Error> {
+        match self.0.phys_addr_resolver.get() {
+            Some(resolver) => resolver.get_phys_addr(virt_addr),
+            None => self.0.adaptor.get_phys_addr(virt_addr),
+        }
+        .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))
+    }
+
+    /// Allocate and fill the page table entries of the `length` bytes at `addr`, and build the
+    /// descriptor of the control operation `op_id` that updates them to the card.
+    ///
+    /// Returns the offset of the entries and the descriptor.
+    fn alloc_page_table(
+        &self,
+        op_id: u32,
+        addr: u64,
+        length: u32,
+        pg_size: u32,
+    ) -> Result<(usize, ToCardCtrlRbDesc), Error> {
         let mut mr_pgt = self
             .0
             .mr_pgt
@@ -72,24 +194,51 @@ impl Device {
             .map_err(|_| Error::LockPoisoned("MR page table lock"))?;
         let pgte_cnt = length.div_ceil(pg_size) as usize;
         let pgt_offset = mr_pgt.alloc(pgte_cnt)?;
-        debug!("==============2-1-1-1-1");
+        if let Err(e) = self.fill_page_table(&mut mr_pgt, pgt_offset, addr, pgte_cnt, pg_size) {
+            mr_pgt.dealloc(pgt_offset, pgte_cnt);
+            return Err(e);
+        }
+        let start_addr = match self.get_phys_addr(mr_pgt.table.as_ptr() as usize) {
+            Ok(start_addr) => start_addr,
+            Err(e) => {
+                mr_pgt.dealloc(pgt_offset, pgte_cnt);
+                return Err(e);
+            }
+        };
+        // `pgt_offset` and `pg_size` are both derived from pg_size, which is a u32. So it's safe to covert
+        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
+        let update_pgt_desc = ToCardCtrlRbDesc::UpdatePageTable(ToCardCtrlRbDescUpdatePageTable {
+            common: ToCardCtrlRbDescCommon { op_id },
+            start_addr: start_addr as u64 + pgt_offset as u64 * 8,
+            pgt_idx: pgt_offset as u32,
+            pgte_cnt: pgte_cnt as u32,
+        });
+        Ok((pgt_offset, update_pgt_desc))
+    }
+
+    /// Fill `pgte_cnt` entries from `pgt_offset` with the physical addresses of the pages from `addr`
+    fn fill_page_table(
+        &self,
+        mr_pgt: &mut MrPgt,
+        pgt_offset: usize,
+        addr: u64,
+        pgte_cnt: usize,
+        pg_size: u32,
+    ) -> Result<(), Error> {
         for pgt_idx in 0..pgte_cnt {
             let va = addr.wrapping_add(((pg_size as usize).wrapping_mul(pgt_idx)) as u64);
             // Should we support 32 bit system?
             let va_in_usize =
                 usize::try_from(va).map_err(|_| Error::NotSupport("32 bit System"))?;
-            let pa = self
-                .0
-                .adaptor
-                .get_phys_addr(va_in_usize)
-                .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))?;
+            let pa = self.get_phys_addr(va_in_usize)?;
             debug!("==============2-1-1-1-2");
             // If we run with hardware DMA,
             // we must make sure va and pa are all allign to pg_size
-            if va_in_usize & (PAGE_SIZE - 1) != 0 {
+            let pg_mask = (pg_size as usize).wrapping_sub(1);
+            if va_in_usize & pg_mask != 0 {
                 return Err(Error::AddressNotAlign("va", va_in_usize));
             }
-            if pa & (PAGE_SIZE - 1) != 0 {
+            if pa & pg_mask != 0 {
                 return Err(Error::AddressNotAlign("pa", pa));
             }
             // `mr_pgt.alloc(pgte_cnt)` has already checked that `pgt_offset + pgt_idx` is in range
@@ -98,37 +247,7 @@ impl Device {
                 mr_pgt.table[pgt_offset + pgt_idx] = pa as u64;
             }
         }
-        debug!("==============2-1-1-1-3");
-        let update_pgt_op_id = self.get_ctrl_op_id();
-        debug!("==============2-1-1-1-4");
-        // `pgt_offset` and `pg_size` are both derived from pg_size, which is a u32. So it's safe to covert
-        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
-        let update_pgt_desc = ToCardCtrlRbDesc::UpdatePageTable(ToCardCtrlRbDescUpdatePageTable {
-            common: ToCardCtrlRbDescCommon {
-                op_id: update_pgt_op_id,
-            },
-            start_addr: self
-                .0
-                .adaptor
-                .get_phys_addr(mr_pgt.table.as_ptr() as usize)
-                .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))?
-                as u64
-                + pgt_offset as u64 * 8,
-            pgt_idx: pgt_offset as u32,
-            pgte_cnt: pgte_cnt as u32,
-        });
-        debug!("==============2-1-1-1-5");
-        let update_pgt_ctx = self.do_ctrl_op(update_pgt_op_id, update_pgt_desc)?;
-        debug!("==============2-1-1-1-6");
-        let update_pgt_result = update_pgt_ctx
-            .wait_result()?
-            .ok_or(Error::SetCtxResultFailed)?;
-        debug!("==============2-1-1-1-7");
-        if !update_pgt_result {
-            mr_pgt.dealloc(pgt_offset, pgte_cnt);
-            return Err(Error::DeviceReturnFailed("update page table"));
-        }
-        Ok(pgt_offset)
+        Ok(())
     }
 
     fn deregister_page_table(&self, pgt_offset: usize, length: u32) -> Result<(), Error> {
@@ -146,14 +265,30 @@ impl Device {
         Ok(())
     }
 
+    /// Release the page table entries of a registration failed with `err`, and return `err`.
+    ///
+    /// A failure to release them is logged rather than returned, so it does not hide why the
+    /// registration failed.
+    fn release_failed_page_table(&self, pgt_offset: usize, length: u32, err: Error) -> Error {
+        if let Err(e) = self.deregister_page_table(pgt_offset, length) {
+            error!("failed to release the page table of a failed MR registration: {e}");
+        }
+        err
+    }
+
     /// Register a Mr
     ///
+    /// The remote and the local sges access the Mr by `addr`, see `reg_mr_iova` to access it by
+    /// another address.
+    ///
     /// # Errors
     ///
     /// Will return `Err` if:
     /// * lock poisoned
     /// * not have enough resouce to allocate a new pagetable
     /// * invalid pd
+    /// * `pg_size` is not a power of two or the device does not support it
+    /// * `addr` or the physical address of a page is not aligned to `pg_size`
     /// * failed to communicate with card(including creating page table and creating mr)
     pub fn reg_mr(
         &self,
@@ -163,102 +298,254 @@ impl Device {
         pg_size: u32,
         acc_flags: MemAccessTypeFlag,
     ) -> Result<Mr, Error> {
+        self.reg_mr_iova(pd, addr, addr, len, pg_size, acc_flags)
+    }
+
+    /// Register the `len` bytes at `va` as a Mr, which is accessed by the addresses from `iova`
+    ///
+    /// The RETH of a remote request and the local sges address the Mr by `iova`, e.g. a Mr
+    /// registered with `iova` 0 is accessed by the offsets in it, regardless of where it is mapped in
+    /// the process.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * not have enough resouce to allocate a new pagetable
+    /// * invalid pd
+    /// * `pg_size` is not a power of two or the device does not support it
+    /// * `va`, `iova` or the physical address of a page is not aligned to `pg_size`
+    /// * failed to communicate with card(including creating page table and creating mr)
+    pub fn reg_mr_iova(
+        &self,
+        pd: Pd,
+        va: u64,
+        iova: u64,
+        len: u32,
+        pg_size: u32,
+        acc_flags: MemAccessTypeFlag,
+    ) -> Result<Mr, Error> {
+        let request = RegMrRequest::new(pd, va, len, pg_size, acc_flags).with_iova(iova);
+        self.reg_mr_batch(&[request])?
+            .pop()
+            .unwrap_or(Err(Error::SetCtxResultFailed))
+    }
+
+    /// Register several Mrs at once
+    ///
+    /// The control operations of all the registrations are submitted before waiting for any of them,
+    /// rather than waiting for the page table and the MR table of each Mr in turn. A registration
+    /// fails independently of the others, so a result is returned for each request in order.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a lock is poisoned. The result of a request is `Err` for the reasons of
+    /// `reg_mr_iova`.
+    pub fn reg_mr_batch(&self, requests: &[RegMrRequest]) -> Result<Vec<Result<Mr, Error>>, Error> {
         // FIXME: must call mlock to lock the pages, prevent form being swapped out.
         let mut mr_table = self
             .0
             .mr_table
             .lock()
             .map_err(|_| Error::LockPoisoned("MR table lock"))?;
-
         let mut pd_pool = self
             .0
             .pd
             .lock()
             .map_err(|_| Error::LockPoisoned("Pd table lock"))?;
 
+        let mut reserved = Vec::with_capacity(requests.len());
+        let submissions: Vec<_> = requests
+            .iter()
+            .map(|request| self.submit_reg_mr(&mr_table[..], &pd_pool, &mut reserved, request))
+            .collect();
+        let results = submissions
+            .into_iter()
+            .map(|submission| {
+                let pending = submission?;
+                let mr_idx = pending.mr_idx;
+                let mr_ctx = self.wait_reg_mr(pending)?;
+                let mr = Mr { key: mr_ctx.key };
+                let pd_ctx = pd_pool
+                    .get_mut(&mr_ctx.pd)
+                    .ok_or(Error::Invalid(format!("PD :{:?}", mr_ctx.pd)))?;
+                if !pd_ctx.mr.insert(mr) {
+                    return Err(Error::Invalid(format!("mr :{mr:?}")));
+                }
+                // `mr_idx` is found in the table by `submit_reg_mr`
+                #[allow(clippy::indexing_slicing)]
+                {
+                    mr_table[mr_idx] = Some(mr_ctx);
+                }
+                Ok(mr)
+            })
+            .collect();
+        Ok(results)
+    }
+
+    /// Allocate a free entry of `mr_table` other than the `reserved` ones and the page table entries
+    /// for `request`, and submit the control operations to update them to the card.
+    fn submit_reg_mr(
+        &self,
+        mr_table: &[Option<MrCtx>],
+        pd_pool: &HashMap<Pd, PdCtx>,
+        reserved: &mut Vec<usize>,
+        request: &RegMrRequest,
+    ) -> Result<PendingMr, Error> {
+        let RegMrRequest {
+            pd,
+            va,
+            iova,
+            len,
+            pg_size,
+            acc_flags,
+        } = *request;
+        if !pg_size.is_power_of_two() || !self.0.adaptor.is_page_size_supported(pg_size) {
+            return Err(Error::Invalid(format!("page size {pg_size}")));
+        }
+        // the page table maps the pages from `iova` to the pages from `va`, which is checked to be aligned
+        // when the page table is filled
+        let pg_mask = u64::from(pg_size.wrapping_sub(1));
+        if iova & pg_mask != va & pg_mask {
+            let iova = usize::try_from(iova).map_err(|_| Error::NotSupport("32 bit System"))?;
+            return Err(Error::AddressNotAlign("iova", iova));
+        }
+        if !pd_pool.contains_key(&pd) {
+            return Err(Error::Invalid(format!("PD :{pd:?}")));
+        }
         let Some(mr_idx) = mr_table
             .iter()
             .enumerate()
-            .find_map(|(idx, ctx)| ctx.is_none().then_some(idx))
+            .find_map(|(idx, ctx)| (ctx.is_none() && !reserved.contains(&idx)).then_some(idx))
         else {
             return Err(Error::ResourceNoAvailable("MR".to_owned()));
         };
 
-        let pd_ctx = pd_pool
-            .get_mut(&pd)
-            .ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
-
-        debug!("==============2-1-1-1");
-        let pgt_offset = self.register_page_table(addr, len, pg_size)?;
-        debug!("==============2-1-1-2");
-        // mr_idx is smaller than `MR_TABLE_SIZE`. Currently, it's a relatively small number.
-        // And it's expected to smaller than 2^32 during transimission
-        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
-        let key_idx = (mr_idx as u32) << (mem::size_of::<u32>() * 8 - crate::MR_KEY_IDX_BIT_CNT);
-        let key_secret = rand::thread_rng().next_u32() >> crate::MR_KEY_IDX_BIT_CNT;
-        let key = Key::new(key_idx | key_secret);
+        let update_pgt_op_id = self.get_ctrl_op_id();
+        let (pgt_offset, update_pgt_desc) =
+            self.alloc_page_table(update_pgt_op_id, va, len, pg_size)?;
+        let pgte_cnt = len.div_ceil(pg_size);
+        let update_pgt_op = match self.do_ctrl_op(update_pgt_op_id, update_pgt_desc) {
+            Ok(ctx) => ctx,
+            Err(e) => return Err(self.release_failed_page_table(pgt_offset, pgte_cnt, e)),
+        };
 
-        let mr = Mr { key };
+        // mr_idx is smaller than `MR_TABLE_SIZE`, which fits in the index of a key
+        #[allow(clippy::cast_possible_truncation)]
+        let key = Key::from_index_secret(mr_idx as u32, rand::thread_rng().next_u32());
         let mr_ctx = MrCtx {
             key,
             pd,
-            va: addr,
+            iova,
+            va,
             len,
             acc_flags,
             pgt_offset,
             pg_size,
         };
-
         let update_mr_op_id = self.get_ctrl_op_id();
-        debug!("==============2-1-1-3");
         #[allow(clippy::cast_possible_truncation)]
         let update_mr_desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
             common: ToCardCtrlRbDescCommon {
                 op_id: update_mr_op_id,
             },
-            addr,
+            addr: iova,
+            va,
             len,
             key,
-            pd_hdl: mr_ctx.pd.handle,
+            pd_hdl: pd.handle,
             acc_flags,
             pgt_offset: pgt_offset as u32,
         });
-        debug!("==============2-1-1-4");
-        let update_mr_ctx = self.do_ctrl_op(update_mr_op_id, update_mr_desc)?;
-        debug!("==============2-1-1-5");
-        let update_mr_result = update_mr_ctx
-            .wait_result()?
-            .ok_or(Error::SetCtxResultFailed)?;
-        debug!("==============2-1-1-6");
-        if !update_mr_result {
-            return Err(Error::DeviceReturnFailed("register mr table"));
-        }
-
-        #[allow(clippy::indexing_slicing)]
-        // `mr_idx` is allocated by `find_map` above, so it's safe to index
-        {
-            mr_table[mr_idx] = Some(mr_ctx);
-        }
+        let update_mr_op = match self.do_ctrl_op(update_mr_op_id, update_mr_desc) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                // the card may still be reading the entries
+                let _: Result<_, _> = update_pgt_op.ctx.wait();
+                return Err(self.release_failed_page_table(pgt_offset, pgte_cnt, e));
+            }
+        };
+        reserved.push(mr_idx);
+        Ok(PendingMr {
+            mr_idx,
+            mr_ctx,
+            update_pgt_op,
+            update_mr_op,
+        })
+    }
 
-        if !pd_ctx.mr.insert(mr) {
-            return Err(Error::Invalid(format!("mr :{mr:?}")));
-        }
+    /// Wait for the control operations of a registration, and release its page table entries if
+    /// any of them fails.
+    fn wait_reg_mr(&self, pending: PendingMr) -> Result<MrCtx, Error> {
+        let update_pgt_result = pending.update_pgt_op.wait_success("update page table");
+        let update_mr_result = pending.update_mr_op.wait_success("register mr table");
+        let err = match (update_pgt_result, update_mr_result) {
+            (Ok(()), Ok(())) => return Ok(pending.mr_ctx),
+            (Err(e), _) | (Ok(()), Err(e)) => e,
+        };
+        let mr_ctx = pending.mr_ctx;
+        let pgte_cnt = mr_ctx.len.div_ceil(mr_ctx.pg_size);
+        Err(self.release_failed_page_table(mr_ctx.pgt_offset, pgte_cnt, err))
+    }
 
-        Ok(mr)
+    /// Allocate `size` bytes of memory aligned to `PAGE_SIZE` and register it as a Mr
+    ///
+    /// The memory is returned with the Mr, so it lives as long as the caller keeps the Mr in use. Use
+    /// `reg_mr` to register memory managed by the caller.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `size` is zero or larger than `u32::MAX`
+    /// * failed to allocate the memory
+    /// * failed to register the Mr, see `reg_mr`
+    pub fn alloc_and_reg_mr(
+        &self,
+        pd: Pd,
+        size: usize,
+        acc_flags: MemAccessTypeFlag,
+    ) -> Result<(Mr, AlignedMemory), Error> {
+        let len = u32::try_from(size)
+            .ok()
+            .filter(|len| *len != 0)
+            .ok_or_else(|| Error::Invalid(format!("MR size :{size}")))?;
+        let buffer = AlignedMemory::new(size)
+            .map_err(|e| Error::ResourceNoAvailable(format!("aligned memory {e}")))?;
+        // the `PAGE_SIZE` is guaranteed to smaller than u32
+        #[allow(clippy::cast_possible_truncation)]
+        let mr = self.reg_mr(
+            pd,
+            buffer.as_ptr() as u64,
+            len,
+            PAGE_SIZE as u32,
+            acc_flags,
+        )?;
+        Ok((mr, buffer))
     }
 
-    pub(crate) fn init_ack_buf(&self) -> Result<Arc<AcknowledgeBuffer>, Error> {
-        let buffer = HugePage::new(ACKNOWLEDGE_BUFFER_SIZE)
+    pub(crate) fn init_ack_buf(&self, size: usize) -> Result<Arc<AcknowledgeBuffer>, Error> {
+        let len = u32::try_from(size)
+            .map_err(|_| Error::Invalid(format!("acknowledge buffer size {size:#x}")))?;
+        let buffer = HugePage::new_or_fallback(size)
             .map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
+        if buffer.backing() == HugePageBacking::Regular {
+            // only the adaptors reading the buffer by the virtual addresses can work with normal pages
+            if self.0.adaptor.requires_hugepages() {
+                return Err(Error::ResourceNoAvailable(
+                    "huge pages for the acknowledge buffer".to_owned(),
+                ));
+            }
+            warn!("acknowledge buffer is not backed by huge pages");
+        }
         let buffer_addr = buffer.as_ptr() as usize;
         let pd = self.alloc_pd()?;
         debug!("==============2-1-1");
-        // the `PAGE_SIZE` and `ACKNOWLEDGE_BUFFER_SIZE` is guaranteed to smaller than u32
+        // the `PAGE_SIZE` is guaranteed to smaller than u32
         #[allow(clippy::cast_possible_truncation)]
         let create_mr_result = self.reg_mr(
             pd,
             u64::try_from(buffer_addr).map_err(|_| Error::NotSupport("Not 64 bit System"))?,
-            ACKNOWLEDGE_BUFFER_SIZE as u32,
+            len,
             PAGE_SIZE as u32, // 2MB
             MemAccessTypeFlag::IbvAccessLocalWrite
                 | MemAccessTypeFlag::IbvAccessRemoteRead
@@ -267,19 +554,97 @@ impl Device {
         debug!("==============2-1-2");
         match create_mr_result {
             Ok(mr) => {
-                let ack_buf = AcknowledgeBuffer::new_with_buf(buffer, mr.get_key());
+                let ack_buf = AcknowledgeBuffer::new_with_buf(buffer, size, mr.get_key());
                 Ok(ack_buf)
             }
             Err(e) => Err(e),
         }
     }
 
+    /// Check that every sge fits in the Mr of its key
+    ///
+    /// The zero key stands for no Mr and is not checked, any other key must be registered on this device.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * a nonzero key is not registered, or its secret does not match the Mr at its index
+    /// * a sge exceeds its Mr
+    pub(crate) fn check_sge_bounds(&self, sges: &[Sge]) -> Result<(), Error> {
+        let mr_table = self
+            .0
+            .mr_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("mr_table lock"))?;
+        for sge in sges {
+            let mr_idx = sge.key.index();
+            let mr_ctx = match mr_table.get(mr_idx as usize) {
+                Some(Some(mr_ctx)) if mr_ctx.key == sge.key => mr_ctx,
+                // the zero key stands for no Mr
+                _ if sge.key == Key::default() => continue,
+                _ => return Err(Error::InvalidKey(sge.key)),
+            };
+            let mr_end = mr_ctx.iova.checked_add(u64::from(mr_ctx.len));
+            let sge_end = sge.addr.checked_add(u64::from(sge.len));
+            let in_bounds = matches!((mr_end, sge_end), (Some(mr_end), Some(sge_end))
+                if sge.addr >= mr_ctx.iova && sge_end <= mr_end);
+            if !in_bounds {
+                return Err(Error::SgeOutOfBounds {
+                    addr: sge.addr,
+                    len: sge.len,
+                    key: sge.key,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the payload of `sges`, which are checked by `check_sge_bounds`, into a buffer in their order
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * a nonzero key is not registered, or its secret does not match the Mr at its index
+    pub(crate) fn stage_sges(&self, sges: &[Sge]) -> Result<Box<[u8]>, Error> {
+        let mr_table = self
+            .0
+            .mr_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("mr_table lock"))?;
+        let mut staged = Vec::new();
+        for sge in sges {
+            let va = match mr_table.get(sge.key.index() as usize) {
+                Some(Some(mr_ctx)) if mr_ctx.key == sge.key => {
+                    mr_ctx.va.wrapping_add(sge.addr.wrapping_sub(mr_ctx.iova))
+                }
+                // the zero key stands for no Mr
+                _ if sge.key == Key::default() => sge.addr,
+                _ => return Err(Error::InvalidKey(sge.key)),
+            };
+            let start = staged.len();
+            staged.resize(start.wrapping_add(sge.len as usize), 0);
+            // the sge is in its Mr, which is not deregistered while the table is locked. The memory is
+            // copied without a reference to it, since the card may write to it meanwhile.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    va as *const u8,
+                    staged.as_mut_ptr().wrapping_add(start),
+                    sge.len as usize,
+                );
+            }
+        }
+        Ok(staged.into_boxed_slice())
+    }
+
     /// Remove a Mr
     ///
     /// # Errors
     ///
     /// Will return `Err` if:
     /// * lock poisoned
+    /// * the key of `mr` does not match the Mr registered at its index
     /// * failed to communicate with card(including remove page table and remove mr)
     /// * Operating system not support
     /// * Setted context result failed
@@ -294,13 +659,13 @@ impl Device {
             .pd
             .lock()
             .map_err(|_| Error::LockPoisoned("pd table lock"))?;
-        #[allow(clippy::arithmetic_side_effects)]
-        let mr_idx = mr.key.get() >> (mem::size_of::<u32>() * 8 - crate::MR_KEY_IDX_BIT_CNT);
+        let mr_idx = mr.key.index();
         let ctx_option = mr_table
             .get_mut(mr_idx as usize)
-            .ok_or(Error::Invalid(format!("MR :{mr_idx}")))?;
-        let Some(mr_ctx) = ctx_option else {
-            return Err(Error::Invalid(format!("MR :{mr_idx}")));
+            .ok_or(Error::InvalidKey(mr.key))?;
+        // a stale key of a reused slot must not deregister the Mr in it
+        let Some(mr_ctx) = ctx_option.as_mut().filter(|ctx| ctx.key == mr.key) else {
+            return Err(Error::InvalidKey(mr.key));
         };
 
         let pd_ctx = pd_pool
@@ -312,6 +677,7 @@ impl Device {
         let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
             common: ToCardCtrlRbDescCommon { op_id },
             addr: 0,
+            va: 0,
             len: 0,
             key: mr.key,
             pd_hdl: 0,
@@ -319,13 +685,7 @@ impl Device {
             pgt_offset: 0,
         });
 
-        let ctx = self.do_ctrl_op(op_id, desc)?;
-
-        let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
-
-        if !res {
-            return Err(Error::DeviceReturnFailed("deregister mr table"));
-        }
+        self.do_ctrl_op(op_id, desc)?.wait_success("deregister mr table")?;
 
         self.deregister_page_table(mr_ctx.pgt_offset, mr_ctx.len.div_ceil(mr_ctx.pg_size))?;
 
@@ -353,6 +713,24 @@ impl MrPgt {
         }
     }
 
+    /// The `cnt` entries from `offset`
+    #[cfg(test)]
+    pub(crate) fn entries(&self, offset: usize, cnt: usize) -> &[u64] {
+        &self.table[offset..offset.saturating_add(cnt)]
+    }
+
+    /// The (offset, len) pairs of the free blocks, in the order of the free list
+    #[cfg(any(test, feature = "debug-introspection"))]
+    fn free_blocks(&self) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut ptr = self.free_blk_list;
+        while let Some(blk) = unsafe { ptr.as_ref() } {
+            blocks.push((blk.idx, blk.len));
+            ptr = blk.next;
+        }
+        blocks
+    }
+
     #[allow(clippy::arithmetic_side_effects)]
     fn alloc(&mut self, len: usize) -> Result<usize, Error> {
         let mut ptr = self.free_blk_list;
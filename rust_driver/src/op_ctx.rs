@@ -1,11 +1,22 @@
 use std::{
-    sync::{Arc, Mutex, OnceLock},
-    thread::{self, Thread},
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 
 
-use crate::Error;
+use crate::{
+    device::{StagedPayload, ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode},
+    types::{Msn, Psn, Qpn},
+    utils::PmtuFragments,
+    Error,
+};
 
 /// The status of operations.
 #[non_exhaustive]
@@ -20,6 +31,10 @@ pub enum CtxStatus {
     Failed(&'static str),
     /// The operation is finished.
     Finished,
+    /// The wait for the operation timed out, the operation is still running.
+    TimedOut,
+    /// The operation is cancelled, its result is dropped if it arrives later.
+    Cancelled,
 }
 
 /// The operation context.
@@ -34,38 +49,368 @@ pub struct OpCtx<Payload>(Arc<OpCtxWrapper<Payload>>);
 #[derive(Debug)]
 struct OpCtxWrapper<Payload> {
     inner: Mutex<OpCtxInner>,
+    /// Wakes up all the threads waiting for the operation when it stops running
+    done: Condvar,
     payload: OnceLock<Payload>,
+    /// The time the first descriptor of the operation is pushed to the work ring of the adaptor
+    submitted_at: OnceLock<Instant>,
+    /// The time the result of the operation is set
+    finished_at: OnceLock<Instant>,
 }
 
 #[derive(Debug)]
 struct OpCtxInner {
-    thread: Option<Thread>,
     status: CtxStatus,
+    /// The span that is closed when the operation is finished
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::Span>,
+    /// Removes the operation from the map it is pending in when it is cancelled
+    reclaim: Option<Reclaim>,
+}
+
+/// The hook run by `OpCtx::cancel`
+struct Reclaim(Box<dyn FnOnce() -> Result<(), Error> + Send>);
+
+impl fmt::Debug for Reclaim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Reclaim")
+    }
 }
 
 /// The control command operation context.
 #[allow(clippy::module_name_repetitions)]
 pub type CtrlOpCtx = OpCtx<bool>; // `is_sucess`
 
+/// A control command issued to the device, with the op id and the summary of its descriptor to
+/// tell its failure apart from the others.
+#[derive(Debug)]
+pub(crate) struct IssuedCtrlOp {
+    pub(crate) op_id: u32,
+    pub(crate) desc: String,
+    pub(crate) ctx: CtrlOpCtx,
+}
+
+impl IssuedCtrlOp {
+    /// Wait for the device to answer the command, and fail with `Error::DeviceReturnFailed` naming
+    /// `op` if the device reports a failure.
+    pub(crate) fn wait_success(&self, op: &'static str) -> Result<(), Error> {
+        let is_success = self.ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
+        if *is_success {
+            Ok(())
+        } else {
+            Err(Error::DeviceReturnFailed {
+                op,
+                op_id: self.op_id,
+                desc: self.desc.clone(),
+            })
+        }
+    }
+}
+
 /// The write command operation context.
 #[allow(clippy::module_name_repetitions)]
-pub type WriteOpCtx = OpCtx<()>;
+pub type WriteOpCtx = OpCtx<OpResult>;
 
 /// The read command operation context.
 #[allow(clippy::module_name_repetitions)]
-pub type ReadOpCtx = OpCtx<()>;
+pub type ReadOpCtx = OpCtx<OpResult>;
+
+/// The completion status of a write or read operation.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionStatus {
+    /// The operation is completed successfully.
+    Success,
+    /// The length of the message does not match the local buffer, such as a short read response.
+    LocalLengthError,
+    /// The remote lost some packets of the operation and could not recover them.
+    SequenceError,
+    /// The remote rejected the request as invalid, such as an unsupported opcode.
+    RemoteInvalidRequest,
+    /// The remote memory region does not exist, or does not allow the access.
+    RemoteAccessError,
+    /// The remote failed to complete the operation for a reason unrelated to the request.
+    RemoteOpError,
+    /// The remote had no receive buffer for the message.
+    RnrRetryExceeded,
+    /// The remote reported an error this driver does not know.
+    GeneralError,
+    /// No response arrived within the ack timeout of the QP.
+    TimedOut,
+}
+
+impl CompletionStatus {
+    /// Map the code and the value of a negative AETH to the status of the operation it completes.
+    pub(crate) fn from_aeth(code: &ToHostWorkRbDescAethCode, value: u8) -> Self {
+        match (code, value) {
+            (ToHostWorkRbDescAethCode::Ack, _) => Self::Success,
+            (ToHostWorkRbDescAethCode::Rnr, _) => Self::RnrRetryExceeded,
+            (ToHostWorkRbDescAethCode::Nak, 0) => Self::SequenceError,
+            (ToHostWorkRbDescAethCode::Nak, 1) => Self::RemoteInvalidRequest,
+            (ToHostWorkRbDescAethCode::Nak, 2) => Self::RemoteAccessError,
+            (ToHostWorkRbDescAethCode::Nak, 3) => Self::RemoteOpError,
+            (ToHostWorkRbDescAethCode::Nak | ToHostWorkRbDescAethCode::Rsvd, _) => {
+                Self::GeneralError
+            }
+        }
+    }
+}
+
+/// The result of a finished write or read operation.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpResult {
+    /// The number of bytes written or read by the operation, which are covered by the ACK of a write
+    /// or carried by the read responses of a read.
+    ///
+    /// A failed operation reports the length of its message.
+    pub byte_len: u32,
+    /// The opcode that completes the operation, such as the ACK of a write or the last read response.
+    ///
+    /// A write on an unreliable QP finishes once it is sent, so it is the opcode of its last packet.
+    pub opcode: ToHostWorkRbDescOpcode,
+    /// Whether the operation succeeded, or why it failed
+    pub status: CompletionStatus,
+}
+
+/// The pending operations, keyed by the QP and the MSN of their messages
+pub(crate) type PendingOpMap = HashMap<(Qpn, Msn), PendingOp>;
+
+/// A write or read operation waiting for its completion, with the length of its message.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingOp {
+    ctx: OpCtx<OpResult>,
+    byte_len: u32,
+    /// The time the operation fails with `CompletionStatus::TimedOut` if it is still pending
+    deadline: Option<Instant>,
+    /// The messages sharing the context, if the operation is one of them
+    repeated: Option<Arc<RepeatedOps>>,
+    /// The psn of the first packet and the packets of the message, to count the bytes an ACK covers
+    packets: Option<(Psn, PmtuFragments)>,
+    /// The payload gathered by the driver, which the card may read again until the message is
+    /// acknowledged
+    staged: Option<StagedPayload>,
+}
+
+/// The messages of an operation issued repeatedly, see `Device::write_repeated`. They share a context
+/// which is finished once all of them are completed, or at once if one of them fails.
+#[derive(Debug)]
+pub(crate) struct RepeatedOps {
+    /// The messages not completed yet
+    remaining: AtomicU32,
+    /// The bytes reported by the completed messages, which may add up beyond `u32::MAX`
+    completed_len: AtomicU64,
+    /// Whether the shared context is finished, by the last message to succeed or the first to fail
+    finished: AtomicBool,
+}
+
+impl RepeatedOps {
+    /// `count` messages
+    pub(crate) fn new(count: u32) -> Self {
+        Self {
+            remaining: AtomicU32::new(count),
+            completed_len: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        }
+    }
+}
+
+impl PendingOp {
+    pub(crate) fn new(ctx: OpCtx<OpResult>, byte_len: u32) -> Self {
+        Self {
+            ctx,
+            byte_len,
+            deadline: None,
+            repeated: None,
+            packets: None,
+            staged: None,
+        }
+    }
+
+    /// Count the bytes acknowledged by an ACK with the `packets` of the message, whose first psn is
+    /// `first_psn`, instead of taking the whole message as acknowledged.
+    pub(crate) fn with_packets(mut self, first_psn: Psn, packets: PmtuFragments) -> Self {
+        self.packets = Some((first_psn, packets));
+        self
+    }
+
+    /// The number of bytes in the packets up to `psn`, or the length of the message if its packets are
+    /// unknown.
+    pub(crate) fn acked_len(&self, psn: Psn) -> u32 {
+        let Some((first_psn, packets)) = &self.packets else {
+            return self.byte_len;
+        };
+        // an ACK before the first packet covers nothing
+        let Ok(acked_cnt) = usize::try_from(first_psn.distance_to(psn).saturating_add(1)) else {
+            return 0;
+        };
+        packets
+            .clone()
+            .take(acked_cnt)
+            .fold(0_u32, |len, packet| len.saturating_add(packet.len))
+    }
+
+    /// Keep the `staged` payload that the descriptors of the message point to.
+    pub(crate) fn with_staged(mut self, staged: Option<StagedPayload>) -> Self {
+        self.staged = staged;
+        self
+    }
+
+    /// Share the context with the other messages of `repeated`.
+    pub(crate) fn with_repeated(mut self, repeated: Arc<RepeatedOps>) -> Self {
+        self.repeated = Some(repeated);
+        self
+    }
+
+    /// Fail the operation if no response arrives within `timeout`, or wait forever if it is `None`.
+    pub(crate) fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.and_then(|duration| Instant::now().checked_add(duration));
+        self
+    }
+
+    /// The time the operation fails if it is still pending
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the operation is still pending at `now` after its deadline
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Finish the operation, which is completed by a packet of `opcode` reporting `byte_len` bytes.
+    pub(crate) fn finish(&self, opcode: ToHostWorkRbDescOpcode, byte_len: u32) -> Result<(), Error> {
+        self.complete(opcode, CompletionStatus::Success, byte_len)
+    }
+
+    /// The context the user waits on
+    pub(crate) fn ctx(&self) -> &OpCtx<OpResult> {
+        &self.ctx
+    }
+
+    /// The length of the message of the operation
+    pub(crate) fn byte_len(&self) -> u32 {
+        self.byte_len
+    }
+
+    /// Finish the operation with a length error, since `byte_len` bytes are transferred instead of the
+    /// length of the message.
+    pub(crate) fn finish_with_length_error(
+        &self,
+        opcode: ToHostWorkRbDescOpcode,
+        byte_len: u32,
+    ) -> Result<(), Error> {
+        self.ctx.set_result(OpResult {
+            byte_len,
+            opcode,
+            status: CompletionStatus::LocalLengthError,
+        })
+    }
+
+    /// Finish the operation with `status`, which is reported by a packet of `opcode`.
+    pub(crate) fn finish_with_status(
+        &self,
+        opcode: ToHostWorkRbDescOpcode,
+        status: CompletionStatus,
+    ) -> Result<(), Error> {
+        self.complete(opcode, status, self.byte_len)
+    }
+
+    /// Set the result of the operation to `status` and the `byte_len` bytes reported by its completion.
+    ///
+    /// A message of a repeated operation finishes the shared context if it's the last one to
+    /// succeed, with the bytes of all the messages, or the first one to fail.
+    fn complete(
+        &self,
+        opcode: ToHostWorkRbDescOpcode,
+        status: CompletionStatus,
+        byte_len: u32,
+    ) -> Result<(), Error> {
+        let Some(repeated) = &self.repeated else {
+            return self.ctx.set_result(OpResult {
+                byte_len,
+                opcode,
+                status,
+            });
+        };
+        let byte_len = if matches!(status, CompletionStatus::Success) {
+            let _: u64 = repeated
+                .completed_len
+                .fetch_add(u64::from(byte_len), Ordering::AcqRel);
+            if repeated.remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+                return Ok(());
+            }
+            // the other messages have added their bytes before they decrease `remaining`
+            let completed_len = repeated.completed_len.load(Ordering::Acquire);
+            u32::try_from(completed_len).unwrap_or(u32::MAX)
+        } else {
+            byte_len
+        };
+        // an earlier message has failed the context
+        if repeated.finished.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.ctx.set_result(OpResult {
+            byte_len,
+            opcode,
+            status,
+        })
+    }
+}
+
+/// The deadlines of the pending operations of a `PendingOpMap`, in the order they expire.
+///
+/// An entry is left here when its operation completes, and dropped once its deadline passes.
+#[derive(Debug, Default)]
+pub(crate) struct OpDeadlines(Mutex<BTreeMap<Instant, Vec<(Qpn, Msn)>>>);
+
+impl OpDeadlines {
+    /// Expire the operation of `key` at `deadline`, if it has one.
+    pub(crate) fn insert(&self, key: (Qpn, Msn), deadline: Option<Instant>) -> Result<(), Error> {
+        if let Some(deadline) = deadline {
+            self.0
+                .lock()
+                .map_err(|_| Error::LockPoisoned("op deadlines lock"))?
+                .entry(deadline)
+                .or_default()
+                .push(key);
+        }
+        Ok(())
+    }
+
+    /// Take the keys of the operations whose deadlines are not after `now`.
+    pub(crate) fn take_expired(&self, now: Instant) -> Result<Vec<(Qpn, Msn)>, Error> {
+        let mut guard = self
+            .0
+            .lock()
+            .map_err(|_| Error::LockPoisoned("op deadlines lock"))?;
+        let mut expired = Vec::new();
+        while let Some(entry) = guard.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            expired.append(&mut entry.remove());
+        }
+        Ok(expired)
+    }
+}
 
 impl<Payload> OpCtx<Payload> {
     /// Create a new operation context with the status of `Running`.
     #[must_use]
     pub fn new_running() -> Self {
         let inner = OpCtxInner {
-            thread: None,
             status: CtxStatus::Running,
+            #[cfg(feature = "tracing")]
+            span: None,
+            reclaim: None,
         };
         let wrapper = OpCtxWrapper {
             inner: Mutex::new(inner),
+            done: Condvar::new(),
             payload: OnceLock::new(),
+            submitted_at: OnceLock::new(),
+            finished_at: OnceLock::new(),
         };
         Self(Arc::new(wrapper))
     }
@@ -73,34 +418,200 @@ impl<Payload> OpCtx<Payload> {
     /// # Errors
     /// Returns an error if the operation context is poisoned.
     pub fn wait(&self) -> Result<(), Error> {
-        let mut guard = self
+        let guard = self
             .0
             .inner
             .lock()
             .map_err(|_| Error::LockPoisoned("Operation context lock"))?;
-        if matches!(guard.status, CtxStatus::Running) {
-            guard.thread = Some(thread::current());
-            drop(guard);
-            thread::park();
-        }
+        let guard = self
+            .0
+            .done
+            .wait_while(guard, |inner| matches!(inner.status, CtxStatus::Running))
+            .map_err(|_| Error::LockPoisoned("Operation context lock"))?;
+        drop(guard);
         Ok(())
     }
 
+    /// Wait for the operation to finish for at most `timeout`.
+    ///
+    /// Returns `CtxStatus::TimedOut` if the operation is still running after `timeout`, it can be
+    /// waited for again.
+    ///
+    /// # Errors
+    /// Returns an error if the operation context is poisoned.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<CtxStatus, Error> {
+        let guard = self
+            .0
+            .inner
+            .lock()
+            .map_err(|_| Error::LockPoisoned("Operation context lock"))?;
+        let (guard, result) = self
+            .0
+            .done
+            .wait_timeout_while(guard, timeout, |inner| {
+                matches!(inner.status, CtxStatus::Running)
+            })
+            .map_err(|_| Error::LockPoisoned("Operation context lock"))?;
+        if result.timed_out() {
+            return Ok(CtxStatus::TimedOut);
+        }
+        Ok(guard.status)
+    }
+
     pub(crate) fn set_result(&self, result: Payload) -> Result<(), Error> {
+        // the status is checked under the lock, so a result racing with `cancel` is either set before
+        // the operation is cancelled or dropped
+        let mut guard = self
+            .0
+            .inner
+            .lock()
+            .map_err(|_| Error::LockPoisoned("op context set result lock"))?;
+        if matches!(guard.status, CtxStatus::Cancelled) {
+            return Ok(());
+        }
+        let finished_at = Instant::now();
+        // set only once
         self.0
             .payload
             .set(result)
             .map_err(|_| Error::SetCtxResultFailed)?;
-        // set only once
+        let _: Result<(), Instant> = self.0.finished_at.set(finished_at);
+        guard.status = CtxStatus::Finished;
+        guard.reclaim = None;
+        #[cfg(feature = "tracing")]
+        {
+            let _: Option<tracing::Span> = guard.span.take();
+        }
+        self.0.done.notify_all();
+        Ok(())
+    }
+
+    /// Record that the operation is submitted now, right before its first descriptor is pushed to the
+    /// work ring of the adaptor. The descriptor still waits for the scheduler of the adaptor before it
+    /// is sent. Only the first call counts, so the rest of the descriptors do not move it.
+    pub(crate) fn mark_submitted(&self) {
+        let _: Result<(), Instant> = self.0.submitted_at.set(Instant::now());
+    }
+
+    /// The time from submitting the operation to finishing it, on the monotonic clock of the host.
+    ///
+    /// It starts when the first descriptor of the operation is pushed to the work ring of the
+    /// adaptor, before it's scheduled, so it covers the queueing in the scheduler, the network, and
+    /// the handling of the completion. A write on an unreliable QP finishes once it is submitted, so
+    /// its latency is close to zero.
+    ///
+    /// Returns `None` if the operation is not finished, or it is not a write or a read.
+    #[must_use]
+    pub fn latency(&self) -> Option<Duration> {
+        let submitted_at = self.0.submitted_at.get()?;
+        let finished_at = self.0.finished_at.get()?;
+        Some(finished_at.saturating_duration_since(*submitted_at))
+    }
+
+    /// Cancel the operation if it is still running.
+    ///
+    /// The waiters are woken up with `CtxStatus::Cancelled`, and the result is dropped if it
+    /// arrives later. The operation is removed from the pending operations of the device, so its
+    /// id can be used again.
+    ///
+    /// Returns `false` if the operation is already finished or cancelled.
+    ///
+    /// # Errors
+    /// Returns an error if the operation context or the pending operations are poisoned.
+    pub fn cancel(&self) -> Result<bool, Error> {
         let mut guard = self
             .0
             .inner
             .lock()
-            .map_err(|_| Error::LockPoisoned("op context set result lock"))?;
-        guard.status = CtxStatus::Finished;
-        if let Some(thread) = guard.thread.take() {
-            thread.unpark();
+            .map_err(|_| Error::LockPoisoned("op context cancel lock"))?;
+        if !matches!(guard.status, CtxStatus::Running) {
+            return Ok(false);
+        }
+        guard.status = CtxStatus::Cancelled;
+        #[cfg(feature = "tracing")]
+        {
+            let _: Option<tracing::Span> = guard.span.take();
+        }
+        let reclaim = guard.reclaim.take();
+        self.0.done.notify_all();
+        // the completion handler locks the map before the context, so the map is locked after the
+        // context is released
+        drop(guard);
+        if let Some(Reclaim(reclaim)) = reclaim {
+            reclaim()?;
         }
+        Ok(true)
+    }
+
+    /// Whether the operation is cancelled
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0
+            .inner
+            .lock()
+            .is_ok_and(|guard| matches!(guard.status, CtxStatus::Cancelled))
+    }
+
+    /// Remove the entry of `key` from `map` when the operation is cancelled, if the entry is still
+    /// there and it is cancelled too.
+    pub(crate) fn reclaim_on_cancel<K, V>(
+        &self,
+        map: &Arc<RwLock<HashMap<K, V>>>,
+        key: K,
+        ctx_of: fn(&V) -> &Self,
+    ) -> Result<(), Error>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        Payload: 'static,
+    {
+        self.reclaim_all_on_cancel(map, vec![key], ctx_of)
+    }
+
+    /// Remove the entries of `keys` from `map` when the operation is cancelled, like
+    /// `reclaim_on_cancel`.
+    pub(crate) fn reclaim_all_on_cancel<K, V>(
+        &self,
+        map: &Arc<RwLock<HashMap<K, V>>>,
+        keys: Vec<K>,
+        ctx_of: fn(&V) -> &Self,
+    ) -> Result<(), Error>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        Payload: 'static,
+    {
+        // a weak reference, since the map owns the context
+        let map = Arc::downgrade(map);
+        let reclaim = move || {
+            let Some(map) = map.upgrade() else {
+                return Ok(());
+            };
+            let mut guard = map
+                .write()
+                .map_err(|_| Error::LockPoisoned("op_ctx_map lock"))?;
+            for key in &keys {
+                if guard.get(key).is_some_and(|op| ctx_of(op).is_cancelled()) {
+                    let _: Option<V> = guard.remove(key);
+                }
+            }
+            Ok(())
+        };
+        self.0
+            .inner
+            .lock()
+            .map_err(|_| Error::LockPoisoned("op context reclaim lock"))?
+            .reclaim = Some(Reclaim(Box::new(reclaim)));
+        Ok(())
+    }
+
+    /// Keep `span` open until the operation is finished.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn attach_span(&self, span: tracing::Span) -> Result<(), Error> {
+        self.0
+            .inner
+            .lock()
+            .map_err(|_| Error::LockPoisoned("op context span lock"))?
+            .span = Some(span);
         Ok(())
     }
 
@@ -122,6 +633,11 @@ impl<Payload> OpCtx<Payload> {
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::device::ToHostWorkRbDescOpcode;
+
+    use super::{CompletionStatus, CtxStatus, OpCtx, OpResult, PendingOp, RepeatedOps};
 
     #[test]
     fn test_op_ctx() {
@@ -143,4 +659,105 @@ mod tests {
         let _ = ctx.wait_result();
         assert_eq!(ctx.get_result(), Some(false).as_ref());
     }
+
+    #[test]
+    fn test_op_ctx_cancel() {
+        // a late result of a cancelled operation is dropped
+        let ctx = super::OpCtx::new_running();
+        assert!(ctx.cancel().unwrap());
+        ctx.set_result(true).unwrap();
+        assert!(matches!(
+            ctx.wait_timeout(Duration::ZERO).unwrap(),
+            CtxStatus::Cancelled
+        ));
+        assert_eq!(ctx.get_result(), None);
+
+        // a finished operation is not cancelled
+        let ctx = super::OpCtx::new_running();
+        ctx.set_result(true).unwrap();
+        assert!(!ctx.cancel().unwrap());
+        assert_eq!(ctx.get_result(), Some(true).as_ref());
+    }
+
+    #[test]
+    fn test_op_ctx_latency() {
+        // only a sent operation has a latency, once it is finished
+        let ctx = super::OpCtx::new_running();
+        ctx.set_result(true).unwrap();
+        assert_eq!(ctx.latency(), None);
+
+        let ctx = super::OpCtx::new_running();
+        ctx.mark_submitted();
+        assert_eq!(ctx.latency(), None);
+        std::thread::sleep(Duration::from_millis(10));
+        ctx.mark_submitted();
+        ctx.set_result(true).unwrap();
+        assert!(ctx.latency().unwrap() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_op_ctx_wait_timeout() {
+        let ctx = super::OpCtx::new_running();
+        let status = ctx.wait_timeout(Duration::from_millis(10)).unwrap();
+        assert!(matches!(status, CtxStatus::TimedOut));
+        assert_eq!(ctx.get_result(), None);
+
+        let ctx_clone = ctx.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            ctx_clone.set_result(true).unwrap();
+        });
+        let status = ctx.wait_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(status, CtxStatus::Finished));
+        assert_eq!(ctx.get_result(), Some(true).as_ref());
+    }
+
+    #[test]
+    fn test_op_ctx_waiters() {
+        // all the threads waiting on a context are woken up
+        let ctx = super::OpCtx::new_running();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let ctx = ctx.clone();
+                std::thread::spawn(move || ctx.wait_timeout(Duration::from_secs(5)).unwrap())
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(10));
+        ctx.set_result(true).unwrap();
+        for waiter in waiters {
+            assert!(matches!(waiter.join().unwrap(), CtxStatus::Finished));
+        }
+    }
+
+    #[test]
+    fn test_repeated_ops_complete_once() {
+        let ack = ToHostWorkRbDescOpcode::Acknowledge;
+        let repeated_ops = |count: u32, byte_len: u32| {
+            let ctx = OpCtx::<OpResult>::new_running();
+            let repeated = Arc::new(RepeatedOps::new(count));
+            let ops: Vec<_> = (0..count)
+                .map(|_| PendingOp::new(ctx.clone(), byte_len).with_repeated(Arc::clone(&repeated)))
+                .collect();
+            (ctx, ops)
+        };
+
+        // the context is finished by the first failure, and the later completions are dropped
+        let (ctx, ops) = repeated_ops(3, 16);
+        ops[0].finish(ack.clone(), 16).unwrap();
+        ops[1].finish_with_status(ack.clone(), CompletionStatus::RemoteAccessError).unwrap();
+        assert_eq!(ctx.get_result().unwrap().status, CompletionStatus::RemoteAccessError);
+        // `set_result` fails on a finished context, so a second finish would be an error here
+        ops[2].finish_with_status(ack.clone(), CompletionStatus::TimedOut).unwrap();
+        ops[2].finish(ack.clone(), 16).unwrap();
+        assert_eq!(ctx.get_result().unwrap().status, CompletionStatus::RemoteAccessError);
+
+        // the bytes of the messages add up beyond u32::MAX without wrapping
+        let (ctx, ops) = repeated_ops(2, u32::MAX);
+        ops[0].finish(ack.clone(), u32::MAX).unwrap();
+        assert_eq!(ctx.get_result(), None);
+        ops[1].finish(ack, u32::MAX).unwrap();
+        let result = ctx.get_result().unwrap();
+        assert_eq!(result.status, CompletionStatus::Success);
+        assert_eq!(result.byte_len, u32::MAX);
+    }
 }
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    io,
+    mem::size_of,
+    net::{Ipv4Addr, UdpSocket},
+    os::fd::AsRawFd,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{types::Pmtu, Error, DEFAULT_RMDA_PORT};
+
+/// The largest headers of a `RoCE` v2 packet around the payload: IPv4, UDP, BTH, RETH, `ImmDt` and ICRC
+const ROCE_HEADERS_MAX_SIZE: u16 = 20 + 8 + 12 + 16 + 4 + 4;
+
+/// The supported PMTUs from the largest one
+pub(crate) const PMTUS_DESCENDING: [Pmtu; 5] = [
+    Pmtu::Mtu4096,
+    Pmtu::Mtu2048,
+    Pmtu::Mtu1024,
+    Pmtu::Mtu512,
+    Pmtu::Mtu256,
+];
+
+/// How long a destination whose probe failed waits to be probed again
+const PROBE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The link MTU of a destination and whether the downgrade of the PMTU has been reported
+#[derive(Debug, Clone, Copy)]
+struct PathMtu {
+    /// `None` if the MTU is unknown, which does not limit the PMTU
+    mtu: Option<u16>,
+    /// When the destination is probed again, if its last probe failed
+    retry_at: Option<Instant>,
+    warned: bool,
+}
+
+/// The link MTUs of the destinations, which clamp the PMTUs of the QPs so that a packet fits in a
+/// frame.
+///
+/// The MTU of a destination is configured by `Device::set_path_mtu`. Otherwise it's probed by
+/// `PathMtuTable::probe` if the device is on a real network, and unlimited until it's known.
+#[derive(Debug)]
+pub(crate) struct PathMtuTable {
+    paths: Mutex<HashMap<Ipv4Addr, PathMtu>>,
+    probe: bool,
+}
+
+impl PathMtuTable {
+    /// Create an empty table, which probes the MTU of the unknown destinations if `probe` is set.
+    pub(crate) fn new(probe: bool) -> Self {
+        Self {
+            paths: Mutex::new(HashMap::new()),
+            probe,
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<Ipv4Addr, PathMtu>>, Error> {
+        self.paths
+            .lock()
+            .map_err(|_| Error::LockPoisoned("path mtu table lock"))
+    }
+
+    /// Whether the table probes the MTU of the unknown destinations.
+    pub(crate) fn is_probing(&self) -> bool {
+        self.probe
+    }
+
+    /// Set the link MTU of `dest`, which replaces the probed one.
+    pub(crate) fn set(&self, dest: Ipv4Addr, mtu: u16) -> Result<(), Error> {
+        let _: Option<PathMtu> = self.lock()?.insert(
+            dest,
+            PathMtu {
+                mtu: Some(mtu),
+                retry_at: None,
+                warned: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Probe the link MTU of `dest` if the table probes, and the MTU is unknown or its last probe
+    /// failed `PROBE_RETRY_INTERVAL` ago.
+    ///
+    /// The probe opens a socket, so the table is not locked meanwhile, and the caller should not
+    /// hold the locks of the device either.
+    pub(crate) fn probe(&self, dest: Ipv4Addr) -> Result<(), Error> {
+        if !self.probe {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let is_due = |paths: &HashMap<Ipv4Addr, PathMtu>| {
+            paths
+                .get(&dest)
+                .map_or(true, |path| path.retry_at.is_some_and(|retry_at| retry_at <= now))
+        };
+        if !is_due(&*self.lock()?) {
+            return Ok(());
+        }
+        let probed = probe_link_mtu(dest);
+        let mut paths = self.lock()?;
+        // the MTU set or probed by another thread meanwhile is kept
+        if !is_due(&paths) {
+            return Ok(());
+        }
+        let path = match probed {
+            Ok(mtu) => PathMtu {
+                mtu: Some(mtu),
+                retry_at: None,
+                warned: false,
+            },
+            Err(e) => {
+                debug!("failed to probe the link mtu to {dest}: {e}");
+                PathMtu {
+                    mtu: None,
+                    retry_at: now.checked_add(PROBE_RETRY_INTERVAL),
+                    warned: false,
+                }
+            }
+        };
+        let _: Option<PathMtu> = paths.insert(dest, path);
+        Ok(())
+    }
+
+    /// The largest PMTU up to `pmtu` whose packets fit in the link MTU of `dest`. An unknown MTU does
+    /// not limit the PMTU.
+    ///
+    /// A link MTU too small for the smallest PMTU still gets it, since there is nothing smaller.
+    pub(crate) fn clamp(&self, dest: Ipv4Addr, pmtu: Pmtu) -> Result<Pmtu, Error> {
+        let mut guard = self.lock()?;
+        let Some(path) = guard.get_mut(&dest) else {
+            return Ok(pmtu);
+        };
+        let Some(link_mtu) = path.mtu else {
+            return Ok(pmtu);
+        };
+        let fits = |candidate: &Pmtu| {
+            u32::from(candidate) <= u32::from(link_mtu.saturating_sub(ROCE_HEADERS_MAX_SIZE))
+        };
+        let clamped = PMTUS_DESCENDING
+            .into_iter()
+            .filter(|candidate| u32::from(candidate) <= u32::from(&pmtu))
+            .find(fits)
+            .unwrap_or(Pmtu::Mtu256);
+        if clamped != pmtu && !path.warned {
+            path.warned = true;
+            warn!("the link mtu {link_mtu} to {dest} does not fit {pmtu:?}, downgraded to {clamped:?}");
+        }
+        Ok(clamped)
+    }
+}
+
+/// Query the MTU of the route to `dest` with a connected UDP socket, without sending anything.
+fn probe_link_mtu(dest: Ipv4Addr) -> io::Result<u16> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((dest, DEFAULT_RMDA_PORT))?;
+    let mut mtu: libc::c_int = 0;
+    // the size of `c_int` fits in `socklen_t`
+    #[allow(clippy::cast_possible_truncation)]
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU,
+            std::ptr::addr_of_mut!(mtu).cast::<libc::c_void>(),
+            std::ptr::addr_of_mut!(len),
+        )
+    };
+    if ret != 0_i32 {
+        return Err(io::Error::last_os_error());
+    }
+    // a MTU beyond `u16` is as good as unlimited
+    Ok(u16::try_from(mtu).unwrap_or(u16::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Instant};
+
+    use crate::types::Pmtu;
+
+    use super::PathMtuTable;
+
+    #[test]
+    fn test_path_mtu_clamp() {
+        let table = PathMtuTable::new(false);
+        let dest = Ipv4Addr::new(10, 0, 0, 1);
+        // an unknown destination does not limit the pmtu
+        assert_eq!(table.clamp(dest, Pmtu::Mtu4096).unwrap(), Pmtu::Mtu4096);
+
+        for (mtu, pmtu) in [
+            (9000, Pmtu::Mtu4096),
+            (4096 + 64, Pmtu::Mtu4096),
+            (4096 + 63, Pmtu::Mtu2048),
+            (1500, Pmtu::Mtu1024),
+            (576, Pmtu::Mtu512),
+            (100, Pmtu::Mtu256),
+        ] {
+            table.set(dest, mtu).unwrap();
+            assert_eq!(table.clamp(dest, Pmtu::Mtu4096).unwrap(), pmtu, "{mtu}");
+        }
+        // a pmtu is never raised
+        table.set(dest, 9000).unwrap();
+        assert_eq!(table.clamp(dest, Pmtu::Mtu1024).unwrap(), Pmtu::Mtu1024);
+    }
+
+    #[test]
+    fn test_probe_loopback_mtu() {
+        let table = PathMtuTable::new(true);
+        table.probe(Ipv4Addr::LOCALHOST).unwrap();
+        // the loopback interface has a jumbo mtu
+        let path = table.lock().unwrap()[&Ipv4Addr::LOCALHOST];
+        assert!(path.mtu.is_some_and(|mtu| mtu > 4096));
+        assert!(path.retry_at.is_none());
+        assert_eq!(table.clamp(Ipv4Addr::LOCALHOST, Pmtu::Mtu4096).unwrap(), Pmtu::Mtu4096);
+    }
+
+    #[test]
+    fn test_probe_retry() {
+        let table = PathMtuTable::new(true);
+        // the broadcast address can't be connected without `SO_BROADCAST`
+        let dest = Ipv4Addr::BROADCAST;
+        table.probe(dest).unwrap();
+        let failed = table.lock().unwrap()[&dest];
+        assert!(failed.mtu.is_none());
+        let retry_at = failed.retry_at.unwrap();
+        // the failed probe is not repeated before its retry time
+        table.probe(dest).unwrap();
+        assert_eq!(table.lock().unwrap()[&dest].retry_at, Some(retry_at));
+        // but after it
+        table.lock().unwrap().get_mut(&dest).unwrap().retry_at = Some(Instant::now());
+        table.probe(dest).unwrap();
+        assert!(table.lock().unwrap()[&dest].retry_at.unwrap() > retry_at);
+        // a configured mtu is never probed
+        table.set(dest, 1500).unwrap();
+        table.probe(dest).unwrap();
+        assert_eq!(table.lock().unwrap()[&dest].mtu, Some(1500));
+    }
+
+    #[test]
+    fn test_no_probe() {
+        let table = PathMtuTable::new(false);
+        table.probe(Ipv4Addr::LOCALHOST).unwrap();
+        assert!(table.lock().unwrap().is_empty());
+    }
+}
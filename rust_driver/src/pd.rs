@@ -79,6 +79,27 @@ impl Device {
 
         Ok(())
     }
+
+    /// list the mrs registered against a pd
+    ///
+    /// The returned list is a snapshot, in no particular order. It can be used to deregister all the
+    /// mrs of a pd before `dealloc_pd`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * invalid Pd
+    pub fn list_mrs(&self, pd: Pd) -> Result<Vec<Mr>, Error> {
+        let pool = self
+            .0
+            .pd
+            .lock()
+            .map_err(|_| Error::LockPoisoned("pd pool lock"))?;
+        let pd_ctx = pool.get(&pd).ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
+
+        Ok(pd_ctx.mr.iter().copied().collect())
+    }
 }
 
 impl Hash for Pd {
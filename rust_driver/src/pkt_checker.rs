@@ -1,34 +1,48 @@
 use std::{
-    collections::{HashMap, LinkedList},
+    cell::Cell,
+    collections::LinkedList,
     sync::{atomic::{AtomicBool, Ordering}, mpsc::Sender, Arc, Mutex, RwLock},
+    time::Instant,
 };
 
 use crate::{
-    op_ctx::ReadOpCtx,
-    recv_pkt_map::RecvPktMap,
-    responser::{RespAckCommand, RespCommand},
-    types::{Msn, Psn},
+    device::ToHostWorkRbDescOpcode,
+    op_ctx::{CompletionStatus, OpDeadlines, PendingOpMap},
+    recv_pkt_map::{RecvPktMap, RecvPktMaps},
+    responser::{AcknowledgeBuffer, RespAckCommand, RespCommand, Slot},
+    trace::enter_span,
+    types::{Msn, Psn, Qpn},
+    utils::stop_thread,
     Error,
 };
 
-use log::{error, info};
+use log::{error, info, warn};
 
 #[derive(Debug)]
 pub(crate) struct PacketChecker {
-    thread: Option<std::thread::JoinHandle<()>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
 }
 
 impl PacketChecker {
     pub(crate) fn new(
         send_queue: Sender<RespCommand>,
-        recv_pkt_map: Arc<RwLock<HashMap<Msn, Arc<Mutex<RecvPktMap>>>>>,
-        read_op_ctx_map: Arc<RwLock<HashMap<Msn, ReadOpCtx>>>,
+        ack_buffers: Arc<AcknowledgeBuffer>,
+        recv_pkt_map: Arc<RwLock<RecvPktMaps>>,
+        read_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+        write_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+        read_deadlines: Arc<OpDeadlines>,
+        write_deadlines: Arc<OpDeadlines>,
     ) -> Self {
         let ctx = PacketCheckerContext {
             send_queue,
+            ack_buffers,
+            is_ack_deferred: Cell::new(false),
             recv_pkt_map,
             read_op_ctx_map,
+            write_op_ctx_map,
+            read_deadlines,
+            write_deadlines,
         };
         let stop_flag = Arc::new(AtomicBool::new(false));
         let thread_stop_flag = Arc::clone(&stop_flag);
@@ -36,38 +50,156 @@ impl PacketChecker {
             PacketCheckerContext::working_thread(&ctx, &thread_stop_flag);
         });
         Self {
-            thread: Some(thread),
+            thread: Mutex::new(Some(thread)),
             stop_flag,
         }
     }
 }
 
+impl PacketChecker {
+    /// Stop the thread and wait for it to exit. Stopping a stopped thread is a no-op.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the thread panicked.
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        if stop_thread(&self.stop_flag, &self.thread) {
+            Ok(())
+        } else {
+            Err(Error::ThreadJoinFailed("packet checker"))
+        }
+    }
+}
+
 impl Drop for PacketChecker {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) =  self.thread.take(){
-            if let Err(e) = thread.join(){
-                error!("Failed to join the WorkDescPoller thread: {:?}", e);
-            }
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the packet checker: {e:?}");
         }
     }
 }
 
 struct PacketCheckerContext {
     send_queue: Sender<RespCommand>,
-    recv_pkt_map: Arc<RwLock<HashMap<Msn, Arc<Mutex<RecvPktMap>>>>>,
-    read_op_ctx_map: Arc<RwLock<HashMap<Msn, ReadOpCtx>>>,
+    ack_buffers: Arc<AcknowledgeBuffer>,
+    /// Whether an ack is held back because the acknowledge buffer is full, to report it only once
+    is_ack_deferred: Cell<bool>,
+    recv_pkt_map: Arc<RwLock<RecvPktMaps>>,
+    read_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+    write_op_ctx_map: Arc<RwLock<PendingOpMap>>,
+    read_deadlines: Arc<OpDeadlines>,
+    write_deadlines: Arc<OpDeadlines>,
 }
 
 impl PacketCheckerContext {
     fn working_thread(ctx: &Self, stop_flag: &AtomicBool) {
         while !stop_flag.load(Ordering::Relaxed) {
-            if let Err(e) = ctx.check_pkt_map() {
+            if let Err(e) = ctx.check_pkt_map().and_then(|()| ctx.expire_ops()) {
                 error!("PacketChecker stopped: {:?}", e);
                 break;
             }
         }
     }
+
+    /// Fail the writes and reads that are still waiting for their responses after their ack timeouts.
+    ///
+    /// The maps are only locked when a deadline has passed.
+    fn expire_ops(&self) -> Result<(), Error> {
+        let now = Instant::now();
+        let maps = [
+            (
+                &self.write_op_ctx_map,
+                &self.write_deadlines,
+                ToHostWorkRbDescOpcode::Acknowledge,
+            ),
+            (
+                &self.read_op_ctx_map,
+                &self.read_deadlines,
+                ToHostWorkRbDescOpcode::RdmaReadResponseOnly,
+            ),
+        ];
+        for (map, deadlines, opcode) in maps {
+            let expired = deadlines.take_expired(now)?;
+            if expired.is_empty() {
+                continue;
+            }
+            let mut guard = map
+                .write()
+                .map_err(|_| Error::LockPoisoned("op_ctx_map lock"))?;
+            for key in expired {
+                // the operation may be completed, or the key taken by a later operation
+                if !guard.get(&key).is_some_and(|op| op.is_expired(now)) {
+                    continue;
+                }
+                let Some(op) = guard.remove(&key) else {
+                    continue;
+                };
+                error!("{key:?} is not responded within its ack timeout");
+                if let Err(e) = op.finish_with_status(opcode.clone(), CompletionStatus::TimedOut) {
+                    error!("Set result failed {e:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a slot of the acknowledge buffer for an ack or a nack.
+    ///
+    /// Return `None` while the buffer is full. The caller keeps the message in the `recv_pkt_map`, so
+    /// that it is acknowledged in a later pass, after the sent acks release their slots.
+    fn acquire_ack_slot(&self) -> Option<Slot> {
+        match self.ack_buffers.acquire_slot() {
+            Ok(slot) => {
+                self.is_ack_deferred.set(false);
+                Some(slot)
+            }
+            Err(e) => {
+                if !self.is_ack_deferred.replace(true) {
+                    warn!("{e}, holding back the acks until a slot is released");
+                }
+                None
+            }
+        }
+    }
+
+    /// Finish the read `(qpn, msn)` whose read response packets are all received.
+    fn complete_read(
+        &self,
+        qpn: Qpn,
+        msn: Msn,
+        is_single_packet: bool,
+        received_len: u32,
+    ) -> Result<(), Error> {
+        let Some(ctx) = self
+            .read_op_ctx_map
+            .write()
+            .map_err(|_| Error::LockPoisoned("read_op_ctx_map lock"))?
+            .remove(&(qpn, msn))
+        else {
+            error!("No read op ctx found for {:?}", msn);
+            return Ok(());
+        };
+        let opcode = if is_single_packet {
+            ToHostWorkRbDescOpcode::RdmaReadResponseOnly
+        } else {
+            ToHostWorkRbDescOpcode::RdmaReadResponseLast
+        };
+        // a responder returning less data than requested should not look like a success
+        let result = if received_len == ctx.byte_len() {
+            ctx.finish(opcode, received_len)
+        } else {
+            error!(
+                "read response of {msn:?} has {received_len} bytes, expected {}",
+                ctx.byte_len()
+            );
+            ctx.finish_with_length_error(opcode, received_len)
+        };
+        if let Err(e) = result {
+            error!("Set result failed {:?}", e);
+        }
+        Ok(())
+    }
+
     fn check_pkt_map(&self) -> Result<(), Error> {
         let mut remove_list = LinkedList::new();
         let iter_maps = {
@@ -80,8 +212,16 @@ impl PacketCheckerContext {
                 .map(|(k, v)| (*k, Arc::clone(v)))
                 .collect::<Vec<_>>()
         };
-        for (msn, map) in iter_maps {
-            let (is_complete, is_read_resp, is_out_of_order, dqpn, end_psn) = {
+        for ((qpn, msn), map) in iter_maps {
+            let (
+                is_complete,
+                is_read_resp,
+                is_out_of_order,
+                is_single_packet,
+                dqpn,
+                end_psn,
+                received_len,
+            ) = {
                 let guard = map
                     .lock()
                     .map_err(|_| Error::LockPoisoned("recv packet map lock"))?;
@@ -89,40 +229,54 @@ impl PacketCheckerContext {
                     guard.is_complete(),
                     guard.is_read_resp(),
                     guard.is_out_of_order(),
+                    guard.is_single_packet(),
                     guard.dqpn(),
                     guard.end_psn(),
+                    guard.received_len(),
                 )
             };
             // send ack
             if is_complete {
+                // If we are not in read response, we should send ack. A message whose ack is held
+                // back is completed in a later pass, so it is only reported then.
+                let slot = if is_read_resp {
+                    None
+                } else {
+                    let Some(slot) = self.acquire_ack_slot() else {
+                        continue;
+                    };
+                    Some(slot)
+                };
+                enter_span!(
+                    "completion",
+                    qpn = dqpn.get(),
+                    psn = end_psn.get(),
+                    msn = msn.get(),
+                    byte_len = received_len
+                );
                 info!("Complete: {:?}", &msn);
-                if !is_read_resp {
-                    // If we are not in read response, we should send ack
-                    let command =
-                        RespCommand::Acknowledge(RespAckCommand::new_ack(dqpn, msn, end_psn));
+                if let Some(slot) = slot {
+                    let command = RespCommand::Acknowledge(RespAckCommand::new_ack(
+                        dqpn, msn, end_psn, slot,
+                    ));
                     self.send_queue
                         .send(command)
                         .map_err(|_| Error::PipeBroken("packet checker send queue"))?;
-                } else if let Some(ctx) = self
-                    .read_op_ctx_map
-                    .read()
-                    .map_err(|_| Error::LockPoisoned("read_op_ctx_map lock"))?
-                    .get(&msn)
-                {
-                    if let Err(e) = ctx.set_result(()) {
-                        error!("Set result failed {:?}", e);
-                    }
                 } else {
-                    error!("No read op ctx found for {:?}", msn);
+                    self.complete_read(qpn, msn, is_single_packet, received_len)?;
                 }
-                remove_list.push_back(msn);
+                remove_list.push_back((qpn, msn));
             } else if is_out_of_order {
+                let Some(slot) = self.acquire_ack_slot() else {
+                    continue;
+                };
                 // TODO: what should we put in NACK packet?
                 let command = RespCommand::Acknowledge(RespAckCommand::new_nack(
                     dqpn,
                     Msn::default(),
                     end_psn,
                     Psn::default(),
+                    slot,
                 ));
                 self.send_queue
                     .send(command)
@@ -139,8 +293,8 @@ impl PacketCheckerContext {
                 .recv_pkt_map
                 .write()
                 .map_err(|_| Error::LockPoisoned("recv_pkt_map lock"))?;
-            for dqpn in &remove_list {
-                let _: Option<Arc<Mutex<RecvPktMap>>> = guard.remove(dqpn);
+            for key in &remove_list {
+                let _: Option<Arc<Mutex<RecvPktMap>>> = guard.remove(key);
             }
         }
         Ok(())
@@ -150,31 +304,42 @@ impl PacketCheckerContext {
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::HashMap,
         sync::{mpsc, Arc, Mutex, RwLock},
         thread::sleep,
         time::Duration,
     };
 
     use crate::{
-        op_ctx::ReadOpCtx,
-        recv_pkt_map::RecvPktMap,
-        types::{Msn, Psn, Qpn},
+        device::ToHostWorkRbDescOpcode,
+        op_ctx::{CompletionStatus, OpDeadlines, PendingOp, PendingOpMap, ReadOpCtx, WriteOpCtx},
+        recv_pkt_map::{RecvPktMap, RecvPktMaps},
+        responser::{AcknowledgeBuffer, RespCommand},
+        types::{Key, Msn, Psn, Qpn},
+        HugePage,
     };
 
     use super::PacketChecker;
 
+    fn new_ack_buf(slot_cnt: usize) -> Arc<AcknowledgeBuffer> {
+        let size = slot_cnt * AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
+        AcknowledgeBuffer::new_with_buf(HugePage::new_or_fallback(size).unwrap(), size, Key::new(0))
+    }
+
     #[test]
     fn test_packet_checker() {
         let (send_queue, recv_queue) = mpsc::channel();
-        let recv_pkt_map = Arc::new(RwLock::new(HashMap::<Msn, Arc<Mutex<RecvPktMap>>>::new()));
-        let read_op_ctx_map = Arc::new(RwLock::new(HashMap::<Msn, ReadOpCtx>::new()));
+        let recv_pkt_map = Arc::new(RwLock::new(RecvPktMaps::new()));
+        let read_op_ctx_map = Arc::new(RwLock::new(PendingOpMap::new()));
         let _packet_checker = PacketChecker::new(
             send_queue,
-            Arc::<RwLock<HashMap<Msn, Arc<Mutex<RecvPktMap>>>>>::clone(&recv_pkt_map),
-            Arc::<RwLock<HashMap<Msn, ReadOpCtx>>>::clone(&read_op_ctx_map),
+            new_ack_buf(4),
+            Arc::<RwLock<RecvPktMaps>>::clone(&recv_pkt_map),
+            Arc::<RwLock<PendingOpMap>>::clone(&read_op_ctx_map),
+            Arc::new(RwLock::new(PendingOpMap::new())),
+            Arc::default(),
+            Arc::default(),
         );
-        let key = Msn::new(1);
+        let key = (Qpn::new(3), Msn::new(1));
         recv_pkt_map.write().unwrap().insert(
             key,
             Mutex::new(RecvPktMap::new(false, 2, Psn::new(1), Qpn::new(3))).into(),
@@ -203,4 +368,117 @@ mod tests {
         sleep(Duration::from_millis(10));
         assert!(recv_queue.try_recv().is_ok());
     }
+
+    #[test]
+    fn test_ack_held_back_while_buffer_full() {
+        let (send_queue, recv_queue) = mpsc::channel();
+        let recv_pkt_map = Arc::new(RwLock::new(RecvPktMaps::new()));
+        let _packet_checker = PacketChecker::new(
+            send_queue,
+            new_ack_buf(1),
+            Arc::<RwLock<RecvPktMaps>>::clone(&recv_pkt_map),
+            Arc::new(RwLock::new(PendingOpMap::new())),
+            Arc::new(RwLock::new(PendingOpMap::new())),
+            Arc::default(),
+            Arc::default(),
+        );
+        for msn in 1..=2_u16 {
+            let mut pkt_map = RecvPktMap::new(false, 1, Psn::new(msn.into()), Qpn::new(3));
+            pkt_map.insert(Psn::new(msn.into()));
+            recv_pkt_map
+                .write()
+                .unwrap()
+                .insert((Qpn::new(3), Msn::new(msn)), Mutex::new(pkt_map).into());
+        }
+        let timeout = Duration::from_secs(5);
+        let RespCommand::Acknowledge(first) = recv_queue.recv_timeout(timeout).unwrap() else {
+            panic!("expect an ack");
+        };
+        // the only slot is held by the first ack, so the second one waits in the map
+        sleep(Duration::from_millis(10));
+        assert!(recv_queue.try_recv().is_err());
+        assert_eq!(recv_pkt_map.read().unwrap().len(), 1);
+
+        let first_msn = first.msn;
+        drop(first);
+        let RespCommand::Acknowledge(second) = recv_queue.recv_timeout(timeout).unwrap() else {
+            panic!("expect an ack");
+        };
+        assert_ne!(second.msn, first_msn);
+        // the acknowledged message is removed after its ack is queued
+        sleep(Duration::from_millis(10));
+        assert!(recv_pkt_map.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_short_read_response() {
+        let (send_queue, _recv_queue) = mpsc::channel();
+        let recv_pkt_map = Arc::new(RwLock::new(RecvPktMaps::new()));
+        let read_op_ctx_map = Arc::new(RwLock::new(PendingOpMap::new()));
+        let _packet_checker = PacketChecker::new(
+            send_queue,
+            new_ack_buf(4),
+            Arc::<RwLock<RecvPktMaps>>::clone(&recv_pkt_map),
+            Arc::<RwLock<PendingOpMap>>::clone(&read_op_ctx_map),
+            Arc::new(RwLock::new(PendingOpMap::new())),
+            Arc::default(),
+            Arc::default(),
+        );
+        // the responder returns 32 bytes of the 64 bytes requested by the first read, and all
+        // of them for the second one
+        for (msn, received_len, status) in [
+            (1, 32, CompletionStatus::LocalLengthError),
+            (2, 64, CompletionStatus::Success),
+        ] {
+            let key = (Qpn::new(3), Msn::new(msn));
+            let ctx = ReadOpCtx::new_running();
+            read_op_ctx_map
+                .write()
+                .unwrap()
+                .insert(key, PendingOp::new(ctx.clone(), 64));
+            let mut pkt_map = RecvPktMap::new(true, 1, Psn::new(1), Qpn::new(3));
+            pkt_map.insert(Psn::new(1));
+            pkt_map.add_received_len(received_len);
+            recv_pkt_map
+                .write()
+                .unwrap()
+                .insert(key, Mutex::new(pkt_map).into());
+
+            let result = ctx.wait_result().unwrap().unwrap();
+            assert_eq!(result.byte_len, received_len);
+            assert_eq!(result.opcode, ToHostWorkRbDescOpcode::RdmaReadResponseOnly);
+            assert_eq!(result.status, status);
+        }
+    }
+
+    #[test]
+    fn test_ack_timeout() {
+        let (send_queue, _recv_queue) = mpsc::channel();
+        let write_op_ctx_map = Arc::new(RwLock::new(PendingOpMap::new()));
+        let write_deadlines = Arc::new(OpDeadlines::default());
+        let _packet_checker = PacketChecker::new(
+            send_queue,
+            new_ack_buf(4),
+            Arc::new(RwLock::new(RecvPktMaps::new())),
+            Arc::new(RwLock::new(PendingOpMap::new())),
+            Arc::<RwLock<PendingOpMap>>::clone(&write_op_ctx_map),
+            Arc::default(),
+            Arc::<OpDeadlines>::clone(&write_deadlines),
+        );
+        let ctx = WriteOpCtx::new_running();
+        let waiting_ctx = WriteOpCtx::new_running();
+        let mut map = write_op_ctx_map.write().unwrap();
+        let op = PendingOp::new(ctx.clone(), 64).with_timeout(Some(Duration::from_millis(10)));
+        write_deadlines.insert((Qpn::new(3), Msn::new(1)), op.deadline()).unwrap();
+        let _ = map.insert((Qpn::new(3), Msn::new(1)), op);
+        let _ = map.insert((Qpn::new(3), Msn::new(2)), PendingOp::new(waiting_ctx.clone(), 64));
+        drop(map);
+
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.byte_len, 64);
+        assert_eq!(result.status, CompletionStatus::TimedOut);
+        // the operation without a timeout keeps waiting
+        assert!(waiting_ctx.get_result().is_none());
+        assert_eq!(write_op_ctx_map.read().unwrap().len(), 1);
+    }
 }
@@ -1,6 +1,6 @@
 use std::{
     collections::HashMap,
-    sync::{atomic::{AtomicBool, Ordering}, Arc, RwLock},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock},
 };
 
 use log::error;
@@ -12,12 +12,14 @@ use crate::{
         ToHostCtrlRbDescUpdatePageTable, ToHostRb,
     },
     op_ctx::CtrlOpCtx,
+    poll::POLL_TIMEOUT,
+    utils::stop_thread,
     Error,
 };
 
 #[derive(Debug)]
 pub(crate) struct ControlPoller {
-    thread: Option<std::thread::JoinHandle<()>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
 }
 
@@ -35,7 +37,7 @@ impl ControlPoller {
         let thread =
             std::thread::spawn(move || ControlPollerContext::poll_ctrl_thread(&ctx, &thread_stop_flag));
         Self {
-            thread: Some(thread),
+            thread: Mutex::new(Some(thread)),
             stop_flag,
         }
     }
@@ -44,8 +46,9 @@ impl ControlPoller {
 impl ControlPollerContext {
     pub(crate) fn poll_ctrl_thread(ctx: &Self, stop_flag: &AtomicBool) {
         while !stop_flag.load(Ordering::Relaxed) {
-            let desc = match ctx.to_host_ctrl_rb.pop() {
-                Ok(desc) => desc,
+            let desc = match ctx.to_host_ctrl_rb.pop(POLL_TIMEOUT) {
+                Ok(Some(desc)) => desc,
+                Ok(None) => continue,
                 Err(e) => {
                     error!("failed to fetch descriptor from ctrl rb : {:?}", e);
                     return;
@@ -169,13 +172,25 @@ impl ControlPollerContext {
     }
 }
 
+impl ControlPoller {
+    /// Stop the thread and wait for it to exit. Stopping a stopped thread is a no-op.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the thread panicked.
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        if stop_thread(&self.stop_flag, &self.thread) {
+            Ok(())
+        } else {
+            Err(Error::ThreadJoinFailed("ctrl poller"))
+        }
+    }
+}
+
 impl Drop for ControlPoller {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) =  self.thread.take(){
-            if let Err(e) = thread.join(){
-                error!("Failed to join the WorkDescPoller thread: {:?}", e);
-            }
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the ctrl poller: {e:?}");
         }
     }
 }
\ No newline at end of file
@@ -1,2 +1,7 @@
+use std::time::Duration;
+
 pub(crate) mod ctrl;
-pub(crate) mod work;
\ No newline at end of file
+pub(crate) mod work;
+
+/// How long the pollers wait for a descriptor before checking their stop flags again
+const POLL_TIMEOUT: Duration = Duration::from_millis(10);
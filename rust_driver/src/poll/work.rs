@@ -1,4 +1,3 @@
-use core::panic;
 use log::{debug, error};
 use std::{
     collections::HashMap,
@@ -7,30 +6,35 @@ use std::{
 
 use crate::{
     device::{
-        ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescNack,
-        ToHostWorkRbDescRead, ToHostWorkRbDescStatus, ToHostWorkRbDescWriteOrReadResp,
-        ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm,
+        ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescNack, ToHostWorkRbDescRecv,
+        ToHostWorkRbDescOpcode, ToHostWorkRbDescRead, ToHostWorkRbDescStatus,
+        ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm,
     },
-    op_ctx::WriteOpCtx,
-    qp::QpContext,
+    op_ctx::{CompletionStatus, PendingOpMap},
+    path_mtu::PathMtuTable,
+    poll::POLL_TIMEOUT,
+    qp::{PsnTracker, QpContext},
     responser::{RespCommand, RespReadRespCommand},
-    types::{Msn, Qpn},
-    Error, RecvPktMap,
+    trace::enter_span,
+    types::Qpn,
+    utils::{calculate_packet_cnt, get_first_packet_max_length, stop_thread},
+    Error, RecvPktMap, RecvPktMaps,
 };
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
 pub(crate) struct WorkDescPoller {
-    thread: Option<std::thread::JoinHandle<()>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
 }
 
 pub(crate) struct WorkDescPollerContext {
     pub(crate) work_rb: Arc<dyn ToHostRb<ToHostWorkRbDesc>>,
-    pub(crate) recv_pkt_map: Arc<RwLock<HashMap<Msn, Arc<Mutex<RecvPktMap>>>>>,
+    pub(crate) recv_pkt_map: Arc<RwLock<RecvPktMaps>>,
     pub(crate) qp_table: Arc<RwLock<HashMap<Qpn, QpContext>>>,
+    pub(crate) path_mtus: Arc<PathMtuTable>,
     pub(crate) sending_queue: std::sync::mpsc::Sender<RespCommand>,
-    pub(crate) write_op_ctx_map: Arc<RwLock<HashMap<Msn, WriteOpCtx>>>,
+    pub(crate) write_op_ctx_map: Arc<RwLock<PendingOpMap>>,
 }
 
 unsafe impl Send for WorkDescPollerContext {}
@@ -41,7 +45,7 @@ impl WorkDescPoller {
         let thread_stop_flag = Arc::clone(&stop_flag);
         let thread = std::thread::spawn(move || WorkDescPollerContext::poll_working_thread(&ctx,&thread_stop_flag));
         Self {
-            thread : Some(thread),
+            thread: Mutex::new(Some(thread)),
             stop_flag
         }
     }
@@ -50,8 +54,9 @@ impl WorkDescPoller {
 impl WorkDescPollerContext {
     pub(crate) fn poll_working_thread(ctx: &Self,stop_flag : &AtomicBool) {
         while !stop_flag.load(Ordering::Relaxed) {
-            let desc = match ctx.work_rb.pop() {
-                Ok(desc) => desc,
+            let desc = match ctx.work_rb.pop(POLL_TIMEOUT) {
+                Ok(Some(desc)) => desc,
+                Ok(None) => continue,
                 Err(e) => {
                     error!("failed to fetch descriptor from work rb : {:?}", e);
                     return;
@@ -69,6 +74,10 @@ impl WorkDescPollerContext {
                 ToHostWorkRbDesc::WriteWithImm(desc) => ctx.handle_work_desc_write_with_imm(&desc),
                 ToHostWorkRbDesc::Ack(desc) => ctx.handle_work_desc_ack(&desc),
                 ToHostWorkRbDesc::Nack(desc) => ctx.handle_work_desc_nack(&desc),
+                ToHostWorkRbDesc::Recv(desc) => {
+                    Self::handle_work_desc_recv(&desc);
+                    Ok(())
+                }
             };
             if let Err(reason) = result {
                 error!("poll_work_rb stopped: {}", reason);
@@ -86,7 +95,7 @@ impl WorkDescPollerContext {
     }
 
     fn handle_work_desc_write(&self, desc: &ToHostWorkRbDescWriteOrReadResp) -> Result<(), Error> {
-        let msn = desc.common.msn;
+        let key = (desc.common.dqpn, desc.common.msn);
 
         if matches!(
             desc.write_type,
@@ -99,24 +108,26 @@ impl WorkDescPollerContext {
                     .read()
                     .map_err(|_| Error::LockPoisoned("qp table lock"))?;
                 if let Some(qp_ctx) = guard.get(&desc.common.dqpn) {
-                    qp_ctx.pmtu
+                    // the requester segments the message by the pmtu of the path
+                    self.path_mtus.clamp(qp_ctx.dqp_ip, qp_ctx.pmtu)?
                 } else {
                     error!("{:?} not found", desc.common.dqpn.get());
                     return Ok(());
                 }
             };
 
-            let pmtu = u32::from(&pmtu);
-
-            #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
-            let first_pkt_len = if matches!(desc.write_type, ToHostWorkRbDescWriteType::First) {
-                u64::from(pmtu) - (desc.addr & (u64::from(pmtu) - 1))
+            // the reth of the first packet carries the length of the whole message
+            let pkt_cnt = if matches!(desc.write_type, ToHostWorkRbDescWriteType::First) {
+                calculate_packet_cnt(pmtu, desc.addr, real_payload_len)
             } else {
-                u64::from(real_payload_len)
-            } as u32;
-
-            #[allow(clippy::arithmetic_side_effects)] // real_payload_len must be greater than first_pkt_len
-            let pkt_cnt = 1 + (real_payload_len - first_pkt_len).div_ceil(pmtu);
+                1
+            };
+            // the first packet carries the payload up to the pmtu boundary
+            let payload_len = if matches!(desc.write_type, ToHostWorkRbDescWriteType::First) {
+                real_payload_len.min(get_first_packet_max_length(desc.addr, u32::from(&pmtu)))
+            } else {
+                real_payload_len
+            };
             let mut pkt_map = RecvPktMap::new(
                 desc.is_read_resp,
                 pkt_cnt as usize,
@@ -124,12 +135,13 @@ impl WorkDescPollerContext {
                 desc.common.dqpn,
             );
             pkt_map.insert(desc.psn);
+            pkt_map.add_received_len(payload_len);
             let mut recv_pkt_map_guard = self
                 .recv_pkt_map
                 .write()
                 .map_err(|_| Error::LockPoisoned("recv_pkt_map lock"))?;
             if recv_pkt_map_guard
-                .insert(msn, Mutex::new(pkt_map).into())
+                .insert(key, Mutex::new(pkt_map).into())
                 .is_some()
             {
                 error!(
@@ -142,13 +154,14 @@ impl WorkDescPollerContext {
                 .recv_pkt_map
                 .read()
                 .map_err(|_| Error::LockPoisoned("map of recv_pkt_map lock"))?;
-            if let Some(recv_pkt_map) = guard.get(&msn) {
+            if let Some(recv_pkt_map) = guard.get(&key) {
                 let mut recv_pkt_map = recv_pkt_map
                     .lock()
                     .map_err(|_| Error::LockPoisoned("recv_pkt_map lock"))?;
                 recv_pkt_map.insert(desc.psn);
+                recv_pkt_map.add_received_len(desc.len);
             } else {
-                error!("recv_pkt_map not found for {:?}", msn);
+                error!("recv_pkt_map not found for {:?}", key);
             }
         }
         Ok(())
@@ -161,14 +174,47 @@ impl WorkDescPollerContext {
         todo!()
     }
 
+    /// Apply `update` to the PSN tracker of `qpn`, if the qp is not destroyed.
+    fn update_psn_tracker(
+        &self,
+        qpn: Qpn,
+        update: impl FnOnce(&mut PsnTracker),
+    ) -> Result<(), Error> {
+        if let Some(qp_ctx) = self
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?
+            .get(&qpn)
+        {
+            update(
+                &mut *qp_ctx
+                    .psn_tracker
+                    .lock()
+                    .map_err(|_| Error::LockPoisoned("qp context psn tracker lock"))?,
+            );
+        }
+        Ok(())
+    }
+
     fn handle_work_desc_ack(&self, desc: &ToHostWorkRbDescAck) -> Result<(), Error> {
-        let guard = self
+        self.update_psn_tracker(desc.common.dqpn, |tracker| tracker.on_ack(desc.psn))?;
+        // the operation is completed, so its MSN can be allocated again
+        let key = (desc.common.dqpn, desc.msn);
+        let op_ctx = self
             .write_op_ctx_map
-            .read()
-            .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?;
-        let key = desc.msn;
-        if let Some(op_ctx) = guard.get(&key) {
-            if let Err(e) = op_ctx.set_result(()) {
+            .write()
+            .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?
+            .remove(&key);
+        enter_span!(
+            "completion",
+            qpn = desc.common.dqpn.get(),
+            psn = desc.psn.get(),
+            msn = desc.msn.get(),
+            byte_len = op_ctx.as_ref().map_or(0, |op| op.acked_len(desc.psn))
+        );
+        if let Some(op_ctx) = op_ctx {
+            let byte_len = op_ctx.acked_len(desc.psn);
+            if let Err(e) = op_ctx.finish(ToHostWorkRbDescOpcode::Acknowledge, byte_len) {
                 error!("Set result failed {:?}", e);
             }
         } else {
@@ -178,20 +224,55 @@ impl WorkDescPollerContext {
         Ok(())
     }
 
-    // This function is still under development
-    #[allow(clippy::unused_self)]
-    fn handle_work_desc_nack(&self, _desc: &ToHostWorkRbDescNack) -> Result<(), Error> {
-        panic!("receive a nack");
+    /// The SEND is delivered and acknowledged by the device, and the host has no receive completion
+    /// to report it to yet.
+    fn handle_work_desc_recv(desc: &ToHostWorkRbDescRecv) {
+        debug!(
+            "{:?} receives a SEND of {} bytes at {:?}",
+            desc.common.dqpn, desc.len, desc.psn
+        );
+    }
+
+    fn handle_work_desc_nack(&self, desc: &ToHostWorkRbDescNack) -> Result<(), Error> {
+        let status = CompletionStatus::from_aeth(&desc.code, desc.value);
+        let key = (desc.common.dqpn, desc.msn);
+        self.update_psn_tracker(desc.common.dqpn, |tracker| tracker.on_nack(desc.msn))?;
+        let op_ctx = self
+            .write_op_ctx_map
+            .write()
+            .map_err(|_| Error::LockPoisoned("write_op_ctx_map lock"))?
+            .remove(&key);
+        if let Some(op_ctx) = op_ctx {
+            if let Err(e) = op_ctx.finish_with_status(ToHostWorkRbDescOpcode::Acknowledge, status) {
+                error!("Set result failed {e:?}");
+            }
+        } else {
+            error!("receive nack {status:?}, but op_ctx not found for {key:?}");
+        }
+
+        Ok(())
+    }
+}
+
+impl WorkDescPoller {
+    /// Stop the thread and wait for it to exit. Stopping a stopped thread is a no-op.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the thread panicked.
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        if stop_thread(&self.stop_flag, &self.thread) {
+            Ok(())
+        } else {
+            Err(Error::ThreadJoinFailed("work descriptor poller"))
+        }
     }
 }
 
 impl Drop for WorkDescPoller {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) =  self.thread.take(){
-            if let Err(e) = thread.join(){
-                error!("Failed to join the WorkDescPoller thread: {:?}", e);
-            }
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the work descriptor poller: {e:?}");
         }
     }
 }
@@ -201,22 +282,25 @@ mod tests {
     use std::{
         collections::HashMap,
         net::Ipv4Addr,
-        sync::{Arc, Mutex, RwLock},
-        thread::sleep,
+        sync::{atomic::AtomicU16, Arc, Mutex, RwLock},
+        time::Duration,
     };
 
     use eui48::MacAddress;
 
     use crate::{
         device::{
-            DeviceError, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescCommon,
-            ToHostWorkRbDescRead, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType,
-            ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType,
+            DeviceError, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescAck,
+            ToHostWorkRbDescAethCode, ToHostWorkRbDescCommon, ToHostWorkRbDescNack,
+            ToHostWorkRbDescOpcode, ToHostWorkRbDescRead, ToHostWorkRbDescStatus,
+            ToHostWorkRbDescTransType, ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType,
         },
-        op_ctx::WriteOpCtx,
+        op_ctx::{CompletionStatus, PendingOp, WriteOpCtx},
+        path_mtu::PathMtuTable,
         qp::QpContext,
         responser::RespCommand,
         types::{Key, MemAccessTypeFlag, Msn, Psn, Qpn},
+        utils::PmtuFragments,
         Pd,
     };
 
@@ -231,12 +315,12 @@ mod tests {
         }
     }
     impl ToHostRb<ToHostWorkRbDesc> for MockToHostRb {
-        fn pop(&self) -> Result<ToHostWorkRbDesc, DeviceError> {
-            let is_empty = self.rb.lock().unwrap().is_empty();
-            if is_empty {
-                sleep(std::time::Duration::from_secs(10))
+        fn pop(&self, timeout: Duration) -> Result<Option<ToHostWorkRbDesc>, DeviceError> {
+            let desc = self.rb.lock().unwrap().pop();
+            if desc.is_none() {
+                std::thread::sleep(timeout);
             }
-            Ok(self.rb.lock().unwrap().pop().unwrap())
+            Ok(desc)
         }
     }
     #[test]
@@ -338,25 +422,39 @@ mod tests {
                 pmtu: crate::types::Pmtu::Mtu1024,
                 local_ip: Ipv4Addr::LOCALHOST,
                 local_mac_addr: MacAddress::new([0; 6]),
+                dqpn: Qpn::new(3),
                 dqp_ip: Ipv4Addr::LOCALHOST,
                 dqp_mac_addr: MacAddress::new([0; 6]),
                 sending_psn: Mutex::new(Psn::new(0)),
+                sending_msn: AtomicU16::new(0),
+                psn_tracker: Mutex::default(),
+                state: crate::types::QpState::Rts,
+                rnr_retry: 0,
+                min_rnr_timer: 0,
+                ack_timeout: None,
             },
         );
         let (sending_queue, recv_queue) = std::sync::mpsc::channel::<RespCommand>();
         let write_op_ctx_map = Arc::new(RwLock::new(HashMap::new()));
-        let key = Msn::default();
+        let key = (Qpn::new(3), Msn::default());
         let ctx = WriteOpCtx::new_running();
-        write_op_ctx_map.write().unwrap().insert(key, ctx.clone());
+        write_op_ctx_map
+            .write()
+            .unwrap()
+            .insert(key, PendingOp::new(ctx.clone(), 3192));
         let work_ctx = super::WorkDescPollerContext {
             work_rb,
             recv_pkt_map,
             qp_table,
+            path_mtus: Arc::new(PathMtuTable::new(false)),
             sending_queue,
             write_op_ctx_map,
         };
         let _poller = WorkDescPoller::new(work_ctx);
-        let _ = ctx.wait();
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.byte_len, 3192);
+        assert_eq!(result.opcode, ToHostWorkRbDescOpcode::Acknowledge);
+        assert_eq!(result.status, CompletionStatus::Success);
         let item = recv_queue.recv().unwrap();
         match item {
             RespCommand::ReadResponse(res) => {
@@ -369,4 +467,137 @@ mod tests {
             _ => panic!("unexpected item"),
         }
     }
+
+    #[test]
+    fn test_nack_completion() {
+        // a NAK of remote access error, such as a write with a wrong rkey
+        let input = vec![ToHostWorkRbDesc::Nack(ToHostWorkRbDescNack {
+            common: ToHostWorkRbDescCommon {
+                dqpn: Qpn::new(3),
+                status: ToHostWorkRbDescStatus::Normal,
+                trans: ToHostWorkRbDescTransType::Rc,
+                pad_cnt: 0,
+                msn: Msn::default(),
+                expected_psn: Psn::default(),
+            },
+            msn: Msn::new(1),
+            code: ToHostWorkRbDescAethCode::Nak,
+            value: 2,
+            lost_psn: Psn::new(0)..Psn::new(0),
+        })];
+        let write_op_ctx_map = Arc::new(RwLock::new(HashMap::new()));
+        let ctx = WriteOpCtx::new_running();
+        write_op_ctx_map
+            .write()
+            .unwrap()
+            .insert((Qpn::new(3), Msn::new(1)), PendingOp::new(ctx.clone(), 64));
+        let (sending_queue, _recv_queue) = std::sync::mpsc::channel::<RespCommand>();
+        let work_ctx = super::WorkDescPollerContext {
+            work_rb: Arc::new(MockToHostRb::new(input)),
+            recv_pkt_map: Arc::new(RwLock::new(HashMap::new())),
+            qp_table: Arc::new(RwLock::new(HashMap::new())),
+            path_mtus: Arc::new(PathMtuTable::new(false)),
+            sending_queue,
+            write_op_ctx_map,
+        };
+        let _poller = WorkDescPoller::new(work_ctx);
+        let result = ctx.wait_result().unwrap().unwrap();
+        assert_eq!(result.byte_len, 64);
+        assert_eq!(result.status, CompletionStatus::RemoteAccessError);
+    }
+
+    #[test]
+    fn test_ack_matched_by_msn() {
+        let ack = |qpn: u32, msn: u16| {
+            ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
+                common: ToHostWorkRbDescCommon {
+                    dqpn: Qpn::new(qpn),
+                    status: ToHostWorkRbDescStatus::Normal,
+                    trans: ToHostWorkRbDescTransType::Rc,
+                    pad_cnt: 0,
+                    msn: Msn::default(),
+                    expected_psn: Psn::default(),
+                },
+                value: 0,
+                msn: Msn::new(msn),
+                psn: Psn::new(0),
+            })
+        };
+        // two writes outstanding on QP 3, and one on QP 4 with the same MSN as the first one
+        let write_op_ctx_map = Arc::new(RwLock::new(HashMap::new()));
+        let ctxs = [(3, 0, 64), (3, 1, 128), (4, 0, 256)].map(|(qpn, msn, len)| {
+            let ctx = WriteOpCtx::new_running();
+            write_op_ctx_map
+                .write()
+                .unwrap()
+                .insert((Qpn::new(qpn), Msn::new(msn)), PendingOp::new(ctx.clone(), len));
+            ctx
+        });
+        // the second write is acknowledged before the first one, popped from the back
+        let input = vec![ack(3, 0), ack(3, 1)];
+        let (sending_queue, _recv_queue) = std::sync::mpsc::channel::<RespCommand>();
+        let work_ctx = super::WorkDescPollerContext {
+            work_rb: Arc::new(MockToHostRb::new(input)),
+            recv_pkt_map: Arc::new(RwLock::new(HashMap::new())),
+            qp_table: Arc::new(RwLock::new(HashMap::new())),
+            path_mtus: Arc::new(PathMtuTable::new(false)),
+            sending_queue,
+            write_op_ctx_map: Arc::clone(&write_op_ctx_map),
+        };
+        let _poller = WorkDescPoller::new(work_ctx);
+        assert_eq!(ctxs[1].wait_result().unwrap().unwrap().byte_len, 128);
+        assert_eq!(ctxs[0].wait_result().unwrap().unwrap().byte_len, 64);
+        assert!(ctxs[2].get_result().is_none());
+        // the completed operations release their MSNs
+        let guard = write_op_ctx_map.read().unwrap();
+        assert_eq!(guard.len(), 1);
+        assert!(guard.contains_key(&(Qpn::new(4), Msn::new(0))));
+    }
+
+    #[test]
+    fn test_ack_byte_len() {
+        let ack = |msn: u16, psn: u32| {
+            ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
+                common: ToHostWorkRbDescCommon {
+                    dqpn: Qpn::new(3),
+                    status: ToHostWorkRbDescStatus::Normal,
+                    trans: ToHostWorkRbDescTransType::Rc,
+                    pad_cnt: 0,
+                    msn: Msn::default(),
+                    expected_psn: Psn::default(),
+                },
+                value: 0,
+                msn: Msn::new(msn),
+                psn: Psn::new(psn),
+            })
+        };
+        // two writes of 3000 bytes in 3 packets of 1024, 1024 and 952 bytes, from psn 10 and 13
+        let write_op_ctx_map = Arc::new(RwLock::new(HashMap::new()));
+        let ctxs = [(0, 10), (1, 13)].map(|(msn, first_psn)| {
+            let ctx = WriteOpCtx::new_running();
+            let op = PendingOp::new(ctx.clone(), 3000).with_packets(
+                Psn::new(first_psn),
+                PmtuFragments::new(0, 3000, crate::types::Pmtu::Mtu1024),
+            );
+            write_op_ctx_map
+                .write()
+                .unwrap()
+                .insert((Qpn::new(3), Msn::new(msn)), op);
+            ctx
+        });
+        // the ACK of the first write covers only two packets
+        let input = vec![ack(1, 15), ack(0, 11)];
+        let (sending_queue, _recv_queue) = std::sync::mpsc::channel::<RespCommand>();
+        let work_ctx = super::WorkDescPollerContext {
+            work_rb: Arc::new(MockToHostRb::new(input)),
+            recv_pkt_map: Arc::new(RwLock::new(HashMap::new())),
+            qp_table: Arc::new(RwLock::new(HashMap::new())),
+            path_mtus: Arc::new(PathMtuTable::new(false)),
+            sending_queue,
+            write_op_ctx_map,
+        };
+        let _poller = WorkDescPoller::new(work_ctx);
+        assert_eq!(ctxs[0].wait_result().unwrap().unwrap().byte_len, 2048);
+        assert_eq!(ctxs[1].wait_result().unwrap().unwrap().byte_len, 3000);
+    }
 }
@@ -1,20 +1,28 @@
 use eui48::MacAddress;
+use log::error;
 
 use crate::{
     device::{ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement},
-    types::{MemAccessTypeFlag, Pmtu, Psn, Qp, QpType, Qpn},
+    types::{
+        MemAccessTypeFlag, Msn, Pmtu, Psn, Qp, QpModifyAttr, QpPsnStats, QpState, QpType, Qpn,
+        MIN_RNR_TIMER_MAX, RNR_RETRY_INFINITE,
+    },
     Device, Error, Pd,
 };
 use std::{
+    collections::{HashSet, VecDeque},
     hash::{Hash, Hasher},
     net::Ipv4Addr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Mutex,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Mutex, MutexGuard, PoisonError,
     },
+    time::Duration,
 };
 
-const QP_MAX_CNT: usize = 1024;
+pub(crate) const QP_MAX_CNT: usize = 1024;
+/// QP0 and QP1 are reserved by IB spec
+pub(crate) const QP_RESERVED_CNT: usize = 2;
 
 /// QP context
 #[allow(clippy::module_name_repetitions)]
@@ -22,8 +30,9 @@ const QP_MAX_CNT: usize = 1024;
 pub struct QpContext {
     pub(crate) pd: Pd,
     pub(crate) qpn: Qpn,
+    /// The QP on the remote the packets are addressed to, see `QpModifyAttr::dqpn`
+    pub(crate) dqpn: Qpn,
     pub(crate) qp_type: QpType,
-    #[allow(unused)]
     pub(crate) rq_acc_flags: MemAccessTypeFlag,
     pub(crate) pmtu: Pmtu,
     #[allow(unused)]
@@ -33,6 +42,14 @@ pub struct QpContext {
     pub(crate) dqp_ip: Ipv4Addr,
     pub(crate) dqp_mac_addr: MacAddress,
     pub(crate) sending_psn: Mutex<Psn>,
+    /// The MSN of the next write or read sent by the QP
+    pub(crate) sending_msn: AtomicU16,
+    /// The PSNs of the writes waiting for an ACK
+    pub(crate) psn_tracker: Mutex<PsnTracker>,
+    pub(crate) state: QpState,
+    pub(crate) rnr_retry: u8,
+    pub(crate) min_rnr_timer: u8,
+    pub(crate) ack_timeout: Option<Duration>,
 }
 
 impl QpContext {
@@ -44,6 +61,7 @@ impl QpContext {
         Self {
             pd: qp.pd,
             qpn: qp.qpn,
+            dqpn: qp.qpn,
             qp_type: qp.qp_type,
             rq_acc_flags: qp.rq_acc_flags,
             pmtu: qp.pmtu,
@@ -52,21 +70,146 @@ impl QpContext {
             dqp_ip: qp.dqp_ip,
             dqp_mac_addr: qp.dqp_mac,
             sending_psn: Mutex::new(Psn::new(0)),
+            sending_msn: AtomicU16::new(0),
+            psn_tracker: Mutex::new(PsnTracker::default()),
+            state: QpState::Rts,
+            rnr_retry: qp.rnr_retry,
+            min_rnr_timer: qp.min_rnr_timer,
+            ack_timeout: qp.ack_timeout,
+        }
+    }
+
+    /// Allocate the MSN of a write or read sent by the QP.
+    ///
+    /// The remote echoes the MSN in its ACK or read response, so the completion is matched with the
+    /// operation by the QP and the MSN. The MSN of the AETH has 24 bits, but a descriptor only carries
+    /// 16 bits of it, so the MSN wraps around after 65536 operations of a QP. An operation should be
+    /// completed before its MSN is allocated again.
+    pub(crate) fn next_msn(&self) -> Msn {
+        Msn::new(self.sending_msn.fetch_add(1, Ordering::AcqRel))
+    }
+
+    /// Check that a one-sided operation such as an RDMA write or read can be issued on the QP.
+    ///
+    /// Only the connected QPs, `QpType::Rc` and `QpType::Uc`, address a remote memory region.
+    pub(crate) fn check_one_sided_op(&self, op: &'static str) -> Result<(), Error> {
+        if matches!(self.qp_type, QpType::Rc | QpType::Uc) {
+            Ok(())
+        } else {
+            Err(Error::InvalidQpTypeForOp {
+                qpn: self.qpn,
+                qp_type: self.qp_type,
+                op,
+            })
         }
     }
 }
 
+/// The acknowledge progress of the packets sent by a QP, see `Device::qp_psn_stats`.
+///
+/// Only the writes of a reliable QP are acknowledged by the remote, so only their packets are
+/// tracked. The reads and the read responses are not.
+#[derive(Debug, Default)]
+pub(crate) struct PsnTracker {
+    /// The highest PSN acknowledged by the remote
+    acked_psn: Option<Psn>,
+    /// The MSN, the first PSN and the packet count of the unacknowledged writes, in the sending order
+    unacked: VecDeque<(Msn, Psn, u32)>,
+}
+
+impl PsnTracker {
+    /// Track the `packet_cnt` packets of the write `msn` starting from `first_psn`.
+    pub(crate) fn on_send(&mut self, msn: Msn, first_psn: Psn, packet_cnt: u32) {
+        self.unacked.push_back((msn, first_psn, packet_cnt));
+    }
+
+    /// The ACK of `psn` acknowledges all the packets up to it.
+    pub(crate) fn on_ack(&mut self, psn: Psn) {
+        while let Some((_, first_psn, packet_cnt)) = self.unacked.front_mut() {
+            // the packets of the write up to `psn` are acknowledged
+            let acked_cnt =
+                u32::try_from(first_psn.distance_to(psn).saturating_add(1)).unwrap_or(0);
+            if acked_cnt == 0 {
+                break;
+            }
+            if acked_cnt < *packet_cnt {
+                *first_psn = first_psn.wrapping_add(acked_cnt);
+                *packet_cnt = packet_cnt.wrapping_sub(acked_cnt);
+                break;
+            }
+            let _: Option<(Msn, Psn, u32)> = self.unacked.pop_front();
+        }
+        match self.acked_psn {
+            Some(acked_psn) if !psn.is_after(acked_psn) => {}
+            _ => self.acked_psn = Some(psn),
+        }
+    }
+
+    /// The write `msn` failed with a NAK, so its packets will never be acknowledged.
+    pub(crate) fn on_nack(&mut self, msn: Msn) {
+        self.unacked.retain(|&(unacked_msn, _, _)| unacked_msn != msn);
+    }
+
+    /// The number of the sent packets which are not acknowledged yet
+    pub(crate) fn outstanding(&self) -> u32 {
+        self.unacked
+            .iter()
+            .fold(0_u32, |sum, &(_, _, packet_cnt)| sum.saturating_add(packet_cnt))
+    }
+}
+
 impl Device {
+    /// Query the sequence numbers of a qp, to tell whether a stuck transfer is not sent or not
+    /// acknowledged.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the qp is not found
+    pub fn qp_psn_stats(&self, qpn: Qpn) -> Result<QpPsnStats, Error> {
+        let qp_pool = self
+            .0
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        let qp_ctx = qp_pool
+            .get(&qpn)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+        let next_psn = *qp_ctx
+            .sending_psn
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp context sending_psn lock"))?;
+        let tracker = qp_ctx
+            .psn_tracker
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp context psn tracker lock"))?;
+        Ok(QpPsnStats {
+            next_psn,
+            acked_psn: tracker.acked_psn,
+            outstanding: tracker.outstanding(),
+        })
+    }
+
     /// create a qp
     ///
     /// # Errors
     ///
     /// Will return `Err` if:
     /// * lock poisoned
+    /// * `rnr_retry` or `min_rnr_timer` is out of range
     /// * opeartion failed
     /// * Operating system not support
     /// * Setted context result failed
     pub fn create_qp(&self, qp: &Qp) -> Result<(), Error> {
+        if qp.rnr_retry > RNR_RETRY_INFINITE {
+            return Err(Error::Invalid(format!("rnr retry :{0}", qp.rnr_retry)));
+        }
+        if qp.min_rnr_timer > MIN_RNR_TIMER_MAX {
+            return Err(Error::Invalid(format!("min rnr timer :{0}", qp.min_rnr_timer)));
+        }
+        // probe the path before taking the locks, which the socket of the probe must not block
+        self.0.path_mtus.probe(qp.dqp_ip)?;
         let mut qp_pool = self
             .0
             .qp_table
@@ -97,15 +240,11 @@ impl Device {
             qp_type: qp.qp_type,
             rq_acc_flags: qp.rq_acc_flags,
             pmtu: qp.pmtu,
+            rnr_retry: qp.rnr_retry,
+            min_rnr_timer: qp.min_rnr_timer,
         });
 
-        let ctx = self.do_ctrl_op(op_id, desc)?;
-
-        let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
-
-        if !res {
-            return Err(Error::DeviceReturnFailed("create qp"));
-        }
+        self.do_ctrl_op(op_id, desc)?.wait_success("create qp")?;
 
         let pd_res = pd_ctx.qp.insert(qp.qpn);
         if !pd_res{
@@ -122,6 +261,9 @@ impl Device {
 
     /// destory a qp
     ///
+    /// The qpn is still taken in the `QpManager` it is allocated from. Call `QpManager::free` after
+    /// the qp is destroyed to reuse the qpn.
+    ///
     /// # Errors
     ///
     /// Will return `Err` if:
@@ -129,6 +271,11 @@ impl Device {
     /// * opeartion failed
     /// * Setted context result failed
     pub fn destroy_qp(&self, qp: Qpn) -> Result<(), Error> {
+        let _modifying: MutexGuard<'_, ()> = self
+            .0
+            .qp_modify_lock
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp modify lock"))?;
         let mut qp_pool = self.0.qp_table.write().map_err(|_| Error::LockPoisoned("qp_table lock"))?;
         let mut pd_pool = self.0.pd.lock().map_err(|_| Error::LockPoisoned("pd pool lock"))?;
 
@@ -146,25 +293,383 @@ impl Device {
                 qp_type: qp_ctx.qp_type,
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessNoFlags,
                 pmtu: qp_ctx.pmtu,
+                rnr_retry: qp_ctx.rnr_retry,
+                min_rnr_timer: qp_ctx.min_rnr_timer,
             });
             (pd_ctx, desc)
         } else {
             return Err(Error::Invalid(format!("Qpn :{qp:?}")));
         };
 
-        let ctx = self.do_ctrl_op(op_id, desc)?;
+        self.do_ctrl_op(op_id, desc)?.wait_success("destroy qp")?;
 
-        let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
+        let _: bool = pd_ctx.qp.remove(&qp);
+        let removed = qp_pool.remove(&qp);
 
-        if !res {
-            return Err(Error::DeviceReturnFailed("destroy qp"));
+        // The qp is gone once it's removed, so the cleanup below no longer fails the destroy. The
+        // device detaches a destroyed qp from its srq.
+        for srq_ctx in self
+            .0
+            .srq_table
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values_mut()
+        {
+            let _: bool = srq_ctx.qp.remove(&qp);
         }
 
-        let _: bool = pd_ctx.qp.remove(&qp);
-        let _: Option<QpContext> = qp_pool.remove(&qp);
+        // a qp created with the same qpn talks to the remote qp of the same number again
+        if removed.is_some_and(|qp_ctx| qp_ctx.dqpn != qp) {
+            if let Err(e) = self.0.adaptor.set_peer_qpn(qp, qp) {
+                error!("failed to reset the destination qp of the destroyed {qp:?}: {e}");
+            }
+        }
+
+        // a destroyed qp no longer receives the packets of its multicast groups
+        let mut groups = self
+            .0
+            .mcast_groups
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut left = Vec::new();
+        for (mgid, qps) in groups.iter_mut() {
+            if qps.remove(&qp) && qps.is_empty() {
+                left.push(*mgid);
+            }
+        }
+        for mgid in left {
+            let _: Option<HashSet<Qpn>> = groups.remove(&mgid);
+            if let Err(e) = self.0.adaptor.leave_multicast(mgid) {
+                error!("failed to leave the multicast group {mgid} of the destroyed {qp:?}: {e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attach a `QpType::Ud` qp to the multicast group `mgid`.
+    ///
+    /// The device joins the group when the first qp is attached to it, and then receives the packets
+    /// sent to the group address. A `Device::send_ud` to `mgid` reaches every device attached to the
+    /// group, where it fills the next receive buffer of the qp. Attaching a qp that is already attached does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * `mgid` is not a multicast address, or the qp is not found
+    /// * the qp is not a `QpType::Ud` qp
+    /// * the adaptor failed to join the group
+    pub fn attach_mcast(&self, qpn: Qpn, mgid: Ipv4Addr) -> Result<(), Error> {
+        if !mgid.is_multicast() {
+            return Err(Error::Invalid(format!("multicast group :{mgid}")));
+        }
+        let qp_pool = self
+            .0
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        let qp_ctx = qp_pool
+            .get(&qpn)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+        if !matches!(qp_ctx.qp_type, QpType::Ud) {
+            return Err(Error::InvalidQpTypeForOp {
+                qpn,
+                qp_type: qp_ctx.qp_type,
+                op: "multicast attach",
+            });
+        }
+        let mut groups = self
+            .0
+            .mcast_groups
+            .lock()
+            .map_err(|_| Error::LockPoisoned("multicast groups lock"))?;
+        if !groups.contains_key(&mgid) {
+            self.0.adaptor.join_multicast(mgid).map_err(|e| Error::Device(Box::new(e)))?;
+        }
+        let _: bool = groups.entry(mgid).or_default().insert(qpn);
+        Ok(())
+    }
+
+    /// Detach a qp from the multicast group `mgid`.
+    ///
+    /// The device leaves the group when the last qp is detached from it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the qp is not attached to `mgid`
+    /// * the adaptor failed to leave the group
+    pub fn detach_mcast(&self, qpn: Qpn, mgid: Ipv4Addr) -> Result<(), Error> {
+        let mut groups = self
+            .0
+            .mcast_groups
+            .lock()
+            .map_err(|_| Error::LockPoisoned("multicast groups lock"))?;
+        let members = groups
+            .get_mut(&mgid)
+            .filter(|members| members.contains(&qpn))
+            .ok_or(Error::Invalid(format!("{qpn:?} is not attached to {mgid}")))?;
+        let _: bool = members.remove(&qpn);
+        if members.is_empty() {
+            let _: Option<HashSet<Qpn>> = groups.remove(&mgid);
+            self.0.adaptor.leave_multicast(mgid).map_err(|e| Error::Device(Box::new(e)))?;
+        }
+        Ok(())
+    }
+
+    /// Switch the reliability of a qp at runtime, e.g. downgrade a `QpType::Rc` qp to `QpType::Uc`
+    /// under extreme loss.
+    ///
+    /// The qp context in the device is rebuilt with the new type, while the qpn and the sending psn
+    /// are preserved. Only the transitions between `QpType::Rc` and `QpType::Uc` are supported.
+    /// The qp must have no operation waiting for its response, so drain or cancel them first.
+    /// If the device fails to rebuild the qp, it is restored with the old type.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the qp is not found or the transition is not supported
+    /// * the qp has operations waiting for their responses
+    /// * opeartion failed
+    /// * Setted context result failed
+    pub fn set_qp_reliability(&self, qpn: Qpn, qp_type: QpType) -> Result<(), Error> {
+        let _modifying: MutexGuard<'_, ()> = self
+            .0
+            .qp_modify_lock
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp modify lock"))?;
+        let mut qp_pool = self
+            .0
+            .qp_table
+            .write()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        let qp_ctx = qp_pool
+            .get_mut(&qpn)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+
+        match (qp_ctx.qp_type, qp_type) {
+            (QpType::Rc, QpType::Rc) | (QpType::Uc, QpType::Uc) => return Ok(()),
+            (QpType::Rc, QpType::Uc) | (QpType::Uc, QpType::Rc) => {}
+            (from, to) => {
+                return Err(Error::Invalid(format!(
+                    "qp type transition {from:?} -> {to:?}"
+                )))
+            }
+        }
+        // the responses of the pending operations would be handled by the wrong type. No operation
+        // can be issued meanwhile, as it looks up the qp table first.
+        let pending = self.pending_ops(qpn)?;
+        if pending > 0 {
+            return Err(Error::QpHasPendingOps(qpn, pending));
+        }
+
+        // The device can only create or destroy a qp context, so we rebuild it with the new type.
+        let old_type = qp_ctx.qp_type;
+        self.update_qp_type(qp_ctx, false, old_type)?;
+        if let Err(e) = self.update_qp_type(qp_ctx, true, qp_type) {
+            // bring the qp back, so that it's still usable with the old type
+            if let Err(restore_err) = self.update_qp_type(qp_ctx, true, old_type) {
+                error!("failed to restore {qpn:?} as {old_type:?}: {restore_err}");
+            }
+            return Err(e);
+        }
+        qp_ctx.qp_type = qp_type;
+
+        Ok(())
+    }
+
+    /// Create or destroy the context of the qp `qp_ctx` in the device, with the type `qp_type`.
+    fn update_qp_type(
+        &self,
+        qp_ctx: &QpContext,
+        is_valid: bool,
+        qp_type: QpType,
+    ) -> Result<(), Error> {
+        let op_id = self.get_ctrl_op_id();
+        let desc = ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
+            common: ToCardCtrlRbDescCommon { op_id },
+            is_valid,
+            qpn: qp_ctx.qpn,
+            pd_hdl: qp_ctx.pd.handle,
+            qp_type,
+            rq_acc_flags: qp_ctx.rq_acc_flags,
+            pmtu: qp_ctx.pmtu,
+            rnr_retry: qp_ctx.rnr_retry,
+            min_rnr_timer: qp_ctx.min_rnr_timer,
+        });
+        self.do_ctrl_op(op_id, desc)?.wait_success("set qp reliability")
+    }
+
+    /// The number of the writes and reads of `qpn` waiting for their responses
+    fn pending_ops(&self, qpn: Qpn) -> Result<usize, Error> {
+        let mut pending: usize = 0;
+        for (map, lock_name) in [
+            (&self.0.write_op_ctx_map, "write_op_ctx_map lock"),
+            (&self.0.read_op_ctx_map, "read_op_ctx_map lock"),
+        ] {
+            let guard = map.read().map_err(|_| Error::LockPoisoned(lock_name))?;
+            let cnt = guard.keys().filter(|(op_qpn, _)| *op_qpn == qpn).count();
+            pending = pending.saturating_add(cnt);
+        }
+        Ok(pending)
+    }
+
+    /// Modify the attributes of a qp, or move it to another state.
+    ///
+    /// The remote address and the destination qp can be changed to re-establish a connection without
+    /// recreating the qp. The qp context in the device is removed when the qp moves to
+    /// `QpState::Reset` or `QpState::Error`, and rebuilt with the new attributes otherwise, preserving
+    /// the sending psn. If the device fails to rebuild it, it is rebuilt with the old attributes.
+    ///
+    /// The qp table is not locked while the device applies the change, so the operations issued
+    /// meanwhile go out with the old attributes.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the qp is not found or the state transition is not allowed
+    /// * the destination qp is changed, but the adaptor does not support it
+    /// * opeartion failed
+    /// * Setted context result failed
+    pub fn modify_qp(&self, qpn: Qpn, attr: QpModifyAttr) -> Result<(), Error> {
+        if let Some(dqp_ip) = attr.dqp_ip {
+            self.0.path_mtus.probe(dqp_ip)?;
+        }
+        let _modifying: MutexGuard<'_, ()> = self
+            .0
+            .qp_modify_lock
+            .lock()
+            .map_err(|_| Error::LockPoisoned("qp modify lock"))?;
+        let (old, old_state, old_dqpn) = {
+            let qp_pool = self
+                .0
+                .qp_table
+                .read()
+                .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+            let qp_ctx = qp_pool
+                .get(&qpn)
+                .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            (QpDeviceAttr::of(qp_ctx), qp_ctx.state, qp_ctx.dqpn)
+        };
+
+        let state = attr.state.unwrap_or(old_state);
+        if !old_state.can_transition_to(state) {
+            return Err(Error::Invalid(format!(
+                "qp state transition {old_state:?} -> {state:?}"
+            )));
+        }
+        let new = QpDeviceAttr {
+            pmtu: attr.pmtu.unwrap_or(old.pmtu),
+            rq_acc_flags: attr.rq_acc_flags.unwrap_or(old.rq_acc_flags),
+            ..old
+        };
+        let dest_qpn = attr.dqpn.unwrap_or(old_dqpn);
+        if dest_qpn != old_dqpn {
+            self.0.adaptor.set_peer_qpn(qpn, dest_qpn).map_err(|e| Error::Device(Box::new(e)))?;
+        }
+        if let Err(e) = self.rebuild_qp(qpn, old, old_state.is_active(), new, state.is_active()) {
+            if dest_qpn != old_dqpn {
+                if let Err(restore_err) = self.0.adaptor.set_peer_qpn(qpn, old_dqpn) {
+                    error!("failed to restore the destination qp of {qpn:?}: {restore_err}");
+                }
+            }
+            return Err(e);
+        }
 
+        let mut qp_pool = self
+            .0
+            .qp_table
+            .write()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        // the qp is not destroyed meanwhile, as it's serialized with the modification
+        let qp_ctx = qp_pool
+            .get_mut(&qpn)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+        qp_ctx.state = state;
+        qp_ctx.pmtu = new.pmtu;
+        qp_ctx.rq_acc_flags = new.rq_acc_flags;
+        qp_ctx.dqpn = dest_qpn;
+        if let Some(dqp_ip) = attr.dqp_ip {
+            qp_ctx.dqp_ip = dqp_ip;
+        }
+        if let Some(dqp_mac) = attr.dqp_mac {
+            qp_ctx.dqp_mac_addr = dqp_mac;
+        }
         Ok(())
     }
+
+    /// Rebuild the context of `qpn` in the device from `old` to `new`, which is active or not.
+    ///
+    /// Like `set_qp_reliability`, the device context is destroyed and created again to apply the
+    /// change. If it fails to be created with `new`, it's created with `old` again, so that the qp
+    /// is still usable.
+    fn rebuild_qp(
+        &self,
+        qpn: Qpn,
+        old: QpDeviceAttr,
+        was_active: bool,
+        new: QpDeviceAttr,
+        is_active: bool,
+    ) -> Result<(), Error> {
+        if was_active {
+            self.update_qp_context(qpn, old, false)?;
+        }
+        if !is_active {
+            return Ok(());
+        }
+        let result = self.update_qp_context(qpn, new, true);
+        if result.is_err() && was_active {
+            if let Err(restore_err) = self.update_qp_context(qpn, old, true) {
+                error!("failed to restore the context of {qpn:?}: {restore_err}");
+            }
+        }
+        result
+    }
+
+    /// Create or destroy the context of the qp `qpn` in the device, with the attributes `attr`.
+    fn update_qp_context(&self, qpn: Qpn, attr: QpDeviceAttr, is_valid: bool) -> Result<(), Error> {
+        let op_id = self.get_ctrl_op_id();
+        let desc = ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
+            common: ToCardCtrlRbDescCommon { op_id },
+            is_valid,
+            qpn,
+            pd_hdl: attr.pd_hdl,
+            qp_type: attr.qp_type,
+            rq_acc_flags: attr.rq_acc_flags,
+            pmtu: attr.pmtu,
+            rnr_retry: attr.rnr_retry,
+            min_rnr_timer: attr.min_rnr_timer,
+        });
+        self.do_ctrl_op(op_id, desc)?.wait_success("modify qp")
+    }
+}
+
+/// The attributes of a qp its context in the device is built from
+#[derive(Debug, Clone, Copy)]
+struct QpDeviceAttr {
+    pd_hdl: u32,
+    qp_type: QpType,
+    rq_acc_flags: MemAccessTypeFlag,
+    pmtu: Pmtu,
+    rnr_retry: u8,
+    min_rnr_timer: u8,
+}
+
+impl QpDeviceAttr {
+    fn of(qp_ctx: &QpContext) -> Self {
+        Self {
+            pd_hdl: qp_ctx.pd.handle,
+            qp_type: qp_ctx.qp_type,
+            rq_acc_flags: qp_ctx.rq_acc_flags,
+            pmtu: qp_ctx.pmtu,
+            rnr_retry: qp_ctx.rnr_retry,
+            min_rnr_timer: qp_ctx.min_rnr_timer,
+        }
+    }
 }
 
 impl Hash for Qp {
@@ -200,10 +705,8 @@ impl QpManager {
             (0..QP_MAX_CNT).map(|_| AtomicBool::new(true)).collect();
 
         // by IB spec, QP0 and QP1 are reserved, so qpn should start with 2
-        #[allow(clippy::indexing_slicing)]
-        {
-            qp_availability[0].store(false, Ordering::Relaxed);
-            qp_availability[1].store(false, Ordering::Relaxed);
+        for reserved in qp_availability.iter().take(QP_RESERVED_CNT) {
+            reserved.store(false, Ordering::Relaxed);
         }
 
         Self {
@@ -230,13 +733,29 @@ impl QpManager {
             .ok_or_else(|| Error::ResourceNoAvailable("QP".to_owned()))
     }
 
-    /// free a qp number
-    /// 
-    /// If the QP number is not allocated, it will be ignored.
-    pub fn free(&self, qpn: Qpn) {
-        if let Some(qp_availability) = self.qp_availability.get(qpn.get() as usize){
-            qp_availability.store(true, Ordering::Relaxed);
+    /// free a qp number, so that it can be allocated again
+    ///
+    /// The qp should be destroyed by `Device::destroy_qp` before, otherwise a new qp with the same
+    /// number can't be created.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the qp number is out of range or reserved
+    /// * the qp number is not allocated, e.g. it is freed twice
+    pub fn free(&self, qpn: Qpn) -> Result<(), Error> {
+        let idx = qpn.get() as usize;
+        if idx < QP_RESERVED_CNT {
+            return Err(Error::Invalid(format!("reserved Qpn :{qpn:?}")));
+        }
+        let qp_availability = self
+            .qp_availability
+            .get(idx)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+        if qp_availability.swap(true, Ordering::AcqRel) {
+            return Err(Error::Invalid(format!("Qpn :{qpn:?} is not allocated")));
         }
+        Ok(())
     }
 }
 
@@ -245,3 +764,293 @@ impl Default for QpManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread::sleep, time::Duration};
+
+    use eui48::MacAddress;
+    use serial_test::serial;
+
+    use crate::{
+        types::{
+            Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpBuilder, QpModifyAttrBuilder, QpState, QpType,
+            Qpn, RdmaDeviceNetworkParamBuilder, Sge,
+        },
+        Device, Error,
+    };
+
+    use super::{PsnTracker, QpManager, QP_MAX_CNT, QP_RESERVED_CNT};
+
+    #[test]
+    #[serial]
+    fn test_downgrade_rc_to_uc() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 3))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(2);
+        // no one listens on the remote address, so the writes will never be acknowledged
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 4))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        let ctx = dev.write(qpn, 0, Key::new(0), flags, sge).unwrap();
+        sleep(Duration::from_millis(100));
+        assert!(ctx.get_result().is_none(), "RC write should wait for ACK");
+
+        // the pending write would be acknowledged to a UC qp
+        assert!(matches!(
+            dev.set_qp_reliability(qpn, QpType::Uc),
+            Err(Error::QpHasPendingOps(pending_qpn, 1)) if pending_qpn == qpn
+        ));
+        assert!(ctx.cancel().unwrap());
+        dev.set_qp_reliability(qpn, QpType::Uc).unwrap();
+        let ctx = dev.write(qpn, 0, Key::new(0), flags, sge).unwrap();
+        assert!(ctx.get_result().is_some(), "UC write should not wait for ACK");
+
+        assert!(matches!(
+            dev.set_qp_reliability(qpn, QpType::Ud),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_psn_tracker() {
+        let mut tracker = PsnTracker::default();
+        // the first write wraps around the psn space
+        tracker.on_send(Msn::new(0), Psn::new(0xfffffe), 3);
+        tracker.on_send(Msn::new(1), Psn::new(1), 1);
+        tracker.on_send(Msn::new(2), Psn::new(2), 2);
+        assert_eq!(tracker.outstanding(), 6);
+        assert_eq!(tracker.acked_psn, None);
+
+        // the ACKs are cumulative, and may stop in the middle of a write
+        tracker.on_ack(Psn::new(0xffffff));
+        assert_eq!(tracker.outstanding(), 4);
+        tracker.on_ack(Psn::new(1));
+        assert_eq!(tracker.outstanding(), 2);
+        assert_eq!(tracker.acked_psn, Some(Psn::new(1)));
+
+        // a late ACK changes nothing
+        tracker.on_ack(Psn::new(0xfffffe));
+        assert_eq!(tracker.outstanding(), 2);
+        assert_eq!(tracker.acked_psn, Some(Psn::new(1)));
+
+        // a failed write will never be acknowledged
+        tracker.on_nack(Msn::new(2));
+        assert_eq!(tracker.outstanding(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_modify_qp() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 10))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(7);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Uc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 11))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let new_ip = Ipv4Addr::new(127, 0, 0, 12);
+        let attr = QpModifyAttrBuilder::default()
+            .dqp_ip(new_ip)
+            .build()
+            .unwrap();
+        dev.modify_qp(qpn, attr).unwrap();
+        assert_eq!(dev.0.qp_table.read().unwrap()[&qpn].dqp_ip, new_ip);
+
+        // a qp in error can't send, and has to be reset before being used again
+        let to_state = |state| QpModifyAttrBuilder::default().state(state).build().unwrap();
+        dev.modify_qp(qpn, to_state(QpState::Error)).unwrap();
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        assert!(matches!(
+            dev.write(qpn, 0, Key::new(0), flags, sge),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            dev.modify_qp(qpn, to_state(QpState::Rts)),
+            Err(Error::Invalid(_))
+        ));
+        for state in [QpState::Reset, QpState::Init, QpState::Rtr, QpState::Rts] {
+            dev.modify_qp(qpn, to_state(state)).unwrap();
+        }
+        let ctx = dev.write(qpn, 0, Key::new(0), flags, sge).unwrap();
+        assert!(ctx.get_result().is_some());
+    }
+
+    #[test]
+    #[serial]
+    fn test_modify_dqpn() {
+        let networks: Vec<_> = [76, 77]
+            .into_iter()
+            .map(|host| {
+                RdmaDeviceNetworkParamBuilder::default()
+                    .gateway(Ipv4Addr::new(127, 0, 0, 1))
+                    .netmask(Ipv4Addr::new(255, 0, 0, 0))
+                    .ipaddr(Ipv4Addr::new(127, 0, 0, host))
+                    .macaddr(MacAddress::default())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        // the qps of different numbers are connected to each other
+        let mut cards: Vec<_> = networks
+            .iter()
+            .zip(networks.iter().rev())
+            .zip([(Qpn::new(5), Qpn::new(6)), (Qpn::new(6), Qpn::new(5))])
+            .map(|((local, remote), (qpn, dqpn))| {
+                let dev = Device::new_software_loopback(local).unwrap();
+                let pd = dev.alloc_pd().unwrap();
+                let (mr, buf) = dev.alloc_and_reg_mr(pd, 4096, access_flag).unwrap();
+                let qp = QpBuilder::default()
+                    .pd(pd)
+                    .qpn(qpn)
+                    .qp_type(QpType::Rc)
+                    .rq_acc_flags(access_flag)
+                    .pmtu(Pmtu::Mtu1024)
+                    .dqp_ip(remote.ipaddr)
+                    .dqp_mac(remote.macaddr)
+                    .build()
+                    .unwrap();
+                dev.create_qp(&qp).unwrap();
+                let attr = QpModifyAttrBuilder::default().dqpn(dqpn).build().unwrap();
+                dev.modify_qp(qpn, attr).unwrap();
+                assert_eq!(dev.0.qp_table.read().unwrap()[&qpn].dqpn, dqpn);
+                (dev, qpn, mr, buf)
+            })
+            .collect();
+
+        let data: Vec<u8> = (0..2048_u32).map(|i| (i % 251) as u8).collect();
+        cards[0].3[..2048].copy_from_slice(&data);
+        let (dev_a, qpn_a, mr_a, buf_a) = &cards[0];
+        let (_, _, mr_b, buf_b) = &cards[1];
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        let sge = Sge::new(buf_a.as_ptr() as u64, 2048, mr_a.get_key());
+        let ctx = dev_a
+            .write(*qpn_a, buf_b.as_ptr() as u64, mr_b.get_key(), flags, sge)
+            .unwrap();
+        assert_eq!(ctx.wait_result().unwrap().unwrap().byte_len, 2048);
+        assert_eq!(buf_b[..2048], data[..]);
+
+        // the read response comes back to the qp of the requester as well
+        let sink = Sge::new(buf_a.as_ptr() as u64 + 2048, 2048, mr_a.get_key());
+        let ctx = dev_a
+            .read(*qpn_a, buf_b.as_ptr() as u64, mr_b.get_key(), flags, sink)
+            .unwrap();
+        assert_eq!(ctx.wait_result().unwrap().unwrap().byte_len, 2048);
+        assert_eq!(buf_a[2048..], data[..]);
+
+        for (dev, qpn, _, _) in &cards {
+            dev.destroy_qp(*qpn).unwrap();
+            dev.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_one_sided_op_on_ud_qp() {
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 31))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let qpn = Qpn::new(4);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Ud)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(Ipv4Addr::new(127, 0, 0, 32))
+            .dqp_mac(MacAddress::default())
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let buf = [0u8; 64];
+        let sge = Sge::new(buf.as_ptr() as u64, 64, Key::new(0));
+        let flags = MemAccessTypeFlag::IbvAccessNoFlags;
+        assert!(matches!(
+            dev.write(qpn, 0, Key::new(0), flags, sge),
+            Err(Error::InvalidQpTypeForOp {
+                qp_type: QpType::Ud,
+                ..
+            })
+        ));
+        assert!(matches!(
+            dev.read(qpn, 0, Key::new(0), flags, sge),
+            Err(Error::InvalidQpTypeForOp {
+                qp_type: QpType::Ud,
+                ..
+            })
+        ));
+        // nothing is sent, so the psn is not consumed
+        assert_eq!(dev.0.qp_table.read().unwrap()[&qpn].sending_psn.lock().unwrap().get(), 0);
+    }
+
+    #[test]
+    fn test_qp_manager_free() {
+        let manager = QpManager::new();
+        let qpns: Vec<Qpn> = std::iter::from_fn(|| manager.alloc().ok()).collect();
+        assert_eq!(qpns.len(), QP_MAX_CNT - QP_RESERVED_CNT);
+        assert_eq!(qpns[0].get(), 2);
+
+        for qpn in [qpns[3], qpns[100]] {
+            manager.free(qpn).unwrap();
+        }
+        assert!(matches!(manager.free(qpns[3]), Err(Error::Invalid(_))));
+        assert!(matches!(manager.free(Qpn::new(1)), Err(Error::Invalid(_))));
+        assert!(matches!(
+            manager.free(Qpn::new(QP_MAX_CNT as u32)),
+            Err(Error::Invalid(_))
+        ));
+
+        assert_eq!(manager.alloc().unwrap(), qpns[3]);
+        assert_eq!(manager.alloc().unwrap(), qpns[100]);
+        assert!(matches!(
+            manager.alloc(),
+            Err(Error::ResourceNoAvailable(_))
+        ));
+    }
+}
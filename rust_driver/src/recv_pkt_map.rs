@@ -1,6 +1,13 @@
-use std::mem;
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex},
+};
 
-use crate::types::{Psn, Qpn};
+use crate::types::{Msn, Psn, Qpn};
+
+/// The packet maps of the messages being received, keyed by the QP and the MSN of the messages
+pub(crate) type RecvPktMaps = HashMap<(Qpn, Msn), Arc<Mutex<RecvPktMap>>>;
 
 #[derive(Debug)]
 pub(crate) struct RecvPktMap {
@@ -16,6 +23,8 @@ pub(crate) struct RecvPktMap {
     last_pkt_psn: Psn,
     is_out_of_order: bool,
     dqpn: Qpn,
+    /// The bytes of payload received by the packets of the message
+    received_len: u32,
 }
 
 impl RecvPktMap {
@@ -53,6 +62,7 @@ impl RecvPktMap {
             last_pkt_psn: start_psn.wrapping_sub(1),
             is_out_of_order: false,
             dqpn,
+            received_len: 0,
         }
     }
 
@@ -117,4 +127,17 @@ impl RecvPktMap {
     pub(crate) fn end_psn(&self) -> Psn {
         self.end_psn
     }
+
+    pub(crate) fn is_single_packet(&self) -> bool {
+        self.start_psn == self.end_psn
+    }
+
+    /// Count the `len` bytes of payload of a received packet.
+    pub(crate) fn add_received_len(&mut self, len: u32) {
+        self.received_len = self.received_len.saturating_add(len);
+    }
+
+    pub(crate) fn received_len(&self) -> u32 {
+        self.received_len
+    }
 }
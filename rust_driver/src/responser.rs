@@ -1,50 +1,63 @@
 use std::collections::HashMap;
 use std::ptr::NonNull;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::RwLock;
-use std::thread::sleep;
-use std::time::Duration;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
 use std::{net::Ipv4Addr, slice::from_raw_parts_mut, sync::Arc, thread::spawn};
 
 use lockfree::queue::Queue;
 use log::error;
 
 use crate::device::descriptor::{Aeth, Bth, Ipv4, NReth, Udp};
+use crate::device::scheduler::credit::AETH_CREDIT_INVALID;
+use crate::path_mtu::PathMtuTable;
 use crate::qp::QpContext;
-use crate::types::{Key, MemAccessTypeFlag, Msn, Psn, QpType, Qpn};
+use crate::types::{Key, MemAccessTypeFlag, Msn, Pmtu, Psn, QpType, Qpn};
 
 use crate::device::{
-    ToCardWorkRbDescBuilder, ToCardWorkRbDescCommon, ToHostWorkRbDescAethCode,
+    DeviceError, ToCardWorkRbDescBuilder, ToCardWorkRbDescCommon, ToHostWorkRbDescAethCode,
     ToHostWorkRbDescOpcode, ToHostWorkRbDescRead,
 };
-use crate::utils::{calculate_packet_cnt, HugePage};
+use crate::utils::{calculate_packet_cnt, stop_thread, HugePage};
 use crate::{Error, Sge, WorkDescriptorSender};
 
 /// Command about ACK and NACK
 /// Typically, the message is sent by checker thread, which is responsible for checking the packet ordering.
+///
+/// The checker takes the acknowledge buffer `slot` the packet is written to, so that it holds back the
+/// ack while the buffer is full.
 pub(crate) struct RespAckCommand {
     pub(crate) dpqn: Qpn,
     pub(crate) msn: Msn,
     pub(crate) psn: Psn,
     pub(crate) last_retry_psn: Option<Psn>,
+    pub(crate) slot: Slot,
 }
 
 impl RespAckCommand {
-    pub(crate) fn new_ack(dpqn: Qpn, msn: Msn, last_psn: Psn) -> Self {
+    pub(crate) fn new_ack(dpqn: Qpn, msn: Msn, last_psn: Psn, slot: Slot) -> Self {
         Self {
             dpqn,
             msn,
             psn: last_psn,
             last_retry_psn: None,
+            slot,
         }
     }
 
-    pub(crate) fn new_nack(dpqn: Qpn, msg_seq_num: Msn, psn: Psn, last_retry_psn: Psn) -> Self {
+    pub(crate) fn new_nack(
+        dpqn: Qpn,
+        msg_seq_num: Msn,
+        psn: Psn,
+        last_retry_psn: Psn,
+        slot: Slot,
+    ) -> Self {
         Self {
             dpqn,
             msn: msg_seq_num,
             psn,
             last_retry_psn: Some(last_retry_psn),
+            slot,
         }
     }
 }
@@ -67,7 +80,7 @@ pub(crate) enum RespCommand {
 /// A thread that is responsible for sending the response to the other side
 #[derive(Debug)]
 pub(crate) struct DescResponser {
-    thread: Option<std::thread::JoinHandle<()>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
 }
 
@@ -75,14 +88,22 @@ impl DescResponser {
     pub(crate) fn new(
         device: Arc<dyn WorkDescriptorSender>,
         recving_queue: std::sync::mpsc::Receiver<RespCommand>,
-        ack_buffers: Arc<AcknowledgeBuffer>,
         qp_table: Arc<RwLock<HashMap<Qpn, QpContext>>>,
+        path_mtus: Arc<PathMtuTable>,
     ) -> Self {
         let stop_flag = Arc::new(AtomicBool::new(false));
         let thread_stop_flag = Arc::clone(&stop_flag);
-        let thread = spawn(move|| Self::working_thread(device, recving_queue, &ack_buffers, &qp_table,&thread_stop_flag));
+        let thread = spawn(move || {
+            Self::working_thread(
+                device,
+                recving_queue,
+                &qp_table,
+                &path_mtus,
+                &thread_stop_flag,
+            );
+        });
         Self {
-            thread : Some(thread),
+            thread: Mutex::new(Some(thread)),
             stop_flag
         }
     }
@@ -91,6 +112,7 @@ impl DescResponser {
         command: &RespAckCommand,
         qp: &QpContext,
         dst_ip: Ipv4Addr,
+        pmtu: Pmtu,
     ) -> ToCardWorkRbDescCommon {
         // `ACKPACKET_SIZE` is a constant, it is safe to cast it to u32
         #[allow(clippy::cast_possible_truncation)]
@@ -101,7 +123,7 @@ impl DescResponser {
             dqp_ip: dst_ip,
             dqpn: command.dpqn,
             mac_addr: qp.dqp_mac_addr,
-            pmtu: qp.pmtu,
+            pmtu,
             flags: MemAccessTypeFlag::IbvAccessNoFlags,
             qp_type: QpType::RawPacket,
             psn: Psn::default(),
@@ -111,6 +133,7 @@ impl DescResponser {
 
     fn create_read_resp_common(
         qp_table: &RwLock<HashMap<Qpn, QpContext>>,
+        path_mtus: &PathMtuTable,
         resp: &RespReadRespCommand,
     ) -> Result<ToCardWorkRbDescCommon, Error> {
         let dpqn = resp.desc.common.dqpn;
@@ -119,6 +142,8 @@ impl DescResponser {
             .map_err(|_| Error::LockPoisoned("qp_table lock"))?
             .get(&dpqn)
         {
+            // the requester expects the response segmented by the pmtu of the path
+            let pmtu = path_mtus.clamp(qp.dqp_ip, qp.pmtu)?;
             let mut common = ToCardWorkRbDescCommon {
                 total_len: resp.desc.len,
                 rkey: resp.desc.rkey,
@@ -126,13 +151,13 @@ impl DescResponser {
                 dqp_ip: qp.dqp_ip,
                 dqpn: resp.desc.common.dqpn,
                 mac_addr: qp.dqp_mac_addr,
-                pmtu: qp.pmtu,
+                pmtu,
                 flags: MemAccessTypeFlag::IbvAccessNoFlags,
                 qp_type: qp.qp_type,
                 psn: Psn::default(),
                 msn: resp.desc.common.msn,
             };
-            let packet_cnt = calculate_packet_cnt(qp.pmtu, resp.desc.raddr, resp.desc.len);
+            let packet_cnt = calculate_packet_cnt(pmtu, resp.desc.raddr, resp.desc.len);
             let first_pkt_psn = {
                 let mut send_psn = qp
                     .sending_psn
@@ -154,25 +179,18 @@ impl DescResponser {
     fn working_thread(
         device: Arc<dyn WorkDescriptorSender>,
         recving_queue: std::sync::mpsc::Receiver<RespCommand>,
-        ack_buffers: &AcknowledgeBuffer,
         qp_table: &RwLock<HashMap<Qpn, QpContext>>,
+        path_mtus: &PathMtuTable,
         stop_flag: &AtomicBool,
     ) {
         // We don't care the time stop_flag is set, so we use `Relaxed` ordering
         while !stop_flag.load(Ordering::Relaxed) {
             let desc = match recving_queue.recv() {
-                Ok(RespCommand::Acknowledge(ack)) => {
+                Ok(RespCommand::Acknowledge(mut ack)) => {
                     // send ack to device
-                    let mut ack_buf = loop {
-                        let buf = ack_buffers.alloc();
-                        match buf {
-                            Some(buf) => break buf,
-                            None => sleep(Duration::from_millis(1)),
-                        }
-                    };
                     // if we can not read qp_table here
                     #[allow(clippy::unwrap_used)]
-                    let (src_ip, dst_ip, common) = {
+                    let (src_ip, dst_ip, dqpn, common) = {
                         let Ok(table) = qp_table.read() else {
                             error!("Failed to get QP from QP table: because of PoisonError ");
                             continue;
@@ -180,8 +198,15 @@ impl DescResponser {
                         if let Some(qp) = table.get(&ack.dpqn) {
                             let dst_ip = qp.dqp_ip;
                             let src_ip = qp.local_ip;
-                            let common = Self::create_ack_common(&ack, qp, dst_ip);
-                            (src_ip, dst_ip, common)
+                            let pmtu = match path_mtus.clamp(dst_ip, qp.pmtu) {
+                                Ok(pmtu) => pmtu,
+                                Err(e) => {
+                                    error!("responser failed to send ack: {:?}", e);
+                                    continue;
+                                }
+                            };
+                            let common = Self::create_ack_common(&ack, qp, dst_ip, pmtu);
+                            (src_ip, dst_ip, qp.dqpn, common)
                         } else {
                             error!("Failed to get QP from QP table: {:?}", ack.dpqn);
                             continue;
@@ -189,24 +214,26 @@ impl DescResponser {
                     };
                     let last_retry_psn = ack.last_retry_psn;
                     write_packet(
-                        ack_buf.as_mut_slice(),
+                        ack.slot.as_mut_slice(),
                         src_ip,
                         dst_ip,
-                        ack.dpqn,
+                        dqpn,
                         ack.msn,
                         ack.psn,
                         last_retry_psn,
                     );
                     #[allow(clippy::cast_possible_truncation)]
-                    let sge = ack_buffers.convert_buf_into_sge(&ack_buf, ACKPACKET_SIZE as u32);
+                    let sge = ack.slot.to_sge(ACKPACKET_SIZE as u32);
+                    // the slot is released after the descriptor is sent
                     ToCardWorkRbDescBuilder::new_write()
                         .with_common(common)
                         .with_sge(sge)
+                        .with_ack_slot(ack.slot)
                         .build()
                 }
                 Ok(RespCommand::ReadResponse(resp)) => {
                     // send read response to device
-                    let common = match Self::create_read_resp_common(qp_table, &resp) {
+                    let common = match Self::create_read_resp_common(qp_table, path_mtus, &resp) {
                         Ok(common) => common,
                         Err(e) => {
                             error!("responser failed to send read response: {:?}", e);
@@ -243,13 +270,25 @@ impl DescResponser {
     }
 }
 
+impl DescResponser {
+    /// Stop the thread and wait for it to exit. Stopping a stopped thread is a no-op.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the thread panicked.
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        if stop_thread(&self.stop_flag, &self.thread) {
+            Ok(())
+        } else {
+            Err(Error::ThreadJoinFailed("responser"))
+        }
+    }
+}
+
 impl Drop for DescResponser {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) =  self.thread.take(){
-            if let Err(e) = thread.join(){
-                error!("Failed to join the responser thread: {:?}", e);
-            }
+        if let Err(e) = self.stop() {
+            error!("Failed to stop the responser: {e:?}");
         }
     }
 }
@@ -277,6 +316,17 @@ impl Slot {
             )
         }
     }
+
+    /// The sge of the first `real_length` bytes of the slot
+    pub(crate) fn to_sge(&self, real_length: u32) -> Sge {
+        self.allocator.convert_buf_into_sge(self, real_length)
+    }
+}
+
+impl fmt::Debug for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Slot").field("buf", &self.buf).finish_non_exhaustive()
+    }
 }
 
 impl Drop for Slot {
@@ -312,13 +362,15 @@ pub(crate) struct AcknowledgeBuffer {
 impl AcknowledgeBuffer {
     pub(crate) const ACKNOWLEDGE_BUFFER_SLOT_SIZE: usize = 64;
 
-    pub(crate) fn new_with_buf(buf : HugePage,lkey: Key) -> Arc<Self>{
+    /// Split the first `length` bytes of `buf` into slots. The huge page is usually larger than the
+    /// acknowledge buffer, but only the `length` bytes are registered with `lkey`.
+    pub(crate) fn new_with_buf(buf : HugePage, length: usize, lkey: Key) -> Arc<Self>{
         let start_va = buf.as_ptr() as usize;
-        let length = buf.size();
         assert!(
             length % Self::ACKNOWLEDGE_BUFFER_SLOT_SIZE == 0,
             "The length should be multiple of 64"
         );
+        assert!(length <= buf.size(), "The length should fit in the buffer");
         let free_list = Queue::new();
         let mut va = start_va;
         let slots: usize = length / Self::ACKNOWLEDGE_BUFFER_SLOT_SIZE;
@@ -337,7 +389,8 @@ impl AcknowledgeBuffer {
         this
     }
 
-    pub(crate) fn alloc(&self) -> Option<Slot> {
+    /// Take a free slot, or `None` if all the slots are in use. The slot is released when dropped.
+    pub(crate) fn try_acquire_slot(&self) -> Option<Slot> {
         let result = self.free_list.pop();
         match result {
             Some(Some(buf)) => Some(buf),
@@ -345,6 +398,12 @@ impl AcknowledgeBuffer {
         }
     }
 
+    /// Take a free slot like `try_acquire_slot`, but report `DeviceError::AckBufferFull` if all
+    /// the slots are in use.
+    pub(crate) fn acquire_slot(&self) -> Result<Slot, DeviceError> {
+        self.try_acquire_slot().ok_or(DeviceError::AckBufferFull)
+    }
+
     pub(crate) fn convert_buf_into_sge(&self, buf: &Slot, real_length: u32) -> Sge {
         Sge {
             addr: buf.buf.as_ptr() as u64,
@@ -416,10 +475,12 @@ fn write_packet(
     let mut aeth_header = Aeth(aeth_hdr_buf);
     if is_nak {
         aeth_header.set_aeth_code(ToHostWorkRbDescAethCode::Nak as u32);
+        aeth_header.set_aeth_value(0);
     } else {
         aeth_header.set_aeth_code(ToHostWorkRbDescAethCode::Ack as u32);
+        // there is no receive queue to limit the sender
+        aeth_header.set_aeth_value(AETH_CREDIT_INVALID.into());
     }
-    aeth_header.set_aeth_value(0);
     aeth_header.set_msn(msg_seq_num.into_be().into());
 
     let mut nreth_header = NReth(
@@ -758,7 +819,7 @@ fn calculate_ipv4_checksum(header: &[u8]) -> u16 {
     //     let base_va = mem.as_ptr() as usize;
     //     let buffer = super::AcknowledgeBuffer::new(base_va, 1024 * 64, Key::new(0x1000));
     //     for i in 0..1024 {
-    //         let slot = buffer.alloc().unwrap();
+    //         let slot = buffer.try_acquire_slot().unwrap();
     //         assert_eq!(
     //             slot.buf.as_ptr() as usize,
     //             mem.as_ptr() as usize + i * super::AcknowledgeBuffer::ACKNOWLEDGE_BUFFER_SLOT_SIZE
@@ -772,7 +833,7 @@ fn calculate_ipv4_checksum(header: &[u8]) -> u16 {
     //     }
     //     // // Now the buffer is full, it recycles the buffer
     //     // for i in 0..1024 {
-    //     //     let slot = buffer.alloc().unwrap();
+    //     //     let slot = buffer.try_acquire_slot().unwrap();
     //     //     assert_eq!(slot.buf.as_ptr() as usize, mem.as_ptr() as usize + i * 64);
     //     // }
     // }
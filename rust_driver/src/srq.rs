@@ -0,0 +1,182 @@
+use crate::{
+    types::{Key, QpType, Qpn, Sge},
+    Device, Error,
+};
+use rand::RngCore as _;
+use std::collections::HashSet;
+
+/// The srqn takes 24 bits, like the XRC SRQ number in XRCETH
+const SRQN_MASK: u32 = 0x00ff_ffff;
+
+/// Shared Receive Queue
+///
+/// The `QpType::XrcRecv` and `QpType::Ud` qps attached to it draw their receive buffers from it, so
+/// an incoming SEND consumes the next receive buffer posted to the queue, whichever qp it targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Srq {
+    pub(crate) srqn: u32,
+}
+
+impl Srq {
+    /// Get the number of the srq.
+    #[must_use]
+    pub fn srqn(&self) -> u32 {
+        self.srqn
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SrqCtx {
+    /// the qps attached to the srq
+    pub(crate) qp: HashSet<Qpn>,
+}
+
+impl Device {
+    /// create a srq, which has no receive buffer yet
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the adaptor does not support srq
+    pub fn create_srq(&self) -> Result<Srq, Error> {
+        let mut pool = self
+            .0
+            .srq_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("srq table lock"))?;
+
+        let srq = Srq {
+            srqn: rand::thread_rng().next_u32() & SRQN_MASK,
+        };
+        if pool.contains_key(&srq) {
+            return Err(Error::Invalid(format!("SRQ :{srq:?}")));
+        }
+
+        self.0
+            .adaptor
+            .create_srq(srq.srqn)
+            .map_err(|e| Error::Device(Box::new(e)))?;
+        let _: Option<SrqCtx> = pool.insert(
+            srq,
+            SrqCtx {
+                qp: HashSet::new(),
+            },
+        );
+
+        Ok(srq)
+    }
+
+    /// destroy a srq, and drop the receive buffers posted to it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * invalid Srq
+    /// * a qp is still attached to it
+    /// * the adaptor failed to destroy it
+    pub fn destroy_srq(&self, srq: Srq) -> Result<(), Error> {
+        let mut pool = self
+            .0
+            .srq_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("srq table lock"))?;
+        let srq_ctx = pool.get(&srq).ok_or(Error::Invalid(format!("SRQ :{srq:?}")))?;
+
+        if !srq_ctx.qp.is_empty() {
+            return Err(Error::Invalid(format!(
+                "{srq:?} is attached by qp:{:?}",
+                srq_ctx.qp
+            )));
+        }
+
+        self.0
+            .adaptor
+            .destroy_srq(srq.srqn)
+            .map_err(|e| Error::Device(Box::new(e)))?;
+        let _: Option<SrqCtx> = pool.remove(&srq);
+
+        Ok(())
+    }
+
+    /// Attach a `QpType::XrcRecv` or `QpType::Ud` qp to `srq`, so that it takes the receive buffers
+    /// posted to `srq` instead of its own ones. The qp is detached when it's destroyed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * the qp or the srq is not found, or the qp is attached to another srq
+    /// * the qp is neither a `QpType::XrcRecv` nor a `QpType::Ud` qp
+    /// * the adaptor failed to attach it
+    pub fn attach_srq(&self, qpn: Qpn, srq: Srq) -> Result<(), Error> {
+        let qp_pool = self
+            .0
+            .qp_table
+            .read()
+            .map_err(|_| Error::LockPoisoned("qp table lock"))?;
+        let qp_ctx = qp_pool
+            .get(&qpn)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+        if !matches!(qp_ctx.qp_type, QpType::XrcRecv | QpType::Ud) {
+            return Err(Error::InvalidQpTypeForOp {
+                qpn,
+                qp_type: qp_ctx.qp_type,
+                op: "srq attach",
+            });
+        }
+        let mut pool = self
+            .0
+            .srq_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("srq table lock"))?;
+        if let Some((attached, _)) = pool
+            .iter()
+            .find(|(other, ctx)| **other != srq && ctx.qp.contains(&qpn))
+        {
+            return Err(Error::Invalid(format!("{qpn:?} is attached to {attached:?}")));
+        }
+        let srq_ctx = pool
+            .get_mut(&srq)
+            .ok_or(Error::Invalid(format!("SRQ :{srq:?}")))?;
+
+        self.0
+            .adaptor
+            .attach_srq(qpn, srq.srqn)
+            .map_err(|e| Error::Device(Box::new(e)))?;
+        let _: bool = srq_ctx.qp.insert(qpn);
+
+        Ok(())
+    }
+
+    /// Post a receive buffer made of `sges` to `srq`. The buffers are consumed in the order they are
+    /// posted, by the messages to any qp attached to `srq`. As the peers write into them, every sge
+    /// must lie in a registered Mr.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * invalid Srq
+    /// * a sge has no Mr, or is out of the bounds of its Mr
+    /// * the adaptor failed to post the buffer
+    pub fn post_srq_recv(&self, srq: Srq, sges: &[Sge]) -> Result<(), Error> {
+        if let Some(sge) = sges.iter().find(|sge| sge.key == Key::default()) {
+            return Err(Error::InvalidKey(sge.key));
+        }
+        self.check_sge_bounds(sges)?;
+        let pool = self
+            .0
+            .srq_table
+            .lock()
+            .map_err(|_| Error::LockPoisoned("srq table lock"))?;
+        if !pool.contains_key(&srq) {
+            return Err(Error::Invalid(format!("SRQ :{srq:?}")));
+        }
+        self.0
+            .adaptor
+            .post_srq_recv(srq.srqn, sges)
+            .map_err(|e| Error::Device(Box::new(e)))
+    }
+}
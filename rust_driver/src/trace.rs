@@ -0,0 +1,155 @@
+/// Enter a trace level `tracing` span that lasts until the end of the current scope.
+///
+/// The spans are only emitted with the `tracing` feature. Without it, the macro expands to nothing,
+/// and the fields are not evaluated.
+macro_rules! enter_span {
+    ($name:literal $(, $($field:tt)+)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($name $(, $($field)+)?).entered();
+    };
+}
+
+pub(crate) use enter_span;
+
+#[cfg(test)]
+#[cfg(feature = "tracing")]
+mod tests {
+    use std::{
+        alloc::{alloc, dealloc, Layout},
+        collections::HashMap,
+        fmt::Debug,
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread::sleep,
+        time::Duration,
+    };
+
+    use eui48::MacAddress;
+    use serial_test::serial;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    use crate::{
+        types::{
+            MemAccessTypeFlag, Pmtu, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParamBuilder, Sge,
+            PAGE_SIZE,
+        },
+        Device,
+    };
+
+    type SpanLog = Arc<Mutex<Vec<(&'static str, HashMap<&'static str, String>)>>>;
+
+    /// Record the name and the fields of every created span
+    struct SpanRecorder {
+        spans: SpanLog,
+        next_id: AtomicU64,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .push((span.metadata().name(), fields));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed))
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    #[serial]
+    fn test_write_spans() {
+        let spans = SpanLog::default();
+        tracing::subscriber::set_global_default(SpanRecorder {
+            spans: Arc::clone(&spans),
+            next_id: AtomicU64::new(1),
+        })
+        .unwrap();
+
+        // write to the device itself
+        let network = RdmaDeviceNetworkParamBuilder::default()
+            .gateway(Ipv4Addr::new(127, 0, 0, 1))
+            .netmask(Ipv4Addr::new(255, 0, 0, 0))
+            .ipaddr(Ipv4Addr::new(127, 0, 0, 5))
+            .macaddr(MacAddress::default())
+            .build()
+            .unwrap();
+        let dev = Device::new_software(&network).unwrap();
+        let pd = dev.alloc_pd().unwrap();
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let buf = unsafe { alloc(layout) };
+        let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+            | MemAccessTypeFlag::IbvAccessRemoteWrite
+            | MemAccessTypeFlag::IbvAccessLocalWrite;
+        let mr = dev
+            .reg_mr(pd, buf as u64, PAGE_SIZE as u32, PAGE_SIZE as u32, access_flag)
+            .unwrap();
+        let qpn = Qpn::new(3);
+        let qp = QpBuilder::default()
+            .pd(pd)
+            .qpn(qpn)
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(access_flag)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(network.ipaddr)
+            .dqp_mac(network.macaddr)
+            .build()
+            .unwrap();
+        dev.create_qp(&qp).unwrap();
+
+        let sge = Sge::new(buf as u64, 64, mr.get_key());
+        let ctx = dev
+            .write(qpn, buf as u64 + 4096, mr.get_key(), MemAccessTypeFlag::empty(), sge)
+            .unwrap();
+        ctx.wait().unwrap();
+        sleep(Duration::from_millis(10));
+        unsafe {
+            dealloc(buf, layout);
+        }
+
+        let spans = spans.lock().unwrap();
+        let qpn = qpn.get().to_string();
+        let byte_len = 64.to_string();
+        for name in ["submit", "schedule", "transmit", "ack_wait", "completion"] {
+            assert!(
+                spans.iter().any(|(span_name, fields)| *span_name == name
+                    && fields.get("qpn") == Some(&qpn)
+                    && fields.contains_key("psn")
+                    && fields.get("byte_len") == Some(&byte_len)),
+                "span {name} not found"
+            );
+        }
+        assert!(spans
+            .iter()
+            .any(|(name, fields)| *name == "submit" && fields["opcode"] == "Write"));
+    }
+}
@@ -1,13 +1,13 @@
-use std::net::Ipv4Addr;
+use std::{net::Ipv4Addr, time::Duration};
 
 use bitflags::bitflags;
-use derive_builder::Builder;
+use derive_builder::{Builder, UninitializedFieldError};
 use eui48::MacAddress;
 use num_enum::TryFromPrimitive;
 use serde::ser::StdError;
 use thiserror::Error;
 
-use crate::Pd;
+use crate::{DeviceError, Pd};
 
 /// page size is 2MB.
 pub const PAGE_SIZE: usize = 1024 * 1024 * 2;
@@ -83,15 +83,43 @@ impl Default for Msn {
 }
 
 /// `RKey` and `LKey`
+///
+/// The high 8 bits are the index of the MR in the MR table, and the low 24 bits are a random secret
+/// which tells the MRs registered at the same index apart.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
 pub struct Key(u32);
 impl Key {
+    const INDEX_WIDTH: u32 = 8;
+    const SECRET_WIDTH: u32 = u32::BITS - Self::INDEX_WIDTH;
+    const SECRET_MASK: u32 = u32::MAX >> Self::INDEX_WIDTH;
+
     /// Create a new `Key` with the given value.
     #[must_use]
     pub fn new(key: u32) -> Self {
         Self(key)
     }
 
+    /// Create a `Key` of the MR at `index` of the MR table with `secret`.
+    ///
+    /// If `index` is greater than 8 bits or `secret` is greater than 24 bits, the higher bits will
+    /// be ignored.
+    #[must_use]
+    pub fn from_index_secret(index: u32, secret: u32) -> Self {
+        Self(index.wrapping_shl(Self::SECRET_WIDTH) | (secret & Self::SECRET_MASK))
+    }
+
+    /// Get the index of the MR in the MR table.
+    #[must_use]
+    pub fn index(&self) -> u32 {
+        self.0.wrapping_shr(Self::SECRET_WIDTH)
+    }
+
+    /// Get the random secret of the MR.
+    #[must_use]
+    pub fn secret(&self) -> u32 {
+        self.0 & Self::SECRET_MASK
+    }
+
     /// Get the value of `Key`.
     #[must_use]
     pub fn get(&self) -> u32 {
@@ -175,8 +203,8 @@ impl ThreeBytesStruct {
     #[must_use]
     #[allow(clippy::arithmetic_side_effects)] 
     pub fn wrapping_add(&self, rhs: u32) -> Self {
-        // since (a+b) mod p  = (a + (b mod p)) mod p, we don't have to let rhs= rhs%p here
-        Self((self.0 + rhs) % Self::MAX)
+        // since (a+b) mod p  = (a + (b mod p)) mod p, reducing rhs first keeps the sum from overflowing
+        Self((self.0 + rhs % Self::MAX) % Self::MAX)
     }
 
     /// wrapping sub the current value with rhs
@@ -184,7 +212,7 @@ impl ThreeBytesStruct {
     #[allow(clippy::arithmetic_side_effects)] 
     pub fn wrapping_sub(&self, rhs: u32) -> Self {
         let rhs = rhs % Self::MAX;
-        if self.0 > rhs {
+        if self.0 >= rhs {
             Self(self.0 - rhs)
         } else {
             Self(Self::MAX - rhs + self.0)
@@ -203,6 +231,32 @@ impl ThreeBytesStruct {
             self.0 + Self::MAX - rhs.0
         }
     }
+
+    /// The signed distance from the current PSN to `other`, which is in `-2^23..2^23`.
+    ///
+    /// It is positive if `other` is after the current PSN. Like the IB spec, a PSN is after another
+    /// one if it is ahead by less than half of the 24 bits space, taking the wrap around into account.
+    #[must_use]
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn distance_to(&self, other: Psn) -> i32 {
+        let distance = other.wrapping_abs(*self);
+        // sign extend the 24 bits distance
+        (distance.wrapping_shl(8) as i32).wrapping_shr(8)
+    }
+
+    /// Whether the current PSN is after `other`
+    #[must_use]
+    pub fn is_after(&self, other: Psn) -> bool {
+        other.distance_to(*self) > 0_i32
+    }
+
+    /// Whether the current PSN is in the range from `start` to `end`, excluding `end`.
+    ///
+    /// The range may wrap around, so `end` can be less than `start`.
+    #[must_use]
+    pub fn is_in_range(&self, start: Psn, end: Psn) -> bool {
+        self.wrapping_abs(start) < end.wrapping_abs(start)
+    }
 }
 
 impl From<u32> for ThreeBytesStruct {
@@ -270,9 +324,131 @@ pub enum QpType {
     XrcRecv = 10,
 }
 
+/// The state of a Queue Pair
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QpState {
+    /// The QP is not able to send or receive
+    Reset,
+    /// Initialized, the QP can be configured
+    Init,
+    /// Ready to receive
+    Rtr,
+    /// Ready to send. A newly created QP is in this state.
+    #[default]
+    Rts,
+    /// An error occurred, the QP can only be moved to `Reset`
+    Error,
+}
+
+impl QpState {
+    /// Whether the QP context is kept by the device in this state
+    pub(crate) fn is_active(self) -> bool {
+        !matches!(self, QpState::Reset | QpState::Error)
+    }
+
+    /// Whether the QP is allowed to move from `self` to `to`
+    pub(crate) fn can_transition_to(self, to: QpState) -> bool {
+        matches!(
+            (self, to),
+            (_, QpState::Reset | QpState::Error)
+                | (QpState::Reset | QpState::Init, QpState::Init)
+                | (QpState::Init, QpState::Rtr)
+                | (QpState::Rtr | QpState::Rts, QpState::Rts)
+        )
+    }
+}
+
+/// The attributes changed by `Device::modify_qp`. The attributes that are not set stay unchanged.
+#[non_exhaustive]
+#[derive(Builder, Debug, Clone, Copy, Default)]
+#[builder(default)]
+pub struct QpModifyAttr {
+    /// The state to move the QP to
+    #[builder(setter(strip_option))]
+    pub state: Option<QpState>,
+    /// Packet MTU
+    #[builder(setter(strip_option))]
+    pub pmtu: Option<Pmtu>,
+    /// Receive queue access flags
+    #[builder(setter(strip_option))]
+    pub rq_acc_flags: Option<MemAccessTypeFlag>,
+    /// Destination QP, which is the QP of the same number on the remote when the QP is created
+    #[builder(setter(strip_option))]
+    pub dqpn: Option<Qpn>,
+    /// Destination IP
+    #[builder(setter(strip_option))]
+    pub dqp_ip: Option<Ipv4Addr>,
+    /// Destination MAC
+    #[builder(setter(strip_option))]
+    pub dqp_mac: Option<MacAddress>,
+}
+
+/// The behavior of the software device when the source and the sink of a read request overlap
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadOverlapPolicy {
+    /// Accept the request. The payload is copied with `memmove` semantics, so the result is well defined.
+    #[default]
+    Memmove,
+    /// Reject the request, which completes with a remote access error.
+    Reject,
+}
+
+/// How the checksum of the UDP header of the sent packets is filled
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpChecksumMode {
+    /// Leave the checksum zero, which means it is not computed. `RoCEv2` receivers rely on the ICRC instead.
+    #[default]
+    Zero,
+    /// Compute the checksum over the pseudo header and the UDP datagram, for the peers checking it.
+    Computed,
+    /// Keep the checksum already in the buffer, e.g. when the NIC fills it.
+    Passthrough,
+}
+
+/// The ECN codepoint in the IP header, see RFC 3168
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EcnCodepoint {
+    /// Not ECN-capable transport
+    #[default]
+    NotEct,
+    /// ECN-capable transport, ECT(1)
+    Ect1,
+    /// ECN-capable transport, ECT(0)
+    Ect0,
+    /// Congestion experienced
+    Ce,
+}
+
+impl EcnCodepoint {
+    /// The 2 bits value in the IP header
+    #[must_use]
+    pub fn bits(self) -> u8 {
+        match self {
+            EcnCodepoint::NotEct => 0b00,
+            EcnCodepoint::Ect1 => 0b01,
+            EcnCodepoint::Ect0 => 0b10,
+            EcnCodepoint::Ce => 0b11,
+        }
+    }
+
+    /// Get the codepoint from the lowest 2 bits of `bits`
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => EcnCodepoint::NotEct,
+            0b01 => EcnCodepoint::Ect1,
+            0b10 => EcnCodepoint::Ect0,
+            _ => EcnCodepoint::Ce,
+        }
+    }
+}
+
 /// Packet MTU
 #[non_exhaustive]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Pmtu {
     /// 256 bytes
     Mtu256 = 1,
@@ -334,6 +510,31 @@ impl Sge {
     }
 }
 
+/// A RDMA write issued repeatedly by `Device::write_repeated`
+#[derive(Debug, Clone)]
+pub struct WriteRequest {
+    pub(crate) dqpn: Qpn,
+    pub(crate) raddr: u64,
+    pub(crate) rkey: Key,
+    pub(crate) flags: MemAccessTypeFlag,
+    pub(crate) sges: Vec<Sge>,
+}
+
+impl WriteRequest {
+    /// Write the payload gathered from `sges` to `raddr` of the remote MR `rkey`, see
+    /// `Device::write_gather`
+    #[must_use]
+    pub fn new(dqpn: Qpn, raddr: u64, rkey: Key, flags: MemAccessTypeFlag, sges: &[Sge]) -> Self {
+        Self {
+            dqpn,
+            raddr,
+            rkey,
+            flags,
+            sges: sges.to_vec(),
+        }
+    }
+}
+
 /// RDMA network param
 #[derive(Debug, Builder, Clone, Copy)]
 #[non_exhaustive]
@@ -348,9 +549,43 @@ pub struct RdmaDeviceNetworkParam {
     pub macaddr: MacAddress,
 }
 
+/// The resources and the features a device supports, see `Device::query_caps`
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCaps {
+    /// The number of MRs the user can register at the same time, the MRs the driver registers for
+    /// itself are excluded
+    pub max_mr: usize,
+    /// The number of page table entries shared by all the MRs, an MR uses one entry per page
+    pub max_pgt_entries: usize,
+    /// The number of QPs, QP0 and QP1 are reserved
+    pub max_qp: usize,
+    /// The supported pmtus
+    pub pmtus: Vec<Pmtu>,
+    /// The supported page sizes of a MR
+    pub page_sizes: Vec<u32>,
+    /// Whether the atomic operations are supported
+    pub atomics: bool,
+    /// Whether the QPs of `QpType::Ud` are supported
+    pub ud: bool,
+}
+
+/// The sequence numbers of a QP, see `Device::qp_psn_stats`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QpPsnStats {
+    /// The PSN of the next packet to send
+    pub next_psn: Psn,
+    /// The highest PSN acknowledged by the remote, `None` if nothing is acknowledged yet
+    pub acked_psn: Option<Psn>,
+    /// The number of the sent packets of the writes waiting for an ACK. The reads are not counted.
+    pub outstanding: u32,
+}
+
 /// Queue Pair imuutable context
 #[non_exhaustive]
 #[derive(Builder, Debug, Clone, Copy)]
+#[builder(build_fn(validate = "Self::validate", error = "Error"))]
 pub struct Qp {
     /// Protection Domain
     pub pd: Pd,
@@ -366,8 +601,52 @@ pub struct Qp {
     pub dqp_ip: Ipv4Addr,
     /// Destination MAC
     pub dqp_mac: MacAddress,
+    /// The times a message is resent after the remote answers it with RNR NAKs, 7 means without
+    /// limit. The operation fails with `CompletionStatus::RnrRetryExceeded` once they are used up.
+    #[builder(default = "RNR_RETRY_INFINITE")]
+    pub rnr_retry: u8,
+    /// The time the remote waits before resending a message answered with an RNR NAK by this QP,
+    /// in the 5 bits encoding of the IB spec, e.g. 1 is 0.01 ms and the default 12 is 0.64 ms.
+    #[builder(default = "12")]
+    pub min_rnr_timer: u8,
+    /// The time to wait for the response of a write or read, after which the operation fails with
+    /// `CompletionStatus::TimedOut`. `None` waits forever.
+    #[builder(default)]
+    pub ack_timeout: Option<Duration>,
+}
+
+impl QpBuilder {
+    /// Check the constraints between the fields that can't be expressed by their types
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(qpn) = self.qpn {
+            if qpn.get() == 0 {
+                return Err(Error::Invalid("qpn 0 is reserved".to_owned()));
+            }
+        }
+        // the mac of a remote host can't be resolved by the driver, it must be given
+        if let (Some(dqp_ip), Some(dqp_mac)) = (self.dqp_ip, self.dqp_mac) {
+            if !dqp_ip.is_loopback() && dqp_mac.is_nil() {
+                return Err(Error::Invalid(format!(
+                    "dqp_mac is required for the non-loopback dqp_ip {dqp_ip}"
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
+impl From<UninitializedFieldError> for Error {
+    fn from(err: UninitializedFieldError) -> Self {
+        Error::Invalid(err.to_string())
+    }
+}
+
+/// The `Qp::rnr_retry` that resends a message until the remote has a receive buffer for it
+pub const RNR_RETRY_INFINITE: u8 = 7;
+
+/// The largest `Qp::min_rnr_timer`, the timer has 5 bits
+pub const MIN_RNR_TIMER_MAX: u8 = 0b1_1111;
+
 /// Error type for RDMA user space driver library
 #[non_exhaustive]
 #[derive(Debug, Error)]
@@ -380,13 +659,43 @@ pub enum Error {
     #[error("init failed: {0}")]
     DoubleInit(String),
 
-    /// Device busy. Typeically ringbuffer is full
+    /// Device busy. Typeically ringbuffer or the scheduler is full
     #[error("device busy")]
     DeviceBusy,
 
+    /// The device has been shut down by `Device::shutdown`
+    #[error("device closed")]
+    DeviceClosed,
+
+    /// A thread of the device panicked, so it could not be joined
+    #[error("failed to join the {0} thread")]
+    ThreadJoinFailed(&'static str),
+
+    /// The ring buffer to the card is full. The caller can back off and retry later
+    #[error("ring buffer {0} is full")]
+    RingFull(String),
+
     /// Adaptor device return a failed status
-    #[error("device return failed in : {0}")]
-    DeviceReturnFailed(&'static str),
+    #[error("device return failed in {op}, op_id {op_id}: {desc}")]
+    DeviceReturnFailed {
+        /// The name of the operation
+        op: &'static str,
+        /// The id of the control command the device failed
+        op_id: u32,
+        /// The summary of the descriptor of the command
+        desc: String,
+    },
+
+    /// A control command could not be passed to the device
+    #[error("failed to issue the control command op_id {op_id}: {desc}")]
+    CtrlOpFailed {
+        /// The id of the control command
+        op_id: u32,
+        /// The summary of the descriptor of the command
+        desc: String,
+        /// The failure of the device
+        source: DeviceError,
+    },
 
     /// Passing an invalid PD handle,MR key or QPN
     #[error("invalid {0}")]
@@ -430,14 +739,75 @@ pub enum Error {
 
     /// Pipe broken
     #[error("Pipe brocken : {0}")]
-    PipeBroken(&'static str)
+    PipeBroken(&'static str),
+
+    /// The pmtu of a descriptor does not match the pmtu of its QP
+    #[error("pmtu {actual:?} of the descriptor mismatches the pmtu {expected:?} of {qpn:?}")]
+    PmtuMismatch {
+        /// The QP of the descriptor
+        qpn: Qpn,
+        /// The pmtu the QP is created with, clamped by the path MTU of its destination
+        expected: Pmtu,
+        /// The pmtu the descriptor claims
+        actual: Pmtu,
+    },
+
+    /// The QP has operations waiting for their responses, which would be handled by the changed QP
+    #[error("{0:?} has {1} operations waiting for their responses")]
+    QpHasPendingOps(Qpn, usize),
+
+    /// Some operations are still running when the timeout of a drain elapses
+    #[error("{0} operations are still running after the timeout")]
+    DrainTimedOut(usize),
+
+    /// The range of a sge exceeds the MR of its key
+    #[error("sge of {len} bytes at {addr:#x} is out of the bounds of the MR {key:?}")]
+    SgeOutOfBounds {
+        /// The address of the sge
+        addr: u64,
+        /// The length of the sge
+        len: u32,
+        /// The key of the sge
+        key: Key,
+    },
+
+    /// The key does not match the MR registered at its index, e.g. a stale key of a reused slot
+    #[error("key {0:?} does not match the registered MR")]
+    InvalidKey(Key),
+
+    /// The operation can't be issued on the type of the QP, e.g. an RDMA write on a UD QP
+    #[error("{op} is not supported by {qpn:?} of type {qp_type:?}")]
+    InvalidQpTypeForOp {
+        /// The QP the operation targets
+        qpn: Qpn,
+        /// The type of the QP
+        qp_type: QpType,
+        /// The name of the operation
+        op: &'static str,
+    },
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::Psn;
+    use crate::types::{Key, MemAccessTypeFlag, Pmtu, Psn, QpBuilder, QpType, Qpn};
+    use crate::{Error, Pd};
+    use eui48::MacAddress;
+    use std::net::Ipv4Addr;
     use std::slice::from_raw_parts;
 
+    #[test]
+    fn test_key_index_secret() {
+        for (index, secret) in [(0, 0), (1, 0x12_3456), (0xff, 0xff_ffff), (0x5a, 1)] {
+            let key = Key::from_index_secret(index, secret);
+            assert_eq!((key.index(), key.secret()), (index, secret));
+            assert_eq!(Key::new(key.get()), key);
+        }
+        assert_eq!(Key::from_index_secret(0x12, 0x34_5678).get(), 0x1234_5678);
+        // the bits beyond the fields are ignored
+        let key = Key::from_index_secret(0x1ff, 0x1ff_ffff);
+        assert_eq!((key.index(), key.secret()), (0xff, 0xff_ffff));
+    }
+
     #[test]
     fn test_wrapping_add() {
         let psn = Psn::new(0xffffff);
@@ -451,6 +821,42 @@ mod tests {
         assert_eq!(ret.get(), 0xffffff - 1);
     }
 
+    #[test]
+    fn test_psn_distance() {
+        // every pair of PSNs around the wrap boundary
+        let boundary = (0xffff00..=0xffffff).chain(0..=0xff).map(Psn::new);
+        for (i, psn) in boundary.clone().enumerate() {
+            for (j, other) in boundary.clone().enumerate() {
+                let distance = j as i32 - i as i32;
+                assert_eq!(psn.distance_to(other), distance, "{psn:?} to {other:?}");
+                assert_eq!(psn.wrapping_add(distance as u32), other);
+                assert_eq!(other.is_after(psn), distance > 0);
+            }
+        }
+
+        // half of the space is after the PSN, and the other half is before it
+        let psn = Psn::new(0xffffff);
+        assert_eq!(psn.distance_to(Psn::new(0x7ffffe)), 0x7fffff);
+        assert!(Psn::new(0x7ffffe).is_after(psn));
+        assert_eq!(psn.distance_to(Psn::new(0x7fffff)), -0x800000);
+        assert!(!Psn::new(0x7fffff).is_after(psn));
+        assert!(!psn.is_after(psn));
+    }
+
+    #[test]
+    fn test_psn_range() {
+        let start = Psn::new(0xfffffe);
+        let end = Psn::new(2);
+        for psn in [0xfffffe, 0xffffff, 0, 1] {
+            assert!(Psn::new(psn).is_in_range(start, end), "{psn:#x}");
+        }
+        for psn in [2, 3, 0xfffffd, 0x800000] {
+            assert!(!Psn::new(psn).is_in_range(start, end), "{psn:#x}");
+        }
+        // an empty range
+        assert!(!start.is_in_range(start, start));
+    }
+
     #[test]
     fn test_to_be() {
         let psn = Psn::new(0x123456);
@@ -471,6 +877,7 @@ mod tests {
         let psn = Psn::new(0);
         let psn2 = psn.wrapping_sub(1);
         assert_eq!(psn2.get(), 0xffffff);
+        assert_eq!(Psn::new(1).wrapping_sub(1).get(), 0);
 
         let psn = psn.wrapping_abs(psn2);
         assert_eq!(psn, 1);
@@ -480,4 +887,47 @@ mod tests {
         let psn2 = psn.wrapping_sub(0x12345678);
         assert_eq!(psn2.get(), psn.wrapping_sub(0x345678).get());
     }
+
+    fn qp_builder(qpn: u32, dqp_ip: Ipv4Addr, dqp_mac: MacAddress) -> QpBuilder {
+        let mut builder = QpBuilder::default();
+        let _: &mut QpBuilder = builder
+            .pd(Pd { handle: 0 })
+            .qpn(Qpn::new(qpn))
+            .qp_type(QpType::Rc)
+            .rq_acc_flags(MemAccessTypeFlag::IbvAccessRemoteWrite)
+            .pmtu(Pmtu::Mtu1024)
+            .dqp_ip(dqp_ip)
+            .dqp_mac(dqp_mac);
+        builder
+    }
+
+    fn invalid_reason(builder: &QpBuilder) -> String {
+        match builder.build() {
+            Err(Error::Invalid(reason)) => reason,
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_qp_builder_validation() {
+        let loopback = Ipv4Addr::new(127, 0, 0, 2);
+        let remote = Ipv4Addr::new(192, 168, 0, 2);
+        let mac = MacAddress::new([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+
+        assert!(qp_builder(2, loopback, MacAddress::nil()).build().is_ok());
+        assert!(qp_builder(2, remote, mac).build().is_ok());
+
+        assert_eq!(
+            invalid_reason(&qp_builder(0, loopback, mac)),
+            "qpn 0 is reserved"
+        );
+        assert_eq!(
+            invalid_reason(&qp_builder(2, remote, MacAddress::nil())),
+            "dqp_mac is required for the non-loopback dqp_ip 192.168.0.2"
+        );
+
+        let mut builder = QpBuilder::default();
+        let _: &mut QpBuilder = builder.qpn(Qpn::new(2));
+        assert_eq!(invalid_reason(&builder), "Field not initialized: pd");
+    }
 }
@@ -1,12 +1,20 @@
 use std::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    collections::VecDeque,
     io,
     ops::{Deref, DerefMut},
+    ptr::NonNull,
     slice::from_raw_parts_mut,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    thread::JoinHandle,
 };
 
-use log::error;
+use log::{error, warn};
 
-use crate::types::{Pmtu, PAGE_SIZE};
+use crate::types::{Pmtu, Sge, PAGE_SIZE};
 
 /// Get the length of the first packet.
 ///
@@ -23,6 +31,11 @@ pub(crate) fn get_first_packet_max_length(va: u64, pmtu: u32) -> u32 {
     pmtu - offset
 }
 
+/// Calculate the number of packets to carry `total_len` bytes starting at `raddr`.
+///
+/// A message is always sent by at least one packet, so a zero-length message counts as one packet
+/// without payload. Otherwise every packet carries payload, and there is no extra empty packet after
+/// the last one, so the count can be used to reserve or check the psn range of a message directly.
 #[allow(clippy::arithmetic_side_effects)] // total_len must be greater or equal than first_pkt_len
 pub(crate) fn calculate_packet_cnt(pmtu: Pmtu, raddr: u64, total_len: u32) -> u32 {
     let first_pkt_max_len = get_first_packet_max_length(raddr, u32::from(&pmtu));
@@ -31,6 +44,151 @@ pub(crate) fn calculate_packet_cnt(pmtu: Pmtu, raddr: u64, total_len: u32) -> u3
     1 + (total_len - first_pkt_len).div_ceil(u32::from(&pmtu))
 }
 
+/// A packet of a message fragmented by pmtu
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Fragment {
+    /// The offset of the payload in the message
+    pub(crate) offset: u32,
+    /// The length of the payload
+    pub(crate) len: u32,
+    pub(crate) is_first: bool,
+    pub(crate) is_last: bool,
+}
+
+/// An iterator over the packets carrying `total_len` bytes starting at `va`.
+///
+/// The first packet ends at the first pmtu boundary after `va`, then each packet carries a full pmtu
+/// except the last one. It yields `calculate_packet_cnt` packets, so a zero-length message is one
+/// empty packet which is both the first and the last.
+#[derive(Debug, Clone)]
+pub(crate) struct PmtuFragments {
+    va: u64,
+    total_len: u32,
+    pmtu: u32,
+    offset: u32,
+    is_done: bool,
+}
+
+impl PmtuFragments {
+    pub(crate) fn new(va: u64, total_len: u32, pmtu: Pmtu) -> Self {
+        Self {
+            va,
+            total_len,
+            pmtu: u32::from(&pmtu),
+            offset: 0,
+            is_done: false,
+        }
+    }
+}
+
+impl Iterator for PmtuFragments {
+    type Item = Fragment;
+
+    fn next(&mut self) -> Option<Fragment> {
+        if self.is_done {
+            return None;
+        }
+        let is_first = self.offset == 0;
+        let max_len = if is_first {
+            get_first_packet_max_length(self.va, self.pmtu)
+        } else {
+            self.pmtu
+        };
+        let len = self.total_len.wrapping_sub(self.offset).min(max_len);
+        let offset = self.offset;
+        self.offset = offset.wrapping_add(len);
+        self.is_done = self.offset == self.total_len;
+        Some(Fragment {
+            offset,
+            len,
+            is_first,
+            is_last: self.is_done,
+        })
+    }
+}
+
+/// Raise the stop flag of a worker thread and join it, if it has not been joined yet.
+///
+/// Returns `false` if the thread panicked. Stopping a thread twice is a no-op.
+pub(crate) fn stop_thread(stop_flag: &AtomicBool, thread: &Mutex<Option<JoinHandle<()>>>) -> bool {
+    stop_flag.store(true, Ordering::Relaxed);
+    // the handle is still valid if another thread panicked while holding the lock
+    let thread = thread.lock().unwrap_or_else(PoisonError::into_inner).take();
+    match thread {
+        Some(thread) => thread.join().is_ok(),
+        None => true,
+    }
+}
+
+/// Split a scatter list into the sge lists of several chained work descriptors.
+///
+/// Except for the last one, the payload of each list ends at a pmtu boundary of the remote address, so the
+/// packets of two descriptors never share a pmtu. An sge is cut in two if it crosses the boundary. The sges keep
+/// the order of `sges`.
+///
+/// A list has at most `max_sge` sges, unless `max_sge` sges are too short to reach the next pmtu boundary. Such
+/// a list takes the sges up to the boundary, and the caller gathers the ones from the `max_sge`th into one sge.
+pub(crate) fn split_sge_list(
+    sges: &[Sge],
+    raddr: u64,
+    pmtu: Pmtu,
+    max_sge: usize,
+) -> Vec<Vec<Sge>> {
+    let pmtu = u64::from(u32::from(&pmtu));
+    let mut remain: VecDeque<Sge> = sges.iter().copied().collect();
+    let mut sge_lists = Vec::new();
+    let mut va = raddr;
+    while remain.len() > max_sge {
+        let mut sge_list: Vec<Sge> = remain.drain(..max_sge).collect();
+        let mut end = sge_list
+            .iter()
+            .fold(va, |acc, sge| acc.wrapping_add(u64::from(sge.len)));
+        #[allow(clippy::arithmetic_side_effects)] // pmtu is never zero
+        let mut boundary = end.wrapping_sub(end.wrapping_rem(pmtu));
+        if boundary <= va {
+            // take more sges to reach the next boundary, or all of them for the last list
+            #[allow(clippy::arithmetic_side_effects)] // pmtu is never zero
+            let next = va.wrapping_sub(va.wrapping_rem(pmtu)).wrapping_add(pmtu);
+            while end < next {
+                let Some(sge) = remain.pop_front() else {
+                    sge_lists.push(sge_list);
+                    return sge_lists;
+                };
+                end = end.wrapping_add(u64::from(sge.len));
+                sge_list.push(sge);
+            }
+            boundary = next;
+        }
+        // move the bytes after the boundary to the next descriptor
+        let mut exceed = end.wrapping_sub(boundary);
+        while let Some(last) = sge_list.last_mut().filter(|_| exceed > 0) {
+            if u64::from(last.len) <= exceed {
+                exceed = exceed.wrapping_sub(u64::from(last.len));
+                if let Some(sge) = sge_list.pop() {
+                    remain.push_front(sge);
+                }
+            } else {
+                // `exceed` is less than `last.len` here
+                #[allow(clippy::cast_possible_truncation)]
+                let tail_len = exceed as u32;
+                last.len = last.len.wrapping_sub(tail_len);
+                remain.push_front(Sge::new(
+                    last.addr.wrapping_add(u64::from(last.len)),
+                    tail_len,
+                    last.key,
+                ));
+                exceed = 0;
+            }
+        }
+        sge_lists.push(sge_list);
+        va = boundary;
+    }
+    if !remain.is_empty() {
+        sge_lists.push(remain.into_iter().collect());
+    }
+    sge_lists
+}
+
 #[allow(clippy::arithmetic_side_effects)]
 pub(crate) fn u8_slice_to_u64(slice: &[u8]) -> u64 {
     // this operation convert a [u8;8] to a u64. So it's safe to left shift
@@ -42,11 +200,55 @@ pub(crate) fn align_up<const PAGE: usize>(addr: usize) -> usize {
     (((addr) + ((PAGE) - 1)) / PAGE) * PAGE
 }
 
+// NUMA memory policy constants from `linux/mempolicy.h`
+const MPOL_BIND: libc::c_ulong = 2;
+const MPOL_MF_STRICT: libc::c_ulong = 1;
+const MPOL_F_NODE: libc::c_ulong = 1;
+const MPOL_F_ADDR: libc::c_ulong = 2;
+
+/// The size of the huge pages backing a `HugePage`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2MB huge pages
+    Size2M,
+    /// 1GB huge pages
+    Size1G,
+}
+
+impl HugePageSize {
+    /// The size of a huge page in bytes
+    #[must_use]
+    pub fn size(self) -> usize {
+        1 << self.shift()
+    }
+
+    /// The log2 of the page size, which is encoded into the mmap flags
+    fn shift(self) -> libc::c_int {
+        match self {
+            HugePageSize::Size2M => 21_i32,
+            HugePageSize::Size1G => 30_i32,
+        }
+    }
+}
+
+/// The kind of pages backing a `HugePage`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageBacking {
+    /// The buffer is backed by huge pages
+    Huge,
+    /// Huge pages are unavailable, the buffer is backed by regular pages advised to use transparent
+    /// huge pages
+    Regular,
+}
+
 /// A struct to manage hugepage memory
 #[derive(Debug)]
 pub struct HugePage {
     size: usize,
     addr: usize,
+    backing: HugePageBacking,
 }
 
 impl HugePage {
@@ -57,12 +259,83 @@ impl HugePage {
     ///
     pub fn new(size: usize) -> io::Result<Self> {
         let size = align_up::<{ Self::HUGE_PAGE_SIZE }>(size);
+        Self::mmap_locked(size, libc::MAP_HUGETLB)
+    }
+
+    /// Allocate huge pages of an explicit size instead of the system default huge page size.
+    ///
+    /// The `size` is aligned up to the size of `huge_kind`.
+    ///
+    /// # Errors
+    ///
+    /// Will return the OS error if the mapping fails, for example, there are not enough huge pages of
+    /// `huge_kind` reserved. Callers can fall back to other kinds of pages.
+    pub fn new_with_size(size: usize, huge_kind: HugePageSize) -> io::Result<Self> {
+        let page_size = huge_kind.size();
+        let size = size
+            .div_ceil(page_size)
+            .checked_mul(page_size)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // the page shift is encoded in the 6 bits above `MAP_HUGE_SHIFT`, which never overflows
+        #[allow(clippy::arithmetic_side_effects)]
+        let flags = libc::MAP_HUGETLB | (huge_kind.shift() << libc::MAP_HUGE_SHIFT);
+        Self::mmap_locked(size, flags)
+    }
+
+    /// Allocate huge pages on the NUMA node `node`, e.g. the node local to the NIC.
+    ///
+    /// The memory is bound to `node` before it is faulted in, so the pages never live on another node.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `node` is invalid or offline, or the mapping fails.
+    pub fn new_on_node(size: usize, node: u32) -> io::Result<Self> {
+        let size = align_up::<{ Self::HUGE_PAGE_SIZE }>(size);
+        Self::mmap_locked_on_node(size, libc::MAP_HUGETLB, Some(node))
+    }
+
+    /// Try to allocate huge pages, and fall back to regular pages if no huge pages are available.
+    ///
+    /// The fallback mapping is advised to use transparent huge pages. Either way the buffer is locked in
+    /// memory and its size is aligned to `HUGE_PAGE_SIZE`. Use `backing` to tell which mode is used.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the huge page mapping fails for other reasons than `ENOMEM` or `EINVAL`,
+    /// or the fallback mapping fails as well.
+    pub fn new_or_fallback(size: usize) -> io::Result<Self> {
+        match Self::new(size) {
+            Ok(page) => Ok(page),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOMEM | libc::EINVAL)) => {
+                warn!("allocate huge page failed: {e}, fall back to regular pages");
+                Self::new_fallback(size)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn new_fallback(size: usize) -> io::Result<Self> {
+        let size = align_up::<{ Self::HUGE_PAGE_SIZE }>(size);
+        Self::mmap_locked(size, 0)
+    }
+
+    /// Create an anonymous mapping with the `extra_flags` and lock it in memory.
+    fn mmap_locked(size: usize, extra_flags: libc::c_int) -> io::Result<Self> {
+        Self::mmap_locked_on_node(size, extra_flags, None)
+    }
+
+    /// Create an anonymous mapping with the `extra_flags`, bind it to the NUMA `node` if any and lock it in memory.
+    fn mmap_locked_on_node(
+        size: usize,
+        extra_flags: libc::c_int,
+        node: Option<u32>,
+    ) -> io::Result<Self> {
         let buffer = unsafe {
             libc::mmap(
                 std::ptr::null_mut(),
                 size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_HUGETLB,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | extra_flags,
                 -1,
                 0,
             )
@@ -70,15 +343,34 @@ impl HugePage {
         if buffer == libc::MAP_FAILED {
             return Err(io::Error::last_os_error());
         }
+        // the mapping will be released by `drop` if the following steps fail
+        let page = HugePage {
+            size,
+            addr: buffer as usize,
+            backing: if extra_flags & libc::MAP_HUGETLB == 0_i32 {
+                HugePageBacking::Regular
+            } else {
+                HugePageBacking::Huge
+            },
+        };
+
+        if page.backing == HugePageBacking::Regular {
+            // transparent huge pages are only a hint, the mapping still works without them
+            let ret = unsafe { libc::madvise(buffer, size, libc::MADV_HUGEPAGE) };
+            if ret != 0_i32 {
+                warn!("madvise huge page failed: {}", io::Error::last_os_error());
+            }
+        }
+
+        if let Some(node) = node {
+            bind_to_node(buffer, size, node)?;
+        }
 
         let ret = unsafe { libc::mlock(buffer, size) };
         if ret != 0_i32 {
             return Err(io::Error::last_os_error());
         }
-        Ok(HugePage {
-            size,
-            addr: buffer as usize,
-        })
+        Ok(page)
     }
 
     /// get raw pointer of huge page buffer
@@ -93,6 +385,12 @@ impl HugePage {
         self.size
     }
 
+    /// The kind of pages backing the buffer, which is `HugePageBacking::Regular` after a fallback
+    #[must_use]
+    pub fn backing(&self) -> HugePageBacking {
+        self.backing
+    }
+
     pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
         unsafe { from_raw_parts_mut(self.addr as *mut u8, self.size) }
     }
@@ -124,11 +422,149 @@ impl Drop for HugePage {
     }
 }
 
+/// A zeroed buffer allocated from the global allocator and aligned to `PAGE_SIZE`
+///
+/// Unlike `HugePage`, it comes from the global allocator, so it also works with the emulated device
+/// whose global allocator lives in the memory shared with the simulator.
+#[derive(Debug)]
+pub struct AlignedMemory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is owned exclusively, like a `Box<[u8]>`
+unsafe impl Send for AlignedMemory {}
+unsafe impl Sync for AlignedMemory {}
+
+impl AlignedMemory {
+    /// Allocate a zeroed buffer of `size` bytes aligned to `PAGE_SIZE`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `size` is zero or too large, or the allocation fails.
+    pub fn new(size: usize) -> io::Result<Self> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "aligned memory of zero bytes",
+            ));
+        }
+        let layout = Layout::from_size_align(size, PAGE_SIZE)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // the size of `layout` is checked to be non-zero above
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        Ok(Self { ptr, layout })
+    }
+
+    /// get raw pointer of the buffer
+    #[must_use]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// get raw mutable pointer of the buffer
+    #[must_use]
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// get size of the buffer
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Deref for AlignedMemory {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedMemory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
+
+/// Bind the memory in `[addr, addr + len)` to the NUMA `node`. It must be called before the memory is faulted in.
+fn bind_to_node(addr: *mut libc::c_void, len: usize, node: u32) -> io::Result<()> {
+    const BITS_PER_MASK: u32 = libc::c_ulong::BITS;
+    const MAX_NODE_CNT: u32 = 1024;
+    const MASK_LEN: usize = (MAX_NODE_CNT / BITS_PER_MASK) as usize;
+    if node >= MAX_NODE_CNT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid numa node {node}"),
+        ));
+    }
+    let mut nodemask: [libc::c_ulong; MASK_LEN] = [0; MASK_LEN];
+    // `node` is less than `MAX_NODE_CNT`, so the index is in range
+    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+    {
+        nodemask[(node / BITS_PER_MASK) as usize] |= 1 << (node % BITS_PER_MASK);
+    }
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            libc::c_ulong::from(MAX_NODE_CNT),
+            MPOL_MF_STRICT,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Query the NUMA node on which the page of `addr` currently resides.
+///
+/// The page is faulted in if it has not been allocated yet.
+///
+/// # Errors
+///
+/// Will return `Err` if `addr` is not mapped or the kernel does not support NUMA.
+pub fn numa_node_of_addr(addr: usize) -> io::Result<u32> {
+    let mut node: libc::c_int = 0;
+    let maxnode: libc::c_ulong = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            std::ptr::addr_of_mut!(node),
+            std::ptr::null_mut::<libc::c_ulong>(),
+            maxnode,
+            addr as *const libc::c_void,
+            MPOL_F_NODE | MPOL_F_ADDR,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    u32::try_from(node).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::types::Pmtu;
+    use crate::types::{Key, Pmtu, Sge};
 
-    use super::align_up;
+    use super::{
+        align_up, numa_node_of_addr, split_sge_list, Fragment, HugePage, HugePageBacking, HugePageSize,
+        PmtuFragments,
+    };
 
     #[test]
     fn test_calculate_packet_cnt() {
@@ -143,6 +579,122 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_calculate_packet_cnt_zero_length() {
+        // a zero-length message is sent by exactly one empty packet
+        for raddr in [0, 1, 512, 1023, 1024, 4095] {
+            let packet_cnt = super::calculate_packet_cnt(Pmtu::Mtu1024, raddr, 0);
+            assert_eq!(packet_cnt, 1);
+        }
+
+        // no extra empty packet when the message ends at a pmtu boundary
+        assert_eq!(super::calculate_packet_cnt(Pmtu::Mtu1024, 1000, 24), 1);
+        assert_eq!(super::calculate_packet_cnt(Pmtu::Mtu1024, 1000, 1048), 2);
+    }
+
+    #[test]
+    fn test_pmtu_fragments() {
+        let fragment = |offset, len, is_first, is_last| Fragment {
+            offset,
+            len,
+            is_first,
+            is_last,
+        };
+
+        // shorter than the first fragment
+        let fragments: Vec<_> = PmtuFragments::new(1000, 10, Pmtu::Mtu1024).collect();
+        assert_eq!(fragments, vec![fragment(0, 10, true, true)]);
+
+        // exactly one pmtu, which is cut by the boundary unless the va is aligned
+        let fragments: Vec<_> = PmtuFragments::new(0, 1024, Pmtu::Mtu1024).collect();
+        assert_eq!(fragments, vec![fragment(0, 1024, true, true)]);
+        let fragments: Vec<_> = PmtuFragments::new(1000, 1024, Pmtu::Mtu1024).collect();
+        assert_eq!(
+            fragments,
+            vec![fragment(0, 24, true, false), fragment(24, 1000, false, true)]
+        );
+
+        // a zero-length message is one empty packet
+        let fragments: Vec<_> = PmtuFragments::new(1000, 0, Pmtu::Mtu1024).collect();
+        assert_eq!(fragments, vec![fragment(0, 0, true, true)]);
+
+        // many pmtus at various offsets
+        for pmtu in [Pmtu::Mtu256, Pmtu::Mtu512, Pmtu::Mtu1024, Pmtu::Mtu4096] {
+            let pmtu_len = u32::from(&pmtu);
+            for va in [0, 1, 255, 256, 1023, 4095, 4096, 0x1_0000_0001] {
+                for total_len in [1, pmtu_len - 1, pmtu_len, pmtu_len + 1, 10 * pmtu_len + 7] {
+                    let fragments: Vec<_> = PmtuFragments::new(va, total_len, pmtu).collect();
+                    assert_eq!(
+                        fragments.len() as u32,
+                        super::calculate_packet_cnt(pmtu, va, total_len)
+                    );
+                    let mut offset = 0;
+                    for (i, fragment) in fragments.iter().enumerate() {
+                        assert_eq!(fragment.offset, offset);
+                        assert_eq!(fragment.is_first, i == 0);
+                        assert_eq!(fragment.is_last, i == fragments.len() - 1);
+                        assert!(fragment.len > 0 && fragment.len <= pmtu_len);
+                        let end = va + u64::from(offset + fragment.len);
+                        // every packet except the last one ends at a pmtu boundary
+                        assert!(fragment.is_last || end % u64::from(pmtu_len) == 0);
+                        offset += fragment.len;
+                    }
+                    assert_eq!(offset, total_len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_sge_list() {
+        let key = Key::new(1);
+        let lens = |sge_list: &Vec<Sge>| sge_list.iter().map(|sge| sge.len).collect::<Vec<_>>();
+
+        // fit in one descriptor
+        let sges = [Sge::new(0x1000, 100, key), Sge::new(0x3000, 200, key)];
+        let sge_lists = split_sge_list(&sges, 0, Pmtu::Mtu1024, 4);
+        assert_eq!(sge_lists.len(), 1);
+        assert_eq!(lens(&sge_lists[0]), vec![100, 200]);
+
+        // 6 * 600 bytes starting from a pmtu boundary. The first descriptor ends at 2048.
+        let sges: Vec<Sge> = (0..6)
+            .map(|i| Sge::new(0x10000 * (i + 1), 600, key))
+            .collect();
+        let sge_lists = split_sge_list(&sges, 0, Pmtu::Mtu1024, 4);
+        assert_eq!(sge_lists.len(), 2);
+        assert_eq!(lens(&sge_lists[0]), vec![600, 600, 600, 248]);
+        assert_eq!(lens(&sge_lists[1]), vec![352, 600, 600]);
+        assert_eq!(sge_lists[1][0].addr, 0x40000 + 248);
+
+        // a descriptor which ends at the boundary exactly
+        let sges: Vec<Sge> = (0..5)
+            .map(|i| Sge::new(0x10000 * (i + 1), 256, key))
+            .collect();
+        let sge_lists = split_sge_list(&sges, 0, Pmtu::Mtu1024, 4);
+        assert_eq!(sge_lists.len(), 2);
+        assert_eq!(lens(&sge_lists[0]), vec![256, 256, 256, 256]);
+        assert_eq!(lens(&sge_lists[1]), vec![256]);
+
+        // the sges of a descriptor can't reach the next boundary, so they are taken up to it
+        let sges: Vec<Sge> = (0..70)
+            .map(|i| Sge::new(0x10000 * (i + 1), 16, key))
+            .collect();
+        let sge_lists = split_sge_list(&sges, 1000, Pmtu::Mtu1024, 4);
+        assert_eq!(sge_lists.len(), 3);
+        assert_eq!(lens(&sge_lists[0]), vec![16, 8]);
+        assert_eq!(sge_lists[1].len(), 65);
+        assert_eq!(sge_lists[1][0].addr, 0x20000 + 8);
+        assert_eq!(sge_lists[1].iter().map(|sge| sge.len).sum::<u32>(), 1024);
+        assert_eq!(sge_lists[2].len(), 5);
+        let addrs: Vec<u64> = sge_lists.iter().flatten().map(|sge| sge.addr).collect();
+        assert!(addrs.windows(2).all(|pair| pair[0] < pair[1]), "the order is changed");
+
+        // too few sges for the first boundary are all in the last list
+        let sge_lists = split_sge_list(&sges[..5], 0, Pmtu::Mtu1024, 4);
+        assert_eq!(sge_lists.len(), 1);
+        assert_eq!(sge_lists[0].len(), 5);
+    }
+
     #[test]
     fn align_up_test() {
         let a = align_up::<{ 1024 * 1024 * 2 }>(1024);
@@ -151,4 +703,65 @@ mod tests {
         assert_eq!(a, 1024 * 1024 * 2);
         assert_eq!(b, 1024 * 1024 * 4);
     }
+
+    /// The size of the pages backing the mapping starting at `addr`, read from `/proc/self/smaps`
+    fn kernel_page_size(addr: usize) -> usize {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut lines = smaps
+            .lines()
+            .skip_while(|line| !line.starts_with(&format!("{addr:x}-")));
+        let kb = lines
+            .find_map(|line| line.strip_prefix("KernelPageSize:"))
+            .and_then(|size| size.trim().strip_suffix("kB"))
+            .unwrap();
+        kb.trim().parse::<usize>().unwrap() * 1024
+    }
+
+    #[test]
+    fn test_huge_page_1g() {
+        // 1GB pages are only available if they are reserved on the host, otherwise there is nothing
+        // to map
+        let free_pages: usize =
+            std::fs::read_to_string("/sys/kernel/mm/hugepages/hugepages-1048576kB/free_hugepages")
+                .map_or(0, |free| free.trim().parse().unwrap());
+        match HugePage::new_with_size(1024, HugePageSize::Size1G) {
+            Ok(mut page) => {
+                assert_eq!(page.size(), HugePageSize::Size1G.size());
+                assert_eq!(page.backing(), HugePageBacking::Huge);
+                assert_eq!(kernel_page_size(page.as_ptr() as usize), HugePageSize::Size1G.size());
+                let last = page.size() - 1;
+                page[last] = 1;
+                assert_eq!(page[last], 1);
+            }
+            Err(e) => assert_eq!(free_pages, 0, "a reserved 1GB page is not mapped: {e}"),
+        }
+
+        // the 2MB pages are told apart from the regular ones the same way
+        if let Ok(page) = HugePage::new_with_size(1024, HugePageSize::Size2M) {
+            assert_eq!(kernel_page_size(page.as_ptr() as usize), HugePageSize::Size2M.size());
+        }
+        let page = HugePage::new_fallback(1024).unwrap();
+        assert!(kernel_page_size(page.as_ptr() as usize) < HugePageSize::Size2M.size());
+    }
+
+    #[test]
+    fn test_huge_page_on_node() {
+        // huge pages may not be reserved on the host, but an invalid node is always rejected
+        assert!(HugePage::new_on_node(1024, 4096).is_err());
+        if let Ok(page) = HugePage::new_on_node(1024, 0) {
+            assert_eq!(numa_node_of_addr(page.as_ptr() as usize).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_huge_page_fallback() {
+        let mut page = HugePage::new_fallback(1024).unwrap();
+        assert_eq!(page.size(), HugePage::HUGE_PAGE_SIZE);
+        assert_eq!(page.backing(), HugePageBacking::Regular);
+        let last = page.size() - 1;
+        page[0] = 1;
+        page[last] = 2;
+        assert_eq!(page[0], 1);
+        assert_eq!(page[last], 2);
+    }
 }